@@ -0,0 +1,77 @@
+//! Many concurrent `echo` calls over one WebSocket and over pools of them, against a running
+//! server, e.g. `cargo bench --package client --bench pool --features native`. The URL
+//! defaults to `client::rpc_client::DEFAULT_URL`, `WORLD_URL` overrides it.
+//!
+//! The server has to let a native client in and take the calls at once, e.g. started with
+//! `--allow-anonymous --allow-missing-origin --rate-limits echo=1000000/1000000
+//! --in-flight-queue 10000`.
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use client::rpc_client::{ClientBuilder, DEFAULT_URL};
+use client::transport::ConnectOptions;
+use futures::future;
+use tarpc::context;
+use tokio::task::LocalSet;
+
+const CALLS: usize = 1_000;
+const ROUNDS: u32 = 5;
+const POOL_SIZES: [usize; 4] = [1, 2, 4, 8];
+
+fn main() {
+    let url = std::env::var("WORLD_URL").unwrap_or_else(|_| DEFAULT_URL.to_string());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("can't start the runtime");
+    let payloads = [
+        ("small", "a small payload".to_string()),
+        ("64 KiB", "x".repeat(64 * 1024)),
+    ];
+    for (name, payload) in &payloads {
+        for pool_size in POOL_SIZES {
+            // The client's tasks are spawned with `spawn_local`, see `client::native`.
+            let calls = burst(&url, pool_size, payload);
+            let elapsed = match LocalSet::new().block_on(&runtime, calls) {
+                Ok(elapsed) => elapsed,
+                Err(e) => {
+                    eprintln!("pool: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let rate = CALLS as f64 / elapsed.as_secs_f64();
+            println!(
+                "echo, {:<8} {} sockets {:>10.2?} per {} calls {:>8.0} calls/s",
+                name, pool_size, elapsed, CALLS, rate
+            );
+        }
+    }
+}
+
+/// Connects a pool of `pool_size` sockets and makes [`CALLS`] calls at once a few times,
+/// answering how long they took on average.
+async fn burst(url: &str, pool_size: usize, payload: &str) -> Result<Duration, Box<dyn Error>> {
+    let options = ConnectOptions {
+        pool_size,
+        ..ConnectOptions::default()
+    };
+    let world = ClientBuilder::new(url)
+        .options(options)
+        .max_in_flight(CALLS)
+        .connect()
+        .await?;
+    let calls = || async {
+        let calls = (0..CALLS).map(|_| world.client().echo(context::current(), payload.into()));
+        for answer in future::join_all(calls).await {
+            answer??;
+        }
+        Ok::<_, Box<dyn Error>>(())
+    };
+    calls().await?;
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        calls().await?;
+    }
+    Ok(start.elapsed() / ROUNDS)
+}
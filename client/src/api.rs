@@ -0,0 +1,322 @@
+//! The `World` methods as a trait, for code that shouldn't care which client makes its calls.
+//!
+//! tarpc generates [`WorldClient`] as a concrete type, so code calling it can only be run
+//! against a server. Code written against [`WorldApi`] takes the client, a wrapper such as the
+//! [`QueueingWorldClient`](crate::offline::QueueingWorldClient), or with the `test-util`
+//! feature a scripted [`MockWorld`](crate::mock::MockWorld), usually as an `Rc<dyn WorldApi>`.
+
+use std::fmt;
+
+use async_trait::async_trait;
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, UploadResult, VersionInfo,
+    WorldClient, WorldError,
+};
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+
+use crate::error::TransportError;
+
+/// The methods of [`rpc::World`] as clients call them. The outer error is the call failing,
+/// the inner one the method.
+#[async_trait(?Send)]
+pub trait WorldApi: fmt::Debug {
+    async fn ping(&self, ctx: Context) -> Result<Result<String, WorldError>, RpcError>;
+
+    async fn echo(
+        &self,
+        ctx: Context,
+        value: String,
+    ) -> Result<Result<String, WorldError>, RpcError>;
+
+    async fn delay(
+        &self,
+        ctx: Context,
+        millis: u64,
+    ) -> Result<Result<String, WorldError>, RpcError>;
+
+    async fn peer_id(&self, ctx: Context) -> Result<Result<u64, WorldError>, RpcError>;
+
+    async fn signal(
+        &self,
+        ctx: Context,
+        to: u64,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError>;
+
+    async fn signals(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<(u64, String)>, WorldError>, RpcError>;
+
+    async fn count(
+        &self,
+        ctx: Context,
+        stream: u64,
+        n: u64,
+    ) -> Result<Result<(), WorldError>, RpcError>;
+
+    async fn subscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError>;
+
+    async fn unsubscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError>;
+
+    async fn publish(
+        &self,
+        ctx: Context,
+        topic: String,
+        payload: String,
+    ) -> Result<Result<u64, WorldError>, RpcError>;
+
+    async fn delay_with_progress(
+        &self,
+        ctx: Context,
+        millis: u64,
+        progress: u64,
+    ) -> Result<Result<String, WorldError>, RpcError>;
+
+    async fn whoami(&self, ctx: Context) -> Result<Result<SessionInfo, WorldError>, RpcError>;
+
+    async fn list_clients(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<ClientInfo>, WorldError>, RpcError>;
+
+    async fn upload_begin(
+        &self,
+        ctx: Context,
+        name: String,
+        size: u64,
+    ) -> Result<Result<u64, WorldError>, RpcError>;
+
+    async fn upload_chunk(
+        &self,
+        ctx: Context,
+        id: u64,
+        offset: u64,
+        bytes: Bytes,
+    ) -> Result<Result<(), WorldError>, RpcError>;
+
+    async fn upload_finish(
+        &self,
+        ctx: Context,
+        id: u64,
+        checksum: u32,
+    ) -> Result<Result<UploadResult, WorldError>, RpcError>;
+
+    async fn list_items(
+        &self,
+        ctx: Context,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Result<Page, WorldError>, RpcError>;
+
+    async fn increment(&self, ctx: Context, by: i64) -> Result<Result<i64, WorldError>, RpcError>;
+
+    async fn get_count(&self, ctx: Context) -> Result<Result<i64, WorldError>, RpcError>;
+
+    async fn version(&self, ctx: Context) -> Result<Result<VersionInfo, WorldError>, RpcError>;
+
+    async fn echo_bytes(
+        &self,
+        ctx: Context,
+        data: Bytes,
+    ) -> Result<Result<Bytes, WorldError>, RpcError>;
+
+    async fn create_task(
+        &self,
+        ctx: Context,
+        request: CreateTaskRequest,
+    ) -> Result<Result<Task, WorldError>, RpcError>;
+
+    async fn log_event(
+        &self,
+        ctx: Context,
+        name: String,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError>;
+
+    /// Why calls fail once the connection they are made on ended for good, `None` before or
+    /// if the client can't tell. tarpc itself only says the connection is gone.
+    fn closed_error(&self) -> Option<TransportError> {
+        None
+    }
+}
+
+#[async_trait(?Send)]
+impl WorldApi for WorldClient {
+    async fn ping(&self, ctx: Context) -> Result<Result<String, WorldError>, RpcError> {
+        WorldClient::ping(self, ctx).await
+    }
+
+    async fn echo(
+        &self,
+        ctx: Context,
+        value: String,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        WorldClient::echo(self, ctx, value).await
+    }
+
+    async fn delay(
+        &self,
+        ctx: Context,
+        millis: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        WorldClient::delay(self, ctx, millis).await
+    }
+
+    async fn peer_id(&self, ctx: Context) -> Result<Result<u64, WorldError>, RpcError> {
+        WorldClient::peer_id(self, ctx).await
+    }
+
+    async fn signal(
+        &self,
+        ctx: Context,
+        to: u64,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        WorldClient::signal(self, ctx, to, payload).await
+    }
+
+    async fn signals(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<(u64, String)>, WorldError>, RpcError> {
+        WorldClient::signals(self, ctx).await
+    }
+
+    async fn count(
+        &self,
+        ctx: Context,
+        stream: u64,
+        n: u64,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        WorldClient::count(self, ctx, stream, n).await
+    }
+
+    async fn subscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        WorldClient::subscribe(self, ctx, topic).await
+    }
+
+    async fn unsubscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        WorldClient::unsubscribe(self, ctx, topic).await
+    }
+
+    async fn publish(
+        &self,
+        ctx: Context,
+        topic: String,
+        payload: String,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        WorldClient::publish(self, ctx, topic, payload).await
+    }
+
+    async fn delay_with_progress(
+        &self,
+        ctx: Context,
+        millis: u64,
+        progress: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        WorldClient::delay_with_progress(self, ctx, millis, progress).await
+    }
+
+    async fn whoami(&self, ctx: Context) -> Result<Result<SessionInfo, WorldError>, RpcError> {
+        WorldClient::whoami(self, ctx).await
+    }
+
+    async fn list_clients(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<ClientInfo>, WorldError>, RpcError> {
+        WorldClient::list_clients(self, ctx).await
+    }
+
+    async fn upload_begin(
+        &self,
+        ctx: Context,
+        name: String,
+        size: u64,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        WorldClient::upload_begin(self, ctx, name, size).await
+    }
+
+    async fn upload_chunk(
+        &self,
+        ctx: Context,
+        id: u64,
+        offset: u64,
+        bytes: Bytes,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        WorldClient::upload_chunk(self, ctx, id, offset, bytes).await
+    }
+
+    async fn upload_finish(
+        &self,
+        ctx: Context,
+        id: u64,
+        checksum: u32,
+    ) -> Result<Result<UploadResult, WorldError>, RpcError> {
+        WorldClient::upload_finish(self, ctx, id, checksum).await
+    }
+
+    async fn list_items(
+        &self,
+        ctx: Context,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Result<Page, WorldError>, RpcError> {
+        WorldClient::list_items(self, ctx, cursor, limit).await
+    }
+
+    async fn increment(&self, ctx: Context, by: i64) -> Result<Result<i64, WorldError>, RpcError> {
+        WorldClient::increment(self, ctx, by).await
+    }
+
+    async fn get_count(&self, ctx: Context) -> Result<Result<i64, WorldError>, RpcError> {
+        WorldClient::get_count(self, ctx).await
+    }
+
+    async fn version(&self, ctx: Context) -> Result<Result<VersionInfo, WorldError>, RpcError> {
+        WorldClient::version(self, ctx).await
+    }
+
+    async fn echo_bytes(
+        &self,
+        ctx: Context,
+        data: Bytes,
+    ) -> Result<Result<Bytes, WorldError>, RpcError> {
+        WorldClient::echo_bytes(self, ctx, data).await
+    }
+
+    async fn create_task(
+        &self,
+        ctx: Context,
+        request: CreateTaskRequest,
+    ) -> Result<Result<Task, WorldError>, RpcError> {
+        WorldClient::create_task(self, ctx, request).await
+    }
+
+    async fn log_event(
+        &self,
+        ctx: Context,
+        name: String,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        WorldClient::log_event(self, ctx, name, payload).await
+    }
+}
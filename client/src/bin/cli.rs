@@ -0,0 +1,69 @@
+//! Calls the server from a terminal, behind the `native` feature.
+//!
+//! `cli [--url URL] COMMAND`, the URL defaulting to `client::rpc_client::DEFAULT_URL`. The
+//! commands:
+//!
+//! - `version`: the build of the server, see `World::version`, one `key: value` per line.
+
+use std::error::Error;
+use std::process::ExitCode;
+
+use client::rpc_client::{ClientBuilder, DEFAULT_URL};
+use tarpc::context;
+use tokio::task::LocalSet;
+
+const USAGE: &str = "usage: cli [--url URL] version";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let (mut url, mut command) = (DEFAULT_URL.to_string(), None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            "--url" => match args.next() {
+                Some(value) => url = value,
+                None => return usage(),
+            },
+            _ if command.is_none() => command = Some(arg),
+            _ => return usage(),
+        }
+    }
+    if command.as_deref() != Some("version") {
+        return usage();
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("can't start the runtime");
+    // The client's tasks are spawned with `spawn_local`, see `client::native`.
+    match LocalSet::new().block_on(&runtime, version(&url)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("cli: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn version(url: &str) -> Result<(), Box<dyn Error>> {
+    let world = ClientBuilder::new(url).connect().await?;
+    let version = world.client().version(context::current()).await??;
+    println!("crate_version: {}", version.crate_version);
+    println!("git_hash: {}", version.git_hash);
+    println!("built_at: {}", version.built_at);
+    let protocols = version
+        .protocols
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>();
+    println!("protocols: {}", protocols.join(", "));
+    Ok(())
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from(2)
+}
@@ -0,0 +1,28 @@
+//! The least a page needs to call the server: one WebSocket in the default frame mode and an
+//! `echo`. Built for wasm32 with and without the `json` feature it shows what JSON costs the
+//! bundle, see the README. It's a binary rather than an example so the dev-dependencies, which
+//! need JSON, stay out of it.
+
+use client::rpc_client::build_client_with_codec;
+use client::transport::{ConnectOptions, FrameMode};
+use rpc::WorldClient;
+use tarpc::context;
+use wasm_bindgen_futures::spawn_local;
+
+fn main() {
+    spawn_local(async {
+        let url = "ws://127.0.0.1:8083";
+        let options = ConnectOptions::default();
+        let (transport, _handle) =
+            match build_client_with_codec(url, FrameMode::default(), options).await {
+                Ok(connected) => connected,
+                Err(e) => return log::error!("Can't connect: {}", e),
+            };
+        let world = WorldClient::new(tarpc::client::Config::default(), transport);
+        spawn_local(async move {
+            let _ = world.dispatch.await;
+        });
+        let answer = world.client.echo(context::current(), "hello".into()).await;
+        log::info!("{:?}", answer);
+    });
+}
@@ -0,0 +1,347 @@
+//! Shared worker owning one WebSocket for every tab of the page, see `client::worker`.
+//!
+//! Each tab's tarpc client numbers its requests from zero, so requests get ids unique on the
+//! connection on their way out and their own ids back in the responses. That needs JSON, tabs
+//! using this worker always send text frames. Acks and other messages that answer no request
+//! go to every tab.
+
+use std::collections::HashMap;
+
+use futures::channel::mpsc;
+use futures::future;
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use gloo_timers::future::TimeoutFuture;
+use js_sys::Array;
+use pharos::{Events, Observable, ObserveConfig};
+use serde_json::Value;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{MessageEvent, MessagePort, SharedWorkerGlobalScope};
+use ws_stream_wasm::{WsErr, WsEvent, WsMessage, WsMeta, WsStream};
+
+// Tabs get text frames only and a `CLOSE` only lets the tab go, so those answers are unused.
+#[allow(dead_code)]
+#[path = "../worker/protocol.rs"]
+mod protocol;
+// Tabs never ask for binary frames, so only the close handling is used.
+#[allow(dead_code)]
+#[path = "../socket.rs"]
+mod socket;
+
+use protocol::*;
+use socket::{wait_for_close, ABNORMAL_CLOSURE};
+
+
+/// How long the socket outlives the last tab, so reloading a page doesn't reconnect.
+const GRACE_PERIOD_MS: u32 = 5_000;
+
+fn main() {
+    let scope: SharedWorkerGlobalScope = js_sys::global().unchecked_into();
+    let (commands_tx, commands) = mpsc::unbounded();
+    let mut next_tab = 0;
+    let on_connect = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let port: MessagePort = event.ports().get(0).unchecked_into();
+        let tab = next_tab;
+        next_tab += 1;
+        let messages = commands_tx.clone();
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let _ = messages.unbounded_send(Command::Tab(tab, Array::from(&event.data())));
+        }) as Box<dyn FnMut(MessageEvent)>);
+        port.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        let joined = Tab {
+            port,
+            opened: false,
+            _on_message: on_message,
+        };
+        let _ = commands_tx.unbounded_send(Command::Joined(tab, joined));
+    }) as Box<dyn FnMut(MessageEvent)>);
+    scope.set_onconnect(Some(on_connect.as_ref().unchecked_ref()));
+    // The worker lives as long as any tab keeps it, and so does the listener.
+    on_connect.forget();
+    spawn_local(Hub::default().run(commands));
+}
+
+struct Tab {
+    port: MessagePort,
+    /// The tab uses the socket, rather than just having connected to the worker.
+    opened: bool,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+}
+
+enum Command {
+    Joined(u32, Tab),
+    Tab(u32, Array),
+}
+
+struct Socket {
+    meta: WsMeta,
+    ws: WsStream,
+    events: Events<WsEvent>,
+    /// What the tab that opened it asked for, others have to ask for the same.
+    url: String,
+    requested: String,
+    /// The subprotocol the server selected.
+    selected: String,
+}
+
+enum Event {
+    Command(Option<Command>),
+    Frame(Option<WsMessage>),
+    Socket(Option<WsEvent>),
+    /// The grace period after the last tab left is over.
+    Idle,
+}
+
+#[derive(Default)]
+struct Hub {
+    tabs: HashMap<u32, Tab>,
+    socket: Option<Socket>,
+    /// Id of a request on the connection, to the tab that sent it and the id it used.
+    requests: HashMap<u64, (u32, u64)>,
+    next_id: u64,
+    grace: Option<TimeoutFuture>,
+}
+
+impl Hub {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>) {
+        loop {
+            let (socket, grace) = (&mut self.socket, &mut self.grace);
+            let event = match socket {
+                Some(socket) => select! {
+                    command = commands.next() => Event::Command(command),
+                    frame = socket.ws.next().fuse() => Event::Frame(frame),
+                    event = socket.events.next().fuse() => Event::Socket(event),
+                    _ = idle(grace).fuse() => Event::Idle,
+                },
+                None => Event::Command(commands.next().await),
+            };
+            match event {
+                Event::Command(None) => return,
+                Event::Command(Some(Command::Joined(tab, joined))) => {
+                    self.tabs.insert(tab, joined);
+                }
+                Event::Command(Some(Command::Tab(tab, command))) => {
+                    self.command(tab, command).await
+                }
+                Event::Frame(Some(WsMessage::Text(text))) => self.incoming(text),
+                // Tabs never ask for binary frames.
+                Event::Frame(Some(WsMessage::Binary(_))) => {}
+                Event::Frame(None) => {
+                    let mut socket = self.socket.take().unwrap();
+                    let (code, reason, was_clean) = wait_for_close(&mut socket.events).await;
+                    self.closed(code, reason, was_clean);
+                }
+                Event::Socket(Some(WsEvent::Closed(event))) => {
+                    self.socket = None;
+                    self.closed(event.code, event.reason, event.was_clean);
+                }
+                Event::Socket(Some(_)) => {}
+                Event::Socket(None) => {
+                    self.socket = None;
+                    self.closed(ABNORMAL_CLOSURE, "socket went away".to_string(), false);
+                }
+                Event::Idle => {
+                    self.grace = None;
+                    if let Some(socket) = self.socket.take() {
+                        let _ = socket.meta.close().await;
+                    }
+                    self.requests.clear();
+                }
+            }
+        }
+    }
+
+    async fn command(&mut self, tab: u32, command: Array) {
+        match command.get(0).as_string().as_deref() {
+            Some(OPEN) => {
+                let open = command.get(1).as_string().unwrap_or_default();
+                match serde_json::from_str::<Open>(&open) {
+                    Ok(open) => self.open(tab, open).await,
+                    Err(e) => self.post(
+                        tab,
+                        &[FAILED.into(), ABNORMAL_CLOSURE.into(), e.to_string().into()],
+                    ),
+                }
+            }
+            Some(SEND) => {
+                if let (Some(text), true) = (command.get(1).as_string(), self.socket.is_some()) {
+                    let text = self.outgoing(tab, text);
+                    // A failed send also shows up as the socket closing.
+                    let _ = self
+                        .socket
+                        .as_mut()
+                        .unwrap()
+                        .ws
+                        .send(WsMessage::Text(text))
+                        .await;
+                }
+                self.post(tab, &[SENT.into()]);
+            }
+            // Only the tab's transport closes, the socket stays for the others.
+            Some(CLOSE) => {
+                let code = command.get(1).as_f64().unwrap_or(1000.0) as u16;
+                let reason = command.get(2).as_string().unwrap_or_default();
+                self.post(
+                    tab,
+                    &[CLOSED.into(), code.into(), reason.into(), true.into()],
+                );
+                self.leave(tab, false);
+            }
+            Some(LEAVE) => self.leave(tab, true),
+            _ => {}
+        }
+    }
+
+    async fn open(&mut self, tab: u32, open: Open) {
+        if let Some(socket) = &self.socket {
+            if socket.url == open.url && socket.requested == open.protocol {
+                let protocol = socket.selected.clone();
+                self.opened(tab, protocol);
+            } else {
+                let reason = "the shared connection is to another server or in another mode";
+                self.post(
+                    tab,
+                    &[FAILED.into(), ABNORMAL_CLOSURE.into(), reason.into()],
+                );
+            }
+            return;
+        }
+        let connected = WsMeta::connect(&open.url, vec![open.protocol.as_str()]).await;
+        let (mut meta, ws) = match connected {
+            Ok(connected) => connected,
+            Err(WsErr::ConnectionFailed { event }) => {
+                self.post(
+                    tab,
+                    &[FAILED.into(), event.code.into(), event.reason.into()],
+                );
+                return;
+            }
+            Err(e) => {
+                self.post(
+                    tab,
+                    &[FAILED.into(), ABNORMAL_CLOSURE.into(), e.to_string().into()],
+                );
+                return;
+            }
+        };
+        let events = match meta.observe(ObserveConfig::default()).await {
+            Ok(events) => events,
+            Err(e) => {
+                let _ = meta.close().await;
+                self.post(
+                    tab,
+                    &[FAILED.into(), ABNORMAL_CLOSURE.into(), e.to_string().into()],
+                );
+                return;
+            }
+        };
+        let protocol = meta.protocol();
+        self.socket = Some(Socket {
+            meta,
+            ws,
+            events,
+            url: open.url,
+            requested: open.protocol,
+            selected: protocol.clone(),
+        });
+        self.opened(tab, protocol);
+    }
+
+    fn opened(&mut self, tab: u32, protocol: String) {
+        if let Some(joined) = self.tabs.get_mut(&tab) {
+            joined.opened = true;
+        }
+        self.grace = None;
+        self.post(tab, &[OPENED.into(), protocol.into()]);
+    }
+
+    /// Forgets about `tab`, starting the grace period if it was the last one using the socket.
+    fn leave(&mut self, tab: u32, gone: bool) {
+        if gone {
+            self.tabs.remove(&tab);
+        } else if let Some(joined) = self.tabs.get_mut(&tab) {
+            joined.opened = false;
+        }
+        self.requests.retain(|_, (owner, _)| *owner != tab);
+        if self.socket.is_some() && !self.tabs.values().any(|joined| joined.opened) {
+            self.grace = Some(TimeoutFuture::new(GRACE_PERIOD_MS));
+        }
+    }
+
+    /// Gives the request in `text` an id unique on the connection.
+    fn outgoing(&mut self, tab: u32, text: String) -> String {
+        let mut envelope: Value = match serde_json::from_str(&text) {
+            Ok(envelope) => envelope,
+            Err(_) => return text,
+        };
+        if let Some(id) = envelope.pointer_mut("/Message/Request/id") {
+            let local = id.as_u64().unwrap_or_default();
+            let global = self.next_id;
+            self.next_id += 1;
+            self.requests.insert(global, (tab, local));
+            *id = global.into();
+        } else if let Some(id) = envelope.pointer_mut("/Message/Cancel/request_id") {
+            let local = id.as_u64().unwrap_or_default();
+            let global = self
+                .requests
+                .iter()
+                .find(|(_, &sender)| sender == (tab, local))
+                .map(|(&global, _)| global);
+            // The server doesn't answer a cancelled request.
+            if let Some(global) = global {
+                self.requests.remove(&global);
+                *id = global.into();
+            }
+        }
+        envelope.to_string()
+    }
+
+    /// Hands a response to the tab that sent the request, with the id it used.
+    fn incoming(&mut self, text: String) {
+        let mut envelope: Value = match serde_json::from_str(&text) {
+            Ok(envelope) => envelope,
+            Err(_) => return self.broadcast(&[TEXT.into(), text.into()]),
+        };
+        let id = match envelope.pointer_mut("/Message/request_id") {
+            Some(id) => id,
+            None => return self.broadcast(&[TEXT.into(), text.into()]),
+        };
+        let global = id.as_u64().unwrap_or_default();
+        // Otherwise the tab left or cancelled the request meanwhile.
+        if let Some((tab, local)) = self.requests.remove(&global) {
+            *id = local.into();
+            let text = envelope.to_string();
+            self.post(tab, &[TEXT.into(), text.into()]);
+        }
+    }
+
+    fn closed(&mut self, code: u16, reason: String, was_clean: bool) {
+        self.requests.clear();
+        self.grace = None;
+        self.broadcast(&[CLOSED.into(), code.into(), reason.into(), was_clean.into()]);
+    }
+
+    fn post(&self, tab: u32, message: &[JsValue]) {
+        if let Some(joined) = self.tabs.get(&tab) {
+            let message: Array = message.iter().collect();
+            let _ = joined.port.post_message(&message);
+        }
+    }
+
+    /// Posts `message` to every tab using the socket.
+    fn broadcast(&self, message: &[JsValue]) {
+        let message: Array = message.iter().collect();
+        for joined in self.tabs.values().filter(|joined| joined.opened) {
+            let _ = joined.port.post_message(&message);
+        }
+    }
+}
+
+async fn idle(grace: &mut Option<TimeoutFuture>) {
+    match grace {
+        Some(grace) => grace.await,
+        None => future::pending().await,
+    }
+}
+
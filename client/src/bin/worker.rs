@@ -0,0 +1,279 @@
+//! Dedicated worker owning the WebSocket for `client::worker`.
+//!
+//! Binary frames are reassembled and inflated here, so the main thread only deserializes the
+//! messages it is handed. Outgoing messages arrive already framed and are sent as they are.
+
+use futures::channel::mpsc;
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use js_sys::{Array, Uint8Array};
+use pharos::{Events, Observable, ObserveConfig};
+use rpc::compression;
+use rpc::framing::{FrameDecoder, FrameError};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent};
+use ws_stream_wasm::{WsErr, WsEvent, WsMessage, WsMeta, WsStream};
+
+#[path = "../worker/protocol.rs"]
+mod protocol;
+#[path = "../socket.rs"]
+mod socket;
+
+use protocol::*;
+use socket::{
+    expect_array_buffers, lost_frame, wait_for_close, ABNORMAL_CLOSURE, UNSUPPORTED_DATA,
+};
+
+
+fn main() {
+    let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+    let (commands_tx, commands) = mpsc::unbounded();
+    let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let _ = commands_tx.unbounded_send(Array::from(&event.data()));
+    }) as Box<dyn FnMut(MessageEvent)>);
+    scope.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    // The worker lives as long as the page keeps it, and so does the listener.
+    on_message.forget();
+    spawn_local(
+        Worker {
+            scope,
+            socket: None,
+        }
+        .run(commands),
+    );
+}
+
+struct Socket {
+    meta: WsMeta,
+    ws: WsStream,
+    events: Events<WsEvent>,
+    decoder: FrameDecoder,
+    max_message_size: usize,
+}
+
+enum Event {
+    Command(Option<Array>),
+    Frame(Option<WsMessage>),
+    Socket(Option<WsEvent>),
+}
+
+struct Worker {
+    scope: DedicatedWorkerGlobalScope,
+    socket: Option<Socket>,
+}
+
+impl Worker {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Array>) {
+        loop {
+            let event = match &mut self.socket {
+                Some(socket) => select! {
+                    command = commands.next() => Event::Command(command),
+                    frame = socket.ws.next().fuse() => Event::Frame(frame),
+                    event = socket.events.next().fuse() => Event::Socket(event),
+                },
+                None => Event::Command(commands.next().await),
+            };
+            match event {
+                // The main thread dropped the transport.
+                Event::Command(None) => {
+                    if let Some(socket) = self.socket.take() {
+                        let _ = socket.meta.close().await;
+                    }
+                    return;
+                }
+                Event::Command(Some(command)) => self.command(command).await,
+                Event::Frame(Some(frame)) => self.frame(frame),
+                Event::Frame(None) => {
+                    let mut socket = self.socket.take().unwrap();
+                    let closed = wait_for_close(&mut socket.events).await;
+                    self.post(&[
+                        CLOSED.into(),
+                        closed.0.into(),
+                        closed.1.into(),
+                        closed.2.into(),
+                    ]);
+                }
+                Event::Socket(Some(WsEvent::Closed(event))) => {
+                    self.socket = None;
+                    self.post(&[
+                        CLOSED.into(),
+                        event.code.into(),
+                        event.reason.into(),
+                        event.was_clean.into(),
+                    ]);
+                }
+                Event::Socket(Some(event)) if lost_frame(&event) => {
+                    let socket = self.socket.take().unwrap();
+                    let reason = "binary frame arrived as a Blob";
+                    let _ = socket.meta.close_reason(UNSUPPORTED_DATA, reason).await;
+                    self.post(&[
+                        CLOSED.into(),
+                        UNSUPPORTED_DATA.into(),
+                        reason.into(),
+                        false.into(),
+                    ]);
+                }
+                Event::Socket(Some(_)) => {}
+                Event::Socket(None) => {
+                    self.socket = None;
+                    self.post(&[
+                        CLOSED.into(),
+                        ABNORMAL_CLOSURE.into(),
+                        "socket went away".into(),
+                        false.into(),
+                    ]);
+                }
+            }
+        }
+    }
+
+    async fn command(&mut self, command: Array) {
+        match command.get(0).as_string().as_deref() {
+            Some(OPEN) => {
+                if let Some(socket) = self.socket.take() {
+                    let _ = socket.meta.close().await;
+                }
+                let open = command.get(1).as_string().unwrap_or_default();
+                match serde_json::from_str::<Open>(&open) {
+                    Ok(open) => self.open(open).await,
+                    Err(e) => {
+                        self.post(&[FAILED.into(), ABNORMAL_CLOSURE.into(), e.to_string().into()])
+                    }
+                }
+            }
+            Some(SEND) => {
+                let data = command.get(1);
+                let message = match data.as_string() {
+                    Some(text) => WsMessage::Text(text),
+                    None => WsMessage::Binary(Uint8Array::new(&data).to_vec()),
+                };
+                if let Some(socket) = &mut self.socket {
+                    // A failed send also shows up as the socket closing.
+                    let _ = socket.ws.send(message).await;
+                }
+                self.post(&[SENT.into()]);
+            }
+            Some(CLOSE) => {
+                let code = command.get(1).as_f64().unwrap_or(1000.0) as u16;
+                let reason = command.get(2).as_string().unwrap_or_default();
+                let socket = match &self.socket {
+                    Some(socket) => socket,
+                    None => {
+                        self.post(&[CLOSED.into(), code.into(), reason.into(), false.into()]);
+                        return;
+                    }
+                };
+                match socket.meta.close_reason(code, &reason).await {
+                    Ok(event) => {
+                        self.socket = None;
+                        self.post(&[
+                            CLOSED.into(),
+                            event.code.into(),
+                            event.reason.into(),
+                            event.was_clean.into(),
+                        ]);
+                    }
+                    Err(WsErr::ConnectionNotOpen) => {
+                        self.socket = None;
+                        self.post(&[CLOSED.into(), code.into(), reason.into(), false.into()]);
+                    }
+                    Err(e) => self.post(&[REJECTED.into(), e.to_string().into()]),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn open(&mut self, open: Open) {
+        let (mut meta, ws) = match WsMeta::connect(&open.url, vec![open.protocol.as_str()]).await {
+            Ok(connected) => connected,
+            Err(WsErr::ConnectionFailed { event }) => {
+                self.post(&[FAILED.into(), event.code.into(), event.reason.into()]);
+                return;
+            }
+            Err(e) => {
+                self.post(&[FAILED.into(), ABNORMAL_CLOSURE.into(), e.to_string().into()]);
+                return;
+            }
+        };
+        if let Err(binary_type) = expect_array_buffers(meta.wrapped()) {
+            let _ = meta.close().await;
+            let reason = format!("socket delivers binary frames as {:?}", binary_type);
+            self.post(&[FAILED.into(), ABNORMAL_CLOSURE.into(), reason.into()]);
+            return;
+        }
+        let events = match meta.observe(ObserveConfig::default()).await {
+            Ok(events) => events,
+            Err(e) => {
+                let _ = meta.close().await;
+                self.post(&[FAILED.into(), ABNORMAL_CLOSURE.into(), e.to_string().into()]);
+                return;
+            }
+        };
+        self.post(&[OPENED.into(), meta.protocol().into()]);
+        self.socket = Some(Socket {
+            meta,
+            ws,
+            events,
+            decoder: FrameDecoder::new(open.max_message_size),
+            max_message_size: open.max_message_size,
+        });
+    }
+
+    fn frame(&mut self, frame: WsMessage) {
+        let bytes = match frame {
+            WsMessage::Text(text) => {
+                self.post(&[TEXT.into(), text.into()]);
+                return;
+            }
+            WsMessage::Binary(bytes) => bytes,
+        };
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => return,
+        };
+        socket.decoder.extend(&bytes);
+        let mut replies = Vec::new();
+        loop {
+            match socket.decoder.next_frame_ref() {
+                Ok(Some(message)) => {
+                    match compression::decompress(message, socket.max_message_size) {
+                        Ok(payload) => replies.push(Reply::Message(Uint8Array::from(&*payload))),
+                        Err(e) => replies.push(Reply::Corrupt(e.to_string())),
+                    }
+                }
+                Ok(None) => break,
+                Err(FrameError::TooLarge { len, .. }) => {
+                    socket.decoder.skip_frame();
+                    replies.push(Reply::Oversized(len));
+                }
+            }
+        }
+        for reply in replies {
+            match reply {
+                Reply::Message(payload) => {
+                    let buffer = payload.buffer();
+                    let message = Array::of2(&MESSAGE.into(), &buffer);
+                    let _ = self
+                        .scope
+                        .post_message_with_transfer(&message, &Array::of1(&buffer));
+                }
+                Reply::Oversized(len) => self.post(&[OVERSIZED.into(), (len as f64).into()]),
+                Reply::Corrupt(reason) => self.post(&[CORRUPT.into(), reason.into()]),
+            }
+        }
+    }
+
+    fn post(&self, message: &[JsValue]) {
+        let message: Array = message.iter().collect();
+        let _ = self.scope.post_message(&message);
+    }
+}
+
+enum Reply {
+    Message(Uint8Array),
+    Oversized(usize),
+    Corrupt(String),
+}
+
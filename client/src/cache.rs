@@ -0,0 +1,494 @@
+//! Answers to repeated lookups served from memory, see [`CachingWorldClient`].
+//!
+//! A caching client keeps the answers of the methods its [`CachePolicy`] gives a time to live,
+//! keyed by the method and its serialized arguments. A call asking the same within that time
+//! gets the kept answer without a request going out. Only the methods [`cacheable`] picks can
+//! be cached at all, and only answers that succeeded are kept.
+//!
+//! The cache holds [`CachePolicy::capacity`] answers at most and drops the least recently used
+//! one to make room. It is emptied whenever the transport connects again, since the server may
+//! have restarted in between and would answer differently.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::info;
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, UploadResult, VersionInfo,
+    WorldClient, WorldError, WorldRequest, WorldResponse,
+};
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+
+use crate::api::WorldApi;
+use crate::intercept::{method, send};
+use crate::transport::{now, ConnectionState, TransportHandle};
+
+/// Answers a cache holds by default.
+pub const DEFAULT_CACHE_CAPACITY: usize = 128;
+
+/// Which answers are kept, and for how long.
+#[derive(Clone, Debug)]
+pub struct CachePolicy {
+    /// Answers kept at once.
+    pub capacity: usize,
+    /// How long the answers of a method are kept, by method name. Methods without one aren't
+    /// cached, nor are those [`cacheable`] turns down whatever their time.
+    pub ttls: HashMap<&'static str, Duration>,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CACHE_CAPACITY,
+            ttls: HashMap::new(),
+        }
+    }
+}
+
+impl CachePolicy {
+    /// Keeps the answers of `method`, e.g. `"echo"`, for `ttl`.
+    pub fn with_ttl(mut self, method: &'static str, ttl: Duration) -> Self {
+        self.ttls.insert(method, ttl);
+        self
+    }
+
+    /// How long the answer to `request` is kept, `None` if it isn't.
+    fn ttl(&self, request: &WorldRequest) -> Option<Duration> {
+        if !cacheable(request) {
+            return None;
+        }
+        self.ttls.get(method(request)).copied()
+    }
+}
+
+/// `ping`, `echo` and `peer_id`, which only look something up. `delay` and
+/// `delay_with_progress` are made for the time they take, the others change something.
+pub fn cacheable(request: &WorldRequest) -> bool {
+    matches!(
+        request,
+        WorldRequest::Ping { .. } | WorldRequest::Echo { .. } | WorldRequest::PeerId { .. }
+    )
+}
+
+/// Calls answered from the cache and calls it had to let through, counting only the methods
+/// the policy caches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Entry {
+    key: (&'static str, String),
+    /// The answer as JSON, tarpc's responses don't clone.
+    response: String,
+    /// When the answer goes stale, in milliseconds as [`now`] counts them.
+    expires: f64,
+}
+
+struct Cache {
+    client: WorldClient,
+    /// Least recently used first.
+    entries: RefCell<VecDeque<Entry>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl Cache {
+    /// The kept answer under `key`, marking it as used. Stale answers are dropped.
+    fn get(&self, key: &(&'static str, String)) -> Option<WorldResponse> {
+        let mut entries = self.entries.borrow_mut();
+        let position = entries.iter().position(|entry| entry.key == *key)?;
+        let entry = entries.remove(position)?;
+        if entry.expires <= now() {
+            return None;
+        }
+        let response = serde_json::from_str(&entry.response).ok();
+        entries.push_back(entry);
+        response
+    }
+
+    fn put(
+        &self,
+        key: (&'static str, String),
+        response: &WorldResponse,
+        ttl: Duration,
+        capacity: usize,
+    ) {
+        // Like requests, responses always serialize.
+        let response = serde_json::to_string(response).unwrap();
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|entry| entry.key != key);
+        if entries.len() >= capacity.max(1) {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            key,
+            response,
+            expires: now() + ttl.as_secs_f64() * 1000.0,
+        });
+    }
+
+    fn clear(&self) {
+        self.entries.borrow_mut().clear();
+    }
+}
+
+/// A [`WorldClient`] answering repeated lookups from a cache, see the [module](self) docs.
+/// Its methods are those of [`WorldApi`]. Clones share the cache.
+#[derive(Clone)]
+pub struct CachingWorldClient {
+    cache: Rc<Cache>,
+    policy: CachePolicy,
+}
+
+impl fmt::Debug for CachingWorldClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachingWorldClient")
+            .field("client", &self.cache.client)
+            .field("policy", &self.policy)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl CachingWorldClient {
+    /// Caches the answers `client` gets, forgetting them whenever `transport`, its connection,
+    /// connects again.
+    pub fn new(client: WorldClient, transport: &TransportHandle, policy: CachePolicy) -> Self {
+        let cache = Rc::new(Cache {
+            client,
+            entries: RefCell::default(),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        });
+        // The transport outlives the clients, it mustn't keep the cache around.
+        let invalidate: Weak<Cache> = Rc::downgrade(&cache);
+        transport.on_state_change(move |state| {
+            if let Some(cache) = invalidate.upgrade() {
+                if matches!(
+                    state,
+                    ConnectionState::Connected | ConnectionState::Closed(_)
+                ) {
+                    cache.clear();
+                }
+            }
+        });
+        Self { cache, policy }
+    }
+
+    pub fn inner(&self) -> &WorldClient {
+        &self.cache.client
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.cache.hits.get(),
+            misses: self.cache.misses.get(),
+        }
+    }
+
+    /// Answers kept right now, stale ones included until they are asked for.
+    pub fn len(&self) -> usize {
+        self.cache.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every answer, the counters stay.
+    pub fn clear(&self) {
+        self.cache.clear();
+    }
+
+    /// Answers `request` from the cache if the policy lets it, otherwise sends it.
+    pub async fn call(
+        &self,
+        ctx: Context,
+        request: WorldRequest,
+    ) -> Result<WorldResponse, RpcError> {
+        let ttl = match self.policy.ttl(&request) {
+            Some(ttl) => ttl,
+            None => return send(&self.cache.client, ctx, request).await,
+        };
+        // Requests always serialize, tarpc sends them that way.
+        let key = (method(&request), serde_json::to_string(&request).unwrap());
+        if let Some(response) = self.cache.get(&key) {
+            self.cache.hits.set(self.cache.hits.get() + 1);
+            return Ok(response);
+        }
+        self.cache.misses.set(self.cache.misses.get() + 1);
+        let response = send(&self.cache.client, ctx, request).await?;
+        if succeeded(&response) {
+            info!("Caching the answer of {} for {:?}", key.0, ttl);
+            self.cache.put(key, &response, ttl, self.policy.capacity);
+        }
+        Ok(response)
+    }
+}
+
+/// Whether the server answered a [`cacheable`] method without an error, which would be
+/// worth asking again.
+fn succeeded(response: &WorldResponse) -> bool {
+    matches!(
+        response,
+        WorldResponse::Ping(Ok(_)) | WorldResponse::Echo(Ok(_)) | WorldResponse::PeerId(Ok(_))
+    )
+}
+
+#[async_trait(?Send)]
+impl WorldApi for CachingWorldClient {
+    async fn ping(&self, ctx: Context) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Ping {}).await? {
+            WorldResponse::Ping(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn echo(
+        &self,
+        ctx: Context,
+        value: String,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Echo { value }).await? {
+            WorldResponse::Echo(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn delay(
+        &self,
+        ctx: Context,
+        millis: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Delay { millis }).await? {
+            WorldResponse::Delay(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn peer_id(&self, ctx: Context) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::PeerId {}).await? {
+            WorldResponse::PeerId(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn signal(
+        &self,
+        ctx: Context,
+        to: u64,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Signal { to, payload }).await? {
+            WorldResponse::Signal(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn signals(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<(u64, String)>, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Signals {}).await? {
+            WorldResponse::Signals(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn count(
+        &self,
+        ctx: Context,
+        stream: u64,
+        n: u64,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Count { stream, n }).await? {
+            WorldResponse::Count(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Subscribe { topic }).await? {
+            WorldResponse::Subscribe(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn unsubscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Unsubscribe { topic }).await? {
+            WorldResponse::Unsubscribe(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn publish(
+        &self,
+        ctx: Context,
+        topic: String,
+        payload: String,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        match self
+            .call(ctx, WorldRequest::Publish { topic, payload })
+            .await?
+        {
+            WorldResponse::Publish(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn delay_with_progress(
+        &self,
+        ctx: Context,
+        millis: u64,
+        progress: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self
+            .call(ctx, WorldRequest::DelayWithProgress { millis, progress })
+            .await?
+        {
+            WorldResponse::DelayWithProgress(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn whoami(&self, ctx: Context) -> Result<Result<SessionInfo, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Whoami {}).await? {
+            WorldResponse::Whoami(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn list_clients(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<ClientInfo>, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::ListClients {}).await? {
+            WorldResponse::ListClients(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_begin(
+        &self,
+        ctx: Context,
+        name: String,
+        size: u64,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadBegin { name, size }).await? {
+            WorldResponse::UploadBegin(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_chunk(
+        &self,
+        ctx: Context,
+        id: u64,
+        offset: u64,
+        bytes: Bytes,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadChunk { id, offset, bytes }).await? {
+            WorldResponse::UploadChunk(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_finish(
+        &self,
+        ctx: Context,
+        id: u64,
+        checksum: u32,
+    ) -> Result<Result<UploadResult, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadFinish { id, checksum }).await? {
+            WorldResponse::UploadFinish(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn list_items(
+        &self,
+        ctx: Context,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Result<Page, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::ListItems { cursor, limit }).await? {
+            WorldResponse::ListItems(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn increment(&self, ctx: Context, by: i64) -> Result<Result<i64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Increment { by }).await? {
+            WorldResponse::Increment(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn get_count(&self, ctx: Context) -> Result<Result<i64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::GetCount {}).await? {
+            WorldResponse::GetCount(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn version(&self, ctx: Context) -> Result<Result<VersionInfo, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Version {}).await? {
+            WorldResponse::Version(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn echo_bytes(
+        &self,
+        ctx: Context,
+        data: Bytes,
+    ) -> Result<Result<Bytes, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::EchoBytes { data }).await? {
+            WorldResponse::EchoBytes(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn create_task(
+        &self,
+        ctx: Context,
+        request: CreateTaskRequest,
+    ) -> Result<Result<Task, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::CreateTask { request }).await? {
+            WorldResponse::CreateTask(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn log_event(
+        &self,
+        ctx: Context,
+        name: String,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::LogEvent { name, payload }).await? {
+            WorldResponse::LogEvent(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+}
+
+/// tarpc answers a request with the response of its own method.
+fn mismatched(response: WorldResponse) -> ! {
+    unreachable!("got {:?} for another method", response)
+}
@@ -0,0 +1,51 @@
+//! Abandoning a call that is still waiting for its response.
+//!
+//! tarpc forgets a request as soon as the future of its call is dropped, and sends the server
+//! a cancellation so it can stop working on it. [`cancellable`] lets code other than the one
+//! awaiting the call drop it, e.g. a Cancel button while the call runs in a spawned task.
+
+use std::fmt;
+use std::future::Future;
+
+use futures::future::{AbortHandle, Abortable};
+use futures::FutureExt;
+
+/// The call was cancelled through its [`CancelHandle`] before it completed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the call was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Cancels the call returned along with it by [`cancellable`].
+#[derive(Clone, Debug)]
+pub struct CancelHandle(AbortHandle);
+
+impl CancelHandle {
+    /// Drops the call the next time it is polled, so it resolves to [`Cancelled`]. Does nothing
+    /// once the call completed.
+    pub fn cancel(&self) {
+        self.0.abort();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.is_aborted()
+    }
+}
+
+/// `call`, resolving to [`Cancelled`] instead of its answer once the handle cancels it.
+pub fn cancellable<F: Future>(
+    call: F,
+) -> (
+    impl Future<Output = Result<F::Output, Cancelled>>,
+    CancelHandle,
+) {
+    let (handle, registration) = AbortHandle::new_pair();
+    let call = Abortable::new(call, registration).map(|answer| answer.map_err(|_| Cancelled));
+    (call, CancelHandle(handle))
+}
@@ -0,0 +1,39 @@
+//! Client side enforcement of a request's deadline.
+//!
+//! tarpc sends the [`Context`] deadline along with the request, but nothing on the client
+//! waits for it: a server that stalls leaves the call pending forever. [`with_deadline`] races
+//! the call against a timer instead. Dropping the call when the timer wins is enough for
+//! tarpc to forget the request and tell the server to cancel it.
+
+use std::future::Future;
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::future::{self, Either};
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+
+use crate::transport::{now, sleep};
+
+/// Time left until `ctx`'s deadline, zero once it passed.
+///
+/// Measured against the browser's clock, `SystemTime::now` isn't available in wasm.
+pub fn remaining(ctx: &Context) -> Duration {
+    let deadline = ctx
+        .deadline
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |since_epoch| since_epoch.as_secs_f64() * 1000.0);
+    Duration::from_millis((deadline - now()).max(0.0) as u64)
+}
+
+/// `call`, failing with [`RpcError::DeadlineExceeded`] if `ctx`'s deadline passes first.
+/// `ctx` has to be the context `call` was made with.
+pub async fn with_deadline<T>(
+    ctx: &Context,
+    call: impl Future<Output = Result<T, RpcError>>,
+) -> Result<T, RpcError> {
+    let timer = sleep(remaining(ctx));
+    match future::select(Box::pin(call), Box::pin(timer)).await {
+        Either::Left((answer, _)) => answer,
+        Either::Right(((), _)) => Err(RpcError::DeadlineExceeded),
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt;
+
+use wasm_bindgen::{JsCast, JsValue};
+use ws_stream_wasm::WsErr;
+
+use crate::transport::{CloseInfo, HEARTBEAT_TIMEOUT};
+
+/// Everything that can go wrong between `build_client` and the socket.
+#[derive(Debug)]
+pub enum TransportError {
+    /// The server address can't be turned into a WebSocket URL.
+    InvalidUrl(String),
+    /// The server, or something in between, refused the WebSocket (or TLS) handshake. Browsers
+    /// don't tell a refused upgrade from an unreachable server, both fail with
+    /// [`ABNORMAL_CLOSURE`](crate::transport::ABNORMAL_CLOSURE) and no reason.
+    HandshakeFailed { code: u16, reason: String },
+    /// The connection was shut down cleanly by either side, with a close handshake.
+    ConnectionClosed { code: u16, reason: String },
+    /// The connection dropped without a close handshake, e.g. because the network failed or
+    /// the server went away. `code` is usually
+    /// [`ABNORMAL_CLOSURE`](crate::transport::ABNORMAL_CLOSURE).
+    AbnormalClosure { code: u16, reason: String },
+    /// A message couldn't be encoded or a received frame couldn't be decoded.
+    Serialization(Box<dyn std::error::Error + Send + Sync>),
+    /// The server rejected the token in
+    /// [`ConnectOptions::auth`](crate::transport::ConnectOptions::auth).
+    Unauthorized(String),
+    /// The server accepted the WebSocket without agreeing on the subprotocol, so it speaks a
+    /// different wire format, see [`rpc::protocol`].
+    ProtocolMismatch { requested: String, selected: String },
+    /// The server doesn't serve the protocol version of this build, see [`rpc::handshake`].
+    /// Usually the page is older than the server and reloading it fixes this.
+    ProtocolVersionMismatch { ours: u32, theirs: Vec<u32> },
+    /// The server's `World` lacks methods of this build's, see [`rpc::schema`]. `ours` and
+    /// `theirs` are the two [`rpc::schema::SCHEMA_HASH`]es, `missing` the methods in question.
+    SchemaMismatch {
+        ours: u64,
+        theirs: u64,
+        missing: Vec<&'static str>,
+    },
+    /// A message is larger than
+    /// [`ConnectOptions::max_message_size`](crate::transport::ConnectOptions::max_message_size).
+    MessageTooLarge { size: usize, limit: usize },
+    /// A browser API failed.
+    JsError(String),
+    /// The server didn't answer in time.
+    Timeout,
+    /// The connection stopped answering heartbeats, see
+    /// [`ConnectOptions::heartbeat_timeout`](crate::transport::ConnectOptions::heartbeat_timeout).
+    HeartbeatTimeout(String),
+}
+
+impl TransportError {
+    /// How the connection ended, for the errors that end it.
+    pub fn close_info(&self) -> Option<CloseInfo> {
+        match self {
+            TransportError::ConnectionClosed { code, reason } => {
+                Some(CloseInfo::new(*code, reason, true))
+            }
+            TransportError::AbnormalClosure { code, reason } => {
+                Some(CloseInfo::new(*code, reason, false))
+            }
+            TransportError::HeartbeatTimeout(reason) => {
+                Some(CloseInfo::new(HEARTBEAT_TIMEOUT, reason, false))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn serialization(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        TransportError::Serialization(e.into())
+    }
+}
+
+/// Best effort text for a value a browser API threw or rejected with.
+pub(crate) fn describe(value: &JsValue) -> String {
+    value
+        .dyn_ref::<js_sys::Error>()
+        .map(|e| String::from(e.message()))
+        .or_else(|| value.as_string())
+        .unwrap_or_else(|| format!("{:?}", value))
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::InvalidUrl(url) => write!(f, "invalid server URL: {}", url),
+            TransportError::HandshakeFailed { code, reason } if reason.is_empty() => write!(
+                f,
+                "server refused the WebSocket upgrade or is unreachable (code {})",
+                code
+            ),
+            TransportError::HandshakeFailed { code, reason } => {
+                write!(
+                    f,
+                    "server refused the WebSocket upgrade (code {}): {}",
+                    code, reason
+                )
+            }
+            TransportError::ConnectionClosed { code, reason } => {
+                write!(f, "{}", CloseInfo::new(*code, reason, true))
+            }
+            TransportError::AbnormalClosure { code, reason } => {
+                write!(f, "{}", CloseInfo::new(*code, reason, false))
+            }
+            TransportError::Serialization(e) => write!(f, "serialization failed: {}", e),
+            TransportError::Unauthorized(reason) => {
+                write!(f, "server rejected the token: {}", reason)
+            }
+            TransportError::ProtocolMismatch { requested, selected } if selected.is_empty() => {
+                write!(f, "server doesn't speak subprotocol {}", requested)
+            }
+            TransportError::ProtocolMismatch { requested, selected } => write!(
+                f,
+                "server selected subprotocol {} instead of {}",
+                selected, requested
+            ),
+            TransportError::ProtocolVersionMismatch { ours, theirs } => write!(
+                f,
+                "server speaks protocol versions {:?} but not our version {}",
+                theirs, ours
+            ),
+            TransportError::SchemaMismatch {
+                ours,
+                theirs,
+                missing,
+            } => write!(
+                f,
+                "server schema {:016x} differs from ours {:016x}, it lacks {}",
+                theirs,
+                ours,
+                missing.join(", ")
+            ),
+            TransportError::MessageTooLarge { size, limit } => write!(
+                f,
+                "message of {} bytes exceeds the limit of {} bytes",
+                size, limit
+            ),
+            TransportError::JsError(e) => write!(f, "browser error: {}", e),
+            TransportError::Timeout => write!(f, "timed out"),
+            TransportError::HeartbeatTimeout(reason) => {
+                write!(f, "connection is dead: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::Serialization(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<CloseInfo> for TransportError {
+    fn from(info: CloseInfo) -> Self {
+        if info.code == HEARTBEAT_TIMEOUT {
+            return TransportError::HeartbeatTimeout(info.reason);
+        }
+        if !info.was_clean {
+            return TransportError::AbnormalClosure {
+                code: info.code,
+                reason: info.reason,
+            };
+        }
+        TransportError::ConnectionClosed {
+            code: info.code,
+            reason: info.reason,
+        }
+    }
+}
+
+impl From<WsErr> for TransportError {
+    fn from(e: WsErr) -> Self {
+        match e {
+            WsErr::InvalidUrl { supplied } => TransportError::InvalidUrl(supplied),
+            WsErr::ConnectionFailed { event } => TransportError::HandshakeFailed {
+                code: event.code,
+                reason: event.reason,
+            },
+            e => TransportError::JsError(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,711 @@
+//! Code running around every `World` call, see [`InterceptedWorldClient`].
+//!
+//! Interceptors see each call before it is sent, with its context and request they may change,
+//! and its outcome afterwards, with how long it took. They are plain synchronous hooks: no
+//! `Send` bound, so they work in the browser as well, and the call itself isn't theirs to
+//! await. [`Log`] and [`InjectMetadata`] are examples to build others on.
+
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use log::info;
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, UploadResult, VersionInfo,
+    WorldClient, WorldError, WorldRequest, WorldResponse,
+};
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+
+use crate::limit::Limiter;
+use crate::telemetry::traced;
+use crate::transport::now;
+
+/// A call on its way through the interceptors.
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// Name of the `World` method, e.g. `"echo"`.
+    pub method: &'static str,
+    /// Context the call is made with, deadline included.
+    pub ctx: Context,
+    /// Key/value pairs interceptors attach for the ones after them.
+    ///
+    /// tarpc's context has no room for them, so they stay on the client and the server never
+    /// sees them.
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Call {
+    /// The value attached under `key` last.
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub trait Interceptor {
+    /// Runs before the call is sent, in the order interceptors were added. The request's
+    /// arguments may change, its method mustn't: the typed methods of
+    /// [`InterceptedWorldClient`] panic on the answer of another one.
+    fn before(&self, _call: &mut Call, _request: &mut WorldRequest) {}
+
+    /// Runs once the call completed or failed, in the reverse order.
+    fn after(&self, _call: &Call, _outcome: &Result<WorldResponse, RpcError>, _elapsed: Duration) {}
+}
+
+/// Logs every call and its outcome at info level.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Log;
+
+impl Interceptor for Log {
+    fn before(&self, call: &mut Call, request: &mut WorldRequest) {
+        info!(
+            "Calling {} with {:?} {:?}",
+            call.method, request, call.metadata
+        );
+    }
+
+    fn after(&self, call: &Call, outcome: &Result<WorldResponse, RpcError>, elapsed: Duration) {
+        match outcome {
+            Ok(response) => info!("{} answered {:?} in {:?}", call.method, response, elapsed),
+            Err(e) => info!("{} failed after {:?}: {}", call.method, elapsed, e),
+        }
+    }
+}
+
+/// Attaches the same metadata, e.g. a tenant, to every call.
+#[derive(Clone, Debug, Default)]
+pub struct InjectMetadata(Vec<(String, String)>);
+
+impl InjectMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.0.push((key.into(), value.into()));
+        self
+    }
+}
+
+impl Interceptor for InjectMetadata {
+    fn before(&self, call: &mut Call, _request: &mut WorldRequest) {
+        call.metadata.extend(self.0.iter().cloned());
+    }
+}
+
+/// A [`WorldClient`] running its calls through a chain of [`Interceptor`]s.
+#[derive(Clone)]
+pub struct InterceptedWorldClient {
+    client: WorldClient,
+    interceptors: Vec<Rc<dyn Interceptor>>,
+    limiter: Option<Limiter>,
+}
+
+impl fmt::Debug for InterceptedWorldClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterceptedWorldClient")
+            .field("client", &self.client)
+            .field("interceptors", &self.interceptors.len())
+            .field("limiter", &self.limiter)
+            .finish()
+    }
+}
+
+impl InterceptedWorldClient {
+    pub fn new(client: WorldClient) -> Self {
+        Self {
+            client,
+            interceptors: Vec::new(),
+            limiter: None,
+        }
+    }
+
+    /// Adds `interceptor` to the end of the chain.
+    pub fn with(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.interceptors.push(Rc::new(interceptor));
+        self
+    }
+
+    /// Lets at most `limit` calls run at once, the others wait for one to finish, see
+    /// [`Limiter`]. The time they wait is part of the elapsed time interceptors see.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limiter = Some(Limiter::new(limit));
+        self
+    }
+
+    /// The limiter of [`with_limit`](Self::with_limit), e.g. to watch the calls in flight.
+    pub fn limiter(&self) -> Option<&Limiter> {
+        self.limiter.as_ref()
+    }
+
+    /// The client calls are made on, bypassing the interceptors.
+    pub fn inner(&self) -> &WorldClient {
+        &self.client
+    }
+
+    /// Runs `request` through the interceptors and sends what comes out of them.
+    pub async fn call(
+        &self,
+        ctx: Context,
+        mut request: WorldRequest,
+    ) -> Result<WorldResponse, RpcError> {
+        let mut call = Call {
+            method: method(&request),
+            ctx,
+            metadata: Vec::new(),
+        };
+        for interceptor in &self.interceptors {
+            interceptor.before(&mut call, &mut request);
+        }
+        let started = now();
+        let permit = match &self.limiter {
+            Some(limiter) => limiter.acquire_by(&call.ctx).await.map(Some),
+            None => Ok(None),
+        };
+        let outcome = match permit {
+            Ok(_permit) => {
+                let sent = send(&self.client, call.ctx, request);
+                traced(call.method, &call.ctx, sent).await
+            }
+            Err(e) => Err(e),
+        };
+        let elapsed = Duration::from_secs_f64((now() - started).max(0.0) / 1000.0);
+        for interceptor in self.interceptors.iter().rev() {
+            interceptor.after(&call, &outcome, elapsed);
+        }
+        outcome
+    }
+
+    pub async fn ping(&self, ctx: Context) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Ping {}).await? {
+            WorldResponse::Ping(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn echo(
+        &self,
+        ctx: Context,
+        value: String,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Echo { value }).await? {
+            WorldResponse::Echo(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn delay(
+        &self,
+        ctx: Context,
+        millis: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Delay { millis }).await? {
+            WorldResponse::Delay(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn peer_id(&self, ctx: Context) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::PeerId {}).await? {
+            WorldResponse::PeerId(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn signal(
+        &self,
+        ctx: Context,
+        to: u64,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Signal { to, payload }).await? {
+            WorldResponse::Signal(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn signals(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<(u64, String)>, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Signals {}).await? {
+            WorldResponse::Signals(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn count(
+        &self,
+        ctx: Context,
+        stream: u64,
+        n: u64,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Count { stream, n }).await? {
+            WorldResponse::Count(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn subscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Subscribe { topic }).await? {
+            WorldResponse::Subscribe(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn unsubscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Unsubscribe { topic }).await? {
+            WorldResponse::Unsubscribe(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn publish(
+        &self,
+        ctx: Context,
+        topic: String,
+        payload: String,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        match self
+            .call(ctx, WorldRequest::Publish { topic, payload })
+            .await?
+        {
+            WorldResponse::Publish(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn delay_with_progress(
+        &self,
+        ctx: Context,
+        millis: u64,
+        progress: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self
+            .call(ctx, WorldRequest::DelayWithProgress { millis, progress })
+            .await?
+        {
+            WorldResponse::DelayWithProgress(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn whoami(&self, ctx: Context) -> Result<Result<SessionInfo, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Whoami {}).await? {
+            WorldResponse::Whoami(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn list_clients(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<ClientInfo>, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::ListClients {}).await? {
+            WorldResponse::ListClients(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn upload_begin(
+        &self,
+        ctx: Context,
+        name: String,
+        size: u64,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadBegin { name, size }).await? {
+            WorldResponse::UploadBegin(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn upload_chunk(
+        &self,
+        ctx: Context,
+        id: u64,
+        offset: u64,
+        bytes: Bytes,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadChunk { id, offset, bytes }).await? {
+            WorldResponse::UploadChunk(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn upload_finish(
+        &self,
+        ctx: Context,
+        id: u64,
+        checksum: u32,
+    ) -> Result<Result<UploadResult, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadFinish { id, checksum }).await? {
+            WorldResponse::UploadFinish(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn list_items(
+        &self,
+        ctx: Context,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Result<Page, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::ListItems { cursor, limit }).await? {
+            WorldResponse::ListItems(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn increment(
+        &self,
+        ctx: Context,
+        by: i64,
+    ) -> Result<Result<i64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Increment { by }).await? {
+            WorldResponse::Increment(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn get_count(&self, ctx: Context) -> Result<Result<i64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::GetCount {}).await? {
+            WorldResponse::GetCount(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn version(&self, ctx: Context) -> Result<Result<VersionInfo, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Version {}).await? {
+            WorldResponse::Version(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn echo_bytes(
+        &self,
+        ctx: Context,
+        data: Bytes,
+    ) -> Result<Result<Bytes, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::EchoBytes { data }).await? {
+            WorldResponse::EchoBytes(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn create_task(
+        &self,
+        ctx: Context,
+        request: CreateTaskRequest,
+    ) -> Result<Result<Task, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::CreateTask { request }).await? {
+            WorldResponse::CreateTask(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+
+    pub async fn log_event(
+        &self,
+        ctx: Context,
+        name: String,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::LogEvent { name, payload }).await? {
+            WorldResponse::LogEvent(answer) => Ok(answer),
+            response => changed(response),
+        }
+    }
+}
+
+/// Makes the call `request` stands for on `client`.
+pub(crate) async fn send(
+    client: &WorldClient,
+    ctx: Context,
+    request: WorldRequest,
+) -> Result<WorldResponse, RpcError> {
+    Ok(match request {
+        WorldRequest::Ping {} => WorldResponse::Ping(client.ping(ctx).await?),
+        WorldRequest::Echo { value } => WorldResponse::Echo(client.echo(ctx, value).await?),
+        WorldRequest::Delay { millis } => WorldResponse::Delay(client.delay(ctx, millis).await?),
+        WorldRequest::PeerId {} => WorldResponse::PeerId(client.peer_id(ctx).await?),
+        WorldRequest::Signal { to, payload } => {
+            WorldResponse::Signal(client.signal(ctx, to, payload).await?)
+        }
+        WorldRequest::Signals {} => WorldResponse::Signals(client.signals(ctx).await?),
+        WorldRequest::Count { stream, n } => {
+            WorldResponse::Count(client.count(ctx, stream, n).await?)
+        }
+        WorldRequest::Subscribe { topic } => {
+            WorldResponse::Subscribe(client.subscribe(ctx, topic).await?)
+        }
+        WorldRequest::Unsubscribe { topic } => {
+            WorldResponse::Unsubscribe(client.unsubscribe(ctx, topic).await?)
+        }
+        WorldRequest::Publish { topic, payload } => {
+            WorldResponse::Publish(client.publish(ctx, topic, payload).await?)
+        }
+        WorldRequest::DelayWithProgress { millis, progress } => WorldResponse::DelayWithProgress(
+            client.delay_with_progress(ctx, millis, progress).await?,
+        ),
+        WorldRequest::Whoami {} => WorldResponse::Whoami(client.whoami(ctx).await?),
+        WorldRequest::ListClients {} => WorldResponse::ListClients(client.list_clients(ctx).await?),
+        WorldRequest::UploadBegin { name, size } => {
+            WorldResponse::UploadBegin(client.upload_begin(ctx, name, size).await?)
+        }
+        WorldRequest::UploadChunk { id, offset, bytes } => {
+            WorldResponse::UploadChunk(client.upload_chunk(ctx, id, offset, bytes).await?)
+        }
+        WorldRequest::UploadFinish { id, checksum } => {
+            WorldResponse::UploadFinish(client.upload_finish(ctx, id, checksum).await?)
+        }
+        WorldRequest::ListItems { cursor, limit } => {
+            WorldResponse::ListItems(client.list_items(ctx, cursor, limit).await?)
+        }
+        WorldRequest::Increment { by } => {
+            WorldResponse::Increment(client.increment(ctx, by).await?)
+        }
+        WorldRequest::GetCount {} => WorldResponse::GetCount(client.get_count(ctx).await?),
+        WorldRequest::Version {} => WorldResponse::Version(client.version(ctx).await?),
+        WorldRequest::EchoBytes { data } => {
+            WorldResponse::EchoBytes(client.echo_bytes(ctx, data).await?)
+        }
+        WorldRequest::CreateTask { request } => {
+            WorldResponse::CreateTask(client.create_task(ctx, request).await?)
+        }
+        WorldRequest::LogEvent { name, payload } => {
+            WorldResponse::LogEvent(client.log_event(ctx, name, payload).await?)
+        }
+    })
+}
+
+pub(crate) fn method(request: &WorldRequest) -> &'static str {
+    match request {
+        WorldRequest::Ping { .. } => "ping",
+        WorldRequest::Echo { .. } => "echo",
+        WorldRequest::Delay { .. } => "delay",
+        WorldRequest::PeerId { .. } => "peer_id",
+        WorldRequest::Signal { .. } => "signal",
+        WorldRequest::Signals { .. } => "signals",
+        WorldRequest::Count { .. } => "count",
+        WorldRequest::Subscribe { .. } => "subscribe",
+        WorldRequest::Unsubscribe { .. } => "unsubscribe",
+        WorldRequest::Publish { .. } => "publish",
+        WorldRequest::DelayWithProgress { .. } => "delay_with_progress",
+        WorldRequest::Whoami { .. } => "whoami",
+        WorldRequest::ListClients { .. } => "list_clients",
+        WorldRequest::UploadBegin { .. } => "upload_begin",
+        WorldRequest::UploadChunk { .. } => "upload_chunk",
+        WorldRequest::UploadFinish { .. } => "upload_finish",
+        WorldRequest::ListItems { .. } => "list_items",
+        WorldRequest::Increment { .. } => "increment",
+        WorldRequest::GetCount { .. } => "get_count",
+        WorldRequest::Version { .. } => "version",
+        WorldRequest::EchoBytes { .. } => "echo_bytes",
+        WorldRequest::CreateTask { .. } => "create_task",
+        WorldRequest::LogEvent { .. } => "log_event",
+    }
+}
+
+/// An interceptor turned the request into one for another method.
+fn changed(response: WorldResponse) -> ! {
+    panic!(
+        "an interceptor changed the method called, got {:?}",
+        response
+    )
+}
+
+#[cfg(all(test, feature = "native", not(target_arch = "wasm32")))]
+mod tests {
+    use std::cell::RefCell;
+
+    use rpc::testing::loopback;
+    use tarpc::context;
+
+    use super::*;
+    use crate::testing::{answer, connect, run, world, ClientEnd};
+
+    /// What the [`Record`]s of a test saw, in order.
+    type Seen = Rc<RefCell<Vec<String>>>;
+
+    /// Notes the calls and outcomes it sees, and the tenant attached before it.
+    struct Record {
+        name: &'static str,
+        seen: Seen,
+    }
+
+    impl Interceptor for Record {
+        fn before(&self, call: &mut Call, request: &mut WorldRequest) {
+            let tenant = call.metadata("tenant").unwrap_or("-");
+            let before = format!(
+                "{} before {} {:?} {}",
+                self.name, call.method, request, tenant
+            );
+            self.seen.borrow_mut().push(before);
+        }
+
+        fn after(&self, call: &Call, outcome: &Result<WorldResponse, RpcError>, _: Duration) {
+            let after = format!("{} after {} {:?}", self.name, call.method, outcome);
+            self.seen.borrow_mut().push(after);
+        }
+    }
+
+    fn record(name: &'static str, seen: &Seen) -> Record {
+        Record {
+            name,
+            seen: seen.clone(),
+        }
+    }
+
+    /// Shouts the values of `echo` calls.
+    struct Shout;
+
+    impl Interceptor for Shout {
+        fn before(&self, _: &mut Call, request: &mut WorldRequest) {
+            if let WorldRequest::Echo { value } = request {
+                *value = value.to_uppercase();
+            }
+        }
+    }
+
+    /// Notes how long calls took.
+    struct Time(Rc<RefCell<Vec<Duration>>>);
+
+    impl Interceptor for Time {
+        fn after(&self, _: &Call, _: &Result<WorldResponse, RpcError>, elapsed: Duration) {
+            self.0.borrow_mut().push(elapsed);
+        }
+    }
+
+    #[test]
+    fn interceptors_run_before_in_order_and_after_in_reverse() {
+        run(async {
+            let seen = Seen::default();
+            let client = InterceptedWorldClient::new(world(answer))
+                .with(record("a", &seen))
+                .with(Log)
+                .with(record("b", &seen));
+            assert_eq!(
+                client.ping(context::current()).await.unwrap(),
+                Ok("pong".into())
+            );
+            assert_eq!(
+                *seen.borrow(),
+                [
+                    "a before ping Ping -",
+                    "b before ping Ping -",
+                    "b after ping Ok(Ping(Ok(\"pong\")))",
+                    "a after ping Ok(Ping(Ok(\"pong\")))",
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn metadata_reaches_the_interceptors_after_it() {
+        run(async {
+            let seen = Seen::default();
+            let client = InterceptedWorldClient::new(world(answer))
+                .with(record("a", &seen))
+                .with(InjectMetadata::new().with("tenant", "acme"))
+                .with(record("b", &seen));
+            client.ping(context::current()).await.unwrap().unwrap();
+            assert!(seen.borrow()[0].ends_with(" -"), "{:?}", seen);
+            assert!(seen.borrow()[1].ends_with(" acme"), "{:?}", seen);
+        });
+    }
+
+    #[test]
+    fn interceptors_change_the_arguments_sent() {
+        run(async {
+            let seen = Seen::default();
+            let client = InterceptedWorldClient::new(world(answer))
+                .with(Shout)
+                .with(record("a", &seen));
+            let echoed = client.echo(context::current(), "hi".into()).await.unwrap();
+            assert_eq!(echoed, Ok("HI".into()));
+            assert!(seen.borrow()[0].contains("\"HI\""), "{:?}", seen);
+            // The client underneath is left alone.
+            let echoed = client.inner().echo(context::current(), "hi".into()).await;
+            assert_eq!(echoed.unwrap(), Ok("hi".into()));
+        });
+    }
+
+    #[test]
+    fn failures_and_their_time_are_seen_after() {
+        run(async {
+            let seen = Seen::default();
+            let times = Rc::new(RefCell::new(Vec::new()));
+            let client = InterceptedWorldClient::new(world(answer))
+                .with(record("a", &seen))
+                .with(Time(times.clone()));
+            let waited = client.delay(context::current(), 20).await.unwrap();
+            assert_eq!(waited, Ok("waited 20ms".into()));
+            assert!(
+                times.borrow()[0] >= Duration::from_millis(20),
+                "{:?}",
+                times
+            );
+
+            let (gone, server): (ClientEnd, _) = loopback();
+            drop(server);
+            let client = InterceptedWorldClient::new(connect(gone)).with(record("b", &seen));
+            let e = client.ping(context::current()).await.unwrap_err();
+            assert!(matches!(e, RpcError::Disconnected), "{:?}", e);
+            assert_eq!(
+                seen.borrow().last().unwrap(),
+                "b after ping Err(Disconnected)"
+            );
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "an interceptor changed the method called")]
+    fn changing_the_method_panics() {
+        struct Swap;
+
+        impl Interceptor for Swap {
+            fn before(&self, _: &mut Call, request: &mut WorldRequest) {
+                *request = WorldRequest::Ping {};
+            }
+        }
+
+        run(async {
+            let client = InterceptedWorldClient::new(world(answer)).with(Swap);
+            let _ = client.echo(context::current(), "hi".into()).await;
+        });
+    }
+
+    #[test]
+    fn methods_are_named_as_in_the_schema() {
+        let requests = [
+            WorldRequest::Ping {},
+            WorldRequest::Echo { value: "".into() },
+            WorldRequest::GetCount {},
+            WorldRequest::DelayWithProgress {
+                millis: 1,
+                progress: 1,
+            },
+        ];
+        for request in &requests {
+            let name = method(request);
+            assert!(
+                rpc::schema::METHODS
+                    .iter()
+                    .any(|method| method.name == name),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! The client for JavaScript that doesn't use yew, see [`JsWorldClient`].
+//!
+//! `wasm-pack build client --target web -- --features js` builds a package exporting it.
+//! Every method returns a `Promise`, which rejects with an `Error` whose `name` says which
+//! layer failed and whose `kind` which way, so scripts can tell failures apart without
+//! parsing messages:
+//!
+//! - `TransportError`: the connection couldn't be made or ended, `kind` is e.g.
+//!   `"handshake_failed"` or `"connection_closed"`, with `code` and `reason` where the socket
+//!   gave them.
+//! - `RpcError`: tarpc gave up on the call while the connection is still open, `kind` is
+//!   `"deadline_exceeded"`, `"disconnected"` or `"rpc"`.
+//! - `WorldError`: the server answered with a [`WorldError`], `kind` is `"invalid_argument"`,
+//!   `"timeout"`, `"unavailable"`, `"internal"`, `"rate_limited"`, with `retryAfter` in
+//!   milliseconds, `"busy"`, `"upload"`, `"invalid_cursor"`, `"payload_too_large"`, with
+//!   `max` in bytes, or `"permission_denied"`.
+
+use std::time::Duration;
+
+use js_sys::{Error, Promise, Reflect};
+use rpc::WorldError;
+use tarpc::client::RpcError;
+use tarpc::context;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
+
+use crate::error::TransportError;
+use crate::rpc_client::{ClientBuilder, WorldHandle};
+
+/// A connection to the server and the `World` client on it, for JavaScript.
+///
+/// ```js
+/// import init, { JsWorldClient } from "./pkg/client.js";
+///
+/// await init();
+/// const world = await JsWorldClient.connect("ws://127.0.0.1:8083");
+/// console.log(await world.echo("Hello"));
+/// ```
+#[wasm_bindgen]
+pub struct JsWorldClient {
+    world: WorldHandle,
+}
+
+#[wasm_bindgen]
+impl JsWorldClient {
+    /// Connects to `url`, resolved as [`resolve_url`](crate::rpc_client::resolve_url) does,
+    /// with the default options.
+    pub async fn connect(url: String) -> Result<JsWorldClient, JsValue> {
+        match ClientBuilder::new(url).connect().await {
+            Ok(world) => Ok(JsWorldClient { world }),
+            Err(e) => Err(transport_error(&e)),
+        }
+    }
+
+    /// Resolves with `"Pong"`.
+    pub fn ping(&self) -> Promise {
+        let world = self.world.clone();
+        future_to_promise(async move {
+            let answer = world.client().ping(context::current()).await;
+            answered(&world, answer).map(JsValue::from)
+        })
+    }
+
+    /// Resolves with `value`.
+    pub fn echo(&self, value: String) -> Promise {
+        let world = self.world.clone();
+        future_to_promise(async move {
+            let answer = world.client().echo(context::current(), value).await;
+            answered(&world, answer).map(JsValue::from)
+        })
+    }
+
+    /// Resolves after `seconds`, which may have a fraction down to milliseconds, or rejects
+    /// with a `WorldError` of kind `"timeout"` if that is past the call's deadline. Negative
+    /// and non-finite seconds reject with kind `"invalid_argument"` without a call.
+    pub fn delay(&self, seconds: f64) -> Promise {
+        let world = self.world.clone();
+        future_to_promise(async move {
+            let millis = match Duration::try_from_secs_f64(seconds) {
+                Ok(delay) => u64::try_from(delay.as_millis()).unwrap_or(u64::MAX),
+                Err(_) => {
+                    let reason = format!("{} isn't a number of seconds to wait", seconds);
+                    return Err(world_error(&WorldError::InvalidArgument(reason)));
+                }
+            };
+            let answer = world.client().delay(context::current(), millis).await;
+            answered(&world, answer).map(JsValue::from)
+        })
+    }
+
+    /// Closes the connection, calls still waiting reject. Dropping the object with `free()`
+    /// closes it too.
+    pub fn disconnect(&self) -> Promise {
+        let world = self.world.clone();
+        future_to_promise(async move {
+            world.transport().disconnect().await;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+}
+
+/// What `answer` resolves or rejects the promise of a call with.
+fn answered<T>(
+    world: &WorldHandle,
+    answer: Result<Result<T, WorldError>, RpcError>,
+) -> Result<T, JsValue> {
+    match answer {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(world_error(&e)),
+        // tarpc only tells the connection is gone, the transport knows why.
+        Err(e) => match world.transport().closed_error() {
+            Some(closed) => Err(transport_error(&closed)),
+            None => Err(rpc_error(&e)),
+        },
+    }
+}
+
+/// An `Error` named `name` with `message` and the properties in `fields`.
+fn thrown(name: &str, kind: &str, message: &str, fields: &[(&str, JsValue)]) -> JsValue {
+    let error = Error::new(message);
+    error.set_name(name);
+    // Setting a property on a plain `Error` can't fail.
+    let _ = Reflect::set(&error, &"kind".into(), &kind.into());
+    for (field, value) in fields {
+        let _ = Reflect::set(&error, &(*field).into(), value);
+    }
+    error.into()
+}
+
+fn transport_error(e: &TransportError) -> JsValue {
+    let message = e.to_string();
+    let (kind, fields) = match e {
+        TransportError::InvalidUrl(_) => ("invalid_url", Vec::new()),
+        TransportError::HandshakeFailed { code, reason } => {
+            ("handshake_failed", close_fields(*code, reason))
+        }
+        TransportError::ConnectionClosed { code, reason } => {
+            ("connection_closed", close_fields(*code, reason))
+        }
+        TransportError::AbnormalClosure { code, reason } => {
+            ("abnormal_closure", close_fields(*code, reason))
+        }
+        TransportError::Serialization(_) => ("serialization", Vec::new()),
+        TransportError::Unauthorized(_) => ("unauthorized", Vec::new()),
+        TransportError::ProtocolMismatch { .. } => ("protocol_mismatch", Vec::new()),
+        TransportError::ProtocolVersionMismatch { .. } => ("protocol_version_mismatch", Vec::new()),
+        TransportError::SchemaMismatch { .. } => ("schema_mismatch", Vec::new()),
+        TransportError::MessageTooLarge { size, limit } => (
+            "message_too_large",
+            vec![
+                ("size", (*size as f64).into()),
+                ("limit", (*limit as f64).into()),
+            ],
+        ),
+        TransportError::JsError(_) => ("js_error", Vec::new()),
+        TransportError::Timeout => ("timeout", Vec::new()),
+        TransportError::HeartbeatTimeout(_) => ("heartbeat_timeout", Vec::new()),
+    };
+    thrown("TransportError", kind, &message, &fields)
+}
+
+fn close_fields(code: u16, reason: &str) -> Vec<(&'static str, JsValue)> {
+    vec![("code", code.into()), ("reason", reason.into())]
+}
+
+fn rpc_error(e: &RpcError) -> JsValue {
+    let kind = match e {
+        RpcError::DeadlineExceeded => "deadline_exceeded",
+        RpcError::Disconnected => "disconnected",
+        _ => "rpc",
+    };
+    thrown("RpcError", kind, &e.to_string(), &[])
+}
+
+fn world_error(e: &WorldError) -> JsValue {
+    let (kind, fields) = match e {
+        WorldError::InvalidArgument(_) => ("invalid_argument", Vec::new()),
+        WorldError::Timeout => ("timeout", Vec::new()),
+        WorldError::Unavailable(_) => ("unavailable", Vec::new()),
+        WorldError::Internal(_) => ("internal", Vec::new()),
+        WorldError::RateLimited(retry_after) => (
+            "rate_limited",
+            vec![("retryAfter", (*retry_after as f64).into())],
+        ),
+        WorldError::Busy => ("busy", Vec::new()),
+        WorldError::Upload(_) => ("upload", Vec::new()),
+        WorldError::InvalidCursor => ("invalid_cursor", Vec::new()),
+        WorldError::PayloadTooLarge(max) => {
+            ("payload_too_large", vec![("max", (*max as f64).into())])
+        }
+        WorldError::PermissionDenied(_) => ("permission_denied", Vec::new()),
+    };
+    thrown("WorldError", kind, &e.to_string(), &fields)
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    /// The property `name` of the error `thrown`, as a string if it is one.
+    fn string(thrown: &JsValue, name: &str) -> Option<String> {
+        Reflect::get(thrown, &name.into()).unwrap().as_string()
+    }
+
+    fn number(thrown: &JsValue, name: &str) -> Option<f64> {
+        Reflect::get(thrown, &name.into()).unwrap().as_f64()
+    }
+
+    #[wasm_bindgen_test]
+    fn transport_errors_carry_how_the_socket_closed() {
+        let closed = TransportError::ConnectionClosed {
+            code: 4000,
+            reason: "bye".into(),
+        };
+        let thrown = transport_error(&closed);
+        assert!(thrown.is_instance_of::<Error>());
+        assert_eq!(string(&thrown, "name").as_deref(), Some("TransportError"));
+        assert_eq!(string(&thrown, "kind").as_deref(), Some("connection_closed"));
+        assert_eq!(string(&thrown, "message"), Some(closed.to_string()));
+        assert_eq!(number(&thrown, "code"), Some(4000.0));
+        assert_eq!(string(&thrown, "reason").as_deref(), Some("bye"));
+
+        let thrown = transport_error(&TransportError::Timeout);
+        assert_eq!(string(&thrown, "kind").as_deref(), Some("timeout"));
+        assert_eq!(string(&thrown, "code"), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn rpc_errors_say_which_way_tarpc_gave_up() {
+        let thrown = rpc_error(&RpcError::DeadlineExceeded);
+        assert_eq!(string(&thrown, "name").as_deref(), Some("RpcError"));
+        assert_eq!(string(&thrown, "kind").as_deref(), Some("deadline_exceeded"));
+    }
+
+    #[wasm_bindgen_test]
+    fn world_errors_carry_what_scripts_act_on() {
+        let thrown = world_error(&WorldError::RateLimited(1500));
+        assert_eq!(string(&thrown, "name").as_deref(), Some("WorldError"));
+        assert_eq!(string(&thrown, "kind").as_deref(), Some("rate_limited"));
+        assert_eq!(number(&thrown, "retryAfter"), Some(1500.0));
+
+        let thrown = world_error(&WorldError::PayloadTooLarge(4096));
+        assert_eq!(string(&thrown, "kind").as_deref(), Some("payload_too_large"));
+        assert_eq!(number(&thrown, "max"), Some(4096.0));
+
+        let denied = world_error(&WorldError::PermissionDenied("admins only".into()));
+        assert_eq!(string(&denied, "kind").as_deref(), Some("permission_denied"));
+    }
+}
@@ -0,0 +1,45 @@
+//! Clients for the `World` service: transports to the server and between browsers, behind
+//! [`rpc_client::build_client`]. The demo page in `main.rs` is built on top of them.
+
+pub mod api;
+#[cfg(feature = "json")]
+pub mod cache;
+pub mod cancel;
+pub mod deadline;
+pub mod error;
+pub mod intercept;
+#[cfg(feature = "js")]
+pub mod js;
+pub mod limit;
+pub mod metrics;
+#[cfg(feature = "json")]
+pub mod mux;
+#[cfg(feature = "test-util")]
+pub mod mock;
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+pub mod native;
+pub mod offline;
+pub mod pages;
+pub mod pool;
+#[cfg(feature = "json")]
+pub mod progress;
+#[cfg(feature = "json")]
+pub mod push;
+pub mod replay;
+pub mod retry;
+pub mod rpc_client;
+pub mod rtt;
+mod socket;
+#[cfg(feature = "json")]
+pub mod sse;
+pub mod telemetry;
+#[cfg(all(test, feature = "native", not(target_arch = "wasm32")))]
+mod testing;
+pub mod transport;
+pub mod upload;
+#[cfg(feature = "json")]
+pub mod webrtc;
+#[cfg(feature = "webtransport")]
+pub mod webtransport;
+#[cfg(feature = "json")]
+pub mod worker;
@@ -0,0 +1,246 @@
+//! A cap on the calls in flight at once, see [`Limiter`].
+//!
+//! tarpc queues every call it is handed, so a loop firing thousands of them keeps all of them
+//! in memory until the transport catches up. A limiter hands out a [`Permit`] per call instead
+//! and makes the rest wait, first come first served. Waiting counts against the call's
+//! deadline like the call itself.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::channel::oneshot;
+use futures::FutureExt;
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+
+use crate::deadline::with_deadline;
+
+struct State {
+    limit: usize,
+    in_flight: usize,
+    /// In arrival order. Waiters that gave up are skipped once a permit is passed on.
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// At most a fixed number of permits out at once, shared by its clones. Not `Send`, it is
+/// meant for the thread making the calls.
+#[derive(Clone)]
+pub struct Limiter {
+    state: Rc<RefCell<State>>,
+}
+
+impl fmt::Debug for Limiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.borrow();
+        f.debug_struct("Limiter")
+            .field("limit", &state.limit)
+            .field("in_flight", &state.in_flight)
+            .field("waiting", &state.waiting.len())
+            .finish()
+    }
+}
+
+impl Limiter {
+    /// A limiter letting `limit` calls run at once, at least one.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                limit: limit.max(1),
+                in_flight: 0,
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.borrow().limit
+    }
+
+    /// Permits currently out.
+    pub fn in_flight(&self) -> usize {
+        self.state.borrow().in_flight
+    }
+
+    /// Calls waiting for a permit, including ones that gave up since the last permit came back.
+    pub fn waiting(&self) -> usize {
+        self.state.borrow().waiting.len()
+    }
+
+    /// Waits for a permit, behind everyone who asked before.
+    pub async fn acquire(&self) -> Permit {
+        let waiting = {
+            let mut state = self.state.borrow_mut();
+            if state.in_flight < state.limit && state.waiting.is_empty() {
+                state.in_flight += 1;
+                None
+            } else {
+                let (permit, waiting) = oneshot::channel();
+                state.waiting.push_back(permit);
+                Some(waiting)
+            }
+        };
+        match waiting {
+            None => Permit {
+                state: self.state.clone(),
+            },
+            // The limiter's state, which holds the sender, outlives this future.
+            Some(waiting) => waiting.await.expect("the limiter dropped a waiting call"),
+        }
+    }
+
+    /// Waits for a permit until `ctx`'s deadline, failing with
+    /// [`RpcError::DeadlineExceeded`] if it passes first.
+    pub async fn acquire_by(&self, ctx: &Context) -> Result<Permit, RpcError> {
+        with_deadline(ctx, self.acquire().map(Ok)).await
+    }
+}
+
+/// A call's place among the ones in flight, given back to the [`Limiter`] when dropped.
+pub struct Permit {
+    state: Rc<RefCell<State>>,
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Permit").finish_non_exhaustive()
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let next = self.state.borrow_mut().waiting.pop_front();
+        match next {
+            // A waiter that gave up hands the permit back, dropping it passes it on again.
+            Some(next) => drop(next.send(Permit {
+                state: self.state.clone(),
+            })),
+            None => self.state.borrow_mut().in_flight -= 1,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "native", not(target_arch = "wasm32")))]
+mod tests {
+    use std::cell::Cell;
+    use std::time::{Duration, SystemTime};
+
+    use futures::future::join_all;
+    use rpc::{WorldRequest, WorldResponse};
+    use tarpc::context;
+
+    use super::*;
+    use crate::intercept::InterceptedWorldClient;
+    use crate::testing::{run, world};
+
+    /// What the server of [`busy_world`] saw.
+    #[derive(Default)]
+    struct Load {
+        running: Cell<usize>,
+        most: Cell<usize>,
+        /// The values echoed, in the order the calls arrived.
+        arrived: RefCell<Vec<String>>,
+    }
+
+    /// A client of a server taking 10ms for every echo, limited to `limit` calls at once.
+    fn busy_world(limit: usize) -> (InterceptedWorldClient, Rc<Load>) {
+        let load = Rc::new(Load::default());
+        let seen = load.clone();
+        let client = world(move |request| {
+            let load = seen.clone();
+            async move {
+                let value = match request {
+                    WorldRequest::Echo { value } => value,
+                    request => panic!("only echo is answered, not {:?}", request),
+                };
+                load.arrived.borrow_mut().push(value.clone());
+                load.running.set(load.running.get() + 1);
+                load.most.set(load.most.get().max(load.running.get()));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                load.running.set(load.running.get() - 1);
+                WorldResponse::Echo(Ok(value))
+            }
+        });
+        (InterceptedWorldClient::new(client).with_limit(limit), load)
+    }
+
+    #[test]
+    fn calls_in_flight_stay_under_the_limit() {
+        run(async {
+            let (client, load) = busy_world(2);
+            let values: Vec<String> = (0..8).map(|i| i.to_string()).collect();
+            let calls = values
+                .iter()
+                .map(|value| client.echo(context::current(), value.clone()));
+            let answers = join_all(calls).await;
+            let answers: Vec<String> = answers.into_iter().map(|a| a.unwrap().unwrap()).collect();
+            assert_eq!(answers, values);
+            assert_eq!(load.most.get(), 2);
+            assert_eq!(client.limiter().unwrap().in_flight(), 0);
+        });
+    }
+
+    #[test]
+    fn waiting_calls_go_first_come_first_served() {
+        run(async {
+            let (client, load) = busy_world(1);
+            let values: Vec<String> = (0..6).map(|i| i.to_string()).collect();
+            let calls = values
+                .iter()
+                .map(|value| client.echo(context::current(), value.clone()));
+            join_all(calls).await;
+            assert_eq!(*load.arrived.borrow(), values);
+            assert_eq!(load.most.get(), 1);
+        });
+    }
+
+    #[test]
+    fn permits_are_counted() {
+        run(async {
+            let limiter = Limiter::new(2);
+            let first = limiter.acquire().await;
+            let second = limiter.acquire().await;
+            assert_eq!((limiter.in_flight(), limiter.waiting()), (2, 0));
+            let waiting = limiter.clone();
+            let third = tokio::task::spawn_local(async move { waiting.acquire().await });
+            tokio::task::yield_now().await;
+            assert_eq!((limiter.in_flight(), limiter.waiting()), (2, 1));
+            // The permit is passed on rather than given back.
+            drop(first);
+            let third = third.await.unwrap();
+            assert_eq!((limiter.in_flight(), limiter.waiting()), (2, 0));
+            drop((second, third));
+            assert_eq!(limiter.in_flight(), 0);
+        });
+    }
+
+    #[test]
+    fn waiting_counts_against_the_deadline() {
+        run(async {
+            let limiter = Limiter::new(1);
+            let permit = limiter.acquire().await;
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + Duration::from_millis(20);
+            let gave_up = limiter.acquire_by(&ctx).await.unwrap_err();
+            assert!(
+                matches!(gave_up, RpcError::DeadlineExceeded),
+                "{:?}",
+                gave_up
+            );
+            // Who gave up is skipped, the permit goes to the next one.
+            assert_eq!(limiter.waiting(), 1);
+            drop(permit);
+            assert_eq!((limiter.in_flight(), limiter.waiting()), (0, 0));
+            let permit = limiter.acquire_by(&context::current()).await.unwrap();
+            assert_eq!(limiter.in_flight(), 1);
+            drop(permit);
+        });
+    }
+
+    #[test]
+    fn limits_are_at_least_one() {
+        assert_eq!(Limiter::new(0).limit(), 1);
+        assert_eq!(Limiter::new(5).limit(), 5);
+    }
+}
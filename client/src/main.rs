@@ -1,119 +1,723 @@
-use crate::rpc_client::build_client;
+use client::api::WorldApi;
+use client::cancel::{cancellable, CancelHandle, Cancelled};
+use client::deadline::with_deadline;
+use client::error::TransportError;
+use client::offline::{OfflinePolicy, QueueingWorldClient};
+use client::pages::pages;
+use client::progress::with_progress;
+use client::push::{self, Topic};
+use client::replay::ReplayPolicy;
+use client::rpc_client::{ClientBuilder, WorldHandle, DEFAULT_URL};
+use client::rtt::RttMonitor;
+use client::telemetry::{self, traced};
+use client::transport::{
+    Auth, ConnectOptions, ConnectionState, FrameMode, HeartbeatTimeout, ReconnectPolicy,
+    WorkerScript,
+};
+use client::webrtc::{accept_peer, connect_peer, serve_peer, PeerOptions};
+use client::worker::{DEFAULT_SHARED_WORKER_URL, DEFAULT_WORKER_URL};
 
-use log::{info, Level};
+use futures::StreamExt;
+use gloo_timers::future::IntervalStream;
+use log::{info, Level, LevelFilter};
 
+use tarpc::client::RpcError;
 use tarpc::context;
-use rpc::WorldClient;
+use rpc::progress::Progress;
+use rpc::push::ANNOUNCEMENT_TOPIC;
+use rpc::stream::StreamRouter;
+use rpc::trace::label;
+use rpc::tasks::{Priority, Repeat};
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, VersionInfo, WorldClient,
+    WorldError,
+};
 
 use wasm_bindgen::JsCast;
-use wasm_bindgen_futures::spawn_local;
-use web_sys::HtmlInputElement;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{HtmlInputElement, HtmlSelectElement};
 use yew::prelude::*;
 
 use std::cell::RefCell;
+use std::future::Future;
 use std::rc::Rc;
-
-pub mod rpc_client;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct Model {
     link: yew::html::Scope<Model>,
-    delay: u64,
+    url: String,
+    /// Sent to the server when connecting, unless empty.
+    token: String,
+    binary: bool,
+    /// Keep the socket in a Web Worker, see [`worker`].
+    in_worker: bool,
+    /// Share one connection with the other tabs through a shared worker.
+    shared_worker: bool,
+    /// Log every message in the console.
+    trace: bool,
+    /// Seconds to delay as typed, e.g. `1.5`, see [`delay_millis`].
+    delay: String,
     delay_result: String,
-    client: Rc<RefCell<Option<WorldClient>>>,
+    /// How far the delay in progress got, `None` without one.
+    delay_progress: Option<Progress>,
+    /// Cancels the last delay call, harmless once it completed.
+    delay_call: Option<CancelHandle>,
+    /// Streams of every connection, see `Msg::Count`.
+    streams: StreamRouter,
+    count_result: String,
+    /// What the server pushed, newest last, at most `NOTIFICATIONS_SHOWN`.
+    notifications: Vec<String>,
+    /// The last announcement, shown until dismissed.
+    announcement: Option<String>,
+    /// The last calls and their trace ids, newest last, at most `HISTORY_SHOWN`.
+    history: Vec<String>,
+    /// Seconds the server has been up, as it last published them.
+    uptime: Option<u64>,
+    /// The current connection, kept once closed for the traffic footer.
+    world: Rc<RefCell<Option<WorldHandle>>>,
+    /// The calls made on `world`, any `WorldApi` does, e.g. a `client::mock::MockWorld`. The
+    /// calls of the page only go through this.
+    api: Rc<RefCell<Option<Rc<dyn WorldApi>>>>,
+    /// The round trips of `Msg::Ping` on the current connection.
+    rtt: RttMonitor,
+    /// The queue `api` keeps calls in while `world` reconnects, for its length.
+    queue: Rc<RefCell<Option<QueueingWorldClient>>>,
     echo_value: String,
     echo_result: String,
-    connected: bool,
+    /// Whether the bytes of the file picked came back from `echo_bytes` unchanged.
+    echoed_file: String,
+    /// `None` until the first connection attempt.
+    state: Option<ConnectionState>,
+    status: String,
+    /// Id other clients enter to open a data channel to this one.
+    peer_id: Option<u64>,
+    /// What the server knows about the connection, see `World::whoami`.
+    session: Option<SessionInfo>,
+    /// The build of the server connected to, see `World::version`.
+    server_version: Option<VersionInfo>,
+    /// The server's connections as `Msg::ListClients` last got them, or why it couldn't.
+    clients: Vec<String>,
+    /// How many connections `Admin::connections` last counted, or why it couldn't.
+    connections: String,
+    /// The first `PAGES_SHOWN` pages of `World::list_items`, one line each.
+    item_pages: Vec<String>,
+    /// The shared count as the last `increment` or `get_count` answered it, or why it couldn't.
+    shared_count: String,
+    /// What `Msg::CreateTask` sends.
+    task_form: TaskForm,
+    /// The task the server created last, or why it didn't.
+    task_result: String,
+    remote_peer: String,
+    peer_client: Rc<RefCell<Option<WorldClient>>>,
+    peer_status: String,
 }
 
 pub enum Msg {
     Connect,
-    Connected,
+    Disconnect,
+    State(ConnectionState),
+    ConnectFailed(String),
     Ping,
+    UpdateUrl(InputEvent),
+    UpdateToken(InputEvent),
+    ToggleBinary,
+    ToggleWorker,
+    ToggleSharedWorker,
+    ToggleTrace,
     UpdateEcho(InputEvent),
     UpdateDelay(InputEvent),
     UpdateEchoResult(String),
     UpdateDelayResult(String),
+    DelayProgress(Progress),
     Echo,
+    /// Round-trips the bytes of the file picked through `echo_bytes`.
+    EchoFile(Event),
+    EchoedFile(String),
     Delay,
+    CancelDelay,
+    /// Streams the numbers up to `COUNT_TO` from the server.
+    Count,
+    UpdateCountResult(String),
+    /// A call completed, already formatted for the history.
+    Called(String),
+    /// The client can't make calls anymore, for this reason.
+    Disconnected(String),
+    /// The server pushed a notification, already formatted.
+    Notification(String),
+    /// An admin told every page this, see `ANNOUNCEMENT_TOPIC`.
+    Announcement(String),
+    DismissAnnouncement,
+    /// Publishes the echo input to `CHAT_TOPIC`, which every connected page subscribes to.
+    Publish,
+    Uptime(u64),
     Redraw,
+    PeerId(u64),
+    Session(SessionInfo),
+    ServerVersion(VersionInfo),
+    ListClients,
+    Clients(Vec<String>),
+    /// Asks the `Admin` service sharing the connection, see `client::mux`.
+    CountConnections,
+    Connections(String),
+    ListItems,
+    ItemPages(Vec<String>),
+    /// Adds one to the count every connection shares.
+    Increment,
+    GetCount,
+    SharedCount(String),
+    UpdateTask(TaskField, String),
+    /// Sends the task form as a `CreateTaskRequest`.
+    CreateTask,
+    TaskCreated(String),
+    UpdateRemotePeer(InputEvent),
+    AcceptPeer,
+    CallPeer,
+    PeerConnected,
+    PeerStatus(String),
+    PeerEcho,
+    ResetStats,
 }
 
 impl Model {
     fn connect(&mut self) {
         info!("Attemping to connect");
-        let client_ptr = self.client.clone();
+        let world_ptr = self.world.clone();
+        let (api_ptr, queue_ptr) = (self.api.clone(), self.queue.clone());
+        let rtt = self.rtt.clone();
         let link = self.link.clone();
+        let url = self.url.clone();
+        let auth = (!self.token.is_empty()).then(|| Auth::Message(self.token.clone()));
+        let worker = match (self.in_worker, self.shared_worker) {
+            (_, true) => Some(WorkerScript::Shared(DEFAULT_SHARED_WORKER_URL.into())),
+            (true, false) => Some(WorkerScript::Dedicated(DEFAULT_WORKER_URL.into())),
+            (false, false) => None,
+        };
+        let trace = self.trace;
+        let streams = self.streams.clone();
+        let frames = if self.binary {
+            FrameMode::Binary
+        } else {
+            FrameMode::Text
+        };
         info!("Connecting");
         spawn_local(async move {
-            let transport = build_client();
-            if let Ok(trans) = transport.await {
-                info!("Connected");
-                let config = tarpc::client::Config::default();
-                let client = WorldClient::new(config, trans);
-                let dispatch = client
-                    .dispatch;
-                info!("Spawning Dispatch");
-                spawn_local(async move {dispatch.await.unwrap();});
-
-                //Store the client.
-                client_ptr.replace(Some(client.client));
-
-                //Force the dom view to refresh to update the Connected status.
-                link.send_message(Msg::Connected);
+            let options = ConnectOptions {
+                reconnect: Some(ReconnectPolicy {
+                    max_attempts: Some(5),
+                    ..ReconnectPolicy::default()
+                }),
+                keepalive: Some(Duration::from_secs(30)),
+                heartbeat_timeout: Some(HeartbeatTimeout::default()),
+                auth,
+                worker,
+                trace,
+                replay: Some(ReplayPolicy::default()),
+                streams: Some(streams),
+                ..ConnectOptions::default()
+            };
+            let (state_link, disconnect_link) = (link.clone(), link.clone());
+            let world = ClientBuilder::new(url.clone())
+                .options(options)
+                .codec(frames)
+                .on_state_change(move |state| state_link.send_message(Msg::State(state.clone())))
+                .on_disconnect(move |reason| {
+                    disconnect_link.send_message(Msg::Disconnected(reason.to_string()))
+                })
+                .connect();
+            match world.await {
+                Ok(world) => {
+                    info!("Connected");
+                    let handle = world.transport().clone();
+
+                    //Follow the server's uptime and the chat of every page. Only the handle
+                    //keeps the connection open, so the task holds its parts instead.
+                    let topics = (world.client().clone(), handle.clone(), link.clone());
+                    spawn_local(async move {
+                        let (client, handle, link) = topics;
+                        let ctx = context::current();
+                        if let Err(e) = client.subscribe(ctx, CHAT_TOPIC.into()).await {
+                            info!("Subscribing to {} failed: {}", CHAT_TOPIC, e);
+                        }
+                        let mut uptime: Topic<u64> =
+                            match push::subscribe(&client, &handle, ctx, UPTIME_TOPIC).await {
+                                Ok(Ok(uptime)) => uptime,
+                                Ok(Err(e)) => {
+                                    info!("Can't follow the uptime: {}", e);
+                                    return;
+                                }
+                                Err(e) => {
+                                    info!("Can't follow the uptime: {}", e);
+                                    return;
+                                }
+                            };
+                        while let Some(seconds) = uptime.next().await {
+                            if let Ok(seconds) = seconds {
+                                link.send_message(Msg::Uptime(seconds));
+                            }
+                        }
+                    });
+
+                    //Store the connection.
+                    let queue = QueueingWorldClient::new(
+                        world.client().clone(),
+                        handle.clone(),
+                        OfflinePolicy::default(),
+                    );
+                    api_ptr.replace(Some(Rc::new(queue.clone())));
+                    rtt.clear();
+                    queue_ptr.replace(Some(queue));
+                    world_ptr.replace(Some(world));
+
+                    //Force the dom view to refresh to update the Connected status.
+                    link.send_message(Msg::State(handle.state()));
+
+                    //List what the server pushes until the connection is gone.
+                    let mut notifications = handle.notifications();
+                    let notification_link = link.clone();
+                    spawn_local(async move {
+                        while let Some(notification) = notifications.next().await {
+                            if notification.topic == ANNOUNCEMENT_TOPIC {
+                                if let Ok(text) = notification.payload() {
+                                    notification_link.send_message(Msg::Announcement(text));
+                                }
+                                continue;
+                            }
+                            notification_link.send_message(Msg::Notification(format!(
+                                "{} {}",
+                                notification.topic, notification.payload
+                            )));
+                        }
+                    });
+
+                    //Redraw the traffic footer every second until the connection is gone.
+                    spawn_local(async move {
+                        let mut ticks = IntervalStream::new(1000);
+                        while ticks.next().await.is_some() {
+                            link.send_message(Msg::Redraw);
+                            if matches!(handle.state(), ConnectionState::Closed(_)) {
+                                break;
+                            }
+                        }
+                    });
+                }
+                Err(
+                    e @ (TransportError::ProtocolVersionMismatch { .. }
+                    | TransportError::SchemaMismatch { .. }),
+                ) => {
+                    info!("Failed to connect to {}: {}", url, e);
+                    link.send_message(Msg::ConnectFailed(format!(
+                        "The server was updated ({}), reload the page",
+                        e
+                    )));
+                }
+                Err(e) => {
+                    info!("Failed to connect to {}: {}", url, e);
+                    link.send_message(Msg::ConnectFailed(format!("Failed to connect: {}", e)));
+                }
             }
         });
     }
     fn ping(&self) {
-        if self.connected {
-            let client = self.client.clone();
+        if self.connected() {
+            let api = match self.api() {
+                Some(api) => api,
+                None => return,
+            };
+            let (link, rtt) = (self.link.clone(), self.rtt.clone());
             let fut = async move {
-                if let Some(ref mut client) = *client.borrow_mut() {
-                    let result = client.ping(context::current()).await.unwrap();
-                    if let Ok(msg) = result {
-                        info!("Ping success: Results {}", msg);
+                let ctx = context::current();
+                let pinged = rtt.ping(api.as_ref(), ctx);
+                match tracked(&link, "ping", &ctx, pinged).await {
+                    Ok(Ok(rtt)) => {
+                        info!("Ping success: answered in {:?}", rtt);
+                        link.send_message(Msg::Redraw);
                     }
+                    Ok(Err(e)) => info!("Ping failed: {}", e),
+                    Err(e) => info!("Ping failed: {}", call_failed(api.as_ref(), e)),
                 }
             };
             spawn_local(fut);
         }
     }
 
-    fn echo(&self, value: String) {
-        if self.connected {
-            let client = self.client.clone();
+    fn publish(&self, value: String) {
+        if self.queueing() {
+            let api = match self.api() {
+                Some(api) => api,
+                None => return,
+            };
             let link = self.link.clone();
             let fut = async move {
-                if let Some(ref mut client) = *client.borrow_mut() {
-                    let result = client.echo(context::current(), value).await.unwrap();
-                    if let Ok(msg) = result {
-                        info!("Echo Success: Results {}", msg);
-                        link.send_message(Msg::UpdateEchoResult(msg));
-                    }
+                let ctx = context::current();
+                // A string always serializes.
+                let payload = serde_json::to_string(&value).unwrap();
+                let published = api.publish(ctx, CHAT_TOPIC.into(), payload);
+                match tracked(&link, "publish", &ctx, published).await {
+                    Ok(Ok(n)) => info!("Published to {} subscribers", n),
+                    Ok(Err(e)) => info!("Publish failed: {}", e),
+                    Err(e) => info!("Publish failed: {}", call_failed(api.as_ref(), e)),
                 }
             };
             spawn_local(fut);
         }
     }
 
-    fn delay(&self, delay: u64) {
-        if self.connected {
-            let client = self.client.clone();
+    fn echo(&self, value: String) {
+        if self.queueing() {
+            let api = match self.api() {
+                Some(api) => api,
+                None => return,
+            };
+            let link = self.link.clone();
+            let fut = async move {
+                let ctx = context::current();
+                let echoed = tracked(&link, "echo", &ctx, api.echo(ctx, value)).await;
+                link.send_message(Msg::UpdateEchoResult(echo_result(api.as_ref(), echoed)));
+            };
+            spawn_local(fut);
+        }
+    }
+
+    /// Text frames can't carry raw bytes, so this shows they arrive and come back intact.
+    fn echo_file(&self, file: web_sys::File) {
+        let signaling = match self.world.borrow().as_ref() {
+            Some(world) => world.client().clone(),
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            let buffer = match JsFuture::from(file.array_buffer()).await {
+                Ok(buffer) => buffer,
+                Err(_) => {
+                    link.send_message(Msg::EchoedFile(format!("Can't read {}", file.name())));
+                    return;
+                }
+            };
+            let sent = Bytes(js_sys::Uint8Array::new(&buffer).to_vec());
+            let echoed = signaling.echo_bytes(context::current(), sent.clone()).await;
+            link.send_message(Msg::EchoedFile(describe_echoed(&file.name(), &sent, echoed)));
+        });
+    }
+
+    fn delay(&mut self) {
+        if self.connected() {
+            // Checked before anything is sent, the server would only reject it.
+            let millis = match delay_millis(&self.delay) {
+                Ok(millis) => millis,
+                Err(reason) => {
+                    self.delay_result = reason;
+                    return;
+                }
+            };
+            let api = match self.api() {
+                Some(api) => api,
+                None => return,
+            };
             let link = self.link.clone();
+            // Gives up at the deadline, 10 seconds from now, even if the server stalls.
+            let ctx = context::current();
+            let (history, reported, streams) = (link.clone(), link.clone(), self.streams.clone());
+            let delaying = api.clone();
+            let (call, cancel) = cancellable(async move {
+                let (call, mut reports) = with_progress(&streams, |progress| {
+                    delaying.delay_with_progress(ctx, millis, progress)
+                });
+                spawn_local(async move {
+                    while let Some(progress) = reports.next().await {
+                        reported.send_message(Msg::DelayProgress(progress));
+                    }
+                });
+                tracked(&history, "delay", &ctx, call).await
+            });
+            self.delay_call = Some(cancel);
             let fut = async move {
-                if let Some(ref mut client) = *client.borrow_mut() {
-                    let result = client.delay(context::current(), delay).await.unwrap();
-                    if let Ok(msg) = result {
+                match call.await {
+                    Ok(Ok(Ok(msg))) => {
                         info!("Delayed Success: Results {}", msg);
                         link.send_message(Msg::UpdateDelayResult(msg));
-                    } else {
-                        link.send_message(Msg::UpdateDelayResult(format!("Delay failed {}", delay)))
+                    }
+                    Ok(Ok(Err(WorldError::Timeout))) => link.send_message(Msg::UpdateDelayResult(
+                        format!(
+                            "A delay of {}s takes longer than the deadline",
+                            millis as f64 / 1000.0
+                        ),
+                    )),
+                    Ok(Ok(Err(e))) => link.send_message(Msg::UpdateDelayResult(failed(&e))),
+                    Ok(Err(e)) => link.send_message(Msg::UpdateDelayResult(format!(
+                        "Delay failed: {}",
+                        call_failed(api.as_ref(), e)
+                    ))),
+                    Err(Cancelled) => {
+                        link.send_message(Msg::UpdateDelayResult("Delay cancelled".into()))
                     }
                 }
             };
             spawn_local(fut);
         }
     }
+
+    fn count(&self) {
+        if self.connected() {
+            let api = match self.api.borrow().clone() {
+                Some(api) => api,
+                None => return,
+            };
+            let (stream, mut numbers) = self.streams.open::<u64>();
+            let link = self.link.clone();
+            spawn_local(async move {
+                match api.count(context::current(), stream, COUNT_TO).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => return link.send_message(Msg::UpdateCountResult(failed(&e))),
+                    Err(e) => {
+                        let failed = format!("Count failed: {}", e);
+                        return link.send_message(Msg::UpdateCountResult(failed));
+                    }
+                }
+                let mut counted = Vec::new();
+                while let Some(number) = numbers.next().await {
+                    match number {
+                        Ok(number) => counted.push(number.to_string()),
+                        Err(e) => counted.push(e.to_string()),
+                    }
+                    link.send_message(Msg::UpdateCountResult(counted.join(" ")));
+                }
+            });
+        }
+    }
+
+    /// Closes the connection for good, calls still in flight fail.
+    fn disconnect(&self) {
+        if let Some(world) = self.world.borrow().clone() {
+            spawn_local(async move { world.transport().disconnect().await });
+        }
+    }
+
+    /// The calls made on the current connection, `None` before the first one.
+    fn api(&self) -> Option<Rc<dyn WorldApi>> {
+        self.api.borrow().clone()
+    }
+
+    fn connected(&self) -> bool {
+        self.state == Some(ConnectionState::Connected)
+    }
+
+    /// Whether calls the offline queue keeps can be made, which they can while reconnecting.
+    fn queueing(&self) -> bool {
+        matches!(
+            self.state,
+            Some(ConnectionState::Connected | ConnectionState::Reconnecting { .. })
+        )
+    }
+
+    /// The server hands out a new id for every connection, reconnects included. The server
+    /// reconnected to may be another build, so its version is asked for again too. Each
+    /// connection is also reported to the server's log, one-way since nobody reads the answer.
+    fn fetch_peer_id(&self) {
+        let world = match self.world.borrow().as_ref() {
+            Some(world) => world.clone(),
+            None => return,
+        };
+        let signaling = world.client().clone();
+        let link = self.link.clone();
+        let url = self.url.clone();
+        spawn_local(async move {
+            let event = world.notify_log_event(context::current(), "connected".into(), url);
+            if let Err(e) = event.await {
+                info!("Couldn't report the connection: {}", e);
+            }
+            // The handle would keep the connection open while the calls below wait.
+            drop(world);
+            if let Ok(Ok(id)) = signaling.peer_id(context::current()).await {
+                link.send_message(Msg::PeerId(id));
+            }
+            if let Ok(Ok(session)) = signaling.whoami(context::current()).await {
+                link.send_message(Msg::Session(session));
+            }
+            if let Ok(Ok(version)) = signaling.version(context::current()).await {
+                link.send_message(Msg::ServerVersion(version));
+            }
+        });
+    }
+
+    /// Only admins get the list, others see why not.
+    fn list_clients(&self) {
+        let signaling = match self.world.borrow().as_ref() {
+            Some(world) => world.client().clone(),
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            let clients = match signaling.list_clients(context::current()).await {
+                Ok(Ok(clients)) => clients.iter().map(describe_client).collect(),
+                Ok(Err(e)) => vec![failed(&e)],
+                Err(e) => vec![e.to_string()],
+            };
+            link.send_message(Msg::Clients(clients));
+        });
+    }
+
+    fn count_connections(&self) {
+        let admin = match self.world.borrow().as_ref() {
+            Some(world) => world.admin().clone(),
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            let connections = match admin.connections(context::current()).await {
+                Ok(Ok(connections)) => connections.to_string(),
+                Ok(Err(e)) => failed(&e),
+                Err(e) => e.to_string(),
+            };
+            link.send_message(Msg::Connections(connections));
+        });
+    }
+
+    /// Follows the cursors of `list_items` for `PAGES_SHOWN` pages.
+    fn list_items(&self) {
+        let signaling = match self.world.borrow().as_ref() {
+            Some(world) => world.client().clone(),
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            let answers: Vec<_> = pages(&signaling, PAGE_LEN).take(PAGES_SHOWN).collect().await;
+            let pages = answers
+                .iter()
+                .map(|answer| match answer {
+                    Ok(Ok(page)) => describe_page(page),
+                    Ok(Err(e)) => failed(e),
+                    Err(e) => e.to_string(),
+                })
+                .collect();
+            link.send_message(Msg::ItemPages(pages));
+        });
+    }
+
+    fn increment(&self) {
+        let signaling = match self.world.borrow().as_ref() {
+            Some(world) => world.client().clone(),
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            let count = signaling.increment(context::current(), 1).await;
+            link.send_message(Msg::SharedCount(describe_count(count)));
+        });
+    }
+
+    /// Other tabs add to the count too, this shows where they got it.
+    fn get_count(&self) {
+        let signaling = match self.world.borrow().as_ref() {
+            Some(world) => world.client().clone(),
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            let count = signaling.get_count(context::current()).await;
+            link.send_message(Msg::SharedCount(describe_count(count)));
+        });
+    }
+
+    fn create_task(&self) {
+        let request = match self.task_form.request() {
+            Ok(request) => request,
+            Err(e) => {
+                self.link.send_message(Msg::TaskCreated(e));
+                return;
+            }
+        };
+        let signaling = match self.world.borrow().as_ref() {
+            Some(world) => world.client().clone(),
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            let task = signaling.create_task(context::current(), request).await;
+            link.send_message(Msg::TaskCreated(describe_task(task)));
+        });
+    }
+
+    fn accept_peer(&self) {
+        let signaling = match self.world.borrow().as_ref() {
+            Some(world) => world.client().clone(),
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            link.send_message(Msg::PeerStatus("Waiting for a peer to call".into()));
+            let options = ConnectOptions::default();
+            match accept_peer(&signaling, options, PeerOptions::default()).await {
+                Ok((transport, handle)) => {
+                    let close_link = link.clone();
+                    handle.on_close(move |info| {
+                        close_link.send_message(Msg::PeerStatus(format!("Peer left: {}", info)))
+                    });
+                    link.send_message(Msg::PeerStatus("Serving a peer".into()));
+                    serve_peer(transport).await;
+                }
+                Err(e) => {
+                    link.send_message(Msg::PeerStatus(format!("Accepting a peer failed: {}", e)))
+                }
+            }
+        });
+    }
+
+    fn call_peer(&self) {
+        let signaling = match self.world.borrow().as_ref() {
+            Some(world) => world.client().clone(),
+            None => return,
+        };
+        let remote = match self.remote_peer.trim().parse() {
+            Ok(remote) => remote,
+            Err(_) => {
+                self.link.send_message(Msg::PeerStatus("Enter the peer's id".into()));
+                return;
+            }
+        };
+        let peer_client = self.peer_client.clone();
+        let link = self.link.clone();
+        spawn_local(async move {
+            link.send_message(Msg::PeerStatus(format!("Calling peer {}", remote)));
+            let options = ConnectOptions::default();
+            match connect_peer(&signaling, remote, options, PeerOptions::default()).await {
+                Ok((transport, handle)) => {
+                    let close_link = link.clone();
+                    handle.on_close(move |info| {
+                        close_link.send_message(Msg::PeerStatus(format!("Peer left: {}", info)))
+                    });
+                    let client = WorldClient::new(tarpc::client::Config::default(), transport);
+                    let dispatch = client.dispatch;
+                    spawn_local(async move {
+                        if let Err(e) = dispatch.await {
+                            info!("Peer dispatch ended: {}", e);
+                        }
+                    });
+                    peer_client.replace(Some(client.client));
+                    link.send_message(Msg::PeerConnected);
+                }
+                Err(e) => link.send_message(Msg::PeerStatus(format!("Calling peer failed: {}", e))),
+            }
+        });
+    }
+
+    fn peer_echo(&self, value: String) {
+        let client = match self.peer_client.borrow().clone() {
+            Some(client) => client,
+            None => return,
+        };
+        let link = self.link.clone();
+        spawn_local(async move {
+            match client.echo(context::current(), value).await {
+                Ok(Ok(msg)) => {
+                    info!("Peer echo success: Results {}", msg);
+                    link.send_message(Msg::UpdateEchoResult(format!("{} (from peer)", msg)));
+                }
+                Ok(Err(e)) => info!("Peer echo failed: {}", e),
+                Err(e) => info!("Peer echo failed: {}", e),
+            }
+        });
+    }
 }
 
 impl Component for Model {
@@ -123,49 +727,268 @@ impl Component for Model {
     fn create(ctx: &Context<Self>) -> Self {
         Self {
             link: ctx.link().clone(),
-            client: Rc::new(RefCell::new(None)),
-            delay: 30,
-            delay_result: "Type number in input and press Delay".into(),
+            world: Rc::new(RefCell::new(None)),
+            api: Rc::new(RefCell::new(None)),
+            rtt: RttMonitor::default(),
+            queue: Rc::new(RefCell::new(None)),
+            url: DEFAULT_URL.into(),
+            token: "".into(),
+            binary: false,
+            in_worker: false,
+            shared_worker: false,
+            trace: false,
+            delay: "30".into(),
+            delay_result: "Type seconds in input, e.g. 1.5, and press Delay".into(),
+            delay_progress: None,
+            delay_call: None,
+            streams: StreamRouter::new(),
+            count_result: "Press Count".into(),
+            notifications: Vec::new(),
+            announcement: None,
+            history: Vec::new(),
+            uptime: None,
             echo_value: "".into(),
             echo_result: "Type string in input and press Echo".into(),
-            connected: false,
+            echoed_file: "-".into(),
+            state: None,
+            status: "Not connected".into(),
+            peer_id: None,
+            session: None,
+            server_version: None,
+            clients: Vec::new(),
+            connections: "-".into(),
+            item_pages: Vec::new(),
+            shared_count: "-".into(),
+            task_form: TaskForm::default(),
+            task_result: "-".into(),
+            remote_peer: "".into(),
+            peer_client: Rc::new(RefCell::new(None)),
+            peer_status: "No peer".into(),
         }
     }
 
     fn update(&mut self, _ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
-            Msg::Connect => self.connect(),
+            Msg::Connect => {
+                self.state = Some(ConnectionState::Connecting);
+                self.status = "Connecting".into();
+                self.connect()
+            }
+            Msg::Disconnect => self.disconnect(),
             Msg::Ping => self.ping(),
+            Msg::UpdateUrl(e) => {
+                let target:HtmlInputElement = e.target().unwrap().dyn_into().unwrap();
+                self.url = target.value();
+            },
+            Msg::UpdateToken(e) => {
+                let target:HtmlInputElement = e.target().unwrap().dyn_into().unwrap();
+                self.token = target.value();
+            },
+            Msg::ToggleBinary => self.binary = !self.binary,
+            Msg::ToggleWorker => self.in_worker = !self.in_worker,
+            Msg::ToggleSharedWorker => self.shared_worker = !self.shared_worker,
+            Msg::ToggleTrace => {
+                self.trace = !self.trace;
+                let level = if self.trace { LevelFilter::Trace } else { LevelFilter::Debug };
+                log::set_max_level(level);
+                if let Some(world) = &*self.world.borrow() {
+                    world.transport().set_trace(self.trace);
+                }
+            }
             Msg::UpdateEcho(e) => {
                 let target:HtmlInputElement = e.target().unwrap().dyn_into().unwrap();
                 self.echo_value = target.value();
             },
             Msg::UpdateDelay(e) => {
                 let target:HtmlInputElement = e.target().unwrap().dyn_into().unwrap();
-                self.delay = target.value().parse().unwrap();
+                self.delay = target.value();
             },
             Msg::UpdateDelayResult(result) => {
                 info!("Updating the delay result");
                 self.delay_result = result.clone();
+                self.delay_progress = None;
             },
+            Msg::DelayProgress(progress) => self.delay_progress = Some(progress),
             Msg::Echo => self.echo(self.echo_value.clone()),
-            Msg::Delay => self.delay(self.delay),
+            Msg::EchoFile(e) => {
+                let target: HtmlInputElement = e.target().unwrap().dyn_into().unwrap();
+                if let Some(file) = target.files().and_then(|files| files.get(0)) {
+                    self.echo_file(file);
+                }
+            }
+            Msg::EchoedFile(echoed) => self.echoed_file = echoed,
+            Msg::Delay => self.delay(),
+            Msg::CancelDelay => {
+                if let Some(call) = self.delay_call.take() {
+                    call.cancel();
+                }
+            }
+            Msg::Count => self.count(),
+            Msg::UpdateCountResult(result) => self.count_result = result,
+            Msg::Notification(notification) => {
+                if self.notifications.len() == NOTIFICATIONS_SHOWN {
+                    self.notifications.remove(0);
+                }
+                self.notifications.push(notification);
+            }
+            Msg::Announcement(text) => self.announcement = Some(text),
+            Msg::DismissAnnouncement => self.announcement = None,
+            Msg::Called(call) => {
+                if self.history.len() == HISTORY_SHOWN {
+                    self.history.remove(0);
+                }
+                self.history.push(call);
+            }
+            Msg::Publish => self.publish(self.echo_value.clone()),
+            Msg::Uptime(seconds) => self.uptime = Some(seconds),
             Msg::Redraw => (),
             Msg::UpdateEchoResult(result) => {
                 info!("Updating the echo result");
                 self.echo_result = result.clone();
             }
-            Msg::Connected => self.connected = true,
+            Msg::State(state) => {
+                self.status = state.to_string();
+                match state {
+                    ConnectionState::Connected => {
+                        self.fetch_peer_id();
+                        self.get_count();
+                    }
+                    _ => {
+                        self.peer_id = None;
+                        self.session = None;
+                        self.server_version = None;
+                    }
+                }
+                self.state = Some(state);
+            }
+            Msg::Disconnected(reason) => {
+                self.status = format!("Disconnected: {}", reason);
+                // The connection closes too, which then shows as `Closed`.
+                if self.connected() {
+                    self.state = None;
+                }
+            }
+            Msg::ConnectFailed(reason) => {
+                self.state = None;
+                self.status = reason;
+            }
+            Msg::PeerId(id) => self.peer_id = Some(id),
+            Msg::Session(session) => self.session = Some(session),
+            Msg::ServerVersion(version) => self.server_version = Some(version),
+            Msg::ListClients => self.list_clients(),
+            Msg::CountConnections => self.count_connections(),
+            Msg::Connections(connections) => self.connections = connections,
+            Msg::Clients(clients) => self.clients = clients,
+            Msg::ListItems => self.list_items(),
+            Msg::ItemPages(pages) => self.item_pages = pages,
+            Msg::Increment => self.increment(),
+            Msg::GetCount => self.get_count(),
+            Msg::SharedCount(count) => self.shared_count = count,
+            Msg::UpdateTask(field, value) => self.task_form.set(field, value),
+            Msg::CreateTask => self.create_task(),
+            Msg::TaskCreated(task) => self.task_result = task,
+            Msg::UpdateRemotePeer(e) => {
+                let target:HtmlInputElement = e.target().unwrap().dyn_into().unwrap();
+                self.remote_peer = target.value();
+            },
+            Msg::AcceptPeer => self.accept_peer(),
+            Msg::CallPeer => self.call_peer(),
+            Msg::PeerConnected => self.peer_status = "Connected to a peer".into(),
+            Msg::PeerStatus(status) => self.peer_status = status,
+            Msg::PeerEcho => self.peer_echo(self.echo_value.clone()),
+            Msg::ResetStats => {
+                if let Some(world) = &*self.world.borrow() {
+                    world.transport().reset_stats();
+                }
+            }
         }
         true
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
         let echo_result = self.echo_result.clone();
+        let connected = self.connected();
+        let queueing = self.queueing();
+        let task_field = |field: TaskField| {
+            ctx.link()
+                .callback(move |e: Event| Msg::UpdateTask(field, target_value(&e)))
+        };
+        let queued = self.queue.borrow().as_ref().map_or(0, |queue| queue.queued());
+        let rtt = self.rtt.stats();
+        // Another connection can only be opened once the last one is closed for good.
+        let connectable = matches!(self.state, None | Some(ConnectionState::Closed(_)));
+        let stats = self
+            .world
+            .borrow()
+            .as_ref()
+            .map(|world| world.transport().stats())
+            .unwrap_or_default();
         html! {
             <div>
-                <button onclick={ctx.link().callback(|_| Msg::Connect)}>{ "Connect" }</button>
-                <button onclick={ctx.link().callback(|_| Msg::Ping)}>{ "Ping" }</button>
+                if let Some(announcement) = &self.announcement {
+                    <div role="alert">
+                        <strong>{announcement.clone()}</strong>
+                        <button onclick={ctx.link().callback(|_| Msg::DismissAnnouncement)}>
+                            { "Dismiss" }
+                        </button>
+                    </div>
+                }
+                <input
+                    type = "text"
+                    placeholder="Server URL"
+                    value={self.url.clone()}
+                    oninput={ctx.link().callback(Msg::UpdateUrl)}
+                />
+                <input
+                    type = "password"
+                    placeholder="Auth token (optional)"
+                    value={self.token.clone()}
+                    oninput={ctx.link().callback(Msg::UpdateToken)}
+                />
+                <label>
+                    <input
+                        type = "checkbox"
+                        checked={self.binary}
+                        onclick={ctx.link().callback(|_| Msg::ToggleBinary)}
+                    />
+                    { "Binary frames" }
+                </label>
+                <label>
+                    <input
+                        type = "checkbox"
+                        checked={self.in_worker}
+                        onclick={ctx.link().callback(|_| Msg::ToggleWorker)}
+                    />
+                    { "In a worker" }
+                </label>
+                <label>
+                    <input
+                        type = "checkbox"
+                        checked={self.shared_worker}
+                        onclick={ctx.link().callback(|_| Msg::ToggleSharedWorker)}
+                    />
+                    { "Shared between tabs" }
+                </label>
+                <label>
+                    <input
+                        type = "checkbox"
+                        checked={self.trace}
+                        onclick={ctx.link().callback(|_| Msg::ToggleTrace)}
+                    />
+                    { "Trace messages" }
+                </label>
+                <button
+                    disabled={!connectable}
+                    onclick={ctx.link().callback(|_| Msg::Connect)}
+                >{ "Connect" }</button>
+                <button
+                    disabled={connectable}
+                    onclick={ctx.link().callback(|_| Msg::Disconnect)}
+                >{ "Disconnect" }</button>
+                <button
+                    disabled={!connected}
+                    onclick={ctx.link().callback(|_| Msg::Ping)}
+                >{ "Ping" }</button>
                 <div>
                     <input
                         type = "text"
@@ -173,34 +996,506 @@ impl Component for Model {
                         value={self.echo_value.clone()}
                         oninput={ctx.link().callback(Msg::UpdateEcho)}
                     />
-                    <button onclick={ctx.link().callback(|_| Msg::Echo)}> { "Echo"} </button>
+                    <button
+                        disabled={!queueing}
+                        onclick={ctx.link().callback(|_| Msg::Echo)}
+                    > { "Echo"} </button>
+                    <button
+                        disabled={!queueing}
+                        onclick={ctx.link().callback(|_| Msg::Publish)}
+                    > { "Publish"} </button>
                     <div>{"Echoed Result: "}{echo_result} </div>
                 </div>
+                <div>
+                    <input
+                        type = "file"
+                        disabled={!connected}
+                        onchange={ctx.link().callback(Msg::EchoFile)}
+                    />
+                    <div>{"Echoed file: "}{self.echoed_file.clone()}</div>
+                </div>
                 <div>
                     <input
                         type = "number"
                         placeholder="Delay(s)"
-                        value={format!("{}",self.delay)}
+                        min="0"
+                        step="0.001"
+                        value={self.delay.clone()}
                         oninput={ctx.link().callback(Msg::UpdateDelay)}
                     />
-                    <button onclick={ctx.link().callback(|_| Msg::Delay)}> { "Delay"} </button>
+                    <button
+                        disabled={!connected}
+                        onclick={ctx.link().callback(|_| Msg::Delay)}
+                    > { "Delay"} </button>
+                    <button
+                        disabled={self.delay_call.is_none()}
+                        onclick={ctx.link().callback(|_| Msg::CancelDelay)}
+                    > { "Cancel"} </button>
+                    if let Some(progress) = self.delay_progress {
+                        <progress
+                            max={progress.total.to_string()}
+                            value={progress.done.to_string()}
+                        >
+                            {format!(
+                                "{:.1}/{:.1}s",
+                                progress.done as f64 / 1000.0,
+                                progress.total as f64 / 1000.0
+                            )}
+                        </progress>
+                    }
                     <div>{"Delayed Result: "}{self.delay_result.clone()} </div>
                 </div>
+                <div>
+                    <button
+                        disabled={!connected}
+                        onclick={ctx.link().callback(|_| Msg::Count)}
+                    > { "Count"} </button>
+                    <div>{"Counted: "}{self.count_result.clone()} </div>
+                </div>
                 <div>
                 {"Connected: "}{
-                    if self.connected {
+                    if connected {
                         "True"
                     }else {
                         "False"
                     }
                 }
                 </div>
+                <div>{"Status: "}{self.status.clone()}</div>
+                <div>{"Queued: "}{queued}</div>
+                <div>{"Ping: "}{
+                    rtt.map_or("-".to_string(), |rtt| format!(
+                        "{:.1} ms (min {:.1}, avg {:.1}, max {:.1} over {})",
+                        millis(rtt.last),
+                        millis(rtt.min),
+                        millis(rtt.avg),
+                        millis(rtt.max),
+                        rtt.samples
+                    ))
+                }</div>
+                <div>{"Server up: "}{
+                    self.uptime.map_or("-".to_string(), |seconds| format!("{}s", seconds))
+                }</div>
+                <div>
+                    <div>{"Your peer id: "}{
+                        self.peer_id.map_or("-".to_string(), |id| id.to_string())
+                    }</div>
+                    <div>{"Session: "}{
+                        self.session.as_ref().map_or("-".to_string(), describe_session)
+                    }</div>
+                    <div>{"Server: "}{
+                        self.server_version.as_ref().map_or("-".to_string(), describe_version)
+                    }</div>
+                    <button onclick={ctx.link().callback(|_| Msg::ListClients)}>
+                        { "List clients" }
+                    </button>
+                    <ul>
+                        {
+                            for self.clients.iter().map(|client| {
+                                html! { <li>{client.clone()}</li> }
+                            })
+                        }
+                    </ul>
+                    <button onclick={ctx.link().callback(|_| Msg::CountConnections)}>
+                        { "Count connections" }
+                    </button>
+                    <div>{"Connections: "}{self.connections.clone()}</div>
+                    <button onclick={ctx.link().callback(|_| Msg::ListItems)}>
+                        { "List items" }
+                    </button>
+                    <ul>
+                        {
+                            for self.item_pages.iter().map(|page| {
+                                html! { <li>{page.clone()}</li> }
+                            })
+                        }
+                    </ul>
+                    <div>{"Shared count: "}{self.shared_count.clone()}</div>
+                    <button onclick={ctx.link().callback(|_| Msg::Increment)}>{ "+1" }</button>
+                    <button onclick={ctx.link().callback(|_| Msg::GetCount)}>
+                        { "Refresh count" }
+                    </button>
+                    <div>
+                        <input
+                            type="text"
+                            placeholder="Task title"
+                            onchange={task_field(TaskField::Title)}
+                        />
+                        <select onchange={task_field(TaskField::Priority)}>
+                            <option value="low">{ "Low" }</option>
+                            <option value="normal" selected=true>{ "Normal" }</option>
+                            <option value="high">{ "High" }</option>
+                        </select>
+                        <select onchange={task_field(TaskField::Repeat)}>
+                            <option value="never" selected=true>{ "Once" }</option>
+                            <option value="daily">{ "Daily" }</option>
+                            <option value="weekly">{ "Weekly" }</option>
+                        </select>
+                        <input
+                            type="number"
+                            min="0"
+                            placeholder="Due in days"
+                            onchange={task_field(TaskField::Due)}
+                        />
+                        <input
+                            type="text"
+                            placeholder="Tags, comma separated"
+                            onchange={task_field(TaskField::Tags)}
+                        />
+                        <button onclick={ctx.link().callback(|_| Msg::CreateTask)}>
+                            { "Create task" }
+                        </button>
+                        <div>{"Task: "}{self.task_result.clone()}</div>
+                    </div>
+                    <input
+                        type = "text"
+                        placeholder="Peer id"
+                        value={self.remote_peer.clone()}
+                        oninput={ctx.link().callback(Msg::UpdateRemotePeer)}
+                    />
+                    <button onclick={ctx.link().callback(|_| Msg::CallPeer)}>{ "Call peer" }</button>
+                    <button onclick={ctx.link().callback(|_| Msg::AcceptPeer)}>{ "Accept peer" }</button>
+                    <button onclick={ctx.link().callback(|_| Msg::PeerEcho)}>{ "Echo via peer" }</button>
+                    <div>{"Peer: "}{self.peer_status.clone()}</div>
+                </div>
+                <div>
+                    {"Calls:"}
+                    <ul>
+                        { for self.history.iter().map(|call| html! { <li>{call.clone()}</li> }) }
+                    </ul>
+                </div>
+                <div>
+                    {"Notifications:"}
+                    <ul>
+                        { for self.notifications.iter().map(|n| html! { <li>{n.clone()}</li> }) }
+                    </ul>
+                </div>
+                <footer>
+                    {format!(
+                        "Sent {} messages ({} bytes), received {} ({} bytes), last activity {} ",
+                        stats.messages_sent,
+                        stats.bytes_sent,
+                        stats.messages_received,
+                        stats.bytes_received,
+                        stats.last_activity.map_or("-".to_string(), |at| {
+                            String::from(js_sys::Date::new(&at.into()).to_locale_time_string("en"))
+                        }),
+                    )}
+                    <button onclick={ctx.link().callback(|_| Msg::ResetStats)}>{ "Reset" }</button>
+                </footer>
             </div>
         }
     }
 }
 
+/// `session` as the page shows it, e.g. `peer 3 from 127.0.0.1:51234 as alice since 10:02:03`.
+fn describe_session(session: &SessionInfo) -> String {
+    let connected = js_sys::Date::new(&(session.connected_at as f64).into());
+    format!(
+        "peer {} from {} as {} since {}",
+        session.peer_id,
+        session.peer_addr.as_deref().unwrap_or("-"),
+        session.identity.as_deref().unwrap_or("anonymous"),
+        String::from(connected.to_locale_time_string("en")),
+    )
+}
+
+fn describe_version(version: &VersionInfo) -> String {
+    let built = js_sys::Date::new(&((version.built_at * 1000) as f64).into());
+    format!(
+        "{} ({}) built {}, protocols {:?}",
+        version.crate_version,
+        version.git_hash,
+        String::from(built.to_iso_string()),
+        version.protocols,
+    )
+}
+
+/// `client` as the list of `Msg::ListClients` shows it.
+fn describe_client(client: &ClientInfo) -> String {
+    let session = SessionInfo {
+        peer_id: client.peer_id,
+        peer_addr: client.peer_addr.clone(),
+        identity: client.identity.clone(),
+        connected_at: client.connected_at,
+    };
+    format!("{}, {} calls", describe_session(&session), client.calls)
+}
+
+/// `page` as the list of `Msg::ListItems` shows it, its item names and cursor.
+fn describe_page(page: &Page) -> String {
+    let names: Vec<&str> = page.items.iter().map(|item| item.name.as_str()).collect();
+    format!(
+        "{} (next: {})",
+        names.join(", "),
+        page.next_cursor.as_deref().unwrap_or("none")
+    )
+}
+
+/// The milliseconds of `seconds` as typed into the delay input, or why it names none.
+fn delay_millis(seconds: &str) -> Result<u64, String> {
+    let not_seconds = || format!("{:?} isn't a number of seconds, e.g. 1.5", seconds);
+    let delay = seconds
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(not_seconds)?;
+    u64::try_from(delay.as_millis()).map_err(|_| not_seconds())
+}
+
+/// What `echo_bytes` answered for the bytes of the file `name`, as `Msg::EchoedFile` shows it.
+fn describe_echoed(
+    name: &str,
+    sent: &Bytes,
+    echoed: Result<Result<Bytes, WorldError>, RpcError>,
+) -> String {
+    match echoed {
+        Ok(Ok(echoed)) if echoed == *sent => {
+            format!("{}: {} bytes came back unchanged", name, sent.len())
+        }
+        Ok(Ok(echoed)) => format!(
+            "{}: sent {} bytes, {} other ones came back",
+            name,
+            sent.len(),
+            echoed.len()
+        ),
+        Ok(Err(e)) => failed(&e),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// What `increment` or `get_count` answered, as `Msg::SharedCount` shows it.
+fn describe_count(count: Result<Result<i64, WorldError>, RpcError>) -> String {
+    match count {
+        Ok(Ok(count)) => count.to_string(),
+        Ok(Err(e)) => failed(&e),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// The fields of the task form, see [`TaskForm`].
+#[derive(Clone, Copy)]
+pub enum TaskField {
+    Title,
+    Priority,
+    Repeat,
+    Due,
+    Tags,
+}
+
+/// The task form as typed, turned into a `CreateTaskRequest` once sent.
+#[derive(Clone, Debug, Default)]
+struct TaskForm {
+    title: String,
+    priority: String,
+    repeat: String,
+    /// Days from now, empty for no due date.
+    due: String,
+    /// Comma separated.
+    tags: String,
+}
+
+impl TaskForm {
+    fn set(&mut self, field: TaskField, value: String) {
+        match field {
+            TaskField::Title => self.title = value,
+            TaskField::Priority => self.priority = value,
+            TaskField::Repeat => self.repeat = value,
+            TaskField::Due => self.due = value,
+            TaskField::Tags => self.tags = value,
+        }
+    }
+
+    /// The request the form stands for, or why it stands for none. The server checks the rest.
+    fn request(&self) -> Result<CreateTaskRequest, String> {
+        let priority = match self.priority.as_str() {
+            "low" => Priority::Low,
+            "high" => Priority::High,
+            _ => Priority::Normal,
+        };
+        let repeat = match self.repeat.as_str() {
+            "daily" => Repeat::Daily,
+            "weekly" => Repeat::Weekly,
+            _ => Repeat::Never,
+        };
+        let due = match self.due.trim() {
+            "" => None,
+            days => {
+                let days: u64 = days
+                    .parse()
+                    .map_err(|_| format!("{:?} isn't a number of days", days))?;
+                Some(js_sys::Date::now() as u64 + days * 24 * 60 * 60 * 1000)
+            }
+        };
+        let tags = self
+            .tags
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .collect();
+        Ok(CreateTaskRequest {
+            title: self.title.clone(),
+            priority,
+            due,
+            tags,
+            repeat,
+        })
+    }
+}
+
+/// What `create_task` answered, as `Msg::TaskCreated` shows it.
+fn describe_task(task: Result<Result<Task, WorldError>, RpcError>) -> String {
+    let task = match task {
+        Ok(Ok(task)) => task,
+        Ok(Err(e)) => return failed(&e),
+        Err(e) => return e.to_string(),
+    };
+    let due = match task.due {
+        Some(due) => String::from(js_sys::Date::new(&(due as f64).into()).to_iso_string()),
+        None => "whenever".to_string(),
+    };
+    format!(
+        "#{} {:?}, {:?} priority, due {}, tags [{}], repeats {:?}, by peer {}",
+        task.id,
+        task.title,
+        task.priority,
+        due,
+        task.tags.join(", "),
+        task.repeat,
+        task.created_by
+    )
+}
+
+/// The value of the input or select `e` happened on.
+fn target_value(e: &Event) -> String {
+    let target = e.target().unwrap();
+    match target.dyn_ref::<HtmlInputElement>() {
+        Some(input) => input.value(),
+        None => target.unchecked_into::<HtmlSelectElement>().value(),
+    }
+}
+
+/// How far `Msg::Count` counts, a second per number.
+const COUNT_TO: u64 = 5;
+
+/// Topic the server publishes its uptime to.
+const UPTIME_TOPIC: &str = "uptime";
+
+/// Topic `Msg::Publish` sends to.
+const CHAT_TOPIC: &str = "chat";
+
+/// Notifications the page lists before dropping the oldest.
+const NOTIFICATIONS_SHOWN: usize = 10;
+
+/// Calls the page lists before dropping the oldest.
+const HISTORY_SHOWN: usize = 10;
+
+/// Items of a page `Msg::ListItems` asks for.
+const PAGE_LEN: u32 = 5;
+
+/// Pages `Msg::ListItems` gets.
+const PAGES_SHOWN: usize = 2;
+
+/// `call`, made with `ctx`, traced and given up at the deadline. Listed in the history with
+/// its trace id once it completed, to find it in the server's log.
+async fn tracked<T>(
+    link: &yew::html::Scope<Model>,
+    method: &'static str,
+    ctx: &context::Context,
+    call: impl Future<Output = Result<T, RpcError>>,
+) -> Result<T, RpcError> {
+    let answer = with_deadline(ctx, traced(method, ctx, call)).await;
+    let outcome = match &answer {
+        Ok(_) => "answered".to_string(),
+        Err(e) => format!("failed: {}", e),
+    };
+    link.send_message(Msg::Called(format!("{} ({}) {}", method, label(ctx), outcome)));
+    answer
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Text for a call the server answered with `e`.
+fn failed(e: &WorldError) -> String {
+    match e {
+        WorldError::InvalidArgument(e) => format!("Rejected: {}", e),
+        WorldError::Timeout => "Timed out".into(),
+        WorldError::Unavailable(e) => format!("Not available: {}", e),
+        WorldError::Internal(e) => format!("The server failed: {}", e),
+        WorldError::RateLimited(retry_after) => {
+            format!("Too many calls, try again in {} ms", retry_after)
+        }
+        WorldError::Busy => "Too many calls running, try again once one answered".into(),
+        WorldError::Upload(e) => format!("The upload failed: {}", e),
+        WorldError::InvalidCursor => "Not a cursor of the list, start over".into(),
+        WorldError::PayloadTooLarge(max) => format!("Too large, at most {} bytes are taken", max),
+        WorldError::PermissionDenied(e) => format!("Not allowed: {}", e),
+    }
+}
+
+/// Text for a call made on `api` that failed in tarpc, which only tells the connection is gone,
+/// so the reason it closed is shown instead if it did.
+fn call_failed(api: &dyn WorldApi, e: RpcError) -> String {
+    match api.closed_error() {
+        Some(closed) => closed.to_string(),
+        None => e.to_string(),
+    }
+}
+
+/// What `Msg::UpdateEchoResult` shows once an echo made on `api` answered `echoed`.
+fn echo_result(
+    api: &dyn WorldApi,
+    echoed: Result<Result<String, WorldError>, RpcError>,
+) -> String {
+    match echoed {
+        Ok(Ok(msg)) => {
+            info!("Echo Success: Results {}", msg);
+            msg
+        }
+        Ok(Err(e)) => failed(&e),
+        Err(e) => format!("Echo failed: {}", call_failed(api, e)),
+    }
+}
+
 fn main() {
-    console_log::init_with_level(Level::Debug).unwrap();
+    // Tracing raises the level at runtime, see `Msg::ToggleTrace`.
+    console_log::init_with_level(Level::Trace).unwrap();
+    log::set_max_level(LevelFilter::Debug);
+    telemetry::init();
     yew::Renderer::<Model>::new().render();
 }
+
+#[cfg(all(test, feature = "test-util", not(target_arch = "wasm32")))]
+mod tests {
+    use client::mock::MockWorld;
+    use futures::executor::block_on;
+    use rpc::WorldResponse;
+
+    use super::*;
+
+    /// What the page shows for an echo of `value` made on `world`.
+    fn echo_shown(world: &MockWorld, value: &str) -> String {
+        let echoed = block_on(world.echo(context::current(), value.into()));
+        echo_result(world, echoed)
+    }
+
+    #[test]
+    fn echoes_show_what_the_mock_answered() {
+        let world = MockWorld::new();
+        world
+            .answer(WorldResponse::Echo(Ok("hi".into())))
+            .answer(WorldResponse::Echo(Err(WorldError::Busy)))
+            .fail("echo", RpcError::Disconnected);
+
+        assert_eq!(echo_shown(&world, "hi"), "hi");
+        assert_eq!(echo_shown(&world, "hi"), failed(&WorldError::Busy));
+        let disconnected = format!("Echo failed: {}", RpcError::Disconnected);
+        assert_eq!(echo_shown(&world, "hi"), disconnected);
+        assert_eq!(world.calls_to("echo"), 3);
+        world.assert_answered();
+    }
+}
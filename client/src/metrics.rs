@@ -0,0 +1,120 @@
+//! Latency of calls per method, as the client experiences it.
+//!
+//! [`Metrics`] is an [`Interceptor`]: added to an
+//! [`InterceptedWorldClient`](crate::intercept::InterceptedWorldClient) it records every call
+//! that completes. Successful calls go into a histogram to read percentiles from, calls that
+//! failed with an [`RpcError`] are only counted, so a spike of errors shows without skewing
+//! the latencies. Answers that are errors of the method itself count as successes, the call
+//! worked. Everything lives in an `Rc<RefCell<_>>` on the thread making the calls.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use rpc::WorldResponse;
+use tarpc::client::RpcError;
+
+use crate::intercept::{Call, Interceptor};
+
+/// Upper bounds of the histogram buckets in milliseconds, the last bucket takes the rest.
+const BUCKETS: [u64; 14] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 30000,
+];
+
+/// Latencies counted in buckets growing roughly exponentially.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS.len() + 1],
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let millis = latency.as_millis();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| millis <= u128::from(bound))
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+    }
+
+    /// Latencies recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Upper bound of the bucket the `q` quantile falls in, e.g. `0.95` for p95. `None` when
+    /// nothing was recorded, [`Duration::MAX`] when it is beyond the last bound.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(
+                    BUCKETS
+                        .get(bucket)
+                        .map_or(Duration::MAX, |&bound| Duration::from_millis(bound)),
+                );
+            }
+        }
+        Some(Duration::MAX)
+    }
+}
+
+/// What was recorded for one method.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MethodStats {
+    /// Latencies of the calls that completed.
+    pub latency: Histogram,
+    /// Calls that failed with an [`RpcError`].
+    pub failures: u64,
+}
+
+impl MethodStats {
+    pub fn successes(&self) -> u64 {
+        self.latency.count()
+    }
+}
+
+/// Per method stats of the calls it saw, shared by its clones.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    methods: Rc<RefCell<BTreeMap<&'static str, MethodStats>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, method: &'static str, completed: bool, latency: Duration) {
+        let mut methods = self.methods.borrow_mut();
+        let stats = methods.entry(method).or_default();
+        if completed {
+            stats.latency.record(latency);
+        } else {
+            stats.failures += 1;
+        }
+    }
+
+    /// The stats so far, by method name.
+    pub fn snapshot(&self) -> BTreeMap<&'static str, MethodStats> {
+        self.methods.borrow().clone()
+    }
+
+    /// Forgets everything recorded so far.
+    pub fn reset(&self) {
+        self.methods.borrow_mut().clear();
+    }
+}
+
+impl Interceptor for Metrics {
+    fn after(&self, call: &Call, outcome: &Result<WorldResponse, RpcError>, elapsed: Duration) {
+        self.record(call.method, outcome.is_ok(), elapsed);
+    }
+}
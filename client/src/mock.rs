@@ -0,0 +1,484 @@
+//! A [`WorldApi`] answering from a script, for testing code written against it without a
+//! server, see [`MockWorld`].
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+
+use async_trait::async_trait;
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, UploadResult, VersionInfo,
+    WorldError, WorldRequest, WorldResponse,
+};
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+
+use crate::api::WorldApi;
+use crate::intercept::method;
+
+#[derive(Default)]
+struct Script {
+    answers: HashMap<&'static str, VecDeque<Result<WorldResponse, RpcError>>>,
+    calls: Vec<WorldRequest>,
+}
+
+/// Answers every call with the next answer scripted for its method, and keeps the calls to
+/// check afterwards. A call with no answer left panics. Clones share the script, so a test
+/// can hand one to the code under test and keep another.
+#[derive(Clone, Default)]
+pub struct MockWorld {
+    script: Rc<RefCell<Script>>,
+}
+
+impl fmt::Debug for MockWorld {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let script = self.script.borrow();
+        f.debug_struct("MockWorld")
+            .field("calls", &script.calls)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MockWorld {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers the next call of the method `response` belongs to with it, after the answers
+    /// already scripted for that method.
+    pub fn answer(&self, response: WorldResponse) -> &Self {
+        self.push_answer(answered(&response), Ok(response))
+    }
+
+    /// Fails the next call of `method` with `error`, e.g. [`RpcError::Disconnected`].
+    pub fn fail(&self, method: &'static str, error: RpcError) -> &Self {
+        self.push_answer(method, Err(error))
+    }
+
+    fn push_answer(&self, method: &'static str, answer: Result<WorldResponse, RpcError>) -> &Self {
+        let mut script = self.script.borrow_mut();
+        script.answers.entry(method).or_default().push_back(answer);
+        self
+    }
+
+    /// The calls made so far, oldest first, leaving none behind.
+    pub fn take_calls(&self) -> Vec<WorldRequest> {
+        std::mem::take(&mut self.script.borrow_mut().calls)
+    }
+
+    /// How many calls of the method `name` were made so far.
+    pub fn calls_to(&self, name: &str) -> usize {
+        let script = self.script.borrow();
+        script
+            .calls
+            .iter()
+            .filter(|call| method(call) == name)
+            .count()
+    }
+
+    /// Panics unless a call with the method and arguments of `expected` was made.
+    pub fn assert_called_with(&self, expected: &WorldRequest) {
+        // Requests don't implement `PartialEq`, their serialized forms compare the same way.
+        let expected_json = serde_json::to_value(expected).unwrap();
+        let script = self.script.borrow();
+        assert!(
+            script
+                .calls
+                .iter()
+                .any(|call| serde_json::to_value(call).unwrap() == expected_json),
+            "{:?} wasn't called, the calls were {:?}",
+            expected,
+            script.calls
+        );
+    }
+
+    /// Panics if an answer was scripted but no call took it.
+    pub fn assert_answered(&self) {
+        let script = self.script.borrow();
+        for (method, answers) in &script.answers {
+            assert!(
+                answers.is_empty(),
+                "{} answers for {} were never taken",
+                answers.len(),
+                method
+            );
+        }
+    }
+
+    /// Keeps `request` and takes the next answer for its method.
+    fn call(&self, request: WorldRequest) -> Result<WorldResponse, RpcError> {
+        let method = method(&request);
+        let mut script = self.script.borrow_mut();
+        script.calls.push(request);
+        match script.answers.get_mut(method).and_then(VecDeque::pop_front) {
+            Some(answer) => answer,
+            None => panic!("no answer is scripted for the call of {}", method),
+        }
+    }
+}
+
+/// The method `response` answers.
+fn answered(response: &WorldResponse) -> &'static str {
+    match response {
+        WorldResponse::Ping(_) => "ping",
+        WorldResponse::Echo(_) => "echo",
+        WorldResponse::Delay(_) => "delay",
+        WorldResponse::PeerId(_) => "peer_id",
+        WorldResponse::Signal(_) => "signal",
+        WorldResponse::Signals(_) => "signals",
+        WorldResponse::Count(_) => "count",
+        WorldResponse::Subscribe(_) => "subscribe",
+        WorldResponse::Unsubscribe(_) => "unsubscribe",
+        WorldResponse::Publish(_) => "publish",
+        WorldResponse::DelayWithProgress(_) => "delay_with_progress",
+        WorldResponse::Whoami(_) => "whoami",
+        WorldResponse::ListClients(_) => "list_clients",
+        WorldResponse::UploadBegin(_) => "upload_begin",
+        WorldResponse::UploadChunk(_) => "upload_chunk",
+        WorldResponse::UploadFinish(_) => "upload_finish",
+        WorldResponse::ListItems(_) => "list_items",
+        WorldResponse::Increment(_) => "increment",
+        WorldResponse::GetCount(_) => "get_count",
+        WorldResponse::Version(_) => "version",
+        WorldResponse::EchoBytes(_) => "echo_bytes",
+        WorldResponse::CreateTask(_) => "create_task",
+        WorldResponse::LogEvent(_) => "log_event",
+    }
+}
+
+/// Answers are filed under the method of their response.
+fn mismatched(response: WorldResponse) -> ! {
+    unreachable!("got {:?} for another method", response)
+}
+
+#[async_trait(?Send)]
+impl WorldApi for MockWorld {
+    async fn ping(&self, _: Context) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(WorldRequest::Ping {})? {
+            WorldResponse::Ping(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn echo(
+        &self,
+        _: Context,
+        value: String,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(WorldRequest::Echo { value })? {
+            WorldResponse::Echo(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn delay(
+        &self,
+        _: Context,
+        millis: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(WorldRequest::Delay { millis })? {
+            WorldResponse::Delay(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn peer_id(&self, _: Context) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(WorldRequest::PeerId {})? {
+            WorldResponse::PeerId(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn signal(
+        &self,
+        _: Context,
+        to: u64,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(WorldRequest::Signal { to, payload })? {
+            WorldResponse::Signal(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn signals(
+        &self,
+        _: Context,
+    ) -> Result<Result<Vec<(u64, String)>, WorldError>, RpcError> {
+        match self.call(WorldRequest::Signals {})? {
+            WorldResponse::Signals(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn count(
+        &self,
+        _: Context,
+        stream: u64,
+        n: u64,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(WorldRequest::Count { stream, n })? {
+            WorldResponse::Count(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        _: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(WorldRequest::Subscribe { topic })? {
+            WorldResponse::Subscribe(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn unsubscribe(
+        &self,
+        _: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(WorldRequest::Unsubscribe { topic })? {
+            WorldResponse::Unsubscribe(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn publish(
+        &self,
+        _: Context,
+        topic: String,
+        payload: String,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(WorldRequest::Publish { topic, payload })? {
+            WorldResponse::Publish(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn delay_with_progress(
+        &self,
+        _: Context,
+        millis: u64,
+        progress: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(WorldRequest::DelayWithProgress { millis, progress })? {
+            WorldResponse::DelayWithProgress(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn whoami(&self, _: Context) -> Result<Result<SessionInfo, WorldError>, RpcError> {
+        match self.call(WorldRequest::Whoami {})? {
+            WorldResponse::Whoami(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn list_clients(
+        &self,
+        _: Context,
+    ) -> Result<Result<Vec<ClientInfo>, WorldError>, RpcError> {
+        match self.call(WorldRequest::ListClients {})? {
+            WorldResponse::ListClients(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_begin(
+        &self,
+        _: Context,
+        name: String,
+        size: u64,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(WorldRequest::UploadBegin { name, size })? {
+            WorldResponse::UploadBegin(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_chunk(
+        &self,
+        _: Context,
+        id: u64,
+        offset: u64,
+        bytes: Bytes,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(WorldRequest::UploadChunk { id, offset, bytes })? {
+            WorldResponse::UploadChunk(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_finish(
+        &self,
+        _: Context,
+        id: u64,
+        checksum: u32,
+    ) -> Result<Result<UploadResult, WorldError>, RpcError> {
+        match self.call(WorldRequest::UploadFinish { id, checksum })? {
+            WorldResponse::UploadFinish(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn list_items(
+        &self,
+        _: Context,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Result<Page, WorldError>, RpcError> {
+        match self.call(WorldRequest::ListItems { cursor, limit })? {
+            WorldResponse::ListItems(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn increment(&self, _: Context, by: i64) -> Result<Result<i64, WorldError>, RpcError> {
+        match self.call(WorldRequest::Increment { by })? {
+            WorldResponse::Increment(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn get_count(&self, _: Context) -> Result<Result<i64, WorldError>, RpcError> {
+        match self.call(WorldRequest::GetCount {})? {
+            WorldResponse::GetCount(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn version(&self, _: Context) -> Result<Result<VersionInfo, WorldError>, RpcError> {
+        match self.call(WorldRequest::Version {})? {
+            WorldResponse::Version(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn echo_bytes(
+        &self,
+        _: Context,
+        data: Bytes,
+    ) -> Result<Result<Bytes, WorldError>, RpcError> {
+        match self.call(WorldRequest::EchoBytes { data })? {
+            WorldResponse::EchoBytes(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn create_task(
+        &self,
+        _: Context,
+        request: CreateTaskRequest,
+    ) -> Result<Result<Task, WorldError>, RpcError> {
+        match self.call(WorldRequest::CreateTask { request })? {
+            WorldResponse::CreateTask(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn log_event(
+        &self,
+        _: Context,
+        name: String,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(WorldRequest::LogEvent { name, payload })? {
+            WorldResponse::LogEvent(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures::executor::block_on;
+    use tarpc::context;
+
+    use super::*;
+
+    fn echo(world: &MockWorld, value: &str) -> Result<Result<String, WorldError>, RpcError> {
+        block_on(world.echo(context::current(), value.into()))
+    }
+
+    #[test]
+    fn answers_come_in_the_order_scripted_for_each_method() {
+        let world = MockWorld::new();
+        world
+            .answer(WorldResponse::Echo(Ok("first".into())))
+            .answer(WorldResponse::Ping(Ok("pong".into())))
+            .answer(WorldResponse::Echo(Err(WorldError::Busy)));
+
+        assert_eq!(echo(&world, "a").unwrap(), Ok("first".into()));
+        assert_eq!(echo(&world, "b").unwrap(), Err(WorldError::Busy));
+        let pinged = block_on(world.ping(context::current())).unwrap();
+        assert_eq!(pinged, Ok("pong".into()));
+        world.assert_answered();
+    }
+
+    #[test]
+    fn failed_calls_fail_with_the_error_scripted() {
+        let world = MockWorld::new();
+        world
+            .fail("echo", RpcError::Disconnected)
+            .answer(WorldResponse::Echo(Ok("back".into())));
+
+        assert!(matches!(echo(&world, "a"), Err(RpcError::Disconnected)));
+        assert_eq!(echo(&world, "b").unwrap(), Ok("back".into()));
+    }
+
+    #[test]
+    fn calls_are_kept_until_taken() {
+        let world = MockWorld::new();
+        world
+            .answer(WorldResponse::Echo(Ok("a".into())))
+            .answer(WorldResponse::Ping(Ok("pong".into())))
+            .answer(WorldResponse::Echo(Ok("b".into())));
+        echo(&world, "a").unwrap().unwrap();
+        block_on(world.ping(context::current())).unwrap().unwrap();
+        echo(&world, "b").unwrap().unwrap();
+
+        assert_eq!(world.calls_to("echo"), 2);
+        assert_eq!(world.calls_to("ping"), 1);
+        assert_eq!(world.calls_to("delay"), 0);
+        world.assert_called_with(&WorldRequest::Echo { value: "b".into() });
+        let calls: Vec<_> = world.take_calls().iter().map(method).collect();
+        assert_eq!(calls, ["echo", "ping", "echo"]);
+        assert!(world.take_calls().is_empty());
+        assert_eq!(world.calls_to("echo"), 0);
+    }
+
+    #[test]
+    fn clones_share_the_script() {
+        let world = MockWorld::new();
+        let handed_out = world.clone();
+        world.answer(WorldResponse::Echo(Ok("a".into())));
+        echo(&handed_out, "a").unwrap().unwrap();
+        assert_eq!(world.calls_to("echo"), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "wasn't called")]
+    fn asserting_a_call_never_made_panics() {
+        let world = MockWorld::new();
+        world.answer(WorldResponse::Echo(Ok("a".into())));
+        echo(&world, "a").unwrap().unwrap();
+        world.assert_called_with(&WorldRequest::Echo { value: "b".into() });
+    }
+
+    #[test]
+    #[should_panic(expected = "never taken")]
+    fn answers_left_over_panic() {
+        let world = MockWorld::new();
+        world.answer(WorldResponse::Echo(Ok("a".into())));
+        world.assert_answered();
+    }
+
+    #[test]
+    #[should_panic(expected = "no answer is scripted")]
+    fn calls_without_an_answer_panic() {
+        echo(&MockWorld::new(), "a").ok();
+    }
+}
@@ -0,0 +1,468 @@
+//! Clients of several services on one connection, see [`Mux`].
+//!
+//! The connection carries [`ServiceRequest`]s, see [`rpc::mux`], while a generated tarpc client
+//! wants a transport of its own service's messages and numbers its requests on its own.
+//! [`Mux::open`] hands out such a [`ServiceTransport`] per client. The driver behind them
+//! renumbers the requests of every client in one sequence on the way out, so the server never
+//! sees an id twice, and gives each response to the transport whose request it answers, under
+//! the id that request had.
+//!
+//! A transport opened with [`Mux::open_with`] sends every request of its client with the same
+//! metadata, see [`rpc::metadata`].
+//!
+//! tarpc doesn't let its messages be built outside of it, so the driver sends [`MuxMessage`]s,
+//! which serialize the same way, and each transport turns the responses it gets back into its
+//! client's with [`mux::rebuild`].
+//!
+//! A request the server found no service for fails with [`RpcError::Server`], its kind
+//! [`io::ErrorKind::NotFound`].
+//!
+//! [`Mux::notify`] sends a request one-way, see [`rpc::oneway`]. It gets an id of the same
+//! sequence, but isn't kept, as no response comes for it.
+//!
+//! [`RpcError::Server`]: tarpc::client::RpcError::Server
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either};
+use futures::{ready, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use log::{debug, info};
+use rpc::metadata::{Metadata, MetadataError, RequestMetadata};
+use rpc::mux::{self, Routed, ServiceRequest, ServiceResponse};
+use rpc::Idempotent;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tarpc::trace;
+use tarpc::{context, ClientMessage, Response, ServerError, Transport};
+
+use crate::replay::{Replayable, Tracking};
+use crate::rpc_client::spawn;
+
+/// What tarpc sends [`io::ErrorKind::NotFound`] as.
+const NOT_FOUND: u32 = 0;
+
+/// What tarpc sends [`io::ErrorKind::InvalidData`] as.
+const INVALID_DATA: u32 = 12;
+
+/// Numbers the transports opened on a mux.
+type Route = u64;
+
+/// Where the driver sends the responses of each transport.
+type Routes = Rc<RefCell<HashMap<Route, mpsc::UnboundedSender<Response<ServiceResponse>>>>>;
+
+/// The messages of every service on the way to the driver.
+type Outgoing = mpsc::UnboundedSender<Queued>;
+
+/// A message for the driver to send.
+enum Queued {
+    /// A message of a service's client, with the transport it came from.
+    Message(Route, MuxMessage),
+    /// A request sent with [`Mux::notify`], and who waits for it to be sent.
+    OneWay(MuxRequest, oneshot::Sender<io::Result<()>>),
+}
+
+/// A `ClientMessage<ServiceRequest>` as the driver sends it, serialized like one.
+#[derive(Debug, Serialize)]
+pub enum MuxMessage {
+    Request(MuxRequest),
+    Cancel {
+        trace_context: trace::Context,
+        request_id: u64,
+    },
+}
+
+/// A `Request<ServiceRequest>`, see [`MuxMessage`].
+#[derive(Debug, Serialize)]
+pub struct MuxRequest {
+    context: context::Context,
+    id: u64,
+    message: ServiceRequest,
+    /// Sent with [`Mux::notify`], for the transport to send one-way.
+    #[serde(skip)]
+    one_way: bool,
+    /// For the transport to send next to the request.
+    #[serde(skip)]
+    metadata: Option<Metadata>,
+}
+
+impl Replayable for MuxMessage {
+    fn tracking(&self) -> Tracking {
+        match self {
+            MuxMessage::Request(request) => Tracking::Request {
+                id: request.id,
+                idempotent: request.message.idempotent(),
+            },
+            MuxMessage::Cancel { request_id, .. } => Tracking::Cancel(*request_id),
+        }
+    }
+
+    fn one_way(&self) -> bool {
+        matches!(self, MuxMessage::Request(request) if request.one_way)
+    }
+
+    fn take_metadata(&mut self) -> Option<RequestMetadata> {
+        match self {
+            MuxMessage::Request(request) => Some(RequestMetadata {
+                trace_id: *request.context.trace_id(),
+                metadata: request.metadata.take()?,
+            }),
+            MuxMessage::Cancel { .. } => None,
+        }
+    }
+}
+
+/// One connection shared by the clients of several services. Clones share it.
+#[derive(Clone)]
+pub struct Mux {
+    outgoing: Outgoing,
+    routes: Routes,
+    last_route: Rc<Cell<Route>>,
+}
+
+impl fmt::Debug for Mux {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mux")
+            .field("transports", &self.routes.borrow().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mux {
+    /// Takes over `transport` and spawns its driver, which runs until the connection ends or
+    /// the mux and every transport opened on it are dropped.
+    pub fn new<T>(transport: T) -> Self
+    where
+        T: Transport<MuxMessage, Response<ServiceResponse>> + 'static,
+    {
+        let (outgoing, requests) = mpsc::unbounded();
+        let routes = Routes::default();
+        spawn(drive(transport, requests, routes.clone()));
+        Self {
+            outgoing,
+            routes,
+            last_route: Rc::default(),
+        }
+    }
+
+    /// A transport for a client of `R`'s service, e.g. `mux.open::<WorldRequest>()` for a
+    /// `WorldClient`. A service can have several, each gets the responses to its own requests.
+    pub fn open<R: Routed>(&self) -> ServiceTransport<R> {
+        let route = self.last_route.get() + 1;
+        self.last_route.set(route);
+        let (responses, incoming) = mpsc::unbounded();
+        self.routes.borrow_mut().insert(route, responses);
+        ServiceTransport {
+            outgoing: self.outgoing.clone(),
+            incoming,
+            route,
+            routes: self.routes.clone(),
+            metadata: None,
+            service: PhantomData,
+        }
+    }
+
+    /// [`open`](Self::open), sending `metadata` with every request. Fails if the metadata is
+    /// too large.
+    pub fn open_with<R: Routed>(
+        &self,
+        metadata: Metadata,
+    ) -> Result<ServiceTransport<R>, MetadataError> {
+        metadata.check()?;
+        let mut transport = self.open();
+        transport.metadata = Some(metadata);
+        Ok(transport)
+    }
+
+    /// Sends `request` one-way, resolving once it was written to the connection rather than
+    /// once the server ran it. Fails if the connection is closed or writing fails.
+    pub async fn notify<R: Routed>(&self, ctx: context::Context, request: R) -> io::Result<()> {
+        let request = MuxRequest {
+            context: ctx,
+            // The driver numbers it.
+            id: 0,
+            message: request.into_request(),
+            one_way: true,
+            metadata: None,
+        };
+        let (sent, written) = oneshot::channel();
+        self.outgoing
+            .unbounded_send(Queued::OneWay(request, sent))
+            .map_err(|_| closed())?;
+        written.await.unwrap_or_else(|_| Err(closed()))
+    }
+}
+
+/// The messages of one service's client on a [`Mux`].
+pub struct ServiceTransport<R> {
+    outgoing: Outgoing,
+    incoming: mpsc::UnboundedReceiver<Response<ServiceResponse>>,
+    route: Route,
+    /// To take this transport's route out once it is dropped.
+    routes: Routes,
+    /// Sent with every request, see [`Mux::open_with`].
+    metadata: Option<Metadata>,
+    service: PhantomData<fn() -> R>,
+}
+
+impl<R> Drop for ServiceTransport<R> {
+    fn drop(&mut self) {
+        self.routes.borrow_mut().remove(&self.route);
+    }
+}
+
+impl<R: Routed> Sink<ClientMessage<R>> for ServiceTransport<R> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.outgoing.is_closed() {
+            return Poll::Ready(Err(closed()));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: ClientMessage<R>) -> io::Result<()> {
+        let message = match message {
+            ClientMessage::Request(request) => MuxMessage::Request(MuxRequest {
+                context: request.context,
+                id: request.id,
+                message: request.message.into_request(),
+                one_way: false,
+                metadata: self.metadata.clone(),
+            }),
+            ClientMessage::Cancel {
+                trace_context,
+                request_id,
+            } => MuxMessage::Cancel {
+                trace_context,
+                request_id,
+            },
+            // tarpc sends nothing else so far.
+            _ => return Ok(()),
+        };
+        self.outgoing
+            .unbounded_send(Queued::Message(self.route, message))
+            .map_err(|_| closed())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Only lets go of this service, the others keep the connection.
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().outgoing.disconnect();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R> Stream for ServiceTransport<R>
+where
+    R: Routed,
+    R::Response: Serialize + DeserializeOwned,
+{
+    type Item = io::Result<Response<R::Response>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Response {
+            request_id,
+            message,
+            ..
+        } = match ready!(self.get_mut().incoming.poll_next_unpin(cx)) {
+            Some(response) => response,
+            None => return Poll::Ready(None),
+        };
+        let response = match message.map(R::response) {
+            Ok(Ok(message)) => mux::rebuild(Answer::<_, ServerError> {
+                request_id,
+                message: Ok(message),
+            }),
+            Ok(Err(response)) => mux::rebuild(Answer::<R::Response, _> {
+                request_id,
+                message: Err(unanswered(response)),
+            }),
+            Err(e) => mux::rebuild(Answer::<R::Response, _> {
+                request_id,
+                message: Err(e),
+            }),
+        };
+        Poll::Ready(Some(response.map_err(io::Error::from)))
+    }
+}
+
+/// The fields of a tarpc `Response`, to [`mux::rebuild`] one from.
+#[derive(Serialize)]
+struct Answer<T, E> {
+    request_id: u64,
+    message: Result<T, E>,
+}
+
+/// The fields of a tarpc `ServerError`, its kind the number tarpc sends it as.
+#[derive(Serialize)]
+struct Failure {
+    kind: u32,
+    detail: String,
+}
+
+/// What a call fails with when `response`, of another service or none, came back instead.
+fn unanswered(response: ServiceResponse) -> Failure {
+    match response {
+        ServiceResponse::Unknown(id) => Failure {
+            kind: NOT_FOUND,
+            detail: format!("the server doesn't serve service {}", id),
+        },
+        _ => Failure {
+            kind: INVALID_DATA,
+            detail: "the server answered for another service".to_string(),
+        },
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the shared connection is closed")
+}
+
+/// Sends the messages of `requests` on `transport` and routes its responses, until either
+/// ends. The transports opened on the mux end with it.
+async fn drive<T>(
+    transport: T,
+    mut requests: mpsc::UnboundedReceiver<Queued>,
+    routes: Routes,
+) where
+    T: Transport<MuxMessage, Response<ServiceResponse>>,
+{
+    let (mut sink, mut responses) = transport.split();
+    let mut ids = Ids::default();
+    loop {
+        match future::select(requests.next(), responses.next()).await {
+            Either::Left((Some(queued), _)) => {
+                let mut written = Vec::new();
+                let sent = send_ready(&mut sink, queued, &mut requests, &mut ids, &mut written);
+                let result = sent.await.map_err(|e| e.to_string());
+                for sent in written {
+                    // Whoever sent it may have stopped waiting.
+                    let _ = sent.send(
+                        result
+                            .clone()
+                            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e)),
+                    );
+                }
+                if let Err(e) = result {
+                    info!("Sending on the shared connection failed: {}", e);
+                    break;
+                }
+            }
+            // The mux and every transport were dropped.
+            Either::Left((None, _)) => break,
+            Either::Right((Some(Ok(response)), _)) => ids.route(response, &routes),
+            Either::Right((Some(Err(e)), _)) => {
+                info!("Reading the shared connection failed: {}", e);
+                break;
+            }
+            Either::Right((None, _)) => break,
+        }
+    }
+    let _ = sink.close().await;
+    routes.borrow_mut().clear();
+}
+
+/// Feeds `queued` and every message `requests` has ready after it to `sink`, then flushes them
+/// in one go. Who waits for the one-way requests among them to be written ends up in `written`.
+async fn send_ready<S>(
+    sink: &mut S,
+    mut queued: Queued,
+    requests: &mut mpsc::UnboundedReceiver<Queued>,
+    ids: &mut Ids,
+    written: &mut Vec<oneshot::Sender<io::Result<()>>>,
+) -> Result<(), S::Error>
+where
+    S: Sink<MuxMessage> + Unpin,
+{
+    loop {
+        let message = match queued {
+            Queued::Message(route, message) => ids.sent(route, message),
+            Queued::OneWay(request, sent) => {
+                written.push(sent);
+                Some(ids.one_way(request))
+            }
+        };
+        if let Some(message) = message {
+            sink.feed(message).await?;
+        }
+        queued = match requests.next().now_or_never() {
+            Some(Some(queued)) => queued,
+            // Nothing else yet, or the end, which the driver sees next.
+            _ => break,
+        };
+    }
+    sink.flush().await
+}
+
+/// The ids the requests in flight were sent with, and those their clients gave them.
+#[derive(Default)]
+struct Ids {
+    last: u64,
+    /// The transport and client's id of each id sent.
+    sent: HashMap<u64, (Route, u64)>,
+    /// The id sent for each client's id, for cancelling.
+    by_client: HashMap<(Route, u64), u64>,
+}
+
+impl Ids {
+    /// `message` from `route` as it is sent, `None` for cancelling a request already answered.
+    fn sent(&mut self, route: Route, message: MuxMessage) -> Option<MuxMessage> {
+        match message {
+            MuxMessage::Request(mut request) => {
+                self.last += 1;
+                self.sent.insert(self.last, (route, request.id));
+                self.by_client.insert((route, request.id), self.last);
+                request.id = self.last;
+                Some(MuxMessage::Request(request))
+            }
+            MuxMessage::Cancel {
+                trace_context,
+                request_id,
+            } => {
+                let id = self.by_client.remove(&(route, request_id))?;
+                self.sent.remove(&id);
+                Some(MuxMessage::Cancel {
+                    trace_context,
+                    request_id: id,
+                })
+            }
+        }
+    }
+
+    /// `request`, sent with [`Mux::notify`], as it is sent. Nothing answers it, so it isn't
+    /// kept.
+    fn one_way(&mut self, mut request: MuxRequest) -> MuxMessage {
+        self.last += 1;
+        request.id = self.last;
+        MuxMessage::Request(request)
+    }
+
+    /// Gives `response` to the transport that sent its request, under the id its client gave
+    /// it.
+    fn route(&mut self, mut response: Response<ServiceResponse>, routes: &Routes) {
+        let (route, id) = match self.sent.remove(&response.request_id) {
+            Some(sent) => sent,
+            None => {
+                debug!("Dropping the response to cancelled request {}", response.request_id);
+                return;
+            }
+        };
+        self.by_client.remove(&(route, id));
+        response.request_id = id;
+        if let Some(responses) = routes.borrow().get(&route) {
+            // A client that is gone doesn't wait for it anymore.
+            let _ = responses.unbounded_send(response);
+        }
+    }
+}
@@ -0,0 +1,524 @@
+//! WebSocket transport outside the browser, behind the `native` feature.
+//!
+//! Speaks the same wire format through tokio-tungstenite and hands out the same
+//! [`WsTransport`] and [`TransportHandle`], so
+//! [`build_client`](crate::rpc_client::build_client) works unchanged in a desktop tool. Like in
+//! the browser everything stays on one thread: the driver is spawned with
+//! `tokio::task::spawn_local`, so the client and its tarpc dispatch have to run inside a
+//! `tokio::task::LocalSet`.
+//!
+//! Workers and the server-sent events fallback only exist in the browser, those
+//! [`ConnectOptions`] are ignored, while pools open their sockets here, see [`crate::pool`].
+//! Every message is flushed as it is sent, which makes `flush_threshold` moot.
+
+use std::borrow::Cow;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use futures::channel::{mpsc, oneshot};
+use futures::{select, stream, FutureExt, SinkExt, Stream, StreamExt};
+use log::info;
+use rpc::codec::Codec;
+use rpc::framing::FrameDecoder;
+use rpc::handshake::Hello;
+use rpc::Envelope;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use ws_stream_wasm::WsMessage;
+
+use crate::error::TransportError;
+use crate::transport::{
+    self, batch, close_handshake, deliver, first_answer, open_first, overtook_welcome, reconnect,
+    welcomed, wire_size, with_token, Answer, Auth, CloseInfo, ConnectOptions, Control, Ended,
+    Liveness, Shared, TransportHandle, WsTransport, ABNORMAL_CLOSURE,
+};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Close code reported for a close frame without a status.
+const NO_STATUS: u16 = 1005;
+
+/// Spawns the task opening the WebSocket at `url` and driving it on the current `LocalSet`.
+///
+/// Like in the browser the transport is handed out before the socket opened, see
+/// [`transport::connect`].
+pub async fn connect<Item, SinkItem>(
+    url: &str,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+{
+    connect_with_codec(url, options.frames, options).await
+}
+
+/// Like [`connect`], with messages serialized by `codec` rather than as `options.frames` says.
+pub async fn connect_with_codec<Item, SinkItem, C>(
+    url: &str,
+    codec: C,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem, C>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+    C: Codec + 'static,
+{
+    let heartbeat = transport::encode(&codec, &Envelope::<SinkItem>::Heartbeat, None)?;
+    let (transport, handle, ends) = transport::channels(codec.clone(), &options);
+    ends.shared.enable_replay(&options.replay);
+    let opened = ends.shared.start_opening();
+    let driver = Driver {
+        url: url.to_string(),
+        codec,
+        decoder: FrameDecoder::new(options.max_message_size),
+        options,
+        shared: ends.shared,
+        control: ends.control,
+        heartbeat,
+        incoming: ends.incoming,
+        outgoing: ends.outgoing,
+    };
+    tokio::task::spawn_local(driver.run(opened));
+    Ok((transport, handle))
+}
+
+async fn open(
+    url: &str,
+    codec: &impl Codec,
+    options: &ConnectOptions,
+) -> Result<Socket, TransportError> {
+    info!("Connecting to server: {}", url);
+    let requested = codec.protocol();
+    let connect_url = match &options.auth {
+        Some(Auth::Query(token)) => with_token(url, token),
+        _ => url.to_string(),
+    };
+    let mut request = connect_url
+        .into_client_request()
+        .map_err(|_| TransportError::InvalidUrl(url.to_string()))?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(requested),
+    );
+    let connecting = tokio_tungstenite::connect_async(request);
+    let connected = match options.connect_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, connecting).await {
+            Ok(connected) => connected,
+            Err(_) => {
+                info!("Connecting to {} timed out after {:?}", url, timeout);
+                return Err(TransportError::Timeout);
+            }
+        },
+        None => connecting.await,
+    };
+    let (mut socket, response) = connected.map_err(|e| {
+        info!("Errored on WebSocket connect\n{:?}", e);
+        handshake_failed(e)
+    })?;
+    // Servers that don't know about subprotocols accept the upgrade without picking one.
+    let selected = response
+        .headers()
+        .get("Sec-WebSocket-Protocol")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if selected != requested {
+        info!(
+            "Server selected subprotocol {:?} instead of {}",
+            selected, requested
+        );
+        let _ = socket.close(None).await;
+        return Err(TransportError::ProtocolMismatch {
+            requested: requested.to_string(),
+            selected,
+        });
+    }
+    if let Some(auth) = &options.auth {
+        if let Err(e) = authenticate(&mut socket, auth, codec, options).await {
+            let _ = socket.close(None).await;
+            return Err(e);
+        }
+    }
+    if options.version_check {
+        if let Err(e) = check_version(&mut socket, codec, options).await {
+            let _ = socket.close(None).await;
+            return Err(e);
+        }
+    }
+    Ok(socket)
+}
+
+fn handshake_failed(e: WsError) -> TransportError {
+    let reason = match e {
+        WsError::Http(response) => format!("server answered with {}", response.status()),
+        e => e.to_string(),
+    };
+    TransportError::HandshakeFailed {
+        code: ABNORMAL_CLOSURE,
+        reason,
+    }
+}
+
+/// Sends the token if it isn't in the URL and waits for the server to accept it, which it
+/// answers with [`Envelope::AuthAck`] before anything else.
+async fn authenticate(
+    socket: &mut Socket,
+    auth: &Auth,
+    codec: &impl Codec,
+    options: &ConnectOptions,
+) -> Result<(), TransportError> {
+    if let Auth::Message(token) = auth {
+        let message = transport::encode(codec, &Envelope::<()>::Auth(token.clone()), None)?;
+        send(socket, message).await?;
+    }
+    let answer = first_answer(next_answer(socket), codec, options, "token").await?;
+    if matches!(answer, Some(Envelope::AuthAck)) {
+        Ok(())
+    } else {
+        Err(TransportError::HandshakeFailed {
+            code: ABNORMAL_CLOSURE,
+            reason: "server didn't acknowledge the token".to_string(),
+        })
+    }
+}
+
+/// Sends our [`Hello`] and waits for the server to welcome it.
+async fn check_version(
+    socket: &mut Socket,
+    codec: &impl Codec,
+    options: &ConnectOptions,
+) -> Result<(), TransportError> {
+    let hello = Envelope::<()>::Hello(Hello::new(codec.protocol()));
+    send(socket, transport::encode(codec, &hello, None)?).await?;
+    let answer = loop {
+        let answer = first_answer(next_answer(socket), codec, options, "version check").await?;
+        if !overtook_welcome(&answer) {
+            break answer;
+        }
+    };
+    welcomed(answer)
+}
+
+async fn send(socket: &mut Socket, message: WsMessage) -> Result<(), TransportError> {
+    socket
+        .send(to_message(message))
+        .await
+        .map_err(|e| TransportError::from(CloseInfo::abnormal(e)))
+}
+
+/// What the server sends next on `socket`, for [`first_answer`].
+async fn next_answer(socket: &mut Socket) -> Answer {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => return Answer::Text(text),
+            Some(Ok(Message::Binary(bytes))) => return Answer::Frame(bytes),
+            Some(Ok(Message::Close(frame))) => return Answer::Closed(close_info(frame)),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Answer::Closed(CloseInfo::abnormal(e)),
+            None => return Answer::Closed(CloseInfo::abnormal("socket went away")),
+        }
+    }
+}
+
+fn to_message(frame: WsMessage) -> Message {
+    match frame {
+        WsMessage::Text(text) => Message::Text(text),
+        WsMessage::Binary(bytes) => Message::Binary(bytes),
+    }
+}
+
+fn close_info(frame: Option<CloseFrame<'_>>) -> CloseInfo {
+    match frame {
+        Some(frame) => CloseInfo {
+            code: frame.code.into(),
+            reason: frame.reason.into_owned(),
+            was_clean: true,
+        },
+        None => CloseInfo {
+            code: NO_STATUS,
+            reason: String::new(),
+            was_clean: true,
+        },
+    }
+}
+
+/// Owns the socket on behalf of a [`WsTransport`].
+struct Driver<Item, C> {
+    url: String,
+    codec: C,
+    options: ConnectOptions,
+    shared: Rc<Shared>,
+    control: mpsc::UnboundedReceiver<Control>,
+    /// Pre-encoded `Envelope::Heartbeat`.
+    heartbeat: WsMessage,
+    /// Reassembles binary frames.
+    decoder: FrameDecoder,
+    incoming: mpsc::Sender<Result<Item, TransportError>>,
+    outgoing: mpsc::UnboundedReceiver<WsMessage>,
+}
+
+impl<Item, C> Driver<Item, C>
+where
+    Item: DeserializeOwned,
+    C: Codec,
+{
+    async fn run(mut self, opened: oneshot::Sender<Result<(), TransportError>>) {
+        let opening = open(&self.url, &self.codec, &self.options);
+        let socket = open_first(&self.shared, &mut self.incoming, opened, opening).await;
+        if let Some(socket) = socket {
+            self.serve(socket).await;
+        }
+        self.shared.finish();
+    }
+
+    async fn serve(&mut self, mut socket: Socket) {
+        loop {
+            let info = match self.pump(&mut socket).await {
+                Ended::Local => {
+                    let _ = socket.close(None).await;
+                    return;
+                }
+                Ended::Closed(info) => {
+                    self.shared.closed(&info);
+                    return;
+                }
+                Ended::Remote(info) => {
+                    let _ = socket.close(None).await;
+                    info
+                }
+            };
+            info!("Connection to {} lost: {}", self.url, info);
+            let reconnected = match &self.options.reconnect {
+                Some(policy) => {
+                    reconnect(&self.url, policy, &self.shared, &self.incoming, || {
+                        open(&self.url, &self.codec, &self.options)
+                    })
+                    .await
+                }
+                None => None,
+            };
+            match reconnected {
+                Some(new_socket) => socket = new_socket,
+                None => {
+                    self.shared.closed(&info);
+                    // A full queue is fine to miss this on, tarpc sees the stream end either way.
+                    let _ = self.incoming.try_send(Err(info.into()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves frames between the socket and the transport until either side goes away.
+    async fn pump(&mut self, socket: &mut Socket) -> Ended {
+        let shared = self.shared.clone();
+        let threshold = self.options.flush_threshold;
+        let batching = self.options.batch.filter(|_| !self.codec.text());
+        self.decoder.clear();
+        let mut keepalive: Pin<Box<dyn Stream<Item = ()>>> = match self.options.keepalive {
+            Some(interval) => {
+                let start = tokio::time::Instant::now() + interval;
+                let mut ticks = tokio::time::interval_at(start, interval);
+                Box::pin(stream::poll_fn(move |cx| {
+                    ticks.poll_tick(cx).map(|_| Some(()))
+                }))
+            }
+            None => Box::pin(stream::pending()),
+        };
+        let mut liveness = Liveness::new(self.options.heartbeat_timeout);
+        for frame in shared.replayed() {
+            let size = wire_size(&frame);
+            if let Err(e) = socket.send(to_message(frame)).await {
+                return Ended::Remote(CloseInfo::abnormal(e));
+            }
+            shared.record_sent(size);
+        }
+        loop {
+            select! {
+                frame = socket.next().fuse() => {
+                    let frame = match frame {
+                        Some(Ok(Message::Text(text))) => WsMessage::Text(text),
+                        Some(Ok(Message::Binary(bytes))) => WsMessage::Binary(bytes),
+                        Some(Ok(Message::Close(frame))) => return Ended::Remote(close_info(frame)),
+                        // Pings are answered by tungstenite on the next read or write.
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Ended::Remote(CloseInfo::abnormal(e)),
+                        None => return Ended::Remote(CloseInfo::abnormal("socket went away")),
+                    };
+                    shared.record_received((&frame).into());
+                    liveness.received();
+                    let (decoder, incoming) = (&mut self.decoder, &mut self.incoming);
+                    let delivered = deliver(&self.codec, decoder, incoming, &self.options, frame);
+                    if let Err(ended) = delivered.await {
+                        return ended;
+                    }
+                },
+                frame = self.outgoing.next() => match frame {
+                    Some(frame) => {
+                        let (frame, messages) = batch(frame, &mut self.outgoing, batching.as_ref());
+                        let size = wire_size(&frame);
+                        let sent = socket.send(to_message(frame)).await;
+                        shared.queued.set(shared.queued.get().saturating_sub(messages));
+                        shared.written(messages);
+                        if let Err(e) = sent {
+                            return Ended::Remote(CloseInfo::abnormal(e));
+                        }
+                        shared.record_sent(size);
+                        if shared.flushed(threshold) {
+                            shared.wake_flush();
+                        }
+                    }
+                    None => return Ended::Local,
+                },
+                control = self.control.next() => match control {
+                    Some(Control::Close { code, reason, done }) => {
+                        let info = close(socket, code, &reason).await;
+                        let _ = done.send(Ok(()));
+                        return Ended::Closed(info);
+                    }
+                    None => {}
+                },
+                _ = keepalive.next().fuse() => {
+                    if let Err(e) = socket.send(to_message(self.heartbeat.clone())).await {
+                        info!("Failed to send heartbeat: {}", e);
+                        return Ended::Remote(CloseInfo::abnormal(e));
+                    }
+                    shared.record_sent(wire_size(&self.heartbeat));
+                    liveness.sent();
+                },
+                info = liveness.missed().fuse() => {
+                    if let Some(info) = info {
+                        return Ended::Remote(info);
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Runs the close handshake, see [`close_handshake`].
+async fn close(socket: &mut Socket, code: u16, reason: &str) -> CloseInfo {
+    let frame = CloseFrame {
+        code: CloseCode::from(code),
+        reason: Cow::Owned(reason.to_string()),
+    };
+    let handshake = async {
+        socket.close(Some(frame)).await.ok()?;
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Close(frame))) => return Some(close_info(frame)),
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => return None,
+            }
+        }
+    };
+    close_handshake(code, reason, handshake)
+        .await
+        .flatten()
+        .unwrap_or_else(|| CloseInfo::new(code, reason, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rpc::codec::Json;
+    use tarpc::{context, ClientMessage, Response};
+    use tokio::net::TcpListener;
+    use tokio::task::spawn_local;
+    use tokio_tungstenite::tungstenite::handshake::server::{
+        ErrorResponse, Request, Response as Upgrade,
+    };
+
+    use super::*;
+    use crate::testing::{self, run};
+    use crate::transport::ConnectionState;
+
+    /// A listener whose connections wait until the test accepts them, and the URL to it.
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    /// Accepts one WebSocket on `listener` in text mode and answers it like the servers of
+    /// [`testing`] do, without the version check.
+    async fn serve(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let agree = |_: &Request, mut upgrade: Upgrade| {
+            let protocol = HeaderValue::from_static(Json.protocol());
+            upgrade.headers_mut().insert("Sec-WebSocket-Protocol", protocol);
+            Ok::<_, ErrorResponse>(upgrade)
+        };
+        let mut socket = tokio_tungstenite::accept_hdr_async(stream, agree).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = socket.next().await {
+            let request = match Json.decode_text(&text) {
+                Ok(Envelope::Message(ClientMessage::Request(request)))
+                | Ok(Envelope::Tagged(ClientMessage::Request(request), _)) => request,
+                _ => continue,
+            };
+            let response = Response {
+                request_id: request.id,
+                message: Ok(testing::answer(request.message).await),
+            };
+            let frame = transport::encode(&Json, &Envelope::Message(response), None).unwrap();
+            socket.send(to_message(frame)).await.unwrap();
+        }
+    }
+
+    fn options() -> ConnectOptions {
+        ConnectOptions {
+            version_check: false,
+            ..ConnectOptions::default()
+        }
+    }
+
+    #[test]
+    fn a_ping_sent_before_the_socket_opened_is_answered_once_it_did() {
+        run(async {
+            let (listener, url) = listen().await;
+            let (transport, handle): (testing::Transport, _) =
+                connect_with_codec(&url, Json, options()).await.unwrap();
+            assert_eq!(handle.state(), ConnectionState::Connecting);
+            let client = testing::connect(transport);
+            let ping = spawn_local(async move { client.ping(context::current()).await });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!ping.is_finished(), "answered before the socket opened");
+            assert_eq!(handle.stats().messages_sent, 0);
+
+            spawn_local(serve(listener));
+            assert_eq!(ping.await.unwrap().unwrap(), Ok("pong".into()));
+            assert_eq!(handle.state(), ConnectionState::Connected);
+            assert!(handle.opened().await.is_ok());
+        });
+    }
+
+    #[test]
+    fn a_ping_sent_before_the_socket_failed_to_open_fails() {
+        run(async {
+            let (listener, url) = listen().await;
+            let (transport, handle): (testing::Transport, _) =
+                connect_with_codec(&url, Json, options()).await.unwrap();
+            let client = testing::connect(transport);
+            let ping = spawn_local(async move { client.ping(context::current()).await });
+
+            let (refused, _) = listener.accept().await.unwrap();
+            drop(refused);
+            let failed = tokio::time::timeout(Duration::from_secs(5), ping).await;
+            assert!(failed.unwrap().unwrap().is_err(), "the ping didn't fail");
+            match handle.opened().await {
+                Err(TransportError::HandshakeFailed { .. }) => {}
+                other => panic!("expected the handshake to fail, got {:?}", other),
+            }
+            assert!(matches!(handle.state(), ConnectionState::Closed(_)));
+        });
+    }
+}
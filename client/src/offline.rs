@@ -0,0 +1,509 @@
+//! Calls made while the connection is down, held until it is back, see
+//! [`QueueingWorldClient`].
+//!
+//! The transport already buffers what tarpc sends while reconnecting, but the calls keep their
+//! deadlines and fail once the network is down for longer. A queueing client doesn't hand
+//! such calls to tarpc at all: it keeps the requests the [`OfflinePolicy`] picks, and once the
+//! transport is connected again sends them in the order they were made, each with a fresh
+//! context that keeps the original trace id. Their futures resolve with the real answer.
+//!
+//! The queue is bounded, [`QueueOverflow`] says what happens past its capacity, and a call
+//! waits at most [`OfflinePolicy::max_offline`] before failing. Calls fail with
+//! [`RpcError::Disconnected`] in both cases, and when the connection closes for good. Calls
+//! the policy doesn't pick go to tarpc right away as usual.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use log::info;
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, UploadResult, VersionInfo,
+    WorldClient, WorldError, WorldRequest, WorldResponse,
+};
+use tarpc::client::RpcError;
+use tarpc::context::{self, Context};
+
+use crate::api::WorldApi;
+use crate::error::TransportError;
+use crate::intercept::{method, send};
+use crate::rpc_client::spawn;
+use crate::transport::{sleep, ConnectionState, TransportHandle};
+
+/// Calls a queue holds by default.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 32;
+
+/// How long a call waits for the connection by default.
+pub const DEFAULT_MAX_OFFLINE: Duration = Duration::from_secs(60);
+
+/// What a full queue does with another call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// The new call fails.
+    #[default]
+    RejectNew,
+    /// The oldest call waiting fails and the new one takes its place.
+    DropOldest,
+}
+
+/// Which calls wait for the connection, and for how long.
+#[derive(Clone, Debug)]
+pub struct OfflinePolicy {
+    /// Calls that may wait at once.
+    pub capacity: usize,
+    pub overflow: QueueOverflow,
+    /// A call fails once it waited this long.
+    pub max_offline: Duration,
+    /// Whether a call waits, the others are sent at once whatever the connection.
+    pub queued: fn(&WorldRequest) -> bool,
+}
+
+impl Default for OfflinePolicy {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            overflow: QueueOverflow::default(),
+            max_offline: DEFAULT_MAX_OFFLINE,
+            queued: answer_anywhere,
+        }
+    }
+}
+
+/// `ping`, `echo`, `echo_bytes`, `delay`, `publish` and `log_event`, whose answers don't
+/// depend on the connection they are made on. Peer ids, signals, streams and subscriptions
+/// belong to one connection.
+pub fn answer_anywhere(request: &WorldRequest) -> bool {
+    matches!(
+        request,
+        WorldRequest::Ping { .. }
+            | WorldRequest::Echo { .. }
+            | WorldRequest::EchoBytes { .. }
+            | WorldRequest::Delay { .. }
+            | WorldRequest::Publish { .. }
+            | WorldRequest::LogEvent { .. }
+    )
+}
+
+struct Waiting {
+    id: u64,
+    ctx: Context,
+    request: WorldRequest,
+    answer: oneshot::Sender<Result<WorldResponse, RpcError>>,
+}
+
+struct Queue {
+    client: WorldClient,
+    last_id: Cell<u64>,
+    waiting: RefCell<VecDeque<Waiting>>,
+}
+
+impl Queue {
+    /// Calls still waiting, forgetting the ones that gave up.
+    fn len(&self) -> usize {
+        let mut waiting = self.waiting.borrow_mut();
+        waiting.retain(|call| !call.answer.is_canceled());
+        waiting.len()
+    }
+
+    /// Fails call `id` if it is still waiting.
+    fn expire(&self, id: u64, waited: Duration) {
+        let mut waiting = self.waiting.borrow_mut();
+        if let Some(position) = waiting.iter().position(|call| call.id == id) {
+            info!("Gave up on a call after {:?} offline", waited);
+            waiting.remove(position);
+        }
+    }
+
+    /// Sends every call still waiting, in the order they were made.
+    fn flush(&self) {
+        let waiting = std::mem::take(&mut *self.waiting.borrow_mut());
+        if !waiting.is_empty() {
+            info!("Back online, sending {} queued calls", waiting.len());
+        }
+        for call in waiting {
+            if call.answer.is_canceled() {
+                continue;
+            }
+            let mut ctx = context::current();
+            ctx.trace_context = call.ctx.trace_context;
+            let client = self.client.clone();
+            // Tasks start in the order they are spawned, so the calls go out in this order.
+            spawn(async move {
+                let answer = send(&client, ctx, call.request).await;
+                let _ = call.answer.send(answer);
+            });
+        }
+    }
+}
+
+/// A [`WorldClient`] keeping calls while its connection is down, see the [module](self) docs.
+/// Its methods are those of [`WorldApi`]. Clones share the queue.
+#[derive(Clone)]
+pub struct QueueingWorldClient {
+    queue: Rc<Queue>,
+    transport: TransportHandle,
+    policy: OfflinePolicy,
+}
+
+impl fmt::Debug for QueueingWorldClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueingWorldClient")
+            .field("client", &self.queue.client)
+            .field("policy", &self.policy)
+            .field("queued", &self.queued())
+            .finish()
+    }
+}
+
+impl QueueingWorldClient {
+    /// Queues calls on `client` while `transport`, its connection, isn't connected.
+    pub fn new(client: WorldClient, transport: TransportHandle, policy: OfflinePolicy) -> Self {
+        let queue = Rc::new(Queue {
+            client,
+            last_id: Cell::new(0),
+            waiting: RefCell::default(),
+        });
+        // The transport outlives the clients, it mustn't keep the queue around.
+        let drain: Weak<Queue> = Rc::downgrade(&queue);
+        transport.on_state_change(move |state| {
+            if let Some(queue) = drain.upgrade() {
+                match state {
+                    ConnectionState::Connected => queue.flush(),
+                    // Dropping the senders fails the calls.
+                    ConnectionState::Closed(_) => queue.waiting.borrow_mut().clear(),
+                    _ => {}
+                }
+            }
+        });
+        Self {
+            queue,
+            transport,
+            policy,
+        }
+    }
+
+    pub fn inner(&self) -> &WorldClient {
+        &self.queue.client
+    }
+
+    /// Calls waiting for the connection.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Sends `request`, or queues it while the connection is down if the policy says so.
+    pub async fn call(
+        &self,
+        ctx: Context,
+        request: WorldRequest,
+    ) -> Result<WorldResponse, RpcError> {
+        let offline = !matches!(self.transport.state(), ConnectionState::Connected);
+        if !offline || !(self.policy.queued)(&request) {
+            return send(&self.queue.client, ctx, request).await;
+        }
+        if matches!(self.transport.state(), ConnectionState::Closed(_)) {
+            return Err(RpcError::Disconnected);
+        }
+        match self.enqueue(ctx, request) {
+            // A dropped sender means the queue failed the call.
+            Some(answer) => answer.await.unwrap_or(Err(RpcError::Disconnected)),
+            None => Err(RpcError::Disconnected),
+        }
+    }
+
+    /// Adds a call to the queue, `None` if it doesn't fit.
+    fn enqueue(
+        &self,
+        ctx: Context,
+        request: WorldRequest,
+    ) -> Option<oneshot::Receiver<Result<WorldResponse, RpcError>>> {
+        let full = self.queued() >= self.policy.capacity.max(1);
+        let mut waiting = self.queue.waiting.borrow_mut();
+        if full {
+            match self.policy.overflow {
+                QueueOverflow::RejectNew => {
+                    info!("The offline queue is full, failing {}", method(&request));
+                    return None;
+                }
+                // Dropping the sender fails the call.
+                QueueOverflow::DropOldest => drop(waiting.pop_front()),
+            }
+        }
+        let id = self.queue.last_id.get() + 1;
+        self.queue.last_id.set(id);
+        let (answer, answered) = oneshot::channel();
+        waiting.push_back(Waiting {
+            id,
+            ctx,
+            request,
+            answer,
+        });
+        let (queue, max_offline) = (Rc::downgrade(&self.queue), self.policy.max_offline);
+        spawn(async move {
+            sleep(max_offline).await;
+            if let Some(queue) = queue.upgrade() {
+                queue.expire(id, max_offline);
+            }
+        });
+        Some(answered)
+    }
+}
+
+#[async_trait(?Send)]
+impl WorldApi for QueueingWorldClient {
+    async fn ping(&self, ctx: Context) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Ping {}).await? {
+            WorldResponse::Ping(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn echo(
+        &self,
+        ctx: Context,
+        value: String,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Echo { value }).await? {
+            WorldResponse::Echo(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn delay(
+        &self,
+        ctx: Context,
+        millis: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Delay { millis }).await? {
+            WorldResponse::Delay(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn peer_id(&self, ctx: Context) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::PeerId {}).await? {
+            WorldResponse::PeerId(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn signal(
+        &self,
+        ctx: Context,
+        to: u64,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Signal { to, payload }).await? {
+            WorldResponse::Signal(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn signals(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<(u64, String)>, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Signals {}).await? {
+            WorldResponse::Signals(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn count(
+        &self,
+        ctx: Context,
+        stream: u64,
+        n: u64,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Count { stream, n }).await? {
+            WorldResponse::Count(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Subscribe { topic }).await? {
+            WorldResponse::Subscribe(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn unsubscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Unsubscribe { topic }).await? {
+            WorldResponse::Unsubscribe(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn publish(
+        &self,
+        ctx: Context,
+        topic: String,
+        payload: String,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        match self
+            .call(ctx, WorldRequest::Publish { topic, payload })
+            .await?
+        {
+            WorldResponse::Publish(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn delay_with_progress(
+        &self,
+        ctx: Context,
+        millis: u64,
+        progress: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        match self
+            .call(ctx, WorldRequest::DelayWithProgress { millis, progress })
+            .await?
+        {
+            WorldResponse::DelayWithProgress(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn whoami(&self, ctx: Context) -> Result<Result<SessionInfo, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Whoami {}).await? {
+            WorldResponse::Whoami(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn list_clients(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<ClientInfo>, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::ListClients {}).await? {
+            WorldResponse::ListClients(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_begin(
+        &self,
+        ctx: Context,
+        name: String,
+        size: u64,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadBegin { name, size }).await? {
+            WorldResponse::UploadBegin(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_chunk(
+        &self,
+        ctx: Context,
+        id: u64,
+        offset: u64,
+        bytes: Bytes,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadChunk { id, offset, bytes }).await? {
+            WorldResponse::UploadChunk(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn upload_finish(
+        &self,
+        ctx: Context,
+        id: u64,
+        checksum: u32,
+    ) -> Result<Result<UploadResult, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::UploadFinish { id, checksum }).await? {
+            WorldResponse::UploadFinish(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn list_items(
+        &self,
+        ctx: Context,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Result<Page, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::ListItems { cursor, limit }).await? {
+            WorldResponse::ListItems(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn increment(&self, ctx: Context, by: i64) -> Result<Result<i64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Increment { by }).await? {
+            WorldResponse::Increment(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn get_count(&self, ctx: Context) -> Result<Result<i64, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::GetCount {}).await? {
+            WorldResponse::GetCount(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn version(&self, ctx: Context) -> Result<Result<VersionInfo, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::Version {}).await? {
+            WorldResponse::Version(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn echo_bytes(
+        &self,
+        ctx: Context,
+        data: Bytes,
+    ) -> Result<Result<Bytes, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::EchoBytes { data }).await? {
+            WorldResponse::EchoBytes(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn create_task(
+        &self,
+        ctx: Context,
+        request: CreateTaskRequest,
+    ) -> Result<Result<Task, WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::CreateTask { request }).await? {
+            WorldResponse::CreateTask(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    async fn log_event(
+        &self,
+        ctx: Context,
+        name: String,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        match self.call(ctx, WorldRequest::LogEvent { name, payload }).await? {
+            WorldResponse::LogEvent(answer) => Ok(answer),
+            response => mismatched(response),
+        }
+    }
+
+    fn closed_error(&self) -> Option<TransportError> {
+        self.transport.closed_error()
+    }
+}
+
+/// tarpc answers a request with the response of its own method.
+fn mismatched(response: WorldResponse) -> ! {
+    unreachable!("got {:?} for another method", response)
+}
@@ -0,0 +1,44 @@
+//! Lists that come in pages, followed as one stream, see [`items`] and [`rpc::items`].
+
+use futures::{stream, Stream, StreamExt};
+use rpc::{Item, Page, WorldError};
+use tarpc::client::RpcError;
+use tarpc::context;
+
+use crate::api::WorldApi;
+
+/// The pages of `list_items` through `client`, `limit` items each, from the first to the
+/// last. Every page is its own call; the stream ends after the first that failed.
+pub fn pages(
+    client: &dyn WorldApi,
+    limit: u32,
+) -> impl Stream<Item = Result<Result<Page, WorldError>, RpcError>> + '_ {
+    // `None` once there is no page left to get, `Some(None)` for the first.
+    stream::unfold(
+        Some(None),
+        move |cursor: Option<Option<String>>| async move {
+            let answer = client.list_items(context::current(), cursor?, limit).await;
+            let next = match &answer {
+                Ok(Ok(page)) => page.next_cursor.clone().map(Some),
+                _ => None,
+            };
+            Some((answer, next))
+        },
+    )
+}
+
+/// Every item of `list_items` through `client`, getting pages of `limit` items as the stream
+/// is read. A failed call is the last item.
+pub fn items(
+    client: &dyn WorldApi,
+    limit: u32,
+) -> impl Stream<Item = Result<Result<Item, WorldError>, RpcError>> + '_ {
+    pages(client, limit).flat_map(|answer| {
+        let items: Vec<_> = match answer {
+            Ok(Ok(page)) => page.items.into_iter().map(|item| Ok(Ok(item))).collect(),
+            Ok(Err(e)) => vec![Ok(Err(e))],
+            Err(e) => vec![Err(e)],
+        };
+        stream::iter(items)
+    })
+}
@@ -0,0 +1,358 @@
+//! Several WebSockets to the same server behind one transport.
+//!
+//! Requests go out over the connected sockets in turn and responses from all of them are
+//! merged into one stream, so a large response on one socket doesn't hold up the others. A
+//! socket that goes away takes only its own in-flight requests with it: new requests go to
+//! the remaining sockets while it reconnects following [`ConnectOptions::reconnect`], and the
+//! pool only closes once every socket did.
+//!
+//! The server sees every socket as a connection of its own, so per-connection state like
+//! `peer_id` differs between requests.
+//!
+//! Outside the browser the sockets are those of [`crate::native`], e.g. for
+//! `cargo bench --package client --bench pool --features native`.
+
+use std::rc::Rc;
+
+use futures::channel::mpsc;
+use futures::future;
+use futures::stream::{self, SelectAll};
+use futures::{select, SinkExt, StreamExt};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+use tokio::task::spawn_local;
+#[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+use wasm_bindgen_futures::spawn_local;
+use ws_stream_wasm::WsMessage;
+
+use crate::error::TransportError;
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+use crate::native::connect as connect_member;
+#[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+use crate::transport::connect as connect_member;
+use crate::transport::{
+    self, CloseInfo, ConnectOptions, ConnectionState, Control, FrameSink, Shared, TransportHandle,
+    WsTransport,
+};
+
+/// Opens [`ConnectOptions::pool_size`] WebSockets to `url`, failing only if none of them
+/// opens.
+pub async fn connect<Item, SinkItem>(
+    url: &str,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+{
+    let size = options.pool_size.max(1);
+    info!("Opening {} connections to {}", size, url);
+    let opening = (0..size).map(|_| async {
+        let (member, handle) = connect_member::<Item, SinkItem>(url, options.clone()).await?;
+        handle.opened().await?;
+        Ok::<_, TransportError>((member, handle))
+    });
+    let mut opened = Vec::with_capacity(size);
+    let mut failure = None;
+    for result in future::join_all(opening).await {
+        match result {
+            Ok(member) => opened.push(member),
+            Err(e) => {
+                info!("Failed to open a pooled connection: {}", e);
+                failure = Some(e);
+            }
+        }
+    }
+    if opened.is_empty() {
+        return Err(failure.unwrap_or(TransportError::Timeout));
+    }
+    Ok(pool(opened, &options))
+}
+
+/// One transport over the members `opened` already, its driver spawned.
+fn pool<Item, SinkItem, C>(
+    opened: Vec<(WsTransport<Item, SinkItem, C>, TransportHandle)>,
+    options: &ConnectOptions,
+) -> (WsTransport<Item, SinkItem>, TransportHandle)
+where
+    Item: 'static,
+{
+    let (transport, handle, ends) = transport::channels(options.frames, options);
+    let (changes_tx, changes) = mpsc::unbounded();
+    let mut members = Vec::with_capacity(opened.len());
+    let mut responses = stream::select_all(Vec::new());
+    for (member, member_handle) in opened {
+        let changes_tx = changes_tx.clone();
+        member_handle.on_state_change(move |_| {
+            let _ = changes_tx.unbounded_send(());
+        });
+        let (incoming, sink) = member.split();
+        ends.shared.adopt(sink.shared.clone());
+        responses.push(incoming);
+        members.push(Member {
+            handle: member_handle,
+            sink,
+        });
+    }
+    let driver = Driver {
+        members,
+        next: 0,
+        shared: ends.shared,
+        control: ends.control,
+        changes,
+        incoming: ends.incoming,
+        outgoing: ends.outgoing,
+    };
+    spawn_local(driver.run(responses));
+    (transport, handle)
+}
+
+struct Member {
+    handle: TransportHandle,
+    sink: FrameSink,
+}
+
+/// What the members received, in the order it arrived.
+type Responses<Item> = SelectAll<mpsc::Receiver<Result<Item, TransportError>>>;
+
+/// Spreads the messages of a [`WsTransport`] over the pooled connections.
+struct Driver<Item> {
+    members: Vec<Member>,
+    /// Where round robin continues.
+    next: usize,
+    shared: Rc<Shared>,
+    control: mpsc::UnboundedReceiver<Control>,
+    /// A member's state changed.
+    changes: mpsc::UnboundedReceiver<()>,
+    incoming: mpsc::Sender<Result<Item, TransportError>>,
+    outgoing: mpsc::UnboundedReceiver<WsMessage>,
+}
+
+impl<Item> Driver<Item> {
+    async fn run(mut self, responses: Responses<Item>) {
+        self.serve(responses).await;
+        self.shared.finish();
+    }
+
+    async fn serve(&mut self, mut responses: Responses<Item>) {
+        loop {
+            select! {
+                frame = self.outgoing.next() => match frame {
+                    Some(frame) => {
+                        self.dispatch(frame);
+                        self.shared.queued.set(self.shared.queued.get().saturating_sub(1));
+                        self.shared.wake_flush();
+                    }
+                    // Members close once they sent what they were given.
+                    None => {
+                        for member in &self.members {
+                            member.sink.close();
+                        }
+                        return;
+                    }
+                },
+                response = responses.next() => match response {
+                    // A member that closed for good, the pool only closes with the last one.
+                    Some(Err(
+                        TransportError::ConnectionClosed { .. }
+                        | TransportError::AbnormalClosure { .. }
+                        | TransportError::HeartbeatTimeout(_),
+                    )) => {}
+                    Some(response) => {
+                        // Nobody reads the pool anymore.
+                        let sent = self.incoming.send(response).await;
+                        if sent.is_err() {
+                            return;
+                        }
+                    }
+                    None => {}
+                },
+                control = self.control.next() => match control {
+                    Some(Control::Close { code, reason, done }) => {
+                        let closing = self.members.iter().map(|member| {
+                            member.handle.close(code, &reason)
+                        });
+                        let result = future::join_all(closing)
+                            .await
+                            .into_iter()
+                            .collect::<Result<Vec<_>, _>>()
+                            .map(|_| ());
+                        let closed = result.is_ok();
+                        let _ = done.send(result);
+                        if closed {
+                            self.shared.closed(&CloseInfo {
+                                code,
+                                reason,
+                                was_clean: true,
+                            });
+                            return;
+                        }
+                    }
+                    None => {}
+                },
+                _ = self.changes.next() => {
+                    if let ConnectionState::Closed(info) = self.state() {
+                        info!("Every pooled connection is closed");
+                        self.shared.closed(&info);
+                        let _ = self.incoming.try_send(Err(info.into()));
+                        return;
+                    }
+                    self.shared.set_state(self.state());
+                },
+            }
+        }
+    }
+
+    /// Hands `frame` to the next connected member, or to one that is reconnecting and
+    /// queues it meanwhile.
+    fn dispatch(&mut self, mut frame: WsMessage) {
+        let count = self.members.len();
+        let order = (0..count).map(|i| (self.next + i) % count);
+        let (connected, others): (Vec<usize>, Vec<usize>) = order
+            .filter(|&i| !matches!(self.members[i].handle.state(), ConnectionState::Closed(_)))
+            .partition(|&i| self.members[i].handle.state() == ConnectionState::Connected);
+        for i in connected.into_iter().chain(others) {
+            match self.members[i].sink.send(frame) {
+                Ok(()) => {
+                    self.next = (i + 1) % count;
+                    return;
+                }
+                Err(returned) => frame = returned,
+            }
+        }
+        // The pool closes as soon as the change of the last member comes in.
+        info!("No pooled connection left to send a message over");
+    }
+
+    /// Connected while any member is, closed once all are.
+    fn state(&self) -> ConnectionState {
+        let states: Vec<ConnectionState> = self
+            .members
+            .iter()
+            .map(|member| member.handle.state())
+            .collect();
+        if states.contains(&ConnectionState::Connected) {
+            return ConnectionState::Connected;
+        }
+        if let Some(attempt) = states
+            .iter()
+            .filter_map(|state| match state {
+                ConnectionState::Reconnecting { attempt } => Some(*attempt),
+                _ => None,
+            })
+            .min()
+        {
+            return ConnectionState::Reconnecting { attempt };
+        }
+        if states.contains(&ConnectionState::Connecting) {
+            return ConnectionState::Connecting;
+        }
+        match states.into_iter().last() {
+            Some(closed @ ConnectionState::Closed(_)) => closed,
+            _ => ConnectionState::Connecting,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "native", not(target_arch = "wasm32")))]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use rpc::WorldClient;
+    use tarpc::client::RpcError;
+    use tarpc::context::{self, Context};
+
+    use super::*;
+    use crate::deadline::with_deadline;
+    use crate::testing::{self, connect, run, until, Restartable};
+
+    /// A client of a pool with a member on each of `servers`, the pool's handle and those of
+    /// its members.
+    async fn pooled(
+        servers: &[Restartable],
+    ) -> (WorldClient, TransportHandle, Vec<TransportHandle>) {
+        let mut opened = Vec::new();
+        for server in servers {
+            let (member, handle) = testing::transport(server, ConnectOptions::default());
+            until(&handle, |state| *state == ConnectionState::Connected).await;
+            opened.push((member, handle));
+        }
+        let members = opened.iter().map(|(_, handle)| handle.clone()).collect();
+        let (transport, handle) = pool(opened, &ConnectOptions::default());
+        (connect(transport), handle, members)
+    }
+
+    /// A context whose calls a member that went away can't hold up the test for long.
+    fn soon() -> Context {
+        let mut ctx = context::current();
+        ctx.deadline = SystemTime::now() + Duration::from_millis(500);
+        ctx
+    }
+
+    fn reconnecting(state: &ConnectionState) -> bool {
+        matches!(state, ConnectionState::Reconnecting { .. })
+    }
+
+    #[test]
+    fn calls_on_the_other_members_are_answered_when_one_goes_away() {
+        run(async {
+            let servers = [Restartable::up(), Restartable::up(), Restartable::up()];
+            let (client, handle, members) = pooled(&servers).await;
+            // Two calls for each member in turn, all still running when the second one's
+            // server goes away.
+            let burst: Vec<_> = (0..6)
+                .map(|_| {
+                    let client = client.clone();
+                    spawn_local(async move {
+                        let ctx = soon();
+                        with_deadline(&ctx, client.delay(ctx, 50)).await
+                    })
+                })
+                .collect();
+            transport::sleep(Duration::from_millis(10)).await;
+            servers[1].stop();
+
+            let mut answered = 0;
+            for call in burst {
+                match call.await.unwrap() {
+                    Ok(waited) => {
+                        assert_eq!(waited, Ok("waited 50ms".into()));
+                        answered += 1;
+                    }
+                    Err(e) => assert!(matches!(e, RpcError::DeadlineExceeded), "{:?}", e),
+                }
+            }
+            assert_eq!(answered, 4);
+
+            until(&members[1], reconnecting).await;
+            assert_eq!(handle.state(), ConnectionState::Connected);
+            for value in ["a", "b", "c", "d"] {
+                let ctx = soon();
+                let echoed = with_deadline(&ctx, client.echo(ctx, value.into())).await;
+                assert_eq!(echoed.unwrap(), Ok(value.into()));
+            }
+        });
+    }
+
+    #[test]
+    fn the_pool_reconnects_only_once_every_member_does() {
+        run(async {
+            let servers = [Restartable::up(), Restartable::up()];
+            let (client, handle, members) = pooled(&servers).await;
+            servers[0].stop();
+            until(&members[0], reconnecting).await;
+            assert_eq!(handle.state(), ConnectionState::Connected);
+
+            servers[1].stop();
+            until(&handle, reconnecting).await;
+            servers[0].start();
+            until(&handle, |state| *state == ConnectionState::Connected).await;
+            assert!(reconnecting(&members[1].state()), "{}", members[1].state());
+            let ctx = soon();
+            let echoed = with_deadline(&ctx, client.echo(ctx, "back".into())).await;
+            assert_eq!(echoed.unwrap(), Ok("back".into()));
+        });
+    }
+}
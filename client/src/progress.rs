@@ -0,0 +1,78 @@
+//! The progress a long call reports while the client waits for its answer, see
+//! [`with_progress`] and [`rpc::progress`].
+
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::{Stream, StreamExt};
+use log::info;
+use rpc::progress::Progress;
+use rpc::stream::{StreamReceiver, StreamRouter};
+
+/// Opens a stream on `streams` for the progress of a call, and makes the call with its id,
+/// e.g. `with_progress(&streams, |progress| client.delay_with_progress(ctx, 30_000, progress))`.
+///
+/// Returns the call, which resolves with its answer as usual, and the reports it makes while
+/// it runs. Reports arriving once the call resolved are dropped, so the stream ends with it at
+/// the latest.
+pub fn with_progress<F, T>(
+    streams: &StreamRouter,
+    call: impl FnOnce(u64) -> F,
+) -> (impl Future<Output = T>, ProgressReports)
+where
+    F: Future<Output = T>,
+{
+    let (id, reports) = streams.open();
+    let answered = Rc::new(Cell::new(false));
+    let reports = ProgressReports {
+        reports,
+        answered: answered.clone(),
+    };
+    let call = call(id);
+    let call = async move {
+        let answer = call.await;
+        answered.set(true);
+        answer
+    };
+    (call, reports)
+}
+
+/// What a call reported, ending once it is answered, see [`with_progress`]. Reports that
+/// aren't a [`Progress`] end it too.
+pub struct ProgressReports {
+    reports: StreamReceiver<Progress>,
+    answered: Rc<Cell<bool>>,
+}
+
+impl fmt::Debug for ProgressReports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressReports")
+            .field("answered", &self.answered.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Stream for ProgressReports {
+    type Item = Progress;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Progress>> {
+        if self.answered.get() {
+            return Poll::Ready(None);
+        }
+        match self.reports.poll_next_unpin(cx) {
+            // The answer may have overtaken the report.
+            Poll::Ready(Some(Ok(_))) if self.answered.get() => Poll::Ready(None),
+            Poll::Ready(Some(Ok(progress))) => Poll::Ready(Some(progress)),
+            Poll::Ready(Some(Err(e))) => {
+                info!("No more progress: {}", e);
+                Poll::Ready(None)
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Notifications the server pushes outside of any request, see [`rpc::push`].
+//!
+//! The transport hands every [`Notification`] it receives to the [`Notifications`] of
+//! [`ConnectOptions::notifications`](crate::transport::ConnectOptions::notifications), which
+//! copies it to each subscriber. Subscribers get a bounded queue: one that doesn't keep up
+//! misses notifications rather than holding up the connection's responses. Nothing is kept
+//! for later subscribers, nor across reconnects. Subscriptions end once every transport
+//! feeding them ended.
+//!
+//! [`subscribe`] asks the server for the messages of a topic others publish to, and reads
+//! them as a [`Topic`] of typed messages. The server forgets the subscription once the
+//! connection is lost, so the topic ends then, even if the transport reconnects.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use log::info;
+use rpc::push::Notification;
+use rpc::{WorldClient, WorldError};
+use serde::de::DeserializeOwned;
+use tarpc::client::RpcError;
+use tarpc::context;
+
+use crate::transport::TransportHandle;
+
+/// Notifications a subscriber may have queued before new ones are dropped.
+pub const DEFAULT_SUBSCRIBER_CAPACITY: usize = 32;
+
+/// The subscribers to a connection's notifications, shared by its clones. Pools and
+/// reconnects feed the same subscribers as long as they are built from the same options.
+#[derive(Clone, Default)]
+pub struct Notifications {
+    subscribers: Rc<RefCell<Vec<Subscriber>>>,
+    /// Transports feeding the subscribers that haven't ended yet.
+    transports: Rc<Cell<usize>>,
+}
+
+struct Subscriber {
+    queue: mpsc::Sender<Notification>,
+    /// Ends once a connection is lost, reconnecting or not, see [`Topic`].
+    per_connection: bool,
+}
+
+impl fmt::Debug for Notifications {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notifications")
+            .field("subscribers", &self.subscribers.borrow().len())
+            .finish()
+    }
+}
+
+impl Notifications {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The notifications received from now on, until every transport feeding them ended.
+    /// Dropping the stream unsubscribes.
+    pub fn subscribe(&self) -> mpsc::Receiver<Notification> {
+        self.add(false)
+    }
+
+    /// Like [`subscribe`](Self::subscribe), ending as well once a connection is lost.
+    pub(crate) fn subscribe_connection(&self) -> mpsc::Receiver<Notification> {
+        self.add(true)
+    }
+
+    fn add(&self, per_connection: bool) -> mpsc::Receiver<Notification> {
+        let (queue, notifications) = mpsc::channel(DEFAULT_SUBSCRIBER_CAPACITY);
+        self.subscribers.borrow_mut().push(Subscriber {
+            queue,
+            per_connection,
+        });
+        notifications
+    }
+
+    /// A transport started feeding the subscribers.
+    pub(crate) fn attach(&self) {
+        self.transports.set(self.transports.get() + 1);
+    }
+
+    /// A transport ended for good. Once it was the last, every subscription ends.
+    pub(crate) fn detach(&self) {
+        let transports = self.transports.get().saturating_sub(1);
+        self.transports.set(transports);
+        if transports == 0 {
+            self.subscribers.borrow_mut().clear();
+        }
+    }
+
+    /// A connection was lost, which ends the subscriptions the server forgets with it.
+    pub(crate) fn connection_lost(&self) {
+        self.subscribers
+            .borrow_mut()
+            .retain(|subscriber| !subscriber.per_connection);
+    }
+
+    /// Hands `notification` to every subscriber with room for it.
+    pub(crate) fn deliver(&self, notification: Notification) {
+        self.subscribers.borrow_mut().retain_mut(|subscriber| {
+            match subscriber.queue.try_send(notification.clone()) {
+                Ok(()) => true,
+                Err(e) if e.is_full() => {
+                    info!(
+                        "A subscriber is behind, dropping {} for it",
+                        notification.topic
+                    );
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+    }
+}
+
+/// Subscribes the connection of `handle` and `client` to `topic`. The server sends its
+/// messages until [`Topic::unsubscribe`] or until the connection is lost, which ends the
+/// topic; reconnecting doesn't subscribe again. Messages that arrive while the subscription is
+/// still being answered are kept.
+pub async fn subscribe<T: DeserializeOwned>(
+    client: &WorldClient,
+    handle: &TransportHandle,
+    ctx: context::Context,
+    topic: &str,
+) -> Result<Result<Topic<T>, WorldError>, RpcError> {
+    let messages = Topic {
+        name: topic.to_string(),
+        notifications: handle.connection_notifications(),
+        _message: PhantomData,
+    };
+    Ok(client
+        .subscribe(ctx, topic.to_string())
+        .await?
+        .map(|()| messages))
+}
+
+/// The messages published to a topic, see [`subscribe`]. Ends once the connection is lost or
+/// the transport ended, subscribe again to read on after a reconnect.
+pub struct Topic<T> {
+    name: String,
+    notifications: mpsc::Receiver<Notification>,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for Topic<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Topic")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Topic<T> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Tells the server to stop sending the topic's messages. Dropping the topic only stops
+    /// reading them.
+    pub async fn unsubscribe(
+        self,
+        client: &WorldClient,
+        ctx: context::Context,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        client.unsubscribe(ctx, self.name).await
+    }
+}
+
+impl<T: DeserializeOwned> Stream for Topic<T> {
+    /// A message that isn't the JSON of a `T` is an error, the next one may be fine again.
+    type Item = Result<T, serde_json::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.notifications.poll_next_unpin(cx) {
+                Poll::Ready(Some(notification)) if notification.topic == self.name => {
+                    return Poll::Ready(Some(notification.payload()))
+                }
+                Poll::Ready(Some(_)) => continue,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "native", not(target_arch = "wasm32")))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{self, connect, run, Restartable};
+    use crate::transport::{ConnectOptions, ConnectionState};
+
+    /// The topic `news` of a client of `server`, and the client with its handle.
+    async fn news(server: &Restartable) -> (Topic<String>, WorldClient, TransportHandle) {
+        let (transport, handle) = testing::transport(server, ConnectOptions::default());
+        let client = connect(transport);
+        let topic = subscribe(&client, &handle, context::current(), "news").await;
+        (topic.unwrap().unwrap(), client, handle)
+    }
+
+    /// What `topic` yields next, failing the test if that takes too long.
+    async fn next(topic: &mut Topic<String>) -> Option<Result<String, serde_json::Error>> {
+        tokio::time::timeout(Duration::from_secs(5), topic.next())
+            .await
+            .expect("the topic didn't end")
+    }
+
+    #[test]
+    fn a_topic_ends_with_the_transport() {
+        run(async {
+            let server = Restartable::up();
+            let (mut topic, client, handle) = news(&server).await;
+            let mut notifications = handle.notifications();
+
+            // The last client takes the dispatch and with it the transport along.
+            drop(client);
+            assert!(next(&mut topic).await.is_none());
+            assert!(notifications.next().await.is_none());
+            assert!(matches!(handle.state(), ConnectionState::Closed(_)));
+        });
+    }
+
+    #[test]
+    fn a_topic_ends_once_the_connection_is_lost() {
+        run(async {
+            let server = Restartable::up();
+            let (mut topic, _client, handle) = news(&server).await;
+            let mut notifications = handle.notifications();
+
+            server.stop();
+            assert!(next(&mut topic).await.is_none());
+            assert!(matches!(handle.state(), ConnectionState::Reconnecting { .. }));
+            // Notifications that aren't subscribed to keep coming across the reconnect.
+            assert!(notifications.try_next().is_err(), "ended along");
+        });
+    }
+}
@@ -0,0 +1,171 @@
+//! Sending requests again that were in flight when the connection dropped, see
+//! [`ConnectOptions::replay`](crate::transport::ConnectOptions::replay).
+//!
+//! The sink keeps the frames of requests tarpc is still waiting for. Once the driver has
+//! reconnected, it sends the ones that already went out on the old socket again, with the same
+//! request ids and before anything queued meanwhile. The server can't tell a replayed request
+//! from a new one, so a request is only kept if its [`Idempotent`] implementation says running
+//! it twice is harmless. If the server answered just before the connection dropped, the
+//! response to the replayed request is a duplicate and doesn't reach tarpc.
+//!
+//! Only the WebSocket drivers of [`crate::transport`] and `native` replay. Pools, workers,
+//! WebTransport and the server-sent events fallback ignore the option.
+
+use std::collections::VecDeque;
+
+use log::info;
+use rpc::metadata::RequestMetadata;
+use rpc::Idempotent;
+use tarpc::{ClientMessage, Response};
+use ws_stream_wasm::WsMessage;
+
+/// Requests kept for replaying by default.
+pub const DEFAULT_MAX_REPLAYED: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct ReplayPolicy {
+    /// Requests kept for replaying at most. While that many are waiting for a response, further
+    /// requests are sent without being kept.
+    pub max_requests: usize,
+}
+
+impl Default for ReplayPolicy {
+    fn default() -> Self {
+        Self {
+            max_requests: DEFAULT_MAX_REPLAYED,
+        }
+    }
+}
+
+/// What a message means for replaying.
+pub enum Tracking {
+    /// A request, kept for replaying if `idempotent`.
+    Request { id: u64, idempotent: bool },
+    /// tarpc no longer waits for the response to this request.
+    Cancel(u64),
+    /// The response to this request.
+    Response(u64),
+    /// Nothing replaying has to know about.
+    Untracked,
+}
+
+/// Messages going through a [`WsTransport`](crate::transport::WsTransport), as far as
+/// replaying needs to tell them apart. Implemented for tarpc's messages.
+pub trait Replayable {
+    fn tracking(&self) -> Tracking;
+
+    /// The metadata to send next to a request, see [`rpc::metadata`]. Gone afterwards.
+    fn take_metadata(&mut self) -> Option<RequestMetadata> {
+        None
+    }
+
+    /// Whether nothing answers the message, a request sent one-way, see [`rpc::oneway`].
+    fn one_way(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Idempotent> Replayable for ClientMessage<T> {
+    fn tracking(&self) -> Tracking {
+        match self {
+            ClientMessage::Request(request) => Tracking::Request {
+                id: request.id,
+                idempotent: request.message.idempotent(),
+            },
+            ClientMessage::Cancel { request_id, .. } => Tracking::Cancel(*request_id),
+            // tarpc sends nothing else so far.
+            _ => Tracking::Untracked,
+        }
+    }
+}
+
+impl<T> Replayable for Response<T> {
+    fn tracking(&self) -> Tracking {
+        Tracking::Response(self.request_id)
+    }
+}
+
+struct Kept {
+    id: u64,
+    /// Position among the messages the sink accepted.
+    seq: u64,
+    frame: WsMessage,
+}
+
+/// Requests waiting for a response, shared by a transport and its driver.
+pub(crate) struct ReplayBuffer {
+    policy: ReplayPolicy,
+    kept: VecDeque<Kept>,
+    /// Requests answered lately, a second response to them is a duplicate.
+    answered: VecDeque<u64>,
+    /// Messages the sink accepted so far.
+    accepted: u64,
+    /// Messages the driver took off the queue so far, whether or not writing them worked.
+    written: u64,
+}
+
+impl ReplayBuffer {
+    pub(crate) fn new(policy: ReplayPolicy) -> Self {
+        Self {
+            policy,
+            kept: VecDeque::new(),
+            answered: VecDeque::new(),
+            accepted: 0,
+            written: 0,
+        }
+    }
+
+    /// The sink accepted a message, encoded into `frame`.
+    pub(crate) fn accepted(&mut self, tracking: Tracking, frame: &WsMessage) {
+        let seq = self.accepted;
+        self.accepted += 1;
+        match tracking {
+            Tracking::Request {
+                id,
+                idempotent: true,
+            } => {
+                if self.kept.len() < self.policy.max_requests {
+                    self.kept.push_back(Kept {
+                        id,
+                        seq,
+                        frame: frame.clone(),
+                    });
+                } else {
+                    info!("Replay buffer is full, request {} won't be replayed", id);
+                }
+            }
+            Tracking::Cancel(id) => self.kept.retain(|kept| kept.id != id),
+            Tracking::Request { .. } | Tracking::Response(_) | Tracking::Untracked => {}
+        }
+    }
+
+    /// The driver took the next `messages` messages off the queue.
+    pub(crate) fn written(&mut self, messages: usize) {
+        self.written += messages as u64;
+    }
+
+    /// Frames of the requests still waiting for a response that went out before. Those still
+    /// queued are sent from the queue anyway.
+    pub(crate) fn frames(&self) -> Vec<WsMessage> {
+        self.kept
+            .iter()
+            .filter(|kept| kept.seq < self.written)
+            .map(|kept| kept.frame.clone())
+            .collect()
+    }
+
+    /// Whether the response to request `id` should reach tarpc, false for duplicates.
+    pub(crate) fn answer(&mut self, id: u64) -> bool {
+        match self.kept.iter().position(|kept| kept.id == id) {
+            Some(position) => {
+                self.kept.remove(position);
+                if self.answered.len() >= self.policy.max_requests {
+                    self.answered.pop_front();
+                }
+                self.answered.push_back(id);
+                true
+            }
+            None => !self.answered.contains(&id),
+        }
+    }
+}
@@ -0,0 +1,677 @@
+//! Calling `World` methods again after transient failures, see [`RetryingWorldClient`].
+//!
+//! Only calls that fail in a way the policy deems transient are retried. By default those are
+//! the calls the server turned away as [`WorldError::Busy`] or [`WorldError::RateLimited`],
+//! the latter not before the time it asks for. Other errors the server returned from the
+//! method are answers and reach the caller as they are. So does [`RpcError::Disconnected`]:
+//! once the dispatch ended no call on the client succeeds again, connect a new one instead.
+//! Every attempt shares the deadline of the call's context: retries stop once the next one
+//! couldn't start before it.
+
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use log::info;
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, UploadResult, VersionInfo,
+    WorldClient, WorldError,
+};
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+
+use crate::deadline::{remaining, with_deadline};
+use crate::transport::sleep;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts per call, the first one included.
+    pub max_attempts: u32,
+    /// Wait before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound for the wait between retries.
+    pub max_backoff: Duration,
+    /// Factor applied to the wait after every retry.
+    pub multiplier: u32,
+    /// Whether a call failing like this may succeed when made again.
+    pub retryable: fn(&Failure<'_>) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2,
+            retryable: transient,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(retry);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// How an attempt failed, for [`RetryPolicy::retryable`].
+#[derive(Clone, Copy, Debug)]
+pub enum Failure<'a> {
+    /// The call got no answer.
+    Rpc(&'a RpcError),
+    /// The server answered with an error.
+    World(&'a WorldError),
+}
+
+impl Failure<'_> {
+    /// How long the server asked to wait before trying again.
+    fn retry_after(&self) -> Duration {
+        match self {
+            Failure::World(WorldError::RateLimited(millis)) => Duration::from_millis(*millis),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+impl fmt::Display for Failure<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Rpc(e) => write!(f, "{}", e),
+            Failure::World(e) => write!(f, "the server answered {}", e),
+        }
+    }
+}
+
+/// What a `World` method answers, so that the policy sees the server's errors too.
+pub trait Answer {
+    /// The error the server answered with, if it did.
+    fn error(&self) -> Option<&WorldError>;
+}
+
+impl<T> Answer for Result<T, WorldError> {
+    fn error(&self) -> Option<&WorldError> {
+        self.as_ref().err()
+    }
+}
+
+/// The default [`RetryPolicy::retryable`]: calls the server turned away for now, as too many
+/// were running or the client made too many. Connections that ended and deadlines that passed
+/// don't come back.
+pub fn transient(failure: &Failure<'_>) -> bool {
+    matches!(
+        failure,
+        Failure::World(WorldError::Busy | WorldError::RateLimited(_))
+    )
+}
+
+/// A [`WorldClient`] retrying failed calls as its [`RetryPolicy`] says.
+///
+/// `ping`, `echo`, `echo_bytes`, `peer_id`, `subscribe`, `unsubscribe`, `whoami`,
+/// `list_clients`, `list_items`, `get_count` and `version` are retried by default. `delay`
+/// isn't, it may take as long as the deadline allows, nor are `signal`, `signals`, `count`,
+/// `publish`, `delay_with_progress`, the upload methods, `increment`, `create_task` and
+/// `log_event`, which aren't idempotent, see [`rpc::Idempotent`].
+/// [`call`](Self::call) decides per call.
+#[derive(Clone, Debug)]
+pub struct RetryingWorldClient {
+    client: WorldClient,
+    policy: RetryPolicy,
+}
+
+impl RetryingWorldClient {
+    pub fn new(client: WorldClient, policy: RetryPolicy) -> Self {
+        Self { client, policy }
+    }
+
+    /// The client calls are made on, for calls that shouldn't be retried at all.
+    pub fn inner(&self) -> &WorldClient {
+        &self.client
+    }
+
+    /// Makes the call `attempt` makes with the given context, e.g. on [`inner`](Self::inner),
+    /// again as long as `retry` is set and the policy allows.
+    pub async fn call<T, F, Fut>(
+        &self,
+        ctx: Context,
+        retry: bool,
+        attempt: F,
+    ) -> Result<T, RpcError>
+    where
+        T: Answer,
+        F: Fn(Context) -> Fut,
+        Fut: Future<Output = Result<T, RpcError>>,
+    {
+        let mut attempts = 1;
+        loop {
+            let outcome = with_deadline(&ctx, attempt(ctx)).await;
+            let failure = match &outcome {
+                Ok(answer) => match answer.error() {
+                    Some(e) => Failure::World(e),
+                    None => return outcome,
+                },
+                Err(e) => Failure::Rpc(e),
+            };
+            if !retry
+                || attempts >= self.policy.max_attempts
+                || !(self.policy.retryable)(&failure)
+            {
+                return outcome;
+            }
+            let backoff = self.policy.backoff(attempts - 1).max(failure.retry_after());
+            if backoff >= remaining(&ctx) {
+                info!("Not retrying after {}, the deadline passes first", failure);
+                return outcome;
+            }
+            info!(
+                "Attempt {} failed: {}, retrying in {:?}",
+                attempts, failure, backoff
+            );
+            sleep(backoff).await;
+            attempts += 1;
+        }
+    }
+
+    pub async fn ping(&self, ctx: Context) -> Result<Result<String, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.ping(ctx)).await
+    }
+
+    pub async fn echo(
+        &self,
+        ctx: Context,
+        value: String,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.echo(ctx, value.clone()))
+            .await
+    }
+
+    pub async fn delay(
+        &self,
+        ctx: Context,
+        millis: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| self.client.delay(ctx, millis))
+            .await
+    }
+
+    pub async fn peer_id(&self, ctx: Context) -> Result<Result<u64, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.peer_id(ctx)).await
+    }
+
+    pub async fn signal(
+        &self,
+        ctx: Context,
+        to: u64,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| {
+            self.client.signal(ctx, to, payload.clone())
+        })
+        .await
+    }
+
+    pub async fn signals(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<(u64, String)>, WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| self.client.signals(ctx)).await
+    }
+
+    pub async fn count(
+        &self,
+        ctx: Context,
+        stream: u64,
+        n: u64,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| self.client.count(ctx, stream, n))
+            .await
+    }
+
+    pub async fn subscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.subscribe(ctx, topic.clone()))
+            .await
+    }
+
+    pub async fn unsubscribe(
+        &self,
+        ctx: Context,
+        topic: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.unsubscribe(ctx, topic.clone()))
+            .await
+    }
+
+    pub async fn publish(
+        &self,
+        ctx: Context,
+        topic: String,
+        payload: String,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| {
+            self.client.publish(ctx, topic.clone(), payload.clone())
+        })
+        .await
+    }
+
+    pub async fn delay_with_progress(
+        &self,
+        ctx: Context,
+        millis: u64,
+        progress: u64,
+    ) -> Result<Result<String, WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| {
+            self.client.delay_with_progress(ctx, millis, progress)
+        })
+        .await
+    }
+
+    pub async fn whoami(&self, ctx: Context) -> Result<Result<SessionInfo, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.whoami(ctx)).await
+    }
+
+    pub async fn list_clients(
+        &self,
+        ctx: Context,
+    ) -> Result<Result<Vec<ClientInfo>, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.list_clients(ctx)).await
+    }
+
+    pub async fn upload_begin(
+        &self,
+        ctx: Context,
+        name: String,
+        size: u64,
+    ) -> Result<Result<u64, WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| self.client.upload_begin(ctx, name.clone(), size))
+            .await
+    }
+
+    pub async fn upload_chunk(
+        &self,
+        ctx: Context,
+        id: u64,
+        offset: u64,
+        bytes: Bytes,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| {
+            self.client.upload_chunk(ctx, id, offset, bytes.clone())
+        })
+        .await
+    }
+
+    pub async fn upload_finish(
+        &self,
+        ctx: Context,
+        id: u64,
+        checksum: u32,
+    ) -> Result<Result<UploadResult, WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| self.client.upload_finish(ctx, id, checksum))
+            .await
+    }
+
+    pub async fn list_items(
+        &self,
+        ctx: Context,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Result<Page, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.list_items(ctx, cursor.clone(), limit))
+            .await
+    }
+
+    pub async fn increment(
+        &self,
+        ctx: Context,
+        by: i64,
+    ) -> Result<Result<i64, WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| self.client.increment(ctx, by)).await
+    }
+
+    pub async fn get_count(&self, ctx: Context) -> Result<Result<i64, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.get_count(ctx)).await
+    }
+
+    pub async fn version(&self, ctx: Context) -> Result<Result<VersionInfo, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.version(ctx)).await
+    }
+
+    pub async fn echo_bytes(
+        &self,
+        ctx: Context,
+        data: Bytes,
+    ) -> Result<Result<Bytes, WorldError>, RpcError> {
+        self.call(ctx, true, |ctx| self.client.echo_bytes(ctx, data.clone()))
+            .await
+    }
+
+    pub async fn create_task(
+        &self,
+        ctx: Context,
+        request: CreateTaskRequest,
+    ) -> Result<Result<Task, WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| self.client.create_task(ctx, request.clone()))
+            .await
+    }
+
+    pub async fn log_event(
+        &self,
+        ctx: Context,
+        name: String,
+        payload: String,
+    ) -> Result<Result<(), WorldError>, RpcError> {
+        self.call(ctx, false, |ctx| {
+            self.client.log_event(ctx, name.clone(), payload.clone())
+        })
+        .await
+    }
+}
+
+#[cfg(all(test, feature = "native", not(target_arch = "wasm32")))]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::task::{self, Poll, Waker};
+    use std::time::SystemTime;
+
+    use futures::{Sink, Stream};
+    use rpc::mux::rebuild;
+    use rpc::{WorldRequest, WorldResponse};
+    use serde::Serialize;
+    use tarpc::{context, ClientMessage, Response};
+
+    use super::*;
+    use crate::testing::{self, answer, connect, run, ClientEnd};
+
+    #[derive(Serialize)]
+    struct ResponseFields {
+        request_id: u64,
+        message: Result<(), ServerErrorFields>,
+    }
+
+    #[derive(Serialize)]
+    struct ServerErrorFields {
+        /// tarpc sends `io::ErrorKind`s as numbers, `ConnectionReset` as 3.
+        kind: u32,
+        detail: String,
+    }
+
+    /// A connection dropping the first `failures` requests sent on it, which it answers as
+    /// reset instead, like a server going away and coming back would.
+    struct Flaky {
+        inner: ClientEnd,
+        failures: usize,
+        /// Requests dropped so far.
+        lost: Rc<Cell<usize>>,
+        resets: VecDeque<Response<WorldResponse>>,
+        reader: Option<Waker>,
+    }
+
+    impl Flaky {
+        fn new(inner: ClientEnd, failures: usize) -> (Self, Rc<Cell<usize>>) {
+            let lost = Rc::new(Cell::new(0));
+            let flaky = Self {
+                inner,
+                failures,
+                lost: lost.clone(),
+                resets: VecDeque::new(),
+                reader: None,
+            };
+            (flaky, lost)
+        }
+    }
+
+    impl Stream for Flaky {
+        type Item = <ClientEnd as Stream>::Item;
+
+        fn poll_next(
+            mut self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            if let Some(reset) = self.resets.pop_front() {
+                return Poll::Ready(Some(Ok(reset)));
+            }
+            self.reader = Some(cx.waker().clone());
+            Pin::new(&mut self.inner).poll_next(cx)
+        }
+    }
+
+    impl Sink<ClientMessage<WorldRequest>> for Flaky {
+        type Error = <ClientEnd as Sink<ClientMessage<WorldRequest>>>::Error;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.inner).poll_ready(cx)
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            item: ClientMessage<WorldRequest>,
+        ) -> Result<(), Self::Error> {
+            let lost = match &item {
+                ClientMessage::Request(request) if self.lost.get() < self.failures => request.id,
+                _ => return Pin::new(&mut self.inner).start_send(item),
+            };
+            self.lost.set(self.lost.get() + 1);
+            let reset = rebuild(ResponseFields {
+                request_id: lost,
+                message: Err(ServerErrorFields {
+                    kind: 3,
+                    detail: "the connection was reset".into(),
+                }),
+            })
+            .unwrap();
+            self.resets.push_back(reset);
+            if let Some(reader) = self.reader.take() {
+                reader.wake();
+            }
+            Ok(())
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.inner).poll_close(cx)
+        }
+    }
+
+    fn is_reset(e: &RpcError) -> bool {
+        matches!(e, RpcError::Server(e) if e.kind == io::ErrorKind::ConnectionReset)
+    }
+
+    fn reset(failure: &Failure<'_>) -> bool {
+        matches!(failure, Failure::Rpc(e) if is_reset(e))
+    }
+
+    /// Retries resets `max_attempts` times in all, right away.
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            multiplier: 2,
+            retryable: reset,
+        }
+    }
+
+    /// A client whose first `failures` requests are lost, with the count of lost ones and of
+    /// those the server answered.
+    fn flaky(
+        failures: usize,
+        max_attempts: u32,
+    ) -> (RetryingWorldClient, Rc<Cell<usize>>, Rc<Cell<usize>>) {
+        let served = Rc::new(Cell::new(0));
+        let counted = served.clone();
+        let (client, _) = testing::serve(move |request| {
+            counted.set(counted.get() + 1);
+            answer(request)
+        });
+        let (flaky, lost) = Flaky::new(client, failures);
+        let client = RetryingWorldClient::new(connect(flaky), policy(max_attempts));
+        (client, lost, served)
+    }
+
+    #[test]
+    fn call_succeeds_on_the_second_attempt() {
+        run(async {
+            let (client, lost, served) = flaky(1, 3);
+            assert_eq!(
+                client.ping(context::current()).await.unwrap(),
+                Ok("pong".into())
+            );
+            assert_eq!((lost.get(), served.get()), (1, 1));
+        });
+    }
+
+    #[test]
+    fn retries_give_up_after_the_limit() {
+        run(async {
+            let (client, lost, served) = flaky(5, 3);
+            let e = client
+                .echo(context::current(), "hi".into())
+                .await
+                .unwrap_err();
+            assert!(is_reset(&e), "{:?}", e);
+            assert_eq!((lost.get(), served.get()), (3, 0));
+            // Calls after it have attempts of their own.
+            let answer = client.echo(context::current(), "hi".into()).await.unwrap();
+            assert_eq!(answer, Ok("hi".into()));
+            assert_eq!((lost.get(), served.get()), (5, 1));
+        });
+    }
+
+    #[test]
+    fn delay_is_not_retried() {
+        run(async {
+            let (client, lost, served) = flaky(1, 3);
+            let e = client.delay(context::current(), 1).await.unwrap_err();
+            assert!(is_reset(&e), "{:?}", e);
+            assert_eq!((lost.get(), served.get()), (1, 0));
+            let answer = client.delay(context::current(), 1).await.unwrap();
+            assert_eq!(answer, Ok("waited 1ms".into()));
+        });
+    }
+
+    #[test]
+    fn calls_decide_whether_they_are_retried() {
+        run(async {
+            let (client, lost, served) = flaky(1, 3);
+            let inner = client.inner().clone();
+            let delayed = client
+                .call(context::current(), true, |ctx| inner.delay(ctx, 1))
+                .await;
+            assert_eq!(delayed.unwrap(), Ok("waited 1ms".into()));
+            assert_eq!((lost.get(), served.get()), (1, 1));
+
+            let (client, lost, _) = flaky(1, 3);
+            let inner = client.inner().clone();
+            let pinged = client
+                .call(context::current(), false, |ctx| inner.ping(ctx))
+                .await;
+            assert!(pinged.is_err());
+            assert_eq!(lost.get(), 1);
+        });
+    }
+
+    #[test]
+    fn answers_of_the_server_are_not_retried() {
+        run(async {
+            let served = Rc::new(Cell::new(0));
+            let counted = served.clone();
+            let client = testing::world(move |_| {
+                counted.set(counted.get() + 1);
+                let invalid = WorldError::InvalidArgument("no".into());
+                async move { WorldResponse::Echo(Err(invalid)) }
+            });
+            let client = RetryingWorldClient::new(client, policy(3));
+            let answer = client.echo(context::current(), "hi".into()).await.unwrap();
+            assert_eq!(answer, Err(WorldError::InvalidArgument("no".into())));
+            assert_eq!(served.get(), 1);
+        });
+    }
+
+    #[test]
+    fn retries_stop_when_the_deadline_passes_first() {
+        run(async {
+            let (client, lost, _) = flaky(1, 3);
+            let client = RetryingWorldClient {
+                policy: RetryPolicy {
+                    initial_backoff: Duration::from_secs(10),
+                    max_backoff: Duration::from_secs(10),
+                    ..policy(3)
+                },
+                ..client
+            };
+            let mut ctx = context::current();
+            ctx.deadline = SystemTime::now() + Duration::from_secs(1);
+            let e = client.ping(ctx).await.unwrap_err();
+            assert!(is_reset(&e), "{:?}", e);
+            assert_eq!(lost.get(), 1);
+        });
+    }
+
+    #[test]
+    fn the_default_policy_retries_calls_the_server_turned_away() {
+        run(async {
+            let served = Rc::new(Cell::new(0));
+            let counted = served.clone();
+            let client = testing::world(move |request| {
+                counted.set(counted.get() + 1);
+                let turned_away = match counted.get() {
+                    1 => Some(WorldError::Busy),
+                    2 => Some(WorldError::RateLimited(50)),
+                    _ => None,
+                };
+                async move {
+                    match turned_away {
+                        Some(e) => WorldResponse::Ping(Err(e)),
+                        None => answer(request).await,
+                    }
+                }
+            });
+            let client = RetryingWorldClient::new(client, RetryPolicy::default());
+            let pinged = client.ping(context::current()).await.unwrap();
+            assert_eq!(pinged, Ok("pong".into()));
+            assert_eq!(served.get(), 3);
+        });
+    }
+
+    #[test]
+    fn only_calls_turned_away_are_transient_by_default() {
+        assert!(transient(&Failure::World(&WorldError::Busy)));
+        assert!(transient(&Failure::World(&WorldError::RateLimited(10))));
+        let invalid = WorldError::InvalidArgument("no".into());
+        assert!(!transient(&Failure::World(&invalid)));
+        // The dispatch is gone for good then.
+        assert!(!transient(&Failure::Rpc(&RpcError::Disconnected)));
+        assert!(!transient(&Failure::Rpc(&RpcError::DeadlineExceeded)));
+    }
+
+    #[test]
+    fn backoff_grows_up_to_its_bound() {
+        let policy = RetryPolicy::default();
+        let backoffs: Vec<_> = (0..6).map(|retry| policy.backoff(retry)).collect();
+        let millis = [200, 400, 800, 1600, 3200, 5000];
+        assert_eq!(backoffs, millis.map(Duration::from_millis));
+        assert_eq!(policy.backoff(u32::MAX), policy.max_backoff);
+    }
+}
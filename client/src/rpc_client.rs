@@ -1,52 +1,649 @@
-use async_io_stream::IoStream;
+#[cfg(feature = "json")]
+use async_trait::async_trait;
+#[cfg(feature = "json")]
+use futures::future::{AbortHandle, Abortable};
 use log::info;
+#[cfg(feature = "json")]
+use rpc::admin::{AdminClient, AdminRequest};
+use rpc::codec::Codec;
+#[cfg(feature = "json")]
+use rpc::metadata::{Metadata, MetadataError};
+#[cfg(feature = "json")]
+use rpc::{WorldClient, WorldError, WorldRequest};
+#[cfg(feature = "json")]
+use tarpc::client::RpcError;
 use tarpc::serde::{Deserialize, Serialize};
+#[cfg(feature = "json")]
+use std::fmt;
+use std::future::Future;
+#[cfg(feature = "json")]
+use std::io;
 use std::marker::Unpin;
-use tarpc::serde_transport::Transport;
-use tokio_serde::*;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use ws_stream_wasm::*;
-
-pub async fn connect<Item, SinkItem, Codec, CodecFn>(
-    codec_fn: CodecFn,
-) -> Result<Transport<IoStream<WsStreamIo, Vec<u8>>, Item, SinkItem, Codec>, std::io::Error>
+#[cfg(feature = "json")]
+use std::rc::Rc;
+#[cfg(feature = "json")]
+use std::time::Duration;
+
+use crate::error::TransportError;
+#[cfg(feature = "json")]
+use crate::mux::Mux;
+use crate::replay::Replayable;
+#[cfg(feature = "json")]
+use crate::rtt::RttMonitor;
+use crate::transport::{ConnectOptions, TransportHandle, WsTransport};
+#[cfg(feature = "json")]
+use crate::transport::{ConnectionState, FrameMode, StateCallback};
+
+/// Server used by the demo when no other URL has been entered.
+pub const DEFAULT_URL: &str = "127.0.0.1:8083";
+
+/// Turns the user supplied address into a WebSocket URL.
+///
+/// `ws://` and `wss://` URLs are used as they are. An address without a scheme gets
+/// `wss://` when the page itself was served over HTTPS and `ws://` otherwise, since
+/// browsers refuse plain WebSocket connections from secure pages. Outside the browser it
+/// always gets `ws://`. With the `webtransport` feature `https://` URLs are accepted too and
+/// connect over WebTransport.
+pub fn resolve_url(url: &str) -> Result<String, TransportError> {
+    match url.split_once("://") {
+        Some(("ws", _)) | Some(("wss", _)) => Ok(url.to_string()),
+        #[cfg(feature = "webtransport")]
+        Some(("https", _)) => Ok(url.to_string()),
+        Some(_) => Err(TransportError::InvalidUrl(url.to_string())),
+        None => {
+            let scheme = if page_is_secure() { "wss" } else { "ws" };
+            Ok(format!("{}://{}", scheme, url))
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn page_is_secure() -> bool {
+    web_sys::window()
+        .and_then(|window| window.location().protocol().ok())
+        .map(|protocol| protocol == "https:")
+        .unwrap_or(false)
+}
+
+/// Outside the browser there is no page to match.
+#[cfg(not(target_arch = "wasm32"))]
+fn page_is_secure() -> bool {
+    false
+}
+
+/// Connects to `url` in the frame mode of `options`, over whichever transport the options
+/// and the URL ask for.
+pub async fn build_client<Item, SinkItem>(
+    url: &str,
+    options: ConnectOptions,
+) -> Result<(impl tarpc::Transport<SinkItem, Item>, TransportHandle), TransportError>
 where
-    Item: for<'de> Deserialize<'de>,
-    SinkItem: Serialize,
-    Codec: Serializer<SinkItem> + Deserializer<Item>,
-    CodecFn: Fn() -> Codec,
+    Item: for<'de> Deserialize<'de> + Unpin + Replayable + 'static,
+    SinkItem: Serialize + Unpin + Replayable,
 {
-    info!("Starting connect2");
-    let _url = "127.0.0.1:8083";
-    info!("Connecting to server: {}", "127.0.0.1:8083");
-    match WsMeta::connect("ws://127.0.0.1:8083", None).await {
-        Ok((_ws, _wsio)) => {
-            //let session = WebSocketSession::connect(url);
-            info!("Creating the frame");
-            let frame = Framed::new(_wsio.into_io(), LengthDelimitedCodec::new());
-            info!("Creating the Transport");
-            let tmp = tarpc::serde_transport::new(frame, codec_fn());
-            info!("Returning Transport");
-            Ok(tmp)
+    info!("In build client");
+    let url = resolve_url(url)?;
+    #[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+    {
+        if options.pool_size > 1 {
+            return crate::pool::connect(&url, options).await;
         }
-        Err(e) => {
-            info!("Errored on WsMeta connect\n{:?}", e);
-            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        crate::native::connect(&url, options).await
+    }
+    #[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+    {
+        #[cfg(feature = "webtransport")]
+        if url.starts_with("https://") {
+            return crate::webtransport::connect(&url, options).await;
+        }
+        #[cfg(feature = "json")]
+        if let Some(script) = &options.worker {
+            let connected = crate::worker::connect(&url, script, options.clone()).await;
+            return fall_back(connected, options).await;
         }
+        let connected = if options.pool_size > 1 {
+            crate::pool::connect(&url, options.clone()).await
+        } else {
+            crate::transport::connect(&url, options.clone()).await
+        };
+        fall_back(connected, options).await
     }
 }
 
-pub async fn build_client<Item, SinkItem>(
-) -> Result<impl tarpc::Transport<SinkItem, Item>, std::io::Error>
+/// Connects over server-sent events at `options.fallback_url` instead, see [`crate::sse`],
+/// if the WebSocket of `connected` couldn't be opened. With a fallback URL this waits for the
+/// WebSocket to open.
+#[cfg(all(feature = "json", not(all(feature = "native", not(target_arch = "wasm32")))))]
+async fn fall_back<Item, SinkItem>(
+    connected: Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
 where
-    Item: for<'de> Deserialize<'de> + Unpin,
-    SinkItem: Serialize + Unpin,
+    Item: for<'de> Deserialize<'de> + Unpin + Replayable + 'static,
+    SinkItem: Serialize + Unpin + Replayable,
 {
-    info!("In build client");
-    Ok(
-        connect(tokio_serde::formats::Json::<Item, SinkItem>::default)
-            .await
-            .unwrap(),
-    )
-    //self.server_handles.push(handle);
+    // Only an open socket tells whether falling back is needed.
+    let connected = match connected {
+        Ok((transport, handle)) if options.fallback_url.is_some() => {
+            handle.opened().await.map(|()| (transport, handle))
+        }
+        connected => connected,
+    };
+    match (connected, options.fallback_url.clone()) {
+        (Err(e @ TransportError::HandshakeFailed { .. }), Some(fallback_url))
+        | (Err(e @ TransportError::Timeout), Some(fallback_url)) => {
+            info!("WebSocket unavailable ({}), falling back to {}", e, fallback_url);
+            crate::sse::connect(&fallback_url, options).await
+        }
+        (connected, _) => connected,
+    }
+}
+
+/// Without JSON there is no fallback.
+#[cfg(all(not(feature = "json"), not(all(feature = "native", not(target_arch = "wasm32")))))]
+async fn fall_back<Item, SinkItem>(
+    connected: Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>,
+    _: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError> {
+    connected
+}
+
+/// Like [`build_client`], with messages serialized by `codec` instead of `options.frames`.
+///
+/// Only plain WebSockets take a codec, so `worker`, `pool_size` and `fallback_url` in
+/// `options` are ignored and `https://` URLs are rejected.
+pub async fn build_client_with_codec<Item, SinkItem, C>(
+    url: &str,
+    codec: C,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem, C>, TransportHandle), TransportError>
+where
+    Item: for<'de> Deserialize<'de> + Unpin + Replayable + 'static,
+    SinkItem: Serialize + Unpin + Replayable,
+    C: Codec + Unpin + 'static,
+{
+    let url = resolve_url(url)?;
+    #[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+    {
+        crate::native::connect_with_codec(&url, codec, options).await
+    }
+    #[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+    {
+        if !url.starts_with("ws") {
+            return Err(TransportError::InvalidUrl(url));
+        }
+        crate::transport::connect_with_codec(&url, codec, options).await
+    }
+}
+
+/// Connects a [`WorldClient`] in one go, see [`ClientBuilder::connect`].
+///
+/// ```ignore
+/// let world = ClientBuilder::new(DEFAULT_URL)
+///     .max_in_flight(100)
+///     .on_state_change(|state| info!("Now {}", state))
+///     .connect()
+///     .await?;
+/// world.client().ping(context::current()).await?;
+/// ```
+#[cfg(feature = "json")]
+pub struct ClientBuilder {
+    url: String,
+    options: ConnectOptions,
+    config: tarpc::client::Config,
+    on_state_change: Vec<StateCallback>,
+    on_disconnect: Vec<DisconnectCallback>,
+}
+
+/// A callback of [`ClientBuilder::on_disconnect`].
+#[cfg(feature = "json")]
+type DisconnectCallback = Box<dyn Fn(&str)>;
+
+#[cfg(feature = "json")]
+impl fmt::Debug for ClientBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientBuilder")
+            .field("url", &self.url)
+            .field("options", &self.options)
+            .field("config", &self.config)
+            .field("on_state_change", &self.on_state_change.len())
+            .field("on_disconnect", &self.on_disconnect.len())
+            .finish()
+    }
+}
+
+#[cfg(feature = "json")]
+impl ClientBuilder {
+    /// A builder for `url`, taken like [`build_client`] takes it, with default options.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            options: ConnectOptions::default(),
+            config: tarpc::client::Config::default(),
+            on_state_change: Vec::new(),
+            on_disconnect: Vec::new(),
+        }
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    /// Replaces every connection option, the frame mode included.
+    pub fn options(mut self, options: ConnectOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// How messages are serialized, [`ConnectOptions::frames`]. Other codecs only work over
+    /// plain WebSockets, see [`build_client_with_codec`].
+    pub fn codec(mut self, frames: FrameMode) -> Self {
+        self.options.frames = frames;
+        self
+    }
+
+    /// Calls tarpc lets wait for an answer at once, further calls fail right away.
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.config.max_in_flight_requests = max;
+        self
+    }
+
+    /// Registers `callback` on the connection, see [`TransportHandle::on_state_change`].
+    pub fn on_state_change(mut self, callback: impl Fn(&ConnectionState) + 'static) -> Self {
+        self.on_state_change.push(Box::new(callback));
+        self
+    }
+
+    /// Registers `callback` to run with the reason once the client's dispatch ended, after
+    /// which every call fails with [`RpcError::Disconnected`](tarpc::client::RpcError),
+    /// calls in flight included.
+    ///
+    /// That happens when the connection ends for good, but also when tarpc gives up on a
+    /// connection that is still open, e.g. on a response it can't read. The connection is
+    /// closed then, so its state ends up `Closed` either way.
+    pub fn on_disconnect(mut self, callback: impl Fn(&str) + 'static) -> Self {
+        self.on_disconnect.push(Box::new(callback));
+        self
+    }
+
+    /// Opens the connection, creates the `World` and `Admin` clients on it, see
+    /// [`crate::mux`], and spawns their dispatches, which run until the connection ends for
+    /// good or every clone of the handle is dropped. Unlike [`build_client`] this waits for the
+    /// connection to open, failing with why it couldn't.
+    pub async fn connect(self) -> Result<WorldHandle, TransportError> {
+        let (transport, handle) = build_client(&self.url, self.options).await?;
+        for callback in self.on_state_change {
+            handle.on_state_change(callback);
+        }
+        handle.opened().await?;
+        let mux = Mux::new(transport);
+        let admin = AdminClient::new(self.config.clone(), mux.open::<AdminRequest>());
+        let (admin_abort, registration) = AbortHandle::new_pair();
+        let admin_dispatch = Abortable::new(admin.dispatch, registration);
+        spawn(async move {
+            if let Ok(Err(e)) = admin_dispatch.await {
+                info!("Admin dispatch ended: {}", e);
+            }
+        });
+        let client = WorldClient::new(self.config.clone(), mux.open::<WorldRequest>());
+        let abort = supervise(client.dispatch, handle.clone(), self.on_disconnect);
+        Ok(WorldHandle {
+            client: client.client,
+            admin: admin.client,
+            mux,
+            config: self.config,
+            transport: handle.clone(),
+            rtt: RttMonitor::default(),
+            _owner: Rc::new(Owner {
+                connection: handle,
+                dispatches: vec![abort, admin_abort],
+            }),
+        })
+    }
+}
+
+/// A connected [`WorldClient`], the [`AdminClient`] sharing its connection and the handle of
+/// the connection, see [`ClientBuilder`]. Clones share them.
+///
+/// The handle owns the connection: dropping its last clone stops the dispatch, so calls still
+/// waiting fail with [`RpcError::Disconnected`](tarpc::client::RpcError), and closes the
+/// socket. Clones of [`client`](Self::client) and [`transport`](Self::transport) don't keep
+/// it open, tasks that outlive whoever made the connection should hold those rather than the
+/// handle.
+#[cfg(feature = "json")]
+#[derive(Clone, Debug)]
+pub struct WorldHandle {
+    client: WorldClient,
+    admin: AdminClient,
+    /// For the requests sent one-way or with metadata, which don't go through `client`.
+    mux: Mux,
+    /// What `client` was made with, for those made with metadata.
+    config: tarpc::client::Config,
+    transport: TransportHandle,
+    rtt: RttMonitor,
+    _owner: Rc<Owner>,
+}
+
+/// What the dispatches of a [`WorldHandle`] run on, as far as ending them goes.
+#[cfg(feature = "json")]
+#[async_trait(?Send)]
+trait Connection: Clone + 'static {
+    /// Why the connection ended for good, `None` while it hasn't.
+    fn closed_error(&self) -> Option<String>;
+
+    async fn disconnect(&self);
+}
+
+#[cfg(feature = "json")]
+#[async_trait(?Send)]
+impl Connection for TransportHandle {
+    fn closed_error(&self) -> Option<String> {
+        TransportHandle::closed_error(self).map(|e| e.to_string())
+    }
+
+    async fn disconnect(&self) {
+        TransportHandle::disconnect(self).await
+    }
+}
+
+/// Spawns `dispatch`, running `on_disconnect` with the reason once it ended. When tarpc gave
+/// up on `connection` while it was still open, it is closed first. The handle aborts the
+/// dispatch, which counts as ending too.
+#[cfg(feature = "json")]
+fn supervise<C, E>(
+    dispatch: impl Future<Output = Result<(), E>> + 'static,
+    connection: C,
+    on_disconnect: Vec<DisconnectCallback>,
+) -> AbortHandle
+where
+    C: Connection,
+    E: fmt::Display,
+{
+    let (abort, registration) = AbortHandle::new_pair();
+    let dispatch = Abortable::new(dispatch, registration);
+    spawn(async move {
+        let reason = match dispatch.await {
+            Ok(Ok(())) => connection
+                .closed_error()
+                .unwrap_or_else(|| "every client was dropped".to_string()),
+            Ok(Err(e)) => {
+                info!("Dispatch ended: {}", e);
+                connection.disconnect().await;
+                e.to_string()
+            }
+            Err(_) => "every handle was dropped".to_string(),
+        };
+        for callback in &on_disconnect {
+            callback(&reason);
+        }
+    });
+    abort
+}
+
+/// Closes the connection once the last [`WorldHandle`] is gone.
+#[cfg(feature = "json")]
+#[derive(Debug)]
+struct Owner<C: Connection = TransportHandle> {
+    connection: C,
+    dispatches: Vec<AbortHandle>,
+}
+
+#[cfg(feature = "json")]
+impl<C: Connection> Drop for Owner<C> {
+    fn drop(&mut self) {
+        for dispatch in &self.dispatches {
+            dispatch.abort();
+        }
+        let connection = self.connection.clone();
+        spawn(async move { connection.disconnect().await });
+    }
+}
+
+#[cfg(feature = "json")]
+impl WorldHandle {
+    pub fn client(&self) -> &WorldClient {
+        &self.client
+    }
+
+    pub fn admin(&self) -> &AdminClient {
+        &self.admin
+    }
+
+    pub fn transport(&self) -> &TransportHandle {
+        &self.transport
+    }
+
+    /// The round trips of [`ping_rtt`](Self::ping_rtt), or of pings timed with
+    /// [`RttMonitor::ping`] on it.
+    pub fn rtt(&self) -> &RttMonitor {
+        &self.rtt
+    }
+
+    /// Pings, recording the round trip in [`rtt`](Self::rtt).
+    pub async fn ping_rtt(
+        &self,
+        ctx: tarpc::context::Context,
+    ) -> Result<Result<Duration, WorldError>, RpcError> {
+        self.rtt.ping(&self.client, ctx).await
+    }
+
+    /// Calls `log_event` one-way, see [`rpc::oneway`]: resolves once the request was written
+    /// to the connection, and whether the server logged it is never known.
+    pub async fn notify_log_event(
+        &self,
+        ctx: tarpc::context::Context,
+        name: String,
+        payload: String,
+    ) -> io::Result<()> {
+        self.mux.notify(ctx, WorldRequest::LogEvent { name, payload }).await
+    }
+
+    /// Another [`WorldClient`] on the connection, sending `metadata` with each of its requests,
+    /// see [`rpc::metadata`]. It works until it and its clones are dropped or the connection
+    /// ends. Fails if the metadata is too large.
+    pub fn client_with(&self, metadata: Metadata) -> Result<WorldClient, MetadataError> {
+        let transport = self.mux.open_with::<WorldRequest>(metadata)?;
+        let client = WorldClient::new(self.config.clone(), transport);
+        let dispatch = client.dispatch;
+        spawn(async move {
+            if let Err(e) = dispatch.await {
+                info!("Dispatch with metadata ended: {}", e);
+            }
+        });
+        Ok(client.client)
+    }
+}
+
+/// Spawns `task` wherever the transports of this build spawn their drivers.
+pub(crate) fn spawn(task: impl Future<Output = ()> + 'static) {
+    #[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+    tokio::task::spawn_local(task);
+    #[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+    wasm_bindgen_futures::spawn_local(task);
+}
+
+#[cfg(all(test, feature = "native", feature = "json", not(target_arch = "wasm32")))]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::{Sink, Stream, StreamExt};
+    use rpc::testing::LoopbackError;
+    use rpc::WorldResponse;
+    use tarpc::{context, ClientMessage, Response};
+
+    use super::*;
+    use crate::testing::{self, ClientEnd};
+
+    /// A connection that only remembers being closed.
+    #[derive(Clone, Debug, Default)]
+    struct Closing(Rc<Cell<bool>>);
+
+    #[async_trait(?Send)]
+    impl Connection for Closing {
+        fn closed_error(&self) -> Option<String> {
+            None
+        }
+
+        async fn disconnect(&self) {
+            self.0.set(true);
+        }
+    }
+
+    /// A client end failing to read what comes after its first `answered` responses.
+    struct Breaking {
+        inner: ClientEnd,
+        answered: usize,
+    }
+
+    impl Stream for Breaking {
+        type Item = Result<Response<WorldResponse>, LoopbackError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            match self.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(_)) if self.answered == 0 => Poll::Ready(Some(Err(
+                    LoopbackError::Decode("cut off mid-stream".to_string()),
+                ))),
+                Poll::Ready(Some(response)) => {
+                    self.answered -= 1;
+                    Poll::Ready(Some(response))
+                }
+                other => other,
+            }
+        }
+    }
+
+    impl Sink<ClientMessage<WorldRequest>> for Breaking {
+        type Error = LoopbackError;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.inner).poll_ready(cx)
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            item: ClientMessage<WorldRequest>,
+        ) -> Result<(), Self::Error> {
+            Pin::new(&mut self.inner).start_send(item)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.inner).poll_close(cx)
+        }
+    }
+
+    /// A client on `transport` with its dispatch supervised on `connection`, what aborts it,
+    /// and the reasons `on_disconnect` got.
+    fn supervised<T>(
+        transport: T,
+        connection: Closing,
+    ) -> (WorldClient, AbortHandle, Rc<RefCell<Vec<String>>>)
+    where
+        T: tarpc::Transport<ClientMessage<WorldRequest>, Response<WorldResponse>> + 'static,
+    {
+        let reasons = Rc::new(RefCell::new(Vec::new()));
+        let heard = reasons.clone();
+        let client = WorldClient::new(tarpc::client::Config::default(), transport);
+        let on_disconnect: Vec<DisconnectCallback> = vec![Box::new(move |reason| {
+            heard.borrow_mut().push(reason.to_string())
+        })];
+        let abort = supervise(client.dispatch, connection, on_disconnect);
+        (client.client, abort, reasons)
+    }
+
+    #[test]
+    fn an_error_mid_stream_fails_calls_with_the_reason() {
+        testing::run(async {
+            let (end, _) = testing::serve(testing::answer);
+            let connection = Closing::default();
+            let transport = Breaking {
+                inner: end,
+                answered: 1,
+            };
+            let (client, _abort, reasons) = supervised(transport, connection.clone());
+
+            let answered = client.ping(context::current()).await;
+            assert_eq!(answered.unwrap(), Ok("pong".to_string()));
+            let broken = client.ping(context::current()).await;
+            assert!(
+                matches!(broken, Err(RpcError::Disconnected)),
+                "{:?}",
+                broken
+            );
+            assert_eq!(*reasons.borrow(), ["could not read from the transport"]);
+            assert!(connection.0.get(), "the broken connection was left open");
+
+            let later = client.ping(context::current()).await;
+            assert!(matches!(later, Err(RpcError::Disconnected)), "{:?}", later);
+            assert_eq!(reasons.borrow().len(), 1);
+        });
+    }
+
+    #[test]
+    fn dropping_every_client_ends_the_dispatch_cleanly() {
+        testing::run(async {
+            let (end, _) = testing::serve(testing::answer);
+            let connection = Closing::default();
+            let (client, _abort, reasons) = supervised(end, connection.clone());
+            assert!(client.ping(context::current()).await.is_ok());
+
+            drop(client);
+            tokio::task::yield_now().await;
+            assert_eq!(*reasons.borrow(), ["every client was dropped"]);
+            assert!(
+                !connection.0.get(),
+                "closed a connection tarpc didn't give up on"
+            );
+        });
+    }
+
+    #[test]
+    fn dropping_the_last_owner_ends_the_connection() {
+        testing::run(async {
+            let (end, noticed) = testing::serve(testing::answer);
+            let connection = Closing::default();
+            let (client, abort, reasons) = supervised(end, connection.clone());
+            let owner = Rc::new(Owner {
+                connection: connection.clone(),
+                dispatches: vec![abort],
+            });
+            let clone = owner.clone();
+            let waiting =
+                tokio::task::spawn_local(
+                    async move { client.delay(context::current(), 200).await },
+                );
+            tokio::task::yield_now().await;
+
+            drop(owner);
+            tokio::task::yield_now().await;
+            assert!(!connection.0.get(), "closed while a clone was left");
+            assert!(reasons.borrow().is_empty());
+
+            drop(clone);
+            let waited = waiting.await.unwrap();
+            assert!(
+                matches!(waited, Err(RpcError::Disconnected)),
+                "{:?}",
+                waited
+            );
+            noticed.await.expect("the server never saw the client go");
+            assert!(connection.0.get(), "the connection was left open");
+            assert_eq!(*reasons.borrow(), ["every handle was dropped"]);
+        });
+    }
 }
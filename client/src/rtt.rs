@@ -0,0 +1,165 @@
+//! Round-trip times measured with `ping`, see [`RttMonitor`].
+//!
+//! A round trip is timed from just before the request is handed to the client until its
+//! answer is back, so it includes the time the request waits in the client and the server,
+//! not only the network. Times come from a [`Clock`]: `performance.now()` in the browser,
+//! [`Instant`](std::time::Instant) natively, or whatever a test plugs in.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use rpc::WorldError;
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+
+use crate::api::WorldApi;
+
+/// Round trips [`RttStats`] are computed over by default, the most recent ones.
+pub const DEFAULT_WINDOW: usize = 20;
+
+/// Where round trips are timed from.
+pub trait Clock {
+    /// Milliseconds since a fixed point, never going back.
+    fn now(&self) -> f64;
+}
+
+/// `performance.now()` of the page or worker, falling back to `Date.now()` where there is no
+/// `performance`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PerformanceClock;
+
+impl Clock for PerformanceClock {
+    fn now(&self) -> f64 {
+        use wasm_bindgen::JsCast;
+
+        js_sys::Reflect::get(&js_sys::global(), &"performance".into())
+            .ok()
+            .and_then(|performance| performance.dyn_into::<web_sys::Performance>().ok())
+            .map_or_else(js_sys::Date::now, |performance| performance.now())
+    }
+}
+
+/// [`Instant`](std::time::Instant)s, counted from when the clock was made.
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+#[derive(Clone, Copy, Debug)]
+pub struct InstantClock(std::time::Instant);
+
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+impl Default for InstantClock {
+    fn default() -> Self {
+        InstantClock(std::time::Instant::now())
+    }
+}
+
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+impl Clock for InstantClock {
+    fn now(&self) -> f64 {
+        self.0.elapsed().as_secs_f64() * 1000.0
+    }
+}
+
+/// The clock of this build's transports.
+#[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+pub type DefaultClock = PerformanceClock;
+
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+pub type DefaultClock = InstantClock;
+
+/// The most recent round trips at a glance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RttStats {
+    pub last: Duration,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    /// Round trips these are computed over.
+    pub samples: usize,
+}
+
+/// Times pings and keeps the last round trips, up to a window. Clones share the samples.
+#[derive(Clone)]
+pub struct RttMonitor {
+    clock: Rc<dyn Clock>,
+    window: usize,
+    samples: Rc<RefCell<VecDeque<Duration>>>,
+}
+
+impl fmt::Debug for RttMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RttMonitor")
+            .field("window", &self.window)
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for RttMonitor {
+    fn default() -> Self {
+        Self::new(DefaultClock::default(), DEFAULT_WINDOW)
+    }
+}
+
+impl RttMonitor {
+    /// Times round trips on `clock`, keeping the last `window` of them.
+    pub fn new(clock: impl Clock + 'static, window: usize) -> Self {
+        Self {
+            clock: Rc::new(clock),
+            window: window.max(1),
+            samples: Rc::default(),
+        }
+    }
+
+    /// Pings through `client` and records how long the answer took. Failed calls aren't
+    /// recorded, pings the server answered with an error are.
+    pub async fn ping(
+        &self,
+        client: &dyn WorldApi,
+        ctx: Context,
+    ) -> Result<Result<Duration, WorldError>, RpcError> {
+        let started = self.clock.now();
+        let answer = client.ping(ctx).await?;
+        let rtt = Duration::from_secs_f64((self.clock.now() - started).max(0.0) / 1000.0);
+        self.record(rtt);
+        Ok(answer.map(|_| rtt))
+    }
+
+    /// Adds a round trip timed elsewhere, dropping the oldest once the window is full.
+    pub fn record(&self, rtt: Duration) {
+        let mut samples = self.samples.borrow_mut();
+        if samples.len() == self.window {
+            samples.pop_front();
+        }
+        samples.push_back(rtt);
+    }
+
+    /// `None` before the first round trip.
+    pub fn stats(&self) -> Option<RttStats> {
+        let samples = self.samples.borrow();
+        let last = *samples.back()?;
+        let total: Duration = samples.iter().sum();
+        Some(RttStats {
+            last,
+            min: *samples.iter().min()?,
+            avg: total / samples.len() as u32,
+            max: *samples.iter().max()?,
+            samples: samples.len(),
+        })
+    }
+
+    pub fn clear(&self) {
+        self.samples.borrow_mut().clear();
+    }
+}
+
+/// Times one ping through `client` on the [`DefaultClock`].
+pub async fn ping_rtt(
+    client: &dyn WorldApi,
+    ctx: Context,
+) -> Result<Result<Duration, WorldError>, RpcError> {
+    RttMonitor::new(DefaultClock::default(), 1)
+        .ping(client, ctx)
+        .await
+}
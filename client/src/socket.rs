@@ -0,0 +1,136 @@
+//! Handling a browser WebSocket, for the transports of the page and the workers' sockets alike.
+//! This file is shared with the worker binaries, so it only depends on what they do.
+
+use futures::StreamExt;
+use pharos::Events;
+use web_sys::{BinaryType, WebSocket};
+use ws_stream_wasm::{WsErr, WsEvent};
+
+/// Close code used when the socket died without a close frame, e.g. on network failures.
+pub const ABNORMAL_CLOSURE: u16 = 1006;
+
+/// Close code for giving up on a socket that delivered a frame it couldn't be read from.
+pub const UNSUPPORTED_DATA: u16 = 1003;
+
+/// Makes sure binary frames arrive as `ArrayBuffer`s, answering the binary type the socket is
+/// stuck with otherwise.
+///
+/// ws_stream_wasm asks for them when it opens the socket and drops frames that arrive as a
+/// `Blob` instead. Reading a `Blob` takes a round trip through the event loop, during which a
+/// later frame could overtake it, so they aren't read here either and a socket that won't
+/// deliver `ArrayBuffer`s is of no use.
+pub fn expect_array_buffers(socket: &WebSocket) -> Result<(), BinaryType> {
+    if socket.binary_type() != BinaryType::Arraybuffer {
+        socket.set_binary_type(BinaryType::Arraybuffer);
+    }
+    match socket.binary_type() {
+        BinaryType::Arraybuffer => Ok(()),
+        binary_type => Err(binary_type),
+    }
+}
+
+/// Whether `event` reports a frame ws_stream_wasm dropped for arriving as a `Blob`, which
+/// happens when something switched the binary type after [`expect_array_buffers`]. The frames
+/// after it can't be trusted, a stream of length prefixed messages is out of step from there.
+pub fn lost_frame(event: &WsEvent) -> bool {
+    matches!(event, WsEvent::WsErr(WsErr::CantDecodeBlob))
+}
+
+/// The code, reason and cleanliness of the close event on `events`. The frame stream can end
+/// before the browser delivers the close event carrying the code.
+pub async fn wait_for_close(events: &mut Events<WsEvent>) -> (u16, String, bool) {
+    while let Some(event) = events.next().await {
+        if let WsEvent::Closed(event) = event {
+            return (event.code, event.reason, event.was_clean);
+        }
+    }
+    (ABNORMAL_CLOSURE, "socket went away".to_string(), false)
+}
+
+#[cfg(test)]
+mod tests {
+    use rpc::framing::{self, FrameDecoder, FrameError};
+    use ws_stream_wasm::{WsErr, WsEvent, WsMessage};
+
+    use super::lost_frame;
+
+    /// What a socket hands over for each binary frame: ArrayBuffers as frames, a Blob as the
+    /// error ws_stream_wasm reports on the events in its place.
+    fn deliver(frames: &[(bool, &[u8])]) -> Vec<Result<WsMessage, WsEvent>> {
+        frames
+            .iter()
+            .map(|&(blob, bytes)| match blob {
+                true => Err(WsEvent::WsErr(WsErr::CantDecodeBlob)),
+                false => Ok(WsMessage::Binary(bytes.to_vec())),
+            })
+            .collect()
+    }
+
+    /// Decodes deliveries until the first lost frame, as the transports do.
+    fn receive(deliveries: Vec<Result<WsMessage, WsEvent>>) -> (Vec<Vec<u8>>, bool) {
+        let mut decoder = FrameDecoder::new(framing::DEFAULT_MAX_FRAME_LEN);
+        let mut messages = Vec::new();
+        for delivery in deliveries {
+            match delivery {
+                Ok(WsMessage::Binary(bytes)) => {
+                    decoder.extend(&bytes);
+                    while let Some(message) = decoder.next_frame().unwrap() {
+                        messages.push(message);
+                    }
+                }
+                Ok(WsMessage::Text(_)) => unreachable!(),
+                Err(event) if lost_frame(&event) => return (messages, true),
+                Err(_) => {}
+            }
+        }
+        (messages, false)
+    }
+
+    #[test]
+    fn array_buffers_are_decoded_in_order() {
+        let (first, second) = (
+            framing::encode_frame(b"first"),
+            framing::encode_frame(b"second"),
+        );
+        let received = receive(deliver(&[(false, &first), (false, &second)]));
+        assert_eq!(
+            received,
+            (vec![b"first".to_vec(), b"second".to_vec()], false)
+        );
+    }
+
+    #[test]
+    fn blob_between_array_buffers_stops_the_stream() {
+        let mut framed = framing::encode_frame(b"first");
+        framed.extend(framing::encode_frame(b"second"));
+        framed.extend(framing::encode_frame(b"third"));
+        // The second message straddles the Blob, the third arrives whole after it.
+        let (head, rest) = framed.split_at(framing::HEADER_LEN + 5 + 3);
+        let (middle, tail) = rest.split_at(framing::HEADER_LEN + 3);
+        let received = receive(deliver(&[(false, head), (true, middle), (false, tail)]));
+        assert_eq!(received, (vec![b"first".to_vec()], true));
+    }
+
+    #[test]
+    fn decoding_past_a_lost_frame_reads_garbage() {
+        let mut framed = framing::encode_frame(b"first");
+        framed.extend(framing::encode_frame(&[0xff; 8]));
+        framed.extend(framing::encode_frame(b"third"));
+        let (head, rest) = framed.split_at(framing::HEADER_LEN + 5 + 2);
+        let (_, tail) = rest.split_at(framing::HEADER_LEN);
+        let mut decoder = FrameDecoder::new(64);
+        decoder.extend(head);
+        decoder.extend(tail);
+        assert_eq!(decoder.next_frame().unwrap(), Some(b"first".to_vec()));
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn other_socket_errors_are_not_lost_frames() {
+        assert!(!lost_frame(&WsEvent::Error));
+        assert!(!lost_frame(&WsEvent::WsErr(WsErr::ConnectionNotOpen)));
+    }
+}
@@ -0,0 +1,330 @@
+//! Fallback for networks that don't let WebSockets through.
+//!
+//! Messages to the server are `fetch` POSTs to `/rpc?session=<id>`, one JSON [`Envelope`] each.
+//! Messages from the server arrive over an `EventSource` on `/rpc/events`, whose first event
+//! carries the session id. POSTs run concurrently, so requests can reach the server in a
+//! different order than they were sent; tarpc matches responses by request id either way.
+
+use std::pin::Pin;
+use std::rc::Rc;
+
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::{select, FutureExt, Stream, StreamExt};
+use gloo_timers::future::{IntervalStream, TimeoutFuture};
+use log::info;
+use rpc::{wire, Envelope};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{EventSource, MessageEvent, RequestInit, Response};
+use ws_stream_wasm::WsMessage;
+
+use crate::error::{describe, TransportError};
+use crate::transport::{
+    self, forward, oversized, reconnect, with_token, CloseInfo, ConnectOptions, Control, Ended,
+    FrameMode, Liveness, Payload, Shared, TransportHandle, WsTransport, ABNORMAL_CLOSURE,
+};
+
+/// Connects to the server's HTTP endpoints under `base_url`, e.g. `http://127.0.0.1:8084`.
+///
+/// Messages are always JSON, `options.frames` is ignored.
+pub async fn connect<Item, SinkItem>(
+    base_url: &str,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+{
+    let base_url = base_url.trim_end_matches('/');
+    let session = open(base_url, &options).await?;
+    let heartbeat = transport::encode(&FrameMode::Text, &Envelope::<SinkItem>::Heartbeat, None)?;
+    let (transport, handle, ends) = transport::channels(FrameMode::Text, &options);
+    let driver = Driver {
+        base_url: base_url.to_string(),
+        options,
+        shared: ends.shared,
+        control: ends.control,
+        heartbeat: into_text(heartbeat),
+        incoming: ends.incoming,
+        outgoing: ends.outgoing,
+    };
+    spawn_local(driver.run(session));
+    Ok((transport, handle))
+}
+
+fn into_text(message: WsMessage) -> String {
+    match message {
+        WsMessage::Text(text) => text,
+        WsMessage::Binary(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+    }
+}
+
+enum SourceEvent {
+    /// The server assigned the session id.
+    Session(String),
+    Message(String),
+    /// The event stream broke. The browser would reconnect it, but that starts a new session.
+    Error,
+}
+
+struct Session {
+    source: EventSource,
+    /// Where this session's messages are POSTed.
+    post_url: String,
+    events: mpsc::UnboundedReceiver<SourceEvent>,
+    _on_session: Closure<dyn FnMut(MessageEvent)>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(web_sys::Event)>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.source.close();
+    }
+}
+
+fn listener(
+    events: &mpsc::UnboundedSender<SourceEvent>,
+    event: fn(String) -> SourceEvent,
+) -> Closure<dyn FnMut(MessageEvent)> {
+    let events = events.clone();
+    Closure::wrap(Box::new(move |message: MessageEvent| {
+        let data = message.data().as_string().unwrap_or_default();
+        let _ = events.unbounded_send(event(data));
+    }) as Box<dyn FnMut(MessageEvent)>)
+}
+
+async fn open(base_url: &str, options: &ConnectOptions) -> Result<Session, TransportError> {
+    let events_url = format!("{}/rpc/events", base_url);
+    info!("Connecting to server over server-sent events: {}", events_url);
+    let connect_url = match &options.auth {
+        Some(auth) => with_token(&events_url, auth.token()),
+        None => events_url.clone(),
+    };
+    let source = EventSource::new(&connect_url).map_err(|e| {
+        info!("Errored on EventSource construction\n{}", describe(&e));
+        TransportError::InvalidUrl(base_url.to_string())
+    })?;
+    let (events_tx, events) = mpsc::unbounded();
+    let on_session = listener(&events_tx, SourceEvent::Session);
+    let on_message = listener(&events_tx, SourceEvent::Message);
+    let on_error = Closure::wrap(Box::new(move |_: web_sys::Event| {
+        let _ = events_tx.unbounded_send(SourceEvent::Error);
+    }) as Box<dyn FnMut(web_sys::Event)>);
+    source
+        .add_event_listener_with_callback("session", on_session.as_ref().unchecked_ref())
+        .map_err(|e| TransportError::JsError(describe(&e)))?;
+    source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    source.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+    let mut session = Session {
+        source,
+        post_url: String::new(),
+        events,
+        _on_session: on_session,
+        _on_message: on_message,
+        _on_error: on_error,
+    };
+
+    let first = match options.connect_timeout {
+        Some(timeout) => {
+            let timer = TimeoutFuture::new(timeout.as_millis() as u32);
+            match future::select(session.events.next(), timer).await {
+                Either::Left((first, _)) => first,
+                Either::Right(_) => {
+                    info!("Connecting to {} timed out after {:?}", events_url, timeout);
+                    return Err(TransportError::Timeout);
+                }
+            }
+        }
+        None => session.events.next().await,
+    };
+    match first {
+        Some(SourceEvent::Session(id)) => {
+            session.post_url = format!("{}/rpc?session={}", base_url, id);
+            Ok(session)
+        }
+        Some(SourceEvent::Message(_)) => Err(TransportError::HandshakeFailed {
+            code: ABNORMAL_CLOSURE,
+            reason: "server didn't send a session id".to_string(),
+        }),
+        Some(SourceEvent::Error) | None => Err(TransportError::HandshakeFailed {
+            code: ABNORMAL_CLOSURE,
+            reason: format!("couldn't open {}", events_url),
+        }),
+    }
+}
+
+/// POSTs one message. Resolves once the server accepted it.
+async fn post(url: String, body: String) -> Result<(), String> {
+    let window = web_sys::window().ok_or("no window to fetch from")?;
+    let init = RequestInit::new();
+    // A plain string body keeps this a simple request, so no CORS preflight is needed.
+    init.set_method("POST");
+    init.set_body(&JsValue::from_str(&body));
+    let response: Response = JsFuture::from(window.fetch_with_str_and_init(&url, &init))
+        .await
+        .map_err(|e| describe(&e))?
+        .unchecked_into();
+    if response.ok() {
+        Ok(())
+    } else {
+        Err(format!("server answered {}", response.status()))
+    }
+}
+
+/// Owns the event stream on behalf of a [`WsTransport`] and POSTs its messages.
+struct Driver<Item> {
+    base_url: String,
+    options: ConnectOptions,
+    shared: Rc<Shared>,
+    control: mpsc::UnboundedReceiver<Control>,
+    /// Pre-encoded `Envelope::Heartbeat`.
+    heartbeat: String,
+    incoming: mpsc::Sender<Result<Item, TransportError>>,
+    outgoing: mpsc::UnboundedReceiver<WsMessage>,
+}
+
+impl<Item> Driver<Item>
+where
+    Item: DeserializeOwned,
+{
+    async fn run(mut self, session: Session) {
+        self.serve(session).await;
+        self.shared.finish();
+    }
+
+    async fn serve(&mut self, mut session: Session) {
+        loop {
+            let info = match self.pump(&mut session).await {
+                Ended::Local => return,
+                Ended::Closed(info) => {
+                    self.shared.closed(&info);
+                    return;
+                }
+                Ended::Remote(info) => info,
+            };
+            info!("Session with {} lost: {}", self.base_url, info);
+            let reconnected = match &self.options.reconnect {
+                Some(policy) => {
+                    let base_url = &self.base_url;
+                    reconnect(base_url, policy, &self.shared, &self.incoming, || {
+                        open(base_url, &self.options)
+                    })
+                    .await
+                }
+                None => None,
+            };
+            match reconnected {
+                Some(new_session) => session = new_session,
+                None => {
+                    self.shared.closed(&info);
+                    let _ = self.incoming.try_send(Err(info.into()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves messages between the session and the transport until either side goes away.
+    async fn pump(&mut self, session: &mut Session) -> Ended {
+        let shared = self.shared.clone();
+        let threshold = self.options.flush_threshold;
+        let mut keepalive: Pin<Box<dyn Stream<Item = ()>>> = match self.options.keepalive {
+            Some(interval) => Box::pin(IntervalStream::new(interval.as_millis() as u32)),
+            None => Box::pin(futures::stream::pending()),
+        };
+        let mut liveness = Liveness::new(self.options.heartbeat_timeout);
+        let (posted_tx, mut posted) = mpsc::unbounded();
+        loop {
+            select! {
+                event = session.events.next() => match event {
+                    Some(SourceEvent::Message(data))
+                        if data.len() > self.options.max_message_size =>
+                    {
+                        shared.record_received(Payload::Text(&data));
+                        liveness.received();
+                        if let Err(ended) =
+                            oversized(&mut self.incoming, &self.options, data.len()).await
+                        {
+                            return ended;
+                        }
+                    }
+                    Some(SourceEvent::Message(data)) => {
+                        shared.record_received(Payload::Text(&data));
+                        liveness.received();
+                        let envelope =
+                            wire::decode_text(&data).map_err(TransportError::serialization);
+                        if let Err(ended) =
+                            forward(&mut self.incoming, &self.options, envelope).await
+                        {
+                            return ended;
+                        }
+                    }
+                    Some(SourceEvent::Session(_)) => {}
+                    Some(SourceEvent::Error) | None => {
+                        return Ended::Remote(CloseInfo::abnormal("event stream failed"));
+                    }
+                },
+                frame = self.outgoing.next() => match frame {
+                    Some(frame) => {
+                        let body = into_text(frame);
+                        let size = body.len();
+                        let request = post(session.post_url.clone(), body);
+                        let posted_tx = posted_tx.clone();
+                        spawn_local(async move {
+                            let _ = posted_tx.unbounded_send(request.await.map(|()| size));
+                        });
+                    }
+                    None => return Ended::Local,
+                },
+                result = posted.next() => {
+                    shared.queued.set(shared.queued.get().saturating_sub(1));
+                    match result {
+                        Some(Ok(size)) => shared.record_sent(size),
+                        Some(Err(e)) => {
+                            info!("Failed to post a message: {}", e);
+                            return Ended::Remote(CloseInfo::abnormal(e));
+                        }
+                        None => {}
+                    }
+                    if shared.flushed(threshold) {
+                        shared.wake_flush();
+                    }
+                },
+                control = self.control.next() => match control {
+                    // The server drops the session once its event stream goes away.
+                    Some(Control::Close { code, reason, done }) => {
+                        let _ = done.send(Ok(()));
+                        return Ended::Closed(CloseInfo {
+                            code,
+                            reason,
+                            was_clean: true,
+                        });
+                    }
+                    None => {}
+                },
+                _ = keepalive.next().fuse() => {
+                    let request = post(session.post_url.clone(), self.heartbeat.clone());
+                    let size = self.heartbeat.len();
+                    let shared = shared.clone();
+                    spawn_local(async move {
+                        match request.await {
+                            Ok(()) => shared.record_sent(size),
+                            Err(e) => info!("Failed to send heartbeat: {}", e),
+                        }
+                    });
+                    liveness.sent();
+                },
+                info = liveness.missed().fuse() => {
+                    if let Some(info) = info {
+                        return Ended::Remote(info);
+                    }
+                },
+            }
+        }
+    }
+}
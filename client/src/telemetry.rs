@@ -0,0 +1,56 @@
+//! `tracing` spans around calls, to tell interleaved requests apart.
+//!
+//! [`traced`] wraps a call in an `rpc` span carrying the method, the trace id tarpc sends the
+//! server along with the request and, once answered, the elapsed time. Its events repeat the
+//! trace id as [`rpc::trace::label`] writes it, which the server logs too, so both ends of a
+//! call can be matched up even where span fields don't show.
+//!
+//! With the `devtools` feature [`init`] sends spans to the browser's performance panel through
+//! `tracing-wasm`. Without it no subscriber is set and tracing's `log` feature turns the events
+//! into `log` records, so they show up wherever the rest of the client's logs go.
+
+use std::future::Future;
+
+use rpc::trace::label;
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+use tracing::field::Empty;
+use tracing::{debug, info_span, warn, Instrument};
+
+use crate::transport::now;
+
+/// Installs the subscriber of the `devtools` feature, does nothing without it.
+pub fn init() {
+    #[cfg(all(feature = "devtools", target_arch = "wasm32"))]
+    tracing_wasm::set_as_global_default();
+}
+
+/// `call`, the call of `method` made with `ctx`, inside a span with events for sending it and
+/// for its answer or error.
+pub async fn traced<T>(
+    method: &'static str,
+    ctx: &Context,
+    call: impl Future<Output = Result<T, RpcError>>,
+) -> Result<T, RpcError> {
+    let span = info_span!(
+        "rpc",
+        method,
+        trace_id = %ctx.trace_id(),
+        elapsed_ms = Empty
+    );
+    let started = now();
+    let recorder = span.clone();
+    let label = label(ctx);
+    async move {
+        debug!("send {} ({})", method, label);
+        let answer = call.await;
+        recorder.record("elapsed_ms", now() - started);
+        match &answer {
+            Ok(_) => debug!("response to {} ({})", method, label),
+            Err(e) => warn!(error = %e, "error of {} ({})", method, label),
+        }
+        answer
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,294 @@
+//! A `World` server in memory for the tests of this crate, on [`rpc::testing::loopback`].
+//!
+//! Native builds only: the deadlines and backoffs of the client wait on tokio's timers there,
+//! and [`run`] gives each test the `LocalSet` the client spawns its tasks on.
+//!
+//! [`transport`] puts a [`WsTransport`] on loopbacks instead of a socket, for the tests of what
+//! the transport itself does, e.g. across a [`Restartable`] server going away. A
+//! [`slow_reader`] puts one on a socket whose server falls behind, for flushes.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::{self, LocalBoxFuture};
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use rpc::codec::{Codec, Json};
+use rpc::testing::{loopback, Loopback};
+use rpc::Envelope;
+use rpc::{WorldClient, WorldRequest, WorldResponse};
+use tarpc::{ClientMessage, Response};
+use tokio::task::{spawn_local, JoinHandle, LocalSet};
+use ws_stream_wasm::WsMessage;
+
+use crate::error::TransportError;
+use crate::transport::{
+    channels, drain_tick, forward, reconnect, sleep, wire_size, CloseInfo, ConnectOptions,
+    ConnectionState, Ended, Ends, ReconnectPolicy, TransportHandle, WsTransport, ABNORMAL_CLOSURE,
+};
+
+/// Runs `test` to completion on a current-thread runtime, inside a `LocalSet`.
+pub fn run(test: impl Future<Output = ()>) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    LocalSet::new().block_on(&runtime, test);
+}
+
+/// How the servers of [`world`] answer unless a test says otherwise: `ping` with `pong`,
+/// `echo` with its value, `delay` once it waited and `subscribe` as if it subscribed.
+pub fn answer(request: WorldRequest) -> LocalBoxFuture<'static, WorldResponse> {
+    async move {
+        match request {
+            WorldRequest::Ping {} => WorldResponse::Ping(Ok("pong".into())),
+            WorldRequest::Echo { value } => WorldResponse::Echo(Ok(value)),
+            WorldRequest::Delay { millis } => {
+                tokio::time::sleep(Duration::from_millis(millis)).await;
+                WorldResponse::Delay(Ok(format!("waited {}ms", millis)))
+            }
+            WorldRequest::Subscribe { .. } => WorldResponse::Subscribe(Ok(())),
+            request => panic!("the test server doesn't answer {:?}", request),
+        }
+    }
+    .boxed_local()
+}
+
+/// Resolves once the transport behind `handle` reached a state `reached` accepts.
+pub async fn until(handle: &TransportHandle, reached: impl Fn(&ConnectionState) -> bool) {
+    let waiting = async {
+        while !reached(&handle.state()) {
+            sleep(Duration::from_millis(1)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .unwrap_or_else(|_| panic!("still {}", handle.state()));
+}
+
+/// The end of a loopback a client sends on.
+pub type ClientEnd = Loopback<Response<WorldResponse>, ClientMessage<WorldRequest>>;
+
+/// Spawns a server answering with `answer` on one end of a new loopback. Hands back the other
+/// end and what resolves once the server saw the client go away.
+pub fn serve<F, Fut>(answer: F) -> (ClientEnd, oneshot::Receiver<()>)
+where
+    F: Fn(WorldRequest) -> Fut + Clone + 'static,
+    Fut: Future<Output = WorldResponse> + 'static,
+{
+    let (client, server) = loopback();
+    let (stopped, noticed) = oneshot::channel();
+    spawn_local(async move {
+        rpc::testing::serve(server, move |_, request| answer(request)).await;
+        let _ = stopped.send(());
+    });
+    (client, noticed)
+}
+
+/// A client of a server answering with `answer`, see [`serve`].
+pub fn world<F, Fut>(answer: F) -> WorldClient
+where
+    F: Fn(WorldRequest) -> Fut + Clone + 'static,
+    Fut: Future<Output = WorldResponse> + 'static,
+{
+    let (client, _) = serve(answer);
+    connect(client)
+}
+
+/// A client on `transport`, its dispatch spawned.
+pub fn connect<T>(transport: T) -> WorldClient
+where
+    T: tarpc::Transport<ClientMessage<WorldRequest>, Response<WorldResponse>> + 'static,
+{
+    let client = WorldClient::new(tarpc::client::Config::default(), transport);
+    let dispatch = client.dispatch;
+    spawn_local(async move {
+        let _ = dispatch.await;
+    });
+    client.client
+}
+
+/// A server answering with [`answer`] that a test takes down and brings back, the way one
+/// restarts. A [`transport`] opens its loopbacks to it.
+#[derive(Clone, Default)]
+pub struct Restartable {
+    up: Rc<Cell<bool>>,
+    running: Rc<RefCell<Vec<JoinHandle<()>>>>,
+}
+
+impl Restartable {
+    /// A server that is up already.
+    pub fn up() -> Self {
+        let server = Self::default();
+        server.start();
+        server
+    }
+
+    pub fn start(&self) {
+        self.up.set(true);
+    }
+
+    /// Drops every connection to the server, and fails opening new ones until it is started
+    /// again.
+    pub fn stop(&self) {
+        self.up.set(false);
+        for task in self.running.borrow_mut().drain(..) {
+            task.abort();
+        }
+    }
+
+    fn open(&self) -> future::Ready<Result<ClientEnd, TransportError>> {
+        if !self.up.get() {
+            return future::ready(Err(TransportError::HandshakeFailed {
+                code: ABNORMAL_CLOSURE,
+                reason: "the server is down".into(),
+            }));
+        }
+        let (client, server) = loopback();
+        let task = spawn_local(rpc::testing::serve(server, |_, request| answer(request)));
+        self.running.borrow_mut().push(task);
+        future::ready(Ok(client))
+    }
+}
+
+/// What [`transport`] hands out.
+pub type Transport = WsTransport<Response<WorldResponse>, ClientMessage<WorldRequest>, Json>;
+
+/// How [`transport`] reconnects unless `options.reconnect` says otherwise.
+const RECONNECT: ReconnectPolicy = ReconnectPolicy {
+    initial_delay: Duration::from_millis(10),
+    max_delay: Duration::from_millis(10),
+    multiplier: 1,
+    max_attempts: None,
+};
+
+/// A transport to `server` in text mode, driven like the socket drivers drive theirs. The first
+/// loopback is opened the way the driver reconnects, so what is sent before it opened waits in
+/// the queue like it does while reconnecting.
+pub fn transport(server: &Restartable, options: ConnectOptions) -> (Transport, TransportHandle) {
+    let (transport, handle, ends) = channels(Json, &options);
+    spawn_local(drive(ends, options, server.clone()));
+    (transport, handle)
+}
+
+async fn drive(ends: Ends<Response<WorldResponse>>, options: ConnectOptions, server: Restartable) {
+    let Ends {
+        shared,
+        mut incoming,
+        mut outgoing,
+        ..
+    } = ends;
+    let policy = options.reconnect.clone().unwrap_or(RECONNECT);
+    let mut lost = CloseInfo::abnormal("the server never came up");
+    while let Some(mut connection) =
+        reconnect("loopback", &policy, &shared, &incoming, || server.open()).await
+    {
+        let ended = loop {
+            select! {
+                response = connection.next().fuse() => match response {
+                    Some(Ok(response)) => {
+                        let envelope = Ok(Envelope::Message(response));
+                        if let Err(ended) = forward(&mut incoming, &options, envelope).await {
+                            break ended;
+                        }
+                    }
+                    _ => break Ended::Remote(CloseInfo::abnormal("the server went away")),
+                },
+                frame = outgoing.next() => match frame {
+                    Some(frame) => {
+                        shared.queued.set(shared.queued.get().saturating_sub(1));
+                        shared.written(1);
+                        shared.record_sent(wire_size(&frame));
+                        if let Some(message) = message(&frame) {
+                            if connection.send(message).await.is_err() {
+                                break Ended::Remote(CloseInfo::abnormal("the server went away"));
+                            }
+                        }
+                        if shared.flushed(options.flush_threshold) {
+                            shared.wake_flush();
+                        }
+                    }
+                    None => break Ended::Local,
+                },
+            }
+        };
+        match ended {
+            Ended::Remote(info) => lost = info,
+            Ended::Local | Ended::Closed(_) => {
+                shared.finish();
+                return;
+            }
+        }
+    }
+    shared.closed(&lost);
+    let _ = incoming.try_send(Err(lost.into()));
+    shared.finish();
+}
+
+/// The tarpc message in a frame the transport queued, `None` for heartbeats and the like.
+fn message(frame: &WsMessage) -> Option<ClientMessage<WorldRequest>> {
+    let WsMessage::Text(text) = frame else {
+        return None;
+    };
+    match Json.decode_text(text).ok()? {
+        Envelope::Message(message) | Envelope::Tagged(message, _) | Envelope::OneWay(message) => {
+            Some(message)
+        }
+        _ => None,
+    }
+}
+
+/// A transport on a socket whose server reads `bytes` bytes every `every`, and the bytes the
+/// socket holds, its `bufferedAmount`. It is driven like the browser drives its socket: the
+/// buffered amount is checked after every write and polled with [`drain_tick`] meanwhile.
+pub fn slow_reader(
+    options: ConnectOptions,
+    bytes: u32,
+    every: Duration,
+) -> (Transport, TransportHandle, Rc<Cell<u32>>) {
+    let (transport, handle, ends) = channels(Json, &options);
+    let buffered = Rc::new(Cell::new(0));
+    let reading = buffered.clone();
+    spawn_local(async move {
+        loop {
+            sleep(every).await;
+            reading.set(reading.get().saturating_sub(bytes));
+        }
+    });
+    spawn_local(drive_slowly(ends, options.flush_threshold, buffered.clone()));
+    (transport, handle, buffered)
+}
+
+async fn drive_slowly(
+    ends: Ends<Response<WorldResponse>>,
+    threshold: u32,
+    buffered: Rc<Cell<u32>>,
+) {
+    let Ends {
+        shared,
+        mut outgoing,
+        // Nothing is answered, but the transport's stream only ends with the driver.
+        incoming: _incoming,
+        ..
+    } = ends;
+    loop {
+        select! {
+            frame = outgoing.next() => match frame {
+                Some(frame) => {
+                    let size = wire_size(&frame);
+                    buffered.set(buffered.get() + size as u32);
+                    shared.queued.set(shared.queued.get().saturating_sub(1));
+                    shared.record_sent(size);
+                    shared.set_buffered(buffered.get(), threshold);
+                }
+                None => break,
+            },
+            _ = Box::pin(drain_tick(&shared, threshold)).fuse() => {
+                shared.set_buffered(buffered.get(), threshold);
+            },
+        }
+    }
+    shared.finish();
+}
@@ -0,0 +1,2276 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::{Rc, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either, LocalBoxFuture};
+use futures::{ready, select, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use gloo_timers::future::{IntervalStream, TimeoutFuture};
+use log::{error, info, trace};
+use pharos::{Events, Observable, ObserveConfig};
+use rpc::codec::Codec;
+use rpc::envelope::UNAUTHORIZED;
+use rpc::framing::{self, FrameDecoder, FrameError};
+use rpc::handshake::{self, Hello};
+use rpc::schema;
+#[cfg(feature = "json")]
+use rpc::push::Notification;
+#[cfg(feature = "json")]
+use rpc::stream::StreamRouter;
+use rpc::wire::{self, Encoder, Frame};
+use rpc::Envelope;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasm_bindgen_futures::spawn_local;
+use ws_stream_wasm::*;
+
+use crate::error::TransportError;
+#[cfg(feature = "json")]
+use crate::push::Notifications;
+use crate::replay::{ReplayBuffer, ReplayPolicy, Replayable, Tracking};
+use crate::socket;
+
+/// How the transport retries after the server goes away.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Wait before the first attempt.
+    pub initial_delay: Duration,
+    /// Upper bound for the wait between two attempts.
+    pub max_delay: Duration,
+    /// Factor applied to the wait after every failed attempt.
+    pub multiplier: u32,
+    /// Give up after this many failed attempts. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+/// How messages are put into WebSocket frames.
+///
+/// The server answers in whatever mode the client uses, so this only needs to be chosen on the
+/// client. It is the [`Codec`] every transport uses unless given another one, see
+/// [`connect_with_codec`]. Binary frames are compressed as set by
+/// [`ConnectOptions::compress_above`]; the socket's `binaryType` is `arraybuffer`, so they
+/// arrive as bytes without a `Blob` round trip. The MessagePack, CBOR and postcard modes need
+/// the feature of the same name on client and server.
+pub use rpc::codec::FrameMode;
+
+/// Serializes `envelope` with `codec`, compressing binary messages larger than
+/// `compress_above`.
+pub(crate) fn encode<C: Codec, T: Serialize>(
+    codec: &C,
+    envelope: &Envelope<T>,
+    compress_above: Option<usize>,
+) -> Result<WsMessage, TransportError> {
+    Encoder::default()
+        .frame(codec, envelope, compress_above)
+        .map(to_message)
+        .map_err(TransportError::serialization)
+}
+
+fn to_message(frame: Frame) -> WsMessage {
+    match frame {
+        Frame::Text(text) => WsMessage::Text(text),
+        Frame::Binary(bytes) => WsMessage::Binary(bytes),
+    }
+}
+
+/// What to do when the server sends faster than tarpc consumes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading from the socket until there is room in the queue again.
+    #[default]
+    Pause,
+    /// Drop the connection.
+    Close,
+}
+
+/// What to do with a received message larger than [`ConnectOptions::max_message_size`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Drop the message and yield a [`TransportError::MessageTooLarge`] in its place.
+    #[default]
+    Error,
+    /// Drop the connection.
+    Close,
+}
+
+/// Largest message sent or received by default.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Messages buffered between the socket and tarpc by default.
+pub const DEFAULT_INCOMING_CAPACITY: usize = 256;
+
+/// Bearer token proving who is connecting, see the server's `auth` module.
+///
+/// Browsers can't set headers on a WebSocket upgrade, so the token travels in the URL or in the
+/// first message. Either way [`connect`] waits for the server to accept it. The fallback and
+/// WebTransport connections always pass it in the URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Auth {
+    /// Appended to the URL as the `token` query parameter. The simplest, but the token may end
+    /// up in server and proxy logs.
+    Query(String),
+    /// Sent as an [`Envelope::Auth`] right after the socket opened.
+    Message(String),
+}
+
+impl Auth {
+    pub fn token(&self) -> &str {
+        match self {
+            Auth::Query(token) | Auth::Message(token) => token,
+        }
+    }
+}
+
+/// `url` with `token` added to its query string.
+pub(crate) fn with_token(url: &str, token: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}token={}", url, separator, encode_uri_component(token))
+}
+
+/// Percent-encodes `value` like JavaScript's `encodeURIComponent`, which isn't available
+/// outside the browser.
+fn encode_uri_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'-' | b'_' | b'.' | b'!' | b'~' | b'*' | b'\'' | b'(' | b')' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Messages the sink accepts by default while the connection is being opened or reestablished.
+pub const DEFAULT_PENDING_CAPACITY: usize = 64;
+
+/// How often the socket's `bufferedAmount` is checked while a flush waits for it to drain.
+pub(crate) const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long [`TransportHandle::close`] waits for the server to complete the close handshake.
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When a connection counts as dead for not answering heartbeats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatTimeout {
+    /// How long the server may take to answer a heartbeat. Any received message counts.
+    pub timeout: Duration,
+    /// Close the connection after this many heartbeats in a row went unanswered.
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatTimeout {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_missed: 2,
+        }
+    }
+}
+
+/// Largest batch of messages sent in one binary frame by default, in bytes.
+pub const DEFAULT_MAX_BATCH_BYTES: usize = 64 * 1024;
+
+/// Most messages sent in one binary frame by default.
+pub const DEFAULT_MAX_BATCH_MESSAGES: usize = 64;
+
+/// How binary messages are batched, see [`ConnectOptions::batch`].
+///
+/// When the driver gets to send a message, the others tarpc queued meanwhile, typically those
+/// of the same turn of the event loop, go along in the same frame, each behind its length
+/// prefix. Nothing waits for more messages to arrive, so batching adds no latency of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchPolicy {
+    /// Stop adding messages once the frame holds this many bytes.
+    pub max_bytes: usize,
+    /// Most messages in one frame.
+    pub max_messages: usize,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_MAX_BATCH_BYTES,
+            max_messages: DEFAULT_MAX_BATCH_MESSAGES,
+        }
+    }
+}
+
+/// `first` with the binary messages queued behind it, as many as `policy` allows, and how many
+/// messages that is. Binary frames already carry their length prefix, so they are simply
+/// appended; the receiving end splits them with its [`FrameDecoder`].
+pub(crate) fn batch(
+    first: WsMessage,
+    outgoing: &mut mpsc::UnboundedReceiver<WsMessage>,
+    policy: Option<&BatchPolicy>,
+) -> (WsMessage, usize) {
+    let (mut frame, policy) = match (first, policy) {
+        (WsMessage::Binary(frame), Some(policy)) => (frame, policy),
+        (first, _) => return (first, 1),
+    };
+    let mut messages = 1;
+    while messages < policy.max_messages && frame.len() < policy.max_bytes {
+        match outgoing.next().now_or_never() {
+            // Every message of a binary codec goes in a binary frame.
+            Some(Some(WsMessage::Binary(next))) => frame.extend_from_slice(&next),
+            Some(Some(WsMessage::Text(_))) => unreachable!("binary codec queued a text frame"),
+            // Closed or empty; the closed channel is noticed on the next read.
+            Some(None) | None => break,
+        }
+        messages += 1;
+    }
+    (WsMessage::Binary(frame), messages)
+}
+
+/// The worker a WebSocket runs in, with the URL of its script.
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WorkerScript {
+    /// A worker of this page only, see [`DEFAULT_WORKER_URL`](crate::worker::DEFAULT_WORKER_URL).
+    Dedicated(String),
+    /// A worker shared by every tab of the origin, so they use one connection, see
+    /// [`DEFAULT_SHARED_WORKER_URL`](crate::worker::DEFAULT_SHARED_WORKER_URL).
+    Shared(String),
+}
+
+/// How long opening the socket may take by default.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How much of a payload tracing logs by default.
+pub const DEFAULT_TRACE_PAYLOAD_LIMIT: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// Text or binary frames.
+    pub frames: FrameMode,
+    /// Give up on opening the socket after this long. Applies to every reconnect attempt too.
+    pub connect_timeout: Option<Duration>,
+    /// Reconnect automatically when the connection drops. `None` ends the transport instead.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Send a heartbeat this often so idle connections aren't dropped by proxies.
+    pub keepalive: Option<Duration>,
+    /// Close connections that stop answering the `keepalive` heartbeats, e.g. after the
+    /// machine slept, with [`HEARTBEAT_TIMEOUT`]. Reconnecting then works as after any other
+    /// failure. Does nothing without `keepalive`.
+    pub heartbeat_timeout: Option<HeartbeatTimeout>,
+    /// Received messages buffered until tarpc picks them up.
+    pub incoming_capacity: usize,
+    /// Behaviour once `incoming_capacity` messages are waiting.
+    pub overflow: OverflowPolicy,
+    /// A flush completes once the browser has at most this many bytes left to send.
+    pub flush_threshold: u32,
+    /// Base URL of the server's HTTP endpoints, e.g. `http://127.0.0.1:8084`. When set and the
+    /// WebSocket can't be opened, [`build_client`](crate::rpc_client::build_client) falls back to
+    /// POSTs and server-sent events, see [`crate::sse`].
+    #[cfg(feature = "json")]
+    pub fallback_url: Option<String>,
+    /// Deflate binary messages larger than this many bytes, see [`rpc::compression`]. Text
+    /// frames are never compressed. The server compresses its large responses once the client
+    /// does.
+    pub compress_above: Option<usize>,
+    /// Largest serialized message in either direction, in bytes. Larger outgoing messages are
+    /// rejected by the sink, larger incoming ones are handled according to `oversize`. For
+    /// compressed messages this bounds both the compressed and the inflated size.
+    pub max_message_size: usize,
+    /// Behaviour once a received message exceeds `max_message_size`.
+    pub oversize: OversizePolicy,
+    /// Messages the sink accepts while the connection is being opened or reestablished. They
+    /// are sent in order once it is open, further sends wait for it. If opening fails or
+    /// reconnecting gives up they are dropped and the transport fails with how the connection
+    /// ended.
+    pub pending_capacity: usize,
+    /// Token to authenticate with. The server has to accept it before any RPC is sent.
+    pub auth: Option<Auth>,
+    /// WebSockets to spread requests over, see [`crate::pool`]. Every one of them is a
+    /// separate connection to the server.
+    pub pool_size: usize,
+    /// Run the WebSocket in a worker, see [`crate::worker`]. `pool_size` is ignored then.
+    #[cfg(feature = "json")]
+    pub worker: Option<WorkerScript>,
+    /// Start with tracing on, see [`TransportHandle::set_trace`].
+    pub trace: bool,
+    /// Traced payloads are cut off after this many bytes.
+    pub trace_payload_limit: usize,
+    /// Send requests again that were unanswered when the connection dropped, once `reconnect`
+    /// opened a new one, see [`crate::replay`].
+    pub replay: Option<ReplayPolicy>,
+    /// Check that the server speaks our protocol version before any RPC is sent, see
+    /// [`rpc::handshake`]. Servers from before the check never answer it, so connecting to
+    /// them needs this off.
+    pub version_check: bool,
+    /// Send the binary messages queued at once in a single frame, see [`BatchPolicy`]. `None`
+    /// sends every message in a frame of its own. Text frames are never batched. Only the
+    /// WebSocket drivers of this module and `native` batch, the server splits such frames
+    /// and batches its responses too once it received one.
+    pub batch: Option<BatchPolicy>,
+    /// Send and receive binary messages as base64 in text frames, for networks that corrupt
+    /// binary frames, see [`rpc::base64`]. Asked for during the handshake, so the server has
+    /// to support it. Costs a third more bytes; ignored for text modes. Only the WebSocket
+    /// driver of this module and pools of it use it.
+    pub base64: bool,
+    /// Receives the server's streams, see [`rpc::stream`]. Without it their frames are
+    /// dropped. Pass a clone and keep the router to open streams with.
+    #[cfg(feature = "json")]
+    pub streams: Option<StreamRouter>,
+    /// Receives the server's notifications, see [`crate::push`]. Clones share their
+    /// subscribers, so every connection made with these options feeds the same ones.
+    #[cfg(feature = "json")]
+    pub notifications: Notifications,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            frames: FrameMode::default(),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            reconnect: None,
+            keepalive: None,
+            heartbeat_timeout: None,
+            incoming_capacity: DEFAULT_INCOMING_CAPACITY,
+            overflow: OverflowPolicy::default(),
+            flush_threshold: 0,
+            #[cfg(feature = "json")]
+            fallback_url: None,
+            compress_above: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            oversize: OversizePolicy::default(),
+            pending_capacity: DEFAULT_PENDING_CAPACITY,
+            auth: None,
+            pool_size: 1,
+            #[cfg(feature = "json")]
+            worker: None,
+            trace: false,
+            trace_payload_limit: DEFAULT_TRACE_PAYLOAD_LIMIT,
+            replay: None,
+            version_check: true,
+            batch: Some(BatchPolicy::default()),
+            base64: false,
+            #[cfg(feature = "json")]
+            streams: None,
+            #[cfg(feature = "json")]
+            notifications: Notifications::default(),
+        }
+    }
+}
+
+pub use crate::socket::ABNORMAL_CLOSURE;
+
+/// Close code for a connection dropped over a message exceeding the size limit.
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Close code for a connection dropped locally as it stopped answering heartbeats. It is
+/// never sent to the server, which only sees a normal close.
+pub const HEARTBEAT_TIMEOUT: u16 = 4408;
+
+/// Why the connection to the server ended.
+///
+/// The transport stream ends with the matching [`TransportError::ConnectionClosed`], or
+/// [`TransportError::AbnormalClosure`] for connections that dropped without a close handshake,
+/// and [`TransportHandle::on_close`] callbacks receive it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseInfo {
+    pub code: u16,
+    pub reason: String,
+    pub was_clean: bool,
+}
+
+impl CloseInfo {
+    pub(crate) fn new(code: u16, reason: &str, was_clean: bool) -> Self {
+        Self {
+            code,
+            reason: reason.to_string(),
+            was_clean,
+        }
+    }
+
+    pub(crate) fn abnormal(reason: impl fmt::Display) -> Self {
+        Self {
+            code: ABNORMAL_CLOSURE,
+            reason: reason.to_string(),
+            was_clean: false,
+        }
+    }
+}
+
+impl From<CloseEvent> for CloseInfo {
+    fn from(event: CloseEvent) -> Self {
+        Self {
+            code: event.code,
+            reason: event.reason,
+            was_clean: event.was_clean,
+        }
+    }
+}
+
+impl fmt::Display for CloseInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.was_clean {
+            write!(f, "connection closed cleanly with code {}", self.code)?;
+        } else {
+            write!(f, "connection dropped abnormally with code {}", self.code)?;
+        }
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CloseInfo {}
+
+/// Where the connection behind a transport stands, see [`TransportHandle::on_state_change`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// A connection attempt is in flight. The WebSockets of [`connect`] are handed out in this
+    /// state.
+    Connecting,
+    /// Messages flow. The transports of workers, pools and the fallback are handed out in this
+    /// state.
+    #[default]
+    Connected,
+    /// The connection dropped and attempt number `attempt` to reestablish it is pending.
+    Reconnecting { attempt: u32 },
+    /// The connection ended for good.
+    Closed(CloseInfo),
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "connecting"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Reconnecting { attempt } => {
+                write!(f, "reconnecting (attempt {})", attempt)
+            }
+            ConnectionState::Closed(info) => write!(f, "{}", info),
+        }
+    }
+}
+
+/// Traffic of a transport since it was created or its stats were last reset, reconnects
+/// included.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransportStats {
+    /// WebSocket messages, heartbeats included.
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Bytes of those messages as they went over the wire.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// `Date.now()` when the last message was sent or received, in milliseconds.
+    pub last_activity: Option<f64>,
+}
+
+impl TransportStats {
+    fn add(&mut self, other: &TransportStats) {
+        self.messages_sent += other.messages_sent;
+        self.messages_received += other.messages_received;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.last_activity = match (self.last_activity, other.last_activity) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+/// A callback of [`TransportHandle::on_close`].
+type CloseCallback = Box<dyn Fn(&CloseInfo)>;
+
+/// A callback of [`TransportHandle::on_state_change`].
+pub(crate) type StateCallback = Box<dyn Fn(&ConnectionState)>;
+
+/// State shared between the transport, its handles and the driver.
+#[derive(Default)]
+pub(crate) struct Shared {
+    on_close: RefCell<Vec<CloseCallback>>,
+    on_state_change: RefCell<Vec<StateCallback>>,
+    state: RefCell<ConnectionState>,
+    /// Messages accepted by the sink that the driver hasn't written to the socket yet.
+    pub(crate) queued: Cell<usize>,
+    /// The socket's `bufferedAmount` as last seen by the driver.
+    pub(crate) buffered: Cell<u32>,
+    stats: Cell<TransportStats>,
+    /// The driver is waiting for a new connection, messages pile up until it opens.
+    connecting: Cell<bool>,
+    /// The driver has exited, nothing will be written anymore.
+    finished: Cell<bool>,
+    /// How the connection ended for good.
+    ended: RefCell<Option<CloseInfo>>,
+    ready_waker: RefCell<Option<Waker>>,
+    flush_waker: RefCell<Option<Waker>>,
+    close_waker: RefCell<Option<Waker>>,
+    /// Connections of a pool, which only counts as flushed once they are.
+    members: RefCell<Vec<Rc<Shared>>>,
+    /// The pool this connection belongs to, woken along when this one flushed.
+    pool: RefCell<Weak<Shared>>,
+    /// Log every message, see [`TransportHandle::set_trace`].
+    trace: Cell<bool>,
+    trace_payload_limit: Cell<usize>,
+    /// Unanswered requests, only kept by drivers that replay them.
+    replay: RefCell<Option<ReplayBuffer>>,
+    /// How opening the first connection went, for transports handed out before it opened.
+    opening: RefCell<Option<oneshot::Receiver<Result<(), TransportError>>>>,
+    /// Ended along with the transport, see [`crate::push`].
+    #[cfg(feature = "json")]
+    notifications: Notifications,
+}
+
+impl Shared {
+    pub(crate) fn closed(&self, info: &CloseInfo) {
+        *self.ended.borrow_mut() = Some(info.clone());
+        self.set_state(ConnectionState::Closed(info.clone()));
+        for callback in self.on_close.borrow().iter() {
+            callback(info);
+        }
+    }
+
+    pub(crate) fn set_state(&self, state: ConnectionState) {
+        if *self.state.borrow() == state {
+            return;
+        }
+        info!("Connection state: {}", state);
+        self.state.replace(state.clone());
+        for callback in self.on_state_change.borrow().iter() {
+            callback(&state);
+        }
+    }
+
+    /// The error sends fail with once the driver is gone.
+    fn closed_error(&self) -> TransportError {
+        match &*self.ended.borrow() {
+            Some(info) => info.clone().into(),
+            None => CloseInfo::abnormal("the connection is closed").into(),
+        }
+    }
+
+    /// Marks a transport handed out before its first connection opened, see [`open_first`].
+    pub(crate) fn start_opening(&self) -> oneshot::Sender<Result<(), TransportError>> {
+        let (opened, opening) = oneshot::channel();
+        *self.opening.borrow_mut() = Some(opening);
+        self.set_connecting(true);
+        self.set_state(ConnectionState::Connecting);
+        opened
+    }
+
+    fn set_connecting(&self, connecting: bool) {
+        self.connecting.set(connecting);
+        if !connecting {
+            self.wake_ready();
+        }
+    }
+
+    fn wake_ready(&self) {
+        if let Some(waker) = self.ready_waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+
+    /// Records the socket's `bufferedAmount`, waking a flush that waited for it to drain.
+    pub(crate) fn set_buffered(&self, amount: u32, threshold: u32) {
+        self.buffered.set(amount);
+        if self.flushed(threshold) {
+            self.wake_flush();
+        }
+    }
+
+    pub(crate) fn flushed(&self, threshold: u32) -> bool {
+        self.queued.get() == 0
+            && self.buffered.get() <= threshold
+            && self
+                .members
+                .borrow()
+                .iter()
+                .all(|member| member.finished.get() || member.flushed(threshold))
+    }
+
+    pub(crate) fn record_sent(&self, bytes: usize) {
+        let mut stats = self.stats.get();
+        stats.messages_sent += 1;
+        stats.bytes_sent += bytes as u64;
+        stats.last_activity = Some(now());
+        self.stats.set(stats);
+    }
+
+    /// Counts a message as it came off the wire, and traces it.
+    pub(crate) fn record_received(&self, payload: Payload<'_>) {
+        self.trace("Received", payload);
+        let mut stats = self.stats.get();
+        stats.messages_received += 1;
+        stats.bytes_received += payload.len() as u64;
+        stats.last_activity = Some(now());
+        self.stats.set(stats);
+    }
+
+    /// A pool's traffic is that of its members.
+    fn stats(&self) -> TransportStats {
+        let mut stats = self.stats.get();
+        for member in self.members.borrow().iter() {
+            stats.add(&member.stats());
+        }
+        stats
+    }
+
+    /// Logs `payload` at trace level while tracing is on.
+    pub(crate) fn trace(&self, direction: &str, payload: Payload<'_>) {
+        if self.trace.get() && log::log_enabled!(log::Level::Trace) {
+            trace!("{} {}", direction, payload.describe(self.trace_payload_limit.get()));
+        }
+    }
+
+    /// A pool's members trace along, each logging what it receives.
+    fn set_trace(&self, enabled: bool) {
+        self.trace.set(enabled);
+        for member in self.members.borrow().iter() {
+            member.set_trace(enabled);
+        }
+    }
+
+    /// Starts keeping requests for [`ConnectOptions::replay`].
+    pub(crate) fn enable_replay(&self, policy: &Option<ReplayPolicy>) {
+        *self.replay.borrow_mut() = policy.clone().map(ReplayBuffer::new);
+    }
+
+    /// The driver took `messages` messages off the queue.
+    pub(crate) fn written(&self, messages: usize) {
+        if let Some(replay) = self.replay.borrow_mut().as_mut() {
+            replay.written(messages);
+        }
+    }
+
+    /// Requests to send again on a new connection, before the queue.
+    pub(crate) fn replayed(&self) -> Vec<WsMessage> {
+        let frames = self
+            .replay
+            .borrow()
+            .as_ref()
+            .map_or_else(Vec::new, ReplayBuffer::frames);
+        if !frames.is_empty() {
+            info!("Replaying {} unanswered requests", frames.len());
+        }
+        frames
+    }
+
+    fn reset_stats(&self) {
+        self.stats.set(TransportStats::default());
+        for member in self.members.borrow().iter() {
+            member.reset_stats();
+        }
+    }
+
+    pub(crate) fn wake_flush(&self) {
+        if let Some(waker) = self.flush_waker.borrow_mut().take() {
+            waker.wake();
+        }
+        if let Some(pool) = self.pool.borrow().upgrade() {
+            pool.wake_flush();
+        }
+    }
+
+    /// Makes `member` part of this pool, see [`crate::pool`].
+    pub(crate) fn adopt(self: &Rc<Self>, member: Rc<Shared>) {
+        *member.pool.borrow_mut() = Rc::downgrade(self);
+        self.members.borrow_mut().push(member);
+    }
+
+    pub(crate) fn finish(&self) {
+        #[cfg(feature = "json")]
+        if !self.finished.get() {
+            self.notifications.detach();
+        }
+        self.finished.set(true);
+        if !matches!(*self.state.borrow(), ConnectionState::Closed(_)) {
+            // Otherwise the application dropped the transport, which closes normally.
+            self.set_state(ConnectionState::Closed(CloseInfo {
+                code: 1000,
+                reason: "transport dropped".to_string(),
+                was_clean: true,
+            }));
+        }
+        let dropped = self.queued.get();
+        if dropped > 0 {
+            info!("Dropping {} messages that were never sent", dropped);
+        }
+        self.wake_ready();
+        self.wake_flush();
+        if let Some(waker) = self.close_waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Requests from a [`TransportHandle`] to the driver.
+pub(crate) enum Control {
+    Close {
+        code: u16,
+        reason: String,
+        done: oneshot::Sender<Result<(), TransportError>>,
+    },
+}
+
+/// Application side handle onto the connection driven by the transport.
+#[derive(Clone)]
+pub struct TransportHandle {
+    shared: Rc<Shared>,
+    control: mpsc::UnboundedSender<Control>,
+    #[cfg(feature = "json")]
+    notifications: Notifications,
+}
+
+impl TransportHandle {
+    /// Registers `callback` to run once the connection ended for good: the application closed
+    /// it, the server closed it, or the network failed and reconnecting is disabled or gave up.
+    pub fn on_close(&self, callback: impl Fn(&CloseInfo) + 'static) {
+        self.shared.on_close.borrow_mut().push(Box::new(callback));
+    }
+
+    /// Registers `callback` to run on every change of [`state`](Self::state), from whichever
+    /// task causes it.
+    pub fn on_state_change(&self, callback: impl Fn(&ConnectionState) + 'static) {
+        self.shared
+            .on_state_change
+            .borrow_mut()
+            .push(Box::new(callback));
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.shared.state.borrow().clone()
+    }
+
+    /// Counts what went over the wire so far.
+    pub fn stats(&self) -> TransportStats {
+        self.shared.stats()
+    }
+
+    /// Starts counting [`stats`](Self::stats) from zero again.
+    pub fn reset_stats(&self) {
+        self.shared.reset_stats();
+    }
+
+    /// Logs every message through `log::trace!` while `enabled`: its direction, size, the
+    /// tarpc request id if it is a JSON request or response, and the payload up to
+    /// [`ConnectOptions::trace_payload_limit`] bytes. Outgoing messages are logged as the
+    /// transport accepts them, incoming ones as they arrive. Costs nothing but a check while
+    /// off or while the log level is above trace.
+    pub fn set_trace(&self, enabled: bool) {
+        self.shared.set_trace(enabled);
+    }
+
+    /// Closes the connection with a close frame carrying `code` and `reason`.
+    ///
+    /// `code` must be 1000 or in 3000..=4999 as the browser rejects everything else. Resolves
+    /// once the server completed the close handshake or [`CLOSE_TIMEOUT`] passed. The tarpc
+    /// dispatch then finishes without an error. Closing an already closed transport does
+    /// nothing.
+    pub async fn close(&self, code: u16, reason: &str) -> Result<(), TransportError> {
+        let (done, closed) = oneshot::channel();
+        let control = Control::Close {
+            code,
+            reason: reason.to_string(),
+            done,
+        };
+        if self.control.unbounded_send(control).is_err() {
+            return Ok(());
+        }
+        // A dropped sender means the driver finished before getting to this request.
+        closed.await.unwrap_or(Ok(()))
+    }
+
+    /// Closes the connection normally, like [`close`](Self::close) with code 1000. The tarpc
+    /// dispatch finishes, so requests still waiting for a response fail at once, and so does
+    /// every call made afterwards. tarpc reports those as disconnected,
+    /// [`closed_error`](Self::closed_error) tells why.
+    pub async fn disconnect(&self) {
+        // Code 1000 is always accepted, so closing can't fail.
+        let _ = self.close(1000, "disconnected by the client").await;
+    }
+
+    /// The server's notifications from now on, see [`Notifications::subscribe`].
+    #[cfg(feature = "json")]
+    pub fn notifications(&self) -> mpsc::Receiver<Notification> {
+        self.notifications.subscribe()
+    }
+
+    /// Like [`notifications`](Self::notifications), ending once the connection is lost.
+    #[cfg(feature = "json")]
+    pub(crate) fn connection_notifications(&self) -> mpsc::Receiver<Notification> {
+        self.notifications.subscribe_connection()
+    }
+
+    /// Waits for the first connection to open, failing with why it couldn't be opened. Only
+    /// the first call waits, later ones answer how the transport stands.
+    pub(crate) async fn opened(&self) -> Result<(), TransportError> {
+        let opening = self.shared.opening.borrow_mut().take();
+        match opening {
+            Some(opening) => opening.await.unwrap_or_else(|_| Err(self.shared.closed_error())),
+            None => self.closed_error().map_or(Ok(()), Err),
+        }
+    }
+
+    /// The error calls fail with once the connection ended for good, `None` before.
+    pub fn closed_error(&self) -> Option<TransportError> {
+        match self.state() {
+            ConnectionState::Closed(info) => Some(info.into()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for TransportHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransportHandle")
+            .field("state", &self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Transport handed to tarpc.
+///
+/// The socket itself is owned by a task spawned in [`connect`], which keeps the transport
+/// alive across reconnects so the `WorldClient` dispatch never notices them.
+pub struct WsTransport<Item, SinkItem, C = FrameMode> {
+    incoming: mpsc::Receiver<Result<Item, TransportError>>,
+    outgoing: mpsc::UnboundedSender<WsMessage>,
+    shared: Rc<Shared>,
+    codec: C,
+    flush_threshold: u32,
+    compress_above: Option<usize>,
+    max_message_size: usize,
+    pending_capacity: usize,
+    /// Kept across messages, so sending doesn't allocate more than the frame itself.
+    encoder: Encoder,
+    ghost: PhantomData<fn(SinkItem)>,
+}
+
+impl<Item, SinkItem, C> Stream for WsTransport<Item, SinkItem, C>
+where
+    Item: Replayable,
+    C: Unpin,
+{
+    type Item = Result<Item, TransportError>;
+
+    /// Skips second responses to replayed requests.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let item = ready!(this.incoming.poll_next_unpin(cx));
+            if let Some(Ok(message)) = &item {
+                if let Tracking::Response(id) = message.tracking() {
+                    let mut replay = this.shared.replay.borrow_mut();
+                    if !replay.as_mut().is_none_or(|replay| replay.answer(id)) {
+                        info!("Dropping a second response to request {}", id);
+                        continue;
+                    }
+                }
+            }
+            return Poll::Ready(item);
+        }
+    }
+}
+
+impl<Item, SinkItem, C> Sink<SinkItem> for WsTransport<Item, SinkItem, C>
+where
+    SinkItem: Serialize + Replayable,
+    C: Codec + Unpin,
+{
+    type Error = TransportError;
+
+    /// Waits while reconnecting once `pending_capacity` messages are queued.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.shared.finished.get() {
+            return Poll::Ready(Err(this.shared.closed_error()));
+        }
+        if this.shared.connecting.get() && this.shared.queued.get() >= this.pending_capacity {
+            *this.shared.ready_waker.borrow_mut() = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut item: SinkItem) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let tracking = item.tracking();
+        let one_way = item.one_way();
+        let envelope = match item.take_metadata() {
+            _ if one_way => Envelope::OneWay(item),
+            Some(metadata) => Envelope::Tagged(item, metadata),
+            None => Envelope::Message(item),
+        };
+        let frame = this
+            .encoder
+            .frame(&this.codec, &envelope, this.compress_above)
+            .map(to_message)
+            .map_err(TransportError::serialization)?;
+        this.shared.trace("Sending", (&frame).into());
+        let size = message_size(&frame);
+        if size > this.max_message_size {
+            return Err(TransportError::MessageTooLarge {
+                size,
+                limit: this.max_message_size,
+            });
+        }
+        // Nothing answers a one-way request, which would keep it in the buffer for good.
+        if !one_way {
+            if let Some(replay) = this.shared.replay.borrow_mut().as_mut() {
+                replay.accepted(tracking, &frame);
+            }
+        }
+        this.outgoing
+            .unbounded_send(frame)
+            .map_err(|_| this.shared.closed_error())?;
+        this.shared.queued.set(this.shared.queued.get() + 1);
+        Ok(())
+    }
+
+    /// Resolves once the driver wrote every accepted message and the browser has sent them
+    /// down to `flush_threshold` bytes, so "flushed" means "on the wire" rather than "queued".
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.shared.flushed(this.flush_threshold) {
+            return Poll::Ready(Ok(()));
+        }
+        if this.shared.finished.get() {
+            return Poll::Ready(Err(this.shared.closed_error()));
+        }
+        *this.shared.flush_waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Sends what is still queued, runs the close handshake and resolves once the socket is
+    /// closed.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.outgoing.close_channel();
+        if this.shared.finished.get() {
+            return Poll::Ready(Ok(()));
+        }
+        *this.shared.close_waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Size of the serialized message in `frame`, without the length prefix of binary frames.
+fn message_size(frame: &WsMessage) -> usize {
+    match frame {
+        WsMessage::Text(text) => text.len(),
+        WsMessage::Binary(bytes) => bytes.len().saturating_sub(framing::HEADER_LEN),
+    }
+}
+
+/// A message as it went over the wire, for stats and tracing.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Payload<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+}
+
+impl<'a> From<&'a WsMessage> for Payload<'a> {
+    fn from(frame: &'a WsMessage) -> Self {
+        match frame {
+            WsMessage::Text(text) => Payload::Text(text),
+            WsMessage::Binary(bytes) => Payload::Binary(bytes),
+        }
+    }
+}
+
+impl Payload<'_> {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Payload::Text(text) => text.len(),
+            Payload::Binary(bytes) => bytes.len(),
+        }
+    }
+
+    /// What tracing logs, with at most `limit` bytes of the payload.
+    fn describe(&self, limit: usize) -> String {
+        let len = self.len();
+        let rest = match len.saturating_sub(limit) {
+            0 => String::new(),
+            rest => format!("... ({} more bytes)", rest),
+        };
+        match self {
+            Payload::Text(text) => {
+                let request =
+                    request_id(text).map_or(String::new(), |id| format!(", request {}", id));
+                let mut end = limit.min(len);
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                format!("text message of {} bytes{}: {}{}", len, request, &text[..end], rest)
+            }
+            Payload::Binary(bytes) => {
+                let hex: String = bytes[..limit.min(len)]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                format!("binary message of {} bytes: {}{}", len, hex, rest)
+            }
+        }
+    }
+}
+
+/// Id of the tarpc request a JSON message sends, cancels or answers. Binary messages are
+/// opaque without knowing their types.
+#[cfg(feature = "json")]
+fn request_id(text: &str) -> Option<u64> {
+    let envelope: serde_json::Value = serde_json::from_str(text).ok()?;
+    ["/Message/Request/id", "/Message/Cancel/request_id", "/Message/request_id"]
+        .iter()
+        .find_map(|pointer| envelope.pointer(pointer).and_then(|id| id.as_u64()))
+}
+
+/// Without JSON there are no text messages.
+#[cfg(not(feature = "json"))]
+fn request_id(_: &str) -> Option<u64> {
+    None
+}
+
+/// Size of `frame` on the wire, as counted in [`TransportStats`].
+pub(crate) fn wire_size(frame: &WsMessage) -> usize {
+    match frame {
+        WsMessage::Text(text) => text.len(),
+        WsMessage::Binary(bytes) => bytes.len(),
+    }
+}
+
+/// The sending half of a [`WsTransport`], taking messages that are already encoded.
+pub(crate) struct FrameSink {
+    outgoing: mpsc::UnboundedSender<WsMessage>,
+    pub(crate) shared: Rc<Shared>,
+}
+
+impl FrameSink {
+    /// Queues `frame` for the driver, handing it back if the driver is gone.
+    pub(crate) fn send(&self, frame: WsMessage) -> Result<(), WsMessage> {
+        self.outgoing
+            .unbounded_send(frame)
+            .map_err(|e| e.into_inner())?;
+        self.shared.queued.set(self.shared.queued.get() + 1);
+        Ok(())
+    }
+
+    /// Lets the driver close the connection once it sent what is queued.
+    pub(crate) fn close(&self) {
+        self.outgoing.close_channel();
+    }
+}
+
+impl<Item, SinkItem, C> WsTransport<Item, SinkItem, C> {
+    pub(crate) fn split(self) -> (mpsc::Receiver<Result<Item, TransportError>>, FrameSink) {
+        let sink = FrameSink {
+            outgoing: self.outgoing,
+            shared: self.shared,
+        };
+        (self.incoming, sink)
+    }
+}
+
+/// The driver's side of the channels behind a [`WsTransport`] and its [`TransportHandle`].
+pub(crate) struct Ends<Item> {
+    pub(crate) shared: Rc<Shared>,
+    pub(crate) control: mpsc::UnboundedReceiver<Control>,
+    pub(crate) incoming: mpsc::Sender<Result<Item, TransportError>>,
+    pub(crate) outgoing: mpsc::UnboundedReceiver<WsMessage>,
+}
+
+/// Creates a transport and its handle, leaving the socket side to whoever drives `Ends`.
+pub(crate) fn channels<Item, SinkItem, C>(
+    codec: C,
+    options: &ConnectOptions,
+) -> (WsTransport<Item, SinkItem, C>, TransportHandle, Ends<Item>) {
+    #[cfg(feature = "json")]
+    options.notifications.attach();
+    let shared = Rc::new(Shared {
+        trace: Cell::new(options.trace),
+        trace_payload_limit: Cell::new(options.trace_payload_limit),
+        #[cfg(feature = "json")]
+        notifications: options.notifications.clone(),
+        ..Shared::default()
+    });
+    let (control, control_rx) = mpsc::unbounded();
+    let (incoming_tx, incoming) = mpsc::channel(options.incoming_capacity);
+    let (outgoing, outgoing_rx) = mpsc::unbounded();
+    let transport = WsTransport {
+        incoming,
+        outgoing,
+        shared: shared.clone(),
+        codec,
+        flush_threshold: options.flush_threshold,
+        compress_above: options.compress_above,
+        max_message_size: options.max_message_size,
+        pending_capacity: options.pending_capacity,
+        encoder: Encoder::default(),
+        ghost: PhantomData,
+    };
+    let handle = TransportHandle {
+        shared: shared.clone(),
+        control,
+        #[cfg(feature = "json")]
+        notifications: options.notifications.clone(),
+    };
+    let ends = Ends {
+        shared,
+        control: control_rx,
+        incoming: incoming_tx,
+        outgoing: outgoing_rx,
+    };
+    (transport, handle, ends)
+}
+
+/// Spawns the task opening the WebSocket at `url` and driving it.
+///
+/// The transport is handed out at once, still [`Connecting`](ConnectionState::Connecting).
+/// What is sent before the socket opened waits for it, see [`ConnectOptions::pending_capacity`],
+/// and fails if it can't be opened.
+pub async fn connect<Item, SinkItem>(
+    url: &str,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+{
+    connect_with_codec(url, options.frames, options).await
+}
+
+/// Like [`connect`], with messages serialized by `codec` rather than as `options.frames` says.
+/// The server has to speak the codec's subprotocol.
+pub async fn connect_with_codec<Item, SinkItem, C>(
+    url: &str,
+    codec: C,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem, C>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+    C: Codec + 'static,
+{
+    let heartbeat = encode(&codec, &Envelope::<SinkItem>::Heartbeat, None)?;
+    let (transport, handle, ends) = channels(codec.clone(), &options);
+    ends.shared.enable_replay(&options.replay);
+    let opened = ends.shared.start_opening();
+    let decoder = FrameDecoder::new(options.max_message_size);
+    let driver = Driver {
+        url: url.to_string(),
+        codec,
+        options,
+        shared: ends.shared,
+        control: ends.control,
+        heartbeat,
+        decoder,
+        incoming: ends.incoming,
+        outgoing: ends.outgoing,
+    };
+    spawn_local(driver.run(opened));
+    Ok((transport, handle))
+}
+
+struct Connection {
+    meta: WsMeta,
+    ws: Socket,
+    events: Events<WsEvent>,
+}
+
+/// The socket's frames, with binary messages carried in base64 text frames if the server
+/// agreed to [`ConnectOptions::base64`]. Everything above it only sees binary frames then.
+struct Socket {
+    ws: WsStream,
+    base64: bool,
+}
+
+impl Stream for Socket {
+    type Item = WsMessage;
+
+    /// Text frames that aren't base64 are passed on and fail to decode as messages.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let frame = ready!(this.ws.poll_next_unpin(cx));
+        Poll::Ready(frame.map(|frame| match frame {
+            WsMessage::Text(text) if this.base64 => match rpc::base64::decode(&text) {
+                Ok(bytes) => WsMessage::Binary(bytes),
+                Err(_) => WsMessage::Text(text),
+            },
+            frame => frame,
+        }))
+    }
+}
+
+impl Sink<WsMessage> for Socket {
+    type Error = WsErr;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().ws.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: WsMessage) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let frame = match frame {
+            WsMessage::Binary(bytes) if this.base64 => WsMessage::Text(rpc::base64::encode(&bytes)),
+            frame => frame,
+        };
+        this.ws.start_send_unpin(frame)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().ws.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().ws.poll_close_unpin(cx)
+    }
+}
+
+async fn open(
+    url: &str,
+    codec: &impl Codec,
+    options: &ConnectOptions,
+) -> Result<Connection, TransportError> {
+    info!("Connecting to server: {}", url);
+    let requested = if options.base64 && !codec.text() {
+        rpc::base64::protocol(codec.protocol())
+    } else {
+        codec.protocol().to_string()
+    };
+    let connect_url = match &options.auth {
+        Some(Auth::Query(token)) => with_token(url, token),
+        _ => url.to_string(),
+    };
+    let protocol = requested.clone();
+    // Owns what it connects with, since it may outlive this call, see below.
+    let connecting =
+        Box::pin(async move { WsMeta::connect(connect_url, vec![protocol.as_str()]).await });
+    let connected = match options.connect_timeout {
+        Some(timeout) => {
+            let timer = TimeoutFuture::new(timeout.as_millis() as u32);
+            match future::select(connecting, timer).await {
+                Either::Left((connected, _)) => connected,
+                Either::Right((_, connecting)) => {
+                    info!("Connecting to {} timed out after {:?}", url, timeout);
+                    // The socket only becomes reachable once it opens, so close it then
+                    // instead of leaving it half open.
+                    spawn_local(async move {
+                        if let Ok((meta, _)) = connecting.await {
+                            let _ = meta.close().await;
+                        }
+                    });
+                    return Err(TransportError::Timeout);
+                }
+            }
+        }
+        None => connecting.await,
+    };
+    let (mut meta, ws) = connected.map_err(|e| {
+        info!("Errored on WsMeta connect\n{:?}", e);
+        TransportError::from(e)
+    })?;
+    // Servers that don't know about subprotocols accept the upgrade without picking one.
+    let selected = meta.protocol();
+    if selected != requested {
+        info!("Server selected subprotocol {:?} instead of {}", selected, requested);
+        let _ = meta.close().await;
+        return Err(TransportError::ProtocolMismatch {
+            requested,
+            selected,
+        });
+    }
+    if let Err(binary_type) = socket::expect_array_buffers(meta.wrapped()) {
+        error!("Socket delivers binary frames as {:?} and won't switch", binary_type);
+        let _ = meta.close().await;
+        return Err(TransportError::JsError(format!(
+            "socket delivers binary frames as {:?} instead of ArrayBuffers",
+            binary_type
+        )));
+    }
+    let events = meta
+        .observe(ObserveConfig::default())
+        .await
+        .map_err(|e| TransportError::JsError(e.to_string()))?;
+    let ws = Socket {
+        ws,
+        base64: selected != codec.protocol(),
+    };
+    let mut connection = Connection { meta, ws, events };
+    if let Some(auth) = &options.auth {
+        if let Err(e) = authenticate(&mut connection, auth, codec, options).await {
+            let _ = connection.meta.close().await;
+            return Err(e);
+        }
+    }
+    if options.version_check {
+        if let Err(e) = check_version(&mut connection, codec, options).await {
+            let _ = connection.meta.close().await;
+            return Err(e);
+        }
+    }
+    Ok(connection)
+}
+
+/// Sends the token if it isn't in the URL and waits for the server to accept it, which it
+/// answers with [`Envelope::AuthAck`] before anything else.
+async fn authenticate(
+    connection: &mut Connection,
+    auth: &Auth,
+    codec: &impl Codec,
+    options: &ConnectOptions,
+) -> Result<(), TransportError> {
+    if let Auth::Message(token) = auth {
+        let message = encode(codec, &Envelope::<()>::Auth(token.clone()), None)?;
+        connection.ws.send(message).await?;
+    }
+    let answer = first_answer(next_answer(connection), codec, options, "token").await?;
+    if matches!(answer, Some(Envelope::AuthAck)) {
+        Ok(())
+    } else {
+        Err(TransportError::HandshakeFailed {
+            code: ABNORMAL_CLOSURE,
+            reason: "server didn't acknowledge the token".to_string(),
+        })
+    }
+}
+
+/// Sends our [`Hello`] and waits for the server to welcome it.
+async fn check_version(
+    connection: &mut Connection,
+    codec: &impl Codec,
+    options: &ConnectOptions,
+) -> Result<(), TransportError> {
+    let hello = Envelope::<()>::Hello(Hello::new(codec.protocol()));
+    connection.ws.send(encode(codec, &hello, None)?).await?;
+    let answer = loop {
+        let answer = first_answer(next_answer(connection), codec, options, "version check").await?;
+        if !overtook_welcome(&answer) {
+            break answer;
+        }
+    };
+    welcomed(answer)
+}
+
+/// Whether `answer` is a notification pushed to every peer, e.g. `peer_joined`, that the
+/// server sent before it got to our [`Hello`]. Those are dropped while waiting for the welcome.
+pub(crate) fn overtook_welcome(answer: &Option<Envelope<()>>) -> bool {
+    matches!(answer, Some(Envelope::Notification(_)))
+}
+
+/// What the server's answer to our [`Hello`] means for the connection.
+pub(crate) fn welcomed(answer: Option<Envelope<()>>) -> Result<(), TransportError> {
+    match answer {
+        Some(Envelope::Welcome(welcome)) if welcome.accepted && welcome.serves_schema() => Ok(()),
+        Some(Envelope::Welcome(welcome)) if welcome.accepted => {
+            let missing = schema::missing_from(welcome.methods.as_deref().unwrap_or_default());
+            info!("Server doesn't serve {:?}", missing);
+            Err(TransportError::SchemaMismatch {
+                ours: schema::SCHEMA_HASH,
+                theirs: welcome.schema.unwrap_or_default(),
+                missing,
+            })
+        }
+        Some(Envelope::Welcome(welcome)) => {
+            info!("Server only speaks protocol versions {:?}", welcome.versions);
+            Err(TransportError::ProtocolVersionMismatch {
+                ours: handshake::PROTOCOL_VERSION,
+                theirs: welcome.versions,
+            })
+        }
+        _ => Err(TransportError::HandshakeFailed {
+            code: ABNORMAL_CLOSURE,
+            reason: "server didn't answer the version check".to_string(),
+        }),
+    }
+}
+
+/// What arrived from the server while setting up a connection, see [`first_answer`].
+pub(crate) enum Answer {
+    Text(String),
+    /// A binary frame, length prefix and all.
+    Frame(Vec<u8>),
+    /// A message already taken out of its binary frames and inflated, by a worker.
+    #[cfg(feature = "json")]
+    Message(Vec<u8>),
+    Closed(CloseInfo),
+}
+
+/// The message `answer` brings while setting up the connection, `None` if it can't be
+/// decoded. Fails if the server closed the connection instead, or didn't answer the `what`
+/// within [`ConnectOptions::connect_timeout`].
+pub(crate) async fn first_answer(
+    answer: impl Future<Output = Answer>,
+    codec: &impl Codec,
+    options: &ConnectOptions,
+    what: &str,
+) -> Result<Option<Envelope<()>>, TransportError> {
+    let answer = within(options.connect_timeout, answer)
+        .await
+        .inspect_err(|_| info!("Server didn't answer the {} in time", what))?;
+    let max_len = options.max_message_size;
+    match answer {
+        Answer::Text(text) => Ok(codec.decode_text(&text).ok()),
+        Answer::Frame(bytes) => {
+            let mut decoder = FrameDecoder::new(max_len);
+            decoder.extend(&bytes);
+            match decoder.next_frame() {
+                Ok(Some(message)) => Ok(wire::decode_message(codec, &message, max_len).ok()),
+                _ => Ok(None),
+            }
+        }
+        #[cfg(feature = "json")]
+        Answer::Message(message) => Ok(codec.decode(&message).ok()),
+        Answer::Closed(info) if info.code == UNAUTHORIZED => {
+            Err(TransportError::Unauthorized(info.reason))
+        }
+        Answer::Closed(info) => Err(info.into()),
+    }
+}
+
+/// What the server sends next on `connection`, for [`first_answer`].
+async fn next_answer(connection: &mut Connection) -> Answer {
+    match connection.ws.next().await {
+        Some(WsMessage::Text(text)) => Answer::Text(text),
+        Some(WsMessage::Binary(bytes)) => Answer::Frame(bytes),
+        None => Answer::Closed(wait_for_close(&mut connection.events).await),
+    }
+}
+
+/// Waits up to `timeout` for `future`.
+pub(crate) async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = T>,
+) -> Result<T, TransportError> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(future.await),
+    };
+    #[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+    {
+        tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| TransportError::Timeout)
+    }
+    #[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+    {
+        let timer = TimeoutFuture::new(timeout.as_millis() as u32);
+        match future::select(Box::pin(future), timer).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(TransportError::Timeout),
+        }
+    }
+}
+
+/// Waits for the close handshake `closing` runs, giving the server [`CLOSE_TIMEOUT`] to
+/// answer. `None` if it didn't.
+pub(crate) async fn close_handshake<T>(
+    code: u16,
+    reason: &str,
+    closing: impl Future<Output = T>,
+) -> Option<T> {
+    info!("Closing the connection with code {}: {}", code, reason);
+    let closed = within(Some(CLOSE_TIMEOUT), closing).await.ok();
+    if closed.is_none() {
+        info!("Server didn't complete the close handshake in time");
+    }
+    closed
+}
+
+/// Resolves after `duration`, on the browser's timers or natively on tokio's.
+#[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    TimeoutFuture::new(duration.as_millis() as u32)
+}
+
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    tokio::time::sleep(duration)
+}
+
+/// Milliseconds since the epoch, as `Date.now()` counts them.
+#[cfg(not(all(feature = "native", not(target_arch = "wasm32"))))]
+pub(crate) fn now() -> f64 {
+    js_sys::Date::now()
+}
+
+#[cfg(all(feature = "native", not(target_arch = "wasm32")))]
+pub(crate) fn now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64() * 1000.0)
+}
+
+/// Counts unanswered heartbeats for [`ConnectOptions::heartbeat_timeout`].
+pub(crate) struct Liveness {
+    config: Option<HeartbeatTimeout>,
+    missed: u32,
+    deadline: Option<LocalBoxFuture<'static, ()>>,
+}
+
+impl Liveness {
+    pub(crate) fn new(config: Option<HeartbeatTimeout>) -> Self {
+        Self {
+            config,
+            missed: 0,
+            deadline: None,
+        }
+    }
+
+    /// A heartbeat went out, the server has until the timeout to show it's still there.
+    pub(crate) fn sent(&mut self) {
+        if let (Some(config), None) = (&self.config, &self.deadline) {
+            self.deadline = Some(sleep(config.timeout).boxed_local());
+        }
+    }
+
+    pub(crate) fn received(&mut self) {
+        self.missed = 0;
+        self.deadline = None;
+    }
+
+    /// Resolves when the server failed to answer, with how the connection ended once it
+    /// missed too many heartbeats.
+    pub(crate) async fn missed(&mut self) -> Option<CloseInfo> {
+        match self.deadline.as_mut() {
+            Some(deadline) => deadline.await,
+            None => return future::pending().await,
+        }
+        self.deadline = None;
+        self.missed += 1;
+        let max_missed = self.config.map_or(1, |config| config.max_missed);
+        info!("Heartbeat {} of {} went unanswered", self.missed, max_missed);
+        (self.missed >= max_missed).then(|| CloseInfo {
+            code: HEARTBEAT_TIMEOUT,
+            reason: format!("{} heartbeats went unanswered", self.missed),
+            was_clean: false,
+        })
+    }
+}
+
+pub(crate) enum Ended {
+    /// The application dropped the transport.
+    Local,
+    /// The application closed the connection through a [`TransportHandle`].
+    Closed(CloseInfo),
+    /// The socket failed or the server went away.
+    Remote(CloseInfo),
+}
+
+/// Owns the socket on behalf of a [`WsTransport`].
+struct Driver<Item, C> {
+    url: String,
+    codec: C,
+    options: ConnectOptions,
+    shared: Rc<Shared>,
+    control: mpsc::UnboundedReceiver<Control>,
+    /// Pre-encoded `Envelope::Heartbeat`.
+    heartbeat: WsMessage,
+    /// Reassembles binary frames.
+    decoder: FrameDecoder,
+    incoming: mpsc::Sender<Result<Item, TransportError>>,
+    outgoing: mpsc::UnboundedReceiver<WsMessage>,
+}
+
+impl<Item, C> Driver<Item, C>
+where
+    Item: DeserializeOwned,
+    C: Codec,
+{
+    async fn run(mut self, opened: oneshot::Sender<Result<(), TransportError>>) {
+        let opening = open(&self.url, &self.codec, &self.options);
+        let connection = open_first(&self.shared, &mut self.incoming, opened, opening).await;
+        if let Some(connection) = connection {
+            self.serve(connection).await;
+        }
+        self.shared.finish();
+    }
+
+    async fn serve(&mut self, mut connection: Connection) {
+        loop {
+            let Connection {
+                meta,
+                ws,
+                mut events,
+            } = connection;
+            let ended = self.pump(&meta, ws, &mut events).await;
+            let info = match ended {
+                Ended::Local => {
+                    let _ = meta.close().await;
+                    return;
+                }
+                Ended::Closed(info) => {
+                    self.shared.closed(&info);
+                    return;
+                }
+                Ended::Remote(info) => {
+                    let _ = meta.close().await;
+                    info
+                }
+            };
+            info!("Connection to {} lost: {}", self.url, info);
+            let reconnected = match &self.options.reconnect {
+                Some(policy) => {
+                    reconnect(&self.url, policy, &self.shared, &self.incoming, || {
+                        open(&self.url, &self.codec, &self.options)
+                    })
+                    .await
+                }
+                None => None,
+            };
+            match reconnected {
+                Some(new_connection) => connection = new_connection,
+                None => {
+                    self.shared.closed(&info);
+                    // A full queue is fine to miss this on, tarpc sees the stream end either way.
+                    let _ = self.incoming.try_send(Err(info.into()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves frames between the socket and the transport until either side goes away.
+    async fn pump(
+        &mut self,
+        meta: &WsMeta,
+        ws: Socket,
+        events: &mut Events<WsEvent>,
+    ) -> Ended {
+        let (mut sink, mut stream) = ws.split();
+        let shared = self.shared.clone();
+        let threshold = self.options.flush_threshold;
+        let batching = self.options.batch.filter(|_| !self.codec.text());
+        self.decoder.clear();
+        let mut keepalive: Pin<Box<dyn Stream<Item = ()>>> = match self.options.keepalive {
+            Some(interval) => Box::pin(IntervalStream::new(interval.as_millis() as u32)),
+            None => Box::pin(futures::stream::pending()),
+        };
+        let mut liveness = Liveness::new(self.options.heartbeat_timeout);
+        let mut errored = false;
+        for frame in shared.replayed() {
+            let size = wire_size(&frame);
+            if let Err(e) = sink.send(frame).await {
+                return Ended::Remote(CloseInfo::abnormal(e));
+            }
+            shared.record_sent(size);
+        }
+        loop {
+            select! {
+                frame = stream.next().fuse() => match frame {
+                    Some(frame) => {
+                        shared.record_received((&frame).into());
+                        liveness.received();
+                        if let Err(ended) = self.deliver(frame).await {
+                            return ended;
+                        }
+                    }
+                    None => return Ended::Remote(wait_for_close(events).await),
+                },
+                event = events.next().fuse() => match event {
+                    Some(WsEvent::Closed(event)) => {
+                        let mut info = CloseInfo::from(event);
+                        // The error event carries no details, the close event after it at
+                        // least tells what the browser saw.
+                        if errored && info.reason.is_empty() {
+                            info.reason = "the browser reported a socket error".to_string();
+                        }
+                        return Ended::Remote(info);
+                    }
+                    Some(WsEvent::Error) => {
+                        info!("Socket error, waiting for the close event");
+                        errored = true;
+                    }
+                    Some(event) if socket::lost_frame(&event) => {
+                        error!("Socket dropped a binary frame delivered as a Blob");
+                        let reason = "binary frame arrived as a Blob";
+                        let _ = meta.close_reason(socket::UNSUPPORTED_DATA, reason).await;
+                        let info = CloseInfo::new(socket::UNSUPPORTED_DATA, reason, false);
+                        return Ended::Remote(info);
+                    }
+                    Some(_) => {}
+                    None => return Ended::Remote(CloseInfo::abnormal("socket went away")),
+                },
+                frame = self.outgoing.next() => match frame {
+                    Some(frame) => {
+                        let (frame, messages) = batch(frame, &mut self.outgoing, batching.as_ref());
+                        let size = wire_size(&frame);
+                        let sent = sink.send(frame).await;
+                        shared.queued.set(shared.queued.get().saturating_sub(messages));
+                        shared.written(messages);
+                        if let Err(e) = sent {
+                            return Ended::Remote(CloseInfo::abnormal(e));
+                        }
+                        shared.record_sent(size);
+                        shared.set_buffered(meta.buffered_amount(), threshold);
+                    }
+                    None => {
+                        let _ = sink.close().await;
+                        return Ended::Local;
+                    }
+                },
+                control = self.control.next() => match control {
+                    Some(Control::Close { code, reason, done }) => {
+                        match close(meta, code, &reason).await {
+                            Ok(info) => {
+                                let _ = done.send(Ok(()));
+                                return Ended::Closed(info);
+                            }
+                            // Rejected code or reason, the socket is untouched.
+                            Err(e) => {
+                                let _ = done.send(Err(e));
+                            }
+                        }
+                    }
+                    None => {}
+                },
+                _ = Box::pin(drain_tick(&shared, threshold)).fuse() => {
+                    shared.set_buffered(meta.buffered_amount(), threshold);
+                },
+                _ = keepalive.next().fuse() => {
+                    if let Err(e) = sink.send(self.heartbeat.clone()).await {
+                        info!("Failed to send heartbeat: {}", e);
+                        return Ended::Remote(CloseInfo::abnormal(e));
+                    }
+                    shared.record_sent(wire_size(&self.heartbeat));
+                    liveness.sent();
+                },
+                info = liveness.missed().fuse() => {
+                    if let Some(info) = info {
+                        return Ended::Remote(info);
+                    }
+                },
+            }
+        }
+    }
+
+    async fn deliver(&mut self, frame: WsMessage) -> Result<(), Ended> {
+        deliver(
+            &self.codec,
+            &mut self.decoder,
+            &mut self.incoming,
+            &self.options,
+            frame,
+        )
+        .await
+    }
+}
+
+/// Resolves after [`DRAIN_POLL_INTERVAL`] while the socket holds more than `threshold` bytes,
+/// never otherwise. The browser has no event for its send buffer draining, so the driver
+/// checks `bufferedAmount` this often while a flush may be waiting for it.
+pub(crate) async fn drain_tick(shared: &Shared, threshold: u32) {
+    if shared.buffered.get() > threshold {
+        sleep(DRAIN_POLL_INTERVAL).await
+    } else {
+        future::pending().await
+    }
+}
+
+/// Decodes a frame and hands the tarpc messages in it to the transport.
+pub(crate) async fn deliver<Item: DeserializeOwned, C: Codec>(
+    codec: &C,
+    decoder: &mut FrameDecoder,
+    incoming: &mut mpsc::Sender<Result<Item, TransportError>>,
+    options: &ConnectOptions,
+    frame: WsMessage,
+) -> Result<(), Ended> {
+    match frame {
+        WsMessage::Text(text) if text.len() > options.max_message_size => {
+            oversized(incoming, options, text.len()).await
+        }
+        WsMessage::Text(text) => {
+            let envelope = codec.decode_text(&text).map_err(TransportError::serialization);
+            forward(incoming, options, envelope).await
+        }
+        WsMessage::Binary(bytes) => {
+            decoder.extend(&bytes);
+            loop {
+                match decoder.next_frame_ref() {
+                    Ok(Some(message)) => {
+                        let max_len = options.max_message_size;
+                        let envelope = wire::decode_message(codec, message, max_len)
+                            .map_err(TransportError::serialization);
+                        forward(incoming, options, envelope).await?
+                    }
+                    Ok(None) => return Ok(()),
+                    Err(FrameError::TooLarge { len, .. }) => {
+                        decoder.skip_frame();
+                        oversized(incoming, options, len).await?
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Deals with a received message of `size` bytes according to [`ConnectOptions::oversize`].
+pub(crate) async fn oversized<Item>(
+    incoming: &mut mpsc::Sender<Result<Item, TransportError>>,
+    options: &ConnectOptions,
+    size: usize,
+) -> Result<(), Ended> {
+    let limit = options.max_message_size;
+    info!("Received a message of {} bytes, the limit is {}", size, limit);
+    match options.oversize {
+        OversizePolicy::Error => {
+            let error = TransportError::MessageTooLarge { size, limit };
+            forward(incoming, options, Err(error)).await
+        }
+        OversizePolicy::Close => Err(Ended::Remote(CloseInfo {
+            code: MESSAGE_TOO_BIG,
+            reason: format!("received a message of {} bytes", size),
+            was_clean: false,
+        })),
+    }
+}
+
+/// Hands a received message to the transport, a stream frame to the streams of
+/// [`ConnectOptions::streams`] and a notification to its subscribers, skipping heartbeats.
+pub(crate) async fn forward<Item>(
+    incoming: &mut mpsc::Sender<Result<Item, TransportError>>,
+    options: &ConnectOptions,
+    envelope: Result<Envelope<Item>, TransportError>,
+) -> Result<(), Ended> {
+    let message = match envelope {
+        // Only requests are tagged, but the message is what counts.
+        Ok(Envelope::Message(message))
+        | Ok(Envelope::Tagged(message, _))
+        | Ok(Envelope::OneWay(message)) => Ok(message),
+        #[cfg(feature = "json")]
+        Ok(Envelope::Stream(frame)) => {
+            if let Some(streams) = &options.streams {
+                streams.route(frame);
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "json")]
+        Ok(Envelope::Notification(notification)) => {
+            options.notifications.deliver(notification);
+            return Ok(());
+        }
+        // Streams and notifications carry JSON, builds without it have nobody to hand them to.
+        #[cfg(not(feature = "json"))]
+        Ok(Envelope::Stream(_)) | Ok(Envelope::Notification(_)) => return Ok(()),
+        Ok(Envelope::Heartbeat)
+        | Ok(Envelope::HeartbeatAck)
+        | Ok(Envelope::Auth(_))
+        | Ok(Envelope::AuthAck)
+        | Ok(Envelope::Hello(_))
+        | Ok(Envelope::Welcome(_)) => return Ok(()),
+        Err(e) => Err(e),
+    };
+    match options.overflow {
+        // Not polling the socket while we wait lets the browser buffer instead of us.
+        OverflowPolicy::Pause => incoming.send(message).await.map_err(|_| Ended::Local),
+        OverflowPolicy::Close => match incoming.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_full() => {
+                info!("Incoming queue is full, dropping the connection");
+                Err(Ended::Remote(CloseInfo::abnormal("incoming queue is full")))
+            }
+            Err(_) => Err(Ended::Local),
+        },
+    }
+}
+
+/// Runs the close handshake, see [`close_handshake`].
+async fn close(meta: &WsMeta, code: u16, reason: &str) -> Result<CloseInfo, TransportError> {
+    match close_handshake(code, reason, meta.close_reason(code, reason)).await {
+        Some(Ok(event)) => Ok(event.into()),
+        Some(Err(WsErr::ConnectionNotOpen)) | None => Ok(CloseInfo::new(code, reason, false)),
+        Some(Err(e)) => Err(e.into()),
+    }
+}
+
+/// See [`socket::wait_for_close`].
+async fn wait_for_close(events: &mut Events<WsEvent>) -> CloseInfo {
+    let (code, reason, was_clean) = socket::wait_for_close(events).await;
+    CloseInfo::new(code, &reason, was_clean)
+}
+
+/// Waits for `opening`, the first connection of a transport marked by
+/// [`Shared::start_opening`]. Messages sent meanwhile are queued and go out once it is open. If
+/// it fails the transport ends with its error, failing them, and
+/// [`TransportHandle::opened`] gets the error.
+pub(crate) async fn open_first<Item, C>(
+    shared: &Shared,
+    incoming: &mut mpsc::Sender<Result<Item, TransportError>>,
+    opened: oneshot::Sender<Result<(), TransportError>>,
+    opening: impl Future<Output = Result<C, TransportError>>,
+) -> Option<C> {
+    match opening.await {
+        Ok(connection) => {
+            shared.set_connecting(false);
+            shared.set_state(ConnectionState::Connected);
+            let _ = opened.send(Ok(()));
+            Some(connection)
+        }
+        Err(e) => {
+            info!("Failed to open the connection: {}", e);
+            let info = CloseInfo::abnormal(&e);
+            shared.closed(&info);
+            // A full queue is fine to miss this on, tarpc sees the stream end either way.
+            let _ = incoming.try_send(Err(info.into()));
+            let _ = opened.send(Err(e));
+            None
+        }
+    }
+}
+
+/// Calls `open` following `policy` until it succeeds, gives up, or the transport is dropped.
+/// Meanwhile the sink queues up to [`ConnectOptions::pending_capacity`] messages. The topics
+/// subscribed on the lost connection end, see [`crate::push`].
+pub(crate) async fn reconnect<Item, C, F, Fut>(
+    url: &str,
+    policy: &ReconnectPolicy,
+    shared: &Shared,
+    incoming: &mpsc::Sender<Result<Item, TransportError>>,
+    mut open: F,
+) -> Option<C>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<C, TransportError>>,
+{
+    #[cfg(feature = "json")]
+    shared.notifications.connection_lost();
+    shared.set_connecting(true);
+    let reconnected = retry(url, policy, shared, incoming, &mut open).await;
+    shared.set_connecting(false);
+    if reconnected.is_some() {
+        shared.set_state(ConnectionState::Connected);
+    }
+    reconnected
+}
+
+async fn retry<Item, C, F, Fut>(
+    url: &str,
+    policy: &ReconnectPolicy,
+    shared: &Shared,
+    incoming: &mpsc::Sender<Result<Item, TransportError>>,
+    open: &mut F,
+) -> Option<C>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<C, TransportError>>,
+{
+    let mut attempt = 0;
+    while policy.max_attempts.is_none_or(|max| attempt < max) {
+        let delay = policy.delay(attempt);
+        info!("Reconnecting to {} in {:?}", url, delay);
+        shared.set_state(ConnectionState::Reconnecting {
+            attempt: attempt + 1,
+        });
+        sleep(delay).await;
+        if incoming.is_closed() {
+            return None;
+        }
+        shared.set_state(ConnectionState::Connecting);
+        match open().await {
+            Ok(connection) => {
+                info!("Reconnected to {}", url);
+                return Some(connection);
+            }
+            // The server was replaced by one this build can't talk to, retrying won't help.
+            Err(
+                e @ (TransportError::ProtocolVersionMismatch { .. }
+                | TransportError::SchemaMismatch { .. }),
+            ) => {
+                info!("Giving up reconnecting: {}", e);
+                return None;
+            }
+            Err(e) => info!("Reconnect attempt {} failed: {}", attempt + 1, e),
+        }
+        attempt += 1;
+    }
+    None
+}
+
+#[cfg(all(test, feature = "native", not(target_arch = "wasm32")))]
+mod tests {
+    use rpc::codec::Json;
+    use rpc::{WorldRequest, WorldResponse};
+    use tarpc::{context, ClientMessage, Response};
+
+    use super::*;
+    use crate::testing::{self, connect, run, until, Restartable};
+
+    /// Text frames of the messages `0`, `1` and so on, as servers send them.
+    fn frames(messages: usize) -> Vec<WsMessage> {
+        (0..messages)
+            .map(|message| encode(&Json, &Envelope::Message(message.to_string()), None).unwrap())
+            .collect()
+    }
+
+    /// A message the sink takes without a tarpc client in front of it. Nothing answers it.
+    fn cancel(request_id: u64) -> ClientMessage<WorldRequest> {
+        ClientMessage::Cancel {
+            trace_context: Default::default(),
+            request_id,
+        }
+    }
+
+    /// Delivers `frames` to a queue of `options.incoming_capacity` that nobody reads, answering
+    /// how the last one went and the reading end.
+    async fn flood(
+        options: &ConnectOptions,
+        frames: Vec<WsMessage>,
+    ) -> (Poll<Result<(), Ended>>, mpsc::Receiver<Result<String, TransportError>>) {
+        let (mut incoming, received) = mpsc::channel(options.incoming_capacity);
+        let mut decoder = FrameDecoder::default();
+        let mut last = Poll::Ready(Ok(()));
+        for frame in frames {
+            let delivered = deliver(&Json, &mut decoder, &mut incoming, options, frame);
+            last = futures::poll!(Box::pin(delivered));
+        }
+        (last, received)
+    }
+
+    #[test]
+    fn an_echo_after_the_server_came_back_is_answered() {
+        run(async {
+            let server = Restartable::up();
+            let (transport, handle) = testing::transport(&server, ConnectOptions::default());
+            let client = connect(transport);
+            let before = client.echo(context::current(), "before".into()).await;
+            assert_eq!(before.unwrap(), Ok("before".into()));
+
+            server.stop();
+            until(&handle, |state| matches!(state, ConnectionState::Reconnecting { .. })).await;
+            server.start();
+            until(&handle, |state| *state == ConnectionState::Connected).await;
+            let after = client.echo(context::current(), "after".into()).await;
+            assert_eq!(after.unwrap(), Ok("after".into()));
+        });
+    }
+
+    #[test]
+    fn a_ping_sent_before_the_connection_opened_is_answered_once_it_did() {
+        run(async {
+            let server = Restartable::default();
+            let (transport, handle) = testing::transport(&server, ConnectOptions::default());
+            let client = connect(transport);
+            let pinging = client.clone();
+            let ping = tokio::task::spawn_local(async move {
+                pinging.ping(context::current()).await
+            });
+            sleep(Duration::from_millis(50)).await;
+            assert!(!ping.is_finished(), "answered before the server was up");
+            assert_eq!(handle.stats().messages_sent, 0);
+
+            server.start();
+            assert_eq!(ping.await.unwrap().unwrap(), Ok("pong".into()));
+            assert_eq!(handle.state(), ConnectionState::Connected);
+        });
+    }
+
+    #[test]
+    fn a_flush_waits_until_the_queue_was_written() {
+        run(async {
+            let server = Restartable::default();
+            let (mut transport, handle) = testing::transport(&server, ConnectOptions::default());
+            let mut sent = transport.send(cancel(1));
+            let waited = tokio::time::timeout(Duration::from_millis(50), &mut sent).await;
+            assert!(waited.is_err(), "flushed before the server was up");
+            assert_eq!(handle.stats().messages_sent, 0);
+
+            server.start();
+            let sent = tokio::time::timeout(Duration::from_secs(5), sent).await;
+            assert!(matches!(sent, Ok(Ok(()))));
+            assert_eq!(handle.stats().messages_sent, 1);
+        });
+    }
+
+    #[test]
+    fn a_flush_waits_until_a_slow_reader_took_what_the_socket_buffered() {
+        run(async {
+            let options = ConnectOptions {
+                flush_threshold: 100,
+                ..ConnectOptions::default()
+            };
+            let (mut transport, handle, buffered) =
+                testing::slow_reader(options, 50, Duration::from_millis(20));
+            for request_id in 0..10 {
+                transport.feed(cancel(request_id)).await.unwrap();
+            }
+            // The driver wrote them all, the reader hasn't come around yet.
+            sleep(Duration::from_millis(1)).await;
+            assert_eq!(handle.stats().messages_sent, 10);
+            assert!(buffered.get() > 100, "only {} bytes buffered", buffered.get());
+
+            let mut flush = transport.flush();
+            assert!(futures::poll!(&mut flush).is_pending());
+            let flushed = tokio::time::timeout(Duration::from_secs(5), flush).await;
+            assert!(matches!(flushed, Ok(Ok(()))));
+            assert!(buffered.get() <= 100, "flushed at {} bytes", buffered.get());
+        });
+    }
+
+    #[test]
+    fn a_full_queue_pauses_reading() {
+        run(async {
+            let options = ConnectOptions {
+                incoming_capacity: 2,
+                ..ConnectOptions::default()
+            };
+            let (fits, _) = flood(&options, frames(2)).await;
+            assert!(matches!(fits, Poll::Ready(Ok(()))));
+            let (waits, mut received) = flood(&options, frames(3)).await;
+            assert!(waits.is_pending());
+            assert_eq!(received.next().await.unwrap().unwrap(), "0");
+        });
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_connection_when_asked_to() {
+        run(async {
+            let options = ConnectOptions {
+                incoming_capacity: 2,
+                overflow: OverflowPolicy::Close,
+                ..ConnectOptions::default()
+            };
+            let (fits, _) = flood(&options, frames(2)).await;
+            assert!(matches!(fits, Poll::Ready(Ok(()))));
+            let (dropped, _) = flood(&options, frames(4)).await;
+            match dropped {
+                Poll::Ready(Err(Ended::Remote(info))) => {
+                    assert_eq!(info.code, ABNORMAL_CLOSURE);
+                    assert_eq!(info.reason, "incoming queue is full");
+                }
+                _ => panic!("the connection wasn't dropped"),
+            }
+        });
+    }
+
+    /// A message of `size` bytes, as a server could send it.
+    fn oversized(size: usize) -> WsMessage {
+        WsMessage::Text(format!("{{\"Message\":\"{}\"}}", "x".repeat(size - 14)))
+    }
+
+    #[test]
+    fn an_oversized_frame_is_dropped_for_an_error() {
+        run(async {
+            let options = ConnectOptions {
+                max_message_size: 64,
+                ..ConnectOptions::default()
+            };
+            let mut frames = frames(2);
+            frames.insert(1, oversized(65));
+            let (delivered, mut received) = flood(&options, frames).await;
+            assert!(matches!(delivered, Poll::Ready(Ok(()))));
+            assert_eq!(received.next().await.unwrap().unwrap(), "0");
+            match received.next().await {
+                Some(Err(TransportError::MessageTooLarge { size, limit })) => {
+                    assert_eq!((size, limit), (65, 64));
+                }
+                other => panic!("expected the size error, got {:?}", other),
+            }
+            assert_eq!(received.next().await.unwrap().unwrap(), "1");
+        });
+    }
+
+    #[test]
+    fn an_oversized_frame_drops_the_connection_when_asked_to() {
+        run(async {
+            let options = ConnectOptions {
+                max_message_size: 64,
+                oversize: OversizePolicy::Close,
+                ..ConnectOptions::default()
+            };
+            let (fits, _) = flood(&options, vec![oversized(64)]).await;
+            assert!(matches!(fits, Poll::Ready(Ok(()))));
+            let (dropped, _) = flood(&options, vec![oversized(65)]).await;
+            match dropped {
+                Poll::Ready(Err(Ended::Remote(info))) => assert_eq!(info.code, MESSAGE_TOO_BIG),
+                _ => panic!("the connection wasn't dropped"),
+            }
+        });
+    }
+
+    #[test]
+    fn an_oversized_message_is_rejected_before_it_is_queued() {
+        run(async {
+            let options = ConnectOptions {
+                max_message_size: 16,
+                ..ConnectOptions::default()
+            };
+            let (mut transport, _, mut ends) =
+                channels::<Response<WorldResponse>, _, _>(Json, &options);
+            match transport.send(cancel(1)).await {
+                Err(TransportError::MessageTooLarge { limit, .. }) => assert_eq!(limit, 16),
+                other => panic!("expected the size error, got {:?}", other),
+            }
+            assert_eq!(ends.shared.queued.get(), 0);
+            assert!(ends.outgoing.try_recv().is_err(), "the message was queued");
+        });
+    }
+
+    /// Heartbeats timing out after 10ms, `max_missed` of them in a row.
+    fn liveness(max_missed: u32) -> Liveness {
+        Liveness::new(Some(HeartbeatTimeout {
+            timeout: Duration::from_millis(10),
+            max_missed,
+        }))
+    }
+
+    #[test]
+    fn heartbeats_nobody_answers_time_the_connection_out() {
+        run(async {
+            let mut heartbeats = liveness(3);
+            for _ in 0..2 {
+                heartbeats.sent();
+                assert!(heartbeats.missed().await.is_none());
+            }
+            heartbeats.sent();
+            let info = heartbeats.missed().await.expect("3 heartbeats went unanswered");
+            match TransportError::from(info) {
+                TransportError::HeartbeatTimeout(reason) => {
+                    assert_eq!(reason, "3 heartbeats went unanswered")
+                }
+                other => panic!("expected a heartbeat timeout, got {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn an_answer_starts_the_count_of_missed_heartbeats_over() {
+        run(async {
+            let mut heartbeats = liveness(2);
+            heartbeats.sent();
+            assert!(heartbeats.missed().await.is_none());
+            heartbeats.received();
+            heartbeats.sent();
+            assert!(heartbeats.missed().await.is_none());
+            heartbeats.sent();
+            assert!(heartbeats.missed().await.is_some());
+
+            // An answer in time leaves nothing to miss.
+            let mut heartbeats = liveness(1);
+            heartbeats.sent();
+            heartbeats.received();
+            let missed = tokio::time::timeout(Duration::from_millis(50), heartbeats.missed()).await;
+            assert!(missed.is_err(), "missed an answered heartbeat");
+        });
+    }
+}
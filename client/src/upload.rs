@@ -0,0 +1,113 @@
+//! Sending files to the server in chunks, see [`upload`] and [`rpc::upload`].
+//!
+//! The bytes are cut into chunks of [`UploadOptions::chunk_len`] and sent with up to
+//! [`UploadOptions::concurrency`] `upload_chunk` calls running at once, so a slow round trip
+//! doesn't stall the whole upload. The server takes the chunks in any order; the first one it
+//! rejects ends the upload, and the chunks not yet sent are never sent.
+
+use std::fmt;
+
+use futures::{stream, StreamExt};
+use js_sys::Uint8Array;
+use log::info;
+use rpc::upload::{checksum, MAX_CHUNK_LEN};
+use rpc::{UploadResult, WorldError};
+use tarpc::client::RpcError;
+use tarpc::context::Context;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::api::WorldApi;
+use crate::error::describe;
+
+/// How [`upload`] cuts and sends the bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UploadOptions {
+    /// Bytes per chunk, at most [`MAX_CHUNK_LEN`].
+    pub chunk_len: usize,
+    /// Chunks sent at once.
+    pub concurrency: usize,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            chunk_len: 256 * 1024,
+            concurrency: 4,
+        }
+    }
+}
+
+/// Why an upload didn't finish.
+#[derive(Debug)]
+pub enum UploadFailed {
+    /// The file couldn't be read.
+    Read(String),
+    /// A call failed, e.g. the connection closed.
+    Call(RpcError),
+    /// The server rejected a call, e.g. with a [`WorldError::Upload`].
+    World(WorldError),
+}
+
+impl fmt::Display for UploadFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadFailed::Read(e) => write!(f, "the file couldn't be read: {}", e),
+            UploadFailed::Call(e) => write!(f, "the call failed: {}", e),
+            UploadFailed::World(e) => write!(f, "the server refused it: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UploadFailed {}
+
+impl From<RpcError> for UploadFailed {
+    fn from(e: RpcError) -> Self {
+        UploadFailed::Call(e)
+    }
+}
+
+impl From<WorldError> for UploadFailed {
+    fn from(e: WorldError) -> Self {
+        UploadFailed::World(e)
+    }
+}
+
+/// Uploads `bytes` as `name` through `client`. Every call is made with `ctx`, so its deadline
+/// is the whole upload's.
+pub async fn upload(
+    client: &dyn WorldApi,
+    ctx: Context,
+    name: &str,
+    bytes: &[u8],
+    options: UploadOptions,
+) -> Result<UploadResult, UploadFailed> {
+    let chunk_len = options.chunk_len.clamp(1, MAX_CHUNK_LEN);
+    let id = client
+        .upload_begin(ctx, name.into(), bytes.len() as u64)
+        .await??;
+    info!("Uploading {} bytes of {:?} as {}", bytes.len(), name, id);
+    let chunks = bytes.chunks(chunk_len).enumerate().map(|(i, chunk)| {
+        let offset = (i * chunk_len) as u64;
+        client.upload_chunk(ctx, id, offset, chunk.into())
+    });
+    let mut sent = stream::iter(chunks).buffer_unordered(options.concurrency.max(1));
+    while let Some(answer) = sent.next().await {
+        answer??;
+    }
+    let uploaded = client.upload_finish(ctx, id, checksum(bytes)).await??;
+    Ok(uploaded)
+}
+
+/// [`upload`]s a file the page was given, e.g. by an `<input type="file">`, under its name.
+pub async fn upload_file(
+    client: &dyn WorldApi,
+    ctx: Context,
+    file: &web_sys::File,
+    options: UploadOptions,
+) -> Result<UploadResult, UploadFailed> {
+    let buffer = JsFuture::from(file.array_buffer())
+        .await
+        .map_err(|e| UploadFailed::Read(describe(&e)))?;
+    let bytes = Uint8Array::new(&buffer).to_vec();
+    upload(client, ctx, &file.name(), &bytes, options).await
+}
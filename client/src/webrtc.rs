@@ -0,0 +1,648 @@
+//! tarpc over a WebRTC data channel between two browsers.
+//!
+//! The offer, answer and ICE candidates travel through the server with the `signal` and
+//! `signals` RPCs; once the channel is open messages go straight from one peer to the other.
+//! [`connect_peer`] calls another client, [`accept_peer`] waits to be called, and
+//! [`serve_peer`] answers `World` requests on an accepted channel.
+
+use std::fmt;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::{select, FutureExt, StreamExt};
+use gloo_timers::future::TimeoutFuture;
+use js_sys::{Array, Function, Reflect, Uint8Array};
+use log::info;
+use rpc::framing::FrameDecoder;
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, UploadResult, VersionInfo,
+    World, WorldClient, WorldError, WorldRequest, WorldResponse,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tarpc::server::{BaseChannel, Channel};
+use tarpc::{context, ClientMessage, Response};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{
+    MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelEvent, RtcDataChannelInit,
+    RtcDataChannelType, RtcIceCandidateInit, RtcIceServer, RtcPeerConnection,
+    RtcPeerConnectionIceEvent, RtcSdpType, RtcSessionDescriptionInit,
+};
+use ws_stream_wasm::WsMessage;
+
+use crate::error::{describe, TransportError};
+use crate::transport::{
+    self, deliver, drain_tick, wire_size, CloseInfo, ConnectOptions, Control, Ended, Shared,
+    TransportHandle, WsTransport, ABNORMAL_CLOSURE,
+};
+
+/// Label of the data channel carrying the tarpc messages.
+const CHANNEL_LABEL: &str = "tarpc";
+
+/// How often relayed signaling messages are fetched from the server while negotiating.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How the data channel delivers messages.
+#[derive(Clone, Debug)]
+pub struct PeerOptions {
+    /// Deliver messages in the order they were sent.
+    pub ordered: bool,
+    /// Give up on a message after this many retransmissions. `None` keeps the channel
+    /// reliable, which tarpc needs unless losing a request or response is acceptable.
+    pub max_retransmits: Option<u16>,
+    /// STUN or TURN server URLs. Peers on the same network don't need any.
+    pub ice_servers: Vec<String>,
+}
+
+impl Default for PeerOptions {
+    fn default() -> Self {
+        Self {
+            ordered: true,
+            max_retransmits: None,
+            ice_servers: Vec::new(),
+        }
+    }
+}
+
+/// What the peers relay to each other through the server.
+#[derive(Serialize, Deserialize)]
+enum Signal {
+    Offer(String),
+    Answer(String),
+    Candidate {
+        candidate: String,
+        sdp_mid: Option<String>,
+        sdp_m_line_index: Option<u16>,
+    },
+}
+
+enum PeerEvent {
+    Candidate(Signal),
+    Channel(RtcDataChannel),
+    Open,
+    Message(WsMessage),
+    Closed,
+}
+
+fn js_error(e: JsValue) -> TransportError {
+    TransportError::JsError(describe(&e))
+}
+
+fn signaling_failed(e: impl fmt::Display) -> TransportError {
+    TransportError::HandshakeFailed {
+        code: ABNORMAL_CLOSURE,
+        reason: format!("signaling failed: {}", e),
+    }
+}
+
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, TransportError>>,
+) -> Result<T, TransportError> {
+    match timeout {
+        Some(timeout) => {
+            let timer = TimeoutFuture::new(timeout.as_millis() as u32);
+            match future::select(Box::pin(future), timer).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(TransportError::Timeout),
+            }
+        }
+        None => future.await,
+    }
+}
+
+async fn send_signal(
+    signaling: &WorldClient,
+    to: u64,
+    signal: &Signal,
+) -> Result<(), TransportError> {
+    let payload = serde_json::to_string(signal).map_err(TransportError::serialization)?;
+    signaling
+        .signal(context::current(), to, payload)
+        .await
+        .map_err(signaling_failed)?
+        .map_err(signaling_failed)
+}
+
+async fn fetch_signals(signaling: &WorldClient) -> Result<Vec<(u64, Signal)>, TransportError> {
+    let signals = signaling
+        .signals(context::current())
+        .await
+        .map_err(signaling_failed)?
+        .map_err(signaling_failed)?;
+    Ok(signals
+        .into_iter()
+        .filter_map(|(from, payload)| match serde_json::from_str(&payload) {
+            Ok(signal) => Some((from, signal)),
+            Err(e) => {
+                info!("Dropping an unreadable signal from peer {}: {}", from, e);
+                None
+            }
+        })
+        .collect())
+}
+
+fn description(kind: RtcSdpType, sdp: &str) -> RtcSessionDescriptionInit {
+    let description = RtcSessionDescriptionInit::new(kind);
+    description.set_sdp(sdp);
+    description
+}
+
+/// A peer connection and the callbacks that turn its events into [`PeerEvent`]s.
+struct Peer {
+    connection: RtcPeerConnection,
+    channel: Option<RtcDataChannel>,
+    events: mpsc::UnboundedReceiver<PeerEvent>,
+    sender: mpsc::UnboundedSender<PeerEvent>,
+    callbacks: Vec<Closure<dyn FnMut(JsValue)>>,
+}
+
+impl Peer {
+    fn new(options: &PeerOptions) -> Result<Self, TransportError> {
+        let servers = Array::new();
+        for url in &options.ice_servers {
+            let server = RtcIceServer::new();
+            server.set_urls(&JsValue::from_str(url));
+            servers.push(&server);
+        }
+        let config = RtcConfiguration::new();
+        config.set_ice_servers(&servers);
+        let connection = RtcPeerConnection::new_with_configuration(&config).map_err(js_error)?;
+        let (sender, events) = mpsc::unbounded();
+        let mut peer = Peer {
+            connection,
+            channel: None,
+            events,
+            sender,
+            callbacks: Vec::new(),
+        };
+        let on_candidate = peer.callback(|event| {
+            // No candidate marks the end of gathering.
+            let candidate = event.unchecked_into::<RtcPeerConnectionIceEvent>().candidate()?;
+            Some(PeerEvent::Candidate(Signal::Candidate {
+                candidate: candidate.candidate(),
+                sdp_mid: candidate.sdp_mid(),
+                sdp_m_line_index: candidate.sdp_m_line_index(),
+            }))
+        });
+        peer.connection.set_onicecandidate(Some(&on_candidate));
+        let on_channel = peer.callback(|event| {
+            let channel = event.unchecked_into::<RtcDataChannelEvent>().channel();
+            Some(PeerEvent::Channel(channel))
+        });
+        peer.connection.set_ondatachannel(Some(&on_channel));
+        Ok(peer)
+    }
+
+    /// Keeps a JS callback alive for as long as the peer, forwarding what `handler` makes of
+    /// each event.
+    fn callback(&mut self, handler: impl Fn(JsValue) -> Option<PeerEvent> + 'static) -> Function {
+        let sender = self.sender.clone();
+        let closure = Closure::wrap(Box::new(move |event: JsValue| {
+            if let Some(event) = handler(event) {
+                let _ = sender.unbounded_send(event);
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+        let function = closure.as_ref().unchecked_ref::<Function>().clone();
+        self.callbacks.push(closure);
+        function
+    }
+
+    fn attach(&mut self, channel: RtcDataChannel) {
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+        channel.set_onopen(Some(&self.callback(|_| Some(PeerEvent::Open))));
+        channel.set_onmessage(Some(&self.callback(|event| {
+            let data = event.unchecked_into::<MessageEvent>().data();
+            let message = match data.as_string() {
+                Some(text) => WsMessage::Text(text),
+                None => WsMessage::Binary(Uint8Array::new(&data).to_vec()),
+            };
+            Some(PeerEvent::Message(message))
+        })));
+        channel.set_onclose(Some(&self.callback(|_| Some(PeerEvent::Closed))));
+        self.channel = Some(channel);
+    }
+
+    async fn apply(&self, signal: Signal) -> Result<(), TransportError> {
+        match signal {
+            Signal::Answer(sdp) => {
+                let answer = description(RtcSdpType::Answer, &sdp);
+                JsFuture::from(self.connection.set_remote_description(&answer))
+                    .await
+                    .map_err(js_error)?;
+            }
+            Signal::Candidate {
+                candidate,
+                sdp_mid,
+                sdp_m_line_index,
+            } => {
+                let init = RtcIceCandidateInit::new(&candidate);
+                init.set_sdp_mid(sdp_mid.as_deref());
+                init.set_sdp_m_line_index(sdp_m_line_index);
+                let added = self
+                    .connection
+                    .add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&init));
+                JsFuture::from(added).await.map_err(js_error)?;
+            }
+            Signal::Offer(_) => info!("Ignoring a second offer"),
+        }
+        Ok(())
+    }
+
+    /// Trades candidates with `remote` until the data channel is open.
+    async fn negotiate(
+        &mut self,
+        signaling: &WorldClient,
+        remote: u64,
+    ) -> Result<(), TransportError> {
+        loop {
+            select! {
+                event = self.events.next() => match event {
+                    Some(PeerEvent::Candidate(candidate)) => {
+                        send_signal(signaling, remote, &candidate).await?
+                    }
+                    Some(PeerEvent::Channel(channel)) => self.attach(channel),
+                    Some(PeerEvent::Open) => return Ok(()),
+                    Some(PeerEvent::Message(_)) => {}
+                    Some(PeerEvent::Closed) | None => {
+                        return Err(TransportError::HandshakeFailed {
+                            code: ABNORMAL_CLOSURE,
+                            reason: "data channel closed while connecting".to_string(),
+                        })
+                    }
+                },
+                _ = TimeoutFuture::new(SIGNAL_POLL_INTERVAL.as_millis() as u32).fuse() => {
+                    for (from, signal) in fetch_signals(signaling).await? {
+                        if from == remote {
+                            self.apply(signal).await?;
+                        } else {
+                            info!("Dropping a signal from peer {} while negotiating", from);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Closes the channel and connection, detaching the callbacks first since they are
+    /// dropped with the peer.
+    fn close(&self) {
+        if let Some(channel) = &self.channel {
+            channel.set_onopen(None);
+            channel.set_onmessage(None);
+            channel.set_onclose(None);
+            channel.close();
+        }
+        self.connection.set_onicecandidate(None);
+        self.connection.set_ondatachannel(None);
+        self.connection.close();
+    }
+}
+
+/// Opens a data channel to the client with peer id `remote`, signaling through `signaling`.
+///
+/// `options.connect_timeout` bounds the whole negotiation. The channel isn't reopened when it
+/// drops, so `options.reconnect` is ignored.
+pub async fn connect_peer<Item, SinkItem>(
+    signaling: &WorldClient,
+    remote: u64,
+    options: ConnectOptions,
+    peer_options: PeerOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+{
+    info!("Connecting to peer {}", remote);
+    let mut peer = Peer::new(&peer_options)?;
+    let init = RtcDataChannelInit::new();
+    init.set_ordered(peer_options.ordered);
+    if let Some(max_retransmits) = peer_options.max_retransmits {
+        init.set_max_retransmits(max_retransmits);
+    }
+    let channel = peer
+        .connection
+        .create_data_channel_with_data_channel_dict(CHANNEL_LABEL, &init);
+    peer.attach(channel);
+    within(options.connect_timeout, async {
+        let offer = JsFuture::from(peer.connection.create_offer())
+            .await
+            .map_err(js_error)?;
+        let sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))
+            .map_err(js_error)?
+            .as_string()
+            .unwrap_or_default();
+        let local = description(RtcSdpType::Offer, &sdp);
+        JsFuture::from(peer.connection.set_local_description(&local))
+            .await
+            .map_err(js_error)?;
+        send_signal(signaling, remote, &Signal::Offer(sdp)).await?;
+        peer.negotiate(signaling, remote).await
+    })
+    .await?;
+    info!("Data channel to peer {} is open", remote);
+    Ok(start(peer, options))
+}
+
+/// Waits for another client to [`connect_peer`] to this one and accepts its data channel.
+///
+/// Waiting for the offer isn't bounded, `options.connect_timeout` applies from the offer on.
+pub async fn accept_peer<Item, SinkItem>(
+    signaling: &WorldClient,
+    options: ConnectOptions,
+    peer_options: PeerOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+{
+    let (remote, sdp) = 'offer: loop {
+        for (from, signal) in fetch_signals(signaling).await? {
+            match signal {
+                Signal::Offer(sdp) => break 'offer (from, sdp),
+                _ => info!("Dropping a signal from peer {} while waiting for an offer", from),
+            }
+        }
+        TimeoutFuture::new(SIGNAL_POLL_INTERVAL.as_millis() as u32).await;
+    };
+    info!("Accepting a data channel from peer {}", remote);
+    let mut peer = Peer::new(&peer_options)?;
+    within(options.connect_timeout, async {
+        let offer = description(RtcSdpType::Offer, &sdp);
+        JsFuture::from(peer.connection.set_remote_description(&offer))
+            .await
+            .map_err(js_error)?;
+        let answer = JsFuture::from(peer.connection.create_answer())
+            .await
+            .map_err(js_error)?;
+        let sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))
+            .map_err(js_error)?
+            .as_string()
+            .unwrap_or_default();
+        let local = description(RtcSdpType::Answer, &sdp);
+        JsFuture::from(peer.connection.set_local_description(&local))
+            .await
+            .map_err(js_error)?;
+        send_signal(signaling, remote, &Signal::Answer(sdp)).await?;
+        peer.negotiate(signaling, remote).await
+    })
+    .await?;
+    info!("Data channel from peer {} is open", remote);
+    Ok(start(peer, options))
+}
+
+fn start<Item, SinkItem>(
+    peer: Peer,
+    options: ConnectOptions,
+) -> (WsTransport<Item, SinkItem>, TransportHandle)
+where
+    Item: DeserializeOwned + 'static,
+{
+    let (transport, handle, ends) = transport::channels(options.frames, &options);
+    let decoder = FrameDecoder::new(options.max_message_size);
+    let driver = Driver {
+        options,
+        shared: ends.shared,
+        control: ends.control,
+        decoder,
+        incoming: ends.incoming,
+        outgoing: ends.outgoing,
+    };
+    spawn_local(driver.run(peer));
+    (transport, handle)
+}
+
+/// Owns the data channel on behalf of a [`WsTransport`].
+struct Driver<Item> {
+    options: ConnectOptions,
+    shared: Rc<Shared>,
+    control: mpsc::UnboundedReceiver<Control>,
+    /// Reassembles binary messages.
+    decoder: FrameDecoder,
+    incoming: mpsc::Sender<Result<Item, TransportError>>,
+    outgoing: mpsc::UnboundedReceiver<WsMessage>,
+}
+
+impl<Item> Driver<Item>
+where
+    Item: DeserializeOwned,
+{
+    async fn run(mut self, mut peer: Peer) {
+        let ended = self.pump(&mut peer).await;
+        peer.close();
+        match ended {
+            Ended::Local => {}
+            Ended::Closed(info) => self.shared.closed(&info),
+            Ended::Remote(info) => {
+                info!("Data channel lost: {}", info);
+                self.shared.closed(&info);
+                let _ = self.incoming.try_send(Err(info.into()));
+            }
+        }
+        self.shared.finish();
+    }
+
+    /// Moves messages between the data channel and the transport until either side goes away.
+    async fn pump(&mut self, peer: &mut Peer) -> Ended {
+        let channel = match peer.channel.clone() {
+            Some(channel) => channel,
+            None => return Ended::Remote(CloseInfo::abnormal("no data channel")),
+        };
+        let shared = self.shared.clone();
+        let threshold = self.options.flush_threshold;
+        loop {
+            select! {
+                event = peer.events.next() => match event {
+                    Some(PeerEvent::Message(frame)) => {
+                        shared.record_received((&frame).into());
+                        let options = &self.options;
+                        let (decoder, incoming) = (&mut self.decoder, &mut self.incoming);
+                        let delivered =
+                            deliver(&options.frames, decoder, incoming, options, frame).await;
+                        if let Err(ended) = delivered {
+                            return ended;
+                        }
+                    }
+                    Some(PeerEvent::Closed) | None => {
+                        return Ended::Remote(CloseInfo::abnormal("data channel closed"));
+                    }
+                    // Late candidates aren't needed once the channel is up.
+                    Some(_) => {}
+                },
+                frame = self.outgoing.next() => match frame {
+                    Some(frame) => {
+                        let sent = match &frame {
+                            WsMessage::Text(text) => channel.send_with_str(text),
+                            WsMessage::Binary(bytes) => channel.send_with_u8_array(bytes),
+                        };
+                        shared.queued.set(shared.queued.get().saturating_sub(1));
+                        if let Err(e) = sent {
+                            return Ended::Remote(CloseInfo::abnormal(describe(&e)));
+                        }
+                        shared.record_sent(wire_size(&frame));
+                        shared.set_buffered(channel.buffered_amount(), threshold);
+                    }
+                    None => return Ended::Local,
+                },
+                control = self.control.next() => match control {
+                    // Data channels carry no close code, the peer only sees the channel end.
+                    Some(Control::Close { code, reason, done }) => {
+                        let _ = done.send(Ok(()));
+                        return Ended::Closed(CloseInfo {
+                            code,
+                            reason,
+                            was_clean: true,
+                        });
+                    }
+                    None => {}
+                },
+                _ = Box::pin(drain_tick(&shared, threshold)).fuse() => {
+                    shared.set_buffered(channel.buffered_amount(), threshold);
+                },
+            }
+        }
+    }
+}
+
+/// `World` as a browser serves it to a peer. Only `ping` and `echo` make sense without the
+/// server.
+#[derive(Clone)]
+pub struct PeerWorld;
+
+#[tarpc::server]
+#[async_trait::async_trait]
+impl World for PeerWorld {
+    async fn ping(self, _: context::Context) -> Result<String, WorldError> {
+        info!("Peer ping called.. responding with Pong!");
+        Ok("Pong".into())
+    }
+    async fn echo(self, _: context::Context, value: String) -> Result<String, WorldError> {
+        info!("Peer echo called.. responding with {}!", value);
+        Ok(value)
+    }
+    async fn delay(self, _: context::Context, _millis: u64) -> Result<String, WorldError> {
+        Err(WorldError::Unavailable("delay is only served by the server".into()))
+    }
+    async fn peer_id(self, _: context::Context) -> Result<u64, WorldError> {
+        Err(WorldError::Unavailable("peer ids are assigned by the server".into()))
+    }
+    async fn signal(
+        self,
+        _: context::Context,
+        _to: u64,
+        _payload: String,
+    ) -> Result<(), WorldError> {
+        Err(WorldError::Unavailable("signaling goes through the server".into()))
+    }
+    async fn signals(self, _: context::Context) -> Result<Vec<(u64, String)>, WorldError> {
+        Err(WorldError::Unavailable("signaling goes through the server".into()))
+    }
+    async fn count(self, _: context::Context, _stream: u64, _n: u64) -> Result<(), WorldError> {
+        Err(WorldError::Unavailable("data channels don't carry streams".into()))
+    }
+    async fn subscribe(self, _: context::Context, _topic: String) -> Result<(), WorldError> {
+        Err(WorldError::Unavailable("topics are served by the server".into()))
+    }
+    async fn unsubscribe(self, _: context::Context, _topic: String) -> Result<(), WorldError> {
+        Err(WorldError::Unavailable("topics are served by the server".into()))
+    }
+    async fn publish(
+        self,
+        _: context::Context,
+        _topic: String,
+        _payload: String,
+    ) -> Result<u64, WorldError> {
+        Err(WorldError::Unavailable("topics are served by the server".into()))
+    }
+    async fn delay_with_progress(
+        self,
+        _: context::Context,
+        _millis: u64,
+        _progress: u64,
+    ) -> Result<String, WorldError> {
+        Err(WorldError::Unavailable("data channels don't carry streams".into()))
+    }
+    async fn whoami(self, _: context::Context) -> Result<SessionInfo, WorldError> {
+        Err(WorldError::Unavailable("sessions are kept by the server".into()))
+    }
+    async fn list_clients(self, _: context::Context) -> Result<Vec<ClientInfo>, WorldError> {
+        Err(WorldError::Unavailable("only the server knows its clients".into()))
+    }
+    async fn upload_begin(self, _: context::Context, _: String, _: u64) -> Result<u64, WorldError> {
+        Err(WorldError::Unavailable("files are uploaded to the server".into()))
+    }
+    async fn upload_chunk(
+        self,
+        _: context::Context,
+        _: u64,
+        _: u64,
+        _: Bytes,
+    ) -> Result<(), WorldError> {
+        Err(WorldError::Unavailable("files are uploaded to the server".into()))
+    }
+    async fn upload_finish(
+        self,
+        _: context::Context,
+        _: u64,
+        _: u32,
+    ) -> Result<UploadResult, WorldError> {
+        Err(WorldError::Unavailable("files are uploaded to the server".into()))
+    }
+    async fn list_items(
+        self,
+        _: context::Context,
+        _: Option<String>,
+        _: u32,
+    ) -> Result<Page, WorldError> {
+        Err(WorldError::Unavailable("the items are kept by the server".into()))
+    }
+    async fn increment(self, _: context::Context, _: i64) -> Result<i64, WorldError> {
+        Err(WorldError::Unavailable("the count is kept by the server".into()))
+    }
+    async fn get_count(self, _: context::Context) -> Result<i64, WorldError> {
+        Err(WorldError::Unavailable("the count is kept by the server".into()))
+    }
+    async fn version(self, _: context::Context) -> Result<VersionInfo, WorldError> {
+        Err(WorldError::Unavailable("peers aren't servers".into()))
+    }
+    async fn echo_bytes(self, _: context::Context, data: Bytes) -> Result<Bytes, WorldError> {
+        info!("Peer echo_bytes called.. responding with {} bytes!", data.len());
+        Ok(data)
+    }
+    async fn create_task(
+        self,
+        _: context::Context,
+        _: CreateTaskRequest,
+    ) -> Result<Task, WorldError> {
+        Err(WorldError::Unavailable("the tasks are kept by the server".into()))
+    }
+    async fn log_event(
+        self,
+        _: context::Context,
+        name: String,
+        payload: String,
+    ) -> Result<(), WorldError> {
+        info!("Peer logged event {}: {:?}", name, payload);
+        Ok(())
+    }
+}
+
+/// Answers `World` requests arriving on an [`accept_peer`] channel until it closes.
+pub async fn serve_peer(
+    transport: WsTransport<ClientMessage<WorldRequest>, Response<WorldResponse>>,
+) {
+    BaseChannel::with_defaults(transport)
+        .requests()
+        .for_each(|request| async move {
+            match request {
+                Ok(request) => spawn_local(request.execute(PeerWorld.serve())),
+                Err(e) => info!("Failed to read a peer request: {}", e),
+            }
+        })
+        .await
+}
@@ -0,0 +1,289 @@
+//! WebTransport (HTTP/3) alternative to the WebSocket transport.
+//!
+//! tarpc messages travel over a single bidirectional stream using the same length prefixed
+//! bincode framing as binary WebSocket frames, see [`rpc::framing`]. The transport handed to
+//! tarpc is the same [`WsTransport`], so nothing above it can tell the two apart.
+//!
+//! `web_sys` only exposes WebTransport with `RUSTFLAGS=--cfg=web_sys_unstable_apis`.
+
+use std::pin::Pin;
+use std::rc::Rc;
+
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::{select, FutureExt, SinkExt, Stream, StreamExt};
+use gloo_timers::future::{IntervalStream, TimeoutFuture};
+use js_sys::{Reflect, Uint8Array};
+use log::info;
+use rpc::framing::FrameDecoder;
+use rpc::Envelope;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use wasm_streams::readable::IntoStream;
+use wasm_streams::writable::IntoSink;
+use wasm_streams::{ReadableStream, WritableStream};
+use web_sys::{WebTransport, WebTransportBidirectionalStream, WebTransportCloseInfo};
+use ws_stream_wasm::WsMessage;
+
+use crate::error::{describe, TransportError};
+use crate::transport::{
+    self, close_handshake, deliver, reconnect, wire_size, with_token, CloseInfo, ConnectOptions,
+    Control, Ended, FrameMode, Liveness, Shared, TransportHandle, WsTransport, ABNORMAL_CLOSURE,
+};
+
+/// Opens a WebTransport session at the `https://` `url` and spawns the task driving it.
+///
+/// Messages are always binary, `options.frames` is ignored. Everything else in `options`
+/// behaves as it does for [`transport::connect`].
+pub async fn connect<Item, SinkItem>(
+    url: &str,
+    options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+{
+    let session = open(url, &options).await?;
+    let heartbeat = transport::encode(&FrameMode::Binary, &Envelope::<SinkItem>::Heartbeat, None)?;
+    let (transport, handle, ends) = transport::channels(FrameMode::Binary, &options);
+    let decoder = FrameDecoder::new(options.max_message_size);
+    let driver = Driver {
+        url: url.to_string(),
+        options,
+        shared: ends.shared,
+        control: ends.control,
+        heartbeat: into_bytes(heartbeat),
+        decoder,
+        incoming: ends.incoming,
+        outgoing: ends.outgoing,
+    };
+    spawn_local(driver.run(session));
+    Ok((transport, handle))
+}
+
+fn into_bytes(message: WsMessage) -> Vec<u8> {
+    match message {
+        WsMessage::Binary(bytes) => bytes,
+        WsMessage::Text(text) => text.into_bytes(),
+    }
+}
+
+struct Session {
+    transport: WebTransport,
+    reader: IntoStream<'static>,
+    writer: IntoSink<'static>,
+}
+
+async fn open(url: &str, options: &ConnectOptions) -> Result<Session, TransportError> {
+    info!("Connecting to server over WebTransport: {}", url);
+    let connect_url = match &options.auth {
+        Some(auth) => with_token(url, auth.token()),
+        None => url.to_string(),
+    };
+    let transport = WebTransport::new(&connect_url).map_err(|e| {
+        info!("Errored on WebTransport construction\n{}", describe(&e));
+        TransportError::InvalidUrl(url.to_string())
+    })?;
+    let ready = JsFuture::from(transport.ready());
+    let ready = match options.connect_timeout {
+        Some(timeout) => {
+            let timer = TimeoutFuture::new(timeout.as_millis() as u32);
+            match future::select(ready, timer).await {
+                Either::Left((ready, _)) => ready,
+                Either::Right(_) => {
+                    info!("Connecting to {} timed out after {:?}", url, timeout);
+                    transport.close();
+                    return Err(TransportError::Timeout);
+                }
+            }
+        }
+        None => ready.await,
+    };
+    ready.map_err(|e| TransportError::HandshakeFailed {
+        code: ABNORMAL_CLOSURE,
+        reason: describe(&e),
+    })?;
+    let stream: WebTransportBidirectionalStream =
+        JsFuture::from(transport.create_bidirectional_stream())
+            .await
+            .map_err(|e| TransportError::JsError(describe(&e)))?
+            .unchecked_into();
+    let reader = ReadableStream::from_raw(stream.readable().unchecked_into()).into_stream();
+    let writer = WritableStream::from_raw(stream.writable().unchecked_into()).into_sink();
+    Ok(Session {
+        transport,
+        reader,
+        writer,
+    })
+}
+
+/// Waits for the session to end. A clean close resolves with the code and reason the server
+/// sent, anything else rejects.
+async fn closed(transport: &WebTransport) -> CloseInfo {
+    match JsFuture::from(transport.closed()).await {
+        Ok(info) => {
+            let field = |name: &str| Reflect::get(&info, &JsValue::from_str(name)).ok();
+            let code = field("closeCode").and_then(|code| code.as_f64()).unwrap_or(0.0);
+            CloseInfo {
+                // WebTransport codes are 32 bits wide, the WebSocket ones we report are 16.
+                code: code.min(u16::MAX as f64) as u16,
+                reason: field("reason")
+                    .and_then(|reason| reason.as_string())
+                    .unwrap_or_default(),
+                was_clean: true,
+            }
+        }
+        Err(e) => CloseInfo::abnormal(describe(&e)),
+    }
+}
+
+/// Closes the session and waits for it to be gone, see [`close_handshake`].
+async fn close(transport: &WebTransport, code: u16, reason: &str) -> CloseInfo {
+    let mut close_info = WebTransportCloseInfo::new();
+    close_info.close_code(code as u32).reason(reason);
+    transport.close_with_close_info(&close_info);
+    let closed = close_handshake(code, reason, closed(transport)).await;
+    CloseInfo::new(code, reason, closed.is_some_and(|info| info.was_clean))
+}
+
+/// Owns the session on behalf of a [`WsTransport`].
+struct Driver<Item> {
+    url: String,
+    options: ConnectOptions,
+    shared: Rc<Shared>,
+    control: mpsc::UnboundedReceiver<Control>,
+    /// Pre-encoded `Envelope::Heartbeat`.
+    heartbeat: Vec<u8>,
+    /// Reassembles messages split across stream chunks.
+    decoder: FrameDecoder,
+    incoming: mpsc::Sender<Result<Item, TransportError>>,
+    outgoing: mpsc::UnboundedReceiver<WsMessage>,
+}
+
+impl<Item> Driver<Item>
+where
+    Item: DeserializeOwned,
+{
+    async fn run(mut self, session: Session) {
+        self.serve(session).await;
+        self.shared.finish();
+    }
+
+    async fn serve(&mut self, mut session: Session) {
+        loop {
+            let ended = self.pump(&mut session).await;
+            let info = match ended {
+                Ended::Local => {
+                    session.transport.close();
+                    return;
+                }
+                Ended::Closed(info) => {
+                    self.shared.closed(&info);
+                    return;
+                }
+                Ended::Remote(info) => {
+                    session.transport.close();
+                    info
+                }
+            };
+            info!("Session with {} lost: {}", self.url, info);
+            let reconnected = match &self.options.reconnect {
+                Some(policy) => {
+                    reconnect(&self.url, policy, &self.shared, &self.incoming, || {
+                        open(&self.url, &self.options)
+                    })
+                    .await
+                }
+                None => None,
+            };
+            match reconnected {
+                Some(new_session) => session = new_session,
+                None => {
+                    self.shared.closed(&info);
+                    let _ = self.incoming.try_send(Err(info.into()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves messages between the session and the transport until either side goes away.
+    async fn pump(&mut self, session: &mut Session) -> Ended {
+        let shared = self.shared.clone();
+        let threshold = self.options.flush_threshold;
+        self.decoder.clear();
+        let mut keepalive: Pin<Box<dyn Stream<Item = ()>>> = match self.options.keepalive {
+            Some(interval) => Box::pin(IntervalStream::new(interval.as_millis() as u32)),
+            None => Box::pin(futures::stream::pending()),
+        };
+        let mut liveness = Liveness::new(self.options.heartbeat_timeout);
+        let transport = session.transport.clone();
+        let mut session_closed = Box::pin(async move { closed(&transport).await }).fuse();
+        loop {
+            select! {
+                chunk = session.reader.next().fuse() => match chunk {
+                    Some(Ok(chunk)) => {
+                        let frame = WsMessage::Binary(Uint8Array::new(&chunk).to_vec());
+                        shared.record_received((&frame).into());
+                        liveness.received();
+                        let options = &self.options;
+                        let (decoder, incoming) = (&mut self.decoder, &mut self.incoming);
+                        let delivered =
+                            deliver(&FrameMode::Binary, decoder, incoming, options, frame).await;
+                        if let Err(ended) = delivered {
+                            return ended;
+                        }
+                    }
+                    Some(Err(e)) => return Ended::Remote(CloseInfo::abnormal(describe(&e))),
+                    None => return Ended::Remote(session_closed.await),
+                },
+                info = session_closed => return Ended::Remote(info),
+                frame = self.outgoing.next() => match frame {
+                    Some(frame) => {
+                        let size = wire_size(&frame);
+                        let chunk = Uint8Array::from(into_bytes(frame).as_slice());
+                        let sent = session.writer.send(chunk.into()).await;
+                        // The writer only resolves once the stream took the bytes, there is no
+                        // separate send buffer to wait for.
+                        shared.queued.set(shared.queued.get().saturating_sub(1));
+                        if let Err(e) = sent {
+                            return Ended::Remote(CloseInfo::abnormal(describe(&e)));
+                        }
+                        shared.record_sent(size);
+                        if shared.flushed(threshold) {
+                            shared.wake_flush();
+                        }
+                    }
+                    None => {
+                        let _ = session.writer.close().await;
+                        return Ended::Local;
+                    }
+                },
+                control = self.control.next() => match control {
+                    Some(Control::Close { code, reason, done }) => {
+                        let info = close(&session.transport, code, &reason).await;
+                        let _ = done.send(Ok(()));
+                        return Ended::Closed(info);
+                    }
+                    None => {}
+                },
+                _ = keepalive.next().fuse() => {
+                    let heartbeat = Uint8Array::from(self.heartbeat.as_slice());
+                    if let Err(e) = session.writer.send(heartbeat.into()).await {
+                        info!("Failed to send heartbeat: {}", describe(&e));
+                        return Ended::Remote(CloseInfo::abnormal(describe(&e)));
+                    }
+                    shared.record_sent(self.heartbeat.len());
+                    liveness.sent();
+                },
+                info = liveness.missed().fuse() => {
+                    if let Some(info) = info {
+                        return Ended::Remote(info);
+                    }
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,625 @@
+//! Runs the WebSocket in a dedicated Web Worker, keeping frame handling off the main thread.
+//!
+//! The worker is this crate's `worker` binary, which trunk builds next to the page, see
+//! `index.html`. It owns the socket, reassembles and inflates binary frames and transfers each
+//! message's bytes to the main thread, which then only deserializes them. Messages to the
+//! server are serialized and framed here as usual and transferred to the worker as they are.
+//! tarpc gets the same [`WsTransport`] as without the worker.
+//!
+//! With a shared worker all tabs use one WebSocket, the worker keeps their request ids apart.
+//!
+//! A flush completes once the worker handed the messages to its socket, the socket's own send
+//! buffer isn't visible from here, so `flush_threshold` doesn't apply.
+
+mod protocol;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use futures::channel::mpsc;
+use futures::future;
+use futures::{select, FutureExt, Stream, StreamExt};
+use gloo_timers::future::IntervalStream;
+use js_sys::{Array, Uint8Array};
+use log::info;
+use rpc::codec::Codec;
+use rpc::handshake::Hello;
+use rpc::{wire, Envelope};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{MessageEvent, MessagePort, SharedWorker, Worker};
+use ws_stream_wasm::WsMessage;
+
+use self::protocol::*;
+use crate::error::{describe, TransportError};
+use crate::transport::{
+    self, close_handshake, first_answer, forward, oversized, overtook_welcome, reconnect, welcomed,
+    within, wire_size, with_token, Answer, Auth, CloseInfo, ConnectOptions, Control, Ended,
+    FrameMode, Liveness, Payload, Shared, TransportHandle, WorkerScript, WsTransport,
+    ABNORMAL_CLOSURE,
+};
+
+/// Where trunk puts the script loading the `worker` binary.
+pub const DEFAULT_WORKER_URL: &str = "./worker_loader.js";
+
+/// Where trunk puts the script loading the `shared_worker` binary.
+pub const DEFAULT_SHARED_WORKER_URL: &str = "./shared_worker_loader.js";
+
+/// Starts the worker running `script`, has it open the WebSocket at `url` and spawns the task
+/// talking to it.
+///
+/// A shared worker keeps one WebSocket for every tab of the origin that connects to the same
+/// `url` with the same options, and closes it a few seconds after the last tab left. Messages
+/// are always JSON then, `options.frames` is ignored. Browsers without `SharedWorker` fail
+/// with [`TransportError::JsError`].
+pub async fn connect<Item, SinkItem>(
+    url: &str,
+    script: &WorkerScript,
+    mut options: ConnectOptions,
+) -> Result<(WsTransport<Item, SinkItem>, TransportHandle), TransportError>
+where
+    Item: DeserializeOwned + 'static,
+    SinkItem: Serialize,
+{
+    if let WorkerScript::Shared(_) = script {
+        options.frames = FrameMode::Text;
+    }
+    let port = Port::spawn(script)?;
+    open(&port, url, &options).await?;
+    let heartbeat = transport::encode(&options.frames, &Envelope::<SinkItem>::Heartbeat, None)?;
+    let (transport, handle, ends) = transport::channels(options.frames, &options);
+    let driver = Driver {
+        url: url.to_string(),
+        options,
+        port,
+        connected: true,
+        in_flight: VecDeque::new(),
+        shared: ends.shared,
+        control: ends.control,
+        heartbeat,
+        incoming: ends.incoming,
+        outgoing: ends.outgoing,
+    };
+    spawn_local(driver.run());
+    Ok((transport, handle))
+}
+
+/// What the worker posted, see [`protocol`].
+enum Reply {
+    Opened(String),
+    Failed { code: u16, reason: String },
+    Sent,
+    Text(String),
+    Message(Vec<u8>),
+    Oversized(usize),
+    Corrupt(String),
+    Rejected(String),
+    Closed(CloseInfo),
+}
+
+impl Reply {
+    fn parse(reply: &Array) -> Option<Reply> {
+        let field = |index| reply.get(index);
+        let reply = match reply.get(0).as_string()?.as_str() {
+            OPENED => Reply::Opened(field(1).as_string().unwrap_or_default()),
+            FAILED => Reply::Failed {
+                code: field(1).as_f64()? as u16,
+                reason: field(2).as_string().unwrap_or_default(),
+            },
+            SENT => Reply::Sent,
+            TEXT => Reply::Text(field(1).as_string()?),
+            MESSAGE => Reply::Message(Uint8Array::new(&field(1)).to_vec()),
+            OVERSIZED => Reply::Oversized(field(1).as_f64()? as usize),
+            CORRUPT => Reply::Corrupt(field(1).as_string().unwrap_or_default()),
+            REJECTED => Reply::Rejected(field(1).as_string().unwrap_or_default()),
+            CLOSED => Reply::Closed(CloseInfo {
+                code: field(1).as_f64()? as u16,
+                reason: field(2).as_string().unwrap_or_default(),
+                was_clean: field(3).as_bool().unwrap_or(false),
+            }),
+            _ => return None,
+        };
+        Some(reply)
+    }
+}
+
+/// The worker as seen from the main thread.
+struct Port {
+    script: WorkerScript,
+    worker: RefCell<Spawned>,
+    replies_tx: mpsc::UnboundedSender<Array>,
+    replies: RefCell<mpsc::UnboundedReceiver<Array>>,
+}
+
+enum Target {
+    Dedicated(Worker),
+    Shared {
+        _worker: SharedWorker,
+        port: MessagePort,
+        /// Tells the worker the tab is gone, which doesn't run destructors.
+        on_pagehide: Closure<dyn FnMut(web_sys::Event)>,
+    },
+}
+
+struct Spawned {
+    target: Target,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(web_sys::Event)>,
+}
+
+impl Spawned {
+    fn post_message(&self, message: &JsValue) -> Result<(), JsValue> {
+        match &self.target {
+            Target::Dedicated(worker) => worker.post_message(message),
+            Target::Shared { port, .. } => port.post_message(message),
+        }
+    }
+}
+
+impl Drop for Spawned {
+    fn drop(&mut self) {
+        match &self.target {
+            Target::Dedicated(worker) => worker.terminate(),
+            Target::Shared {
+                port, on_pagehide, ..
+            } => {
+                let _ = port.post_message(&Array::of1(&LEAVE.into()));
+                port.close();
+                if let Some(window) = web_sys::window() {
+                    let _ = window.remove_event_listener_with_callback(
+                        "pagehide",
+                        on_pagehide.as_ref().unchecked_ref(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Port {
+    fn spawn(script: &WorkerScript) -> Result<Self, TransportError> {
+        let (replies_tx, replies) = mpsc::unbounded();
+        let worker = Self::start(script, &replies_tx)?;
+        Ok(Self {
+            script: script.clone(),
+            worker: RefCell::new(worker),
+            replies_tx,
+            replies: RefCell::new(replies),
+        })
+    }
+
+    fn start(
+        script: &WorkerScript,
+        replies: &mpsc::UnboundedSender<Array>,
+    ) -> Result<Spawned, TransportError> {
+        let messages = replies.clone();
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let _ = messages.unbounded_send(Array::from(&event.data()));
+        }) as Box<dyn FnMut(MessageEvent)>);
+        let errors = replies.clone();
+        let on_error = Closure::wrap(Box::new(move |_: web_sys::Event| {
+            let closed = Array::of4(
+                &CLOSED.into(),
+                &ABNORMAL_CLOSURE.into(),
+                &"the transport worker failed".into(),
+                &false.into(),
+            );
+            let _ = errors.unbounded_send(closed);
+        }) as Box<dyn FnMut(web_sys::Event)>);
+        let js_error = |e: JsValue| TransportError::JsError(describe(&e));
+        let target = match script {
+            WorkerScript::Dedicated(url) => {
+                info!("Starting the transport worker: {}", url);
+                let worker = Worker::new(url).map_err(js_error)?;
+                worker.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+                worker.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+                Target::Dedicated(worker)
+            }
+            WorkerScript::Shared(url) => {
+                info!("Joining the shared transport worker: {}", url);
+                let worker = SharedWorker::new(url).map_err(js_error)?;
+                let port = worker.port();
+                // Setting the handler also starts the port.
+                port.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+                worker.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+                let leaving = port.clone();
+                let on_pagehide = Closure::wrap(Box::new(move |_: web_sys::Event| {
+                    let _ = leaving.post_message(&Array::of1(&LEAVE.into()));
+                }) as Box<dyn FnMut(web_sys::Event)>);
+                if let Some(window) = web_sys::window() {
+                    window
+                        .add_event_listener_with_callback(
+                            "pagehide",
+                            on_pagehide.as_ref().unchecked_ref(),
+                        )
+                        .map_err(js_error)?;
+                }
+                Target::Shared {
+                    _worker: worker,
+                    port,
+                    on_pagehide,
+                }
+            }
+        };
+        Ok(Spawned {
+            target,
+            _on_message: on_message,
+            _on_error: on_error,
+        })
+    }
+
+    /// Replaces the worker, so nothing the old one still had to say is mistaken for an answer
+    /// to what is asked next. A shared worker stays, this tab just talks to it over a new port.
+    fn restart(&self) -> Result<(), TransportError> {
+        let worker = Self::start(&self.script, &self.replies_tx)?;
+        self.worker.replace(worker);
+        Ok(())
+    }
+
+    fn post(&self, message: &[JsValue]) -> Result<(), TransportError> {
+        let message: Array = message.iter().collect();
+        self.worker
+            .borrow()
+            .post_message(&message)
+            .map_err(|e| TransportError::JsError(describe(&e)))
+    }
+
+    /// Sends `frame`, handing binary buffers over to a dedicated worker instead of copying
+    /// them.
+    fn send(&self, frame: WsMessage) -> Result<(), TransportError> {
+        let bytes = match frame {
+            WsMessage::Text(text) => return self.post(&[SEND.into(), text.into()]),
+            WsMessage::Binary(bytes) => bytes,
+        };
+        let buffer = Uint8Array::from(bytes.as_slice()).buffer();
+        let message = Array::of2(&SEND.into(), &buffer);
+        let spawned = self.worker.borrow();
+        let sent = match &spawned.target {
+            Target::Dedicated(worker) => {
+                worker.post_message_with_transfer(&message, &Array::of1(&buffer))
+            }
+            Target::Shared { port, .. } => port.post_message(&message),
+        };
+        sent.map_err(|e| TransportError::JsError(describe(&e)))
+    }
+
+    async fn reply(&self) -> Reply {
+        loop {
+            let reply = future::poll_fn(|cx| self.replies.borrow_mut().poll_next_unpin(cx)).await;
+            match reply {
+                Some(reply) => {
+                    if let Some(reply) = Reply::parse(&reply) {
+                        return reply;
+                    }
+                }
+                // The port holds a sender itself, but better safe than spinning.
+                None => return Reply::Closed(CloseInfo::abnormal("the transport worker is gone")),
+            }
+        }
+    }
+}
+
+async fn open(port: &Port, url: &str, options: &ConnectOptions) -> Result<(), TransportError> {
+    info!("Connecting to server from the worker: {}", url);
+    let requested = options.frames.protocol();
+    let open = Open {
+        url: match &options.auth {
+            Some(Auth::Query(token)) => with_token(url, token),
+            _ => url.to_string(),
+        },
+        protocol: requested.to_string(),
+        max_message_size: options.max_message_size,
+    };
+    let open = serde_json::to_string(&open).map_err(TransportError::serialization)?;
+    port.post(&[OPEN.into(), open.into()])?;
+    let opened = async {
+        loop {
+            match port.reply().await {
+                Reply::Opened(selected) => return Ok(selected),
+                Reply::Failed { code, reason } => {
+                    return Err(TransportError::HandshakeFailed { code, reason })
+                }
+                Reply::Closed(info) => {
+                    return Err(TransportError::HandshakeFailed {
+                        code: info.code,
+                        reason: info.reason,
+                    })
+                }
+                // Left over from the previous connection.
+                _ => {}
+            }
+        }
+    };
+    let selected = match within(options.connect_timeout, opened).await {
+        Ok(opened) => opened?,
+        Err(e) => {
+            info!("Connecting to {} timed out", url);
+            port.restart()?;
+            return Err(e);
+        }
+    };
+    if selected != requested {
+        info!("Server selected subprotocol {:?} instead of {}", selected, requested);
+        port.post(&[CLOSE.into(), 1000.into(), "".into()])?;
+        return Err(TransportError::ProtocolMismatch {
+            requested: requested.to_string(),
+            selected,
+        });
+    }
+    if let Some(auth) = &options.auth {
+        if let Err(e) = authenticate(port, auth, options).await {
+            port.post(&[CLOSE.into(), 1000.into(), "".into()])?;
+            return Err(e);
+        }
+    }
+    if options.version_check {
+        if let Err(e) = check_version(port, options).await {
+            port.post(&[CLOSE.into(), 1000.into(), "".into()])?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Same as for a socket on the main thread: sends the token if it isn't in the URL and waits
+/// for [`Envelope::AuthAck`].
+async fn authenticate(
+    port: &Port,
+    auth: &Auth,
+    options: &ConnectOptions,
+) -> Result<(), TransportError> {
+    if let Auth::Message(token) = auth {
+        let auth = Envelope::<()>::Auth(token.clone());
+        port.send(transport::encode(&options.frames, &auth, None)?)?;
+    }
+    let answer = first_answer(next_answer(port), &options.frames, options, "token").await?;
+    if matches!(answer, Some(Envelope::AuthAck)) {
+        Ok(())
+    } else {
+        Err(TransportError::HandshakeFailed {
+            code: ABNORMAL_CLOSURE,
+            reason: "server didn't acknowledge the token".to_string(),
+        })
+    }
+}
+
+/// Same as for a socket on the main thread: sends our [`Hello`] and waits for the server to
+/// welcome it.
+async fn check_version(port: &Port, options: &ConnectOptions) -> Result<(), TransportError> {
+    let hello = Envelope::<()>::Hello(Hello::new(options.frames.protocol()));
+    port.send(transport::encode(&options.frames, &hello, None)?)?;
+    let answer = loop {
+        let answer = first_answer(next_answer(port), &options.frames, options, "version check");
+        let answer = answer.await?;
+        if !overtook_welcome(&answer) {
+            break answer;
+        }
+    };
+    welcomed(answer)
+}
+
+/// What the worker passes on next from the server, for [`first_answer`].
+async fn next_answer(port: &Port) -> Answer {
+    loop {
+        match port.reply().await {
+            Reply::Text(text) => return Answer::Text(text),
+            Reply::Message(message) => return Answer::Message(message),
+            Reply::Closed(info) => return Answer::Closed(info),
+            _ => {}
+        }
+    }
+}
+
+/// A message posted to the worker that it hasn't confirmed yet.
+struct InFlight {
+    size: usize,
+    /// Counted in [`Shared::queued`], unlike heartbeats.
+    queued: bool,
+}
+
+/// Talks to the worker on behalf of a [`WsTransport`].
+struct Driver<Item> {
+    url: String,
+    options: ConnectOptions,
+    port: Port,
+    /// The worker's socket may be open.
+    connected: bool,
+    in_flight: VecDeque<InFlight>,
+    shared: Rc<Shared>,
+    control: mpsc::UnboundedReceiver<Control>,
+    /// Pre-encoded `Envelope::Heartbeat`.
+    heartbeat: WsMessage,
+    incoming: mpsc::Sender<Result<Item, TransportError>>,
+    outgoing: mpsc::UnboundedReceiver<WsMessage>,
+}
+
+impl<Item> Driver<Item>
+where
+    Item: DeserializeOwned,
+{
+    async fn run(mut self) {
+        self.serve().await;
+        self.shared.finish();
+    }
+
+    async fn serve(&mut self) {
+        loop {
+            let ended = self.pump().await;
+            if self.connected && !matches!(ended, Ended::Closed(_)) {
+                let _ = self.close(1000, "").await;
+            }
+            self.connected = false;
+            let info = match ended {
+                Ended::Local => return,
+                Ended::Closed(info) => {
+                    self.shared.closed(&info);
+                    return;
+                }
+                Ended::Remote(info) => info,
+            };
+            info!("Connection to {} lost: {}", self.url, info);
+            let reconnected = match &self.options.reconnect {
+                Some(policy) => {
+                    let (url, options, port) = (&self.url, &self.options, &self.port);
+                    reconnect(url, policy, &self.shared, &self.incoming, || {
+                        open(port, url, options)
+                    })
+                    .await
+                }
+                None => None,
+            };
+            match reconnected {
+                Some(()) => self.connected = true,
+                None => {
+                    self.shared.closed(&info);
+                    let _ = self.incoming.try_send(Err(info.into()));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves messages between the worker and the transport until either side goes away.
+    async fn pump(&mut self) -> Ended {
+        let mut keepalive: Pin<Box<dyn Stream<Item = ()>>> = match self.options.keepalive {
+            Some(interval) => Box::pin(IntervalStream::new(interval.as_millis() as u32)),
+            None => Box::pin(futures::stream::pending()),
+        };
+        let mut liveness = Liveness::new(self.options.heartbeat_timeout);
+        loop {
+            select! {
+                reply = self.port.reply().fuse() => {
+                    if !matches!(reply, Reply::Sent) {
+                        liveness.received();
+                    }
+                    if let Err(ended) = self.receive(reply).await {
+                        return ended;
+                    }
+                },
+                frame = self.outgoing.next() => match frame {
+                    Some(frame) => {
+                        let size = wire_size(&frame);
+                        if let Err(e) = self.port.send(frame) {
+                            return Ended::Remote(CloseInfo::abnormal(e));
+                        }
+                        self.in_flight.push_back(InFlight { size, queued: true });
+                    }
+                    None => return Ended::Local,
+                },
+                control = self.control.next() => match control {
+                    Some(Control::Close { code, reason, done }) => {
+                        match self.close(code, &reason).await {
+                            Ok(info) => {
+                                let _ = done.send(Ok(()));
+                                return Ended::Closed(info);
+                            }
+                            Err(e) => {
+                                let _ = done.send(Err(e));
+                            }
+                        }
+                    }
+                    None => {}
+                },
+                _ = keepalive.next().fuse() => {
+                    let size = wire_size(&self.heartbeat);
+                    if let Err(e) = self.port.send(self.heartbeat.clone()) {
+                        return Ended::Remote(CloseInfo::abnormal(e));
+                    }
+                    self.in_flight.push_back(InFlight { size, queued: false });
+                    liveness.sent();
+                },
+                info = liveness.missed().fuse() => {
+                    if let Some(info) = info {
+                        return Ended::Remote(info);
+                    }
+                },
+            }
+        }
+    }
+
+    async fn receive(&mut self, reply: Reply) -> Result<(), Ended> {
+        let (incoming, options) = (&mut self.incoming, &self.options);
+        match reply {
+            Reply::Sent => {
+                self.sent();
+                Ok(())
+            }
+            Reply::Text(text) => {
+                self.shared.record_received(Payload::Text(&text));
+                if text.len() > options.max_message_size {
+                    return oversized(incoming, options, text.len()).await;
+                }
+                let envelope = wire::decode_text(&text).map_err(TransportError::serialization);
+                forward(incoming, options, envelope).await
+            }
+            Reply::Message(bytes) => {
+                self.shared.record_received(Payload::Binary(&bytes));
+                let envelope = options.frames.decode(&bytes);
+                let envelope = envelope.map_err(TransportError::serialization);
+                forward(incoming, options, envelope).await
+            }
+            Reply::Oversized(len) => oversized(incoming, options, len).await,
+            Reply::Corrupt(reason) => {
+                let error = TransportError::serialization(reason);
+                forward(incoming, options, Err(error)).await
+            }
+            Reply::Closed(info) => {
+                self.connected = false;
+                Err(Ended::Remote(info))
+            }
+            // Left over from opening or closing.
+            Reply::Opened(_) | Reply::Failed { .. } | Reply::Rejected(_) => Ok(()),
+        }
+    }
+
+    fn sent(&mut self) {
+        if let Some(sent) = self.in_flight.pop_front() {
+            self.shared.record_sent(sent.size);
+            if sent.queued {
+                let queued = self.shared.queued.get().saturating_sub(1);
+                self.shared.queued.set(queued);
+            }
+        }
+        if self.shared.flushed(self.options.flush_threshold) {
+            self.shared.wake_flush();
+        }
+    }
+
+    /// Runs the close handshake, see [`close_handshake`].
+    async fn close(&mut self, code: u16, reason: &str) -> Result<CloseInfo, TransportError> {
+        self.port.post(&[CLOSE.into(), code.into(), reason.into()])?;
+        let closing = async {
+            loop {
+                match self.port.reply().await {
+                    Reply::Closed(info) => return Ok(info),
+                    Reply::Rejected(reason) => return Err(TransportError::JsError(reason)),
+                    Reply::Sent => self.sent(),
+                    _ => {}
+                }
+            }
+        };
+        match close_handshake(code, reason, closing).await {
+            Some(closed) => closed,
+            None => {
+                self.port.restart()?;
+                // Whatever the old worker still had is gone with it.
+                for sent in self.in_flight.drain(..) {
+                    if sent.queued {
+                        let queued = self.shared.queued.get().saturating_sub(1);
+                        self.shared.queued.set(queued);
+                    }
+                }
+                Ok(CloseInfo {
+                    code,
+                    reason: reason.to_string(),
+                    was_clean: false,
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Messages between the main thread and the worker.
+//!
+//! Both sides post arrays whose first element names the message, followed by its fields. This
+//! file is shared with the worker binary, so it only depends on `serde`.
+
+use serde::{Deserialize, Serialize};
+
+/// `[OPEN, json Open]`: connects to a server, closing the previous socket if there is one.
+pub const OPEN: &str = "open";
+/// `[SEND, data]`: sends a WebSocket message, a string or a transferred `ArrayBuffer`.
+pub const SEND: &str = "send";
+/// `[CLOSE, code, reason]`: runs the close handshake. The shared worker only lets the tab
+/// go, the socket stays open for the other tabs.
+pub const CLOSE: &str = "close";
+/// `[LEAVE]`: the tab goes away, only sent to the shared worker.
+// Unused by the dedicated worker, which includes this file too.
+#[allow(dead_code)]
+pub const LEAVE: &str = "leave";
+
+/// `[OPENED, protocol]`: the socket is open with the subprotocol the server selected.
+pub const OPENED: &str = "opened";
+/// `[FAILED, code, reason]`: the socket couldn't be opened.
+pub const FAILED: &str = "failed";
+/// `[SENT]`: the socket took the message of a `SEND`, or there was no socket to take it.
+pub const SENT: &str = "sent";
+/// `[TEXT, string]`: a text frame arrived.
+pub const TEXT: &str = "text";
+/// `[MESSAGE, ArrayBuffer]`: a message taken out of binary frames, already inflated.
+pub const MESSAGE: &str = "message";
+/// `[OVERSIZED, len]`: a message larger than `Open::max_message_size` was skipped.
+pub const OVERSIZED: &str = "oversized";
+/// `[CORRUPT, reason]`: a message couldn't be inflated.
+pub const CORRUPT: &str = "corrupt";
+/// `[REJECTED, reason]`: the browser refused a `CLOSE`, the socket is untouched.
+pub const REJECTED: &str = "rejected";
+/// `[CLOSED, code, reason, was_clean]`: the socket is gone.
+pub const CLOSED: &str = "closed";
+
+#[derive(Serialize, Deserialize)]
+pub struct Open {
+    pub url: String,
+    /// The one subprotocol to ask for.
+    pub protocol: String,
+    pub max_message_size: usize,
+}
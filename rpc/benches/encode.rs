@@ -0,0 +1,104 @@
+//! Encodes a burst of small messages the way the transports did before [`Encoder`] and with
+//! it, e.g. `cargo bench --package rpc --bench encode`. Starts with the sizes of `World`
+//! requests in either format.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rpc::framing::{self, FrameDecoder};
+use rpc::wire::{self, Encoder};
+use rpc::{Envelope, WorldRequest};
+
+const MESSAGES: usize = 10_000;
+const ROUNDS: u32 = 20;
+
+fn main() {
+    let requests = [
+        ("ping", WorldRequest::Ping {}),
+        (
+            "echo",
+            WorldRequest::Echo {
+                value: "a small payload".to_string(),
+            },
+        ),
+        (
+            "echo, non-ASCII",
+            WorldRequest::Echo {
+                value: "grüße, 世界 🌍".to_string(),
+            },
+        ),
+        (
+            "echo, 64 KiB",
+            WorldRequest::Echo {
+                value: "x".repeat(64 * 1024),
+            },
+        ),
+        ("delay", WorldRequest::Delay { millis: 30_000 }),
+    ];
+    let mut encoder = Encoder::default();
+    for (name, request) in requests {
+        let envelope = Envelope::Message(request);
+        let text = encoder.text(&envelope).unwrap().len();
+        let binary = encoder.binary_frame(&envelope, None).unwrap().len() - framing::HEADER_LEN;
+        println!(
+            "{:<24} {:>8} bytes JSON {:>8} bytes bincode",
+            name, text, binary
+        );
+    }
+
+    let envelope = Envelope::Message(("echo", "a small payload".to_string()));
+
+    report("text, fresh buffers", || {
+        for _ in 0..MESSAGES {
+            black_box(wire::encode_text(&envelope).unwrap());
+        }
+    });
+    report("text, reused buffer", || {
+        for _ in 0..MESSAGES {
+            black_box(encoder.text(&envelope).unwrap());
+        }
+    });
+
+    report("binary, fresh buffers", || {
+        for _ in 0..MESSAGES {
+            let message = wire::encode_binary(&envelope, None).unwrap();
+            black_box(framing::encode_frame(&message));
+        }
+    });
+    report("binary, reused buffer", || {
+        for _ in 0..MESSAGES {
+            black_box(encoder.binary_frame(&envelope, None).unwrap());
+        }
+    });
+
+    // All messages in one WebSocket frame, the worst case for shifting the decode buffer.
+    let mut burst = Vec::new();
+    for _ in 0..MESSAGES {
+        burst.extend(encoder.binary_frame(&envelope, None).unwrap());
+    }
+    report("decode, copied out", || {
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&burst);
+        while let Some(message) = decoder.next_frame().unwrap() {
+            black_box(wire::decode_binary::<(String, String)>(&message, usize::MAX).unwrap());
+        }
+    });
+    report("decode, borrowed", || {
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&burst);
+        while let Some(message) = decoder.next_frame_ref().unwrap() {
+            black_box(wire::decode_binary::<(String, String)>(message, usize::MAX).unwrap());
+        }
+    });
+}
+
+/// Runs `burst` a few times and prints how long one took on average.
+fn report(name: &str, mut burst: impl FnMut()) {
+    burst();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        burst();
+    }
+    let elapsed: Duration = start.elapsed() / ROUNDS;
+    println!("{:<24} {:>10.2?} per {} messages", name, elapsed, MESSAGES);
+}
@@ -0,0 +1,562 @@
+//! Hashes the `World` service definition in `src/lib.rs` for `rpc::schema`, and describes it
+//! as an OpenRPC document.
+//!
+//! Every method is hashed from its position and the JSON schemas of its arguments and result,
+//! including those of the types they name, directly or through other types. Doc comments and
+//! formatting keep the hashes, while renaming a method, changing an argument, moving it around
+//! or changing a type it uses, e.g. adding a field, changes them.
+//!
+//! The document lists the methods with their doc comments and the JSON schemas of their
+//! arguments and results, as serde writes them in text frames. Types other than strings,
+//! integers, `Bytes`, tuples, `Option`s, `Vec`s and `Result`s must be enums or structs in one
+//! of [`TYPES`], which may name each other. It comes out the same for the same source, and a
+//! warning tells when `openrpc.json` differs from it.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+const SOURCE: &str = "src/lib.rs";
+const SERVICE: &str = "pub trait World";
+/// Where the types the service returns are defined.
+const TYPES: &[&str] = &[
+    "src/error.rs",
+    "src/items.rs",
+    "src/session.rs",
+    "src/tasks.rs",
+    "src/upload.rs",
+    "src/version.rs",
+];
+/// The document as last committed.
+const DOCUMENT: &str = "openrpc.json";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SOURCE);
+    for types in TYPES {
+        println!("cargo:rerun-if-changed={}", types);
+    }
+    println!("cargo:rerun-if-changed={}", DOCUMENT);
+    let source = fs::read_to_string(SOURCE).expect("can't read the service definition");
+    let types = TYPES
+        .iter()
+        .map(|types| fs::read_to_string(types).expect("can't read the service's types"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let methods = documented(&source);
+    assert!(!methods.is_empty(), "no methods found in `{}`", SERVICE);
+
+    let mut generated = String::from("pub const METHODS: &[Method] = &[\n");
+    let mut schema = Fnv::default();
+    for (index, method) in methods.iter().enumerate() {
+        let mut hash = Fnv::default();
+        hash.write(&(index as u64).to_le_bytes());
+        let mut written = String::new();
+        undocumented(&method_schema(method, &types)).write(&mut written, 0);
+        hash.write(written.as_bytes());
+        let hash = hash.finish();
+        schema.write(&hash.to_le_bytes());
+        writeln!(
+            generated,
+            "    Method {{ name: {:?}, hash: {:#018x} }},",
+            method.name, hash
+        )
+        .unwrap();
+    }
+    generated.push_str("];\n");
+    writeln!(
+        generated,
+        "pub const SCHEMA_HASH: u64 = {:#018x};",
+        schema.finish()
+    )
+    .unwrap();
+
+    let out = env::var("OUT_DIR").unwrap();
+    let out = Path::new(&out);
+    fs::write(out.join("schema.rs"), generated).expect("can't write the schema hashes");
+
+    let document = openrpc(&source, &types, &env::var("CARGO_PKG_VERSION").unwrap());
+    fs::write(out.join("openrpc.json"), &document).expect("can't write the OpenRPC document");
+    if fs::read_to_string(DOCUMENT).ok().as_deref() != Some(document.as_str()) {
+        println!(
+            "cargo:warning={} is out of date, run `cargo run --package rpc --bin openrpc > rpc/{}`",
+            DOCUMENT, DOCUMENT
+        );
+    }
+}
+
+/// A method as documented: its name, doc comment, arguments and return type.
+struct Documented {
+    name: String,
+    doc: String,
+    params: Vec<(String, String)>,
+    returns: String,
+}
+
+/// The service's methods with their doc comments, in declaration order.
+fn documented(source: &str) -> Vec<Documented> {
+    let start = source
+        .find(SERVICE)
+        .expect("no `World` service in src/lib.rs");
+    let mut methods = Vec::new();
+    let (mut doc, mut signature) = (Vec::new(), String::new());
+    for line in source[start..].lines().skip(1) {
+        let line = line.trim();
+        if line == "}" {
+            break;
+        }
+        if let Some(comment) = line.strip_prefix("///") {
+            doc.push(comment.trim().to_string());
+            continue;
+        }
+        signature.push_str(line.split("//").next().unwrap_or_default());
+        signature.push(' ');
+        if let Some(declared) = signature.trim().strip_suffix(';') {
+            methods.push(method(declared, doc.join(" ")));
+            doc.clear();
+            signature.clear();
+        }
+    }
+    methods
+}
+
+/// `async fn name(arg: Type, ...) -> Type` taken apart.
+fn method(signature: &str, doc: String) -> Documented {
+    let signature = signature.split_whitespace().collect::<Vec<_>>().join(" ");
+    let rest = signature
+        .split("fn ")
+        .nth(1)
+        .unwrap_or_else(|| panic!("`{}` isn't a method", signature));
+    let (name, rest) = rest.split_once('(').unwrap();
+    let (args, returns) = rest
+        .rsplit_once(") ->")
+        .unwrap_or((rest.trim_end_matches(')'), "()"));
+    let params = split_top_level(args)
+        .into_iter()
+        .map(|arg| {
+            let (name, ty) = arg
+                .split_once(':')
+                .unwrap_or_else(|| panic!("`{}` has no type", arg));
+            (name.trim().to_string(), ty.trim().to_string())
+        })
+        .collect();
+    Documented {
+        name: name.trim().to_string(),
+        doc,
+        params,
+        returns: returns.trim().to_string(),
+    }
+}
+
+/// `list` split at the commas outside of any brackets, empty items dropped.
+fn split_top_level(list: &str) -> Vec<&str> {
+    let (mut items, mut depth, mut from) = (Vec::new(), 0, 0);
+    for (i, c) in list.char_indices() {
+        match c {
+            '<' | '(' => depth += 1,
+            '>' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&list[from..i]);
+                from = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&list[from..]);
+    items
+        .into_iter()
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// The OpenRPC document of the service in `source`, whose types are defined in `types`.
+fn openrpc(source: &str, types: &str, version: &str) -> String {
+    let mut referenced = Vec::new();
+    let methods = documented(source)
+        .iter()
+        .map(|method| method_json(method, &mut referenced))
+        .collect();
+    let schemas = type_schemas(types, &mut referenced);
+    let document = Json::object([
+        ("openrpc", Json::string("1.2.6")),
+        (
+            "info",
+            Json::object([
+                ("title", Json::string("World")),
+                ("version", Json::string(version)),
+            ]),
+        ),
+        ("methods", Json::Array(methods)),
+        (
+            "components",
+            Json::object([("schemas", Json::Object(schemas))]),
+        ),
+    ]);
+    let mut out = String::new();
+    document.write(&mut out, 0);
+    out.push('\n');
+    out
+}
+
+/// `method` as the OpenRPC document lists it, adding the enums and structs it names to
+/// `referenced`.
+fn method_json(method: &Documented, referenced: &mut Vec<String>) -> Json {
+    let params = method
+        .params
+        .iter()
+        .map(|(name, ty)| {
+            Json::object([
+                ("name", Json::string(name)),
+                ("required", Json::Bool(true)),
+                ("schema", schema(ty, referenced)),
+            ])
+        })
+        .collect();
+    let mut fields = vec![("name", Json::string(&method.name))];
+    if !method.doc.is_empty() {
+        fields.push(("description", Json::string(&method.doc)));
+    }
+    fields.push(("params", Json::Array(params)));
+    fields.push((
+        "result",
+        Json::object([
+            ("name", Json::string(&method.name)),
+            ("schema", schema(&method.returns, referenced)),
+        ]),
+    ));
+    Json::object(fields)
+}
+
+/// The schemas of the types in `referenced`, in that order. Describing a type may name more
+/// of them, which are added to `referenced` and described after it.
+fn type_schemas(types: &str, referenced: &mut Vec<String>) -> Vec<(String, Json)> {
+    let mut schemas = Vec::new();
+    while schemas.len() < referenced.len() {
+        let name = referenced[schemas.len()].clone();
+        let described = type_schema(types, &name, referenced);
+        schemas.push((name, described));
+    }
+    schemas
+}
+
+/// `method` with the schemas of every type it names, directly or not: what its hash covers.
+fn method_schema(method: &Documented, types: &str) -> Json {
+    let mut referenced = Vec::new();
+    let method = method_json(method, &mut referenced);
+    let schemas = type_schemas(types, &mut referenced);
+    Json::object([("method", method), ("schemas", Json::Object(schemas))])
+}
+
+/// `json` without the descriptions doc comments gave it. Properties that happen to be called
+/// `description` are schemas, not strings, and stay.
+fn undocumented(json: &Json) -> Json {
+    match json {
+        Json::Array(items) => Json::Array(items.iter().map(undocumented).collect()),
+        Json::Object(fields) => Json::Object(
+            fields
+                .iter()
+                .filter(|(key, value)| !(key == "description" && matches!(value, Json::String(_))))
+                .map(|(key, value)| (key.clone(), undocumented(value)))
+                .collect(),
+        ),
+        json => json.clone(),
+    }
+}
+
+/// The JSON schema of the Rust type `ty`, adding the enums and structs it names to `referenced`.
+fn schema(ty: &str, referenced: &mut Vec<String>) -> Json {
+    let ty = ty.trim();
+    if let Some(inner) = ty.strip_prefix("Vec<").and_then(|ty| ty.strip_suffix('>')) {
+        return Json::object([
+            ("type", Json::string("array")),
+            ("items", schema(inner, referenced)),
+        ]);
+    }
+    if let Some(inner) = ty
+        .strip_prefix("Option<")
+        .and_then(|ty| ty.strip_suffix('>'))
+    {
+        // serde writes `None` as `null` and `Some` as the value itself.
+        return Json::object([(
+            "oneOf",
+            Json::Array(vec![
+                schema(inner, referenced),
+                Json::object([("type", Json::string("null"))]),
+            ]),
+        )]);
+    }
+    if let Some(inner) = ty
+        .strip_prefix("Result<")
+        .and_then(|ty| ty.strip_suffix('>'))
+    {
+        let (ok, err) = match split_top_level(inner)[..] {
+            [ok, err] => (ok, err),
+            _ => panic!("`{}` isn't a `Result<T, E>`", ty),
+        };
+        // serde tags the variant like any other enum's.
+        return Json::object([(
+            "oneOf",
+            Json::Array(vec![
+                variant("Ok", Some(schema(ok, referenced))),
+                variant("Err", Some(schema(err, referenced))),
+            ]),
+        )]);
+    }
+    if let Some(inner) = ty.strip_prefix('(').and_then(|ty| ty.strip_suffix(')')) {
+        let items: Vec<Json> = split_top_level(inner)
+            .into_iter()
+            .map(|item| schema(item, referenced))
+            .collect();
+        if items.is_empty() {
+            return Json::object([("type", Json::string("null"))]);
+        }
+        let len = Json::Number(items.len() as u64);
+        return Json::object([
+            ("type", Json::string("array")),
+            ("prefixItems", Json::Array(items)),
+            ("minItems", len.clone()),
+            ("maxItems", len),
+        ]);
+    }
+    match ty {
+        "String" | "&str" => Json::object([("type", Json::string("string"))]),
+        "bool" => Json::object([("type", Json::string("boolean"))]),
+        "u8" | "u16" | "u32" | "u64" | "usize" => Json::object([
+            ("type", Json::string("integer")),
+            ("minimum", Json::Number(0)),
+        ]),
+        "i8" | "i16" | "i32" | "i64" | "isize" => Json::object([("type", Json::string("integer"))]),
+        // `rpc::Bytes`, as base64 in text frames.
+        "Bytes" => Json::object([
+            ("type", Json::string("string")),
+            ("contentEncoding", Json::string("base64")),
+        ]),
+        name => {
+            if !referenced.iter().any(|known| known == name) {
+                referenced.push(name.to_string());
+            }
+            Json::object([(
+                "$ref",
+                Json::String(format!("#/components/schemas/{}", name)),
+            )])
+        }
+    }
+}
+
+/// A variant of a serde enum: its name for a unit variant, an object with it as the only key
+/// otherwise.
+fn variant(name: &str, value: Option<Json>) -> Json {
+    match value {
+        None => Json::object([("const", Json::string(name))]),
+        Some(value) => Json::object([
+            ("type", Json::string("object")),
+            ("properties", Json::object([(name, value)])),
+            ("required", Json::Array(vec![Json::string(name)])),
+            ("additionalProperties", Json::Bool(false)),
+        ]),
+    }
+}
+
+/// The schema of the enum or struct `name` in `types`.
+fn type_schema(types: &str, name: &str, referenced: &mut Vec<String>) -> Json {
+    if types.contains(&format!("pub enum {} {{", name)) {
+        return enum_schema(types, name, referenced);
+    }
+    if types.contains(&format!("pub struct {} {{", name)) {
+        return struct_schema(types, name, referenced);
+    }
+    panic!("no schema for `{}`, it isn't an enum or struct in {:?}", name, TYPES)
+}
+
+/// The lines of the doc comment above the declaration starting at `start`.
+fn doc_above(types: &str, start: usize) -> Vec<&str> {
+    let mut doc = types[..start]
+        .lines()
+        .rev()
+        .skip(1)
+        .map(str::trim)
+        .skip_while(|line| line.starts_with("#["))
+        .take_while(|line| line.starts_with("///"))
+        .map(|line| line.trim_start_matches('/').trim())
+        .collect::<Vec<_>>();
+    doc.reverse();
+    doc
+}
+
+/// The schema of `pub enum name` in `types`, whose variants are units or hold one type.
+fn enum_schema(types: &str, name: &str, referenced: &mut Vec<String>) -> Json {
+    let declaration = format!("pub enum {} {{", name);
+    let start = types.find(&declaration).unwrap();
+    let doc = doc_above(types, start);
+    let mut variants = Vec::new();
+    for line in types[start + declaration.len()..].lines() {
+        let line = line.trim();
+        if line == "}" {
+            break;
+        }
+        // Attributes like `#[default]` aren't variants.
+        if line.is_empty() || line.starts_with("//") || line.starts_with("#[") {
+            continue;
+        }
+        let line = line.trim_end_matches(',');
+        match line.split_once('(') {
+            Some((variant_name, held)) => {
+                let held = schema(held.trim_end_matches(')'), referenced);
+                variants.push(variant(variant_name, Some(held)));
+            }
+            None => variants.push(variant(line, None)),
+        }
+    }
+    let mut fields = Vec::new();
+    if !doc.is_empty() {
+        fields.push(("description", Json::String(doc.join(" "))));
+    }
+    fields.push(("oneOf", Json::Array(variants)));
+    Json::object(fields)
+}
+
+/// The schema of `pub struct name` in `types`, whose fields are all `pub`. Structs with
+/// `#[serde(default)]` may miss fields and have more.
+fn struct_schema(types: &str, name: &str, referenced: &mut Vec<String>) -> Json {
+    let declaration = format!("pub struct {} {{", name);
+    let start = types.find(&declaration).unwrap();
+    let doc = doc_above(types, start);
+    let open = types[..start]
+        .lines()
+        .rev()
+        .map(str::trim)
+        .take_while(|line| line.starts_with("#["))
+        .any(|line| line == "#[serde(default)]");
+    let (mut properties, mut required) = (Vec::new(), Vec::new());
+    let mut field_doc = Vec::new();
+    for line in types[start + declaration.len()..].lines() {
+        let line = line.trim();
+        if line == "}" {
+            break;
+        }
+        if let Some(doc) = line.strip_prefix("///") {
+            field_doc.push(doc.trim());
+            continue;
+        }
+        let field = match line.strip_prefix("pub ").and_then(|line| line.split_once(':')) {
+            Some(field) => field,
+            None => continue,
+        };
+        let (field, ty) = (field.0.trim(), field.1.trim().trim_end_matches(','));
+        let mut property = match schema(ty, referenced) {
+            Json::Object(property) => property,
+            _ => unreachable!("schemas are objects"),
+        };
+        if !field_doc.is_empty() {
+            property.insert(0, ("description".into(), Json::String(field_doc.join(" "))));
+            field_doc.clear();
+        }
+        properties.push((field.to_string(), Json::Object(property)));
+        // serde writes every field, `None`s as `null`.
+        if !open {
+            required.push(Json::string(field));
+        }
+    }
+    let mut fields = Vec::new();
+    if !doc.is_empty() {
+        fields.push(("description", Json::String(doc.join(" "))));
+    }
+    fields.push(("type", Json::string("object")));
+    fields.push(("properties", Json::Object(properties)));
+    fields.push(("required", Json::Array(required)));
+    fields.push(("additionalProperties", Json::Bool(open)));
+    Json::object(fields)
+}
+
+/// Just enough JSON to write the document, keys in the order given.
+#[derive(Clone)]
+enum Json {
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn string(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+
+    fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Writes the value indented by `indent` levels of two spaces.
+    fn write(&self, out: &mut String, indent: usize) {
+        match self {
+            Json::Bool(value) => write!(out, "{}", value).unwrap(),
+            Json::Number(value) => write!(out, "{}", value).unwrap(),
+            Json::String(value) => write_string(out, value),
+            Json::Array(items) if items.is_empty() => out.push_str("[]"),
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(if i == 0 { "\n" } else { ",\n" });
+                    out.push_str(&"  ".repeat(indent + 1));
+                    item.write(out, indent + 1);
+                }
+                write!(out, "\n{}]", "  ".repeat(indent)).unwrap();
+            }
+            Json::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Json::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(if i == 0 { "\n" } else { ",\n" });
+                    out.push_str(&"  ".repeat(indent + 1));
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, indent + 1);
+                }
+                write!(out, "\n{}}}", "  ".repeat(indent)).unwrap();
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// 64 bit FNV-1a, stable across Rust versions unlike `DefaultHasher`.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,29 @@
+//! A second service on the connections `World` is served on, see [`Admin`] and
+//! [`crate::mux`].
+
+use async_trait::async_trait;
+use tarpc::service;
+
+use crate::{Idempotent, WorldError};
+
+#[service]
+#[async_trait]
+pub trait Admin {
+    /// How many connections the server has open. Only admins may ask, others are answered
+    /// [`WorldError::PermissionDenied`].
+    async fn connections() -> Result<u64, WorldError>;
+    /// Shows `text` on every connected page, like the server's `announce` command. Answers how
+    /// many peers it reached. Only admins may, others are answered
+    /// [`WorldError::PermissionDenied`].
+    async fn announce(text: String) -> Result<u64, WorldError>;
+}
+
+impl Idempotent for AdminRequest {
+    fn idempotent(&self) -> bool {
+        match self {
+            AdminRequest::Connections { .. } => true,
+            // Every page would show the announcement again.
+            AdminRequest::Announce { .. } => false,
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Binary WebSocket messages carried in text frames, for networks that mangle binary frames.
+//!
+//! A client asks for it by appending [`SUFFIX`] to the subprotocol of a binary codec, e.g.
+//! `tarpc.v1.bincode+base64`. A server accepting that subprotocol sends and expects every
+//! binary message, length prefixes, compression headers and batches included, as the standard
+//! padded base64 of its bytes in a text frame. Nothing else changes, so it works with any
+//! codec writing binary frames. It costs a third more bytes on the wire and is off unless the
+//! client asks for it.
+
+use std::fmt;
+
+/// Appended to a codec's subprotocol to ask for base64 text frames.
+pub const SUFFIX: &str = "+base64";
+
+/// `protocol` asking for base64 text frames.
+pub fn protocol(protocol: &str) -> String {
+    format!("{}{}", protocol, SUFFIX)
+}
+
+/// The codec's subprotocol if `protocol` asks for base64 text frames.
+pub fn strip(protocol: &str) -> Option<&str> {
+    protocol.strip_suffix(SUFFIX)
+}
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// `bytes` as padded base64.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |n, (i, &byte)| n | (u32::from(byte) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Base64Error {
+    /// The length isn't a multiple of four.
+    Length(usize),
+    /// A character outside the alphabet, or padding before the end, at this offset.
+    InvalidByte(usize),
+}
+
+impl fmt::Display for Base64Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Base64Error::Length(len) => write!(f, "base64 text of {} bytes", len),
+            Base64Error::InvalidByte(at) => write!(f, "invalid base64 at offset {}", at),
+        }
+    }
+}
+
+impl std::error::Error for Base64Error {}
+
+/// The bytes of padded base64 `text`.
+pub fn decode(text: &str) -> Result<Vec<u8>, Base64Error> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return Err(Base64Error::Length(text.len()));
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    for (index, chunk) in text.chunks(4).enumerate() {
+        let last = index == text.len() / 4 - 1;
+        let padding = match chunk {
+            [_, _, b'=', b'='] if last => 2,
+            [_, _, _, b'='] if last => 1,
+            _ => 0,
+        };
+        let mut n = 0u32;
+        for (i, &c) in chunk[..4 - padding].iter().enumerate() {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return Err(Base64Error::InvalidByte(index * 4 + i)),
+            };
+            n |= u32::from(value) << (18 - 6 * i);
+        }
+        let decoded = n.to_be_bytes();
+        bytes.extend_from_slice(&decoded[1..4 - padding]);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (bytes, text) in vectors {
+            assert_eq!(encode(bytes.as_bytes()), text);
+            assert_eq!(decode(text).unwrap(), bytes.as_bytes());
+        }
+    }
+
+    #[test]
+    fn every_byte_value_round_trips() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        // Every offset in a chunk of three, and every amount of padding.
+        for start in 0..3 {
+            for end in 253..=256 {
+                let bytes = &bytes[start..end];
+                assert_eq!(decode(&encode(bytes)).unwrap(), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn every_byte_value_alone_round_trips() {
+        for byte in 0..=255u8 {
+            let text = encode(&[byte]);
+            assert_eq!(text.len(), 4);
+            assert!(text.ends_with("=="));
+            assert_eq!(decode(&text).unwrap(), [byte]);
+        }
+    }
+
+    #[test]
+    fn encodes_with_the_whole_alphabet() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let text = encode(&bytes);
+        for c in ALPHABET {
+            assert!(text.as_bytes().contains(c), "{} never used", *c as char);
+        }
+    }
+
+    #[test]
+    fn wrong_length_is_rejected() {
+        assert_eq!(decode("Zm9"), Err(Base64Error::Length(3)));
+        assert_eq!(decode("Zm9vY"), Err(Base64Error::Length(5)));
+    }
+
+    #[test]
+    fn invalid_characters_are_rejected() {
+        assert_eq!(decode("Zm9v!mFy"), Err(Base64Error::InvalidByte(4)));
+        assert_eq!(decode("Zm-v"), Err(Base64Error::InvalidByte(2)));
+        // Padding only ends the text.
+        assert_eq!(decode("Zg==Zm9v"), Err(Base64Error::InvalidByte(2)));
+        assert_eq!(decode("Z==="), Err(Base64Error::InvalidByte(1)));
+    }
+
+    #[test]
+    fn subprotocols() {
+        assert_eq!(protocol("tarpc.v1.bincode"), "tarpc.v1.bincode+base64");
+        assert_eq!(strip("tarpc.v1.bincode+base64"), Some("tarpc.v1.bincode"));
+        assert_eq!(strip("tarpc.v1.bincode"), None);
+    }
+}
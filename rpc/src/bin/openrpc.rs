@@ -0,0 +1,6 @@
+//! Prints [`rpc::schema::OPENRPC`], e.g.
+//! `cargo run --package rpc --bin openrpc > rpc/openrpc.json` after changing the service.
+
+fn main() {
+    print!("{}", rpc::schema::OPENRPC);
+}
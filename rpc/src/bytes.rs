@@ -0,0 +1,213 @@
+//! Arbitrary bytes as arguments and results, see [`Bytes`].
+//!
+//! serde writes a `Vec<u8>` as a sequence of numbers: JSON spells out `[104,105]`, up to four
+//! characters a byte, and MessagePack and CBOR tag every byte on its own. [`Bytes`] is written
+//! as bytes instead, as they are by the binary codecs and as the padded base64 of
+//! [`crate::base64`] by the text ones, a third more than the bytes themselves. It reads
+//! sequences of numbers too.
+
+use std::fmt;
+use std::ops::Deref;
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::base64;
+
+/// Bytes `echo_bytes` takes at most, see `PayloadPolicy` on the server.
+pub const MAX_ECHO_BYTES: usize = 8 * 1024 * 1024;
+
+/// Bytes serialized as such, not as a sequence of numbers.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Bytes(pub Vec<u8>);
+
+impl Bytes {
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Only the length, buffers can be megabytes.
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bytes({} bytes)", self.0.len())
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Text formats describe themselves, so a sequence of numbers gets through too.
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(BytesVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bytes or a base64 string")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<Bytes, E> {
+        base64::decode(text).map(Bytes).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Bytes, E> {
+        Ok(Bytes(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "bincode")]
+    use crate::codec::Bincode;
+    use crate::codec::Codec;
+    #[cfg(feature = "json")]
+    use crate::codec::Json;
+
+    fn every_value() -> Bytes {
+        Bytes((0..=255).collect())
+    }
+
+    /// A few megabytes that don't repeat in any short period.
+    fn large() -> Bytes {
+        let mut state = 1u32;
+        Bytes(
+            (0..3 * 1024 * 1024 + 1)
+                .map(|_| {
+                    state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    (state >> 24) as u8
+                })
+                .collect(),
+        )
+    }
+
+    fn round_trip(codec: impl Codec, bytes: &Bytes) -> usize {
+        let mut message = Vec::new();
+        codec.encode(bytes, &mut message).unwrap();
+        let decoded: Bytes = match codec.text() {
+            true => codec.decode_text(std::str::from_utf8(&message).unwrap()),
+            false => codec.decode(&message),
+        }
+        .unwrap();
+        assert!(decoded == *bytes, "{:?} came out as {:?}", bytes, decoded);
+        message.len()
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_writes_base64() {
+        let mut message = Vec::new();
+        Json.encode(&Bytes(b"hi".to_vec()), &mut message).unwrap();
+        assert_eq!(message, b"\"aGk=\"");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_reads_sequences_of_numbers() {
+        let decoded: Bytes = Json.decode_text("[104,105]").unwrap();
+        assert_eq!(&*decoded, b"hi");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_rejects_invalid_base64() {
+        assert!(Json.decode_text::<Bytes>("\"aGk\"").is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trips_every_value_and_megabytes() {
+        round_trip(Json, &every_value());
+        let bytes = large();
+        let written = round_trip(Json, &bytes);
+        // Base64 and the quotes, not up to four characters a byte.
+        assert_eq!(written, bytes.len().div_ceil(3) * 4 + 2);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trips_every_value_and_megabytes() {
+        round_trip(Bincode, &every_value());
+        let bytes = large();
+        assert_eq!(round_trip(Bincode, &bytes), bytes.len() + 8);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips_every_value_and_megabytes() {
+        round_trip(crate::codec::MsgPack, &every_value());
+        let bytes = large();
+        assert_eq!(round_trip(crate::codec::MsgPack, &bytes), bytes.len() + 5);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trips_every_value_and_megabytes() {
+        round_trip(crate::codec::Cbor, &every_value());
+        let bytes = large();
+        assert_eq!(round_trip(crate::codec::Cbor, &bytes), bytes.len() + 5);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_round_trips_every_value_and_megabytes() {
+        round_trip(crate::codec::Postcard, &every_value());
+        let bytes = large();
+        assert!(round_trip(crate::codec::Postcard, &bytes) <= bytes.len() + 5);
+    }
+
+    #[test]
+    fn debug_shows_the_length() {
+        assert_eq!(format!("{:?}", large()), "Bytes(3145729 bytes)");
+    }
+}
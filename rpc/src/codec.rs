@@ -0,0 +1,900 @@
+//! Serialization formats for the [`Envelope`](crate::Envelope)s on the wire.
+//!
+//! A [`Codec`] turns envelopes into bytes and back, names itself with the subprotocol both ends
+//! agree on during the WebSocket handshake, and says whether its messages go in text or binary
+//! frames. Everything around that is the same for every codec: binary messages get the
+//! compression header of [`crate::compression`] and the length prefix of [`crate::framing`],
+//! see [`crate::wire`]. Client and server transports take the codec as a type parameter, so a
+//! new format only needs an implementation of this trait and a subprotocol of its own.
+//!
+//! Each codec here is behind the cargo feature of the same name, `json` and `bincode` by
+//! default. Builds that leave out `json` don't carry `serde_json`. [`FrameMode`] picks one of
+//! them by subprotocol and is what client and server use unless given another codec.
+//!
+//! A format of one's own implements [`Codec`] and reports its errors as [`WireError::codec`].
+//! The typed methods like [`Codec::encode_request`] are the messages as tarpc sends them, in
+//! their envelope, and are what round-trip tests of a codec go through.
+
+#[cfg(feature = "bincode")]
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tarpc::{ClientMessage, Response};
+
+use crate::protocol;
+use crate::wire::WireError;
+use crate::Envelope;
+
+pub trait Codec: Clone {
+    /// Subprotocol announcing this codec, see [`crate::protocol`].
+    fn protocol(&self) -> &'static str;
+
+    /// Whether messages go in text frames, which requires [`encode`](Self::encode) to write
+    /// UTF-8. Otherwise they go in binary frames.
+    fn text(&self) -> bool;
+
+    /// Appends the serialized `value` to `dst`.
+    fn encode<T: Serialize>(&self, value: &T, dst: &mut Vec<u8>) -> Result<(), WireError>;
+
+    /// Decodes a message, inflated if it came in a binary frame.
+    fn decode<T: DeserializeOwned>(&self, message: &[u8]) -> Result<T, WireError>;
+
+    /// Decodes a message that came in a text frame. Codecs that only write binary frames
+    /// don't expect any, by default they fail on it like on any other message they can't
+    /// decode.
+    fn decode_text<T: DeserializeOwned>(&self, text: &str) -> Result<T, WireError> {
+        self.decode(text.as_bytes())
+    }
+
+    /// Appends a request of the client, in its [`Envelope::Message`], to `dst`.
+    fn encode_request<Req: Serialize>(
+        &self,
+        request: &ClientMessage<Req>,
+        dst: &mut Vec<u8>,
+    ) -> Result<(), WireError> {
+        self.encode(&Envelope::Message(request), dst)
+    }
+
+    /// Decodes what the client sent, a request or any other envelope.
+    fn decode_request<Req: DeserializeOwned>(
+        &self,
+        message: &[u8],
+    ) -> Result<Envelope<ClientMessage<Req>>, WireError> {
+        self.decode(message)
+    }
+
+    /// Appends a response of the server, in its [`Envelope::Message`], to `dst`.
+    fn encode_response<Resp: Serialize>(
+        &self,
+        response: &Response<Resp>,
+        dst: &mut Vec<u8>,
+    ) -> Result<(), WireError> {
+        self.encode(&Envelope::Message(response), dst)
+    }
+
+    /// Decodes what the server sent, a response or any other envelope.
+    fn decode_response<Resp: DeserializeOwned>(
+        &self,
+        message: &[u8],
+    ) -> Result<Envelope<Response<Resp>>, WireError> {
+        self.decode(message)
+    }
+}
+
+/// The codec of each subprotocol of [`crate::protocol`], chosen by the client and answered in
+/// by the server. Writes [`Json`] in text mode and [`Bincode`] in binary mode, and reads
+/// whichever kind of frame arrives. The MessagePack, CBOR and postcard modes read and write
+/// binary frames in their format instead. Each mode needs the feature of its codec; the
+/// default is text, or the first binary format built in without `json`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameMode {
+    /// JSON in text frames. Easy to read in the browser's network tab.
+    #[cfg(feature = "json")]
+    Text,
+    /// Length prefixed bincode in binary frames, see [`crate::framing`]. Can be compressed,
+    /// see [`crate::compression`].
+    #[cfg(feature = "bincode")]
+    Binary,
+    /// MessagePack in binary frames like [`Binary`](Self::Binary), for tools outside Rust
+    /// that want to read the traffic.
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+    /// CBOR in binary frames like [`Binary`](Self::Binary).
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// postcard in binary frames like [`Binary`](Self::Binary), the most compact of them. Both
+    /// ends have to agree on every type, see [`Postcard`].
+    #[cfg(feature = "postcard")]
+    Postcard,
+}
+
+impl Default for FrameMode {
+    fn default() -> Self {
+        #[cfg(feature = "json")]
+        return FrameMode::Text;
+        #[cfg(all(feature = "bincode", not(feature = "json")))]
+        return FrameMode::Binary;
+        #[cfg(all(feature = "msgpack", not(any(feature = "json", feature = "bincode"))))]
+        return FrameMode::MsgPack;
+        #[cfg(all(
+            feature = "cbor",
+            not(any(feature = "json", feature = "bincode", feature = "msgpack"))
+        ))]
+        return FrameMode::Cbor;
+        #[cfg(all(
+            feature = "postcard",
+            not(any(
+                feature = "json",
+                feature = "bincode",
+                feature = "msgpack",
+                feature = "cbor"
+            ))
+        ))]
+        return FrameMode::Postcard;
+    }
+}
+
+impl FrameMode {
+    /// Mode announced by a subprotocol from [`crate::protocol`], the default for unknown
+    /// ones.
+    pub fn from_protocol(protocol: &str) -> Self {
+        match protocol {
+            #[cfg(feature = "json")]
+            protocol::JSON => FrameMode::Text,
+            #[cfg(feature = "bincode")]
+            protocol::BINCODE => FrameMode::Binary,
+            #[cfg(feature = "msgpack")]
+            protocol::MSGPACK => FrameMode::MsgPack,
+            #[cfg(feature = "cbor")]
+            protocol::CBOR => FrameMode::Cbor,
+            #[cfg(feature = "postcard")]
+            protocol::POSTCARD => FrameMode::Postcard,
+            _ => FrameMode::default(),
+        }
+    }
+}
+
+impl Codec for FrameMode {
+    fn protocol(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "json")]
+            FrameMode::Text => Json.protocol(),
+            #[cfg(feature = "bincode")]
+            FrameMode::Binary => Bincode.protocol(),
+            #[cfg(feature = "msgpack")]
+            FrameMode::MsgPack => MsgPack.protocol(),
+            #[cfg(feature = "cbor")]
+            FrameMode::Cbor => Cbor.protocol(),
+            #[cfg(feature = "postcard")]
+            FrameMode::Postcard => Postcard.protocol(),
+        }
+    }
+
+    fn text(&self) -> bool {
+        #[cfg(feature = "json")]
+        return *self == FrameMode::Text;
+        #[cfg(not(feature = "json"))]
+        false
+    }
+
+    fn encode<T: Serialize>(&self, value: &T, dst: &mut Vec<u8>) -> Result<(), WireError> {
+        match self {
+            #[cfg(feature = "json")]
+            FrameMode::Text => Json.encode(value, dst),
+            #[cfg(feature = "bincode")]
+            FrameMode::Binary => Bincode.encode(value, dst),
+            #[cfg(feature = "msgpack")]
+            FrameMode::MsgPack => MsgPack.encode(value, dst),
+            #[cfg(feature = "cbor")]
+            FrameMode::Cbor => Cbor.encode(value, dst),
+            #[cfg(feature = "postcard")]
+            FrameMode::Postcard => Postcard.encode(value, dst),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, message: &[u8]) -> Result<T, WireError> {
+        match self {
+            // Binary frames in text mode are bincode, when there is bincode.
+            #[cfg(all(feature = "json", feature = "bincode"))]
+            FrameMode::Text => Bincode.decode(message),
+            #[cfg(all(feature = "json", not(feature = "bincode")))]
+            FrameMode::Text => Json.decode(message),
+            #[cfg(feature = "bincode")]
+            FrameMode::Binary => Bincode.decode(message),
+            #[cfg(feature = "msgpack")]
+            FrameMode::MsgPack => MsgPack.decode(message),
+            #[cfg(feature = "cbor")]
+            FrameMode::Cbor => Cbor.decode(message),
+            #[cfg(feature = "postcard")]
+            FrameMode::Postcard => Postcard.decode(message),
+        }
+    }
+
+    #[cfg(feature = "json")]
+    fn decode_text<T: DeserializeOwned>(&self, text: &str) -> Result<T, WireError> {
+        Json.decode_text(text)
+    }
+}
+
+/// JSON in text frames, easy to read in the browser's network tab.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn protocol(&self) -> &'static str {
+        protocol::JSON
+    }
+
+    fn text(&self) -> bool {
+        true
+    }
+
+    fn encode<T: Serialize>(&self, value: &T, dst: &mut Vec<u8>) -> Result<(), WireError> {
+        serde_json::to_writer(dst, value).map_err(WireError::Json)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, message: &[u8]) -> Result<T, WireError> {
+        serde_json::from_slice(message).map_err(WireError::Json)
+    }
+}
+
+/// Largest message bincode deserializes, above the client's default `max_message_size` so it
+/// only stops messages claiming more than they contain, e.g. a corrupt string length.
+#[cfg(feature = "bincode")]
+pub const BINCODE_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// The bincode configuration of both ends: fixed size little endian integers like
+/// `bincode::serialize`, at most [`BINCODE_LIMIT`] bytes and no trailing bytes. Anything
+/// reading or writing [`Bincode`] messages outside the transports has to use it too.
+#[cfg(feature = "bincode")]
+pub fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .with_limit(BINCODE_LIMIT)
+        .reject_trailing_bytes()
+}
+
+/// bincode in binary frames, configured by [`bincode_options`] on both ends.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn protocol(&self) -> &'static str {
+        protocol::BINCODE
+    }
+
+    fn text(&self) -> bool {
+        false
+    }
+
+    fn encode<T: Serialize>(&self, value: &T, dst: &mut Vec<u8>) -> Result<(), WireError> {
+        bincode_options()
+            .serialize_into(dst, value)
+            .map_err(WireError::Bincode)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, message: &[u8]) -> Result<T, WireError> {
+        bincode_options()
+            .deserialize(message)
+            .map_err(WireError::Bincode)
+    }
+}
+
+/// MessagePack in binary frames, behind the `msgpack` feature. Structs are written as maps
+/// with their field names, so other MessagePack tools can make sense of the traffic, and are
+/// read from maps and arrays alike.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MsgPack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPack {
+    fn protocol(&self) -> &'static str {
+        protocol::MSGPACK
+    }
+
+    fn text(&self) -> bool {
+        false
+    }
+
+    fn encode<T: Serialize>(&self, value: &T, dst: &mut Vec<u8>) -> Result<(), WireError> {
+        rmp_serde::encode::write_named(dst, value).map_err(WireError::codec)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, message: &[u8]) -> Result<T, WireError> {
+        rmp_serde::from_slice(message).map_err(WireError::codec)
+    }
+}
+
+/// CBOR in binary frames, behind the `cbor` feature. Enums, tarpc's messages among them, are
+/// written as maps from the variant name to its contents, so no CBOR tags are needed.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn protocol(&self) -> &'static str {
+        protocol::CBOR
+    }
+
+    fn text(&self) -> bool {
+        false
+    }
+
+    fn encode<T: Serialize>(&self, value: &T, dst: &mut Vec<u8>) -> Result<(), WireError> {
+        ciborium::ser::into_writer(value, dst).map_err(WireError::codec)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, message: &[u8]) -> Result<T, WireError> {
+        ciborium::de::from_reader(message).map_err(WireError::codec)
+    }
+}
+
+/// postcard in binary frames, behind the `postcard` feature.
+///
+/// postcard isn't self-describing: a message is decoded by following the layout of the type it
+/// is decoded as, so both ends have to be built from the same `World` definition. Changes that
+/// shift the layout, e.g. a field added at the end, fail with an error as messages end early or
+/// bytes are left over. Changes that keep it, e.g. two fields of the same type swapped, decode
+/// without one. The version in [`protocol::POSTCARD`] goes up with the former kind.
+#[cfg(feature = "postcard")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn protocol(&self) -> &'static str {
+        protocol::POSTCARD
+    }
+
+    fn text(&self) -> bool {
+        false
+    }
+
+    fn encode<T: Serialize>(&self, value: &T, dst: &mut Vec<u8>) -> Result<(), WireError> {
+        let bytes = postcard::to_stdvec(value).map_err(WireError::codec)?;
+        dst.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    /// Fails on bytes left over, which a message of a different layout would leave.
+    fn decode<T: DeserializeOwned>(&self, message: &[u8]) -> Result<T, WireError> {
+        match postcard::take_from_bytes(message) {
+            Ok((value, [])) => Ok(value),
+            Ok((_, rest)) => Err(WireError::codec(format!(
+                "{} bytes left over after the message",
+                rest.len()
+            ))),
+            Err(e) => Err(WireError::codec(e)),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use serde::Serialize;
+    use tarpc::{context, ClientMessage, Request, Response, ServerError};
+
+    use super::*;
+    use crate::mux::rebuild;
+    #[cfg(feature = "msgpack")]
+    use crate::VersionInfo;
+    use crate::{WorldError, WorldRequest, WorldResponse};
+
+    /// The fields of a `ClientMessage::Request`, see [`rebuild`].
+    #[derive(Serialize)]
+    enum MessageFields {
+        Request(RequestFields),
+    }
+
+    #[derive(Serialize)]
+    struct RequestFields {
+        context: context::Context,
+        id: u64,
+        message: WorldRequest,
+    }
+
+    #[derive(Serialize)]
+    struct ResponseFields {
+        request_id: u64,
+        message: Result<WorldResponse, ServerError>,
+    }
+
+    fn request(id: u64, message: WorldRequest) -> ClientMessage<WorldRequest> {
+        rebuild(MessageFields::Request(RequestFields {
+            context: context::current(),
+            id,
+            message,
+        }))
+        .unwrap()
+    }
+
+    fn response(request_id: u64, message: WorldResponse) -> Response<WorldResponse> {
+        rebuild(ResponseFields {
+            request_id,
+            message: Ok(message),
+        })
+        .unwrap()
+    }
+
+    /// Calls of `ping`, `echo` and `delay`, with large and non-ASCII strings and embedded
+    /// NULs.
+    fn requests() -> Vec<(&'static str, WorldRequest)> {
+        vec![
+            ("ping", WorldRequest::Ping {}),
+            (
+                "echo",
+                WorldRequest::Echo {
+                    value: "a small payload".to_string(),
+                },
+            ),
+            (
+                "echo, non-ASCII",
+                WorldRequest::Echo {
+                    value: "grüße, 世界 🌍".to_string(),
+                },
+            ),
+            (
+                "echo, NULs",
+                WorldRequest::Echo {
+                    value: "\0leading, in\0the middle and trailing\0".to_string(),
+                },
+            ),
+            (
+                "echo, 1 MiB",
+                WorldRequest::Echo {
+                    value: "x".repeat(1 << 20),
+                },
+            ),
+            ("delay", WorldRequest::Delay { millis: 30_000 }),
+        ]
+    }
+
+    /// Answers to them, failed ones among them.
+    fn responses() -> Vec<WorldResponse> {
+        vec![
+            WorldResponse::Ping(Ok("pong".to_string())),
+            WorldResponse::Echo(Ok("grüße, 世界 🌍".to_string())),
+            WorldResponse::Echo(Ok("x".repeat(1 << 20))),
+            WorldResponse::Echo(Ok("\0nul\0\0".to_string())),
+            WorldResponse::Delay(Ok("waited".to_string())),
+            WorldResponse::Delay(Err(WorldError::Timeout)),
+            WorldResponse::Echo(Err(WorldError::InvalidArgument("too long".to_string()))),
+        ]
+    }
+
+    /// Whether `a` and `b` serialize alike, tarpc's types don't implement `PartialEq`.
+    fn same(a: &impl Serialize, b: &impl Serialize) -> bool {
+        serde_json::to_value(a).unwrap() == serde_json::to_value(b).unwrap()
+    }
+
+    /// Sends every call and answer through `codec` and checks what comes out, as a transport
+    /// would for a message of a frame of `codec`'s kind.
+    fn round_trips(codec: impl Codec) {
+        let decode_request = |message: &[u8]| match codec.text() {
+            true => codec.decode_text(std::str::from_utf8(message).unwrap()),
+            false => codec.decode_request::<WorldRequest>(message),
+        };
+        for (id, ((_, sent), (_, expected))) in requests().into_iter().zip(requests()).enumerate() {
+            let mut message = Vec::new();
+            codec
+                .encode_request(&request(id as u64, sent), &mut message)
+                .unwrap();
+            match decode_request(&message).unwrap() {
+                Envelope::Message(ClientMessage::Request(Request {
+                    id: received,
+                    message,
+                    ..
+                })) => {
+                    assert_eq!(received, id as u64);
+                    assert!(
+                        same(&message, &expected),
+                        "{:?} came out as {:?}",
+                        expected,
+                        message
+                    );
+                }
+                other => panic!("{:?} came out as {:?}", expected, other),
+            }
+        }
+        for (id, (sent, expected)) in responses().into_iter().zip(responses()).enumerate() {
+            let mut message = Vec::new();
+            codec
+                .encode_response(&response(id as u64, sent), &mut message)
+                .unwrap();
+            let decoded = match codec.text() {
+                true => codec.decode_text(std::str::from_utf8(&message).unwrap()),
+                false => codec.decode_response::<WorldResponse>(&message),
+            };
+            match decoded.unwrap() {
+                Envelope::Message(Response {
+                    request_id,
+                    message: Ok(message),
+                    ..
+                }) => {
+                    assert_eq!(request_id, id as u64);
+                    assert!(
+                        same(&message, &expected),
+                        "{:?} came out as {:?}",
+                        expected,
+                        message
+                    );
+                }
+                other => panic!("{:?} came out as {:?}", expected, other),
+            }
+        }
+        let envelopes = [
+            Envelope::Heartbeat,
+            Envelope::HeartbeatAck,
+            Envelope::Auth("token".to_string()),
+            Envelope::AuthAck,
+            Envelope::OneWay(7u8),
+        ];
+        for envelope in envelopes {
+            let mut message = Vec::new();
+            codec.encode(&envelope, &mut message).unwrap();
+            let decoded: Envelope<u8> = match codec.text() {
+                true => codec.decode_text(std::str::from_utf8(&message).unwrap()),
+                false => codec.decode(&message),
+            }
+            .unwrap();
+            assert_eq!(decoded, envelope);
+        }
+    }
+
+    /// What `codec` writes for `value`.
+    fn encoded(codec: impl Codec, value: &impl Serialize) -> Vec<u8> {
+        let mut message = Vec::new();
+        codec.encode(value, &mut message).unwrap();
+        message
+    }
+
+    /// Bytes `codec` encodes `ping`'s request into.
+    fn ping(codec: impl Codec) -> Vec<u8> {
+        let mut message = Vec::new();
+        codec
+            .encode_request(&request(1, WorldRequest::Ping {}), &mut message)
+            .unwrap();
+        message
+    }
+
+    #[test]
+    fn json_round_trips() {
+        round_trips(Json);
+    }
+
+    #[test]
+    fn json_writes_text() {
+        assert!(std::str::from_utf8(&ping(Json)).is_ok());
+    }
+
+    #[test]
+    fn json_rejects_garbage() {
+        let decoded = Json.decode_text::<Envelope<ClientMessage<WorldRequest>>>("{\"Message\"");
+        assert!(matches!(decoded, Err(WireError::Json(_))));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trips() {
+        round_trips(Bincode);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_is_smaller_than_json() {
+        for ((name, message), (_, again)) in requests().into_iter().zip(requests()) {
+            let (mut json, mut bincode) = (Vec::new(), Vec::new());
+            Json.encode_request(&request(1, message), &mut json).unwrap();
+            Bincode.encode_request(&request(1, again), &mut bincode).unwrap();
+            assert!(
+                bincode.len() < json.len(),
+                "{}: {} bytes of bincode, {} of JSON",
+                name,
+                bincode.len(),
+                json.len()
+            );
+        }
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_writes_fixed_size_integers() {
+        let mut message = Vec::new();
+        Bincode.encode(&1u64, &mut message).unwrap();
+        assert_eq!(message, 1u64.to_le_bytes());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_rejects_trailing_bytes() {
+        let mut message = ping(Bincode);
+        message.push(0);
+        let decoded = Bincode.decode_request::<WorldRequest>(&message);
+        assert!(matches!(decoded, Err(WireError::Bincode(_))));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_rejects_truncated_messages() {
+        let message = ping(Bincode);
+        for len in 0..message.len() {
+            let decoded = Bincode.decode_request::<WorldRequest>(&message[..len]);
+            assert!(matches!(decoded, Err(WireError::Bincode(_))), "at {}", len);
+        }
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_rejects_lengths_past_the_limit() {
+        // A string claiming more bytes than the limit, with none of them there.
+        let message = (BINCODE_LIMIT + 1).to_le_bytes();
+        assert!(matches!(
+            Bincode.decode::<String>(&message),
+            Err(WireError::Bincode(_))
+        ));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn frame_modes_write_their_codec() {
+        let envelope = Envelope::<u8>::Auth("token".to_string());
+        assert_eq!(
+            encoded(FrameMode::Text, &envelope),
+            encoded(Json, &envelope)
+        );
+        assert_eq!(
+            encoded(FrameMode::Binary, &envelope),
+            encoded(Bincode, &envelope)
+        );
+        round_trips(FrameMode::Text);
+        round_trips(FrameMode::Binary);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn frame_modes_read_text_frames_as_json() {
+        let text = String::from_utf8(ping(Json)).unwrap();
+        let decoded = FrameMode::Binary.decode_text::<Envelope<ClientMessage<WorldRequest>>>(&text);
+        assert!(matches!(decoded, Ok(Envelope::Message(_))));
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn frame_modes_by_protocol() {
+        assert_eq!(FrameMode::from_protocol(protocol::JSON), FrameMode::Text);
+        assert_eq!(
+            FrameMode::from_protocol(protocol::BINCODE),
+            FrameMode::Binary
+        );
+        assert_eq!(FrameMode::from_protocol("unknown"), FrameMode::Text);
+        assert_eq!(FrameMode::Binary.protocol(), protocol::BINCODE);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips() {
+        round_trips(MsgPack);
+    }
+
+    /// A MessagePack string of fewer than 32 bytes.
+    #[cfg(feature = "msgpack")]
+    fn fixstr(s: &str) -> Vec<u8> {
+        let mut bytes = vec![0xa0 | s.len() as u8];
+        bytes.extend_from_slice(s.as_bytes());
+        bytes
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_writes_field_names() {
+        let version = VersionInfo {
+            crate_version: "1.2.3".to_string(),
+            ..VersionInfo::default()
+        };
+        let message = encoded(MsgPack, &version);
+        // A map of four fields, the first of them by name.
+        assert_eq!(message[0], 0x84);
+        assert!(message[1..].starts_with(&fixstr("crate_version")));
+    }
+
+    /// Structs as another MessagePack producer writes them, maps with the fields in an order
+    /// of their own.
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_reads_maps_of_other_producers() {
+        let mut message = vec![0x84];
+        message.extend(fixstr("protocols"));
+        message.extend([0x92, 0x01, 0x02]);
+        message.extend(fixstr("git_hash"));
+        message.extend(fixstr("abc1234"));
+        message.extend(fixstr("built_at"));
+        message.extend([0xcd, 0x12, 0x34]);
+        message.extend(fixstr("crate_version"));
+        message.extend(fixstr("1.2.3"));
+        let version: VersionInfo = MsgPack.decode(&message).unwrap();
+        assert_eq!(
+            version,
+            VersionInfo {
+                crate_version: "1.2.3".to_string(),
+                git_hash: "abc1234".to_string(),
+                built_at: 0x1234,
+                protocols: vec![1, 2],
+            }
+        );
+
+        let mut message = vec![0x81];
+        message.extend(fixstr("Echo"));
+        message.push(0x81);
+        message.extend(fixstr("value"));
+        message.extend(fixstr("hi"));
+        match MsgPack.decode(&message).unwrap() {
+            WorldRequest::Echo { value } => assert_eq!(value, "hi"),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    /// rmp-serde's own compact encoding, structs as arrays, reads too.
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_reads_arrays() {
+        let version = VersionInfo {
+            crate_version: "1.2.3".to_string(),
+            git_hash: "abc1234".to_string(),
+            built_at: 7,
+            protocols: vec![1],
+        };
+        let message = rmp_serde::to_vec(&version).unwrap();
+        assert_eq!(message[0], 0x94);
+        assert_eq!(MsgPack.decode::<VersionInfo>(&message).unwrap(), version);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_rejects_truncated_messages() {
+        let message = ping(MsgPack);
+        for len in 0..message.len() {
+            let decoded = MsgPack.decode_request::<WorldRequest>(&message[..len]);
+            assert!(matches!(decoded, Err(WireError::Codec(_))), "at {}", len);
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trips() {
+        round_trips(Cbor);
+    }
+
+    /// Whether `value` holds no CBOR tags.
+    #[cfg(feature = "cbor")]
+    fn untagged(value: &ciborium::value::Value) -> bool {
+        use ciborium::value::Value;
+        match value {
+            Value::Tag(..) => false,
+            Value::Array(items) => items.iter().all(untagged),
+            Value::Map(entries) => entries.iter().all(|(k, v)| untagged(k) && untagged(v)),
+            _ => true,
+        }
+    }
+
+    /// Enums are maps from the variant name, no tags needed.
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_writes_enums_as_maps() {
+        let message = ping(Cbor);
+        assert_eq!(message[0], 0xa1);
+        assert_eq!(&message[1..9], b"\x67Message");
+        let value: ciborium::value::Value = ciborium::de::from_reader(&message[..]).unwrap();
+        assert!(untagged(&value));
+        assert_eq!(encoded(Cbor, &Envelope::<u8>::Heartbeat), b"\x69Heartbeat");
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_rejects_truncated_messages() {
+        let message = ping(Cbor);
+        for len in 0..message.len() {
+            let decoded = Cbor.decode_request::<WorldRequest>(&message[..len]);
+            assert!(matches!(decoded, Err(WireError::Codec(_))), "at {}", len);
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_rejects_wrong_major_types() {
+        // A text string, an unsigned integer and an array where an envelope's map belongs.
+        for message in [&b"\x62hi"[..], &[0x18, 0x2a], &[0x82, 0x01, 0x02]] {
+            let decoded = Cbor.decode_request::<WorldRequest>(message);
+            assert!(matches!(decoded, Err(WireError::Codec(_))), "{:?}", message);
+        }
+        // A byte string where `echo`'s text belongs.
+        let mut message = vec![0xa1, 0x64];
+        message.extend_from_slice(b"Echo");
+        message.extend_from_slice(&[0xa1, 0x65]);
+        message.extend_from_slice(b"value");
+        message.extend_from_slice(&[0x42, 0x68, 0x69]);
+        let decoded = Cbor.decode::<WorldRequest>(&message);
+        assert!(matches!(decoded, Err(WireError::Codec(_))));
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_round_trips() {
+        round_trips(Postcard);
+    }
+
+    /// Two builds of a struct, the later one with a field more.
+    #[cfg(feature = "postcard")]
+    mod layouts {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct Before {
+            pub id: u64,
+            pub name: String,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct After {
+            pub id: u64,
+            pub name: String,
+            pub tags: Vec<String>,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct Swapped {
+            pub name: String,
+            pub id: u64,
+        }
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_rejects_a_field_more() {
+        let before = layouts::Before {
+            id: 7,
+            name: "seven".to_string(),
+        };
+        let decoded = Postcard.decode::<layouts::After>(&encoded(Postcard, &before));
+        assert!(matches!(decoded, Err(WireError::Codec(_))));
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_rejects_a_field_less() {
+        let after = layouts::After {
+            id: 7,
+            name: "seven".to_string(),
+            tags: vec!["odd".to_string()],
+        };
+        let decoded = Postcard.decode::<layouts::Before>(&encoded(Postcard, &after));
+        assert!(matches!(decoded, Err(WireError::Codec(_))));
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_rejects_reordered_fields_of_other_types() {
+        let before = layouts::Before {
+            id: 300,
+            name: "three hundred".to_string(),
+        };
+        let decoded = Postcard.decode::<layouts::Swapped>(&encoded(Postcard, &before));
+        assert!(matches!(decoded, Err(WireError::Codec(_))));
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_rejects_truncated_messages() {
+        let message = ping(Postcard);
+        for len in 0..message.len() {
+            let decoded = Postcard.decode_request::<WorldRequest>(&message[..len]);
+            assert!(matches!(decoded, Err(WireError::Codec(_))), "at {}", len);
+        }
+    }
+}
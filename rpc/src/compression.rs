@@ -0,0 +1,181 @@
+//! Optional deflate compression of binary messages.
+//!
+//! Every serialized message inside a binary frame starts with a one byte header telling how
+//! the rest is encoded, so a peer decodes compressed and plain messages alike. Messages at or
+//! below the sender's threshold, and messages that wouldn't get any smaller, stay plain.
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// Header of a message sent as it is.
+pub const PLAIN: u8 = 0;
+
+/// Header of a deflated message.
+pub const DEFLATE: u8 = 1;
+
+/// Messages larger than this are worth compressing when no other threshold is configured.
+pub const DEFAULT_THRESHOLD: usize = 64 * 1024;
+
+/// Compression level handed to `miniz_oxide`, trading speed for size in the middle.
+const LEVEL: u8 = 6;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompressionError {
+    /// The message doesn't even have a header.
+    Empty,
+    /// The header names an encoding this build doesn't know.
+    UnknownHeader(u8),
+    /// The deflated data is corrupt or inflates to more than the allowed size.
+    Corrupt(String),
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionError::Empty => write!(f, "message has no compression header"),
+            CompressionError::UnknownHeader(header) => {
+                write!(f, "unknown compression header {}", header)
+            }
+            CompressionError::Corrupt(e) => write!(f, "can't inflate message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CompressionError {}
+
+/// Prepends the header to `payload`, deflating it first when it is longer than `threshold`.
+/// `None` never compresses.
+pub fn compress(payload: &[u8], threshold: Option<usize>) -> Vec<u8> {
+    let mut message = Vec::new();
+    compress_into(payload, threshold, &mut message);
+    message
+}
+
+/// Appends what [`compress`] returns to `dst`, so it can go behind a length prefix without
+/// being copied again.
+pub fn compress_into(payload: &[u8], threshold: Option<usize>, dst: &mut Vec<u8>) {
+    if threshold.is_some_and(|threshold| payload.len() > threshold) {
+        let deflated = miniz_oxide::deflate::compress_to_vec(payload, LEVEL);
+        if deflated.len() < payload.len() {
+            dst.reserve(1 + deflated.len());
+            dst.push(DEFLATE);
+            dst.extend_from_slice(&deflated);
+            return;
+        }
+    }
+    dst.reserve(1 + payload.len());
+    dst.push(PLAIN);
+    dst.extend_from_slice(payload);
+}
+
+/// Strips the header from `message`, inflating the rest if needed. Inflating stops with an
+/// error past `max_len` bytes so a small message can't expand into an enormous one.
+pub fn decompress(message: &[u8], max_len: usize) -> Result<Cow<'_, [u8]>, CompressionError> {
+    match message.split_first() {
+        Some((&PLAIN, payload)) => Ok(Cow::Borrowed(payload)),
+        Some((&DEFLATE, deflated)) => {
+            miniz_oxide::inflate::decompress_to_vec_with_limit(deflated, max_len)
+                .map(Cow::Owned)
+                .map_err(|e| CompressionError::Corrupt(format!("{:?}", e.status)))
+        }
+        Some((&header, _)) => Err(CompressionError::UnknownHeader(header)),
+        None => Err(CompressionError::Empty),
+    }
+}
+
+/// Whether the peer compressed `message`.
+pub fn is_compressed(message: &[u8]) -> bool {
+    message.first() == Some(&DEFLATE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes deflate can't shrink, from a little xorshift generator.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compressible_input_above_the_threshold_is_deflated() {
+        let payload = "hello, world ".repeat(1000).into_bytes();
+        let message = compress(&payload, Some(100));
+        assert!(is_compressed(&message));
+        assert!(message.len() < payload.len() / 10);
+        assert_eq!(decompress(&message, payload.len()).unwrap(), payload);
+    }
+
+    #[test]
+    fn incompressible_input_stays_plain() {
+        let payload = noise(4096);
+        let message = compress(&payload, Some(100));
+        assert_eq!(message[0], PLAIN);
+        assert_eq!(&message[1..], &payload[..]);
+        assert_eq!(decompress(&message, payload.len()).unwrap(), payload);
+    }
+
+    #[test]
+    fn input_at_or_below_the_threshold_stays_plain() {
+        let payload = vec![0; 100];
+        assert!(!is_compressed(&compress(&payload, Some(100))));
+        assert!(is_compressed(&compress(&payload, Some(99))));
+        assert!(!is_compressed(&compress(&payload, None)));
+    }
+
+    #[test]
+    fn plain_messages_are_borrowed() {
+        let message = compress(b"small", None);
+        assert!(matches!(
+            decompress(&message, usize::MAX),
+            Ok(Cow::Borrowed(b"small"))
+        ));
+    }
+
+    #[test]
+    fn compress_into_appends() {
+        let mut dst = b"prefix".to_vec();
+        compress_into(b"payload", None, &mut dst);
+        assert_eq!(dst, b"prefix\0payload");
+    }
+
+    #[test]
+    fn empty_payload_round_trips() {
+        let message = compress(b"", Some(0));
+        assert_eq!(message, [PLAIN]);
+        assert_eq!(decompress(&message, 0).unwrap(), &b""[..]);
+    }
+
+    #[test]
+    fn bad_messages_are_rejected() {
+        assert_eq!(decompress(&[], usize::MAX), Err(CompressionError::Empty));
+        assert_eq!(
+            decompress(&[7, 1, 2], usize::MAX),
+            Err(CompressionError::UnknownHeader(7))
+        );
+        assert!(matches!(
+            decompress(&[DEFLATE, 0xff, 0xff, 0xff], usize::MAX),
+            Err(CompressionError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn inflating_past_the_limit_fails() {
+        let payload = vec![0; 1 << 20];
+        let message = compress(&payload, Some(0));
+        assert!(message.len() < 10 * 1024);
+        assert!(matches!(
+            decompress(&message, 1024),
+            Err(CompressionError::Corrupt(_))
+        ));
+        assert_eq!(decompress(&message, payload.len()).unwrap(), payload);
+    }
+}
@@ -0,0 +1,16 @@
+//! Controlling a server from tests, see [`Control`].
+//!
+//! Only built with the `test-util` feature, which no release build turns on. The server then
+//! serves `Control` on a port of its own, as length-delimited JSON like its plain TCP
+//! listener, so a test harness can stop it with a tarpc client instead of killing the process
+//! and waiting for its ports to be let go.
+
+use async_trait::async_trait;
+use tarpc::service;
+
+#[service]
+#[async_trait]
+pub trait Control {
+    /// Shuts the server down the way SIGTERM does, answering once the drain began.
+    async fn shutdown();
+}
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+use crate::handshake::{Hello, Welcome};
+use crate::metadata::RequestMetadata;
+use crate::push::Notification;
+use crate::stream::StreamFrame;
+
+/// Close code of connections whose auth token was missing or rejected.
+pub const UNAUTHORIZED: u16 = 4401;
+
+/// Wrapper around every tarpc message on the wire.
+///
+/// Keeps transport level traffic such as keepalives out of tarpc's request/response matching.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Envelope<T> {
+    /// A tarpc `ClientMessage` or `Response`.
+    Message(T),
+    /// A tarpc `ClientMessage` sent with the metadata attached to its request, see
+    /// [`crate::metadata`].
+    Tagged(T, RequestMetadata),
+    /// Keepalive sent by the client while the connection is idle.
+    Heartbeat,
+    /// The server's answer to a `Heartbeat`.
+    HeartbeatAck,
+    /// Bearer token sent as the first message by clients that don't put it in the URL.
+    Auth(String),
+    /// The server accepted the connection's token and serves RPCs from now on.
+    AuthAck,
+    /// The client's protocol version, sent once authenticated, see [`crate::handshake`].
+    Hello(Hello),
+    /// The server's answer to a `Hello`.
+    Welcome(Welcome),
+    /// Part of a stream the server sends for a request, see [`crate::stream`].
+    Stream(StreamFrame),
+    /// A message of the server outside any request, see [`crate::push`].
+    Notification(Notification),
+    /// A tarpc `ClientMessage` whose client expects no response to it, see [`crate::oneway`].
+    OneWay(T),
+}
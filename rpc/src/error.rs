@@ -0,0 +1,59 @@
+//! Why a `World` method failed, see [`WorldError`].
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::upload::UploadError;
+
+/// The error every `World` method answers with, for clients to tell failures apart without
+/// reading messages meant for people.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WorldError {
+    /// The call can't succeed with these arguments, e.g. a peer id nobody has.
+    InvalidArgument(String),
+    /// The call can't finish before its deadline.
+    Timeout,
+    /// Whoever serves the call doesn't offer the method, e.g. a browser serving a peer.
+    Unavailable(String),
+    /// Something went wrong on the serving side, trying again may work.
+    Internal(String),
+    /// The client made too many calls of the method, it may try again in this many
+    /// milliseconds.
+    RateLimited(u64),
+    /// The connection has as many calls running as the server allows, trying again once one
+    /// of them answered may work.
+    Busy,
+    /// An upload call failed, see [`crate::upload`].
+    Upload(UploadError),
+    /// `list_items` was given a cursor no page of it ended with.
+    InvalidCursor,
+    /// The arguments are larger than the server takes for the method, which is this many
+    /// bytes as JSON.
+    PayloadTooLarge(u64),
+    /// The method is served, but not to this caller, e.g. `list_clients` to a non-admin.
+    PermissionDenied(String),
+}
+
+impl fmt::Display for WorldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorldError::InvalidArgument(e) => write!(f, "invalid argument: {}", e),
+            WorldError::Timeout => write!(f, "the call can't finish before its deadline"),
+            WorldError::Unavailable(e) => write!(f, "unavailable: {}", e),
+            WorldError::Internal(e) => write!(f, "internal error: {}", e),
+            WorldError::RateLimited(retry_after) => {
+                write!(f, "rate limited, try again in {} ms", retry_after)
+            }
+            WorldError::Busy => write!(f, "too many calls are running"),
+            WorldError::Upload(e) => write!(f, "upload failed: {}", e),
+            WorldError::InvalidCursor => write!(f, "the cursor isn't one a page ended with"),
+            WorldError::PayloadTooLarge(max) => {
+                write!(f, "the arguments are larger than the {} bytes allowed", max)
+            }
+            WorldError::PermissionDenied(e) => write!(f, "permission denied: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WorldError {}
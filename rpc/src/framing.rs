@@ -0,0 +1,309 @@
+//! Length prefixed framing for binary WebSocket frames.
+//!
+//! Every serialized message is preceded by its length as a big endian `u32`. A decoder buffers
+//! whatever arrives, so a frame holding several messages or a message split over several frames
+//! (e.g. by a proxy) both decode the same way. The message itself carries the header described
+//! in [`crate::compression`].
+
+use std::fmt;
+
+/// Size of the length prefix.
+pub const HEADER_LEN: usize = 4;
+
+/// Largest message a [`FrameDecoder`] accepts unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The length prefix announces more than the decoder is willing to buffer. Usually this
+    /// means the stream is corrupted and out of sync.
+    TooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the limit of {} bytes", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Appends `payload` with its length prefix to `dst`.
+pub fn write_frame(payload: &[u8], dst: &mut Vec<u8>) {
+    let len = u32::try_from(payload.len()).expect("message larger than 4 GiB");
+    dst.reserve(HEADER_LEN + payload.len());
+    dst.extend_from_slice(&len.to_be_bytes());
+    dst.extend_from_slice(payload);
+}
+
+/// Returns `payload` with its length prefix.
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    write_frame(payload, &mut frame);
+    frame
+}
+
+/// Reassembles length prefixed messages from arbitrarily chunked input.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// Where the unread part of `buf` starts. Consumed messages are only dropped from the
+    /// front once they make up half of it, instead of shifting the rest after each one.
+    start: usize,
+    max_frame_len: usize,
+    /// Bytes of a skipped message that haven't arrived yet and are dropped as they do.
+    skipping: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_LEN)
+    }
+}
+
+impl FrameDecoder {
+    pub fn new(max_frame_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            start: 0,
+            max_frame_len,
+            skipping: 0,
+        }
+    }
+
+    /// Buffers a chunk of received bytes.
+    pub fn extend(&mut self, chunk: &[u8]) {
+        let skipped = self.skipping.min(chunk.len());
+        self.skipping -= skipped;
+        if self.start == self.buf.len() {
+            self.buf.clear();
+            self.start = 0;
+        } else if self.start > self.buf.len() / 2 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(&chunk[skipped..]);
+    }
+
+    /// Length announced by the header at the front of the buffer.
+    fn frame_len(&self) -> Option<usize> {
+        let mut header = [0; HEADER_LEN];
+        header.copy_from_slice(self.buf.get(self.start..self.start + HEADER_LEN)?);
+        Some(u32::from_be_bytes(header) as usize)
+    }
+
+    /// Pops the next complete message, if one has been buffered.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        Ok(self.next_frame_ref()?.map(<[u8]>::to_vec))
+    }
+
+    /// Like [`next_frame`](Self::next_frame), but borrows the message from the buffer instead
+    /// of copying it out.
+    pub fn next_frame_ref(&mut self) -> Result<Option<&[u8]>, FrameError> {
+        let len = match self.frame_len() {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if len > self.max_frame_len {
+            return Err(FrameError::TooLarge {
+                len,
+                max: self.max_frame_len,
+            });
+        }
+        let start = self.start + HEADER_LEN;
+        if self.buf.len() < start + len {
+            return Ok(None);
+        }
+        self.start = start + len;
+        Ok(Some(&self.buf[start..start + len]))
+    }
+
+    /// Drops the message at the front, including the parts still to be received, so decoding
+    /// can carry on after [`FrameError::TooLarge`] without buffering the whole message.
+    pub fn skip_frame(&mut self) {
+        let len = match self.frame_len() {
+            Some(len) => len,
+            None => return,
+        };
+        self.start += HEADER_LEN;
+        let dropped = len.min(self.buf.len() - self.start);
+        self.start += dropped;
+        self.skipping = len - dropped;
+    }
+
+    /// Bytes of partial messages still waiting for the rest of their data.
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    /// Drops buffered data, e.g. after the connection was replaced.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.start = 0;
+        self.skipping = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut framed = Vec::new();
+        for payload in payloads {
+            write_frame(payload, &mut framed);
+        }
+        framed
+    }
+
+    fn drain(decoder: &mut FrameDecoder) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        while let Some(message) = decoder.next_frame().unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn encode_frame_prefixes_the_length() {
+        assert_eq!(encode_frame(b"abc"), [0, 0, 0, 3, b'a', b'b', b'c']);
+        assert_eq!(encode_frame(b""), [0; HEADER_LEN]);
+    }
+
+    #[test]
+    fn partial_header_and_payload_wait_for_the_rest() {
+        let framed = encode_frame(b"hello");
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&framed[..2]);
+        assert_eq!(decoder.next_frame(), Ok(None));
+        decoder.extend(&framed[2..7]);
+        assert_eq!(decoder.next_frame(), Ok(None));
+        assert_eq!(decoder.buffered(), 7);
+        decoder.extend(&framed[7..]);
+        assert_eq!(decoder.next_frame(), Ok(Some(b"hello".to_vec())));
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn byte_by_byte_input_decodes_every_message() {
+        let framed = frames(&[b"one", b"", b"three"]);
+        let mut decoder = FrameDecoder::default();
+        let mut messages = Vec::new();
+        for byte in &framed {
+            decoder.extend(std::slice::from_ref(byte));
+            messages.extend(drain(&mut decoder));
+        }
+        assert_eq!(messages, [b"one".to_vec(), Vec::new(), b"three".to_vec()]);
+    }
+
+    #[test]
+    fn multiple_messages_in_one_chunk() {
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&frames(&[b"first", b"second", b"third"]));
+        assert_eq!(
+            drain(&mut decoder),
+            [b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+    }
+
+    #[test]
+    fn chunk_ending_inside_a_message_keeps_the_partial_one() {
+        let framed = frames(&[b"first", b"second"]);
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&framed[..framed.len() - 2]);
+        assert_eq!(drain(&mut decoder), [b"first".to_vec()]);
+        decoder.extend(&framed[framed.len() - 2..]);
+        assert_eq!(drain(&mut decoder), [b"second".to_vec()]);
+    }
+
+    #[test]
+    fn borrowed_messages_match_copied_ones() {
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&frames(&[b"first", b"second"]));
+        assert_eq!(decoder.next_frame_ref(), Ok(Some(&b"first"[..])));
+        assert_eq!(decoder.next_frame_ref(), Ok(Some(&b"second"[..])));
+        assert_eq!(decoder.next_frame_ref(), Ok(None));
+    }
+
+    #[test]
+    fn corrupted_length_is_too_large() {
+        let mut decoder = FrameDecoder::new(1024);
+        decoder.extend(&[0xff, 0xff, 0xff, 0xff, 1, 2, 3]);
+        assert_eq!(
+            decoder.next_frame(),
+            Err(FrameError::TooLarge {
+                len: u32::MAX as usize,
+                max: 1024,
+            })
+        );
+    }
+
+    #[test]
+    fn message_at_the_limit_is_accepted() {
+        let mut decoder = FrameDecoder::new(4);
+        decoder.extend(&frames(&[b"four", b"five!"]));
+        assert_eq!(decoder.next_frame(), Ok(Some(b"four".to_vec())));
+        assert_eq!(
+            decoder.next_frame(),
+            Err(FrameError::TooLarge { len: 5, max: 4 })
+        );
+    }
+
+    #[test]
+    fn skip_frame_drops_a_buffered_message() {
+        let mut decoder = FrameDecoder::new(4);
+        decoder.extend(&frames(&[b"too large", b"ok"]));
+        assert!(decoder.next_frame().is_err());
+        decoder.skip_frame();
+        assert_eq!(drain(&mut decoder), [b"ok".to_vec()]);
+    }
+
+    #[test]
+    fn skip_frame_drops_the_parts_still_to_come() {
+        let framed = frames(&[&[7; 100], b"after"]);
+        let mut decoder = FrameDecoder::new(10);
+        decoder.extend(&framed[..20]);
+        assert!(decoder.next_frame().is_err());
+        decoder.skip_frame();
+        assert_eq!(decoder.buffered(), 0);
+        // The rest of the skipped message arrives split over chunks, with the next one after.
+        decoder.extend(&framed[20..60]);
+        assert_eq!(decoder.next_frame(), Ok(None));
+        decoder.extend(&framed[60..]);
+        assert_eq!(drain(&mut decoder), [b"after".to_vec()]);
+    }
+
+    #[test]
+    fn clear_forgets_partial_messages() {
+        let framed = encode_frame(b"partial");
+        let mut decoder = FrameDecoder::default();
+        decoder.extend(&framed[..6]);
+        decoder.clear();
+        assert_eq!(decoder.buffered(), 0);
+        decoder.extend(&encode_frame(b"fresh"));
+        assert_eq!(drain(&mut decoder), [b"fresh".to_vec()]);
+    }
+
+    #[test]
+    fn buffer_compacts_without_losing_data() {
+        let mut decoder = FrameDecoder::default();
+        let mut expected = Vec::new();
+        let mut received = Vec::new();
+        for i in 0..1000u32 {
+            let payload = i.to_be_bytes().repeat(i as usize % 7);
+            let framed = encode_frame(&payload);
+            // Each message arrives in two chunks.
+            let (head, tail) = framed.split_at(framed.len() / 2);
+            decoder.extend(head);
+            received.extend(drain(&mut decoder));
+            decoder.extend(tail);
+            received.extend(drain(&mut decoder));
+            expected.push(payload);
+        }
+        assert_eq!(received, expected);
+    }
+}
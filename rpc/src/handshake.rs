@@ -0,0 +1,119 @@
+//! Version check at the start of a connection, before any tarpc message.
+//!
+//! A browser can keep running an old wasm bundle long after a new server was deployed, and
+//! the subprotocol only says how messages are encoded, not what they mean. So once the
+//! connection is authenticated the client sends an [`Envelope::Hello`](crate::Envelope::Hello)
+//! naming the protocol version it speaks, and waits for the server's
+//! [`Envelope::Welcome`](crate::Envelope::Welcome) before handing the connection to tarpc.
+//! Servers answer a `Hello` whenever one arrives, so clients that skip the check still work.
+//!
+//! Both also carry the [`crate::schema`] hashes of the service definition, so a client whose
+//! `World` differs from the server's in a way the version number didn't capture notices too.
+//! The fields are optional, peers from before they existed leave them out.
+
+use serde::{Deserialize, Serialize};
+
+use crate::schema;
+
+/// Version of the protocol spoken on top of the wire format, i.e. of the `World` service and
+/// the envelopes around it. Goes up whenever either changes incompatibly. Version 2 sends
+/// every request with its service, see [`crate::mux`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Versions this build serves, newest first.
+pub const SUPPORTED_VERSIONS: &[u32] = &[PROTOCOL_VERSION];
+
+/// Name of the service this crate defines.
+pub const SERVICE: &str = "World";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol: u32,
+    pub service: String,
+    /// Subprotocol of the codec the client speaks, see [`crate::protocol`].
+    pub codec: String,
+    /// [`schema::SCHEMA_HASH`] of the client.
+    #[serde(default)]
+    pub schema: Option<u64>,
+}
+
+impl Hello {
+    /// The hello of this build, for a client speaking `codec`.
+    pub fn new(codec: &str) -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            service: SERVICE.to_string(),
+            codec: codec.to_string(),
+            schema: Some(schema::SCHEMA_HASH),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Welcome {
+    /// Whether the server serves the client's version, in the codec of the connection. The
+    /// server doesn't close the connection when it doesn't, the client does.
+    pub accepted: bool,
+    /// Versions the server serves, newest first.
+    pub versions: Vec<u32>,
+    /// [`schema::SCHEMA_HASH`] of the server.
+    #[serde(default)]
+    pub schema: Option<u64>,
+    /// Hashes of the methods the server serves, see [`schema::served_by`].
+    #[serde(default)]
+    pub methods: Option<Vec<u64>>,
+}
+
+impl Welcome {
+    /// This build's answer to `hello` on a connection speaking `codec`, the subprotocol of the
+    /// codec the server reads it with. A client encoding differently is turned away.
+    pub fn answer(hello: &Hello, codec: &str) -> Self {
+        Self {
+            accepted: hello.service == SERVICE
+                && hello.codec == codec
+                && SUPPORTED_VERSIONS.contains(&hello.protocol),
+            versions: SUPPORTED_VERSIONS.to_vec(),
+            schema: Some(schema::SCHEMA_HASH),
+            methods: Some(schema::method_hashes()),
+        }
+    }
+
+    /// Whether a client with this welcome can call every method it knows. Servers that don't
+    /// send their methods are taken to serve them all.
+    pub fn serves_schema(&self) -> bool {
+        self.methods.as_deref().is_none_or(schema::served_by)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Codecs of the connections, whether or not this build has them.
+    const JSON: &str = "tarpc.v1.json";
+    const BINCODE: &str = "tarpc.v1.bincode";
+
+    #[test]
+    fn hellos_of_this_build_are_welcomed_in_their_codec_only() {
+        let hello = Hello::new(JSON);
+        assert!(Welcome::answer(&hello, JSON).accepted);
+        assert!(!Welcome::answer(&hello, BINCODE).accepted);
+    }
+
+    #[test]
+    fn other_services_and_versions_are_turned_away() {
+        let other_service = Hello {
+            service: "Other".to_string(),
+            ..Hello::new(JSON)
+        };
+        let newer = Hello {
+            protocol: PROTOCOL_VERSION + 1,
+            ..Hello::new(JSON)
+        };
+        for hello in [other_service, newer] {
+            let welcome = Welcome::answer(&hello, JSON);
+            assert!(!welcome.accepted, "{:?}", hello);
+            assert_eq!(welcome.versions, SUPPORTED_VERSIONS);
+        }
+    }
+}
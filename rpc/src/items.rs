@@ -0,0 +1,28 @@
+//! The items `World::list_items` pages through.
+//!
+//! Items come in the order of their ids, at most [`MAX_PAGE_LEN`] a page. Every page but the
+//! last ends with a cursor, which the next call passes back as it is to get the items after
+//! it. Cursors name the last item of their page rather than how many came before it, so items
+//! added or removed between calls don't make later pages repeat or skip any.
+
+use serde::{Deserialize, Serialize};
+
+/// Items one page holds at most, larger limits are lowered to it.
+pub const MAX_PAGE_LEN: u32 = 100;
+
+/// One item of the server's collection.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Item {
+    /// Unique, and the order items are listed in.
+    pub id: u64,
+    pub name: String,
+}
+
+/// Items as `list_items` answers them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Page {
+    /// At most the limit asked for.
+    pub items: Vec<Item>,
+    /// What to pass for the next page, `None` on the last one. Only meant for `list_items`.
+    pub next_cursor: Option<String>,
+}
@@ -1,10 +1,152 @@
 use async_trait::async_trait;
 use tarpc::service;
 
+pub mod admin;
+pub mod base64;
+pub mod bytes;
+pub mod codec;
+pub mod compression;
+#[cfg(feature = "test-util")]
+pub mod control;
+pub mod envelope;
+pub mod error;
+pub mod framing;
+pub mod handshake;
+pub mod items;
+pub mod metadata;
+pub mod mux;
+pub mod oneway;
+pub mod progress;
+pub mod protocol;
+pub mod push;
+pub mod schema;
+pub mod session;
+pub mod stream;
+pub mod tasks;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod trace;
+pub mod upload;
+pub mod version;
+pub mod wire;
+
+pub use bytes::Bytes;
+pub use envelope::Envelope;
+pub use error::WorldError;
+pub use items::{Item, Page};
+pub use session::{ClientInfo, SessionInfo};
+pub use tasks::{CreateTaskRequest, Task};
+pub use upload::UploadResult;
+pub use version::VersionInfo;
+
+#[cfg(not(any(
+    feature = "json",
+    feature = "bincode",
+    feature = "msgpack",
+    feature = "cbor",
+    feature = "postcard"
+)))]
+compile_error!("rpc needs at least one of the json, bincode, msgpack, cbor or postcard features");
+
+/// Numbers `count` streams at most, it fails the stream after that.
+pub const MAX_COUNT: u64 = 100;
+
 #[service]
 #[async_trait]
 pub trait World {
-    async fn ping() -> Result<String, String>;
-    async fn echo(value: String) -> Result<String, String>;
-    async fn delay(duration: u64) -> Result<String, String>;
+    async fn ping() -> Result<String, WorldError>;
+    async fn echo(value: String) -> Result<String, WorldError>;
+    /// Answers after `millis` milliseconds. Servers answer delays longer than they allow, five
+    /// minutes by default, [`WorldError::InvalidArgument`], and those ending past the call's
+    /// deadline [`WorldError::Timeout`].
+    async fn delay(millis: u64) -> Result<String, WorldError>;
+    /// Id other clients use to `signal` this connection.
+    async fn peer_id() -> Result<u64, WorldError>;
+    /// Relays an opaque WebRTC signaling message to the connection with id `to`.
+    async fn signal(to: u64, payload: String) -> Result<(), WorldError>;
+    /// Takes the signaling messages relayed to this connection, with the sender's id.
+    async fn signals() -> Result<Vec<(u64, String)>, WorldError>;
+    /// Streams the numbers from 1 to `n`, a second apart, as `stream`, see [`stream`]. Fails
+    /// the stream after [`MAX_COUNT`] numbers.
+    async fn count(stream: u64, n: u64) -> Result<(), WorldError>;
+    /// Sends this connection the messages published to `topic` as notifications, see
+    /// [`push`].
+    async fn subscribe(topic: String) -> Result<(), WorldError>;
+    async fn unsubscribe(topic: String) -> Result<(), WorldError>;
+    /// Sends `payload`, a JSON value, to the subscribers of `topic`. Answers how many there
+    /// were.
+    async fn publish(topic: String, payload: String) -> Result<u64, WorldError>;
+    /// Like `delay` for `millis` milliseconds, reporting the milliseconds waited as a
+    /// [`progress::Progress`] on `progress` every second and once done, see [`progress`].
+    async fn delay_with_progress(millis: u64, progress: u64) -> Result<String, WorldError>;
+    /// What the server knows about this connection, see [`session`].
+    async fn whoami() -> Result<SessionInfo, WorldError>;
+    /// Every connection open on the server, for admins only. Others are answered
+    /// [`WorldError::PermissionDenied`].
+    async fn list_clients() -> Result<Vec<ClientInfo>, WorldError>;
+    /// Starts an upload of the file `name` of `size` bytes, answering its id, see [`upload`].
+    async fn upload_begin(name: String, size: u64) -> Result<u64, WorldError>;
+    /// Stores `bytes` at `offset` of the upload `id`. Chunks may come in any order, each once.
+    async fn upload_chunk(id: u64, offset: u64, bytes: Bytes) -> Result<(), WorldError>;
+    /// Ends the upload `id` once every byte arrived and `checksum` is theirs, keeping the file.
+    async fn upload_finish(id: u64, checksum: u32) -> Result<UploadResult, WorldError>;
+    /// Up to `limit` of the server's items, from the start or after `cursor`, see [`items`].
+    /// Cursors other than those of earlier pages are answered [`WorldError::InvalidCursor`].
+    async fn list_items(cursor: Option<String>, limit: u32) -> Result<Page, WorldError>;
+    /// Adds `by` to the count every connection shares, answering the new count. Counts that
+    /// would overflow are answered [`WorldError::InvalidArgument`].
+    async fn increment(by: i64) -> Result<i64, WorldError>;
+    /// The count `increment` adds to.
+    async fn get_count() -> Result<i64, WorldError>;
+    /// The build of the server answering, see [`version`].
+    async fn version() -> Result<VersionInfo, WorldError>;
+    /// Answers `data` as it came, up to [`bytes::MAX_ECHO_BYTES`] of them, see [`bytes`].
+    async fn echo_bytes(data: Bytes) -> Result<Bytes, WorldError>;
+    /// Stores the task `request` describes, answering it with its id, see [`tasks`].
+    async fn create_task(request: CreateTaskRequest) -> Result<Task, WorldError>;
+    /// Logs the event `name` with `payload` on the server's side, meant to be called one-way,
+    /// see [`oneway`]. Names are at most [`oneway::MAX_EVENT_NAME_LEN`] bytes.
+    async fn log_event(name: String, payload: String) -> Result<(), WorldError>;
+}
+
+/// Requests that can safely run twice, e.g. when a client sends them again after reconnecting
+/// without knowing whether the server got them the first time.
+pub trait Idempotent {
+    fn idempotent(&self) -> bool;
+}
+
+impl Idempotent for WorldRequest {
+    fn idempotent(&self) -> bool {
+        match self {
+            WorldRequest::Ping { .. }
+            | WorldRequest::Echo { .. }
+            | WorldRequest::Delay { .. }
+            | WorldRequest::PeerId { .. } => true,
+            // Relaying twice sends the peer a duplicate, and `signals` empties the queue.
+            WorldRequest::Signal { .. } | WorldRequest::Signals { .. } => false,
+            // Counting twice sends the items of the stream twice.
+            WorldRequest::Count { .. } => false,
+            WorldRequest::Subscribe { .. } | WorldRequest::Unsubscribe { .. } => true,
+            // Subscribers would get the message twice.
+            WorldRequest::Publish { .. } => false,
+            // The progress would be reported twice on the same stream.
+            WorldRequest::DelayWithProgress { .. } => false,
+            WorldRequest::Whoami { .. } => true,
+            WorldRequest::ListClients { .. } => true,
+            // Another upload would begin, the chunk would be a duplicate, or the upload gone.
+            WorldRequest::UploadBegin { .. }
+            | WorldRequest::UploadChunk { .. }
+            | WorldRequest::UploadFinish { .. } => false,
+            WorldRequest::ListItems { .. } => true,
+            // Adding twice counts twice.
+            WorldRequest::Increment { .. } => false,
+            WorldRequest::GetCount { .. } => true,
+            WorldRequest::Version { .. } => true,
+            WorldRequest::EchoBytes { .. } => true,
+            // The task would be stored twice, under two ids.
+            WorldRequest::CreateTask { .. } => false,
+            // The event would be logged twice.
+            WorldRequest::LogEvent { .. } => false,
+        }
+    }
 }
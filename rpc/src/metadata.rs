@@ -0,0 +1,106 @@
+//! Key-value pairs a client sends along with a request, such as a tenant id or a locale.
+//!
+//! tarpc's `Context` only carries a deadline and a trace id, so the metadata travels next to
+//! the request instead: the client opens a transport for it with `client::mux::Mux::open_with`,
+//! which hands every request to the connection with the metadata, and the connection sends it as
+//! an [`Envelope::Tagged`](crate::Envelope::Tagged) with the request's trace id. The server
+//! keeps it for the handler, which finds it by the trace id of its own context.
+//!
+//! Servers older than this module can't decode tagged requests, so only attach metadata when
+//! talking to one that knows it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tarpc::trace::TraceId;
+
+/// Bytes of keys and values a request's metadata takes at most.
+pub const MAX_METADATA_SIZE: usize = 4096;
+
+/// Key whose value `echo` appends to its answer, to show the metadata arrived.
+pub const ECHO_KEY: &str = "echo";
+
+/// The metadata of one request.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata(BTreeMap<String, String>);
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `key` to `value`, replacing what it was set to.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.0.insert(key.into(), value.into());
+    }
+
+    /// `insert` for building metadata in one expression.
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Bytes of all keys and values, what [`MAX_METADATA_SIZE`] limits.
+    pub fn size(&self) -> usize {
+        self.0
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum()
+    }
+
+    /// Fails if the metadata is too large to send.
+    pub fn check(&self) -> Result<(), MetadataError> {
+        let size = self.size();
+        if size > MAX_METADATA_SIZE {
+            return Err(MetadataError::TooLarge {
+                size,
+                limit: MAX_METADATA_SIZE,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+/// Metadata and the trace id of the request it goes with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestMetadata {
+    pub trace_id: TraceId,
+    pub metadata: Metadata,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataError {
+    /// The metadata takes `size` bytes, more than [`MAX_METADATA_SIZE`].
+    TooLarge { size: usize, limit: usize },
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::TooLarge { size, limit } => {
+                write!(
+                    f,
+                    "Metadata of {} bytes exceeds the limit of {}",
+                    size, limit
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
@@ -0,0 +1,164 @@
+//! Several services on one connection, see [`ServiceRequest`].
+//!
+//! Every request names the service it is for by a [`ServiceId`] ahead of its arguments, and
+//! every response the service that answered. The server hands each request to the service of
+//! its id, and answers ids it doesn't serve with [`ServiceResponse::Unknown`] rather than
+//! failing the connection. tarpc numbers the requests of each client on its own, so clients
+//! sharing a connection need their ids kept apart; `client::mux` does that.
+//!
+//! The arguments of a request for an unknown service are skipped without knowing their type,
+//! which only self-describing formats can do: JSON, MessagePack and CBOR. In bincode and
+//! postcard such a request fails to decode like any other malformed message.
+
+use std::fmt;
+
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+use serde::de::{self, IgnoredAny, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::admin::{AdminRequest, AdminResponse};
+use crate::{Idempotent, WorldRequest, WorldResponse};
+
+/// Which service a request is for.
+pub type ServiceId = u16;
+
+/// The id of [`World`](crate::World).
+pub const WORLD: ServiceId = 0;
+
+/// The id of [`Admin`](crate::admin::Admin).
+pub const ADMIN: ServiceId = 1;
+
+/// A request to one of the services, sent as its service id and the request.
+#[derive(Debug)]
+pub enum ServiceRequest {
+    World(WorldRequest),
+    Admin(AdminRequest),
+    /// A request for a service this build doesn't know, its arguments skipped.
+    Unknown(ServiceId),
+}
+
+/// The tarpc message `fields` are the fields of, e.g. a `Response<WorldResponse>` out of a
+/// `Response<ServiceResponse>`. tarpc doesn't let its messages be built outside of it, but they
+/// deserialize from what their fields serialize to, here through a JSON value.
+#[cfg(feature = "json")]
+pub fn rebuild<T: DeserializeOwned>(fields: impl Serialize) -> serde_json::Result<T> {
+    serde_json::to_value(fields).and_then(serde_json::from_value)
+}
+
+/// The answer of one of the services.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServiceResponse {
+    World(WorldResponse),
+    Admin(AdminResponse),
+    /// The answer to a request for a service the server doesn't serve.
+    Unknown(ServiceId),
+}
+
+impl Idempotent for ServiceRequest {
+    fn idempotent(&self) -> bool {
+        match self {
+            ServiceRequest::World(request) => request.idempotent(),
+            ServiceRequest::Admin(request) => request.idempotent(),
+            ServiceRequest::Unknown(_) => false,
+        }
+    }
+}
+
+impl Serialize for ServiceRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        match self {
+            ServiceRequest::World(request) => {
+                tuple.serialize_element(&WORLD)?;
+                tuple.serialize_element(request)?;
+            }
+            ServiceRequest::Admin(request) => {
+                tuple.serialize_element(&ADMIN)?;
+                tuple.serialize_element(request)?;
+            }
+            ServiceRequest::Unknown(id) => {
+                tuple.serialize_element(id)?;
+                tuple.serialize_element(&())?;
+            }
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ServiceRequest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(2, ServiceRequestVisitor)
+    }
+}
+
+struct ServiceRequestVisitor;
+
+impl<'de> Visitor<'de> for ServiceRequestVisitor {
+    type Value = ServiceRequest;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a service id and a request")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ServiceRequest, A::Error> {
+        let id: ServiceId = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let request = match id {
+            WORLD => seq.next_element()?.map(ServiceRequest::World),
+            ADMIN => seq.next_element()?.map(ServiceRequest::Admin),
+            id => seq
+                .next_element::<IgnoredAny>()?
+                .map(|_| ServiceRequest::Unknown(id)),
+        };
+        request.ok_or_else(|| de::Error::invalid_length(1, &self))
+    }
+}
+
+/// The requests of one service, as its generated client sends them.
+pub trait Routed: Sized {
+    type Response;
+
+    const ID: ServiceId;
+
+    fn into_request(self) -> ServiceRequest;
+
+    /// This service's answer in `response`, or `response` if it is another's.
+    fn response(response: ServiceResponse) -> Result<Self::Response, ServiceResponse>;
+}
+
+impl Routed for WorldRequest {
+    type Response = WorldResponse;
+
+    const ID: ServiceId = WORLD;
+
+    fn into_request(self) -> ServiceRequest {
+        ServiceRequest::World(self)
+    }
+
+    fn response(response: ServiceResponse) -> Result<WorldResponse, ServiceResponse> {
+        match response {
+            ServiceResponse::World(response) => Ok(response),
+            response => Err(response),
+        }
+    }
+}
+
+impl Routed for AdminRequest {
+    type Response = AdminResponse;
+
+    const ID: ServiceId = ADMIN;
+
+    fn into_request(self) -> ServiceRequest {
+        ServiceRequest::Admin(self)
+    }
+
+    fn response(response: ServiceResponse) -> Result<AdminResponse, ServiceResponse> {
+        match response {
+            ServiceResponse::Admin(response) => Ok(response),
+            response => Err(response),
+        }
+    }
+}
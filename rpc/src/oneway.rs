@@ -0,0 +1,14 @@
+//! Requests the client expects no answer to, such as telemetry.
+//!
+//! The client makes the call with `client::mux::Mux::notify`, which flags the request it hands
+//! to the transport, and the transport sends a flagged request as an
+//! [`Envelope::OneWay`](crate::Envelope::OneWay). The call is done as soon as the message is
+//! on the wire: nothing waits for a response, and none comes. The server runs the handler like
+//! any other but drops what it answers, logging the errors since there is nobody to tell.
+//!
+//! Any method can be called this way, though only those whose answer doesn't matter make
+//! sense. Metadata attached to a one-way request isn't sent, and servers older than this module
+//! can't decode one-way requests.
+
+/// Bytes the name of an event `World::log_event` logs takes at most.
+pub const MAX_EVENT_NAME_LEN: usize = 64;
@@ -0,0 +1,26 @@
+//! How far a long call got, reported while it runs, see [`Progress`].
+//!
+//! A method reporting progress takes a stream id like the streaming methods of
+//! [`crate::stream`] and sends a [`Progress`] on it now and then, ending the stream when it
+//! answers. The reports are only a hint: one may still be on its way when the answer arrives,
+//! and clients drop it then.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Work done so far, in the method's unit, e.g. milliseconds waited.
+    pub done: u64,
+    /// Work there is in all, in the same unit.
+    pub total: u64,
+}
+
+impl Progress {
+    /// The share done, from 0 to 1. A call with nothing to do is done.
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        (self.done as f64 / self.total as f64).min(1.0)
+    }
+}
@@ -0,0 +1,40 @@
+//! WebSocket subprotocols naming the wire format.
+//!
+//! The client asks for the one matching its frame mode and the server only accepts upgrades
+//! asking for one it knows, so incompatible deployments fail at the handshake instead of with
+//! undecodable messages later. The version goes up whenever the format changes incompatibly.
+//! Every [`Codec`](crate::codec::Codec) names one of these, or one of its own.
+
+/// JSON envelopes in text frames.
+#[cfg(feature = "json")]
+pub const JSON: &str = "tarpc.v1.json";
+
+/// Length prefixed, optionally compressed bincode envelopes in binary frames.
+#[cfg(feature = "bincode")]
+pub const BINCODE: &str = "tarpc.v1.bincode";
+
+/// Length prefixed, optionally compressed MessagePack envelopes in binary frames.
+#[cfg(feature = "msgpack")]
+pub const MSGPACK: &str = "tarpc.v1.msgpack";
+
+/// Length prefixed, optionally compressed CBOR envelopes in binary frames.
+#[cfg(feature = "cbor")]
+pub const CBOR: &str = "tarpc.v1.cbor";
+
+/// Length prefixed, optionally compressed postcard envelopes in binary frames.
+#[cfg(feature = "postcard")]
+pub const POSTCARD: &str = "tarpc.v1.postcard";
+
+/// Every subprotocol this build speaks, in order of preference.
+pub const SUPPORTED: &[&str] = &[
+    #[cfg(feature = "json")]
+    JSON,
+    #[cfg(feature = "bincode")]
+    BINCODE,
+    #[cfg(feature = "msgpack")]
+    MSGPACK,
+    #[cfg(feature = "cbor")]
+    CBOR,
+    #[cfg(feature = "postcard")]
+    POSTCARD,
+];
@@ -0,0 +1,106 @@
+//! Messages the server sends a connection on its own, outside of any request.
+//!
+//! A [`Notification`] names a topic and carries a JSON payload, e.g. `"peer_joined"` with the
+//! new peer's id, in an [`Envelope::Notification`](crate::Envelope::Notification). The server
+//! gets a [`PushHandle`] per connection to send them, and the streams of [`crate::stream`],
+//! and clients hand them to whoever subscribed instead of tarpc.
+//!
+//! Notifications are only delivered over a live connection and never buffered for one that
+//! isn't. Once a connection is gone its handle fails with [`Closed`], including while the
+//! client reconnects: the new connection gets a new handle, and whatever was sent in between
+//! is lost. Clients that care refetch the state the notifications describe after
+//! reconnecting.
+
+use serde::{Deserialize, Serialize};
+
+/// Topic of what admins tell every connected client, e.g. that maintenance is coming. The
+/// payload is the text to show.
+pub const ANNOUNCEMENT_TOPIC: &str = "announcement";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    /// What happened, for subscribers to tell notifications apart.
+    pub topic: String,
+    /// Details as JSON, whatever the codec of the connection.
+    pub payload: String,
+}
+
+#[cfg(feature = "push")]
+pub use self::handle::*;
+
+#[cfg(feature = "push")]
+mod handle {
+    use std::fmt;
+
+    use futures::channel::mpsc;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    use super::Notification;
+    use crate::stream::StreamSender;
+    use crate::Envelope;
+
+    impl Notification {
+        pub fn new<T: Serialize>(
+            topic: impl Into<String>,
+            payload: &T,
+        ) -> Result<Self, serde_json::Error> {
+            Ok(Self {
+                topic: topic.into(),
+                payload: serde_json::to_string(payload)?,
+            })
+        }
+
+        /// The payload as the type the topic carries.
+        pub fn payload<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+            serde_json::from_str(&self.payload)
+        }
+    }
+
+    /// The connection went away, nothing sent on it arrives anymore.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Closed;
+
+    impl fmt::Display for Closed {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "the connection is gone")
+        }
+    }
+
+    impl std::error::Error for Closed {}
+
+    /// A connection's [`PushHandle`], and the envelopes it sends for the connection's transport
+    /// to write.
+    pub fn channel() -> (PushHandle, mpsc::UnboundedReceiver<Envelope<()>>) {
+        let (pushes, outgoing) = mpsc::unbounded();
+        (PushHandle(pushes), outgoing)
+    }
+
+    /// Sends a connection notifications and streams, shared by its clones.
+    #[derive(Clone, Debug)]
+    pub struct PushHandle(mpsc::UnboundedSender<Envelope<()>>);
+
+    impl PushHandle {
+        /// A handle whose messages go nowhere, for transports that can't carry them.
+        pub fn closed() -> Self {
+            let (handle, _) = channel();
+            handle
+        }
+
+        pub fn notify(&self, notification: Notification) -> Result<(), Closed> {
+            self.0
+                .unbounded_send(Envelope::Notification(notification))
+                .map_err(|_| Closed)
+        }
+
+        /// Starts the stream the client asked for with id `stream`.
+        pub fn open<T: Serialize>(&self, stream: u64) -> StreamSender<T> {
+            StreamSender::new(stream, self.0.clone())
+        }
+
+        /// Whether the connection is gone.
+        pub fn is_closed(&self) -> bool {
+            self.0.is_closed()
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Hashes of the `World` service definition, to tell a cached client built from another
+//! version of it during the [`crate::handshake`].
+//!
+//! `build.rs` hashes every method from its position in the trait and the JSON schemas of its
+//! arguments and result, as [`OPENRPC`] has them but without the doc comments. The schemas
+//! of the types the method names, and of those these name in turn, are part of it, so a
+//! field added to a struct the method returns changes its hash too. Changes serde doesn't
+//! show in JSON, e.g. how a type is written in binary frames, still need
+//! [`crate::handshake::PROTOCOL_VERSION`] to go up.
+//!
+//! Adding a method at the end of the trait is compatible: the hashes of the methods before it
+//! stay the same, and a client is served as long as the server knows every method the client
+//! knows, see [`served_by`]. Renaming, removing or reordering methods or changing a signature
+//! changes the hashes of the methods involved and isn't.
+
+/// A method of the service and the hash of its definition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Method {
+    pub name: &'static str,
+    pub hash: u64,
+}
+
+// `METHODS`, in declaration order, and `SCHEMA_HASH`, the hash over all of them.
+include!(concat!(env!("OUT_DIR"), "/schema.rs"));
+
+/// The service as an OpenRPC document, its methods' arguments and results as JSON schemas of
+/// what text frames carry. Written by `build.rs` and committed as `rpc/openrpc.json`, see the
+/// `openrpc` binary.
+pub const OPENRPC: &str = include_str!(concat!(env!("OUT_DIR"), "/openrpc.json"));
+
+/// Hashes of [`METHODS`], as sent in the handshake.
+pub fn method_hashes() -> Vec<u64> {
+    METHODS.iter().map(|method| method.hash).collect()
+}
+
+/// Whether a server serving the methods with these hashes knows every method of this build.
+pub fn served_by(methods: &[u64]) -> bool {
+    METHODS.iter().all(|method| methods.contains(&method.hash))
+}
+
+/// Methods of this build the server serving `methods` doesn't know.
+pub fn missing_from(methods: &[u64]) -> Vec<&'static str> {
+    METHODS
+        .iter()
+        .filter(|method| !methods.contains(&method.hash))
+        .map(|method| method.name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn committed_openrpc_document_is_up_to_date() {
+        assert!(
+            OPENRPC == include_str!("../openrpc.json"),
+            "rpc/openrpc.json is out of date, run `cargo run --package rpc --bin openrpc > \
+             rpc/openrpc.json`"
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! What the server knows about connections, see `World::whoami` and `World::list_clients`.
+
+use serde::{Deserialize, Serialize};
+
+/// The connection a call came on, as the server sees it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// The connection's id, as `peer_id` answers it.
+    pub peer_id: u64,
+    /// Address the connection came from, if its transport tells.
+    pub peer_addr: Option<String>,
+    /// Whose token opened the connection, `None` for anonymous ones.
+    pub identity: Option<String>,
+    /// When the server accepted the connection, in milliseconds since the Unix epoch.
+    pub connected_at: u64,
+}
+
+/// A connection open on the server, as `list_clients` answers it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    /// The connection's id, as `peer_id` answers it.
+    pub peer_id: u64,
+    /// Address the connection came from, if its transport tells.
+    pub peer_addr: Option<String>,
+    /// Whose token opened the connection, `None` for anonymous ones.
+    pub identity: Option<String>,
+    /// When the server accepted the connection, in milliseconds since the Unix epoch.
+    pub connected_at: u64,
+    /// Calls the connection made so far, answered or not.
+    pub calls: u64,
+}
@@ -0,0 +1,279 @@
+//! Sequences of items the server sends for one request, next to tarpc's single response.
+//!
+//! tarpc only knows requests and responses, so a streaming method takes a stream id the client
+//! picked, answers right away and sends its items afterwards in [`Envelope::Stream`] frames
+//! tagged with that id. Each frame carries a sequence number counting up from zero, and the
+//! last one says whether the stream ended or failed. Transports hand the frames to a
+//! [`StreamRouter`] on the client instead of tarpc, so they never get mixed up with
+//! responses.
+//!
+//! Items are carried as JSON whatever the codec of the connection, which lets transports pass
+//! frames along without knowing the item types. The server sends them through the connection's
+//! [`PushHandle`](crate::push::PushHandle) like notifications. The WebSocket and SSE transports
+//! carry streams, WebTransport and WebRTC data channels don't.
+//!
+//! [`Envelope::Stream`]: crate::Envelope::Stream
+
+use serde::{Deserialize, Serialize};
+
+/// A stream's share of the connection, in an [`Envelope::Stream`](crate::Envelope::Stream).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamFrame {
+    /// Id the client passed to the streaming method.
+    pub stream: u64,
+    /// Position in the stream, counting from zero.
+    pub sequence: u64,
+    pub event: StreamEvent,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamEvent {
+    /// The next item, as JSON.
+    Item(String),
+    /// No more items.
+    End,
+    /// The stream stopped early for this reason.
+    Error(String),
+}
+
+#[cfg(feature = "push")]
+pub use self::helpers::*;
+
+#[cfg(feature = "push")]
+mod helpers {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::marker::PhantomData;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use futures::channel::mpsc;
+    use futures::{Stream, StreamExt};
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    use super::{StreamEvent, StreamFrame};
+    use crate::push::Closed;
+    use crate::Envelope;
+
+    impl<T> StreamSender<T> {
+        /// The stream the client asked for with id `stream`, see
+        /// [`PushHandle::open`](crate::push::PushHandle::open).
+        pub(crate) fn new(stream: u64, pushes: mpsc::UnboundedSender<Envelope<()>>) -> Self {
+            StreamSender {
+                stream,
+                sequence: 0,
+                pushes,
+                finished: false,
+                _item: PhantomData,
+            }
+        }
+    }
+
+    /// The server's end of a stream. Dropping it ends the stream.
+    pub struct StreamSender<T> {
+        stream: u64,
+        sequence: u64,
+        pushes: mpsc::UnboundedSender<Envelope<()>>,
+        finished: bool,
+        _item: PhantomData<fn(T)>,
+    }
+
+    impl<T> fmt::Debug for StreamSender<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("StreamSender")
+                .field("stream", &self.stream)
+                .field("sequence", &self.sequence)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl<T: Serialize> StreamSender<T> {
+        /// Sends the next item. An item that can't be serialized fails the stream instead.
+        pub fn send(&mut self, item: &T) -> Result<(), Closed> {
+            if self.finished {
+                return Err(Closed);
+            }
+            match serde_json::to_string(item) {
+                Ok(item) => self.frame(StreamEvent::Item(item)),
+                Err(e) => {
+                    let _ = self.frame(StreamEvent::Error(format!("can't serialize item: {}", e)));
+                    self.finished = true;
+                    Err(Closed)
+                }
+            }
+        }
+
+        /// Stops the stream, the client gets `error` after the items sent so far.
+        pub fn fail(mut self, error: impl Into<String>) {
+            let _ = self.frame(StreamEvent::Error(error.into()));
+            self.finished = true;
+        }
+
+        pub fn end(self) {
+            drop(self)
+        }
+
+        fn frame(&mut self, event: StreamEvent) -> Result<(), Closed> {
+            let frame = StreamFrame {
+                stream: self.stream,
+                sequence: self.sequence,
+                event,
+            };
+            self.sequence += 1;
+            self.pushes
+                .unbounded_send(Envelope::Stream(frame))
+                .map_err(|_| Closed)
+        }
+    }
+
+    impl<T> Drop for StreamSender<T> {
+        fn drop(&mut self) {
+            if !self.finished {
+                let _ = self.pushes.unbounded_send(Envelope::Stream(StreamFrame {
+                    stream: self.stream,
+                    sequence: self.sequence,
+                    event: StreamEvent::End,
+                }));
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum StreamError {
+        /// The server stopped the stream for this reason.
+        Failed(String),
+        /// An item isn't the JSON of the type the client expects.
+        Decode(String),
+        /// A frame arrived out of order, frames got lost.
+        OutOfOrder { expected: u64, got: u64 },
+        /// The connection went away before the stream ended.
+        Closed,
+    }
+
+    impl fmt::Display for StreamError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                StreamError::Failed(e) => write!(f, "the stream failed: {}", e),
+                StreamError::Decode(e) => write!(f, "invalid stream item: {}", e),
+                StreamError::OutOfOrder { expected, got } => {
+                    write!(f, "expected stream frame {}, got {}", expected, got)
+                }
+                StreamError::Closed => write!(f, "the connection closed before the stream ended"),
+            }
+        }
+    }
+
+    impl std::error::Error for StreamError {}
+
+    #[derive(Debug, Default)]
+    struct Routes {
+        last_id: u64,
+        open: HashMap<u64, mpsc::UnboundedSender<StreamFrame>>,
+    }
+
+    /// The client's streams, handing each frame the transport receives to its stream.
+    #[derive(Clone, Debug, Default)]
+    pub struct StreamRouter {
+        routes: Arc<Mutex<Routes>>,
+    }
+
+    impl StreamRouter {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// A new stream id to pass to a streaming method, and the stream of its items.
+        pub fn open<T: DeserializeOwned>(&self) -> (u64, StreamReceiver<T>) {
+            let (frames, incoming) = mpsc::unbounded();
+            let mut routes = self.routes.lock().unwrap();
+            routes.last_id += 1;
+            let id = routes.last_id;
+            routes.open.insert(id, frames);
+            let receiver = StreamReceiver {
+                frames: incoming,
+                expected: 0,
+                done: false,
+                _item: PhantomData,
+            };
+            (id, receiver)
+        }
+
+        /// Hands `frame` to its stream. Frames of streams nobody waits for are dropped.
+        pub fn route(&self, frame: StreamFrame) {
+            let mut routes = self.routes.lock().unwrap();
+            let last = !matches!(frame.event, StreamEvent::Item(_));
+            let id = frame.stream;
+            let delivered = match routes.open.get(&id) {
+                Some(stream) => stream.unbounded_send(frame).is_ok(),
+                None => false,
+            };
+            if last || !delivered {
+                routes.open.remove(&id);
+            }
+        }
+
+        /// Ends every stream with [`StreamError::Closed`], e.g. once the connection is gone.
+        pub fn close_all(&self) {
+            self.routes.lock().unwrap().open.clear();
+        }
+    }
+
+    /// The client's end of a stream, yielding its items in order.
+    pub struct StreamReceiver<T> {
+        frames: mpsc::UnboundedReceiver<StreamFrame>,
+        expected: u64,
+        done: bool,
+        _item: PhantomData<fn() -> T>,
+    }
+
+    impl<T> fmt::Debug for StreamReceiver<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("StreamReceiver")
+                .field("expected", &self.expected)
+                .field("done", &self.done)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl<T: DeserializeOwned> Stream for StreamReceiver<T> {
+        type Item = Result<T, StreamError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            if self.done {
+                return Poll::Ready(None);
+            }
+            let frame = match self.frames.poll_next_unpin(cx) {
+                Poll::Ready(Some(frame)) => frame,
+                Poll::Ready(None) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(StreamError::Closed)));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            if frame.sequence != self.expected {
+                self.done = true;
+                let error = StreamError::OutOfOrder {
+                    expected: self.expected,
+                    got: frame.sequence,
+                };
+                return Poll::Ready(Some(Err(error)));
+            }
+            self.expected += 1;
+            match frame.event {
+                StreamEvent::Item(item) => Poll::Ready(Some(
+                    serde_json::from_str(&item).map_err(|e| StreamError::Decode(e.to_string())),
+                )),
+                StreamEvent::End => {
+                    self.done = true;
+                    Poll::Ready(None)
+                }
+                StreamEvent::Error(e) => {
+                    self.done = true;
+                    Poll::Ready(Some(Err(StreamError::Failed(e))))
+                }
+            }
+        }
+    }
+}
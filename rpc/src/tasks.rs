@@ -0,0 +1,64 @@
+//! Tasks kept on the server, see `World::create_task`.
+//!
+//! A client describes the task in a [`CreateTaskRequest`] and the server answers the [`Task`]
+//! it stored, with its id and who created it when. Titles may be up to
+//! [`MAX_TITLE_LEN`] characters and tasks may have up to [`MAX_TAGS`] tags, other requests are
+//! answered [`WorldError::InvalidArgument`](crate::WorldError::InvalidArgument).
+
+use serde::{Deserialize, Serialize};
+
+/// Characters a task's title has at most.
+pub const MAX_TITLE_LEN: usize = 200;
+/// Tags a task has at most.
+pub const MAX_TAGS: usize = 10;
+
+/// How urgent a task is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// How often a task comes back once done.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Repeat {
+    #[default]
+    Never,
+    Daily,
+    Weekly,
+    /// Every this many days, at least one.
+    EveryDays(u32),
+}
+
+/// A task to create with `create_task`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreateTaskRequest {
+    /// Not empty, at most [`MAX_TITLE_LEN`] characters.
+    pub title: String,
+    pub priority: Priority,
+    /// When the task is due, in milliseconds since the Unix epoch, `None` for whenever.
+    pub due: Option<u64>,
+    /// At most [`MAX_TAGS`] of them.
+    pub tags: Vec<String>,
+    pub repeat: Repeat,
+}
+
+/// A task the server stored, as `create_task` answers it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Task {
+    /// Unique, in the order the tasks were created.
+    pub id: u64,
+    pub title: String,
+    pub priority: Priority,
+    /// When the task is due, in milliseconds since the Unix epoch, `None` for whenever.
+    pub due: Option<u64>,
+    /// Without duplicates, in the order they were given.
+    pub tags: Vec<String>,
+    pub repeat: Repeat,
+    /// The peer id of the connection that created it.
+    pub created_by: u64,
+    /// When it was created, in milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
@@ -0,0 +1,150 @@
+//! In-memory transports for exercising clients and services without a network.
+//!
+//! [`loopback`] connects two ends with channels. Messages are passed as JSON, the way the
+//! WebSocket transport sends them in text mode, so a type that doesn't survive the wire fails
+//! here as well. Nothing in this module needs a particular executor: the ends are plain
+//! streams and sinks and [`serve`] is a future to run on whatever the test uses, tokio or
+//! `wasm_bindgen_futures::spawn_local`.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::{Sink, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoopbackError {
+    /// The other end was dropped or closed.
+    Closed,
+    /// A message couldn't be serialized.
+    Encode(String),
+    /// A message couldn't be deserialized as the type this end expects.
+    Decode(String),
+}
+
+impl fmt::Display for LoopbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoopbackError::Closed => write!(f, "the other end of the loopback is gone"),
+            LoopbackError::Encode(e) => write!(f, "can't serialize message: {}", e),
+            LoopbackError::Decode(e) => write!(f, "can't deserialize message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoopbackError {}
+
+/// One end of a [`loopback`], receiving `Item`s and sending `SinkItem`s.
+pub struct Loopback<Item, SinkItem> {
+    incoming: mpsc::UnboundedReceiver<String>,
+    outgoing: mpsc::UnboundedSender<String>,
+    _types: PhantomData<fn(SinkItem) -> Item>,
+}
+
+impl<Item, SinkItem> fmt::Debug for Loopback<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Loopback")
+            .field("closed", &self.outgoing.is_closed())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns two connected ends, what is sent into one comes out of the other.
+///
+/// For tarpc the first end goes to the client and the second to the server:
+///
+/// ```ignore
+/// let (client, server) = rpc::testing::loopback();
+/// spawn_local(rpc::testing::serve(server, WorldImpl::default().serve()));
+/// let client = WorldClient::new(tarpc::client::Config::default(), client).spawn();
+/// ```
+pub fn loopback<Item, SinkItem>() -> (Loopback<Item, SinkItem>, Loopback<SinkItem, Item>) {
+    let (a_tx, a_rx) = mpsc::unbounded();
+    let (b_tx, b_rx) = mpsc::unbounded();
+    let a = Loopback {
+        incoming: b_rx,
+        outgoing: a_tx,
+        _types: PhantomData,
+    };
+    let b = Loopback {
+        incoming: a_rx,
+        outgoing: b_tx,
+        _types: PhantomData,
+    };
+    (a, b)
+}
+
+impl<Item, SinkItem> Stream for Loopback<Item, SinkItem>
+where
+    Item: DeserializeOwned,
+{
+    type Item = Result<Item, LoopbackError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx).map(|text| {
+            text.map(|text| {
+                serde_json::from_str(&text).map_err(|e| LoopbackError::Decode(e.to_string()))
+            })
+        })
+    }
+}
+
+impl<Item, SinkItem> Sink<SinkItem> for Loopback<Item, SinkItem>
+where
+    SinkItem: Serialize,
+{
+    type Error = LoopbackError;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.outgoing.is_closed() {
+            Poll::Ready(Err(LoopbackError::Closed))
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> Result<(), Self::Error> {
+        let text =
+            serde_json::to_string(&item).map_err(|e| LoopbackError::Encode(e.to_string()))?;
+        self.outgoing
+            .unbounded_send(text)
+            .map_err(|_| LoopbackError::Closed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.outgoing.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Answers the requests arriving on `transport` with `service` until the client end goes
+/// away, handling them concurrently. Stops at the first error of the transport: tarpc keeps
+/// reporting the closed end on every poll after that.
+#[cfg(feature = "server")]
+pub async fn serve<Req, S>(
+    transport: Loopback<tarpc::ClientMessage<Req>, tarpc::Response<S::Resp>>,
+    service: S,
+) where
+    Req: DeserializeOwned,
+    S: tarpc::server::Serve<Req> + Clone,
+    S::Resp: Serialize,
+{
+    use futures::TryStreamExt;
+    use tarpc::server::{BaseChannel, Channel};
+
+    let _ = BaseChannel::with_defaults(transport)
+        .requests()
+        .try_for_each_concurrent(None, |request| async {
+            request.execute(service.clone()).await;
+            Ok(())
+        })
+        .await;
+}
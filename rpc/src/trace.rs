@@ -0,0 +1,14 @@
+//! How both ends write down which call a log line is about.
+//!
+//! tarpc sends the trace id of the client's [`Context`] along with every request, and the
+//! server's handlers get it back in theirs. Clients and the server log it through [`label`],
+//! so a line from the browser's console can be searched for in the server's log as it is.
+//! The request ids tarpc numbers calls with per connection only show in the transports' trace
+//! logs, the handlers never see them.
+
+use tarpc::context::Context;
+
+/// `trace <id>` for the call made with `ctx`.
+pub fn label(ctx: &Context) -> String {
+    format!("trace {}", ctx.trace_id())
+}
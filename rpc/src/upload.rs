@@ -0,0 +1,117 @@
+//! Files sent to the server in chunks, see `World::upload_begin`.
+//!
+//! A client begins an upload with the file's name and size and gets its id, then sends the
+//! bytes with `upload_chunk` in chunks of at most [`MAX_CHUNK_LEN`], in any order and several
+//! at once, and ends it with `upload_finish` and the [`checksum`] of the whole file. The server
+//! only keeps the file once every byte arrived exactly once and the checksums agree; it fails
+//! the calls with the [`UploadError`] saying what went wrong otherwise.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Bytes one `upload_chunk` call carries at most.
+pub const MAX_CHUNK_LEN: usize = 1024 * 1024;
+
+/// Why an upload call failed, in [`WorldError::Upload`](crate::WorldError::Upload).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UploadError {
+    /// No upload has this id, it finished already or was abandoned.
+    UnknownUpload,
+    /// The file is larger than the server takes, which is this many bytes.
+    TooLarge(u64),
+    /// A chunk is larger than [`MAX_CHUNK_LEN`] or empty.
+    InvalidChunk,
+    /// A chunk at this offset arrived already.
+    DuplicateChunk(u64),
+    /// The chunk at this offset overlaps bytes another chunk brought.
+    Overlapping(u64),
+    /// The chunk at this offset ends past the size the upload began with.
+    PastEnd(u64),
+    /// The upload finished without the bytes from this offset on.
+    Missing(u64),
+    /// The bytes received have this checksum, not the one the upload finished with.
+    ChecksumMismatch(u32),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::UnknownUpload => write!(f, "no such upload"),
+            UploadError::TooLarge(max) => write!(f, "the file is larger than {} bytes", max),
+            UploadError::InvalidChunk => {
+                write!(f, "chunks carry 1 to {} bytes", MAX_CHUNK_LEN)
+            }
+            UploadError::DuplicateChunk(offset) => {
+                write!(f, "the chunk at {} arrived already", offset)
+            }
+            UploadError::Overlapping(offset) => {
+                write!(f, "the chunk at {} overlaps another one", offset)
+            }
+            UploadError::PastEnd(offset) => {
+                write!(f, "the chunk at {} ends past the end of the file", offset)
+            }
+            UploadError::Missing(offset) => write!(f, "the bytes from {} on are missing", offset),
+            UploadError::ChecksumMismatch(checksum) => {
+                write!(f, "the bytes received have the checksum {:#010x}", checksum)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// A file the server kept, as `upload_finish` answers it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadResult {
+    /// The id the upload had.
+    pub id: u64,
+    /// The name the upload began with.
+    pub name: String,
+    /// Bytes kept.
+    pub size: u64,
+    /// Their [`checksum`].
+    pub checksum: u32,
+}
+
+/// The Adler-32 checksum of [`Checksum`] for all of `bytes` at once.
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut checksum = Checksum::default();
+    checksum.update(bytes);
+    checksum.finish()
+}
+
+/// The Adler-32 checksum `upload_finish` takes, over bytes given in order.
+#[derive(Clone, Copy, Debug)]
+pub struct Checksum(u32);
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+/// The modulus of both Adler-32 sums.
+const ADLER_MOD: u32 = 65521;
+
+/// Bytes summed before the sums have to be reduced, so they can't overflow a `u32`.
+const ADLER_CHUNK: usize = 5552;
+
+impl Checksum {
+    pub fn update(&mut self, bytes: &[u8]) {
+        let (mut a, mut b) = (self.0 & 0xffff, self.0 >> 16);
+        for chunk in bytes.chunks(ADLER_CHUNK) {
+            for &byte in chunk {
+                a += u32::from(byte);
+                b += a;
+            }
+            a %= ADLER_MOD;
+            b %= ADLER_MOD;
+        }
+        self.0 = (b << 16) | a;
+    }
+
+    pub fn finish(self) -> u32 {
+        self.0
+    }
+}
@@ -0,0 +1,21 @@
+//! What build of the server answers, see `World::version`.
+//!
+//! Servers add fields here as they learn to tell more. Clients ignore the fields they don't
+//! know and take the defaults for those an older server leaves out, so either side can be
+//! upgraded first.
+
+use serde::{Deserialize, Serialize};
+
+/// The server's build, as `version` answers it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VersionInfo {
+    /// The version of the server's crate, e.g. `0.1.0`.
+    pub crate_version: String,
+    /// The commit the server was built from, abbreviated, `unknown` outside a git checkout.
+    pub git_hash: String,
+    /// When the server was built, in seconds since the Unix epoch.
+    pub built_at: u64,
+    /// The protocol versions the server accepts in its handshake, see `rpc::handshake`.
+    pub protocols: Vec<u32>,
+}
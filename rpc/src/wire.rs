@@ -0,0 +1,164 @@
+//! How [`Envelope`]s are put into WebSocket messages, the same on every transport and on both
+//! ends.
+//!
+//! Text messages carry one envelope as serialized by the [`Codec`], JSON by default. Binary
+//! messages carry it behind the header of [`crate::compression`], each preceded by the length
+//! prefix of [`crate::framing`]; a single WebSocket message can hold several, so
+//! [`decode_binary`] takes one out of a [`FrameDecoder`](crate::framing::FrameDecoder). The
+//! functions without a codec parameter use JSON for text and bincode for binary messages, and
+//! need the features of those codecs.
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+#[cfg(feature = "bincode")]
+use crate::codec::Bincode;
+use crate::codec::Codec;
+#[cfg(feature = "json")]
+use crate::codec::Json;
+use crate::compression::{self, CompressionError};
+use crate::framing::HEADER_LEN;
+use crate::Envelope;
+
+#[derive(Debug)]
+pub enum WireError {
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    #[cfg(feature = "bincode")]
+    Bincode(bincode::Error),
+    Compression(CompressionError),
+    /// A text codec wrote something that isn't UTF-8.
+    NotText,
+    /// The error of any other codec.
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "json")]
+            WireError::Json(e) => write!(f, "invalid JSON message: {}", e),
+            #[cfg(feature = "bincode")]
+            WireError::Bincode(e) => write!(f, "invalid bincode message: {}", e),
+            WireError::Compression(e) => write!(f, "{}", e),
+            WireError::NotText => write!(f, "text message isn't UTF-8"),
+            WireError::Codec(e) => write!(f, "invalid message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+impl WireError {
+    /// The error of a codec without a variant of its own.
+    pub fn codec(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        WireError::Codec(e.into())
+    }
+}
+
+#[cfg(feature = "json")]
+pub fn encode_text<T: Serialize>(envelope: &Envelope<T>) -> Result<String, WireError> {
+    serde_json::to_string(envelope).map_err(WireError::Json)
+}
+
+#[cfg(feature = "json")]
+pub fn decode_text<T: DeserializeOwned>(text: &str) -> Result<Envelope<T>, WireError> {
+    serde_json::from_str(text).map_err(WireError::Json)
+}
+
+/// Serializes a message for a binary frame, compressing it when larger than `compress_above`.
+/// The length prefix isn't included.
+#[cfg(feature = "bincode")]
+pub fn encode_binary<T: Serialize>(
+    envelope: &Envelope<T>,
+    compress_above: Option<usize>,
+) -> Result<Vec<u8>, WireError> {
+    let mut payload = Vec::new();
+    Bincode.encode(envelope, &mut payload)?;
+    Ok(compression::compress(&payload, compress_above))
+}
+
+/// Decodes a message taken out of a binary frame, inflating it to at most `max_len` bytes.
+#[cfg(feature = "bincode")]
+pub fn decode_binary<T: DeserializeOwned>(
+    message: &[u8],
+    max_len: usize,
+) -> Result<Envelope<T>, WireError> {
+    decode_message(&Bincode, message, max_len)
+}
+
+/// Like [`decode_binary`], for a message serialized by `codec`.
+pub fn decode_message<C: Codec, T: DeserializeOwned>(
+    codec: &C,
+    message: &[u8],
+    max_len: usize,
+) -> Result<Envelope<T>, WireError> {
+    let payload = compression::decompress(message, max_len).map_err(WireError::Compression)?;
+    codec.decode(&payload)
+}
+
+/// Contents of a WebSocket message, text or binary as the codec wants.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    /// A binary message with its length prefix.
+    Binary(Vec<u8>),
+}
+
+/// Serializes envelopes through a buffer kept from one message to the next.
+///
+/// [`encode_text`] and [`encode_binary`] grow a fresh buffer for every message, and binary
+/// messages are copied twice more on the way into a frame. Here the message is serialized
+/// into the kept buffer and the frame is allocated once, at its final size.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    scratch: Vec<u8>,
+}
+
+impl Encoder {
+    #[cfg(feature = "json")]
+    pub fn text<T: Serialize>(&mut self, envelope: &Envelope<T>) -> Result<String, WireError> {
+        match self.frame(&Json, envelope, None)? {
+            Frame::Text(text) => Ok(text),
+            Frame::Binary(_) => unreachable!("JSON goes in text frames"),
+        }
+    }
+
+    /// The binary message with its length prefix, compressed when larger than
+    /// `compress_above`.
+    #[cfg(feature = "bincode")]
+    pub fn binary_frame<T: Serialize>(
+        &mut self,
+        envelope: &Envelope<T>,
+        compress_above: Option<usize>,
+    ) -> Result<Vec<u8>, WireError> {
+        match self.frame(&Bincode, envelope, compress_above)? {
+            Frame::Binary(frame) => Ok(frame),
+            Frame::Text(_) => unreachable!("bincode goes in binary frames"),
+        }
+    }
+
+    /// `envelope` serialized by `codec`. Binary messages are compressed when larger than
+    /// `compress_above`, text messages never are.
+    pub fn frame<C: Codec, T: Serialize>(
+        &mut self,
+        codec: &C,
+        envelope: &Envelope<T>,
+        compress_above: Option<usize>,
+    ) -> Result<Frame, WireError> {
+        self.scratch.clear();
+        codec.encode(envelope, &mut self.scratch)?;
+        if codec.text() {
+            let text = std::str::from_utf8(&self.scratch).map_err(|_| WireError::NotText)?;
+            return Ok(Frame::Text(text.to_string()));
+        }
+        let mut frame = Vec::with_capacity(HEADER_LEN + 1 + self.scratch.len());
+        frame.extend_from_slice(&[0; HEADER_LEN]);
+        compression::compress_into(&self.scratch, compress_above, &mut frame);
+        let len = u32::try_from(frame.len() - HEADER_LEN).expect("message larger than 4 GiB");
+        frame[..HEADER_LEN].copy_from_slice(&len.to_be_bytes());
+        Ok(Frame::Binary(frame))
+    }
+}
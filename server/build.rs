@@ -0,0 +1,36 @@
+//! Tells the server which build it is, for `World::version`: the commit it was built from in
+//! `GIT_HASH` and when in `BUILD_TIMESTAMP`, in seconds since the Unix epoch.
+//!
+//! Builds outside a git checkout, or without git, get the hash `unknown`. `SOURCE_DATE_EPOCH`
+//! overrides the timestamp, for builds that should come out the same every time.
+
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", hash);
+
+    let built_at: u64 = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse().expect("SOURCE_DATE_EPOCH isn't in seconds"),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("the clock is before the Unix epoch")
+            .as_secs(),
+    };
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", built_at);
+}
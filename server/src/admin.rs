@@ -0,0 +1,116 @@
+//! Commands an admin types into the server's terminal, and the [`Admin`] service that takes
+//! them over a connection.
+//!
+//! `announce <text>` shows `text` on every connected page, e.g.
+//! `announce Maintenance in 5 minutes`. The server keeps running without a terminal; reading
+//! just stops once stdin is closed.
+
+use std::sync::Arc;
+
+use log::info;
+use rpc::admin::Admin;
+use rpc::trace::label;
+use rpc::WorldError;
+use tarpc::context::Context;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::rate::RateLimiter;
+use crate::service_impl::{Peers, Session};
+
+/// [`Admin`] for the connection of `session`, taking tokens from its `rate` like `World` does.
+#[derive(Clone)]
+pub struct AdminImpl {
+    session: Arc<Session>,
+    peers: Arc<Peers>,
+    rate: RateLimiter,
+}
+
+impl AdminImpl {
+    pub fn new(session: Arc<Session>, peers: Arc<Peers>, rate: RateLimiter) -> Self {
+        Self {
+            session,
+            peers,
+            rate,
+        }
+    }
+}
+
+#[tarpc::server]
+#[async_trait::async_trait]
+impl Admin for AdminImpl {
+    async fn connections(self, ctx: Context) -> Result<u64, WorldError> {
+        self.rate.admit("connections")?;
+        if !self.session.admin {
+            info!("Peer {} may not count the connections ({})", self.session.id, label(&ctx));
+            return Err(WorldError::PermissionDenied(
+                "only admins may count the connections".into(),
+            ));
+        }
+        info!("Peer {} counted the connections ({})", self.session.id, label(&ctx));
+        Ok(self.peers.connections() as u64)
+    }
+    async fn announce(self, ctx: Context, text: String) -> Result<u64, WorldError> {
+        self.rate.admit("announce")?;
+        if !self.session.admin {
+            info!("Peer {} may not announce ({})", self.session.id, label(&ctx));
+            return Err(WorldError::PermissionDenied("only admins may announce".into()));
+        }
+        let reached = self.peers.announce(&text);
+        info!(
+            "Peer {} announced {:?} to {} peers ({})",
+            self.session.id,
+            text,
+            reached,
+            label(&ctx)
+        );
+        Ok(reached as u64)
+    }
+}
+
+/// Runs the commands typed into stdin until it is closed.
+pub async fn read_commands(peers: Arc<Peers>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                info!("Not reading commands anymore: {}", e);
+                break;
+            }
+        };
+        let (command, argument) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        match (command, argument.trim()) {
+            ("", _) => (),
+            ("announce", "") => info!("Usage: announce <text>"),
+            ("announce", text) => {
+                let reached = peers.announce(text);
+                info!("Announced {:?} to {} peers", text, reached);
+            }
+            (command, _) => info!("Unknown command {:?}, try: announce <text>", command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{connect, connect_as, uploads};
+
+    #[tokio::test]
+    async fn only_admins_announce() {
+        let peers = Arc::new(Peers::default());
+        let uploads = uploads();
+        let admin = connect_as(&peers, &uploads, true);
+        let other = connect(&peers, &uploads);
+
+        let reached = other.admin.clone().announce(tarpc::context::current(), "hi".into()).await;
+        assert!(
+            matches!(reached, Err(WorldError::PermissionDenied(_))),
+            "{:?}",
+            reached
+        );
+        let reached = admin.admin.clone().announce(tarpc::context::current(), "hi".into()).await;
+        assert_eq!(reached, Ok(2));
+    }
+}
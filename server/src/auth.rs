@@ -0,0 +1,328 @@
+//! Token check for new connections, run before they are handed to `WorldImpl`.
+//!
+//! Browsers can't set headers on a WebSocket upgrade, so clients pass their bearer token either
+//! as the `token` query parameter or in an [`Envelope::Auth`] right after the socket opened.
+//! Either way the server answers with [`Envelope::AuthAck`] before serving RPCs, and closes
+//! with [`UNAUTHORIZED`] when the token is missing or rejected. The [`Identity`] the
+//! [`Authenticator`] finds for the token stays with the connection for the handlers.
+//!
+//! Serving clients without a token has to be asked for, see
+//! [`ServerConfig::allow_anonymous`](crate::config::ServerConfig::allow_anonymous).
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::CloseFrame;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{SinkExt, StreamExt};
+use log::info;
+use rpc::base64;
+use rpc::codec::Codec;
+use rpc::envelope::UNAUTHORIZED;
+use rpc::framing::FrameDecoder;
+use rpc::Envelope;
+
+use crate::config::ServerConfig;
+use crate::transport::{self, decode_message};
+
+/// Who holds the token of `--auth-token`.
+pub const SHARED_IDENTITY: &str = "shared";
+
+/// How long a client may take to send its token after the socket opened.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Who a connection's token belongs to, as the [`Authenticator`] says.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Identity(pub String);
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The identities allowed to call the admin methods, from `--admins`.
+#[derive(Clone, Debug, Default)]
+pub struct Admins(HashSet<Identity>);
+
+impl Admins {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        let names = config.admins.as_deref().unwrap_or_default().split(',');
+        let admins = names
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| Identity(name.into()))
+            .collect();
+        Self(admins)
+    }
+
+    /// Anonymous connections never are admins.
+    pub fn contains(&self, identity: Option<&Identity>) -> bool {
+        identity.is_some_and(|identity| self.0.contains(identity))
+    }
+}
+
+/// Decides whether a token may use the server, and whose it is. Closures taking the token
+/// are authenticators too.
+pub trait Authenticator: Send + Sync {
+    /// `None` rejects the token.
+    fn authenticate(&self, token: &str) -> Option<Identity>;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&str) -> Option<Identity> + Send + Sync,
+{
+    fn authenticate(&self, token: &str) -> Option<Identity> {
+        self(token)
+    }
+}
+
+/// Accepts a fixed set of tokens, each as its own identity.
+#[derive(Clone, Default)]
+pub struct Tokens(HashMap<String, Identity>);
+
+impl Tokens {
+    pub fn with(mut self, token: impl Into<String>, identity: impl Into<String>) -> Self {
+        self.0.insert(token.into(), Identity(identity.into()));
+        self
+    }
+}
+
+impl Authenticator for Tokens {
+    fn authenticate(&self, token: &str) -> Option<Identity> {
+        self.0.get(token).cloned()
+    }
+}
+
+/// Who may connect.
+#[derive(Clone)]
+pub enum Access {
+    /// Everyone, with or without a token. Servers only run like this when told to.
+    Anonymous,
+    /// Whoever presents a token the authenticator accepts.
+    Token(Arc<dyn Authenticator>),
+}
+
+impl fmt::Debug for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Anonymous => f.write_str("Anonymous"),
+            Access::Token(_) => f.write_str("Token"),
+        }
+    }
+}
+
+/// Why a connection was turned away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejected {
+    NoToken,
+    InvalidToken,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::NoToken => f.write_str("no token"),
+            Rejected::InvalidToken => f.write_str("invalid token"),
+        }
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// The tokens of `--auth-token` and `--auth-tokens`, or everyone without either. Whether
+/// serving everyone was asked for is checked by [`ServerConfig::validate`].
+pub fn from_config(config: &ServerConfig) -> Access {
+    let mut tokens = Tokens::default();
+    if let Some(token) = config.auth_token.as_deref().filter(|token| !token.is_empty()) {
+        tokens = tokens.with(token, SHARED_IDENTITY);
+    }
+    if let Some(pairs) = &config.auth_tokens {
+        for pair in pairs.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            // Entries without a name don't get past `ServerConfig::validate`.
+            if let Some((name, token)) = pair.split_once('=') {
+                tokens = tokens.with(token.trim(), name.trim());
+            }
+        }
+    }
+    if !tokens.0.is_empty() {
+        info!("Connections need one of the tokens of --auth-token or --auth-tokens");
+        return Access::Token(Arc::new(tokens));
+    }
+    info!("Serving connections without a token");
+    Access::Anonymous
+}
+
+/// The `token` parameter of a request's query string.
+pub fn query_token(query: Option<&str>) -> Option<String> {
+    form_urlencoded::parse(query?.as_bytes())
+        .find(|(key, _)| key == "token")
+        .map(|(_, token)| token.into_owned())
+}
+
+/// Who `token` belongs to if `access` lets it in. Anonymous connections have no identity.
+pub fn check(access: &Access, token: Option<&str>) -> Result<Option<Identity>, Rejected> {
+    match access {
+        Access::Token(authenticator) => {
+            let token = token.ok_or(Rejected::NoToken)?;
+            match authenticator.authenticate(token) {
+                Some(identity) => Ok(Some(identity)),
+                None => Err(Rejected::InvalidToken),
+            }
+        }
+        Access::Anonymous => Ok(None),
+    }
+}
+
+/// Checks the token of a freshly accepted WebSocket, taken from the URL or, failing that, from
+/// the first message, and answers who it belongs to. Closes the socket and returns `Err` if it
+/// doesn't pass.
+pub async fn authenticate<S, C>(
+    ws: &mut WebSocketStream<S>,
+    codec: &C,
+    base64: bool,
+    query_token: Option<String>,
+    access: &Access,
+) -> Result<Option<Identity>, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    let token = match (query_token, access) {
+        (Some(token), _) => Some(token),
+        // Anonymous clients sending a token later just get it acked by the transport.
+        (None, Access::Anonymous) => return Ok(None),
+        (None, Access::Token(_)) => first_message_token(ws, codec, base64).await,
+    };
+    match check(access, token.as_deref()) {
+        Ok(identity) => {
+            let ack = transport::encode(codec, &Envelope::<()>::AuthAck, false)
+                .map_err(|e| e.to_string())?;
+            let ack = transport::armor(ack, base64);
+            ws.send(ack).await.map_err(|e| e.to_string())?;
+            Ok(identity)
+        }
+        Err(rejected) => {
+            let close = CloseFrame {
+                code: CloseCode::from(UNAUTHORIZED),
+                reason: "missing or invalid token".into(),
+            };
+            let _ = ws.close(Some(close)).await;
+            Err(rejected.to_string())
+        }
+    }
+}
+
+async fn first_message_token<S, C>(
+    ws: &mut WebSocketStream<S>,
+    codec: &C,
+    base64: bool,
+) -> Option<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    let message = match tokio::time::timeout(AUTH_TIMEOUT, ws.next()).await.ok()?? {
+        Ok(Message::Text(text)) if base64 => Ok(Message::Binary(base64::decode(&text).ok()?)),
+        message => message,
+    };
+    let envelope = match message {
+        Ok(Message::Text(text)) => codec.decode_text(&text).ok()?,
+        Ok(Message::Binary(bytes)) => {
+            let mut decoder = FrameDecoder::default();
+            decoder.extend(&bytes);
+            decode_message(codec, &decoder.next_frame().ok()??).ok()?
+        }
+        _ => return None,
+    };
+    match envelope {
+        Envelope::<()>::Auth(token) => Some(token),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_tungstenite::tokio::TokioAdapter;
+    use async_tungstenite::tungstenite::protocol::Role;
+    use rpc::codec::Json;
+    use tokio::io::DuplexStream;
+
+    type Socket = WebSocketStream<TokioAdapter<DuplexStream>>;
+
+    /// Both ends of an upgraded connection, without the HTTP request.
+    async fn sockets() -> (Socket, Socket) {
+        let (server, client) = tokio::io::duplex(4096);
+        let server =
+            WebSocketStream::from_raw_socket(TokioAdapter::new(server), Role::Server, None).await;
+        let client =
+            WebSocketStream::from_raw_socket(TokioAdapter::new(client), Role::Client, None).await;
+        (server, client)
+    }
+
+    fn alice() -> Access {
+        Access::Token(Arc::new(Tokens::default().with("secret", "alice")))
+    }
+
+    async fn send(client: &mut Socket, envelope: Envelope<()>) {
+        let message = transport::encode(&Json, &envelope, false).unwrap();
+        client.send(message).await.unwrap();
+    }
+
+    async fn close_code(client: &mut Socket) -> Option<u16> {
+        match client.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => Some(frame.code.into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn tokens_are_checked_against_the_authenticator() {
+        let alice_id = Identity("alice".into());
+        assert_eq!(check(&alice(), Some("secret")), Ok(Some(alice_id)));
+        assert_eq!(check(&alice(), Some("guess")), Err(Rejected::InvalidToken));
+        assert_eq!(check(&alice(), None), Err(Rejected::NoToken));
+        assert_eq!(check(&Access::Anonymous, None), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn a_valid_token_is_acked() {
+        let (mut server, mut client) = sockets().await;
+        send(&mut client, Envelope::Auth("secret".into())).await;
+
+        let identity = authenticate(&mut server, &Json, false, None, &alice()).await;
+        assert_eq!(identity, Ok(Some(Identity("alice".into()))));
+        let ack = match client.next().await {
+            Some(Ok(Message::Text(ack))) => Json.decode_text::<Envelope<()>>(&ack).unwrap(),
+            other => panic!("expected the ack, got {:?}", other),
+        };
+        assert!(matches!(ack, Envelope::AuthAck));
+    }
+
+    #[tokio::test]
+    async fn an_invalid_token_is_closed_as_unauthorized() {
+        let (mut server, mut client) = sockets().await;
+
+        let query = Some("guess".to_string());
+        let identity = authenticate(&mut server, &Json, false, query, &alice()).await;
+        assert_eq!(identity, Err("invalid token".to_string()));
+        assert_eq!(close_code(&mut client).await, Some(UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn a_missing_token_is_closed_as_unauthorized() {
+        let (mut server, mut client) = sockets().await;
+        send(&mut client, Envelope::Heartbeat).await;
+
+        let identity = authenticate(&mut server, &Json, false, None, &alice()).await;
+        assert_eq!(identity, Err("no token".to_string()));
+        assert_eq!(close_code(&mut client).await, Some(UNAUTHORIZED));
+    }
+}
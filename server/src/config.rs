@@ -0,0 +1,755 @@
+//! Everything the server can be told at startup, see [`ServerConfig`].
+//!
+//! Every option is a flag, and falls back to the environment variable named in `--help` when
+//! the flag isn't given, e.g. `--ws-port 9000` or `WS_PORT=9000`. Ports and the metrics
+//! address also take `off` to not serve them at all. `--print-config` shows the values the
+//! server would run with, tokens left out, and exits.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::builder::FalseyValueParser;
+use clap::Parser;
+
+use crate::deadline::Expired;
+use crate::inflight::DEFAULT_MAX_IN_FLIGHT;
+use crate::ip_filter::{FilterOrder, InvalidCidr, IpFilter};
+use crate::limit::DEFAULT_MAX_CONNECTIONS;
+use crate::middleware::{Middleware, DEFAULT_LAYERS};
+use crate::origin::{self, InvalidOrigin, OriginPolicy};
+use crate::service_impl::DEFAULT_MAX_DELAY;
+use crate::shutdown::DEFAULT_DRAIN_TIMEOUT;
+#[cfg(feature = "test-util")]
+use crate::control;
+#[cfg(feature = "sse")]
+use crate::sse;
+use crate::uploads;
+#[cfg(feature = "webtransport")]
+use crate::webtransport;
+use crate::{health, prometheus, tcp, web};
+
+/// Something served unless it is turned `off`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Toggle<T> {
+    Off,
+    On(T),
+}
+
+impl<T> Toggle<T> {
+    pub fn on(self) -> Option<T> {
+        match self {
+            Toggle::Off => None,
+            Toggle::On(value) => Some(value),
+        }
+    }
+}
+
+impl<T: FromStr> FromStr for Toggle<T>
+where
+    T::Err: fmt::Display,
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Toggle::Off),
+            s => s
+                .parse()
+                .map(Toggle::On)
+                .map_err(|e| format!("{} (or `off`)", e)),
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Toggle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Toggle::Off => f.write_str("off"),
+            Toggle::On(value) => value.fmt(f),
+        }
+    }
+}
+
+/// The certificate chain and PKCS#8 key TLS is served with, both PEM encoded.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// How the server runs, from its flags and environment.
+#[derive(Clone, Debug, Parser)]
+#[command(
+    version,
+    about = "Serves the World service to browsers and native clients"
+)]
+pub struct ServerConfig {
+    /// Address every listener binds to.
+    #[arg(long, env = "HOST", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub host: IpAddr,
+
+    /// Port of the WebSocket listener.
+    #[arg(long, env = "WS_PORT", default_value_t = Toggle::On(web::DEFAULT_PORT))]
+    pub ws_port: Toggle<u16>,
+
+    /// Port of plain tarpc over TCP, only served to anonymous clients.
+    #[arg(long, env = "TCP_PORT", default_value_t = Toggle::On(tcp::DEFAULT_PORT))]
+    pub tcp_port: Toggle<u16>,
+
+    /// UDP port of the WebTransport listener.
+    #[cfg(feature = "webtransport")]
+    #[arg(
+        long,
+        env = "WEBTRANSPORT_PORT",
+        default_value_t = Toggle::On(webtransport::DEFAULT_PORT)
+    )]
+    pub webtransport_port: Toggle<u16>,
+
+    /// Port of the server-sent events endpoints.
+    #[cfg(feature = "sse")]
+    #[arg(long, env = "SSE_PORT", default_value_t = Toggle::On(sse::DEFAULT_PORT))]
+    pub sse_port: Toggle<u16>,
+
+    /// Port of `/healthz` and `/readyz`.
+    #[arg(long, env = "HEALTH_PORT", default_value_t = Toggle::On(health::DEFAULT_PORT))]
+    pub health_port: Toggle<u16>,
+
+    /// Port of the control service tests shut the server down with, on the loopback address.
+    #[cfg(feature = "test-util")]
+    #[arg(long, env = "CONTROL_PORT", default_value_t = Toggle::On(control::DEFAULT_PORT))]
+    pub control_port: Toggle<u16>,
+
+    /// Where Prometheus scrapes `/metrics`.
+    #[arg(
+        long,
+        env = "METRICS_ADDR",
+        default_value_t = Toggle::On(prometheus::DEFAULT_ADDR.parse().unwrap())
+    )]
+    pub metrics_addr: Toggle<SocketAddr>,
+
+    /// What gets logged, as `RUST_LOG` takes it, e.g. `info` or `server=debug`. `RUST_LOG`
+    /// applies when this isn't given.
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Connections open at once, over every listener.
+    #[arg(long, env = "MAX_CONNECTIONS", default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    pub max_connections: usize,
+
+    /// Seconds the calls running at shutdown get to finish.
+    #[arg(long, env = "DRAIN_TIMEOUT", default_value_t = DEFAULT_DRAIN_TIMEOUT.as_secs())]
+    pub drain_timeout: u64,
+
+    /// Calls one connection runs at once.
+    #[arg(long, env = "MAX_IN_FLIGHT", default_value_t = DEFAULT_MAX_IN_FLIGHT)]
+    pub max_in_flight: usize,
+
+    /// Calls of one connection waiting for `--max-in-flight` to let them run, further calls are
+    /// answered `Busy` right away. With 0 every call over the limit is.
+    #[arg(long, env = "IN_FLIGHT_QUEUE", default_value_t = 0)]
+    pub in_flight_queue: usize,
+
+    /// What clients hear of calls still running at their deadline.
+    #[arg(long, env = "EXPIRED_CALLS", value_enum, default_value_t = Expired::Answer)]
+    pub expired_calls: Expired,
+
+    /// What runs around every call, outermost first, separated by commas. Leaving one out
+    /// turns it off.
+    #[arg(
+        long,
+        env = "LAYERS",
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = DEFAULT_LAYERS.to_vec()
+    )]
+    pub layers: Vec<Middleware>,
+
+    /// Log one of this many calls of each connection, whatever their outcome, none with 0.
+    /// Failed calls are logged anyway.
+    #[arg(long, env = "REQUEST_LOG_EVERY", default_value_t = 1)]
+    pub request_log_every: u64,
+
+    /// Rates as `method=per_second/burst` entries, `*` for every other method.
+    #[arg(long, env = "RATE_LIMITS")]
+    pub rate_limits: Option<String>,
+
+    /// Bytes of arguments as `method=bytes` entries, `*` for every other method, see
+    /// [`crate::payload`].
+    #[arg(long, env = "PAYLOAD_LIMITS")]
+    pub payload_limits: Option<String>,
+
+    /// Seconds each method may run as `method=seconds` entries, `none` for no limit and `*`
+    /// for every other method, see [`crate::timeouts`].
+    #[arg(long, env = "METHOD_TIMEOUTS")]
+    pub method_timeouts: Option<String>,
+
+    /// Directory uploaded files are kept in, `uploads` in the temp directory if not given.
+    #[arg(long, env = "UPLOAD_DIR")]
+    pub upload_dir: Option<PathBuf>,
+
+    /// Bytes one uploaded file may have.
+    #[arg(long, env = "MAX_UPLOAD_SIZE", default_value_t = uploads::DEFAULT_MAX_SIZE)]
+    pub max_upload_size: u64,
+
+    /// Seconds an upload waits for its next chunk before it is abandoned.
+    #[arg(long, env = "UPLOAD_TIMEOUT", default_value_t = uploads::DEFAULT_TIMEOUT.as_secs())]
+    pub upload_timeout: u64,
+
+    /// Seconds `delay` and `delay_with_progress` may wait, longer delays are rejected.
+    #[arg(long, env = "MAX_DELAY", default_value_t = DEFAULT_MAX_DELAY.as_secs())]
+    pub max_delay: u64,
+
+    /// Directory of the built client, e.g. trunk's `dist/`, served on the WebSocket port to
+    /// requests that aren't upgrades.
+    #[arg(long, env = "STATIC_DIR")]
+    pub static_dir: Option<PathBuf>,
+
+    /// PEM certificate chain to serve `wss://` with, needs `--tls-key`.
+    #[arg(long, env = "TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM PKCS#8 private key of `--tls-cert`.
+    #[arg(long, env = "TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Token every client may connect with, as the identity `shared`.
+    #[arg(long, env = "AUTH_TOKEN", hide_env_values = true)]
+    pub auth_token: Option<String>,
+
+    /// `name=token` pairs separated by commas, each token connecting as its name.
+    #[arg(long, env = "AUTH_TOKENS", hide_env_values = true)]
+    pub auth_tokens: Option<String>,
+
+    /// Identities that may call the admin methods, e.g. `list_clients`, separated by commas.
+    #[arg(long, env = "ADMINS")]
+    pub admins: Option<String>,
+
+    /// Serve clients without a token, when no tokens are given.
+    #[arg(long, env = "ALLOW_ANONYMOUS", value_parser = FalseyValueParser::new())]
+    pub allow_anonymous: bool,
+
+    /// Origins of the pages allowed to open WebSockets, comma separated, e.g.
+    /// `https://example.com,https://*.example.com`, or `*` for any page.
+    #[arg(long, env = "ALLOWED_ORIGINS", default_value = origin::DEFAULT_ORIGINS)]
+    pub allowed_origins: String,
+
+    /// Let WebSocket clients without an `Origin` header in, e.g. native ones.
+    #[arg(long, env = "ALLOW_MISSING_ORIGIN", value_parser = FalseyValueParser::new())]
+    pub allow_missing_origin: bool,
+
+    /// Address ranges allowed to connect, comma separated, e.g. `10.0.0.0/8,fd00::/8`. Every
+    /// address the deny list doesn't name if not given.
+    #[arg(long, env = "ALLOW_IPS")]
+    pub allow_ips: Option<String>,
+
+    /// Address ranges turned away right after they connected, comma separated.
+    #[arg(long, env = "DENY_IPS")]
+    pub deny_ips: Option<String>,
+
+    /// Which list decides for addresses on both.
+    #[arg(long, env = "IP_ORDER", value_enum, default_value_t = FilterOrder::DenyWins)]
+    pub ip_order: FilterOrder,
+
+    /// Print the values the server would run with and exit.
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+/// Why a [`ServerConfig`] can't be run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Only one of the TLS files was given.
+    TlsHalf {
+        missing: &'static str,
+    },
+    ZeroConnections,
+    ZeroInFlight,
+    ZeroUploadTimeout,
+    /// `--layers` names this layer twice.
+    RepeatedLayer(&'static str),
+    /// Neither tokens nor anonymous clients are allowed, so nobody could connect.
+    NoAccess,
+    /// An `--auth-tokens` entry isn't `name=token`.
+    TokenWithoutName,
+    /// Two listeners want the same port.
+    SamePort {
+        first: &'static str,
+        second: &'static str,
+        port: u16,
+    },
+    /// An `--allowed-origins` entry isn't an origin.
+    InvalidOrigin(InvalidOrigin),
+    /// An `--allow-ips` or `--deny-ips` entry isn't an address range.
+    InvalidCidr(InvalidCidr),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::TlsHalf { missing } => write!(
+                f,
+                "TLS needs both a certificate and a key, set {} too",
+                missing
+            ),
+            ConfigError::ZeroConnections => {
+                f.write_str("--max-connections (MAX_CONNECTIONS) must be at least 1")
+            }
+            ConfigError::ZeroInFlight => {
+                f.write_str("--max-in-flight (MAX_IN_FLIGHT) must be at least 1")
+            }
+            ConfigError::ZeroUploadTimeout => {
+                f.write_str("--upload-timeout (UPLOAD_TIMEOUT) must be at least 1")
+            }
+            ConfigError::RepeatedLayer(layer) => {
+                write!(f, "--layers (LAYERS) names {} more than once", layer)
+            }
+            ConfigError::NoAccess => f.write_str(
+                "set --auth-token or --auth-tokens (AUTH_TOKEN, AUTH_TOKENS) to the tokens \
+                 clients need, or --allow-anonymous (ALLOW_ANONYMOUS) to serve them without one",
+            ),
+            ConfigError::TokenWithoutName => {
+                f.write_str("every --auth-tokens (AUTH_TOKENS) entry needs to be name=token")
+            }
+            ConfigError::SamePort {
+                first,
+                second,
+                port,
+            } => write!(f, "the {} and {} ports are both {}", first, second, port),
+            ConfigError::InvalidOrigin(e) => {
+                write!(f, "--allowed-origins (ALLOWED_ORIGINS): {}", e)
+            }
+            ConfigError::InvalidCidr(e) => {
+                write!(f, "--allow-ips or --deny-ips (ALLOW_IPS, DENY_IPS): {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    /// Fails on the first option the server couldn't run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => {
+                return Err(ConfigError::TlsHalf {
+                    missing: "--tls-key (TLS_KEY)",
+                })
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::TlsHalf {
+                    missing: "--tls-cert (TLS_CERT)",
+                })
+            }
+            _ => (),
+        }
+        if self.max_connections == 0 {
+            return Err(ConfigError::ZeroConnections);
+        }
+        if self.max_in_flight == 0 {
+            return Err(ConfigError::ZeroInFlight);
+        }
+        if self.upload_timeout == 0 {
+            return Err(ConfigError::ZeroUploadTimeout);
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            if self.layers[..i].contains(layer) {
+                return Err(ConfigError::RepeatedLayer(layer.name()));
+            }
+        }
+        if !self.has_tokens() && !self.allow_anonymous {
+            return Err(ConfigError::NoAccess);
+        }
+        let pairs = self.auth_tokens.as_deref().unwrap_or_default().split(',');
+        if pairs
+            .map(str::trim)
+            .any(|pair| !pair.is_empty() && !pair.contains('='))
+        {
+            return Err(ConfigError::TokenWithoutName);
+        }
+        self.origins().map_err(ConfigError::InvalidOrigin)?;
+        self.ip_filter().map_err(ConfigError::InvalidCidr)?;
+        let ports = [
+            ("websocket", self.ws_port),
+            ("tcp", self.tcp_port),
+            #[cfg(feature = "webtransport")]
+            ("webtransport", self.webtransport_port),
+            #[cfg(feature = "sse")]
+            ("sse", self.sse_port),
+            ("health", self.health_port),
+            #[cfg(feature = "test-util")]
+            ("control", self.control_port),
+        ];
+        for (i, &(first, port)) in ports.iter().enumerate() {
+            for &(second, other) in &ports[i + 1..] {
+                if let (Toggle::On(port), Toggle::On(other)) = (port, other) {
+                    if port == other {
+                        return Err(ConfigError::SamePort {
+                            first,
+                            second,
+                            port,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any token was given, which turns anonymous clients away.
+    pub fn has_tokens(&self) -> bool {
+        let given = |tokens: &Option<String>| tokens.as_deref().is_some_and(|t| !t.is_empty());
+        given(&self.auth_token) || given(&self.auth_tokens)
+    }
+
+    pub fn tls(&self) -> Option<TlsFiles> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+
+    pub fn upload_dir(&self) -> PathBuf {
+        match &self.upload_dir {
+            Some(dir) => dir.clone(),
+            None => std::env::temp_dir().join("uploads"),
+        }
+    }
+
+    pub fn upload_timeout(&self) -> Duration {
+        Duration::from_secs(self.upload_timeout)
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_secs(self.max_delay)
+    }
+
+    /// Who may open WebSockets, see [`crate::origin`].
+    pub fn origins(&self) -> Result<OriginPolicy, InvalidOrigin> {
+        OriginPolicy::parse(&self.allowed_origins, self.allow_missing_origin)
+    }
+
+    /// Which peers may connect, see [`crate::ip_filter`].
+    pub fn ip_filter(&self) -> Result<IpFilter, InvalidCidr> {
+        let list = |ranges: &Option<String>| ranges.clone().unwrap_or_default();
+        IpFilter::parse(&list(&self.allow_ips), &list(&self.deny_ips), self.ip_order)
+    }
+
+    /// `port` on [`host`](Self::host).
+    pub fn addr(&self, port: u16) -> SocketAddr {
+        SocketAddr::new(self.host, port)
+    }
+
+    /// The values as `--print-config` shows them, one `name = value` per line.
+    pub fn describe(&self) -> String {
+        let or_unset = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+        let path =
+            |path: &Option<PathBuf>| or_unset(path.as_ref().map(|p| p.display().to_string()));
+        let secret = |value: &Option<String>| or_unset(value.as_ref().map(|_| "(set)".into()));
+        let layers: Vec<_> = self.layers.iter().map(|layer| layer.name()).collect();
+        [
+            ("host", self.host.to_string()),
+            ("ws_port", self.ws_port.to_string()),
+            ("tcp_port", self.tcp_port.to_string()),
+            #[cfg(feature = "webtransport")]
+            ("webtransport_port", self.webtransport_port.to_string()),
+            #[cfg(feature = "sse")]
+            ("sse_port", self.sse_port.to_string()),
+            ("health_port", self.health_port.to_string()),
+            #[cfg(feature = "test-util")]
+            ("control_port", self.control_port.to_string()),
+            ("metrics_addr", self.metrics_addr.to_string()),
+            ("log_level", or_unset(self.log_level.clone())),
+            ("max_connections", self.max_connections.to_string()),
+            ("drain_timeout", format!("{}s", self.drain_timeout)),
+            ("max_in_flight", self.max_in_flight.to_string()),
+            ("in_flight_queue", self.in_flight_queue.to_string()),
+            ("expired_calls", format!("{:?}", self.expired_calls).to_lowercase()),
+            ("layers", layers.join(",")),
+            ("request_log_every", self.request_log_every.to_string()),
+            ("rate_limits", or_unset(self.rate_limits.clone())),
+            ("payload_limits", or_unset(self.payload_limits.clone())),
+            ("method_timeouts", or_unset(self.method_timeouts.clone())),
+            ("upload_dir", self.upload_dir().display().to_string()),
+            ("max_upload_size", self.max_upload_size.to_string()),
+            ("upload_timeout", format!("{}s", self.upload_timeout)),
+            ("max_delay", format!("{}s", self.max_delay)),
+            ("static_dir", path(&self.static_dir)),
+            ("tls_cert", path(&self.tls_cert)),
+            ("tls_key", path(&self.tls_key)),
+            ("auth_token", secret(&self.auth_token)),
+            ("auth_tokens", secret(&self.auth_tokens)),
+            ("admins", or_unset(self.admins.clone())),
+            ("allow_anonymous", self.allow_anonymous.to_string()),
+            ("allowed_origins", self.allowed_origins.clone()),
+            ("allow_missing_origin", self.allow_missing_origin.to_string()),
+            ("allow_ips", or_unset(self.allow_ips.clone())),
+            ("deny_ips", or_unset(self.deny_ips.clone())),
+            ("ip_order", self.ip_order.name().into()),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{} = {}\n", name, value))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// The environment is shared by every test, so parsing waits for tests that change it.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn parse(args: &[&str]) -> Result<ServerConfig, clap::Error> {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        ServerConfig::try_parse_from(["server"].iter().chain(args))
+    }
+
+    /// Parses `args` while `var` is set to `value`.
+    fn parse_with_env(var: &str, value: &str, args: &[&str]) -> Result<ServerConfig, clap::Error> {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::env::set_var(var, value);
+        let config = ServerConfig::try_parse_from(["server"].iter().chain(args));
+        std::env::remove_var(var);
+        config
+    }
+
+    fn validate(args: &[&str]) -> Result<(), ConfigError> {
+        parse(args).unwrap().validate()
+    }
+
+    #[test]
+    fn defaults_need_tokens_or_anonymous_clients() {
+        assert_eq!(validate(&[]), Err(ConfigError::NoAccess));
+        assert_eq!(validate(&["--allow-anonymous"]), Ok(()));
+        assert_eq!(validate(&["--auth-token", "secret"]), Ok(()));
+        assert_eq!(validate(&["--auth-tokens", "alice=a,bob=b"]), Ok(()));
+    }
+
+    #[test]
+    fn defaults() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.host, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.ws_port, Toggle::On(web::DEFAULT_PORT));
+        assert_eq!(config.tcp_port, Toggle::On(tcp::DEFAULT_PORT));
+        assert_eq!(config.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(config.max_in_flight, DEFAULT_MAX_IN_FLIGHT);
+        assert_eq!(config.drain_timeout(), DEFAULT_DRAIN_TIMEOUT);
+        assert_eq!(config.layers, DEFAULT_LAYERS.to_vec());
+        assert_eq!(config.allowed_origins, origin::DEFAULT_ORIGINS);
+        assert_eq!(config.ip_order, FilterOrder::DenyWins);
+        assert!(!config.allow_anonymous);
+        assert!(config.tls().is_none());
+    }
+
+    #[test]
+    fn environment_fills_in_missing_flags() {
+        let config = parse_with_env("WS_PORT", "9000", &[]).unwrap();
+        assert_eq!(config.ws_port, Toggle::On(9000));
+        let config = parse_with_env("ALLOW_ANONYMOUS", "true", &[]).unwrap();
+        assert!(config.allow_anonymous);
+        let config = parse_with_env("ALLOW_ANONYMOUS", "0", &[]).unwrap();
+        assert!(!config.allow_anonymous);
+        let config = parse_with_env("LAYERS", "log,timeout", &[]).unwrap();
+        assert_eq!(config.layers, [Middleware::Log, Middleware::Timeout]);
+    }
+
+    #[test]
+    fn flags_win_over_the_environment() {
+        let config = parse_with_env("WS_PORT", "9000", &["--ws-port", "9001"]).unwrap();
+        assert_eq!(config.ws_port, Toggle::On(9001));
+        let config = parse_with_env("MAX_IN_FLIGHT", "8", &["--max-in-flight", "16"]).unwrap();
+        assert_eq!(config.max_in_flight, 16);
+    }
+
+    #[test]
+    fn bad_environment_values_are_rejected() {
+        assert!(parse_with_env("WS_PORT", "ninety", &[]).is_err());
+        assert!(parse_with_env("MAX_CONNECTIONS", "-1", &[]).is_err());
+    }
+
+    #[test]
+    fn toggles_parse_off_or_a_value() {
+        assert_eq!("off".parse::<Toggle<u16>>(), Ok(Toggle::Off));
+        assert_eq!("80".parse::<Toggle<u16>>(), Ok(Toggle::On(80)));
+        let err = "on".parse::<Toggle<u16>>().unwrap_err();
+        assert!(err.ends_with(" (or `off`)"), "{}", err);
+        assert_eq!(Toggle::<u16>::Off.to_string(), "off");
+        assert_eq!(Toggle::On(80).on(), Some(80));
+
+        let config = parse(&["--health-port", "off", "--metrics-addr", "off"]).unwrap();
+        assert_eq!(config.health_port, Toggle::Off);
+        assert_eq!(config.metrics_addr, Toggle::Off);
+        assert!(parse(&["--ws-port", "none"]).is_err());
+    }
+
+    #[test]
+    fn tls_needs_both_files() {
+        assert_eq!(
+            validate(&["--allow-anonymous", "--tls-cert", "cert.pem"]),
+            Err(ConfigError::TlsHalf {
+                missing: "--tls-key (TLS_KEY)"
+            })
+        );
+        assert_eq!(
+            validate(&["--allow-anonymous", "--tls-key", "key.pem"]),
+            Err(ConfigError::TlsHalf {
+                missing: "--tls-cert (TLS_CERT)"
+            })
+        );
+        let args = [
+            "--allow-anonymous",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+        ];
+        assert_eq!(validate(&args), Ok(()));
+        let tls = parse(&args).unwrap().tls().unwrap();
+        assert_eq!(tls.cert, PathBuf::from("cert.pem"));
+        assert_eq!(tls.key, PathBuf::from("key.pem"));
+    }
+
+    #[test]
+    fn zero_limits_are_rejected() {
+        let zero = |flag: &str| validate(&["--allow-anonymous", flag, "0"]);
+        assert_eq!(zero("--max-connections"), Err(ConfigError::ZeroConnections));
+        assert_eq!(zero("--max-in-flight"), Err(ConfigError::ZeroInFlight));
+        assert_eq!(
+            zero("--upload-timeout"),
+            Err(ConfigError::ZeroUploadTimeout)
+        );
+        assert_eq!(zero("--in-flight-queue"), Ok(()));
+    }
+
+    #[test]
+    fn layers_may_only_appear_once() {
+        assert_eq!(
+            validate(&["--allow-anonymous", "--layers", "log,timeout,log"]),
+            Err(ConfigError::RepeatedLayer("log"))
+        );
+        assert_eq!(
+            validate(&["--allow-anonymous", "--layers", "timeout,log"]),
+            Ok(())
+        );
+        assert!(parse(&["--layers", "log,gzip"]).is_err());
+    }
+
+    #[test]
+    fn named_tokens_need_a_name() {
+        assert_eq!(
+            validate(&["--auth-tokens", "alice=a,b"]),
+            Err(ConfigError::TokenWithoutName)
+        );
+        assert_eq!(validate(&["--auth-tokens", "alice=a, ,bob=b,"]), Ok(()));
+    }
+
+    #[test]
+    fn empty_tokens_are_no_tokens() {
+        let config = parse(&["--auth-token", "", "--auth-tokens", ""]).unwrap();
+        assert!(!config.has_tokens());
+        assert_eq!(config.validate(), Err(ConfigError::NoAccess));
+        assert!(parse(&["--auth-tokens", "alice=a"]).unwrap().has_tokens());
+    }
+
+    #[test]
+    fn listeners_need_their_own_ports() {
+        assert_eq!(
+            validate(&[
+                "--allow-anonymous",
+                "--ws-port",
+                "9000",
+                "--tcp-port",
+                "9000"
+            ]),
+            Err(ConfigError::SamePort {
+                first: "websocket",
+                second: "tcp",
+                port: 9000
+            })
+        );
+        assert_eq!(
+            validate(&[
+                "--allow-anonymous",
+                "--tcp-port",
+                "9000",
+                "--health-port",
+                "9000"
+            ]),
+            Err(ConfigError::SamePort {
+                first: "tcp",
+                second: "health",
+                port: 9000
+            })
+        );
+        let off = ["--allow-anonymous", "--ws-port", "off", "--tcp-port", "off"];
+        assert_eq!(validate(&off), Ok(()));
+    }
+
+    #[cfg(feature = "sse")]
+    #[test]
+    fn server_sent_events_need_their_own_port() {
+        assert_eq!(parse(&[]).unwrap().sse_port, Toggle::On(sse::DEFAULT_PORT));
+        assert_eq!(
+            validate(&["--allow-anonymous", "--tcp-port", "9000", "--sse-port", "9000"]),
+            Err(ConfigError::SamePort {
+                first: "tcp",
+                second: "sse",
+                port: 9000
+            })
+        );
+    }
+
+    #[test]
+    fn origins_and_address_ranges_are_checked() {
+        let origins = validate(&["--allow-anonymous", "--allowed-origins", "example.com"]);
+        assert!(
+            matches!(origins, Err(ConfigError::InvalidOrigin(_))),
+            "{:?}",
+            origins
+        );
+        let cidr = validate(&["--allow-anonymous", "--allow-ips", "10.0.0.0/33"]);
+        assert!(
+            matches!(cidr, Err(ConfigError::InvalidCidr(_))),
+            "{:?}",
+            cidr
+        );
+        let cidr = validate(&["--allow-anonymous", "--deny-ips", "nowhere"]);
+        assert!(
+            matches!(cidr, Err(ConfigError::InvalidCidr(_))),
+            "{:?}",
+            cidr
+        );
+        let args = [
+            "--allow-anonymous",
+            "--allow-ips",
+            "10.0.0.0/8",
+            "--deny-ips",
+            "::1",
+        ];
+        assert_eq!(validate(&args), Ok(()));
+    }
+
+    #[test]
+    fn described_config_leaves_tokens_out() {
+        let config = parse(&["--auth-token", "hunter2", "--ws-port", "off"]).unwrap();
+        let described = config.describe();
+        assert!(!described.contains("hunter2"), "{}", described);
+        assert!(described.contains("auth_token = (set)\n"), "{}", described);
+        assert!(described.contains("auth_tokens = -\n"), "{}", described);
+        assert!(described.contains("ws_port = off\n"), "{}", described);
+        assert!(described.lines().all(|line| line.contains(" = ")));
+    }
+}
@@ -0,0 +1,67 @@
+//! The [`Control`] service tests stop the server with, only built with `test-util`.
+//!
+//! `shutdown` begins the same drain SIGTERM does: the listeners stop accepting, the calls
+//! running get the drain timeout and connections close with code 1001. The listener only
+//! binds on the loopback address, whatever `--host` says, and takes no token.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use futures::{future, StreamExt};
+use log::{error, info};
+use rpc::control::Control;
+use tarpc::context::Context;
+use tarpc::server::{BaseChannel, Channel};
+use tarpc::tokio_serde::formats::Json;
+use tokio::sync::Notify;
+
+use crate::shutdown::Begun;
+
+/// Port of the control listener unless `--control-port` says otherwise.
+pub const DEFAULT_PORT: u16 = 8087;
+
+/// Resolves once a test asked for the shutdown.
+pub async fn requested(requests: Arc<Notify>) {
+    requests.notified().await;
+    info!("Shutdown requested over the control port");
+}
+
+#[derive(Clone)]
+struct ControlImpl {
+    requests: Arc<Notify>,
+    begun: Begun,
+}
+
+#[tarpc::server]
+#[async_trait::async_trait]
+impl Control for ControlImpl {
+    async fn shutdown(self, _: Context) {
+        self.requests.notify_one();
+        self.begun.wait().await;
+    }
+}
+
+/// Serves [`Control`] on `port` of the loopback address, telling `requests` about shutdowns
+/// and answering them once `begun` says the drain began.
+pub async fn serve(port: u16, requests: Arc<Notify>, begun: Begun) {
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+    let listener = match tarpc::serde_transport::tcp::listen(&addr, Json::default).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind the control listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Serving the control service on {}", addr);
+    listener
+        .filter_map(|transport| future::ready(transport.ok()))
+        .for_each(|transport| {
+            let service = ControlImpl {
+                requests: requests.clone(),
+                begun: begun.clone(),
+            };
+            tokio::spawn(BaseChannel::with_defaults(transport).execute(service.serve()));
+            future::ready(())
+        })
+        .await
+}
@@ -0,0 +1,195 @@
+//! Calls stop running once their deadline passes, see [`Deadlined`].
+//!
+//! Clients send a deadline with every call and give up waiting at it, so a handler still
+//! running by then only holds on to the server for nothing. [`Deadlined`] races the handler
+//! against the deadline and drops it when time is up, and then either answers
+//! [`WorldError::Timeout`] or nothing at all, as [`Expired`] says.
+//!
+//! Answers sent right at the deadline would arrive after the client stopped waiting, so the
+//! race ends [`ANSWER_MARGIN`] earlier.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+
+use clap::ValueEnum;
+use log::info;
+use rpc::admin::{AdminRequest, AdminResponse};
+use rpc::{WorldError, WorldRequest, WorldResponse};
+use tarpc::context::Context;
+use tarpc::server::Serve;
+
+use crate::middleware::{BoxServe, Call, Layer};
+
+/// How long before the deadline a call still running is given up on.
+pub const ANSWER_MARGIN: Duration = Duration::from_millis(50);
+
+/// What a client hears of a call that ran out of time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Expired {
+    /// [`WorldError::Timeout`], just before the deadline.
+    #[default]
+    Answer,
+    /// Nothing, it stopped waiting anyway.
+    Drop,
+}
+
+impl<R: Call> Layer<R> for Expired {
+    fn layer(&self, inner: BoxServe<R>) -> BoxServe<R> {
+        BoxServe::new(Deadlined::new(inner, *self))
+    }
+}
+
+/// `serve` giving up on calls past their deadline, see the [module](self) docs.
+#[derive(Clone)]
+pub struct Deadlined<S> {
+    serve: S,
+    expired: Expired,
+}
+
+impl<S> Deadlined<S> {
+    pub fn new(serve: S, expired: Expired) -> Self {
+        Self { serve, expired }
+    }
+}
+
+impl<S, R> Serve<R> for Deadlined<S>
+where
+    R: Call,
+    S: Serve<R, Resp = R::Response>,
+    S::Fut: Send + 'static,
+{
+    type Resp = R::Response;
+    type Fut = Pin<Box<dyn Future<Output = R::Response> + Send>>;
+
+    fn method(&self, request: &R) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: R) -> Self::Fut {
+        let method = self.serve.method(&request).unwrap_or("unknown");
+        let failure = request.failure();
+        let left = ctx
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .saturating_sub(ANSWER_MARGIN);
+        let answered = self.serve.serve(ctx, request);
+        let expired = self.expired;
+        Box::pin(async move {
+            if let Ok(response) = tokio::time::timeout(left, answered).await {
+                return response;
+            }
+            info!(
+                "call method={} trace={} ran past its deadline, {}",
+                method,
+                ctx.trace_id(),
+                match expired {
+                    Expired::Answer => "answering Timeout",
+                    Expired::Drop => "not answering",
+                }
+            );
+            match expired {
+                Expired::Answer => failure(WorldError::Timeout),
+                // tarpc forgets the call at its deadline, which ends this too.
+                Expired::Drop => std::future::pending().await,
+            }
+        })
+    }
+}
+
+/// Answers a call of one method with `Err`, see [`failure`].
+pub type Failure = fn(WorldError) -> WorldResponse;
+
+/// How `request`'s method answers an error, see [`Call::failure`].
+pub fn failure(request: &WorldRequest) -> Failure {
+    match request {
+        WorldRequest::Ping { .. } => |error| WorldResponse::Ping(Err(error)),
+        WorldRequest::Echo { .. } => |error| WorldResponse::Echo(Err(error)),
+        WorldRequest::Delay { .. } => |error| WorldResponse::Delay(Err(error)),
+        WorldRequest::PeerId { .. } => |error| WorldResponse::PeerId(Err(error)),
+        WorldRequest::Signal { .. } => |error| WorldResponse::Signal(Err(error)),
+        WorldRequest::Signals { .. } => |error| WorldResponse::Signals(Err(error)),
+        WorldRequest::Count { .. } => |error| WorldResponse::Count(Err(error)),
+        WorldRequest::Subscribe { .. } => |error| WorldResponse::Subscribe(Err(error)),
+        WorldRequest::Unsubscribe { .. } => |error| WorldResponse::Unsubscribe(Err(error)),
+        WorldRequest::Publish { .. } => |error| WorldResponse::Publish(Err(error)),
+        WorldRequest::DelayWithProgress { .. } => {
+            |error| WorldResponse::DelayWithProgress(Err(error))
+        }
+        WorldRequest::Whoami { .. } => |error| WorldResponse::Whoami(Err(error)),
+        WorldRequest::ListClients { .. } => |error| WorldResponse::ListClients(Err(error)),
+        WorldRequest::UploadBegin { .. } => |error| WorldResponse::UploadBegin(Err(error)),
+        WorldRequest::UploadChunk { .. } => |error| WorldResponse::UploadChunk(Err(error)),
+        WorldRequest::UploadFinish { .. } => |error| WorldResponse::UploadFinish(Err(error)),
+        WorldRequest::ListItems { .. } => |error| WorldResponse::ListItems(Err(error)),
+        WorldRequest::Increment { .. } => |error| WorldResponse::Increment(Err(error)),
+        WorldRequest::GetCount { .. } => |error| WorldResponse::GetCount(Err(error)),
+        WorldRequest::Version { .. } => |error| WorldResponse::Version(Err(error)),
+        WorldRequest::EchoBytes { .. } => |error| WorldResponse::EchoBytes(Err(error)),
+        WorldRequest::CreateTask { .. } => |error| WorldResponse::CreateTask(Err(error)),
+        WorldRequest::LogEvent { .. } => |error| WorldResponse::LogEvent(Err(error)),
+    }
+}
+
+/// [`failure`] for the requests of `Admin`.
+pub fn admin_failure(request: &AdminRequest) -> fn(WorldError) -> AdminResponse {
+    match request {
+        AdminRequest::Connections { .. } => |error| AdminResponse::Connections(Err(error)),
+        AdminRequest::Announce { .. } => |error| AdminResponse::Announce(Err(error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::testing::{self, Sleeper};
+
+    const DEADLINE: Duration = Duration::from_secs(2);
+
+    fn delay(millis: u64) -> WorldRequest {
+        WorldRequest::Delay { millis }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_call_past_its_deadline_is_answered_timeout() {
+        let sleeper = Sleeper::default();
+        let deadlined = Deadlined::new(sleeper.clone(), Expired::Answer);
+        let started = Instant::now();
+        let answer = deadlined.serve(testing::context(DEADLINE), delay(30_000)).await;
+        assert!(matches!(answer, WorldResponse::Delay(Err(WorldError::Timeout))));
+        assert!(started.elapsed() < DEADLINE);
+        assert!(started.elapsed() >= DEADLINE - ANSWER_MARGIN - Duration::from_millis(100));
+        assert_eq!(sleeper.sleeping(), 0, "the handler still runs");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_call_past_its_deadline_goes_unanswered_when_dropping() {
+        let sleeper = Sleeper::default();
+        let deadlined = Deadlined::new(sleeper.clone(), Expired::Drop);
+        let answer = deadlined.serve(testing::context(DEADLINE), delay(30_000));
+        let answer = tokio::time::timeout(Duration::from_secs(60), answer).await;
+        assert!(answer.is_err(), "answered {:?}", answer.map(|_| ()));
+        assert_eq!(sleeper.sleeping(), 0, "the handler still runs");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_call_within_its_deadline_is_answered() {
+        let deadlined = Deadlined::new(Sleeper::default(), Expired::Answer);
+        let answer = deadlined.serve(testing::context(DEADLINE), delay(1_000)).await;
+        assert!(matches!(answer, WorldResponse::Delay(Ok(_))));
+    }
+
+    #[test]
+    fn failures_answer_the_method_called() {
+        let echo = failure(&WorldRequest::Echo { value: "hi".into() });
+        assert!(matches!(echo(WorldError::Busy), WorldResponse::Echo(Err(WorldError::Busy))));
+        let version = failure(&WorldRequest::Version {});
+        let answer = version(WorldError::Timeout);
+        assert!(matches!(answer, WorldResponse::Version(Err(WorldError::Timeout))));
+    }
+}
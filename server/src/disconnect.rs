@@ -0,0 +1,137 @@
+//! Calls stop running once their client is gone, see [`UntilClosed`].
+//!
+//! tarpc runs every call in a task of its own, which outlives the connection it came from: a
+//! tab closed during a five minute `delay` would leave it running for five more minutes. The
+//! task serving a connection holds an [`Open`] until its transport ended, and dropping it ends
+//! every call of the connection still running.
+//!
+//! A call that already answered keeps its answer; the race only drops calls still waiting.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use log::info;
+use rpc::WorldError;
+use tarpc::context::Context;
+use tarpc::server::Serve;
+use tokio::sync::watch;
+
+use crate::middleware::{BoxServe, Call, Layer};
+
+/// A connection's [`Open`] and a [`Closed`] watching it.
+pub fn channel() -> (Open, Closed) {
+    let (open, closed) = watch::channel(());
+    (Open { _open: open }, Closed(closed))
+}
+
+/// Held for as long as a connection is served.
+pub struct Open {
+    _open: watch::Sender<()>,
+}
+
+/// Whether the connection's [`Open`] was dropped.
+#[derive(Clone)]
+pub struct Closed(watch::Receiver<()>);
+
+impl Closed {
+    /// Resolves once the connection is closed.
+    pub async fn wait(mut self) {
+        // Nothing is ever sent, `changed` only returns once the sender is gone.
+        while self.0.changed().await.is_ok() {}
+    }
+}
+
+impl<R: Call> Layer<R> for Closed {
+    fn layer(&self, inner: BoxServe<R>) -> BoxServe<R> {
+        BoxServe::new(UntilClosed::new(inner, self.clone()))
+    }
+}
+
+/// `serve` dropping calls once `closed`, see the [module](self) docs.
+#[derive(Clone)]
+pub struct UntilClosed<S> {
+    serve: S,
+    closed: Closed,
+}
+
+impl<S> UntilClosed<S> {
+    pub fn new(serve: S, closed: Closed) -> Self {
+        Self { serve, closed }
+    }
+}
+
+impl<S, R> Serve<R> for UntilClosed<S>
+where
+    R: Call,
+    S: Serve<R, Resp = R::Response>,
+    S::Fut: Send + 'static,
+{
+    type Resp = R::Response;
+    type Fut = Pin<Box<dyn Future<Output = R::Response> + Send>>;
+
+    fn method(&self, request: &R) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: R) -> Self::Fut {
+        let method = self.serve.method(&request).unwrap_or("unknown");
+        let failure = request.failure();
+        let answered = self.serve.serve(ctx, request);
+        let closed = self.closed.wait();
+        Box::pin(async move {
+            tokio::select! {
+                // An answer ready by the time the connection closed is still sent.
+                biased;
+                response = answered => return response,
+                _ = closed => (),
+            }
+            info!(
+                "call method={} trace={} dropped, its client disconnected",
+                method,
+                ctx.trace_id()
+            );
+            // Nobody reads it, it only lets tarpc's task finish.
+            failure(WorldError::Unavailable("the client disconnected".into()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Sleeper};
+    use rpc::WorldClient;
+    use std::time::Duration;
+    use tarpc::server::{BaseChannel, Channel};
+
+    #[tokio::test]
+    async fn a_dropped_connection_ends_the_calls_still_running() {
+        let sleeper = Sleeper::default();
+        let (open, closed) = channel();
+        let (client, transport) = tarpc::transport::channel::unbounded();
+        let serve = UntilClosed::new(sleeper.clone(), closed);
+        // Drops `open` once the channel's requests end, as `main` does after its `execute`.
+        tokio::spawn(async move {
+            BaseChannel::with_defaults(transport).execute(serve).await;
+            drop(open);
+        });
+
+        let client = WorldClient::new(tarpc::client::Config::default(), client);
+        // The connection drops with the dispatch, which holds the client's end.
+        let connection = tokio::spawn(client.dispatch);
+        let ctx = testing::context(Duration::from_secs(60));
+        tokio::spawn(async move { client.client.delay(ctx, 30_000).await });
+        while sleeper.sleeping() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        connection.abort();
+
+        let ended = async {
+            while sleeper.sleeping() > 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        let ended = tokio::time::timeout(Duration::from_secs(5), ended).await;
+        ended.expect("the call kept running without its connection");
+    }
+}
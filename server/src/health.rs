@@ -0,0 +1,200 @@
+//! `GET /healthz` and `GET /readyz` for load balancers, on a port of their own.
+//!
+//! `/healthz` answers 200 as long as the process runs. `/readyz` answers 200 only while every
+//! listener's accept loop runs, connections are below the [`ConnectionLimit`], the server
+//! isn't shutting down and every custom check passes, and 503 otherwise. Both answer JSON
+//! saying why, so people can read them too.
+//!
+//! This is just enough HTTP/1.1 for probes: one request per connection, closed after the
+//! answer.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::limit::ConnectionLimit;
+use crate::shutdown::Begun;
+
+/// Port of the endpoints unless `--health-port` says otherwise.
+pub const DEFAULT_PORT: u16 = 8086;
+
+/// Bytes of a probe's request read at most, headers included.
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// How long a probe gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A custom readiness check, failing with why.
+pub type Check = Box<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// Whether a listener's accept loop runs, as the listener tells [`Health`].
+#[derive(Clone, Default)]
+pub struct Listening(Arc<AtomicBool>);
+
+impl Listening {
+    pub fn set(&self, running: bool) {
+        self.0.store(running, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What `/readyz` looks at.
+pub struct Health {
+    started: Instant,
+    limit: ConnectionLimit,
+    shutdown: Begun,
+    listeners: Vec<(&'static str, Listening)>,
+    checks: Vec<(&'static str, Check)>,
+}
+
+impl Health {
+    pub fn new(limit: ConnectionLimit, shutdown: Begun) -> Self {
+        Self {
+            started: Instant::now(),
+            limit,
+            shutdown,
+            listeners: Vec::new(),
+            checks: Vec::new(),
+        }
+    }
+
+    /// A listener called `name` the server is only ready with. It counts as stopped until it
+    /// says it runs.
+    pub fn listener(&mut self, name: &'static str) -> Listening {
+        let listening = Listening::default();
+        self.listeners.push((name, listening.clone()));
+        listening
+    }
+
+    /// Something else the server is only ready with, reported as `name`.
+    pub fn with_check(
+        mut self,
+        name: &'static str,
+        check: impl Fn() -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.checks.push((name, Box::new(check)));
+        self
+    }
+
+    fn alive(&self) -> Value {
+        json!({
+            "status": "up",
+            "uptime_secs": self.started.elapsed().as_secs(),
+        })
+    }
+
+    /// Whether the server is ready, and the answer saying why.
+    fn ready(&self) -> (bool, Value) {
+        let mut ready = true;
+        let mut listeners = Map::new();
+        for (name, listening) in &self.listeners {
+            let running = listening.get();
+            ready &= running;
+            listeners.insert(name.to_string(), running.into());
+        }
+        let (open, max) = (self.limit.open(), self.limit.max());
+        ready &= open < max;
+        let shutting_down = self.shutdown.get();
+        ready &= !shutting_down;
+        let mut checks = Map::new();
+        for (name, check) in &self.checks {
+            let result = match check() {
+                Ok(()) => "ok".into(),
+                Err(e) => {
+                    ready = false;
+                    e
+                }
+            };
+            checks.insert(name.to_string(), result.into());
+        }
+        let status = if ready { "ready" } else { "not ready" };
+        let answer = json!({
+            "status": status,
+            "listeners": listeners,
+            "connections": { "open": open, "max": max },
+            "shutting_down": shutting_down,
+            "checks": checks,
+        });
+        (ready, answer)
+    }
+}
+
+/// Serves the endpoints on `addr` until the process exits.
+pub async fn serve(health: Health, addr: SocketAddr) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind the health endpoints on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Serving /healthz and /readyz on {}", addr);
+    let health = Arc::new(health);
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let health = health.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = answer(stream, &health).await {
+                        error!("Health probe from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept a health probe: {}", e),
+        }
+    }
+}
+
+async fn answer(mut stream: TcpStream, health: &Health) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 512];
+    // The request line is all that matters, the headers are only read to get past them.
+    let read = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await;
+    match read {
+        Ok(read) => read?,
+        Err(_) => return Ok(()),
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.lines().next().unwrap_or_default().split(' ');
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/healthz")) => ("200 OK", health.alive()),
+        (Some("GET"), Some("/readyz")) => match health.ready() {
+            (true, body) => ("200 OK", body),
+            (false, body) => ("503 Service Unavailable", body),
+        },
+        (Some("GET"), Some(_)) => ("404 Not Found", json!({ "error": "not found" })),
+        _ => (
+            "405 Method Not Allowed",
+            json!({ "error": "only GET is served" }),
+        ),
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,186 @@
+//! A cap on the calls a connection runs at once, see [`InFlight`].
+//!
+//! tarpc starts a task for every request as it arrives, however many of the connection's calls
+//! are still running. [`Bounded`] makes each call take one of the connection's permits before
+//! its handler runs. Calls finding none wait in a queue of `queue` calls, and are answered
+//! [`WorldError::Busy`] right away once that is full too, so `queue` 0 rejects every call over the
+//! cap. Connections never share permits.
+//!
+//! A permit is given back as soon as the handler answered, not once the answer was written, so
+//! a client reading its answers slowly can't keep its own calls from running.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::info;
+use rpc::WorldError;
+use tarpc::context::Context;
+use tarpc::server::Serve;
+use tokio::sync::Semaphore;
+
+use crate::middleware::{BoxServe, Call, Layer};
+
+/// Calls a connection runs at once unless `--max-in-flight` says otherwise.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// How many calls a connection may run at once, and how many more may wait for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InFlightPolicy {
+    pub max: usize,
+    pub queue: usize,
+}
+
+/// One connection's permits. Clones share them.
+#[derive(Clone, Debug)]
+pub struct InFlight {
+    policy: InFlightPolicy,
+    permits: Arc<Semaphore>,
+    waiting: Arc<AtomicUsize>,
+}
+
+impl InFlight {
+    pub fn new(policy: InFlightPolicy) -> Self {
+        Self {
+            policy,
+            permits: Arc::new(Semaphore::new(policy.max)),
+            waiting: Arc::default(),
+        }
+    }
+
+    /// A place in the queue, `None` if it is full.
+    fn enqueue(&self) -> Option<Waiting> {
+        let queue = self.policy.queue;
+        self.waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < queue).then_some(waiting + 1)
+            })
+            .ok()?;
+        Some(Waiting(self.waiting.clone()))
+    }
+}
+
+impl<R: Call> Layer<R> for InFlight {
+    fn layer(&self, inner: BoxServe<R>) -> BoxServe<R> {
+        BoxServe::new(Bounded::new(inner, self.clone()))
+    }
+}
+
+/// A call counted as waiting for a permit, until it is dropped.
+struct Waiting(Arc<AtomicUsize>);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// `serve` running at most as many calls as `in_flight` allows, see the [module](self) docs.
+#[derive(Clone)]
+pub struct Bounded<S> {
+    serve: S,
+    in_flight: InFlight,
+}
+
+impl<S> Bounded<S> {
+    pub fn new(serve: S, in_flight: InFlight) -> Self {
+        Self { serve, in_flight }
+    }
+}
+
+impl<S, R> Serve<R> for Bounded<S>
+where
+    R: Call,
+    S: Serve<R, Resp = R::Response>,
+    S::Fut: Send + 'static,
+{
+    type Resp = R::Response;
+    type Fut = Pin<Box<dyn Future<Output = R::Response> + Send>>;
+
+    fn method(&self, request: &R) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: R) -> Self::Fut {
+        let in_flight = self.in_flight;
+        // Taken before the handler is even created, tarpc may start many calls in a row.
+        let permit = in_flight.permits.clone().try_acquire_owned();
+        let queued = match permit {
+            Ok(_) => None,
+            Err(_) => match in_flight.enqueue() {
+                Some(waiting) => Some(waiting),
+                None => {
+                    info!(
+                        "call method={} trace={} turned away, {} calls are running",
+                        self.serve.method(&request).unwrap_or("unknown"),
+                        ctx.trace_id(),
+                        in_flight.policy.max
+                    );
+                    let busy = request.failure()(WorldError::Busy);
+                    return Box::pin(std::future::ready(busy));
+                }
+            },
+        };
+        let answered = self.serve.serve(ctx, request);
+        Box::pin(async move {
+            let _permit = match (permit, queued) {
+                (Ok(permit), _) => permit,
+                (Err(_), waiting) => {
+                    // The semaphore is never closed.
+                    let permit = in_flight.permits.acquire_owned().await.unwrap();
+                    drop(waiting);
+                    permit
+                }
+            };
+            answered.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Sleeper};
+    use rpc::{WorldRequest, WorldResponse};
+    use std::time::Duration;
+
+    fn delay(millis: u64) -> WorldRequest {
+        WorldRequest::Delay { millis }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_connection_past_its_cap_leaves_the_others_be() {
+        let policy = InFlightPolicy { max: 1, queue: 0 };
+        let (full, other) = (InFlight::new(policy), InFlight::new(policy));
+        let sleeper = Sleeper::default();
+        let ctx = testing::context(Duration::from_secs(60));
+        let running = Bounded::new(sleeper.clone(), full.clone()).serve(ctx, delay(30_000));
+
+        let turned_away = Bounded::new(sleeper.clone(), full).serve(ctx, delay(1_000));
+        assert!(matches!(turned_away.await, WorldResponse::Delay(Err(WorldError::Busy))));
+        let served = Bounded::new(sleeper.clone(), other).serve(ctx, delay(1_000));
+        assert!(matches!(served.await, WorldResponse::Delay(Ok(_))));
+        assert!(matches!(running.await, WorldResponse::Delay(Ok(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_past_the_cap_wait_while_the_queue_has_room() {
+        let policy = InFlightPolicy { max: 1, queue: 1 };
+        let in_flight = InFlight::new(policy);
+        let sleeper = Sleeper::default();
+        let ctx = testing::context(Duration::from_secs(60));
+        let serve = |request: WorldRequest| {
+            Bounded::new(sleeper.clone(), in_flight.clone()).serve(ctx, request)
+        };
+        let running = serve(delay(1_000));
+        let queued = serve(delay(1_000));
+
+        assert!(matches!(serve(delay(1_000)).await, WorldResponse::Delay(Err(WorldError::Busy))));
+        let (running, queued) = futures::join!(running, queued);
+        assert!(matches!(running, WorldResponse::Delay(Ok(_))));
+        assert!(matches!(queued, WorldResponse::Delay(Ok(_))));
+        // Once both answered, the queue has room again.
+        assert!(matches!(serve(delay(1_000)).await, WorldResponse::Delay(Ok(_))));
+    }
+}
@@ -0,0 +1,311 @@
+//! Which addresses may connect at all, see [`IpFilter`].
+//!
+//! The WebSocket and TCP listeners ask the filter about every peer right after accepting it,
+//! and close the socket without a word when it says no: no TLS, no HTTP, nothing a scanner
+//! could learn the server from. WebTransport only tells the address after the QUIC handshake,
+//! so it refuses the session then, and the server-sent events endpoints answer 403 Forbidden.
+//! Turned away peers are counted in
+//! [`DENIED_CONNECTIONS`](crate::prometheus::DENIED_CONNECTIONS) by listener.
+//!
+//! IPv4 peers accepted on an IPv6 socket arrive as IPv4-mapped addresses (`::ffff:10.0.0.1`);
+//! they are matched as the IPv4 address they are, so `10.0.0.0/8` covers them.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+
+/// Which list decides for addresses on both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum FilterOrder {
+    /// Addresses on the deny list are turned away, even when also allowed.
+    #[default]
+    DenyWins,
+    /// Addresses on the allow list are let in, even when also denied.
+    AllowWins,
+}
+
+impl FilterOrder {
+    /// The name `--ip-order` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterOrder::DenyWins => "deny-wins",
+            FilterOrder::AllowWins => "allow-wins",
+        }
+    }
+}
+
+/// Addresses sharing their first `prefix` bits with `addr`, e.g. `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// A list entry that isn't an address or a CIDR range.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidCidr(pub String);
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} is no address range, expected e.g. 10.0.0.0/8 or fd00::/8",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidCidr {}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    /// An address without a prefix is a range of its own.
+    fn from_str(entry: &str) -> Result<Self, InvalidCidr> {
+        let invalid = || InvalidCidr(entry.into());
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits: u8 = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        // `::ffff:10.0.0.0/104` is `10.0.0.0/8`, as the peers it should match are compared.
+        match canonical(addr) {
+            IpAddr::V4(v4) if addr.is_ipv6() => match prefix.checked_sub(96) {
+                Some(prefix) => Ok(Self {
+                    addr: IpAddr::V4(v4),
+                    prefix,
+                }),
+                None => Err(invalid()),
+            },
+            addr => Ok(Self { addr, prefix }),
+        }
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// `ip` as IPv4 if it is an IPv4-mapped IPv6 address.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// The ranges let in and turned away.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    order: FilterOrder,
+}
+
+impl IpFilter {
+    /// The comma separated ranges of `allow` and `deny`. An empty allow list allows every
+    /// address the deny list doesn't name.
+    pub fn parse(allow: &str, deny: &str, order: FilterOrder) -> Result<Self, InvalidCidr> {
+        Ok(Self {
+            allow: parse_list(allow)?,
+            deny: parse_list(deny)?,
+            order,
+        })
+    }
+
+    /// Whether `ip` may connect. Addresses on neither list may only when there is no allow
+    /// list.
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        let allowed = self.allow.iter().any(|range| range.contains(ip));
+        let denied = self.deny.iter().any(|range| range.contains(ip));
+        match (allowed, denied, self.order) {
+            (true, true, FilterOrder::DenyWins) => false,
+            (true, true, FilterOrder::AllowWins) => true,
+            (true, false, _) => true,
+            (false, true, _) => false,
+            (false, false, _) => self.allow.is_empty(),
+        }
+    }
+}
+
+fn parse_list(ranges: &str) -> Result<Vec<Cidr>, InvalidCidr> {
+    ranges
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn cidr(range: &str) -> Cidr {
+        range.parse().unwrap()
+    }
+
+    fn filter(allow: &str, deny: &str, order: FilterOrder) -> IpFilter {
+        IpFilter::parse(allow, deny, order).unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        let range = cidr("10.0.0.0/8");
+        assert!(range.contains(ip("10.0.0.0")));
+        assert!(range.contains(ip("10.255.255.255")));
+        assert!(!range.contains(ip("11.0.0.0")));
+        assert!(!range.contains(ip("9.255.255.255")));
+        let loopback = cidr("127.0.0.0/8");
+        assert!(loopback.contains(ip("127.0.0.1")));
+        assert!(loopback.contains(ip("127.1.2.3")));
+        assert!(!loopback.contains(ip("128.0.0.1")));
+    }
+
+    #[test]
+    fn ranges_ignore_the_host_bits_they_are_written_with() {
+        assert!(cidr("10.1.2.3/8").contains(ip("10.200.0.1")));
+        assert!(cidr("192.168.1.77/24").contains(ip("192.168.1.1")));
+        assert!(!cidr("192.168.1.77/24").contains(ip("192.168.2.1")));
+    }
+
+    #[test]
+    fn prefix_edges() {
+        let everything = cidr("0.0.0.0/0");
+        assert!(everything.contains(ip("0.0.0.0")));
+        assert!(everything.contains(ip("255.255.255.255")));
+        let single = cidr("10.0.0.1");
+        assert_eq!(single, cidr("10.0.0.1/32"));
+        assert!(single.contains(ip("10.0.0.1")));
+        assert!(!single.contains(ip("10.0.0.2")));
+        assert!(cidr("::/0").contains(ip("ffff:ffff::1")));
+        assert!(cidr("::1").contains(ip("::1")));
+        assert!(!cidr("::1").contains(ip("::2")));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        let range = cidr("fd00::/8");
+        assert!(range.contains(ip("fd12:3456::1")));
+        assert!(!range.contains(ip("fe80::1")));
+        let range = cidr("2001:db8::/32");
+        assert!(range.contains(ip("2001:db8:ffff::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn families_do_not_mix() {
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_as_ipv4() {
+        let range = cidr("10.0.0.0/8");
+        assert!(range.contains(ip("::ffff:10.0.0.1")));
+        assert!(!range.contains(ip("::ffff:11.0.0.1")));
+        assert!(cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+        // Mapped ranges are the IPv4 ranges they map.
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.1.1")));
+        assert!(cidr("::ffff:10.0.0.1").contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn malformed_ranges_are_rejected() {
+        for range in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "localhost",
+            "::ffff:10.0.0.0/95",
+        ] {
+            assert_eq!(
+                range.parse::<Cidr>(),
+                Err(InvalidCidr(range.into())),
+                "{:?}",
+                range
+            );
+        }
+        assert_eq!(
+            IpFilter::parse("10.0.0.0/8, nope", "", FilterOrder::DenyWins),
+            Err(InvalidCidr("nope".into()))
+        );
+    }
+
+    #[test]
+    fn empty_lists_admit_everyone() {
+        let filter = IpFilter::default();
+        assert!(filter.admits(ip("203.0.113.9")));
+        assert!(filter.admits(ip("::1")));
+        assert_eq!(filter, IpFilter::parse(" , ", "", FilterOrder::DenyWins).unwrap());
+    }
+
+    #[test]
+    fn allow_list_turns_away_everyone_else() {
+        let filter = filter("127.0.0.0/8, fd00::/8", "", FilterOrder::DenyWins);
+        assert!(filter.admits(ip("127.0.0.1")));
+        assert!(filter.admits(ip("::ffff:127.0.0.1")));
+        assert!(filter.admits(ip("fd00::5")));
+        assert!(!filter.admits(ip("10.0.0.1")));
+        assert!(!filter.admits(ip("::1")));
+    }
+
+    #[test]
+    fn deny_list_turns_away_only_its_ranges() {
+        let filter = filter("", "10.0.0.0/8,::1", FilterOrder::DenyWins);
+        assert!(!filter.admits(ip("10.9.9.9")));
+        assert!(!filter.admits(ip("::ffff:10.9.9.9")));
+        assert!(!filter.admits(ip("::1")));
+        assert!(filter.admits(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn order_decides_addresses_on_both_lists() {
+        let (allow, deny) = ("10.0.0.0/8", "10.1.0.0/16");
+        let deny_wins = filter(allow, deny, FilterOrder::DenyWins);
+        assert!(!deny_wins.admits(ip("10.1.2.3")));
+        assert!(deny_wins.admits(ip("10.2.2.3")));
+        assert!(!deny_wins.admits(ip("11.0.0.1")));
+        let allow_wins = filter(allow, deny, FilterOrder::AllowWins);
+        assert!(allow_wins.admits(ip("10.1.2.3")));
+        assert!(allow_wins.admits(ip("10.2.2.3")));
+        assert!(!allow_wins.admits(ip("11.0.0.1")));
+    }
+}
@@ -0,0 +1,166 @@
+//! The collection `World::list_items` pages through, see [`Items`] and [`rpc::items`].
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use rpc::items::MAX_PAGE_LEN;
+use rpc::{Item, Page, WorldError};
+
+/// Items the collection starts with.
+const SEEDED: u64 = 250;
+
+/// Items by id, seeded with [`SEEDED`] of them.
+pub struct Items {
+    items: BTreeMap<u64, Item>,
+}
+
+impl Default for Items {
+    fn default() -> Self {
+        let items = (1..=SEEDED)
+            .map(|id| {
+                let item = Item {
+                    id,
+                    name: format!("Item {}", id),
+                };
+                (id, item)
+            })
+            .collect();
+        Self { items }
+    }
+}
+
+impl Items {
+    /// Up to `limit` items after the one `cursor` names, 1 to [`MAX_PAGE_LEN`] of them.
+    pub fn page(&self, cursor: Option<&str>, limit: u32) -> Result<Page, WorldError> {
+        let after = match cursor {
+            Some(cursor) => match parse_cursor(cursor) {
+                Some(id) => Bound::Excluded(id),
+                None => return Err(WorldError::InvalidCursor),
+            },
+            None => Bound::Unbounded,
+        };
+        let limit = limit.clamp(1, MAX_PAGE_LEN) as usize;
+        let mut rest = self
+            .items
+            .range((after, Bound::Unbounded))
+            .map(|(_, item)| item);
+        let items: Vec<Item> = rest.by_ref().take(limit).cloned().collect();
+        let next_cursor = match (items.last(), rest.next()) {
+            (Some(last), Some(_)) => Some(cursor_after(last.id)),
+            _ => None,
+        };
+        Ok(Page { items, next_cursor })
+    }
+}
+
+/// The cursor of a page ending with the item `id`.
+fn cursor_after(id: u64) -> String {
+    format!("after-{:x}", id)
+}
+
+fn parse_cursor(cursor: &str) -> Option<u64> {
+    let id = cursor.strip_prefix("after-")?;
+    // `from_str_radix` takes a sign, which no cursor has.
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(id, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(page: &Page) -> Vec<u64> {
+        page.items.iter().map(|item| item.id).collect()
+    }
+
+    #[test]
+    fn pages_walk_every_item_once_in_order() {
+        let items = Items::default();
+        let mut cursor = None;
+        let mut seen = Vec::new();
+        let mut pages = 0;
+        loop {
+            let page = items.page(cursor.as_deref(), 40).unwrap();
+            assert!(page.items.len() <= 40);
+            seen.extend(ids(&page));
+            pages += 1;
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(seen, (1..=SEEDED).collect::<Vec<_>>());
+        assert_eq!(pages, 7);
+    }
+
+    #[test]
+    fn cursors_are_stable() {
+        let items = Items::default();
+        let first = items.page(None, 10).unwrap();
+        let cursor = first.next_cursor.clone().unwrap();
+        assert_eq!(cursor, cursor_after(10));
+        let second = items.page(Some(&cursor), 10).unwrap();
+        assert_eq!(items.page(Some(&cursor), 10).unwrap(), second);
+        assert_eq!(ids(&second), (11..=20).collect::<Vec<_>>());
+        // A cursor names an item, not a page, so the next page may be of another length.
+        assert_eq!(ids(&items.page(Some(&cursor), 3).unwrap()), [11, 12, 13]);
+    }
+
+    #[test]
+    fn last_page_has_no_cursor() {
+        let items = Items::default();
+        let cursor = cursor_after(SEEDED - 5);
+        let page = items.page(Some(&cursor), 5).unwrap();
+        assert_eq!(ids(&page), (SEEDED - 4..=SEEDED).collect::<Vec<_>>());
+        assert_eq!(page.next_cursor, None);
+        let past = items.page(Some(&cursor_after(SEEDED)), 5).unwrap();
+        assert!(past.items.is_empty());
+        assert_eq!(past.next_cursor, None);
+    }
+
+    #[test]
+    fn limits_are_clamped() {
+        let items = Items::default();
+        assert_eq!(items.page(None, 0).unwrap().items.len(), 1);
+        assert_eq!(items.page(None, 1).unwrap().items.len(), 1);
+        let max = MAX_PAGE_LEN as usize;
+        assert_eq!(items.page(None, MAX_PAGE_LEN).unwrap().items.len(), max);
+        assert_eq!(items.page(None, MAX_PAGE_LEN + 1).unwrap().items.len(), max);
+        assert_eq!(items.page(None, u32::MAX).unwrap().items.len(), max);
+    }
+
+    #[test]
+    fn made_up_cursors_are_rejected() {
+        let items = Items::default();
+        for cursor in [
+            "",
+            "after-",
+            "after-+a",
+            "after--1",
+            "after-xyz",
+            "before-a",
+            "10",
+        ] {
+            assert_eq!(
+                items.page(Some(cursor), 10),
+                Err(WorldError::InvalidCursor),
+                "{:?}",
+                cursor
+            );
+        }
+        let overflow = format!("after-{}", "f".repeat(17));
+        assert_eq!(
+            items.page(Some(&overflow), 10),
+            Err(WorldError::InvalidCursor)
+        );
+    }
+
+    #[test]
+    fn cursors_are_hex_ids() {
+        assert_eq!(parse_cursor(&cursor_after(0xabc)), Some(0xabc));
+        assert_eq!(parse_cursor("after-A"), Some(10));
+        assert_eq!(parse_cursor(&cursor_after(u64::MAX)), Some(u64::MAX));
+    }
+}
@@ -0,0 +1,69 @@
+//! A cap on the connections open at once, see [`ConnectionLimit`].
+//!
+//! Every connection holds a [`ConnectionPermit`] for as long as its transport lives. Upgrades
+//! arriving while all permits are taken are answered with 503 Service Unavailable, before
+//! anything is spawned for them, and TCP connections are closed right after accepting.
+//! WebTransport sessions are refused the same way, and server-sent event streams are answered
+//! 503 too.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::info;
+
+/// Connections open at once unless `--max-connections` says otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Counts the connections open. Clones share the count.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    open: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        info!("Serving {} connections at most", max);
+        Self {
+            max,
+            open: Arc::default(),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Connections holding a permit right now.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// A permit for one more connection, `None` if `max` are open already.
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        let max = self.max;
+        let previous = self
+            .open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()?;
+        info!("{} of {} connections open", previous + 1, max);
+        Some(ConnectionPermit {
+            open: self.open.clone(),
+        })
+    }
+}
+
+/// One connection counted by a [`ConnectionLimit`], until it is dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let previous = self.open.fetch_sub(1, Ordering::AcqRel);
+        info!("Connection closed, {} open", previous - 1);
+    }
+}
@@ -1,45 +1,392 @@
-use futures::{StreamExt, TryStreamExt};
+use admin::AdminImpl;
+use clap::{CommandFactory, Parser};
+use config::ServerConfig;
+use deadline::Expired;
+use futures::{SinkExt, StreamExt, TryStream, TryStreamExt};
+use health::Health;
+use inflight::{InFlight, InFlightPolicy};
+use limit::ConnectionLimit;
 use log::info;
+use metrics::{decrement_gauge, increment_gauge};
+use middleware::{layered, BoxServe, Layer, Middleware};
+use mux::Services;
+use panics::CatchPanic;
+use payload::PayloadPolicy;
+use rpc::admin::Admin;
+use rpc::mux::{ServiceRequest, ServiceResponse};
 use rpc::World;
-use service_impl::WorldImpl;
-use tarpc::{
-    serde::{Deserialize, Serialize},
-    server::Channel,
-};
-use web::bind;
+use rate::{RateLimiter, RatePolicy};
+use request_log::{LogLayer, RequestLog};
+use service_impl::{Peers, WorldImpl};
+use shutdown::Shutdown;
+use static_files::StaticFiles;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tarpc::server::{BaseChannel, Channel, Serve};
+use tarpc::{ClientMessage, Response};
+use timeouts::TimeoutPolicy;
+use transport::{CarryIdentity, CarryMetadata, CarryOneWay, CarryPeerAddr, CarryPushes};
+use uploads::Uploads;
+use web::{bind, Endpoint};
 
+mod admin;
+mod auth;
+mod config;
+#[cfg(feature = "test-util")]
+mod control;
+mod deadline;
+mod disconnect;
+mod health;
+mod inflight;
+mod ip_filter;
+mod items;
+mod limit;
+mod metadata;
+mod middleware;
+mod mux;
+mod oneway;
+mod origin;
+mod panics;
+mod payload;
+mod prometheus;
+mod rate;
+mod request_log;
 mod service_impl;
+mod shutdown;
+mod static_files;
+#[cfg(feature = "sse")]
+mod sse;
+mod tasks;
+mod tcp;
+#[cfg(test)]
+mod testing;
+mod timeouts;
+#[cfg(feature = "tls")]
+mod tls;
+mod topics;
+mod transport;
+mod uploads;
 mod web;
+#[cfg(feature = "webtransport")]
+mod webtransport;
+
+#[cfg(all(feature = "test-util", not(debug_assertions)))]
+compile_error!("test-util lets anyone on the machine stop the server, leave it out of releases");
+
+/// Topic the server publishes its uptime to, see [`publish_uptime`].
+const UPTIME_TOPIC: &str = "uptime";
+const UPTIME_INTERVAL: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-    info!("First Message");
-
-    let server = build_server().await.expect("Failed to get server channel");
-    let stream = server.map_ok(move |x| {
-        info!("Mapping the client session");
-        let server = tarpc::server::BaseChannel::with_defaults(x);
-        let service = WorldImpl {};
-        info!("Spawning client channel");
-        tokio::spawn(server.execute(service.serve()))
+    let config = ServerConfig::parse();
+    if let Err(e) = config.validate() {
+        ServerConfig::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, e)
+            .exit();
+    }
+    if config.print_config {
+        print!("{}", config.describe());
+        return Ok(());
+    }
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = &config.log_level {
+        logger.parse_filters(level);
+    }
+    logger.init();
+
+    let peers = Arc::new(Peers::default());
+    let access = auth::from_config(&config);
+    let (trigger, shutdown) = shutdown::channel(config.drain_timeout());
+    // Watches without keeping the trigger waiting, unlike `shutdown`.
+    #[cfg(feature = "test-util")]
+    let begun = shutdown.watch();
+    let calls = Calls {
+        admins: Arc::new(auth::Admins::from_config(&config)),
+        rates: Arc::new(match &config.rate_limits {
+            Some(limits) => RatePolicy::parse(limits),
+            None => RatePolicy::default(),
+        }),
+        payloads: Arc::new(match &config.payload_limits {
+            Some(limits) => PayloadPolicy::parse(limits),
+            None => PayloadPolicy::default(),
+        }),
+        timeouts: Arc::new(match &config.method_timeouts {
+            Some(limits) => TimeoutPolicy::parse(limits),
+            None => TimeoutPolicy::default(),
+        }),
+        in_flight: InFlightPolicy {
+            max: config.max_in_flight,
+            queue: config.in_flight_queue,
+        },
+        expired: config.expired_calls,
+        layers: config.layers.clone(),
+        log: RequestLog {
+            every: config.request_log_every,
+        },
+        uploads: Arc::new(Uploads::new(
+            config.upload_dir(),
+            config.max_upload_size,
+            config.upload_timeout(),
+        )?),
+        max_delay: config.max_delay(),
+    };
+    tokio::spawn(uploads::abandon_stalled(calls.uploads.clone()));
+    prometheus::install(config.metrics_addr.on());
+    let limit = ConnectionLimit::new(config.max_connections);
+    let uptime = tokio::spawn(publish_uptime(peers.clone()));
+    tokio::spawn(admin::read_commands(peers.clone()));
+    let mut health = Health::new(limit.clone(), shutdown.watch()).with_check("uptime", move || {
+        if uptime.is_finished() {
+            Err("stopped publishing the uptime".into())
+        } else {
+            Ok(())
+        }
     });
 
-    //TODO: Will likely need a way to kill the connection. Need to figure that out.
-    let handle = tokio::spawn(stream.for_each(|_| async {}));
-    handle.await.unwrap();
+    #[cfg(feature = "sse")]
+    match config.sse_port.on() {
+        Some(port) => {
+            let ips = config.ip_filter().expect("address ranges are checked by validate");
+            let addr = config.addr(port);
+            let sessions = sse::bind(addr, access.clone(), ips, limit.clone()).await;
+            tokio::spawn(serve(sessions, peers.clone(), calls.clone(), shutdown.clone()));
+        }
+        None => info!("Not serving server-sent events, --sse-port is off"),
+    }
+
+    #[cfg(feature = "webtransport")]
+    match config.webtransport_port.on() {
+        Some(port) => {
+            let ips = config.ip_filter().expect("address ranges are checked by validate");
+            let (addr, tls) = (config.addr(port), config.tls());
+            let bound = webtransport::bind(addr, access.clone(), tls, ips, limit.clone()).await;
+            if let Some(sessions) = bound {
+                tokio::spawn(serve(sessions, peers.clone(), calls.clone(), shutdown.clone()));
+            }
+        }
+        None => info!("Not listening for WebTransport, --webtransport-port is off"),
+    }
+
+    match config.tcp_port.on() {
+        Some(port) => {
+            let listening = health.listener("tcp");
+            let listener = tcp::Listener {
+                addr: config.addr(port),
+                max_message: calls.payloads.max_message(),
+                ips: config.ip_filter().expect("address ranges are checked by validate"),
+                limit: limit.clone(),
+            };
+            let bound = tcp::bind(&access, listener, listening, shutdown.clone()).await;
+            if let Some(connections) = bound {
+                let (peers, calls) = (peers.clone(), calls.clone());
+                tokio::spawn(serve(connections, peers, calls, shutdown.clone()));
+            }
+        }
+        None => info!("Not listening for tarpc over TCP, --tcp-port is off"),
+    }
+
+    match config.ws_port.on() {
+        Some(port) => {
+            let listening = health.listener("websocket");
+            let addr = config.addr(port);
+            let files = config.static_dir.clone().map(|dir| Arc::new(StaticFiles::new(dir)));
+            let mut origins = config.origins().expect("origins are checked by validate");
+            if files.is_some() {
+                origins = origins.serving_pages_on(addr);
+            }
+            let endpoint = Endpoint {
+                addr,
+                tls: config.tls(),
+                max_message: calls.payloads.max_message(),
+                files,
+                origins,
+                ips: config.ip_filter().expect("address ranges are checked by validate"),
+            };
+            let server = bind(access, limit, endpoint, listening, shutdown.clone()).await?;
+            tokio::spawn(serve(server, peers, calls, shutdown));
+        }
+        None => {
+            info!("Not listening for WebSockets, --ws-port is off");
+            // The trigger waits for every clone, this one included.
+            drop(shutdown);
+        }
+    }
+    match config.health_port.on() {
+        Some(port) => {
+            tokio::spawn(health::serve(health, config.addr(port)));
+        }
+        None => info!("Not serving health endpoints, --health-port is off"),
+    }
+    #[cfg(feature = "test-util")]
+    let requested = {
+        let requests = Arc::new(tokio::sync::Notify::new());
+        if let Some(port) = config.control_port.on() {
+            tokio::spawn(control::serve(port, requests.clone(), begun));
+        }
+        control::requested(requests)
+    };
+    #[cfg(not(feature = "test-util"))]
+    let requested = std::future::pending::<()>();
+    tokio::select! {
+        _ = shutdown::signalled() => {}
+        _ = requested => {}
+    }
+    info!("Shutting down, letting the calls running finish");
+    trigger.begin();
+    if !trigger.finished().await {
+        info!("Connections were still open past the drain timeout");
+    }
     Ok(())
 }
 
-async fn build_server<Item, SinkItem>(
-) -> Option<impl TryStreamExt<Ok = impl tarpc::Transport<SinkItem, Item>, Error = std::io::Error>>
+/// How every connection's calls are served, see [`serve`].
+#[derive(Clone)]
+struct Calls {
+    admins: Arc<auth::Admins>,
+    rates: Arc<RatePolicy>,
+    payloads: Arc<PayloadPolicy>,
+    timeouts: Arc<TimeoutPolicy>,
+    in_flight: InFlightPolicy,
+    expired: Expired,
+    layers: Vec<Middleware>,
+    log: RequestLog,
+    uploads: Arc<Uploads>,
+    max_delay: Duration,
+}
+
+/// Runs the `World` and `Admin` services on every transport `transports` yields, until
+/// `shutdown` begins. Each connection calls them at the rates of `calls`.
+///
+/// Every call goes through the layers `calls` names, in their order. They log the call and
+/// answer it if its handler panics. They turn it away if its arguments are too large. They
+/// give up on it past its method's limit or its deadline, and drop it once its connection
+/// closed. They also cap how many calls of a connection run at once. One-way requests go
+/// through the same layers, see [`oneway`].
+async fn serve<T>(
+    transports: impl TryStream<Ok = T, Error = std::io::Error> + Send + 'static,
+    peers: Arc<Peers>,
+    calls: Calls,
+    shutdown: Shutdown,
+) where
+    T: tarpc::Transport<Response<ServiceResponse>, ClientMessage<ServiceRequest>>
+        + CarryPushes
+        + CarryMetadata
+        + CarryOneWay<ClientMessage<ServiceRequest>>
+        + CarryIdentity
+        + CarryPeerAddr
+        + Send
+        + 'static,
+{
+    let stopped = shutdown.begun();
+    transports
+        .into_stream()
+        .take_until(stopped)
+        .map_ok(move |mut x| {
+            info!("Mapping the client session");
+            let (push, pushes) = rpc::push::channel();
+            x.carry_pushes(pushes);
+            let (one_way, one_way_calls) = futures::channel::mpsc::unbounded();
+            x.carry_one_way(one_way);
+            let metadata = x.metadata();
+            let identity = x.identity();
+            let peer = x.peer_addr();
+            let server = tarpc::server::BaseChannel::with_defaults(x);
+            let admin = calls.admins.contains(identity.as_ref());
+            // Leaves `peers` once dropped, also if the task below never finishes normally.
+            let (session, joined) = peers.join(&push, peer, identity, admin);
+            // `World` and `Admin` methods have buckets of their own, but one connection's.
+            let rate = RateLimiter::new(calls.rates.clone());
+            let service = WorldImpl::new(
+                session.clone(),
+                peers.clone(),
+                push,
+                metadata,
+                shutdown.clone(),
+                rate.clone(),
+                calls.uploads.clone(),
+            )
+            .with_max_delay(calls.max_delay);
+            let admin_service = AdminImpl::new(session.clone(), peers.clone(), rate);
+            let (open, closed) = disconnect::channel();
+            let layers: Vec<Box<dyn Layer<ServiceRequest>>> = calls
+                .layers
+                .iter()
+                .map(|middleware| -> Box<dyn Layer<ServiceRequest>> {
+                    match middleware {
+                        Middleware::Log => Box::new(LogLayer {
+                            session: session.clone(),
+                            log: calls.log,
+                        }),
+                        Middleware::CatchPanic => Box::new(CatchPanic),
+                        Middleware::Payload => Box::new(calls.payloads.clone()),
+                        Middleware::Timeout => Box::new(calls.timeouts.clone()),
+                        Middleware::Deadline => Box::new(calls.expired),
+                        Middleware::Disconnect => Box::new(closed.clone()),
+                        Middleware::InFlight => Box::new(InFlight::new(calls.in_flight)),
+                    }
+                })
+                .collect();
+            match &session.identity {
+                Some(identity) => {
+                    info!("Spawning client channel for peer {} of {}", session.id, identity)
+                }
+                None => info!("Spawning client channel for peer {}", session.id),
+            }
+            tokio::spawn(async move {
+                increment_gauge!(prometheus::CONNECTIONS, 1.0);
+                let world = BoxServe::new(service.serve());
+                let services = Services::new(world, admin_service.serve());
+                let services = layered(BoxServe::new(services), &layers);
+                tokio::spawn(oneway::serve(one_way_calls, services.clone()));
+                execute(server, services).await;
+                // The calls still running have nobody to answer to.
+                drop(open);
+                decrement_gauge!(prometheus::CONNECTIONS, 1.0);
+                drop(joined);
+            })
+        })
+        .for_each(|_| async {})
+        .await
+}
+
+/// Runs the requests of `channel` on `serve` like tarpc's `Channel::execute`, then closes its
+/// transport, which tarpc leaves to whoever owns it. WebSockets drained by the shutdown send
+/// their close frame with code 1001 there.
+async fn execute<T, S>(channel: BaseChannel<ServiceRequest, ServiceResponse, T>, serve: S)
 where
-    Item: for<'de> Deserialize<'de> + Unpin,
-    SinkItem: Serialize + Unpin,
+    T: tarpc::Transport<Response<ServiceResponse>, ClientMessage<ServiceRequest>> + Send,
+    S: Serve<ServiceRequest, Resp = ServiceResponse> + Clone + Send + 'static,
+    S::Fut: Send,
 {
-    Some(
-        bind(tokio_serde::formats::Json::<Item, SinkItem>::default)
-            .await
-            .unwrap(),
-    )
+    let requests = channel.requests();
+    tokio::pin!(requests);
+    while let Some(request) = requests.next().await {
+        match request {
+            Ok(request) => {
+                tokio::spawn(request.execute(serve.clone()));
+            }
+            Err(e) => {
+                info!("Connection failed: {}", e);
+                break;
+            }
+        }
+    }
+    let mut transport = requests.channel_pin_mut().get_pin_ref();
+    if let Err(e) = transport.close().await {
+        info!("Connection didn't close cleanly: {}", e);
+    }
+}
+
+/// Publishes the seconds the server has been up to [`UPTIME_TOPIC`] every
+/// [`UPTIME_INTERVAL`].
+async fn publish_uptime(peers: Arc<Peers>) {
+    let started = Instant::now();
+    let mut ticks = tokio::time::interval(UPTIME_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let uptime = started.elapsed().as_secs();
+        // A number always serializes.
+        peers.topics.publish(UPTIME_TOPIC, &uptime).unwrap();
+    }
 }
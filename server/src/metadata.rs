@@ -0,0 +1,47 @@
+//! Where a connection keeps the metadata of its requests until their handlers ask for it, see
+//! [`rpc::metadata`].
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use rpc::metadata::{Metadata, RequestMetadata, MAX_METADATA_SIZE};
+use tarpc::context::Context;
+
+/// Requests whose metadata a connection keeps at most. Handlers that don't ask for theirs leave
+/// it behind, so the oldest is dropped first.
+const MAX_KEPT: usize = 64;
+
+/// The metadata of a connection's requests, shared by its transport and its handlers.
+#[derive(Clone, Default)]
+pub struct ReceivedMetadata(Arc<Mutex<VecDeque<RequestMetadata>>>);
+
+impl ReceivedMetadata {
+    /// Keeps the metadata of a request that just arrived. Metadata over [`MAX_METADATA_SIZE`]
+    /// is dropped, the handler finds none.
+    pub fn keep(&self, request: RequestMetadata) {
+        let size = request.metadata.size();
+        if size > MAX_METADATA_SIZE {
+            info!(
+                "Dropping {} bytes of metadata of trace {}, over the limit of {}",
+                size, request.trace_id, MAX_METADATA_SIZE
+            );
+            return;
+        }
+        let mut kept = self.0.lock().unwrap();
+        if kept.len() == MAX_KEPT {
+            kept.pop_front();
+        }
+        kept.push_back(request);
+    }
+
+    /// Takes the metadata the request of `ctx` came with, empty if it came without.
+    pub fn take(&self, ctx: &Context) -> Metadata {
+        let mut kept = self.0.lock().unwrap();
+        kept.iter()
+            .position(|request| request.trace_id == *ctx.trace_id())
+            .and_then(|index| kept.remove(index))
+            .map(|request| request.metadata)
+            .unwrap_or_default()
+    }
+}
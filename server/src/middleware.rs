@@ -0,0 +1,389 @@
+//! What runs around every call, as [`Layer`]s stacked on the services of a connection.
+//!
+//! Each concern is a `Serve` wrapping the next one, e.g.
+//! [`Logged`](crate::request_log::Logged) or [`Deadlined`](crate::deadline::Deadlined), and a
+//! [`Layer`] puts it around what it is given. [`layered`] stacks them in the order `--layers` names
+//! them, the first outermost: it sees every call first and its answer last. A layer can
+//! answer a call itself without calling what it wraps, e.g. with [`Call::failure`].
+//!
+//! Layers serve any [`Call`]. The server stacks them around the whole
+//! [`Services`](crate::mux::Services) of a connection, so `Admin` calls go through the same
+//! ones as `World` calls.
+//!
+//! Layers wrap a [`BoxServe`], which hides the type of what is below, so the order can be
+//! chosen at startup instead of being spelled out in the accept loop.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use clap::ValueEnum;
+use rpc::admin::{AdminRequest, AdminResponse};
+use rpc::mux::{ServiceRequest, ServiceResponse};
+use rpc::{WorldError, WorldRequest, WorldResponse};
+use tarpc::context::Context;
+use tarpc::server::Serve;
+
+use crate::deadline;
+use crate::request_log::{self, json_len};
+
+/// A concern `--layers` can name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Middleware {
+    /// Logs and measures every call, and counts it for `list_clients`.
+    Log,
+    /// Answers calls whose handler panicked with `WorldError::Internal`.
+    CatchPanic,
+    /// Answers calls with arguments over `--payload-limits`.
+    Payload,
+    /// Gives up on calls running longer than `--method-timeouts` lets their method.
+    Timeout,
+    /// Gives up on calls past their deadline, as `--expired-calls` says.
+    Deadline,
+    /// Drops the calls of connections that closed.
+    Disconnect,
+    /// Runs at most `--max-in-flight` calls of a connection at once.
+    InFlight,
+}
+
+/// Every [`Middleware`], outermost first, as the server runs them unless told otherwise.
+pub const DEFAULT_LAYERS: &[Middleware] = &[
+    Middleware::Log,
+    Middleware::CatchPanic,
+    Middleware::Payload,
+    Middleware::Timeout,
+    Middleware::Deadline,
+    Middleware::Disconnect,
+    Middleware::InFlight,
+];
+
+impl Middleware {
+    /// The name `--layers` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Middleware::Log => "log",
+            Middleware::CatchPanic => "catch-panic",
+            Middleware::Payload => "payload",
+            Middleware::Timeout => "timeout",
+            Middleware::Deadline => "deadline",
+            Middleware::Disconnect => "disconnect",
+            Middleware::InFlight => "in-flight",
+        }
+    }
+}
+
+/// A request the layers serve: of `World`, of `Admin` or of either behind the mux.
+pub trait Call: Send + 'static {
+    type Response: Send + 'static;
+
+    /// Bytes the arguments take as JSON, see [`json_len`].
+    fn arg_bytes(&self) -> usize;
+
+    /// How the request's method answers an error. Layers take it before handing the request
+    /// on and only build the response once the call fails.
+    fn failure(&self) -> Box<dyn FnOnce(WorldError) -> Self::Response + Send>;
+
+    /// What the call `response` answers failed with, if it did.
+    fn error(response: &Self::Response) -> Option<&WorldError>;
+}
+
+impl Call for WorldRequest {
+    type Response = WorldResponse;
+
+    fn arg_bytes(&self) -> usize {
+        json_len(self)
+    }
+
+    fn failure(&self) -> Box<dyn FnOnce(WorldError) -> WorldResponse + Send> {
+        Box::new(deadline::failure(self))
+    }
+
+    fn error(response: &WorldResponse) -> Option<&WorldError> {
+        request_log::error(response)
+    }
+}
+
+impl Call for AdminRequest {
+    type Response = AdminResponse;
+
+    fn arg_bytes(&self) -> usize {
+        json_len(self)
+    }
+
+    fn failure(&self) -> Box<dyn FnOnce(WorldError) -> AdminResponse + Send> {
+        Box::new(deadline::admin_failure(self))
+    }
+
+    fn error(response: &AdminResponse) -> Option<&WorldError> {
+        request_log::admin_error(response)
+    }
+}
+
+/// Measured and answered as the request of the service it names. Requests for unknown services
+/// have no arguments, and no error to answer with.
+impl Call for ServiceRequest {
+    type Response = ServiceResponse;
+
+    fn arg_bytes(&self) -> usize {
+        match self {
+            ServiceRequest::World(request) => request.arg_bytes(),
+            ServiceRequest::Admin(request) => request.arg_bytes(),
+            ServiceRequest::Unknown(_) => 0,
+        }
+    }
+
+    fn failure(&self) -> Box<dyn FnOnce(WorldError) -> ServiceResponse + Send> {
+        match self {
+            ServiceRequest::World(request) => {
+                let failure = deadline::failure(request);
+                Box::new(move |error| ServiceResponse::World(failure(error)))
+            }
+            ServiceRequest::Admin(request) => {
+                let failure = deadline::admin_failure(request);
+                Box::new(move |error| ServiceResponse::Admin(failure(error)))
+            }
+            &ServiceRequest::Unknown(id) => Box::new(move |_| ServiceResponse::Unknown(id)),
+        }
+    }
+
+    fn error(response: &ServiceResponse) -> Option<&WorldError> {
+        match response {
+            ServiceResponse::World(response) => request_log::error(response),
+            ServiceResponse::Admin(response) => request_log::admin_error(response),
+            ServiceResponse::Unknown(_) => None,
+        }
+    }
+}
+
+/// Puts a concern around the calls of one connection.
+pub trait Layer<R: Call = WorldRequest>: Send {
+    fn layer(&self, inner: BoxServe<R>) -> BoxServe<R>;
+}
+
+/// `inner` wrapped in `layers`, the first outermost.
+pub fn layered<R: Call>(inner: BoxServe<R>, layers: &[Box<dyn Layer<R>>]) -> BoxServe<R> {
+    layers
+        .iter()
+        .rev()
+        .fold(inner, |inner, layer| layer.layer(inner))
+}
+
+/// The future every [`Layer`]'s serve answers with.
+pub type Answer<Resp = WorldResponse> = Pin<Box<dyn Future<Output = Resp> + Send>>;
+
+/// Any `Serve<R>`, behind one type.
+pub struct BoxServe<R: Call = WorldRequest>(Box<dyn DynServe<R>>);
+
+impl<R: Call> BoxServe<R> {
+    pub fn new<S>(serve: S) -> Self
+    where
+        S: Serve<R, Resp = R::Response> + Clone + Send + 'static,
+        S::Fut: Send + 'static,
+    {
+        Self(Box::new(serve))
+    }
+}
+
+impl<R: Call> Clone for BoxServe<R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+impl<R: Call> Serve<R> for BoxServe<R> {
+    type Resp = R::Response;
+    type Fut = Answer<R::Response>;
+
+    fn method(&self, request: &R) -> Option<&'static str> {
+        self.0.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: R) -> Answer<R::Response> {
+        self.0.serve(ctx, request)
+    }
+}
+
+/// `Serve` as a trait object can be: taking a box, and cloned into one.
+trait DynServe<R: Call>: Send {
+    fn method(&self, request: &R) -> Option<&'static str>;
+    fn serve(self: Box<Self>, ctx: Context, request: R) -> Answer<R::Response>;
+    fn clone_box(&self) -> Box<dyn DynServe<R>>;
+}
+
+impl<S, R> DynServe<R> for S
+where
+    R: Call,
+    S: Serve<R, Resp = R::Response> + Clone + Send + 'static,
+    S::Fut: Send + 'static,
+{
+    fn method(&self, request: &R) -> Option<&'static str> {
+        <S as Serve<R>>::method(self, request)
+    }
+
+    fn serve(self: Box<Self>, ctx: Context, request: R) -> Answer<R::Response> {
+        Box::pin(<S as Serve<R>>::serve(*self, ctx, request))
+    }
+
+    fn clone_box(&self) -> Box<dyn DynServe<R>> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// What the layers of a test saw, in order.
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Names itself to the log on the way in and out, or fails the call on its own if `answers`.
+    struct Tag {
+        name: &'static str,
+        answers: bool,
+        log: Log,
+    }
+
+    impl Layer for Tag {
+        fn layer(&self, inner: BoxServe) -> BoxServe {
+            BoxServe::new(Tagged {
+                name: self.name,
+                answers: self.answers,
+                log: self.log.clone(),
+                inner,
+            })
+        }
+    }
+
+    #[derive(Clone)]
+    struct Tagged {
+        name: &'static str,
+        answers: bool,
+        log: Log,
+        inner: BoxServe,
+    }
+
+    impl Serve<WorldRequest> for Tagged {
+        type Resp = WorldResponse;
+        type Fut = Answer;
+
+        fn method(&self, request: &WorldRequest) -> Option<&'static str> {
+            Serve::method(&self.inner, request)
+        }
+
+        fn serve(self, ctx: Context, request: WorldRequest) -> Answer {
+            Box::pin(async move {
+                self.log.lock().unwrap().push(format!("{} in", self.name));
+                if self.answers {
+                    return WorldResponse::Ping(Err(WorldError::Internal(self.name.into())));
+                }
+                let response = self.inner.serve(ctx, request).await;
+                self.log.lock().unwrap().push(format!("{} out", self.name));
+                response
+            })
+        }
+    }
+
+    /// What the layers are stacked on, answering `pong`.
+    #[derive(Clone)]
+    struct World {
+        log: Log,
+    }
+
+    impl Serve<WorldRequest> for World {
+        type Resp = WorldResponse;
+        type Fut = Answer;
+
+        fn method(&self, _: &WorldRequest) -> Option<&'static str> {
+            Some("ping")
+        }
+
+        fn serve(self, _: Context, _: WorldRequest) -> Answer {
+            Box::pin(async move {
+                self.log.lock().unwrap().push("world".into());
+                WorldResponse::Ping(Ok("pong".into()))
+            })
+        }
+    }
+
+    /// Serves one ping through `layers`, each named and answering as given, outermost first.
+    /// Answers what the ping was answered and the log.
+    fn call(layers: &[(&'static str, bool)]) -> (Result<String, WorldError>, Vec<String>) {
+        let log = Log::default();
+        let layers: Vec<Box<dyn Layer>> = layers
+            .iter()
+            .map(|&(name, answers)| {
+                let tag = Tag {
+                    name,
+                    answers,
+                    log: log.clone(),
+                };
+                Box::new(tag) as Box<dyn Layer>
+            })
+            .collect();
+        let serve = layered(BoxServe::new(World { log: log.clone() }), &layers);
+        let response = block_on(serve.serve(tarpc::context::current(), WorldRequest::Ping {}));
+        let answer = match response {
+            WorldResponse::Ping(answer) => answer,
+            other => panic!("a ping answered {:?}", other),
+        };
+        let log = log.lock().unwrap().clone();
+        (answer, log)
+    }
+
+    #[test]
+    fn first_layer_is_outermost() {
+        let (answer, log) = call(&[("a", false), ("b", false), ("c", false)]);
+        assert_eq!(answer, Ok("pong".into()));
+        assert_eq!(
+            log,
+            ["a in", "b in", "c in", "world", "c out", "b out", "a out"]
+        );
+    }
+
+    #[test]
+    fn order_follows_the_list() {
+        let (_, log) = call(&[("c", false), ("a", false)]);
+        assert_eq!(log, ["c in", "a in", "world", "a out", "c out"]);
+    }
+
+    #[test]
+    fn no_layers_serve_directly() {
+        let (answer, log) = call(&[]);
+        assert_eq!(answer, Ok("pong".into()));
+        assert_eq!(log, ["world"]);
+    }
+
+    #[test]
+    fn failing_layer_skips_what_it_wraps() {
+        let (answer, log) = call(&[("a", false), ("b", true), ("c", false)]);
+        assert_eq!(answer, Err(WorldError::Internal("b".into())));
+        assert_eq!(log, ["a in", "b in", "a out"]);
+    }
+
+    #[test]
+    fn boxed_serves_keep_methods_and_clone() {
+        let log = Log::default();
+        let layers: Vec<Box<dyn Layer>> = vec![Box::new(Tag {
+            name: "a",
+            answers: false,
+            log: log.clone(),
+        })];
+        let serve = layered(BoxServe::new(World { log: log.clone() }), &layers);
+        assert_eq!(Serve::method(&serve, &WorldRequest::Ping {}), Some("ping"));
+        let copy = serve.clone();
+        block_on(serve.serve(tarpc::context::current(), WorldRequest::Ping {}));
+        block_on(copy.serve(tarpc::context::current(), WorldRequest::Ping {}));
+        assert_eq!(log.lock().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn names_parse_back() {
+        for &layer in DEFAULT_LAYERS {
+            assert_eq!(Middleware::from_str(layer.name(), false), Ok(layer));
+        }
+        assert_eq!(DEFAULT_LAYERS.len(), Middleware::value_variants().len());
+    }
+}
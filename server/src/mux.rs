@@ -0,0 +1,128 @@
+//! The services of a connection behind one `Serve`, see [`Services`].
+
+use std::future::{self, Future};
+use std::pin::Pin;
+
+use futures::FutureExt;
+use log::info;
+use rpc::admin::{AdminRequest, AdminResponse};
+use rpc::mux::{ServiceRequest, ServiceResponse};
+use tarpc::context::Context;
+use tarpc::server::Serve;
+
+use crate::middleware::BoxServe;
+
+/// Hands every request to the service it names. Requests for services the server doesn't know
+/// are answered [`ServiceResponse::Unknown`]. The layers are stacked around this rather than
+/// around `world`, so the calls of every service go through them.
+#[derive(Clone)]
+pub struct Services<A> {
+    world: BoxServe,
+    admin: A,
+}
+
+impl<A> Services<A> {
+    pub fn new(world: BoxServe, admin: A) -> Self {
+        Self { world, admin }
+    }
+}
+
+impl<A> Serve<ServiceRequest> for Services<A>
+where
+    A: Serve<AdminRequest, Resp = AdminResponse>,
+    A::Fut: Send + 'static,
+{
+    type Resp = ServiceResponse;
+    type Fut = Pin<Box<dyn Future<Output = ServiceResponse> + Send>>;
+
+    fn method(&self, request: &ServiceRequest) -> Option<&'static str> {
+        match request {
+            ServiceRequest::World(request) => self.world.method(request),
+            ServiceRequest::Admin(request) => self.admin.method(request),
+            ServiceRequest::Unknown(_) => None,
+        }
+    }
+
+    fn serve(self, ctx: Context, request: ServiceRequest) -> Self::Fut {
+        match request {
+            ServiceRequest::World(request) => {
+                Box::pin(self.world.serve(ctx, request).map(ServiceResponse::World))
+            }
+            ServiceRequest::Admin(request) => {
+                Box::pin(self.admin.serve(ctx, request).map(ServiceResponse::Admin))
+            }
+            ServiceRequest::Unknown(id) => {
+                info!(
+                    "call trace={} is for service {}, which isn't served",
+                    ctx.trace_id(),
+                    id
+                );
+                Box::pin(future::ready(ServiceResponse::Unknown(id)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use rpc::admin::Admin;
+    use rpc::WorldError;
+
+    use super::*;
+    use crate::admin::AdminImpl;
+    use crate::middleware::{layered, Layer};
+    use crate::panics::CatchPanic;
+    use crate::rate::{Rate, RateLimiter, RatePolicy};
+    use crate::request_log::{LogLayer, RequestLog};
+    use crate::service_impl::Peers;
+    use crate::testing::Sleeper;
+
+    #[tokio::test]
+    async fn admin_calls_are_logged_and_rate_limited() {
+        let peers = Arc::new(Peers::default());
+        let (push, _pushes) = rpc::push::channel();
+        let (session, _joined) = peers.join(&push, None, None, true);
+        // One call, and the next one in over ten minutes.
+        let rates = RatePolicy {
+            default: Rate::new(0.001, 1),
+            methods: HashMap::new(),
+        };
+        let rate = RateLimiter::new(Arc::new(rates));
+        let admin = AdminImpl::new(session.clone(), peers.clone(), rate);
+        let layers: Vec<Box<dyn Layer<ServiceRequest>>> = vec![
+            Box::new(LogLayer {
+                session: session.clone(),
+                log: RequestLog::default(),
+            }),
+            Box::new(CatchPanic),
+        ];
+        let services = Services::new(BoxServe::new(Sleeper::default()), admin.serve());
+        let services = layered(BoxServe::new(services), &layers);
+        let count = || {
+            let request = ServiceRequest::Admin(AdminRequest::Connections {});
+            services.clone().serve(tarpc::context::current(), request)
+        };
+
+        let answer = count().await;
+        assert!(
+            matches!(answer, ServiceResponse::Admin(AdminResponse::Connections(Ok(1)))),
+            "{:?}",
+            answer
+        );
+        let answer = count().await;
+        assert!(
+            matches!(
+                answer,
+                ServiceResponse::Admin(AdminResponse::Connections(Err(WorldError::RateLimited(_))))
+            ),
+            "{:?}",
+            answer
+        );
+        // `Logged` counts the calls it sees for `list_clients`.
+        assert_eq!(session.calls.load(Ordering::Relaxed), 2);
+    }
+}
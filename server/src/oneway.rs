@@ -0,0 +1,65 @@
+//! Serves the requests clients expect no response to, see [`serve`] and [`rpc::oneway`].
+//!
+//! tarpc's channel answers every request it reads, so transports hand one-way requests over
+//! here instead, see [`CarryOneWay`](crate::transport::CarryOneWay). They run through the
+//! same services and layers as the others, but what they answer goes nowhere: failures are
+//! logged, since the client never hears of them.
+
+use std::borrow::Cow;
+use std::time::SystemTime;
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::info;
+use rpc::mux::{ServiceRequest, ServiceResponse};
+use tarpc::server::Serve;
+use tarpc::ClientMessage;
+
+use crate::request_log;
+
+/// Runs every request coming out of `calls` on `serve`, each in a task of its own until it
+/// answered or its deadline passed. Ends once the connection's transport is dropped.
+pub async fn serve<S>(mut calls: mpsc::UnboundedReceiver<ClientMessage<ServiceRequest>>, serve: S)
+where
+    S: Serve<ServiceRequest, Resp = ServiceResponse> + Clone + Send + 'static,
+    S::Fut: Send + 'static,
+{
+    while let Some(message) = calls.next().await {
+        let request = match message {
+            ClientMessage::Request(request) => request,
+            // Nothing keeps track of one-way requests, so there is nothing to cancel.
+            _ => continue,
+        };
+        let ctx = request.context;
+        let method = serve.method(&request.message).unwrap_or("unknown");
+        let left = ctx
+            .deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        let answered = serve.clone().serve(ctx, request.message);
+        tokio::spawn(async move {
+            let outcome = match tokio::time::timeout(left, answered).await {
+                Ok(response) => outcome(&response),
+                Err(_) => "err:Timeout".into(),
+            };
+            if outcome != "ok" {
+                info!(
+                    "one-way call method={} trace={} outcome={}",
+                    method,
+                    ctx.trace_id(),
+                    outcome
+                );
+            }
+        });
+    }
+}
+
+fn outcome(response: &ServiceResponse) -> Cow<'static, str> {
+    match response {
+        ServiceResponse::World(response) => request_log::outcome(request_log::error(response)),
+        ServiceResponse::Admin(response) => {
+            request_log::outcome(request_log::admin_error(response))
+        }
+        ServiceResponse::Unknown(_) => "err:UnknownService".into(),
+    }
+}
@@ -0,0 +1,240 @@
+//! Which pages may open WebSocket connections, see [`OriginPolicy`].
+//!
+//! Browsers let any page open a socket to any server, only telling the server which page it
+//! is in the upgrade's `Origin` header. Without a check, a page elsewhere could call RPCs from
+//! a visitor's browser, from wherever the visitor's network reaches. Upgrades from origins the
+//! policy doesn't allow are answered 403 Forbidden before the handshake completes.
+//!
+//! Pages served by the server itself, see [`crate::static_files`], are allowed when they were
+//! loaded from the address the server listens on: their origin names the host the upgrade was
+//! sent to, and that host is the server's own. Any other host could be a name an attacker
+//! pointed at the server, so a page of it must be allowed like one from elsewhere. Clients
+//! outside browsers usually send no `Origin` at all, which is only let through when asked for.
+
+use std::fmt;
+use std::net::SocketAddr;
+
+/// The origins trunk serves the demo from during development.
+pub const DEFAULT_ORIGINS: &str = "http://127.0.0.1:8080,http://localhost:8080";
+
+/// An `--allowed-origins` entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Allowed {
+    /// `*`, every origin.
+    Any,
+    /// e.g. `https://app.example.com`.
+    Exact(String),
+    /// `https://*.example.com`, as the scheme and `.example.com`.
+    Subdomains { scheme: String, suffix: String },
+}
+
+impl Allowed {
+    fn allows(&self, origin: &str) -> bool {
+        match self {
+            Allowed::Any => true,
+            Allowed::Exact(allowed) => origin == allowed,
+            Allowed::Subdomains { scheme, suffix } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(suffix.as_str()))
+                .is_some_and(|subdomain| !subdomain.is_empty()),
+        }
+    }
+}
+
+/// An entry that isn't an origin.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidOrigin(pub String);
+
+impl fmt::Display for InvalidOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} is no origin, expected e.g. https://example.com or https://*.example.com",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidOrigin {}
+
+/// The origins allowed to connect.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OriginPolicy {
+    allowed: Vec<Allowed>,
+    /// Upgrades without an `Origin` are let through.
+    pub allow_missing: bool,
+    /// The `Host`s of the server's own pages, see [`serving_pages_on`](Self::serving_pages_on).
+    own_hosts: Vec<String>,
+}
+
+impl OriginPolicy {
+    /// The origins of `origins`, comma separated, `*` for any.
+    pub fn parse(origins: &str, allow_missing: bool) -> Result<Self, InvalidOrigin> {
+        let allowed = origins
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(parse_entry)
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            allowed,
+            allow_missing,
+            own_hosts: Vec::new(),
+        })
+    }
+
+    /// Also allows the server's own pages, loaded from `addr`, see the [module](self) docs.
+    pub fn serving_pages_on(mut self, addr: SocketAddr) -> Self {
+        self.own_hosts.push(addr.to_string());
+        self
+    }
+
+    /// Whether a page of `origin` may connect to `host`, the upgrade's `Host` header.
+    pub fn allows(&self, origin: Option<&str>, host: Option<&str>) -> bool {
+        let origin = match origin {
+            Some(origin) => origin.trim().to_ascii_lowercase(),
+            None => return self.allow_missing,
+        };
+        let own_host = host.filter(|host| {
+            self.own_hosts
+                .iter()
+                .any(|own| own.eq_ignore_ascii_case(host))
+        });
+        let same_origin = own_host.is_some_and(|host| {
+            origin
+                .split_once("://")
+                .is_some_and(|(_, origin_host)| origin_host.eq_ignore_ascii_case(host))
+        });
+        same_origin || self.allowed.iter().any(|allowed| allowed.allows(&origin))
+    }
+}
+
+fn parse_entry(entry: &str) -> Result<Allowed, InvalidOrigin> {
+    if entry == "*" {
+        return Ok(Allowed::Any);
+    }
+    let invalid = || InvalidOrigin(entry.into());
+    let lowered = entry.trim_end_matches('/').to_ascii_lowercase();
+    let (scheme, host) = lowered.split_once("://").ok_or_else(invalid)?;
+    if scheme.is_empty() || host.is_empty() || host.contains('/') {
+        return Err(invalid());
+    }
+    match host.strip_prefix('*') {
+        Some(suffix) if suffix.starts_with('.') && !suffix.contains('*') => {
+            Ok(Allowed::Subdomains {
+                scheme: format!("{}://", scheme),
+                suffix: suffix.into(),
+            })
+        }
+        Some(_) => Err(invalid()),
+        None if host.contains('*') => Err(invalid()),
+        None => Ok(Allowed::Exact(format!("{}://{}", scheme, host))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &str) -> OriginPolicy {
+        OriginPolicy::parse(origins, false).unwrap()
+    }
+
+    fn allows(policy: &OriginPolicy, origin: &str) -> bool {
+        policy.allows(Some(origin), Some("127.0.0.1:8000"))
+    }
+
+    #[test]
+    fn listed_origins_are_allowed() {
+        let policy = policy(DEFAULT_ORIGINS);
+        assert!(allows(&policy, "http://127.0.0.1:8080"));
+        assert!(allows(&policy, "http://localhost:8080"));
+        assert!(allows(&policy, "HTTP://LocalHost:8080"));
+        assert!(allows(&policy, " http://localhost:8080 "));
+    }
+
+    #[test]
+    fn other_origins_are_denied() {
+        let policy = policy(DEFAULT_ORIGINS);
+        assert!(!allows(&policy, "http://localhost:8081"));
+        assert!(!allows(&policy, "https://localhost:8080"));
+        assert!(!allows(&policy, "http://localhost:8080.evil.example"));
+        assert!(!allows(&policy, "http://evil.example"));
+        assert!(!allows(&policy, "null"));
+        assert!(!allows(&policy, ""));
+    }
+
+    #[test]
+    fn missing_origins_are_denied_unless_allowed() {
+        let denied = OriginPolicy::parse(DEFAULT_ORIGINS, false).unwrap();
+        assert!(!denied.allows(None, Some("127.0.0.1:8000")));
+        let allowed = OriginPolicy::parse(DEFAULT_ORIGINS, true).unwrap();
+        assert!(allowed.allows(None, Some("127.0.0.1:8000")));
+        assert!(allowed.allows(None, None));
+        assert!(!allowed.allows(Some("http://evil.example"), None));
+    }
+
+    #[test]
+    fn star_allows_every_origin() {
+        let policy = policy("*");
+        assert!(allows(&policy, "https://anything.example"));
+        assert!(allows(&policy, "null"));
+        assert!(!policy.allows(None, None));
+    }
+
+    #[test]
+    fn wildcards_allow_subdomains_only() {
+        let policy = policy("https://*.example.com");
+        assert!(allows(&policy, "https://app.example.com"));
+        assert!(allows(&policy, "https://a.b.example.com"));
+        assert!(!allows(&policy, "https://example.com"));
+        assert!(!allows(&policy, "https://.example.com"));
+        assert!(!allows(&policy, "http://app.example.com"));
+        assert!(!allows(&policy, "https://app.example.com.evil.example"));
+        assert!(!allows(&policy, "https://appexample.com"));
+    }
+
+    #[test]
+    fn entries_are_normalized() {
+        let policy = policy(" HTTPS://App.Example.com/ ,, ");
+        assert!(allows(&policy, "https://app.example.com"));
+        assert_eq!(OriginPolicy::parse("", false).unwrap().allowed, []);
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        for entry in [
+            "example.com",
+            "://example.com",
+            "https://",
+            "https://example.com/app",
+            "https://app.*.example.com",
+            "https://*example.com",
+            "https://*.*.example.com",
+        ] {
+            assert_eq!(
+                OriginPolicy::parse(entry, false),
+                Err(InvalidOrigin(entry.into())),
+                "{:?}",
+                entry
+            );
+        }
+        let err = OriginPolicy::parse("http://ok.example,nope", false).unwrap_err();
+        assert_eq!(err, InvalidOrigin("nope".into()));
+    }
+
+    #[test]
+    fn own_pages_are_allowed_on_the_address_served() {
+        let addr: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let policy = policy("https://app.example.com").serving_pages_on(addr);
+        assert!(policy.allows(Some("http://127.0.0.1:8000"), Some("127.0.0.1:8000")));
+        assert!(policy.allows(Some("https://127.0.0.1:8000"), Some("127.0.0.1:8000")));
+        // The page and the upgrade must both name the server's address.
+        assert!(!policy.allows(Some("http://127.0.0.1:8000"), Some("evil.example")));
+        assert!(!policy.allows(Some("http://evil.example"), Some("evil.example")));
+        assert!(!policy.allows(Some("http://localhost:8000"), Some("localhost:8000")));
+        assert!(!policy.allows(Some("http://127.0.0.1:8000"), None));
+        assert!(!policy.allows(Some("http://127.0.0.1:9000"), Some("127.0.0.1:8000")));
+        assert!(policy.allows(Some("https://app.example.com"), Some("evil.example")));
+    }
+}
@@ -0,0 +1,147 @@
+//! Handlers that panic fail their own call, see [`Caught`].
+//!
+//! Without this a panic unwinds through whatever polls the call, and the client only sees its
+//! call go unanswered or its socket close. [`Caught`] polls the call inside `catch_unwind` and
+//! answers a panic with [`WorldError::Internal`], so the connection keeps serving its other
+//! calls.
+//!
+//! Nothing the panicking call held is looked at again, so the `AssertUnwindSafe` holds. State
+//! it shares with other calls sits behind locks that poison, or atomics.
+
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+
+use futures::FutureExt;
+use log::error;
+use rpc::WorldError;
+use tarpc::context::Context;
+use tarpc::server::Serve;
+
+use crate::middleware::{BoxServe, Call, Layer};
+
+/// Puts [`Caught`] around the calls.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CatchPanic;
+
+impl<R: Call> Layer<R> for CatchPanic {
+    fn layer(&self, inner: BoxServe<R>) -> BoxServe<R> {
+        BoxServe::new(Caught::new(inner))
+    }
+}
+
+/// `serve` answering the calls that panic, see the [module](self) docs.
+#[derive(Clone)]
+pub struct Caught<S> {
+    serve: S,
+}
+
+impl<S> Caught<S> {
+    pub fn new(serve: S) -> Self {
+        Self { serve }
+    }
+}
+
+impl<S, R> Serve<R> for Caught<S>
+where
+    R: Call,
+    S: Serve<R, Resp = R::Response>,
+    S::Fut: Send + 'static,
+{
+    type Resp = R::Response;
+    type Fut = Pin<Box<dyn Future<Output = R::Response> + Send>>;
+
+    fn method(&self, request: &R) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: R) -> Self::Fut {
+        let method = self.serve.method(&request).unwrap_or("unknown");
+        let failure = request.failure();
+        // Handlers may panic before their future is made, as well as while it runs.
+        let answered = panic::catch_unwind(AssertUnwindSafe(|| self.serve.serve(ctx, request)));
+        Box::pin(async move {
+            let payload = match answered {
+                Ok(answered) => match AssertUnwindSafe(answered).catch_unwind().await {
+                    Ok(response) => return response,
+                    Err(payload) => payload,
+                },
+                Err(payload) => payload,
+            };
+            error!(
+                "call method={} trace={} panicked: {}",
+                method,
+                ctx.trace_id(),
+                message(payload.as_ref())
+            );
+            failure(WorldError::Internal("the server failed to answer".into()))
+        })
+    }
+}
+
+/// What `panic!` was given, if it was a message.
+fn message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message,
+            None => "(not a message)",
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpc::{WorldClient, WorldRequest, WorldResponse};
+    use tarpc::context;
+    use tarpc::server::{BaseChannel, Channel};
+
+    /// Serves `echo`, panicking on the values `"before"`, before making its future, and
+    /// `"during"`, while it runs.
+    #[derive(Clone)]
+    struct Panicky;
+
+    impl Serve<WorldRequest> for Panicky {
+        type Resp = WorldResponse;
+        type Fut = Pin<Box<dyn Future<Output = WorldResponse> + Send>>;
+
+        fn method(&self, _: &WorldRequest) -> Option<&'static str> {
+            Some("echo")
+        }
+
+        fn serve(self, _: Context, request: WorldRequest) -> Self::Fut {
+            let value = match request {
+                WorldRequest::Echo { value } => value,
+                _ => unreachable!("only echo is called"),
+            };
+            if value == "before" {
+                panic!("asked to panic before answering");
+            }
+            Box::pin(async move {
+                if value == "during" {
+                    panic!("asked to panic while answering");
+                }
+                WorldResponse::Echo(Ok(value))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn a_panic_fails_its_call_and_the_connection_serves_on() {
+        let (client, transport) = tarpc::transport::channel::unbounded();
+        let serving = BaseChannel::with_defaults(transport).execute(Caught::new(Panicky));
+        tokio::spawn(serving);
+        let client = WorldClient::new(tarpc::client::Config::default(), client);
+        tokio::spawn(client.dispatch);
+        let world = client.client;
+
+        for value in ["before", "during"] {
+            let answer = world.echo(context::current(), value.into()).await.unwrap();
+            assert!(matches!(answer, Err(WorldError::Internal(_))), "{:?}", answer);
+        }
+        let answer = world.echo(context::current(), "after".into()).await.unwrap();
+        assert_eq!(answer, Ok("after".to_string()));
+    }
+}
@@ -0,0 +1,284 @@
+//! How large a call's arguments may be, see [`PayloadPolicy`].
+//!
+//! Limits are enforced twice. The transports refuse messages larger than
+//! [`PayloadPolicy::max_message`], which fits the most generous method, before decoding them:
+//! WebSocket connections close with code 1009 (message too big), since what the message asked
+//! for is unknown. Calls that fit but are larger than their own method allows are answered
+//! [`WorldError::PayloadTooLarge`] by [`Limited`] before their handler runs.
+//!
+//! Arguments are measured as JSON, like the request log's `arg_bytes`, so a method allows the
+//! same calls whichever codec the client speaks.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use log::info;
+use rpc::bytes::MAX_ECHO_BYTES;
+use rpc::upload::MAX_CHUNK_LEN;
+use rpc::WorldError;
+use tarpc::context::Context;
+use tarpc::server::Serve;
+
+use crate::middleware::{BoxServe, Call, Layer};
+
+/// Bytes of arguments a method takes unless `--payload-limits` says otherwise.
+pub const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+
+/// Bytes a message may have on top of its arguments: its envelope, context and request id.
+const ENVELOPE_OVERHEAD: usize = 16 * 1024;
+
+/// The largest arguments of each method.
+#[derive(Clone, Debug)]
+pub struct PayloadPolicy {
+    /// Bytes of the methods without a limit of their own.
+    pub default: usize,
+    /// Bytes by method name, e.g. `"echo"`.
+    pub methods: HashMap<&'static str, usize>,
+}
+
+impl Default for PayloadPolicy {
+    /// `upload_chunk` and `echo_bytes` take their bytes as base64, four characters for every
+    /// three bytes.
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_MAX_PAYLOAD,
+            methods: HashMap::new(),
+        }
+        .with("upload_chunk", MAX_CHUNK_LEN.div_ceil(3) * 4 + ENVELOPE_OVERHEAD)
+        .with("echo_bytes", MAX_ECHO_BYTES.div_ceil(3) * 4 + ENVELOPE_OVERHEAD)
+    }
+}
+
+impl PayloadPolicy {
+    pub fn with(mut self, method: &'static str, limit: usize) -> Self {
+        self.methods.insert(method, limit);
+        self
+    }
+
+    pub fn limit(&self, method: &str) -> usize {
+        self.methods.get(method).copied().unwrap_or(self.default)
+    }
+
+    /// Bytes a transport takes in one message, enough for the calls of every method.
+    pub fn max_message(&self) -> usize {
+        let largest = self
+            .methods
+            .values()
+            .copied()
+            .fold(self.default, usize::max);
+        largest.saturating_add(ENVELOPE_OVERHEAD)
+    }
+
+    /// The default policy with the limits of `limits` instead, e.g. `echo=1024,*=65536` for
+    /// `echo` arguments of 1 KiB and 64 KiB for the other methods. Entries that don't parse
+    /// are ignored.
+    pub fn parse(limits: &str) -> Self {
+        let mut policy = Self::default();
+        for entry in limits
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(name, bytes)| Some((name.trim(), bytes.trim().parse().ok()?)));
+            match parsed {
+                Some(("*", bytes)) => policy.default = bytes,
+                Some((name, bytes)) => {
+                    let known = rpc::schema::METHODS
+                        .iter()
+                        .find(|method| method.name == name);
+                    match known {
+                        Some(method) => {
+                            policy.methods.insert(method.name, bytes);
+                        }
+                        None => info!("Ignoring the limit of {}, there is no such method", name),
+                    }
+                }
+                None => info!("Ignoring payload limit entry {:?}", entry),
+            }
+        }
+        policy
+    }
+}
+
+/// Answers the calls with arguments over the limit of their method, see [`Limited`].
+impl<R: Call> Layer<R> for Arc<PayloadPolicy> {
+    fn layer(&self, inner: BoxServe<R>) -> BoxServe<R> {
+        BoxServe::new(Limited::new(inner, self.clone()))
+    }
+}
+
+/// `serve` turning away calls with arguments larger than `policy` allows.
+#[derive(Clone)]
+pub struct Limited<S> {
+    serve: S,
+    policy: Arc<PayloadPolicy>,
+}
+
+impl<S> Limited<S> {
+    pub fn new(serve: S, policy: Arc<PayloadPolicy>) -> Self {
+        Self { serve, policy }
+    }
+}
+
+impl<S, R> Serve<R> for Limited<S>
+where
+    R: Call,
+    S: Serve<R, Resp = R::Response>,
+    S::Fut: Send + 'static,
+{
+    type Resp = R::Response;
+    type Fut = Pin<Box<dyn Future<Output = R::Response> + Send>>;
+
+    fn method(&self, request: &R) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: R) -> Self::Fut {
+        let method = self.serve.method(&request).unwrap_or("unknown");
+        let limit = self.policy.limit(method);
+        let len = request.arg_bytes();
+        if len <= limit {
+            return Box::pin(self.serve.serve(ctx, request));
+        }
+        info!(
+            "call method={} trace={} turned away, {} bytes of arguments are more than {}",
+            method,
+            ctx.trace_id(),
+            len,
+            limit
+        );
+        let too_large = request.failure()(WorldError::PayloadTooLarge(limit as u64));
+        Box::pin(std::future::ready(too_large))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use rpc::{WorldRequest, WorldResponse};
+
+    use super::*;
+    use crate::request_log::json_len;
+
+    /// Answers `echo` calls with their message.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Serve<WorldRequest> for Echo {
+        type Resp = WorldResponse;
+        type Fut = std::future::Ready<WorldResponse>;
+
+        fn method(&self, request: &WorldRequest) -> Option<&'static str> {
+            match request {
+                WorldRequest::Echo { .. } => Some("echo"),
+                _ => None,
+            }
+        }
+
+        fn serve(self, _: Context, request: WorldRequest) -> Self::Fut {
+            match request {
+                WorldRequest::Echo { value } => std::future::ready(WorldResponse::Echo(Ok(value))),
+                _ => panic!("only echo is served"),
+            }
+        }
+    }
+
+    fn echo(msg: &str) -> WorldRequest {
+        WorldRequest::Echo { value: msg.into() }
+    }
+
+    /// What echoing `msg` is answered under `policy`.
+    fn answer(policy: PayloadPolicy, msg: &str) -> Result<String, WorldError> {
+        let limited = Limited::new(Echo, Arc::new(policy));
+        match block_on(limited.serve(tarpc::context::current(), echo(msg))) {
+            WorldResponse::Echo(answer) => answer,
+            _ => panic!("echo answered another method"),
+        }
+    }
+
+    #[test]
+    fn arguments_up_to_the_limit_are_served() {
+        let msg = "x".repeat(100);
+        let len = json_len(&echo(&msg));
+        let at = PayloadPolicy::default().with("echo", len);
+        assert_eq!(answer(at, &msg), Ok(msg.clone()));
+        let roomier = PayloadPolicy::default().with("echo", len + 1);
+        assert_eq!(answer(roomier, &msg), Ok(msg));
+    }
+
+    #[test]
+    fn arguments_over_the_limit_are_turned_away() {
+        let msg = "x".repeat(100);
+        let len = json_len(&echo(&msg));
+        let below = PayloadPolicy::default().with("echo", len - 1);
+        assert_eq!(
+            answer(below, &msg),
+            Err(WorldError::PayloadTooLarge(len as u64 - 1))
+        );
+    }
+
+    #[test]
+    fn arguments_are_measured_as_json() {
+        // Quotes are escaped, so they take two bytes each.
+        let msg = "\"".repeat(10);
+        let len = json_len(&echo(&msg));
+        assert_eq!(len, json_len(&echo(&"x".repeat(20))));
+        let policy = PayloadPolicy::default().with("echo", len - 1);
+        assert!(answer(policy, &msg).is_err());
+    }
+
+    #[test]
+    fn methods_without_a_limit_take_the_default() {
+        let msg = "x".repeat(100);
+        let len = json_len(&echo(&msg));
+        let policy = PayloadPolicy {
+            default: len - 1,
+            ..PayloadPolicy::default()
+        };
+        assert_eq!(policy.limit("echo"), len - 1);
+        assert!(answer(policy.clone(), &msg).is_err());
+        let other = policy.with("upload_chunk", 1);
+        assert_eq!(
+            answer(other, &msg),
+            Err(WorldError::PayloadTooLarge(len as u64 - 1))
+        );
+    }
+
+    #[test]
+    fn largest_limit_decides_the_message_size() {
+        let policy = PayloadPolicy::default();
+        let largest = policy.limit("echo_bytes").max(policy.limit("upload_chunk"));
+        assert!(largest > DEFAULT_MAX_PAYLOAD);
+        assert_eq!(policy.max_message(), largest + ENVELOPE_OVERHEAD);
+        let policy = policy.with("echo", 64 << 20);
+        assert_eq!(policy.max_message(), (64 << 20) + ENVELOPE_OVERHEAD);
+        let policy = PayloadPolicy {
+            default: usize::MAX,
+            ..policy
+        };
+        assert_eq!(policy.max_message(), usize::MAX);
+    }
+
+    #[test]
+    fn parse_sets_limits() {
+        let policy = PayloadPolicy::parse("echo=1024, *=65536");
+        assert_eq!(policy.limit("echo"), 1024);
+        assert_eq!(policy.limit("ping"), 65536);
+        assert_eq!(
+            policy.limit("upload_chunk"),
+            PayloadPolicy::default().limit("upload_chunk")
+        );
+    }
+
+    #[test]
+    fn parse_ignores_bad_entries() {
+        let policy = PayloadPolicy::parse("shout=10,echo,echo=many,=5,,");
+        assert_eq!(policy.limit("echo"), DEFAULT_MAX_PAYLOAD);
+        assert!(!policy.methods.contains_key("shout"));
+        assert_eq!(policy.methods.len(), PayloadPolicy::default().methods.len());
+    }
+}
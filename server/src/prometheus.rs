@@ -0,0 +1,70 @@
+//! Prometheus metrics, scraped from `/metrics` on `--metrics-addr`.
+//!
+//! Calls are counted and timed by the same wrapper that logs them, see [`crate::request_log`],
+//! so the logs and the metrics always agree. Bytes are counted by the transports, see
+//! [`crate::transport`]. The names recorded are the constants below.
+
+use std::net::SocketAddr;
+
+use log::{error, info};
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+
+/// Where `/metrics` is served unless `--metrics-addr` says otherwise, or is `off`.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:9090";
+
+/// Calls answered, by `method` and `outcome` as the request log writes them.
+pub const REQUESTS: &str = "rpc_requests_total";
+/// How long calls took to answer, by `method`.
+pub const REQUEST_DURATION: &str = "rpc_request_duration_seconds";
+/// Bytes of messages received as they came off the wire, by `transport`: `websocket` or
+/// `webtransport`. tarpc's TCP listener frames its messages itself, they aren't counted.
+pub const BYTES_IN: &str = "rpc_request_bytes_total";
+/// Bytes of messages sent as they went on the wire, by `transport` like [`BYTES_IN`].
+pub const BYTES_OUT: &str = "rpc_response_bytes_total";
+/// Connections being served, of every transport.
+pub const CONNECTIONS: &str = "rpc_connections_active";
+/// Connections given up on before they were served, by the `stage` they failed at.
+pub const HANDSHAKE_FAILURES: &str = "rpc_handshake_failures_total";
+/// Connections closed right away because of their address, by `listener`, see
+/// [`crate::ip_filter`].
+pub const DENIED_CONNECTIONS: &str = "rpc_connections_denied_total";
+
+/// Seconds the latency histogram tells apart, from lookups to the longest delays.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0,
+];
+
+/// Records the metrics from now on and serves them on `addr`. Without it, or if it can't be
+/// bound, the metrics are not recorded at all.
+pub fn install(addr: Option<SocketAddr>) {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => {
+            info!("Not serving metrics, --metrics-addr is off");
+            return;
+        }
+    };
+    let installed = PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .set_buckets_for_metric(Matcher::Full(REQUEST_DURATION.into()), &DURATION_BUCKETS)
+        .and_then(|builder| builder.install());
+    if let Err(e) = installed {
+        error!("Failed to serve metrics on {}: {}", addr, e);
+        return;
+    }
+    describe_counter!(REQUESTS, "Calls answered");
+    describe_histogram!(REQUEST_DURATION, Unit::Seconds, "How long calls took");
+    describe_counter!(BYTES_IN, Unit::Bytes, "Bytes of messages received");
+    describe_counter!(BYTES_OUT, Unit::Bytes, "Bytes of messages sent");
+    describe_gauge!(CONNECTIONS, "Connections being served");
+    describe_counter!(
+        HANDSHAKE_FAILURES,
+        "Connections given up on before they were served"
+    );
+    describe_counter!(
+        DENIED_CONNECTIONS,
+        "Connections closed right away because of their address"
+    );
+    info!("Serving metrics on http://{}/metrics", addr);
+}
@@ -0,0 +1,226 @@
+//! How often a connection may call each method, see [`RateLimiter`].
+//!
+//! Every connection has a token bucket per method, filled at the method's [`Rate`] up to its
+//! burst. A call takes a token before its handler runs; a call finding the bucket empty is
+//! answered with [`WorldError::RateLimited`] and the time until the next token, so the client
+//! knows how long to back off. Connections never share buckets.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::info;
+use rpc::WorldError;
+
+/// How fast calls of a method may come.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    /// Calls per second in the long run.
+    pub per_second: f64,
+    /// Calls that may come at once after a quiet time.
+    pub burst: u32,
+}
+
+impl Rate {
+    pub const fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+/// The [`Rate`] of each method.
+#[derive(Clone, Debug)]
+pub struct RatePolicy {
+    /// Rate of the methods without one of their own.
+    pub default: Rate,
+    /// Rates by method name, e.g. `"echo"`.
+    pub methods: HashMap<&'static str, Rate>,
+}
+
+impl Default for RatePolicy {
+    /// Lookups are cheap, delays hold a task for as long as they wait.
+    fn default() -> Self {
+        Self {
+            default: Rate::new(10.0, 20),
+            methods: HashMap::new(),
+        }
+        .with("ping", Rate::new(20.0, 40))
+        .with("delay", Rate::new(0.5, 2))
+        .with("delay_with_progress", Rate::new(0.5, 2))
+        // A file takes a chunk every few hundred KiB, and several at once.
+        .with("upload_chunk", Rate::new(100.0, 200))
+    }
+}
+
+impl RatePolicy {
+    pub fn with(mut self, method: &'static str, rate: Rate) -> Self {
+        self.methods.insert(method, rate);
+        self
+    }
+
+    fn rate(&self, method: &str) -> Rate {
+        self.methods.get(method).copied().unwrap_or(self.default)
+    }
+
+    /// The default policy with the rates of `limits` instead, e.g. `echo=5/10,delay=1/1` for 5
+    /// `echo` calls a second with bursts of 10 and one `delay` a second. `*` sets the default.
+    /// Entries that don't parse are ignored.
+    pub fn parse(limits: &str) -> Self {
+        let mut policy = Self::default();
+        for entry in limits
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            match parse_entry(entry) {
+                Some(("*", rate)) => policy.default = rate,
+                Some((name, rate)) => {
+                    let known = rpc::schema::METHODS
+                        .iter()
+                        .find(|method| method.name == name);
+                    match known {
+                        Some(method) => {
+                            policy.methods.insert(method.name, rate);
+                        }
+                        None => info!("Ignoring the rate of {}, there is no such method", name),
+                    }
+                }
+                None => info!("Ignoring rate limit entry {:?}", entry),
+            }
+        }
+        policy
+    }
+}
+
+/// `method=per_second/burst`.
+fn parse_entry(entry: &str) -> Option<(&str, Rate)> {
+    let (method, rate) = entry.split_once('=')?;
+    let (per_second, burst) = rate.split_once('/')?;
+    let rate = Rate::new(per_second.trim().parse().ok()?, burst.trim().parse().ok()?);
+    (rate.per_second > 0.0 && rate.burst > 0).then_some((method.trim(), rate))
+}
+
+struct Bucket {
+    tokens: f64,
+    filled: Instant,
+}
+
+/// The buckets of one connection. Clones share them.
+#[derive(Clone)]
+pub struct RateLimiter {
+    policy: Arc<RatePolicy>,
+    buckets: Arc<Mutex<HashMap<&'static str, Bucket>>>,
+}
+
+impl RateLimiter {
+    /// Buckets for a new connection. `policy` is usually shared by all of them.
+    pub fn new(policy: Arc<RatePolicy>) -> Self {
+        Self {
+            policy,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Takes a token for a call of `method`, or fails with the milliseconds until there is one.
+    pub fn admit(&self, method: &'static str) -> Result<(), WorldError> {
+        let rate = self.policy.rate(method);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(method).or_insert(Bucket {
+            tokens: rate.burst.into(),
+            filled: now,
+        });
+        let refilled = now.duration_since(bucket.filled).as_secs_f64() * rate.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(rate.burst.into());
+        bucket.filled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let retry_after = (1.0 - bucket.tokens) / rate.per_second * 1000.0;
+        Err(WorldError::RateLimited(retry_after.ceil() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter(rate: Rate) -> RateLimiter {
+        RateLimiter::new(Arc::new(RatePolicy {
+            default: rate,
+            methods: HashMap::new(),
+        }))
+    }
+
+    #[test]
+    fn burst_then_rate_limited() {
+        let limiter = limiter(Rate::new(1.0, 3));
+        for _ in 0..3 {
+            assert_eq!(limiter.admit("echo"), Ok(()));
+        }
+        match limiter.admit("echo") {
+            Err(WorldError::RateLimited(retry_after)) => assert!(retry_after > 900),
+            other => panic!("admitted past the burst: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = limiter(Rate::new(100.0, 1));
+        assert_eq!(limiter.admit("echo"), Ok(()));
+        match limiter.admit("echo") {
+            Err(WorldError::RateLimited(retry_after)) => assert!(retry_after <= 10),
+            other => panic!("admitted past the burst: {:?}", other),
+        }
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(limiter.admit("echo"), Ok(()));
+    }
+
+    #[test]
+    fn refills_up_to_the_burst() {
+        let limiter = limiter(Rate::new(1000.0, 2));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(limiter.admit("echo"), Ok(()));
+        assert_eq!(limiter.admit("echo"), Ok(()));
+        assert!(limiter.admit("echo").is_err());
+    }
+
+    #[test]
+    fn connections_have_buckets_of_their_own() {
+        let policy = Arc::new(RatePolicy::default().with("echo", Rate::new(0.1, 1)));
+        let (first, second) = (RateLimiter::new(policy.clone()), RateLimiter::new(policy));
+        assert_eq!(first.admit("echo"), Ok(()));
+        assert!(first.admit("echo").is_err());
+        assert_eq!(second.admit("echo"), Ok(()));
+        // Clones are the same connection.
+        assert!(second.clone().admit("echo").is_err());
+    }
+
+    #[test]
+    fn methods_have_buckets_of_their_own() {
+        let limiter = limiter(Rate::new(0.1, 1));
+        assert_eq!(limiter.admit("echo"), Ok(()));
+        assert!(limiter.admit("echo").is_err());
+        assert_eq!(limiter.admit("ping"), Ok(()));
+    }
+
+    #[test]
+    fn parse_sets_rates() {
+        let policy = RatePolicy::parse("echo = 5/10, *=2/4,delay=1/1");
+        assert_eq!(policy.rate("echo"), Rate::new(5.0, 10));
+        assert_eq!(policy.rate("delay"), Rate::new(1.0, 1));
+        assert_eq!(policy.rate("version"), Rate::new(2.0, 4));
+        // Methods the entries leave out keep their defaults.
+        assert_eq!(policy.rate("ping"), RatePolicy::default().rate("ping"));
+    }
+
+    #[test]
+    fn parse_ignores_bad_entries() {
+        let policy = RatePolicy::parse("nonexistent=1/1,echo,echo=x/1,echo=0/1,echo=1/0,,");
+        assert_eq!(policy.rate("echo"), RatePolicy::default().rate("echo"));
+        assert!(!policy.methods.contains_key("nonexistent"));
+    }
+}
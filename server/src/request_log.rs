@@ -0,0 +1,244 @@
+//! One log line per call, written around the service instead of in every handler.
+//!
+//! The same wrapper records the calls in the [metrics](crate::prometheus) and counts them in
+//! the connection's [`Session`], so every method added to `World` or `Admin` is logged and
+//! measured without doing anything for it.
+//!
+//! [`Logged`] wraps the services of a connection and logs, once a call answered, its method,
+//! trace id, the client's address, the size of its arguments as JSON, how long it took and
+//! whether it succeeded or which [`WorldError`](rpc::WorldError) variant it failed with:
+//!
+//! `call method=echo trace=... peer=127.0.0.1:51234 arg_bytes=17 duration_ms=0.2 outcome=ok`
+//!
+//! Failed calls are always logged. Busy servers can log only every `--request-log-every`th
+//! call of a connection, or none with `0`, and then skip measuring the arguments of the
+//! others: their failures are logged with `arg_bytes=-`.
+
+use std::borrow::Cow;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::info;
+use metrics::{histogram, increment_counter};
+use rpc::admin::AdminResponse;
+use rpc::{WorldError, WorldResponse};
+use serde::Serialize;
+use tarpc::context::Context;
+use tarpc::server::Serve;
+
+use crate::middleware::{BoxServe, Call, Layer};
+use crate::prometheus::{REQUESTS, REQUEST_DURATION};
+use crate::service_impl::Session;
+
+/// Which calls get a line.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLog {
+    /// One of this many calls is logged even if it succeeded, none if 0.
+    pub every: u64,
+}
+
+impl Default for RequestLog {
+    fn default() -> Self {
+        Self { every: 1 }
+    }
+}
+
+impl RequestLog {
+    /// Whether the next of the calls counted in `seen` gets a line whatever its outcome.
+    fn samples(&self, seen: &AtomicU64) -> bool {
+        let seen = seen.fetch_add(1, Ordering::Relaxed);
+        self.every != 0 && seen.is_multiple_of(self.every)
+    }
+}
+
+/// `serve` logging every call as [`RequestLog`] says, see the [module](self) docs.
+#[derive(Clone)]
+pub struct Logged<S> {
+    serve: S,
+    session: Arc<Session>,
+    log: RequestLog,
+    /// Calls seen so far by the clones of this, for sampling.
+    seen: Arc<AtomicU64>,
+}
+
+impl<S> Logged<S> {
+    /// Logs the calls `serve` answers for the connection of `session`.
+    pub fn new(serve: S, session: Arc<Session>, log: RequestLog) -> Self {
+        Self {
+            serve,
+            session,
+            log,
+            seen: Arc::default(),
+        }
+    }
+}
+
+/// Logs the calls of the connection of `session`, see [`Logged`]. Calls are sampled among
+/// those of the connection.
+pub struct LogLayer {
+    pub session: Arc<Session>,
+    pub log: RequestLog,
+}
+
+impl<R: Call> Layer<R> for LogLayer {
+    fn layer(&self, inner: BoxServe<R>) -> BoxServe<R> {
+        BoxServe::new(Logged::new(inner, self.session.clone(), self.log))
+    }
+}
+
+impl<S, R> Serve<R> for Logged<S>
+where
+    R: Call,
+    S: Serve<R, Resp = R::Response>,
+    S::Fut: Send + 'static,
+{
+    type Resp = R::Response;
+    type Fut = Pin<Box<dyn Future<Output = R::Response> + Send>>;
+
+    fn method(&self, request: &R) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: R) -> Self::Fut {
+        let method = self.serve.method(&request).unwrap_or("unknown");
+        self.session.calls.fetch_add(1, Ordering::Relaxed);
+        let sampled = self.log.samples(&self.seen);
+        let arg_bytes = sampled.then(|| request.arg_bytes());
+        let started = Instant::now();
+        let answered = self.serve.serve(ctx, request);
+        let peer = self.session.peer;
+        Box::pin(async move {
+            let response = answered.await;
+            let duration = started.elapsed();
+            let error = R::error(&response);
+            let outcome = outcome(error);
+            increment_counter!(REQUESTS, "method" => method, "outcome" => outcome.clone());
+            histogram!(REQUEST_DURATION, duration, "method" => method);
+            if sampled || error.is_some() {
+                let peer = match peer {
+                    Some(peer) => peer.to_string(),
+                    None => "-".into(),
+                };
+                let arg_bytes = match arg_bytes {
+                    Some(len) => len.to_string(),
+                    None => "-".into(),
+                };
+                info!(
+                    "call method={} trace={} peer={} arg_bytes={} duration_ms={:.1} outcome={}",
+                    method,
+                    ctx.trace_id(),
+                    peer,
+                    arg_bytes,
+                    duration.as_secs_f64() * 1000.0,
+                    outcome
+                );
+            }
+            response
+        })
+    }
+}
+
+/// Bytes `value` takes as JSON, without keeping them.
+pub(crate) fn json_len(value: &impl Serialize) -> usize {
+    struct Count(usize);
+
+    impl io::Write for Count {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut count = Count(0);
+    // Requests and responses always serialize, tarpc sends them that way.
+    serde_json::to_writer(&mut count, value).unwrap();
+    count.0
+}
+
+/// `ok`, or `err:` and the [`WorldError`] variant the call failed with.
+pub fn outcome(error: Option<&WorldError>) -> Cow<'static, str> {
+    let variant = match error {
+        None => return "ok".into(),
+        Some(WorldError::InvalidArgument(_)) => "InvalidArgument",
+        Some(WorldError::Timeout) => "Timeout",
+        Some(WorldError::Unavailable(_)) => "Unavailable",
+        Some(WorldError::Internal(_)) => "Internal",
+        Some(WorldError::RateLimited(_)) => "RateLimited",
+        Some(WorldError::Busy) => "Busy",
+        Some(WorldError::Upload(_)) => "Upload",
+        Some(WorldError::InvalidCursor) => "InvalidCursor",
+        Some(WorldError::PayloadTooLarge(_)) => "PayloadTooLarge",
+        Some(WorldError::PermissionDenied(_)) => "PermissionDenied",
+    };
+    format!("err:{}", variant).into()
+}
+
+/// What the call `response` answers failed with, if it did.
+pub fn error(response: &WorldResponse) -> Option<&WorldError> {
+    match response {
+        WorldResponse::Ping(answer) => answer.as_ref().err(),
+        WorldResponse::Echo(answer) => answer.as_ref().err(),
+        WorldResponse::Delay(answer) => answer.as_ref().err(),
+        WorldResponse::PeerId(answer) => answer.as_ref().err(),
+        WorldResponse::Signal(answer) => answer.as_ref().err(),
+        WorldResponse::Signals(answer) => answer.as_ref().err(),
+        WorldResponse::Count(answer) => answer.as_ref().err(),
+        WorldResponse::Subscribe(answer) => answer.as_ref().err(),
+        WorldResponse::Unsubscribe(answer) => answer.as_ref().err(),
+        WorldResponse::Publish(answer) => answer.as_ref().err(),
+        WorldResponse::DelayWithProgress(answer) => answer.as_ref().err(),
+        WorldResponse::Whoami(answer) => answer.as_ref().err(),
+        WorldResponse::ListClients(answer) => answer.as_ref().err(),
+        WorldResponse::UploadBegin(answer) => answer.as_ref().err(),
+        WorldResponse::UploadChunk(answer) => answer.as_ref().err(),
+        WorldResponse::UploadFinish(answer) => answer.as_ref().err(),
+        WorldResponse::ListItems(answer) => answer.as_ref().err(),
+        WorldResponse::Increment(answer) => answer.as_ref().err(),
+        WorldResponse::GetCount(answer) => answer.as_ref().err(),
+        WorldResponse::Version(answer) => answer.as_ref().err(),
+        WorldResponse::EchoBytes(answer) => answer.as_ref().err(),
+        WorldResponse::CreateTask(answer) => answer.as_ref().err(),
+        WorldResponse::LogEvent(answer) => answer.as_ref().err(),
+    }
+}
+
+/// [`error`] for the responses of `Admin`.
+pub fn admin_error(response: &AdminResponse) -> Option<&WorldError> {
+    match response {
+        AdminResponse::Connections(answer) => answer.as_ref().err(),
+        AdminResponse::Announce(answer) => answer.as_ref().err(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes_name_the_error_variant() {
+        assert_eq!(outcome(error(&WorldResponse::Ping(Ok("pong".into())))), "ok");
+        let failed = WorldResponse::Echo(Err(WorldError::Internal("oops".into())));
+        assert_eq!(outcome(error(&failed)), "err:Internal");
+        let denied = WorldError::PermissionDenied("admins only".into());
+        let failed = AdminResponse::Announce(Err(denied));
+        assert_eq!(outcome(admin_error(&failed)), "err:PermissionDenied");
+    }
+
+    #[test]
+    fn every_connection_samples_its_own_calls() {
+        let log = RequestLog { every: 3 };
+        let (first, second) = (AtomicU64::default(), AtomicU64::default());
+        let sampled: Vec<bool> = (0..6).map(|_| log.samples(&first)).collect();
+        assert_eq!(sampled, [true, false, false, true, false, false]);
+        assert!(log.samples(&second), "the first call of a connection is logged");
+        assert!(!RequestLog { every: 0 }.samples(&second));
+    }
+}
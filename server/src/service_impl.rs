@@ -1,28 +1,816 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use log::info;
-use rpc::World;
+use rpc::bytes::MAX_ECHO_BYTES;
+use rpc::handshake::SUPPORTED_VERSIONS;
+use rpc::metadata::{Metadata, ECHO_KEY};
+use rpc::oneway::MAX_EVENT_NAME_LEN;
+use rpc::progress::Progress;
+use rpc::push::{Notification, PushHandle, ANNOUNCEMENT_TOPIC};
+use rpc::trace::label;
+use rpc::{
+    Bytes, ClientInfo, CreateTaskRequest, Page, SessionInfo, Task, UploadResult, VersionInfo,
+    World, WorldError, MAX_COUNT,
+};
+use serde::de::IgnoredAny;
+use serde::Serialize;
 use tarpc::context;
-use tokio::time::{sleep_until, Instant};
+use tokio::time::{sleep, sleep_until, Instant};
 
+use crate::auth::Identity;
+use crate::items::Items;
+use crate::metadata::ReceivedMetadata;
+use crate::rate::RateLimiter;
+use crate::shutdown::{shutting_down, Shutdown};
+use crate::tasks::Tasks;
+use crate::topics::Topics;
+use crate::uploads::Uploads;
+
+/// How long `delay` and `delay_with_progress` may wait unless configured otherwise.
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// State every connection shares: the sessions and signaling mailboxes of the connected
+/// clients, keyed by peer id, where to push to them and their subscriptions, the items
+/// they list, the count they add to and their tasks. What belongs to one connection is in
+/// its [`Session`].
+#[derive(Default)]
+pub struct Peers {
+    last_id: AtomicU64,
+    /// Every connection open, see `World::list_clients`.
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+    mailboxes: Mutex<HashMap<u64, Vec<(u64, String)>>>,
+    /// Where to push notifications for each peer.
+    pushes: Mutex<HashMap<u64, PushHandle>>,
+    /// What the peers subscribed to, see `World::subscribe`.
+    pub topics: Topics,
+    /// What `World::list_items` pages through.
+    pub items: Items,
+    /// What `World::increment` adds to.
+    pub count: AtomicI64,
+    /// What `World::create_task` stores.
+    pub tasks: Tasks,
+}
+
+impl Peers {
+    /// Gives a new connection from `peer`, opened with a token of `identity`, its peer id and
+    /// session, and tells the other peers about it. It stays listed until its [`Joined`] is
+    /// dropped.
+    pub fn join(
+        self: &Arc<Self>,
+        push: &PushHandle,
+        peer: Option<SocketAddr>,
+        identity: Option<Identity>,
+        admin: bool,
+    ) -> (Arc<Session>, Joined) {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Arc::new(Session {
+            id,
+            peer,
+            identity,
+            admin,
+            connected: SystemTime::now(),
+            calls: AtomicU64::new(0),
+        });
+        self.sessions.lock().unwrap().insert(id, session.clone());
+        self.mailboxes.lock().unwrap().insert(id, Vec::new());
+        self.broadcast("peer_joined", &id);
+        self.pushes.lock().unwrap().insert(id, push.clone());
+        let joined = Joined {
+            peers: self.clone(),
+            id,
+        };
+        (session, joined)
+    }
+
+    /// Drops the session, mailbox and subscriptions of a connection that went away and tells
+    /// the other peers.
+    fn leave(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+        self.mailboxes.lock().unwrap().remove(&id);
+        self.pushes.lock().unwrap().remove(&id);
+        self.topics.leave(id);
+        self.broadcast("peer_left", &id);
+    }
+
+    /// Notifies every peer of `topic` with `payload`, answering how many it reached.
+    /// Connections found gone on the way are forgotten, they just haven't left yet.
+    pub fn broadcast<T: Serialize>(&self, topic: &str, payload: &T) -> usize {
+        let notification = match Notification::new(topic, payload) {
+            Ok(notification) => notification,
+            Err(e) => {
+                info!("Not broadcasting to {}, the payload doesn't serialize: {}", topic, e);
+                return 0;
+            }
+        };
+        let mut pushes = self.pushes.lock().unwrap();
+        pushes.retain(|id, push| match push.notify(notification.clone()) {
+            Ok(()) => true,
+            Err(_) => {
+                info!("Peer {} is gone, not pushing to it anymore", id);
+                false
+            }
+        });
+        pushes.len()
+    }
+
+    /// Shows `text` on every connected page, see [`ANNOUNCEMENT_TOPIC`].
+    pub fn announce(&self, text: &str) -> usize {
+        self.broadcast(ANNOUNCEMENT_TOPIC, &text)
+    }
+
+    /// How many connections are open.
+    pub fn connections(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// The connections open, by peer id.
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|session| session.client_info())
+            .collect();
+        clients.sort_by_key(|client| client.peer_id);
+        clients
+    }
+}
+
+/// A connection listed in [`Peers`]. Dropping it makes the connection leave, however its task
+/// ended.
+pub struct Joined {
+    peers: Arc<Peers>,
+    id: u64,
+}
+
+impl Drop for Joined {
+    fn drop(&mut self) {
+        self.peers.leave(self.id);
+    }
+}
+
+/// Who one connection is, fixed once it is accepted, and how many calls it made.
+#[derive(Debug)]
+pub struct Session {
+    /// Peer id, see `World::peer_id`.
+    pub id: u64,
+    /// Where the connection came from, if its transport tells.
+    pub peer: Option<SocketAddr>,
+    /// Whose token opened the connection, `None` if it needed none.
+    pub identity: Option<Identity>,
+    /// Whether the identity may call the admin methods, e.g. `list_clients`.
+    pub admin: bool,
+    pub connected: SystemTime,
+    /// Calls made so far, counted as they arrive.
+    pub calls: AtomicU64,
+}
+
+impl Session {
+    /// `self` as `whoami` answers it.
+    pub fn info(&self) -> SessionInfo {
+        let connected_at = self.connected.duration_since(UNIX_EPOCH).unwrap_or_default();
+        SessionInfo {
+            peer_id: self.id,
+            peer_addr: self.peer.map(|peer| peer.to_string()),
+            identity: self.identity.as_ref().map(|identity| identity.0.clone()),
+            connected_at: connected_at.as_millis() as u64,
+        }
+    }
+
+    /// `self` as `list_clients` answers it.
+    pub fn client_info(&self) -> ClientInfo {
+        let info = self.info();
+        ClientInfo {
+            peer_id: info.peer_id,
+            peer_addr: info.peer_addr,
+            identity: info.identity,
+            connected_at: info.connected_at,
+            calls: self.calls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The service of one connection, cloned for every call it makes. The clones share its
+/// [`Session`], and every connection's share the [`Peers`].
 #[derive(Clone)]
-pub struct WorldImpl {}
+pub struct WorldImpl {
+    session: Arc<Session>,
+    peers: Arc<Peers>,
+    /// Where the notifications and streams of this connection go.
+    push: PushHandle,
+    /// What its transport received along with the requests.
+    metadata: ReceivedMetadata,
+    shutdown: Shutdown,
+    /// Checked before every call is handled.
+    rate: RateLimiter,
+    uploads: Arc<Uploads>,
+    /// Delays longer than this are rejected.
+    max_delay: Duration,
+}
+
+impl WorldImpl {
+    /// Creates the service for a new connection, which joined `peers` as `session`.
+    pub fn new(
+        session: Arc<Session>,
+        peers: Arc<Peers>,
+        push: PushHandle,
+        metadata: ReceivedMetadata,
+        shutdown: Shutdown,
+        rate: RateLimiter,
+        uploads: Arc<Uploads>,
+    ) -> Self {
+        Self {
+            session,
+            peers,
+            push,
+            metadata,
+            shutdown,
+            rate,
+            uploads,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+
+    /// Rejects delays longer than `max_delay` instead.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Fails with `WorldError::InvalidArgument` if waiting `duration` takes longer than the
+    /// server allows, or with `WorldError::Timeout` if it ends past the deadline of `ctx`,
+    /// which would only have tarpc drop the answer.
+    fn check_delay(&self, ctx: &context::Context, duration: Duration) -> Result<(), WorldError> {
+        if duration > self.max_delay {
+            info!(
+                "Delay of {:?} rejected, longer than {:?} ({})",
+                duration,
+                self.max_delay,
+                label(ctx)
+            );
+            return Err(WorldError::InvalidArgument(format!(
+                "a delay of {} ms is longer than the {} ms allowed",
+                duration.as_millis(),
+                self.max_delay.as_millis()
+            )));
+        }
+        let left = ctx.deadline.duration_since(SystemTime::now()).unwrap_or_default();
+        if duration > left {
+            info!("Delay of {:?} ends after the deadline ({})", duration, label(ctx));
+            return Err(WorldError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// The metadata the request of `ctx` came with, see [`rpc::metadata`]. Only the first
+    /// call gets it.
+    fn metadata(&self, ctx: &context::Context) -> Metadata {
+        self.metadata.take(ctx)
+    }
+}
 
 #[tarpc::server]
 #[async_trait::async_trait]
 impl World for WorldImpl {
-    async fn ping(self, _: context::Context) -> Result<String, String> {
-        info!("Ping Called.. responding with Pong!");
+    async fn ping(self, ctx: context::Context) -> Result<String, WorldError> {
+        self.rate.admit("ping")?;
+        info!("Ping Called.. responding with Pong! ({})", label(&ctx));
         Ok("Pong".into())
     }
-    async fn echo(self, _: context::Context, value: String) -> Result<String, String> {
-        info!("Echo Called.. responding with {}!", value);
-        Ok(value)
+    async fn echo(self, ctx: context::Context, value: String) -> Result<String, WorldError> {
+        self.rate.admit("echo")?;
+        info!("Echo Called.. responding with {}! ({})", value, label(&ctx));
+        match self.metadata(&ctx).get(ECHO_KEY) {
+            Some(extra) => Ok(format!("{} ({})", value, extra)),
+            None => Ok(value),
+        }
+    }
+    async fn delay(self, ctx: context::Context, millis: u64) -> Result<String, WorldError> {
+        self.rate.admit("delay")?;
+        info!("Delayed called! ({})", label(&ctx));
+        let duration = Duration::from_millis(millis);
+        self.check_delay(&ctx, duration)?;
+        tokio::select! {
+            _ = sleep(duration) => {}
+            _ = self.shutdown.drained() => {
+                info!("Delay cut off by the shutdown ({})", label(&ctx));
+                return Err(shutting_down());
+            }
+        }
+        info!("Delay ended! ({})", label(&ctx));
+        Ok(format!("Delayed for {} ms", millis))
+    }
+    async fn peer_id(self, ctx: context::Context) -> Result<u64, WorldError> {
+        self.rate.admit("peer_id")?;
+        info!("Peer {} asked for its id ({})", self.session.id, label(&ctx));
+        Ok(self.session.id)
+    }
+    async fn signal(
+        self,
+        ctx: context::Context,
+        to: u64,
+        payload: String,
+    ) -> Result<(), WorldError> {
+        self.rate.admit("signal")?;
+        info!(
+            "Relaying a signal from peer {} to peer {} ({})",
+            self.session.id,
+            to,
+            label(&ctx)
+        );
+        match self.peers.mailboxes.lock().unwrap().get_mut(&to) {
+            Some(mailbox) => {
+                mailbox.push((self.session.id, payload));
+                Ok(())
+            }
+            None => Err(WorldError::InvalidArgument(format!("No peer with id {}", to))),
+        }
+    }
+    async fn signals(self, _: context::Context) -> Result<Vec<(u64, String)>, WorldError> {
+        self.rate.admit("signals")?;
+        let mut mailboxes = self.peers.mailboxes.lock().unwrap();
+        Ok(mailboxes.get_mut(&self.session.id).map(std::mem::take).unwrap_or_default())
+    }
+    async fn count(self, ctx: context::Context, stream: u64, n: u64) -> Result<(), WorldError> {
+        self.rate.admit("count")?;
+        info!("Counting to {} as stream {} ({})", n, stream, label(&ctx));
+        let mut sender = self.push.open(stream);
+        let stopped = self.shutdown.begun();
+        tokio::spawn(async move {
+            tokio::pin!(stopped);
+            for i in 1..=n.min(MAX_COUNT) {
+                if i > 1 {
+                    tokio::select! {
+                        _ = sleep(Duration::from_secs(1)) => {}
+                        _ = &mut stopped => {
+                            sender.fail("The server is shutting down");
+                            return;
+                        }
+                    }
+                }
+                if sender.send(&i).is_err() {
+                    info!("Stream {} lost its client", stream);
+                    return;
+                }
+            }
+            if n > MAX_COUNT {
+                sender.fail(format!("Counting stops at {}", MAX_COUNT));
+            }
+        });
+        Ok(())
+    }
+    async fn subscribe(self, ctx: context::Context, topic: String) -> Result<(), WorldError> {
+        self.rate.admit("subscribe")?;
+        info!("Peer {} subscribed to {} ({})", self.session.id, topic, label(&ctx));
+        self.peers.topics.subscribe(&topic, self.session.id, self.push.clone());
+        Ok(())
+    }
+    async fn unsubscribe(self, ctx: context::Context, topic: String) -> Result<(), WorldError> {
+        self.rate.admit("unsubscribe")?;
+        info!("Peer {} unsubscribed from {} ({})", self.session.id, topic, label(&ctx));
+        self.peers.topics.unsubscribe(&topic, self.session.id);
+        Ok(())
+    }
+    async fn publish(
+        self,
+        ctx: context::Context,
+        topic: String,
+        payload: String,
+    ) -> Result<u64, WorldError> {
+        self.rate.admit("publish")?;
+        info!("Peer {} publishes to {} ({})", self.session.id, topic, label(&ctx));
+        let notification = Notification { topic, payload };
+        if let Err(e) = notification.payload::<IgnoredAny>() {
+            return Err(WorldError::InvalidArgument(format!(
+                "The payload isn't JSON: {}",
+                e
+            )));
+        }
+        Ok(self.peers.topics.send(notification) as u64)
     }
-    async fn delay(self, _:context::Context, duration: u64) -> Result<String, String> {
-        info!("Delayed called!");
-        sleep_until(Instant::now()+ Duration::from_secs(duration)).await;
-        info!("Delay ended!");
-        Ok(format!("Delayed for {} seconds", duration))
+    async fn delay_with_progress(
+        self,
+        ctx: context::Context,
+        millis: u64,
+        progress: u64,
+    ) -> Result<String, WorldError> {
+        self.rate.admit("delay_with_progress")?;
+        info!("Delayed with progress as stream {} ({})", progress, label(&ctx));
+        self.check_delay(&ctx, Duration::from_millis(millis))?;
+        let mut reports = self.push.open(progress);
+        let started = Instant::now();
+        let drained = self.shutdown.drained();
+        tokio::pin!(drained);
+        // Every second, and once the time is up.
+        for done in (0..millis).step_by(1000).chain([millis]) {
+            tokio::select! {
+                _ = sleep_until(started + Duration::from_millis(done)) => {}
+                _ = &mut drained => {
+                    info!("Delay cut off by the shutdown ({})", label(&ctx));
+                    return Err(shutting_down());
+                }
+            }
+            // A client that stopped listening still gets its answer.
+            let _ = reports.send(&Progress { done, total: millis });
+        }
+        info!("Delay ended! ({})", label(&ctx));
+        Ok(format!("Delayed for {} ms", millis))
+    }
+    async fn whoami(self, ctx: context::Context) -> Result<SessionInfo, WorldError> {
+        self.rate.admit("whoami")?;
+        info!("Peer {} asked who it is ({})", self.session.id, label(&ctx));
+        Ok(self.session.info())
+    }
+    async fn list_clients(self, ctx: context::Context) -> Result<Vec<ClientInfo>, WorldError> {
+        self.rate.admit("list_clients")?;
+        if !self.session.admin {
+            info!("Peer {} may not list the clients ({})", self.session.id, label(&ctx));
+            return Err(WorldError::PermissionDenied("only admins may list the clients".into()));
+        }
+        info!("Peer {} listed the clients ({})", self.session.id, label(&ctx));
+        Ok(self.peers.clients())
+    }
+    async fn upload_begin(
+        self,
+        ctx: context::Context,
+        name: String,
+        size: u64,
+    ) -> Result<u64, WorldError> {
+        self.rate.admit("upload_begin")?;
+        let id = self.uploads.begin(&name, size)?;
+        info!(
+            "Peer {} began upload {} of {:?}, {} bytes ({})",
+            self.session.id,
+            id,
+            name,
+            size,
+            label(&ctx)
+        );
+        Ok(id)
+    }
+    async fn upload_chunk(
+        self,
+        _: context::Context,
+        id: u64,
+        offset: u64,
+        bytes: Bytes,
+    ) -> Result<(), WorldError> {
+        self.rate.admit("upload_chunk")?;
+        let uploads = self.uploads.clone();
+        blocking(move || uploads.chunk(id, offset, &bytes)).await
+    }
+    async fn upload_finish(
+        self,
+        ctx: context::Context,
+        id: u64,
+        checksum: u32,
+    ) -> Result<UploadResult, WorldError> {
+        self.rate.admit("upload_finish")?;
+        let uploads = self.uploads.clone();
+        let uploaded = blocking(move || uploads.finish(id, checksum)).await?;
+        info!(
+            "Peer {} finished upload {}, {} bytes ({})",
+            self.session.id,
+            id,
+            uploaded.size,
+            label(&ctx)
+        );
+        Ok(uploaded)
+    }
+    async fn list_items(
+        self,
+        ctx: context::Context,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<Page, WorldError> {
+        self.rate.admit("list_items")?;
+        let page = self.peers.items.page(cursor.as_deref(), limit)?;
+        info!(
+            "Peer {} listed {} items after {} ({})",
+            self.session.id,
+            page.items.len(),
+            cursor.as_deref().unwrap_or("the start"),
+            label(&ctx)
+        );
+        Ok(page)
+    }
+
+    async fn increment(self, ctx: context::Context, by: i64) -> Result<i64, WorldError> {
+        self.rate.admit("increment")?;
+        // Adds atomically, so calls of every connection at once each count.
+        let previous = self
+            .peers
+            .count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_add(by)
+            })
+            .map_err(|count| {
+                WorldError::InvalidArgument(format!("adding {} to {} overflows", by, count))
+            })?;
+        let count = previous + by;
+        info!(
+            "Peer {} added {} to the count, now {} ({})",
+            self.session.id,
+            by,
+            count,
+            label(&ctx)
+        );
+        Ok(count)
+    }
+
+    async fn get_count(self, ctx: context::Context) -> Result<i64, WorldError> {
+        self.rate.admit("get_count")?;
+        let count = self.peers.count.load(Ordering::SeqCst);
+        info!("Peer {} read the count, {} ({})", self.session.id, count, label(&ctx));
+        Ok(count)
+    }
+    async fn version(self, ctx: context::Context) -> Result<VersionInfo, WorldError> {
+        self.rate.admit("version")?;
+        info!("Peer {} asked for the version ({})", self.session.id, label(&ctx));
+        Ok(VersionInfo {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: env!("GIT_HASH").to_string(),
+            // `build.rs` writes a number.
+            built_at: env!("BUILD_TIMESTAMP").parse().unwrap(),
+            protocols: SUPPORTED_VERSIONS.to_vec(),
+        })
+    }
+    async fn echo_bytes(self, ctx: context::Context, data: Bytes) -> Result<Bytes, WorldError> {
+        self.rate.admit("echo_bytes")?;
+        if data.len() > MAX_ECHO_BYTES {
+            return Err(WorldError::InvalidArgument(format!(
+                "echo_bytes takes at most {} bytes",
+                MAX_ECHO_BYTES
+            )));
+        }
+        info!("Peer {} echoed {} bytes ({})", self.session.id, data.len(), label(&ctx));
+        Ok(data)
+    }
+    async fn create_task(
+        self,
+        ctx: context::Context,
+        request: CreateTaskRequest,
+    ) -> Result<Task, WorldError> {
+        self.rate.admit("create_task")?;
+        let task = self.peers.tasks.create(request, self.session.id)?;
+        info!("Peer {} created task {} ({})", self.session.id, task.id, label(&ctx));
+        Ok(task)
+    }
+    async fn log_event(
+        self,
+        ctx: context::Context,
+        name: String,
+        payload: String,
+    ) -> Result<(), WorldError> {
+        self.rate.admit("log_event")?;
+        if name.is_empty() || name.len() > MAX_EVENT_NAME_LEN {
+            return Err(WorldError::InvalidArgument(format!(
+                "event names take 1 to {} bytes",
+                MAX_EVENT_NAME_LEN
+            )));
+        }
+        info!("Peer {} event {}: {:?} ({})", self.session.id, name, payload, label(&ctx));
+        Ok(())
+    }
+}
+
+/// Runs `f`, which reads or writes files, off the threads serving calls.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, WorldError> + Send + 'static,
+) -> Result<T, WorldError> {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => Err(WorldError::Internal(format!("the upload failed: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::{FutureExt, StreamExt};
+    use rpc::{Envelope, WorldClient};
+
+    use super::*;
+    use crate::testing::{connect, connect_as, uploads};
+
+    /// Calls made at once, by each connection.
+    const CALLS: i64 = 1000;
+
+    /// A client calling `world` over a loopback, the server and the dispatch spawned.
+    fn client_of(world: &WorldImpl) -> WorldClient {
+        let (client, server) = rpc::testing::loopback();
+        tokio::spawn(rpc::testing::serve(server, world.clone().serve()));
+        let client = WorldClient::new(tarpc::client::Config::default(), client);
+        tokio::spawn(client.dispatch);
+        client.client
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_increments_are_all_counted() {
+        let peers = Arc::new(Peers::default());
+        let uploads = uploads();
+        let connections: Vec<_> = (0..8).map(|_| connect(&peers, &uploads)).collect();
+        let mut tasks = Vec::new();
+        for connection in &connections {
+            for _ in 0..CALLS {
+                let world = connection.world.clone();
+                tasks.push(tokio::spawn(async move {
+                    world.increment(context::current(), 1).await.unwrap()
+                }));
+            }
+        }
+        let mut answers = Vec::new();
+        for task in tasks {
+            answers.push(task.await.unwrap());
+        }
+        let total = connections.len() as i64 * CALLS;
+        let world = connections[0].world.clone();
+        assert_eq!(world.get_count(context::current()).await, Ok(total));
+        // Each call answers the count right after its own addition, so no two answer the same.
+        answers.sort_unstable();
+        assert_eq!(answers, (1..=total).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn overflowing_increments_leave_the_count() {
+        let peers = Arc::new(Peers::default());
+        let connection = connect(&peers, &uploads());
+        let world = connection.world.clone();
+        peers.count.store(i64::MAX - 1, Ordering::SeqCst);
+        assert_eq!(
+            world.clone().increment(context::current(), 1).await,
+            Ok(i64::MAX)
+        );
+        let overflow = world.clone().increment(context::current(), 1).await;
+        assert!(
+            matches!(overflow, Err(WorldError::InvalidArgument(_))),
+            "{:?}",
+            overflow
+        );
+        assert_eq!(world.get_count(context::current()).await, Ok(i64::MAX));
+    }
+
+    #[tokio::test]
+    async fn connections_share_the_count() {
+        let peers = Arc::new(Peers::default());
+        let uploads = uploads();
+        let first = connect(&peers, &uploads);
+        let second = connect(&peers, &uploads);
+        assert_eq!(
+            first.world.clone().increment(context::current(), 5).await,
+            Ok(5)
+        );
+        assert_eq!(
+            second.world.clone().increment(context::current(), -2).await,
+            Ok(3)
+        );
+        assert_eq!(
+            first.world.clone().get_count(context::current()).await,
+            Ok(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admins_list_the_clients() {
+        let peers = Arc::new(Peers::default());
+        let uploads = uploads();
+        let admin = connect_as(&peers, &uploads, true);
+        let other = connect(&peers, &uploads);
+
+        let listed = admin.world.clone().list_clients(context::current()).await;
+        assert_eq!(listed.map(|clients| clients.len()), Ok(2));
+        let listed = other.world.clone().list_clients(context::current()).await;
+        assert!(
+            matches!(listed, Err(WorldError::PermissionDenied(_))),
+            "{:?}",
+            listed
+        );
+    }
+
+    /// The next notification pushed on `topic`, skipping peers joining and leaving.
+    async fn next_on(
+        pushes: &mut mpsc::UnboundedReceiver<Envelope<()>>,
+        topic: &str,
+    ) -> Notification {
+        loop {
+            match pushes.next().await {
+                Some(Envelope::Notification(notification)) if notification.topic == topic => {
+                    return notification
+                }
+                Some(_) => {}
+                None => panic!(
+                    "the connection was closed before a notification on {}",
+                    topic
+                ),
+            }
+        }
+    }
+
+    /// What is queued on `topic` without waiting for more.
+    fn queued_on(pushes: &mut mpsc::UnboundedReceiver<Envelope<()>>, topic: &str) -> usize {
+        std::iter::from_fn(|| pushes.next().now_or_never().flatten())
+            .filter(|push| matches!(push, Envelope::Notification(n) if n.topic == topic))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn publishing_reaches_the_other_clients_subscription() {
+        let peers = Arc::new(Peers::default());
+        let uploads = uploads();
+        let mut subscriber = connect(&peers, &uploads);
+        let mut publisher = connect(&peers, &uploads);
+        let subscribing = client_of(&subscriber.world);
+        let publishing = client_of(&publisher.world);
+
+        subscribing
+            .subscribe(context::current(), "news".into())
+            .await
+            .unwrap()
+            .unwrap();
+        let reached = publishing
+            .publish(
+                context::current(),
+                "news".into(),
+                r#"{"headline":"hi"}"#.into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reached, Ok(1));
+
+        let notification = next_on(&mut subscriber.pushes, "news").await;
+        assert_eq!(
+            notification.payload::<serde_json::Value>().unwrap(),
+            serde_json::json!({ "headline": "hi" })
+        );
+        assert_eq!(queued_on(&mut publisher.pushes, "news"), 0);
+        assert_eq!(queued_on(&mut subscriber.pushes, "news"), 0);
+    }
+
+    #[tokio::test]
+    async fn unsubscribed_clients_hear_nothing() {
+        let peers = Arc::new(Peers::default());
+        let uploads = uploads();
+        let mut subscriber = connect(&peers, &uploads);
+        let publisher = connect(&peers, &uploads);
+        let subscribing = client_of(&subscriber.world);
+        let publishing = client_of(&publisher.world);
+
+        for topic in ["news", "weather"] {
+            subscribing
+                .subscribe(context::current(), topic.into())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        subscribing
+            .unsubscribe(context::current(), "news".into())
+            .await
+            .unwrap()
+            .unwrap();
+        let news = publishing
+            .publish(context::current(), "news".into(), "1".into())
+            .await
+            .unwrap();
+        let weather = publishing
+            .publish(context::current(), "weather".into(), "2".into())
+            .await
+            .unwrap();
+        assert_eq!((news, weather), (Ok(0), Ok(1)));
+        assert_eq!(
+            next_on(&mut subscriber.pushes, "weather").await.payload,
+            "2"
+        );
+        assert_eq!(queued_on(&mut subscriber.pushes, "news"), 0);
+    }
+
+    #[tokio::test]
+    async fn gone_subscribers_are_forgotten() {
+        let peers = Arc::new(Peers::default());
+        let uploads = uploads();
+        let left = connect(&peers, &uploads);
+        let closed = connect(&peers, &uploads);
+        let publisher = connect(&peers, &uploads);
+        for connection in [&left, &closed] {
+            client_of(&connection.world)
+                .subscribe(context::current(), "news".into())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let publishing = client_of(&publisher.world);
+
+        // One connection left the peers, the other is still among them but its socket closed.
+        drop(left.joined);
+        drop(closed.pushes);
+        let reached = publishing
+            .publish(context::current(), "news".into(), "1".into())
+            .await
+            .unwrap();
+        assert_eq!(reached, Ok(0));
     }
 }
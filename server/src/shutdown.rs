@@ -0,0 +1,151 @@
+//! Stopping the server without cutting calls off, see [`channel`].
+//!
+//! Once SIGINT or SIGTERM arrives the server stops accepting connections, and each WebSocket
+//! and TCP connection stops reading requests. The calls already running get the drain timeout
+//! to finish; a connection whose calls all answered is closed, WebSockets with code 1001 (going
+//! away), so browsers see a normal closure. Delays still waiting when the timeout is up fail with
+//! [`WorldError::Unavailable`] instead of hanging, and the server exits once every connection
+//! closed, or shortly after the timeout if some didn't.
+
+use std::future::{self, Future};
+use std::time::Duration;
+
+use log::info;
+use rpc::WorldError;
+use tokio::sync::{mpsc, watch};
+
+/// How long the calls running at shutdown get to finish, unless `--drain-timeout` says
+/// otherwise.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long connections get past the drain timeout to send their last answers and close.
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+/// A shutdown the [`Trigger`] begins and every [`Shutdown`] clone watches. The trigger waits
+/// for the clones to be dropped, so whatever the server runs until it is done holds one.
+pub fn channel(drain_timeout: Duration) -> (Trigger, Shutdown) {
+    let (begin, begun) = watch::channel(false);
+    let (alive, done) = mpsc::channel(1);
+    let trigger = Trigger {
+        begin,
+        done,
+        drain_timeout,
+    };
+    let shutdown = Shutdown {
+        begun,
+        drain_timeout,
+        _alive: alive,
+    };
+    (trigger, shutdown)
+}
+
+/// Resolves once the process got SIGINT, or SIGTERM on Unix.
+pub async fn signalled() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => info!("Got SIGINT"),
+                    _ = terminate.recv() => info!("Got SIGTERM"),
+                }
+                return;
+            }
+            Err(e) => info!("Can't listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        info!(
+            "Can't listen for SIGINT either, running until killed: {}",
+            e
+        );
+        future::pending::<()>().await;
+    }
+}
+
+/// Begins the shutdown and waits for it.
+pub struct Trigger {
+    begin: watch::Sender<bool>,
+    done: mpsc::Receiver<()>,
+    drain_timeout: Duration,
+}
+
+impl Trigger {
+    pub fn begin(&self) {
+        self.begin.send_replace(true);
+    }
+
+    /// Waits until every [`Shutdown`] is dropped, for the drain timeout and a little longer
+    /// at most. `false` if some were still around.
+    pub async fn finished(mut self) -> bool {
+        let waited = self.drain_timeout + CLOSE_GRACE;
+        tokio::time::timeout(waited, self.done.recv()).await.is_ok()
+    }
+}
+
+/// The server's side of a shutdown. Clones watch the same one.
+#[derive(Clone)]
+pub struct Shutdown {
+    begun: watch::Receiver<bool>,
+    drain_timeout: Duration,
+    /// Never sent on; the trigger knows everything is done once every clone dropped it.
+    _alive: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    /// Resolves once the shutdown began, right away if it has. Holds no clone of `self`.
+    pub fn begun(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut begun = self.begun.clone();
+        async move {
+            while !*begun.borrow() {
+                // Without a trigger the shutdown never begins.
+                if begun.changed().await.is_err() {
+                    future::pending::<()>().await;
+                }
+            }
+        }
+    }
+
+    /// Tells whether the shutdown began without keeping the trigger waiting.
+    pub fn watch(&self) -> Begun {
+        Begun(self.begun.clone())
+    }
+
+    /// Resolves once the calls running had the drain timeout to finish since the shutdown
+    /// began. Calls still waiting for something then give up with [`shutting_down`].
+    pub fn drained(&self) -> impl Future<Output = ()> + Send + 'static {
+        let (begun, drain_timeout) = (self.begun(), self.drain_timeout);
+        async move {
+            begun.await;
+            tokio::time::sleep(drain_timeout).await;
+        }
+    }
+}
+
+/// Whether the shutdown began, for things outliving it like the health endpoints.
+#[derive(Clone)]
+pub struct Begun(watch::Receiver<bool>);
+
+impl Begun {
+    pub fn get(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the shutdown began, or never if it can't anymore.
+    #[cfg(feature = "test-util")]
+    pub async fn wait(&self) {
+        let mut begun = self.0.clone();
+        while !*begun.borrow() {
+            if begun.changed().await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// What calls cut off by the shutdown fail with.
+pub fn shutting_down() -> WorldError {
+    WorldError::Unavailable("The server is shutting down".into())
+}
@@ -0,0 +1,361 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use futures::channel::mpsc;
+use futures::{stream, Sink, Stream, StreamExt};
+use log::{debug, error, info};
+use metrics::increment_counter;
+use rpc::handshake::Welcome;
+use rpc::wire;
+use rpc::Envelope;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, Access, Identity};
+use crate::ip_filter::IpFilter;
+use crate::limit::{ConnectionLimit, ConnectionPermit};
+use crate::metadata::ReceivedMetadata;
+use crate::prometheus::DENIED_CONNECTIONS;
+use crate::transport::{CarryIdentity, CarryMetadata, CarryOneWay, CarryPeerAddr, CarryPushes};
+
+/// Port of the HTTP endpoints unless `--sse-port` says otherwise.
+pub const DEFAULT_PORT: u16 = 8084;
+
+/// The client page is served from another origin.
+const CORS: [(header::HeaderName, &str); 1] = [(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")];
+
+/// tarpc transport for a client talking over `POST /rpc` and `GET /rpc/events`.
+///
+/// Requests are fed in by the POST handler, responses go out as events on the client's
+/// event stream.
+pub struct SseTransport<Item, SinkItem> {
+    incoming: mpsc::UnboundedReceiver<Item>,
+    events: mpsc::UnboundedSender<String>,
+    /// Filled by the POST handler, like `incoming`.
+    metadata: ReceivedMetadata,
+    /// The one-way requests the POST handler received, until `carry_one_way` takes them.
+    one_way: Option<mpsc::UnboundedReceiver<Item>>,
+    /// Whose token opened the event stream.
+    identity: Option<Identity>,
+    _permit: ConnectionPermit,
+    ghost: PhantomData<fn(SinkItem)>,
+}
+
+impl<Item, SinkItem> Stream for SseTransport<Item, SinkItem> {
+    type Item = io::Result<Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().incoming.poll_next_unpin(cx).map(|item| item.map(Ok))
+    }
+}
+
+impl<Item, SinkItem> Sink<SinkItem> for SseTransport<Item, SinkItem>
+where
+    SinkItem: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> Result<(), Self::Error> {
+        let event = wire::encode_text(&Envelope::Message(item))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.get_mut()
+            .events
+            .unbounded_send(event)
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().events.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Notifications and stream frames become events like responses, in the order they are sent.
+impl<Item, SinkItem> CarryPushes for SseTransport<Item, SinkItem> {
+    fn carry_pushes(&mut self, pushes: mpsc::UnboundedReceiver<Envelope<()>>) {
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let mut pushes = pushes;
+            while let Some(envelope) = pushes.next().await {
+                let event = wire::encode_text(&envelope).unwrap();
+                if events.unbounded_send(event).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+impl<Item: Send + 'static, SinkItem> CarryOneWay<Item> for SseTransport<Item, SinkItem> {
+    fn carry_one_way(&mut self, calls: mpsc::UnboundedSender<Item>) {
+        if let Some(one_way) = self.one_way.take() {
+            tokio::spawn(one_way.map(Ok).forward(calls));
+        }
+    }
+}
+
+impl<Item, SinkItem> CarryMetadata for SseTransport<Item, SinkItem> {
+    fn metadata(&self) -> ReceivedMetadata {
+        self.metadata.clone()
+    }
+}
+
+impl<Item, SinkItem> CarryIdentity for SseTransport<Item, SinkItem> {
+    fn identity(&self) -> Option<Identity> {
+        self.identity.clone()
+    }
+}
+
+/// Requests come in separate POSTs, possibly over different connections.
+impl<Item, SinkItem> CarryPeerAddr for SseTransport<Item, SinkItem> {}
+
+struct Session<Item> {
+    incoming: mpsc::UnboundedSender<Item>,
+    one_way: mpsc::UnboundedSender<Item>,
+    events: mpsc::UnboundedSender<String>,
+    metadata: ReceivedMetadata,
+}
+
+/// Open sessions and where new transports are handed to the server.
+struct Hub<Item, SinkItem> {
+    sessions: Mutex<HashMap<String, Session<Item>>>,
+    ids: RandomState,
+    opened: AtomicUsize,
+    access: Access,
+    ips: IpFilter,
+    limit: ConnectionLimit,
+    transports: mpsc::UnboundedSender<io::Result<SseTransport<Item, SinkItem>>>,
+}
+
+impl<Item, SinkItem> Hub<Item, SinkItem> {
+    /// Session ids double as the only credential for POSTing into a session, so they are
+    /// randomly keyed rather than sequential.
+    fn session_id(&self) -> String {
+        let mut hasher = self.ids.build_hasher();
+        hasher.write_usize(self.opened.fetch_add(1, Ordering::Relaxed));
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// Whether `peer` may use the endpoints, counting it if not.
+    fn admits(&self, peer: SocketAddr) -> bool {
+        let admitted = self.ips.admits(peer.ip());
+        if !admitted {
+            info!("Turning {} away, its address isn't allowed", peer);
+            increment_counter!(DENIED_CONNECTIONS, "listener" => "sse");
+        }
+        admitted
+    }
+}
+
+/// Removes the session once its event stream is dropped, i.e. the client went away.
+struct SessionGuard<Item, SinkItem> {
+    hub: Arc<Hub<Item, SinkItem>>,
+    id: String,
+}
+
+impl<Item, SinkItem> Drop for SessionGuard<Item, SinkItem> {
+    fn drop(&mut self) {
+        info!("Event stream for session {} closed", self.id);
+        self.hub.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Deserialize)]
+struct SessionQuery {
+    session: String,
+}
+
+/// `EventSource` can't send headers either, so the token comes in the URL, see
+/// [`crate::auth`].
+#[derive(Deserialize)]
+struct EventsQuery {
+    token: Option<String>,
+}
+
+async fn events<Item, SinkItem>(
+    State(hub): State<Arc<Hub<Item, SinkItem>>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(query): Query<EventsQuery>,
+) -> Response
+where
+    Item: Send + 'static,
+    SinkItem: Send + 'static,
+{
+    if !hub.admits(peer) {
+        return (CORS, StatusCode::FORBIDDEN).into_response();
+    }
+    let permit = match hub.limit.try_acquire() {
+        Some(permit) => permit,
+        None => {
+            error!("Turning {} away, {} connections are open", peer, hub.limit.open());
+            return (CORS, StatusCode::SERVICE_UNAVAILABLE).into_response();
+        }
+    };
+    let identity = match auth::check(&hub.access, query.token.as_deref()) {
+        Ok(identity) => identity,
+        Err(rejected) => {
+            error!("Rejected an event stream: {}", rejected);
+            return (CORS, StatusCode::UNAUTHORIZED).into_response();
+        }
+    };
+    let (incoming_tx, incoming) = mpsc::unbounded();
+    let (one_way_tx, one_way) = mpsc::unbounded();
+    let (events_tx, events) = mpsc::unbounded();
+    let id = hub.session_id();
+    let metadata = ReceivedMetadata::default();
+    let session = Session {
+        incoming: incoming_tx,
+        one_way: one_way_tx,
+        events: events_tx.clone(),
+        metadata: metadata.clone(),
+    };
+    hub.sessions.lock().unwrap().insert(id.clone(), session);
+    info!("New event stream session: {}", id);
+    let transport = SseTransport {
+        incoming,
+        events: events_tx,
+        metadata,
+        one_way: Some(one_way),
+        identity,
+        _permit: permit,
+        ghost: PhantomData,
+    };
+    let _ = hub.transports.unbounded_send(Ok(transport));
+
+    let first = Event::default().event("session").data(id.clone());
+    let guard = SessionGuard { hub, id };
+    let messages = events.map(move |data| {
+        let _ = &guard;
+        Event::default().data(data)
+    });
+    let stream = stream::once(async { first })
+        .chain(messages)
+        .map(Ok::<_, Infallible>);
+    (CORS, Sse::new(stream).keep_alive(KeepAlive::default())).into_response()
+}
+
+async fn push<Item, SinkItem>(
+    State(hub): State<Arc<Hub<Item, SinkItem>>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(query): Query<SessionQuery>,
+    body: String,
+) -> impl IntoResponse
+where
+    Item: DeserializeOwned,
+{
+    // A session id alone doesn't let another address in.
+    if !hub.admits(peer) {
+        return (CORS, StatusCode::FORBIDDEN);
+    }
+    let envelope: Envelope<Item> = match wire::decode_text(&body) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            error!("Undecodable message for session {}: {}", query.session, e);
+            return (CORS, StatusCode::BAD_REQUEST);
+        }
+    };
+    let sessions = hub.sessions.lock().unwrap();
+    let session = match sessions.get(&query.session) {
+        Some(session) => session,
+        None => return (CORS, StatusCode::NOT_FOUND),
+    };
+    let delivered = match envelope {
+        Envelope::Message(message) => session.incoming.unbounded_send(message).is_ok(),
+        Envelope::Tagged(message, metadata) => {
+            session.metadata.keep(metadata);
+            session.incoming.unbounded_send(message).is_ok()
+        }
+        Envelope::OneWay(message) => session.one_way.unbounded_send(message).is_ok(),
+        Envelope::Heartbeat => {
+            debug!("Heartbeat received");
+            let ack = wire::encode_text(&Envelope::<()>::HeartbeatAck).unwrap();
+            session.events.unbounded_send(ack).is_ok()
+        }
+        // The token was already checked when the event stream opened.
+        Envelope::Auth(_) => {
+            let ack = wire::encode_text(&Envelope::<()>::AuthAck).unwrap();
+            session.events.unbounded_send(ack).is_ok()
+        }
+        Envelope::Hello(hello) => {
+            // Sessions only ever speak JSON, whatever the client's sockets would.
+            let welcome = Welcome::answer(&hello, rpc::protocol::JSON);
+            let welcome = Envelope::<()>::Welcome(welcome);
+            let welcome = wire::encode_text(&welcome).unwrap();
+            session.events.unbounded_send(welcome).is_ok()
+        }
+        Envelope::HeartbeatAck
+        | Envelope::AuthAck
+        | Envelope::Welcome(_)
+        | Envelope::Stream(_)
+        | Envelope::Notification(_) => true,
+    };
+    if delivered {
+        (CORS, StatusCode::ACCEPTED)
+    } else {
+        (CORS, StatusCode::GONE)
+    }
+}
+
+/// Serves `POST /rpc` and `GET /rpc/events` on `addr`, yielding a transport for
+/// every event stream a client opens with a token `access` lets in. Requests from addresses
+/// `ips` doesn't admit are answered 403 Forbidden, event streams past `limit` 503 Service
+/// Unavailable.
+pub async fn bind<Item, SinkItem>(
+    addr: SocketAddr,
+    access: Access,
+    ips: IpFilter,
+    limit: ConnectionLimit,
+) -> impl Stream<Item = io::Result<SseTransport<Item, SinkItem>>>
+where
+    Item: DeserializeOwned + Send + 'static,
+    SinkItem: Serialize + Send + 'static,
+{
+    info!("Binding server-sent events endpoints on {}", addr);
+    let (transports, accepted) = mpsc::unbounded();
+    let hub = Arc::new(Hub {
+        sessions: Mutex::new(HashMap::new()),
+        ids: RandomState::new(),
+        opened: AtomicUsize::new(0),
+        access,
+        ips,
+        limit,
+        transports,
+    });
+    let app = Router::new()
+        .route("/rpc", post(push::<Item, SinkItem>))
+        .route("/rpc/events", get(events::<Item, SinkItem>))
+        .with_state(hub);
+
+    tokio::spawn(async move {
+        info!("Bound, waiting on event stream clients");
+        // The handlers check the address of every request against the filter.
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        if let Err(e) = axum::Server::bind(&addr).serve(service).await {
+            error!("HTTP server failed: {}", e);
+        }
+    });
+    accepted
+}
@@ -0,0 +1,267 @@
+//! The built client, served on the WebSocket listener, see [`StaticFiles`].
+//!
+//! With `--static-dir` pointing at trunk's `dist/` output, the page and the RPC connection
+//! share one origin. The accept loop reads each connection's request head before the
+//! WebSocket handshake: upgrades go on to the handshake with the head handed back, anything
+//! else is answered from the directory here. Without `--static-dir` the head isn't read at
+//! all.
+//!
+//! Like the health endpoints, this is just enough HTTP/1.1: `GET` and `HEAD`, one request
+//! per connection, closed after the answer.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Bytes of a request read at most, headers included.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// How long a connection gets to send its request head.
+pub const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What files with hashed names are cached for, trunk gives every build new ones.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Hex digits of the shortest hash trunk puts in file names.
+const MIN_HASH_LEN: usize = 16;
+
+/// The page SPA routes fall back to.
+const INDEX: &str = "index.html";
+
+/// Files served from a directory.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+/// The request line and headers of a connection, and the bytes they came in.
+pub struct Head {
+    pub bytes: Vec<u8>,
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    /// The value of the header called `name`, in any case.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the request asks for a WebSocket.
+    pub fn is_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// Reads the request head off `stream`, `None` if it closes or sends too much first.
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Head>> {
+    let mut bytes = Vec::new();
+    let mut buf = [0; 1024];
+    while !bytes.windows(4).any(|end| end == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || bytes.len() + n > MAX_HEAD_LEN {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&buf[..n]);
+    }
+    let text = String::from_utf8_lossy(&bytes);
+    let mut lines = text.lines();
+    let mut words = lines.next().unwrap_or_default().split(' ');
+    let method = words.next().unwrap_or_default().to_string();
+    let path = words.next().unwrap_or_default().to_string();
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok(Some(Head {
+        bytes,
+        method,
+        path,
+        headers,
+    }))
+}
+
+impl StaticFiles {
+    /// Serves the files under `root`, which should hold an `index.html`.
+    pub fn new(root: PathBuf) -> Self {
+        if !root.join(INDEX).is_file() {
+            error!("{} has no {}, the page won't load", root.display(), INDEX);
+        }
+        Self { root }
+    }
+
+    /// Answers the request of `head` and closes `stream`.
+    pub async fn answer<S>(self: Arc<Self>, mut stream: S, head: Head) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let response = match head.method.as_str() {
+            "GET" | "HEAD" => {
+                let files = self.clone();
+                let path = head.path.clone();
+                let found = tokio::task::spawn_blocking(move || files.find(&path))
+                    .await
+                    .map_err(io::Error::other)?;
+                match found {
+                    Some(file) => self.respond(&head, file),
+                    None => Response::plain("404 Not Found", "not found"),
+                }
+            }
+            _ => Response::plain("405 Method Not Allowed", "only GET and HEAD are served"),
+        };
+        info!("{} {} {}", head.method, head.path, response.status);
+        stream
+            .write_all(&response.encode(head.method == "HEAD"))
+            .await?;
+        stream.shutdown().await
+    }
+
+    /// The file `path` asks for, or the index for paths without an extension that name no
+    /// file, so the page can route them itself.
+    fn find(&self, path: &str) -> Option<File> {
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let relative = Path::new(path.trim_start_matches('/'));
+        // Nothing outside the directory, however the path is spelled.
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+        let requested = self.root.join(relative);
+        let (path, fallback) = if requested.is_file() {
+            (requested, false)
+        } else if relative.as_os_str().is_empty() || relative.extension().is_none() {
+            (self.root.join(INDEX), true)
+        } else {
+            return None;
+        };
+        match std::fs::read(&path) {
+            Ok(body) => Some(File {
+                path,
+                body,
+                fallback,
+            }),
+            Err(e) => {
+                error!("Failed to read {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// `file`'s answer: cached for good if its name has a hash, revalidated by its ETag
+    /// otherwise.
+    fn respond(&self, head: &Head, file: File) -> Response {
+        let content_type = content_type(&file.path);
+        if !file.fallback && has_hash(&file.path) {
+            return Response {
+                status: "200 OK",
+                headers: vec![
+                    ("Content-Type", content_type.into()),
+                    ("Cache-Control", IMMUTABLE.into()),
+                ],
+                body: file.body,
+            };
+        }
+        let mut hasher = DefaultHasher::new();
+        file.body.hash(&mut hasher);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+        let headers = vec![
+            ("Content-Type", content_type.into()),
+            ("Cache-Control", "no-cache".into()),
+            ("ETag", etag.clone()),
+        ];
+        match head.header("if-none-match") {
+            Some(tags) if tags.split(',').any(|tag| tag.trim() == etag) => Response {
+                status: "304 Not Modified",
+                headers,
+                body: Vec::new(),
+            },
+            _ => Response {
+                status: "200 OK",
+                headers,
+                body: file.body,
+            },
+        }
+    }
+}
+
+/// A file found for a request.
+struct File {
+    path: PathBuf,
+    body: Vec<u8>,
+    /// The index, answering a path that names no file.
+    fallback: bool,
+}
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn plain(status: &'static str, body: &str) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "text/plain; charset=utf-8".into())],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    /// The bytes on the wire, without the body for `HEAD` requests.
+    fn encode(&self, head_only: bool) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in &self.headers {
+            response += &format!("{}: {}\r\n", name, value);
+        }
+        response += &format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        );
+        let mut bytes = response.into_bytes();
+        if !head_only {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+}
+
+/// The `Content-Type` of `path`, by its extension. Browsers only compile `.wasm` streaming
+/// when it is `application/wasm`.
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether trunk put a hash in `path`'s name, e.g. `client-4f1e0d9a3a2b7c6d_bg.wasm`.
+fn has_hash(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    name.split(['-', '_', '.'])
+        .any(|part| part.len() >= MIN_HASH_LEN && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
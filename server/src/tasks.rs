@@ -0,0 +1,59 @@
+//! The tasks `World::create_task` stores, see [`Tasks`] and [`rpc::tasks`].
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rpc::tasks::{Repeat, MAX_TAGS, MAX_TITLE_LEN};
+use rpc::{CreateTaskRequest, Task, WorldError};
+
+/// Every task created since the server started, in the order they were.
+#[derive(Default)]
+pub struct Tasks {
+    tasks: Mutex<Vec<Task>>,
+}
+
+impl Tasks {
+    /// Stores the task `request` describes as created by the peer `created_by`.
+    pub fn create(&self, request: CreateTaskRequest, created_by: u64) -> Result<Task, WorldError> {
+        let title = request.title.trim();
+        if title.is_empty() || title.chars().count() > MAX_TITLE_LEN {
+            return Err(WorldError::InvalidArgument(format!(
+                "titles have 1 to {} characters",
+                MAX_TITLE_LEN
+            )));
+        }
+        if request.repeat == Repeat::EveryDays(0) {
+            return Err(WorldError::InvalidArgument(
+                "tasks repeat every day at most".into(),
+            ));
+        }
+        let mut tags: Vec<String> = Vec::new();
+        for tag in request.tags.iter().map(|tag| tag.trim()) {
+            if !tag.is_empty() && !tags.iter().any(|known| known == tag) {
+                tags.push(tag.to_string());
+            }
+        }
+        if tags.len() > MAX_TAGS {
+            return Err(WorldError::InvalidArgument(format!(
+                "tasks have at most {} tags",
+                MAX_TAGS
+            )));
+        }
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut tasks = self.tasks.lock().unwrap();
+        let task = Task {
+            id: tasks.len() as u64 + 1,
+            title: title.to_string(),
+            priority: request.priority,
+            due: request.due,
+            tags,
+            repeat: request.repeat,
+            created_by,
+            created_at: created_at.as_millis() as u64,
+        };
+        tasks.push(task.clone());
+        Ok(task)
+    }
+}
@@ -0,0 +1,259 @@
+//! Plain tarpc over TCP for native clients, next to the WebSocket listener.
+//!
+//! Tools built on tarpc's own `serde_transport::tcp` connect here without pretending to be a
+//! browser: messages are length-delimited JSON, with none of the envelopes, heartbeats or
+//! handshakes of the WebSocket transport. Connections are served by the same `serve` loop and
+//! share the server's [`Peers`](crate::service_impl::Peers) with the WebSocket clients.
+//!
+//! A stock `WorldClient` sends plain `World` requests, without the service ids of
+//! [`rpc::mux`], so [`TcpConnection`] hands them on as the `World` requests of a multiplexed
+//! connection and answers with `World`'s responses alone. `Admin` isn't served over TCP.
+//!
+//! There is nowhere to put a token either, so the listener only runs when the server lets
+//! clients in without one, see [`crate::auth`].
+//!
+//! Connections count towards the server's [`ConnectionLimit`] like WebSocket ones, those over
+//! it are closed right after accepting. Once the server shuts down a connection reads no more
+//! requests and closes once tarpc answered the ones it has.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{future, ready, Sink, Stream, StreamExt, TryStreamExt};
+use log::{error, info};
+use metrics::increment_counter;
+use rpc::mux::{self, ServiceRequest, ServiceResponse};
+use rpc::{WorldRequest, WorldResponse};
+use serde::Serialize;
+use tarpc::serde_transport::Transport;
+use tarpc::tokio_serde::formats::Json;
+use tarpc::{context, trace, ClientMessage, Response, ServerError};
+use tokio::net::TcpStream;
+
+use crate::auth::Access;
+use crate::health::Listening;
+use crate::ip_filter::IpFilter;
+use crate::limit::{ConnectionLimit, ConnectionPermit};
+use crate::prometheus::DENIED_CONNECTIONS;
+use crate::shutdown::Shutdown;
+use crate::transport::{CarryIdentity, CarryMetadata, CarryOneWay, CarryPeerAddr, CarryPushes};
+
+/// Port of the listener unless `--tcp-port` says otherwise.
+pub const DEFAULT_PORT: u16 = 8085;
+
+/// What a native client's connection carries.
+pub type TcpTransport = Transport<
+    TcpStream,
+    ClientMessage<WorldRequest>,
+    Response<WorldResponse>,
+    Json<ClientMessage<WorldRequest>, Response<WorldResponse>>,
+>;
+
+/// A native client's connection, as the server serves it, one with `World`'s messages alone.
+pub struct TcpConnection {
+    transport: TcpTransport,
+    /// Resolves once the server shuts down, see [`crate::shutdown`].
+    shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// Counts the connection as open until it is dropped.
+    _permit: ConnectionPermit,
+}
+
+/// The fields of a `ClientMessage<ServiceRequest>`, see [`mux::rebuild`].
+#[derive(Serialize)]
+enum MessageFields {
+    Request(RequestFields),
+    Cancel {
+        trace_context: trace::Context,
+        request_id: u64,
+    },
+}
+
+/// The fields of a `Request<ServiceRequest>`, see [`MessageFields`].
+#[derive(Serialize)]
+struct RequestFields {
+    context: context::Context,
+    id: u64,
+    message: ServiceRequest,
+}
+
+/// The fields of a `Response<WorldResponse>`, see [`mux::rebuild`].
+#[derive(Serialize)]
+struct ResponseFields {
+    request_id: u64,
+    message: Result<WorldResponse, ServerError>,
+}
+
+impl Stream for TcpConnection {
+    type Item = io::Result<ClientMessage<ServiceRequest>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // tarpc answers the requests it has and closes the sink once this stream ended.
+        if this.shutdown.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        loop {
+            let fields = match ready!(this.transport.poll_next_unpin(cx)) {
+                Some(Ok(ClientMessage::Request(request))) => MessageFields::Request(RequestFields {
+                    context: request.context,
+                    id: request.id,
+                    message: ServiceRequest::World(request.message),
+                }),
+                Some(Ok(ClientMessage::Cancel {
+                    trace_context,
+                    request_id,
+                })) => MessageFields::Cancel {
+                    trace_context,
+                    request_id,
+                },
+                // Nothing the server would know what to do with.
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            };
+            return Poll::Ready(Some(mux::rebuild(fields).map_err(io::Error::from)));
+        }
+    }
+}
+
+impl Sink<Response<ServiceResponse>> for TcpConnection {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().transport).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, response: Response<ServiceResponse>) -> io::Result<()> {
+        let Response {
+            request_id,
+            message,
+            ..
+        } = response;
+        let message = match message {
+            Ok(ServiceResponse::World(response)) => Ok(response),
+            Err(e) => Err(e),
+            // Only `World` requests come in.
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "a service other than World answered a TCP client",
+                ))
+            }
+        };
+        let response = mux::rebuild(ResponseFields {
+            request_id,
+            message,
+        })?;
+        Pin::new(&mut self.get_mut().transport).start_send(response)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().transport).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().transport).poll_close(cx)
+    }
+}
+
+/// Nothing but tarpc's messages fit, so notifications, metadata, one-way requests and
+/// identities stay behind.
+impl CarryPushes for TcpConnection {}
+
+impl CarryMetadata for TcpConnection {}
+
+impl<Item> CarryOneWay<Item> for TcpConnection {}
+
+impl CarryIdentity for TcpConnection {}
+
+impl CarryPeerAddr for TcpConnection {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.transport.peer_addr().ok()
+    }
+}
+
+/// Where and how [`bind`] listens.
+pub struct Listener {
+    pub addr: SocketAddr,
+    /// Frames larger than this end their connection.
+    pub max_message: usize,
+    pub ips: IpFilter,
+    pub limit: ConnectionLimit,
+}
+
+/// Accepts tarpc connections on `listener.addr` from the peers it admits, telling `listening`
+/// once it does, until `shutdown` begins. `None` if `access` wants tokens, or the address
+/// can't be bound.
+pub async fn bind(
+    access: &Access,
+    listener: Listener,
+    listening: Listening,
+    shutdown: Shutdown,
+) -> Option<impl Stream<Item = io::Result<TcpConnection>>> {
+    let Listener {
+        addr,
+        max_message,
+        ips,
+        limit,
+    } = listener;
+    if let Access::Token(_) = access {
+        error!("Not listening for tarpc over TCP, its clients can't send a token");
+        return None;
+    }
+    info!("Binding tarpc TCP listener");
+    let mut listener = match tarpc::serde_transport::tcp::listen(&addr, Json::default).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind the tarpc TCP listener on {}: {}", addr, e);
+            return None;
+        }
+    };
+    listener.config_mut().max_frame_length(max_message);
+    info!(
+        "Bound, waiting on tarpc clients on {}",
+        listener.local_addr()
+    );
+    listening.set(true);
+    // A connection failing to set up doesn't stop the others.
+    let accepted = listener
+        .inspect_err(|e| error!("Failed to accept a tarpc connection: {}", e))
+        .filter_map(|transport| future::ready(transport.ok()))
+        .filter(move |transport| {
+            let admitted = match transport.peer_addr() {
+                Ok(peer) if !ips.admits(peer.ip()) => {
+                    info!("Closing the connection of {}, its address isn't allowed", peer);
+                    increment_counter!(DENIED_CONNECTIONS, "listener" => "tcp");
+                    false
+                }
+                _ => true,
+            };
+            future::ready(admitted)
+        })
+        .filter_map(move |transport| {
+            let peer = transport.peer_addr();
+            let connection = match limit.try_acquire() {
+                Some(permit) => {
+                    if let Ok(peer) = peer {
+                        info!("New tarpc TCP connection: {}", peer);
+                    }
+                    Some(Ok(TcpConnection {
+                        transport,
+                        shutdown: Box::pin(shutdown.begun()),
+                        _permit: permit,
+                    }))
+                }
+                None => {
+                    info!(
+                        "Closing a tarpc TCP connection, {} are open already",
+                        limit.max()
+                    );
+                    None
+                }
+            };
+            future::ready(connection)
+        });
+    Some(accepted)
+}
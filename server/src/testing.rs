@@ -0,0 +1,130 @@
+//! A handler for the tests of the layers around `WorldImpl`, and connections to a server's
+//! [`Peers`] for the tests of the services themselves.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use rpc::{Envelope, WorldRequest, WorldResponse};
+use tarpc::context::Context;
+use tarpc::server::Serve;
+
+use crate::admin::AdminImpl;
+use crate::metadata::ReceivedMetadata;
+use crate::rate::{Rate, RateLimiter, RatePolicy};
+use crate::service_impl::{Joined, Peers, WorldImpl};
+use crate::shutdown::{self, Trigger};
+use crate::uploads::Uploads;
+
+/// Answers `ping` right away and `delay` once its time is up, whatever the deadline. Other
+/// methods aren't served.
+#[derive(Clone, Default)]
+pub struct Sleeper {
+    /// Calls of `delay` sleeping right now. Those answered or dropped aren't counted.
+    pub sleeping: Arc<AtomicUsize>,
+}
+
+/// Keeps a call counted in [`Sleeper::sleeping`] until dropped.
+struct Counted(Arc<AtomicUsize>);
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Sleeper {
+    pub fn sleeping(&self) -> usize {
+        self.sleeping.load(Ordering::SeqCst)
+    }
+}
+
+impl Serve<WorldRequest> for Sleeper {
+    type Resp = WorldResponse;
+    type Fut = Pin<Box<dyn Future<Output = WorldResponse> + Send>>;
+
+    fn method(&self, request: &WorldRequest) -> Option<&'static str> {
+        match request {
+            WorldRequest::Ping { .. } => Some("ping"),
+            WorldRequest::Delay { .. } => Some("delay"),
+            _ => None,
+        }
+    }
+
+    fn serve(self, _: Context, request: WorldRequest) -> Self::Fut {
+        match request {
+            WorldRequest::Ping {} => Box::pin(async { WorldResponse::Ping(Ok("pong".into())) }),
+            WorldRequest::Delay { millis } => {
+                self.sleeping.fetch_add(1, Ordering::SeqCst);
+                let counted = Counted(self.sleeping);
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(millis)).await;
+                    drop(counted);
+                    WorldResponse::Delay(Ok("waited".into()))
+                })
+            }
+            _ => panic!("only ping and delay are served"),
+        }
+    }
+}
+
+/// A context whose deadline is `timeout` from now.
+pub fn context(timeout: Duration) -> Context {
+    let mut ctx = tarpc::context::current();
+    ctx.deadline = std::time::SystemTime::now() + timeout;
+    ctx
+}
+
+/// A connection to the server of `peers` with both its services, never rate limited.
+pub struct Connection {
+    pub world: WorldImpl,
+    pub admin: AdminImpl,
+    /// What the server pushes to this connection.
+    pub pushes: mpsc::UnboundedReceiver<Envelope<()>>,
+    /// Keeps the connection among the peers, it leaves when dropped.
+    pub joined: Joined,
+    _trigger: Trigger,
+}
+
+pub fn connect(peers: &Arc<Peers>, uploads: &Arc<Uploads>) -> Connection {
+    connect_as(peers, uploads, false)
+}
+
+/// [`connect`], of an admin if `admin`.
+pub fn connect_as(peers: &Arc<Peers>, uploads: &Arc<Uploads>, admin: bool) -> Connection {
+    let (push, pushes) = rpc::push::channel();
+    let (session, joined) = peers.join(&push, None, None, admin);
+    let (trigger, shutdown) = shutdown::channel(Duration::from_secs(1));
+    let rates = RatePolicy {
+        default: Rate::new(f64::MAX, u32::MAX),
+        methods: HashMap::new(),
+    };
+    // Both services share the connection's buckets, like in `main`.
+    let rate = RateLimiter::new(Arc::new(rates));
+    let world = WorldImpl::new(
+        session.clone(),
+        peers.clone(),
+        push,
+        ReceivedMetadata::default(),
+        shutdown,
+        rate.clone(),
+        uploads.clone(),
+    );
+    Connection {
+        world,
+        admin: AdminImpl::new(session, peers.clone(), rate),
+        pushes,
+        joined,
+        _trigger: trigger,
+    }
+}
+
+/// Uploads of the tests' connections, to a directory of this process.
+pub fn uploads() -> Arc<Uploads> {
+    let dir = std::env::temp_dir().join(format!("world-tests-{}", std::process::id()));
+    Arc::new(Uploads::new(dir, 1024, Duration::from_secs(1)).unwrap())
+}
@@ -0,0 +1,155 @@
+//! How long handlers may run whatever their deadline, see [`TimeoutPolicy`].
+//!
+//! Client deadlines cap calls in [`crate::deadline`], but a client can ask for an hour. The
+//! policy caps each method on the server's side: a handler still running at its method's
+//! limit is dropped and the call answered [`WorldError::Timeout`], as if its deadline had
+//! passed. Methods without a limit of their own get the default one, which may be none.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use rpc::WorldError;
+use tarpc::context::Context;
+use tarpc::server::Serve;
+
+use crate::middleware::{BoxServe, Call, Layer};
+
+/// The longest each method runs, `None` for as long as its deadline lets it.
+#[derive(Clone, Debug, Default)]
+pub struct TimeoutPolicy {
+    /// The limit of the methods without one of their own.
+    pub default: Option<Duration>,
+    /// Limits by method name, e.g. `"echo"`.
+    pub methods: HashMap<&'static str, Option<Duration>>,
+}
+
+impl TimeoutPolicy {
+    pub fn limit(&self, method: &str) -> Option<Duration> {
+        self.methods.get(method).copied().unwrap_or(self.default)
+    }
+
+    /// The limits of `limits`, e.g. `echo=1,delay=300,*=none` for `echo`s of a second at
+    /// most, `delay`s of five minutes and no limit for the other methods. Entries that don't
+    /// parse are ignored.
+    pub fn parse(limits: &str) -> Self {
+        let mut policy = Self::default();
+        for entry in limits
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            match parse_entry(entry) {
+                Some(("*", limit)) => policy.default = limit,
+                Some((name, limit)) => {
+                    let known = rpc::schema::METHODS
+                        .iter()
+                        .find(|method| method.name == name);
+                    match known {
+                        Some(method) => {
+                            policy.methods.insert(method.name, limit);
+                        }
+                        None => info!("Ignoring the timeout of {}, there is no such method", name),
+                    }
+                }
+                None => info!("Ignoring method timeout entry {:?}", entry),
+            }
+        }
+        policy
+    }
+}
+
+/// `method=seconds` or `method=none`.
+fn parse_entry(entry: &str) -> Option<(&str, Option<Duration>)> {
+    let (method, limit) = entry.split_once('=')?;
+    let limit = match limit.trim() {
+        "none" => None,
+        seconds => match Duration::try_from_secs_f64(seconds.parse().ok()?) {
+            Ok(limit) if !limit.is_zero() => Some(limit),
+            _ => return None,
+        },
+    };
+    Some((method.trim(), limit))
+}
+
+/// Caps the calls at the limits of their method, see [`Capped`].
+impl<R: Call> Layer<R> for Arc<TimeoutPolicy> {
+    fn layer(&self, inner: BoxServe<R>) -> BoxServe<R> {
+        BoxServe::new(Capped::new(inner, self.clone()))
+    }
+}
+
+/// `serve` dropping handlers that ran past their method's limit.
+#[derive(Clone)]
+pub struct Capped<S> {
+    serve: S,
+    policy: Arc<TimeoutPolicy>,
+}
+
+impl<S> Capped<S> {
+    pub fn new(serve: S, policy: Arc<TimeoutPolicy>) -> Self {
+        Self { serve, policy }
+    }
+}
+
+impl<S, R> Serve<R> for Capped<S>
+where
+    R: Call,
+    S: Serve<R, Resp = R::Response>,
+    S::Fut: Send + 'static,
+{
+    type Resp = R::Response;
+    type Fut = Pin<Box<dyn Future<Output = R::Response> + Send>>;
+
+    fn method(&self, request: &R) -> Option<&'static str> {
+        self.serve.method(request)
+    }
+
+    fn serve(self, ctx: Context, request: R) -> Self::Fut {
+        let method = self.serve.method(&request).unwrap_or("unknown");
+        let limit = match self.policy.limit(method) {
+            Some(limit) => limit,
+            None => return Box::pin(self.serve.serve(ctx, request)),
+        };
+        let failure = request.failure();
+        let answered = self.serve.serve(ctx, request);
+        Box::pin(async move {
+            if let Ok(response) = tokio::time::timeout(limit, answered).await {
+                return response;
+            }
+            info!(
+                "call method={} trace={} ran past the {:?} {} may run, answering Timeout",
+                method,
+                ctx.trace_id(),
+                limit,
+                method
+            );
+            failure(WorldError::Timeout)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Sleeper};
+    use rpc::{WorldRequest, WorldResponse};
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_delay_past_its_cap_is_answered_timeout_at_the_cap() {
+        let policy = Arc::new(TimeoutPolicy::parse("delay=2"));
+        let sleeper = Sleeper::default();
+        let capped = Capped::new(sleeper.clone(), policy);
+        let ctx = testing::context(Duration::from_secs(60));
+
+        let started = Instant::now();
+        let answered = capped.serve(ctx, WorldRequest::Delay { millis: 10_000 }).await;
+        assert!(matches!(answered, WorldResponse::Delay(Err(WorldError::Timeout))));
+        assert_eq!(started.elapsed().as_secs(), 2);
+        assert_eq!(sleeper.sleeping(), 0, "the handler still runs");
+    }
+}
@@ -0,0 +1,44 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::info;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsFiles;
+
+/// How long a client gets to finish the TLS handshake. One stalling mid-handshake would
+/// otherwise keep its task and socket forever.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds the TLS acceptor from `--tls-cert`/`--tls-key`.
+pub fn acceptor(files: &TlsFiles) -> io::Result<TlsAcceptor> {
+    load_acceptor(&files.cert, &files.key)
+}
+
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    info!("Loading TLS certificate from {}", cert_path.display());
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no PKCS#8 private key found in {}", key_path.display()),
+            )
+        })?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
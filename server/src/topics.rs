@@ -0,0 +1,81 @@
+//! Topics connections subscribe to, and publishing to them.
+//!
+//! A message published to a topic goes to every connection subscribed to it as a
+//! [`Notification`] with the topic's name, through the connection's [`PushHandle`]. Handlers
+//! and background tasks alike publish through [`Topics::publish`]. Connections are dropped
+//! from every topic when they go away, and once their handle turns out to be closed, so the
+//! registry doesn't keep handles of dead connections.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use log::info;
+use rpc::push::{Notification, PushHandle};
+use serde::Serialize;
+
+/// The subscribers of every topic, keyed by topic and then by peer id.
+#[derive(Default)]
+pub struct Topics {
+    topics: Mutex<HashMap<String, HashMap<u64, PushHandle>>>,
+}
+
+impl Topics {
+    /// Sends `topic`'s messages to the connection with peer id `id` from now on. Subscribing
+    /// twice changes nothing.
+    pub fn subscribe(&self, topic: &str, id: u64, push: PushHandle) {
+        let mut topics = self.topics.lock().unwrap();
+        topics
+            .entry(topic.to_string())
+            .or_default()
+            .insert(id, push);
+    }
+
+    /// Stops sending `topic`'s messages to the connection with peer id `id`.
+    pub fn unsubscribe(&self, topic: &str, id: u64) {
+        let mut topics = self.topics.lock().unwrap();
+        if let Some(subscribers) = topics.get_mut(topic) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                topics.remove(topic);
+            }
+        }
+    }
+
+    /// Drops the connection with peer id `id` from every topic.
+    pub fn leave(&self, id: u64) {
+        let mut topics = self.topics.lock().unwrap();
+        topics.retain(|_, subscribers| {
+            subscribers.remove(&id);
+            !subscribers.is_empty()
+        });
+    }
+
+    /// Sends `payload` to the subscribers of `topic`, returning how many it reached.
+    pub fn publish<T: Serialize>(&self, topic: &str, payload: &T) -> Result<usize, String> {
+        let notification = Notification::new(topic, payload)
+            .map_err(|e| format!("can't serialize the message: {}", e))?;
+        Ok(self.send(notification))
+    }
+
+    /// Sends `notification` to the subscribers of its topic, returning how many it reached.
+    pub fn send(&self, notification: Notification) -> usize {
+        let topic = notification.topic.clone();
+        let mut topics = self.topics.lock().unwrap();
+        let subscribers = match topics.get_mut(&topic) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        subscribers.retain(|id, push| match push.notify(notification.clone()) {
+            Ok(()) => true,
+            Err(_) => {
+                info!("Peer {} is gone, dropping it from {}", id, topic);
+                false
+            }
+        });
+        let reached = subscribers.len();
+        if reached == 0 {
+            topics.remove(&topic);
+        }
+        reached
+    }
+}
@@ -0,0 +1,849 @@
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::CloseFrame;
+use async_tungstenite::tungstenite::{Error as WsError, Message};
+use async_tungstenite::WebSocketStream;
+use futures::channel::mpsc;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{ready, Sink, Stream, StreamExt};
+use log::{debug, info};
+use metrics::counter;
+use rpc::base64;
+use rpc::codec::Codec;
+use rpc::compression;
+use rpc::framing::{self, FrameDecoder};
+use rpc::handshake::Welcome;
+use rpc::schema;
+use rpc::wire::{self, Encoder, Frame};
+use rpc::Envelope;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::auth::Identity;
+use crate::limit::ConnectionPermit;
+use crate::metadata::ReceivedMetadata;
+use crate::prometheus::{BYTES_IN, BYTES_OUT};
+use crate::shutdown::Shutdown;
+
+/// Frame type the client talks in; responses are sent back the same way.
+pub use rpc::codec::FrameMode;
+
+/// Serializes `envelope` with `codec`, compressing binary messages when they are large and
+/// `compress` is set.
+pub fn encode<C: Codec, T: Serialize>(
+    codec: &C,
+    envelope: &Envelope<T>,
+    compress: bool,
+) -> io::Result<Message> {
+    encode_with(&mut Encoder::default(), codec, envelope, compress)
+}
+
+/// [`encode`] with the scratch buffer of `encoder`.
+fn encode_with<C: Codec, T: Serialize>(
+    encoder: &mut Encoder,
+    codec: &C,
+    envelope: &Envelope<T>,
+    compress: bool,
+) -> io::Result<Message> {
+    let threshold = compress.then_some(compression::DEFAULT_THRESHOLD);
+    match encoder.frame(codec, envelope, threshold) {
+        Ok(Frame::Text(text)) => Ok(Message::Text(text)),
+        Ok(Frame::Binary(frame)) => Ok(Message::Binary(frame)),
+        Err(e) => Err(invalid_data(e)),
+    }
+}
+
+/// `message` as it goes on a connection that carries binary messages in base64 text frames if
+/// `base64` is set, see [`rpc::base64`].
+pub fn armor(message: Message, base64: bool) -> Message {
+    match message {
+        Message::Binary(bytes) if base64 => Message::Text(base64::encode(&bytes)),
+        message => message,
+    }
+}
+
+fn to_io(e: async_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Serializes a message for a binary frame, compressing it when it is large and `compress`
+/// is set.
+#[cfg(feature = "webtransport")]
+fn encode_binary<T: Serialize>(envelope: &Envelope<T>, compress: bool) -> io::Result<Vec<u8>> {
+    let threshold = compress.then_some(compression::DEFAULT_THRESHOLD);
+    wire::encode_binary(envelope, threshold).map_err(invalid_data)
+}
+
+/// Decodes a message taken out of a binary frame.
+#[cfg(feature = "webtransport")]
+pub fn decode_binary<T: DeserializeOwned>(message: &[u8]) -> io::Result<Envelope<T>> {
+    decode_message(&rpc::codec::Bincode, message)
+}
+
+/// Decodes a message serialized by `codec` taken out of a binary frame.
+pub fn decode_message<C: Codec, T: DeserializeOwned>(
+    codec: &C,
+    message: &[u8],
+) -> io::Result<Envelope<T>> {
+    wire::decode_message(codec, message, framing::DEFAULT_MAX_FRAME_LEN).map_err(invalid_data)
+}
+
+/// Transports that can send the server's notifications and streams next to tarpc's
+/// responses, see [`rpc::push`].
+pub trait CarryPushes {
+    /// Sends the envelopes coming out of `pushes` to the client. Transports that can't carry
+    /// them drop it, so the connection's [`PushHandle`](rpc::push::PushHandle) fails right
+    /// away.
+    fn carry_pushes(&mut self, pushes: mpsc::UnboundedReceiver<Envelope<()>>) {
+        drop(pushes)
+    }
+}
+
+/// Transports that can receive the metadata clients attach to their requests, see
+/// [`rpc::metadata`].
+pub trait CarryMetadata {
+    /// Where the metadata of the requests received so far is kept for the handlers.
+    /// Transports that can't carry it answer an empty store, so handlers find none.
+    fn metadata(&self) -> ReceivedMetadata {
+        ReceivedMetadata::default()
+    }
+}
+
+/// Transports that can receive the requests clients expect no response to, see
+/// [`rpc::oneway`].
+pub trait CarryOneWay<Item> {
+    /// Hands the one-way requests received from now on to `calls`, which serves them outside
+    /// tarpc's channel, see [`crate::oneway`]. Transports that can't carry them drop it.
+    fn carry_one_way(&mut self, calls: mpsc::UnboundedSender<Item>) {
+        drop(calls)
+    }
+}
+
+/// Gives `message` to what serves the connection's one-way requests, see [`CarryOneWay`].
+pub fn hand_over<Item>(calls: Option<&mpsc::UnboundedSender<Item>>, message: Item) {
+    let handed = calls.is_some_and(|calls| calls.unbounded_send(message).is_ok());
+    if !handed {
+        debug!("Dropping a one-way request, nothing serves them on this connection");
+    }
+}
+
+/// Transports that know who the client is, see [`crate::auth`].
+pub trait CarryIdentity {
+    /// Who the token the connection was opened with belongs to. Anonymous connections, and
+    /// transports that don't check tokens, have no identity.
+    fn identity(&self) -> Option<Identity> {
+        None
+    }
+}
+
+/// Transports that know where the client connects from, for the logs.
+pub trait CarryPeerAddr {
+    /// `None` where the transport doesn't tell.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Bytes of batched responses sent in one binary frame at most.
+const BATCH_MAX_BYTES: usize = 64 * 1024;
+
+/// tarpc transport over a WebSocket carrying one [`Envelope`] per message.
+///
+/// Unwraps the envelopes for tarpc and answers client heartbeats. Messages are serialized by
+/// the codec whose subprotocol the client asked for.
+pub struct WsTransport<S, Item, SinkItem, C = FrameMode> {
+    inner: WebSocketStream<S>,
+    codec: C,
+    /// The client compresses its large messages, so ours get compressed too.
+    compress: bool,
+    /// The client batches its binary messages, so ours get batched too: responses are
+    /// collected in `batched` and go out in one frame when tarpc flushes.
+    batch: bool,
+    batched: Vec<u8>,
+    /// Messages taken out of the last binary frame so far.
+    in_frame: usize,
+    /// Binary messages go in base64 text frames, as the client asked during the handshake.
+    base64: bool,
+    /// Reassembles binary frames.
+    decoder: FrameDecoder,
+    /// Kept across messages, so sending doesn't allocate more than the frame itself.
+    encoder: Encoder,
+    /// The client sent a close frame; tungstenite's reply still has to be flushed.
+    closing: bool,
+    /// The client sent a message over the limit, and the close frame saying so hasn't been
+    /// queued yet.
+    oversized: Option<String>,
+    /// A heartbeat, token or hello arrived and the answer hasn't been queued yet.
+    pending_ack: Option<Envelope<()>>,
+    /// An ack was queued but not flushed yet.
+    flush_pending: bool,
+    /// Notifications and stream frames, sent whenever the socket takes them.
+    pushes: Option<mpsc::UnboundedReceiver<Envelope<()>>>,
+    /// The metadata of tagged requests, until their handlers take it.
+    metadata: ReceivedMetadata,
+    /// Where one-way requests go, instead of to tarpc.
+    one_way: Option<mpsc::UnboundedSender<Item>>,
+    /// Resolves once the server shuts down, see [`crate::shutdown`].
+    shutdown: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    /// The server shuts down: no more requests are read, and the connection closes as
+    /// going away once tarpc answered the ones it has.
+    draining: bool,
+    /// Counts the connection as open until the transport is dropped.
+    _permit: Option<ConnectionPermit>,
+    /// Whose token opened the connection.
+    identity: Option<Identity>,
+    /// Where the connection came from.
+    peer: Option<SocketAddr>,
+    ghost: PhantomData<fn(SinkItem) -> Item>,
+}
+
+impl<S, Item, SinkItem, C> WsTransport<S, Item, SinkItem, C> {
+    /// `codec` is the one the client asked for during the handshake.
+    pub fn new(inner: WebSocketStream<S>, codec: C) -> Self {
+        Self {
+            inner,
+            codec,
+            compress: false,
+            batch: false,
+            batched: Vec::new(),
+            in_frame: 0,
+            base64: false,
+            decoder: FrameDecoder::default(),
+            encoder: Encoder::default(),
+            closing: false,
+            oversized: None,
+            pending_ack: None,
+            flush_pending: false,
+            pushes: None,
+            metadata: ReceivedMetadata::default(),
+            one_way: None,
+            shutdown: None,
+            draining: false,
+            _permit: None,
+            identity: None,
+            peer: None,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Sends and expects binary messages in base64 text frames, see [`rpc::base64`].
+    pub fn with_base64(mut self, base64: bool) -> Self {
+        self.base64 = base64;
+        self
+    }
+
+    /// Closes with code 1009 once a binary frame announces a message larger than `len`, see
+    /// [`crate::payload`].
+    pub fn with_max_message(mut self, len: usize) -> Self {
+        self.decoder = FrameDecoder::new(len);
+        self
+    }
+
+    /// Holds `permit` for as long as the connection is open, see [`crate::limit`].
+    pub fn with_permit(mut self, permit: ConnectionPermit) -> Self {
+        self._permit = Some(permit);
+        self
+    }
+
+    /// The connection was opened with a token of `identity`.
+    pub fn with_identity(mut self, identity: Option<Identity>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// The connection came from `peer`.
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Drains the connection once `shutdown` begins.
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.shutdown = Some(Box::pin(shutdown.begun()));
+        self
+    }
+
+    /// Whether the server shuts down, noticing it the first time.
+    fn poll_draining(&mut self, cx: &mut Context<'_>) -> bool {
+        if let Some(begun) = self.shutdown.as_mut() {
+            if begun.as_mut().poll(cx).is_ready() {
+                info!("Shutting down, not reading more requests");
+                self.shutdown = None;
+                self.draining = true;
+            }
+        }
+        self.draining
+    }
+}
+
+impl<S, Item, SinkItem, C> WsTransport<S, Item, SinkItem, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    SinkItem: Serialize,
+    C: Codec,
+{
+    fn encode<T: Serialize>(&mut self, envelope: &Envelope<T>) -> io::Result<Message> {
+        encode_with(&mut self.encoder, &self.codec, envelope, self.compress)
+    }
+
+    /// Queues `message` on the socket, once it is ready, counting it in [`BYTES_OUT`].
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        counter!(BYTES_OUT, message.len() as u64, "transport" => "websocket");
+        Pin::new(&mut self.inner).start_send(message).map_err(to_io)
+    }
+
+    /// Makes progress on a pending ack without blocking reads.
+    fn poll_ack(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if let Some(envelope) = self.pending_ack.take() {
+            match Pin::new(&mut self.inner).poll_ready(cx) {
+                Poll::Ready(ready) => {
+                    ready.map_err(to_io)?;
+                    let ack = armor(self.encode(&envelope)?, self.base64);
+                    self.send(ack)?;
+                    self.flush_pending = true;
+                }
+                Poll::Pending => self.pending_ack = Some(envelope),
+            }
+        }
+        if self.flush_pending {
+            if let Poll::Ready(flushed) = Pin::new(&mut self.inner).poll_flush(cx) {
+                flushed.map_err(to_io)?;
+                self.flush_pending = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the pushed envelopes waiting, as long as the socket takes them without blocking.
+    fn poll_pushes(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        while let Some(pushes) = self.pushes.as_mut() {
+            match Pin::new(&mut self.inner).poll_ready(cx) {
+                Poll::Ready(ready) => ready.map_err(to_io)?,
+                Poll::Pending => break,
+            }
+            let envelope = match pushes.poll_next_unpin(cx) {
+                Poll::Ready(Some(envelope)) => envelope,
+                Poll::Ready(None) => {
+                    self.pushes = None;
+                    break;
+                }
+                Poll::Pending => break,
+            };
+            let message = armor(self.encode(&envelope)?, self.base64);
+            self.send(message)?;
+            self.flush_pending = true;
+        }
+        Ok(())
+    }
+
+    /// Sends the batched responses, if any, as one binary frame.
+    fn poll_send_batch(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.batched.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io)?;
+        let frame = armor(Message::Binary(std::mem::take(&mut self.batched)), self.base64);
+        Poll::Ready(self.send(frame))
+    }
+}
+
+impl<S, Item, SinkItem, C> WsTransport<S, Item, SinkItem, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    Item: DeserializeOwned,
+    C: Codec,
+{
+    /// Reads the next envelope, from buffered binary data first and the socket after that.
+    fn poll_envelope(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Envelope<Item>>>> {
+        loop {
+            if self.oversized.is_some() {
+                if let Err(e) = ready!(self.poll_close_oversized(cx)) {
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+            if self.closing {
+                // Failing to deliver the reply doesn't matter, the connection is done either way.
+                let _ = ready!(Pin::new(&mut self.inner).poll_flush(cx));
+                return Poll::Ready(None);
+            }
+            match self.decoder.next_frame() {
+                Ok(Some(message)) => {
+                    self.compress |= compression::is_compressed(&message);
+                    self.in_frame += 1;
+                    self.batch |= self.in_frame > 1;
+                    return Poll::Ready(Some(decode_message(&self.codec, &message)));
+                }
+                Ok(None) => {}
+                // What the message asked for is unknown, so there is no call to answer.
+                Err(e) => {
+                    info!("Closing the connection, {}", e);
+                    self.oversized = Some(e.to_string());
+                    continue;
+                }
+            }
+            let message = ready!(Pin::new(&mut self.inner).poll_next(cx));
+            if let Some(Ok(message @ (Message::Text(_) | Message::Binary(_)))) = &message {
+                counter!(BYTES_IN, message.len() as u64, "transport" => "websocket");
+            }
+            match message {
+                Some(Ok(Message::Text(text))) if self.base64 => match base64::decode(&text) {
+                    Ok(bytes) => {
+                        self.decoder.extend(&bytes);
+                        self.in_frame = 0;
+                    }
+                    Err(e) => return Poll::Ready(Some(Err(invalid_data(e)))),
+                },
+                Some(Ok(Message::Text(text))) => {
+                    let envelope = self.codec.decode_text(&text).map_err(invalid_data);
+                    return Poll::Ready(Some(envelope));
+                }
+                Some(Ok(Message::Binary(bytes))) => {
+                    self.decoder.extend(&bytes);
+                    self.in_frame = 0;
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    match frame {
+                        Some(frame) => info!(
+                            "Client closed the connection with code {}: {}",
+                            u16::from(frame.code),
+                            frame.reason
+                        ),
+                        None => info!("Client closed the connection without a code"),
+                    }
+                    self.closing = true;
+                }
+                // Pings are answered by tungstenite itself.
+                Some(Ok(_)) => {}
+                Some(Err(WsError::Capacity(e))) => {
+                    info!("Closing the connection, {}", e);
+                    self.oversized = Some(e.to_string());
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(to_io(e)))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+
+    /// Queues the close frame telling the client its message was too big.
+    fn poll_close_oversized(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io)?;
+        let close = CloseFrame {
+            code: CloseCode::Size,
+            reason: self.oversized.take().unwrap_or_default().into(),
+        };
+        let closed = Pin::new(&mut self.inner).start_send(Message::Close(Some(close)));
+        self.closing = true;
+        Poll::Ready(closed.map_err(to_io))
+    }
+}
+
+impl<S, Item, SinkItem, C> Stream for WsTransport<S, Item, SinkItem, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    Item: DeserializeOwned,
+    SinkItem: Serialize,
+    C: Codec + Unpin,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Err(e) = this.poll_pushes(cx).and_then(|()| this.poll_ack(cx)) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            // tarpc answers the requests it has and closes the sink once this stream ended.
+            if this.poll_draining(cx) {
+                return Poll::Ready(None);
+            }
+            match ready!(this.poll_envelope(cx)) {
+                Some(Ok(Envelope::Message(message))) => return Poll::Ready(Some(Ok(message))),
+                Some(Ok(Envelope::Tagged(message, metadata))) => {
+                    this.metadata.keep(metadata);
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                Some(Ok(Envelope::OneWay(message))) => hand_over(this.one_way.as_ref(), message),
+                Some(Ok(Envelope::Heartbeat)) => {
+                    debug!("Heartbeat received");
+                    this.pending_ack = Some(Envelope::HeartbeatAck);
+                }
+                // Only reaches here when the server doesn't check tokens, see `crate::auth`.
+                Some(Ok(Envelope::Auth(_))) => this.pending_ack = Some(Envelope::AuthAck),
+                Some(Ok(Envelope::Hello(hello))) => {
+                    let welcome = Welcome::answer(&hello, this.codec.protocol());
+                    let drifted = hello.schema.filter(|&theirs| theirs != schema::SCHEMA_HASH);
+                    if !welcome.accepted {
+                        info!(
+                            "Client speaks {} version {} in {}, turning it away",
+                            hello.service, hello.protocol, hello.codec
+                        );
+                    } else if let Some(theirs) = drifted {
+                        // The client decides whether it can live with the difference.
+                        info!(
+                            "Client's {} schema {:016x} differs from ours",
+                            hello.service, theirs
+                        );
+                    }
+                    this.pending_ack = Some(Envelope::Welcome(welcome));
+                }
+                // Streams and notifications only go from the server to the client.
+                Some(Ok(Envelope::HeartbeatAck))
+                | Some(Ok(Envelope::AuthAck))
+                | Some(Ok(Envelope::Welcome(_)))
+                | Some(Ok(Envelope::Stream(_)))
+                | Some(Ok(Envelope::Notification(_))) => {}
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<S, Item, SinkItem, C> Sink<SinkItem> for WsTransport<S, Item, SinkItem, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    SinkItem: Serialize,
+    C: Codec + Unpin,
+{
+    type Error = io::Error;
+
+    /// Sends the batch first once it is full.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.batched.len() >= BATCH_MAX_BYTES {
+            ready!(this.poll_send_batch(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_ready(cx).map_err(to_io)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        match this.encode(&Envelope::Message(item))? {
+            // Binary frames carry their length prefix, so they can simply be appended.
+            Message::Binary(frame) if this.batch => {
+                this.batched.extend_from_slice(&frame);
+                Ok(())
+            }
+            message => {
+                let message = armor(message, this.base64);
+                this.send(message)
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.poll_pushes(cx)?;
+        this.poll_ack(cx)?;
+        ready!(this.poll_send_batch(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx).map_err(to_io)
+    }
+
+    /// Closes as going away when the server shuts down, tungstenite's normal closure
+    /// otherwise.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_batch(cx))?;
+        // A client that closed first already got tungstenite's reply.
+        if this.draining && !this.closing {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(to_io)?;
+            let close = CloseFrame {
+                code: CloseCode::Away,
+                reason: "server shutting down".into(),
+            };
+            Pin::new(&mut this.inner).start_send(Message::Close(Some(close))).map_err(to_io)?;
+            this.closing = true;
+        }
+        ready!(Pin::new(&mut this.inner).poll_close(cx)).map_err(to_io)?;
+        // The closing handshake is only done once the client answered our close frame, which
+        // it can't anymore once the server exited.
+        if this.draining {
+            while let Some(Ok(_)) = ready!(this.inner.poll_next_unpin(cx)) {}
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S, Item, SinkItem, C> CarryPushes for WsTransport<S, Item, SinkItem, C> {
+    fn carry_pushes(&mut self, pushes: mpsc::UnboundedReceiver<Envelope<()>>) {
+        self.pushes = Some(pushes);
+    }
+}
+
+impl<S, Item, SinkItem, C> CarryOneWay<Item> for WsTransport<S, Item, SinkItem, C> {
+    fn carry_one_way(&mut self, calls: mpsc::UnboundedSender<Item>) {
+        self.one_way = Some(calls);
+    }
+}
+
+impl<S, Item, SinkItem, C> CarryMetadata for WsTransport<S, Item, SinkItem, C> {
+    fn metadata(&self) -> ReceivedMetadata {
+        self.metadata.clone()
+    }
+}
+
+impl<S, Item, SinkItem, C> CarryIdentity for WsTransport<S, Item, SinkItem, C> {
+    fn identity(&self) -> Option<Identity> {
+        self.identity.clone()
+    }
+}
+
+impl<S, Item, SinkItem, C> CarryPeerAddr for WsTransport<S, Item, SinkItem, C> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+}
+
+/// Bytes read from a [`StreamTransport`]'s stream at a time.
+#[cfg(feature = "webtransport")]
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Encoded bytes a [`StreamTransport`] buffers before it waits for the stream to take them.
+#[cfg(feature = "webtransport")]
+const WRITE_HIGH_WATER: usize = 64 * 1024;
+
+/// tarpc transport over a plain byte stream, e.g. a WebTransport stream.
+///
+/// Carries the same length prefixed bincode [`Envelope`]s as binary WebSocket frames, and
+/// answers client heartbeats the same way [`WsTransport`] does.
+#[cfg(feature = "webtransport")]
+pub struct StreamTransport<S, Item, SinkItem> {
+    inner: S,
+    /// The client compresses its large messages, so ours get compressed too.
+    compress: bool,
+    decoder: FrameDecoder,
+    read_buf: Box<[u8]>,
+    /// Encoded frames not fully written to `inner` yet.
+    write_buf: Vec<u8>,
+    /// How much of `write_buf` has been written.
+    written: usize,
+    /// A heartbeat arrived and its ack hasn't been queued yet.
+    ack_pending: bool,
+    /// An ack was written but not flushed yet.
+    flush_pending: bool,
+    /// The metadata of tagged requests, until their handlers take it.
+    metadata: ReceivedMetadata,
+    /// Where one-way requests go, instead of to tarpc.
+    one_way: Option<mpsc::UnboundedSender<Item>>,
+    /// Whose token opened the session.
+    identity: Option<Identity>,
+    /// Where the session came from.
+    peer: Option<SocketAddr>,
+    ghost: PhantomData<fn(SinkItem) -> Item>,
+}
+
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> StreamTransport<S, Item, SinkItem> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            compress: false,
+            decoder: FrameDecoder::default(),
+            read_buf: vec![0; READ_CHUNK].into_boxed_slice(),
+            write_buf: Vec::new(),
+            written: 0,
+            ack_pending: false,
+            flush_pending: false,
+            metadata: ReceivedMetadata::default(),
+            one_way: None,
+            identity: None,
+            peer: None,
+            ghost: PhantomData,
+        }
+    }
+
+    /// The session was opened with a token of `identity`.
+    pub fn with_identity(mut self, identity: Option<Identity>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// The session came from `peer`.
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+}
+
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> StreamTransport<S, Item, SinkItem>
+where
+    S: tokio::io::AsyncWrite + Unpin,
+    SinkItem: Serialize,
+{
+    fn queue<T: Serialize>(&mut self, envelope: &Envelope<T>) -> io::Result<()> {
+        let message = encode_binary(envelope, self.compress)?;
+        framing::write_frame(&message, &mut self.write_buf);
+        Ok(())
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.write_buf.len() {
+            let unwritten = &self.write_buf[self.written..];
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, unwritten))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+            counter!(BYTES_OUT, n as u64, "transport" => "webtransport");
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Makes progress on a pending heartbeat ack without blocking reads.
+    fn poll_ack(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if self.ack_pending {
+            self.queue(&Envelope::<SinkItem>::HeartbeatAck)?;
+            self.ack_pending = false;
+            self.flush_pending = true;
+        }
+        if self.flush_pending {
+            if let Poll::Ready(written) = self.poll_write_buf(cx) {
+                written?;
+                if let Poll::Ready(flushed) = Pin::new(&mut self.inner).poll_flush(cx) {
+                    flushed?;
+                    self.flush_pending = false;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// WebTransport sessions don't carry notifications or streams yet, see [`rpc::push`].
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> CarryPushes for StreamTransport<S, Item, SinkItem> {}
+
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> CarryOneWay<Item> for StreamTransport<S, Item, SinkItem> {
+    fn carry_one_way(&mut self, calls: mpsc::UnboundedSender<Item>) {
+        self.one_way = Some(calls);
+    }
+}
+
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> CarryMetadata for StreamTransport<S, Item, SinkItem> {
+    fn metadata(&self) -> ReceivedMetadata {
+        self.metadata.clone()
+    }
+}
+
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> CarryIdentity for StreamTransport<S, Item, SinkItem> {
+    fn identity(&self) -> Option<Identity> {
+        self.identity.clone()
+    }
+}
+
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> CarryPeerAddr for StreamTransport<S, Item, SinkItem> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+}
+
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> Stream for StreamTransport<S, Item, SinkItem>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    Item: DeserializeOwned,
+    SinkItem: Serialize,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Err(e) = this.poll_ack(cx) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            match this.decoder.next_frame() {
+                Ok(Some(message)) => {
+                    this.compress |= compression::is_compressed(&message);
+                    match decode_binary(&message) {
+                        Ok(Envelope::Message(message)) => return Poll::Ready(Some(Ok(message))),
+                        Ok(Envelope::Tagged(message, metadata)) => {
+                            this.metadata.keep(metadata);
+                            return Poll::Ready(Some(Ok(message)));
+                        }
+                        Ok(Envelope::OneWay(message)) => {
+                            hand_over(this.one_way.as_ref(), message);
+                            continue;
+                        }
+                        Ok(Envelope::Heartbeat) => {
+                            debug!("Heartbeat received");
+                            this.ack_pending = true;
+                            continue;
+                        }
+                        // WebTransport clients pass their token with the session request and
+                        // don't check versions.
+                        Ok(Envelope::HeartbeatAck)
+                        | Ok(Envelope::Auth(_))
+                        | Ok(Envelope::AuthAck)
+                        | Ok(Envelope::Hello(_))
+                        | Ok(Envelope::Welcome(_))
+                        | Ok(Envelope::Stream(_))
+                        | Ok(Envelope::Notification(_)) => continue,
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => return Poll::Ready(Some(Err(invalid_data(e)))),
+            }
+            let mut buf = tokio::io::ReadBuf::new(&mut this.read_buf);
+            if let Err(e) = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf)) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if buf.filled().is_empty() {
+                return Poll::Ready(None);
+            }
+            counter!(BYTES_IN, buf.filled().len() as u64, "transport" => "webtransport");
+            this.decoder.extend(buf.filled());
+        }
+    }
+}
+
+#[cfg(feature = "webtransport")]
+impl<S, Item, SinkItem> Sink<SinkItem> for StreamTransport<S, Item, SinkItem>
+where
+    S: tokio::io::AsyncWrite + Unpin,
+    SinkItem: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.write_buf.len() >= WRITE_HIGH_WATER {
+            ready!(this.poll_write_buf(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> Result<(), Self::Error> {
+        self.get_mut().queue(&Envelope::Message(item))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.poll_ack(cx)?;
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,327 @@
+//! Files clients upload in chunks, see [`rpc::upload`].
+//!
+//! Each upload is written to `<dir>/<id>.part` as its chunks arrive, every chunk at its offset,
+//! and renamed to `<dir>/<id>-<name>` once it finished. Uploads no chunk arrived for in the
+//! timeout are abandoned, and their part deleted; parts an earlier run left are deleted at
+//! startup.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use rpc::upload::{Checksum, UploadError, MAX_CHUNK_LEN};
+use rpc::{UploadResult, WorldError};
+
+/// Bytes an upload may have unless `--max-upload-size` says otherwise.
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How long an upload waits for its next chunk unless `--upload-timeout` says otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The uploads running, shared by every connection so an upload survives reconnecting.
+pub struct Uploads {
+    dir: PathBuf,
+    max_size: u64,
+    timeout: Duration,
+    /// Keys the ids, so clients can't guess each other's.
+    ids: RandomState,
+    last_id: AtomicU64,
+    open: Mutex<HashMap<u64, Arc<Mutex<Upload>>>>,
+}
+
+/// One upload, and the chunks it got so far.
+struct Upload {
+    name: String,
+    size: u64,
+    path: PathBuf,
+    file: File,
+    /// The length of every chunk received, by offset.
+    received: BTreeMap<u64, u64>,
+    touched: Instant,
+    /// Set once the file was renamed, which keeps it from being deleted.
+    kept: bool,
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.kept {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("Failed to delete {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+impl Uploads {
+    /// Uploads into `dir` of at most `max_size` bytes, abandoned after `timeout` without a
+    /// chunk. Creates `dir` if needed.
+    pub fn new(dir: PathBuf, max_size: u64, timeout: Duration) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "part")
+            {
+                info!("Deleting {}, left by an earlier run", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(Self {
+            dir,
+            max_size,
+            timeout,
+            ids: RandomState::new(),
+            last_id: AtomicU64::new(0),
+            open: Mutex::default(),
+        })
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Starts an upload of `size` bytes, answering its id.
+    pub fn begin(&self, name: &str, size: u64) -> Result<u64, WorldError> {
+        if size > self.max_size {
+            return Err(WorldError::Upload(UploadError::TooLarge(self.max_size)));
+        }
+        let id = loop {
+            let mut hasher = self.ids.build_hasher();
+            hasher.write_u64(self.last_id.fetch_add(1, Ordering::Relaxed));
+            let id = hasher.finish();
+            if !self.open.lock().unwrap().contains_key(&id) {
+                break id;
+            }
+        };
+        let path = self.dir.join(format!("{}.part", id));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| internal("create", &path, e))?;
+        let upload = Upload {
+            name: name.into(),
+            size,
+            path,
+            file,
+            received: BTreeMap::new(),
+            touched: Instant::now(),
+            kept: false,
+        };
+        self.open
+            .lock()
+            .unwrap()
+            .insert(id, Arc::new(Mutex::new(upload)));
+        Ok(id)
+    }
+
+    /// Writes `bytes` at `offset` of the upload `id`.
+    pub fn chunk(&self, id: u64, offset: u64, bytes: &[u8]) -> Result<(), WorldError> {
+        if bytes.is_empty() || bytes.len() > MAX_CHUNK_LEN {
+            return Err(WorldError::Upload(UploadError::InvalidChunk));
+        }
+        let upload = self.get(id)?;
+        let mut upload = upload.lock().unwrap();
+        let end = offset.saturating_add(bytes.len() as u64);
+        if end > upload.size {
+            return Err(WorldError::Upload(UploadError::PastEnd(offset)));
+        }
+        if upload.received.contains_key(&offset) {
+            return Err(WorldError::Upload(UploadError::DuplicateChunk(offset)));
+        }
+        let before = upload.received.range(..offset).next_back();
+        let after = upload.received.range(offset..).next();
+        let overlaps = before.is_some_and(|(start, len)| start + len > offset)
+            || after.is_some_and(|(start, _)| *start < end);
+        if overlaps {
+            return Err(WorldError::Upload(UploadError::Overlapping(offset)));
+        }
+        let path = upload.path.clone();
+        upload
+            .file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| upload.file.write_all(bytes))
+            .map_err(|e| internal("write", &path, e))?;
+        upload.received.insert(offset, bytes.len() as u64);
+        upload.touched = Instant::now();
+        Ok(())
+    }
+
+    /// Keeps the upload `id` if every byte arrived with `checksum`. A missing chunk can still
+    /// be sent after this failed, a wrong checksum ends the upload.
+    pub fn finish(&self, id: u64, checksum: u32) -> Result<UploadResult, WorldError> {
+        let upload = self.get(id)?;
+        let mut upload = upload.lock().unwrap();
+        let mut expected = 0;
+        for (&start, &len) in &upload.received {
+            if start != expected {
+                break;
+            }
+            expected = start + len;
+        }
+        if expected != upload.size {
+            return Err(WorldError::Upload(UploadError::Missing(expected)));
+        }
+        let path = upload.path.clone();
+        let received = checksum_of(&mut upload.file).map_err(|e| internal("read", &path, e))?;
+        if received != checksum {
+            self.open.lock().unwrap().remove(&id);
+            return Err(WorldError::Upload(UploadError::ChecksumMismatch(received)));
+        }
+        let kept = self.dir.join(format!("{}-{}", id, file_name(&upload.name)));
+        fs::rename(&path, &kept).map_err(|e| internal("keep", &path, e))?;
+        upload.kept = true;
+        self.open.lock().unwrap().remove(&id);
+        info!("Kept upload {} as {}", id, kept.display());
+        Ok(UploadResult {
+            id,
+            name: upload.name.clone(),
+            size: upload.size,
+            checksum,
+        })
+    }
+
+    /// Forgets the uploads that waited longer than the timeout for a chunk.
+    pub fn abandon_stalled(&self) {
+        self.open.lock().unwrap().retain(|id, upload| {
+            // Uploads busy with a chunk aren't stalled.
+            let stalled = match upload.try_lock() {
+                Ok(upload) => upload.touched.elapsed() > self.timeout,
+                Err(_) => false,
+            };
+            if stalled {
+                info!("Abandoning upload {}, no chunk arrived in time", id);
+            }
+            !stalled
+        });
+    }
+
+    fn get(&self, id: u64) -> Result<Arc<Mutex<Upload>>, WorldError> {
+        match self.open.lock().unwrap().get(&id) {
+            Some(upload) => Ok(upload.clone()),
+            None => Err(WorldError::Upload(UploadError::UnknownUpload)),
+        }
+    }
+}
+
+/// Abandons stalled uploads of `uploads` until the process exits.
+pub async fn abandon_stalled(uploads: Arc<Uploads>) {
+    let mut ticks = tokio::time::interval(uploads.timeout() / 4);
+    loop {
+        ticks.tick().await;
+        uploads.abandon_stalled();
+    }
+}
+
+fn checksum_of(file: &mut File) -> io::Result<u32> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut checksum = Checksum::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(checksum.finish()),
+            n => checksum.update(&buf[..n]),
+        }
+    }
+}
+
+/// `name` without directories or characters file systems may not like.
+fn file_name(name: &str) -> String {
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "upload".into(),
+        name => name.into(),
+    }
+}
+
+fn internal(doing: &str, path: &Path, e: io::Error) -> WorldError {
+    error!("Failed to {} {}: {}", doing, path.display(), e);
+    WorldError::Internal(format!("the upload couldn't be stored: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpc::upload::checksum;
+
+    /// Uploads of 1 KiB at most into a directory of `test`'s own, since creating them deletes
+    /// the parts there.
+    fn uploads(test: &str) -> Uploads {
+        let dir = format!("uploads-tests-{}-{}", std::process::id(), test);
+        Uploads::new(std::env::temp_dir().join(dir), 1024, Duration::from_secs(60)).unwrap()
+    }
+
+    /// Deletes the directory of `uploads`, after the parts of those still open.
+    fn clean_up(uploads: Uploads) {
+        let dir = uploads.dir.clone();
+        drop(uploads);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn failed<T>(result: Result<T, WorldError>) -> Option<UploadError> {
+        match result {
+            Err(WorldError::Upload(e)) => Some(e),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn chunks_out_of_order_make_up_the_file() {
+        let uploads = uploads("order");
+        let id = uploads.begin("notes.txt", 8).unwrap();
+        uploads.chunk(id, 4, b"5678").unwrap();
+        uploads.chunk(id, 0, b"1234").unwrap();
+
+        let kept = uploads.finish(id, checksum(b"12345678")).unwrap();
+        assert_eq!((kept.name.as_str(), kept.size), ("notes.txt", 8));
+        let path = uploads.dir.join(format!("{}-notes.txt", id));
+        assert_eq!(fs::read(&path).unwrap(), b"12345678");
+        clean_up(uploads);
+    }
+
+    #[test]
+    fn a_chunk_sent_twice_is_a_duplicate() {
+        let uploads = uploads("duplicate");
+        let id = uploads.begin("notes.txt", 8).unwrap();
+        uploads.chunk(id, 0, b"1234").unwrap();
+
+        let again = uploads.chunk(id, 0, b"1234");
+        assert_eq!(failed(again), Some(UploadError::DuplicateChunk(0)));
+        let overlapping = uploads.chunk(id, 2, b"3456");
+        assert_eq!(failed(overlapping), Some(UploadError::Overlapping(2)));
+        clean_up(uploads);
+    }
+
+    #[test]
+    fn finishing_with_a_chunk_missing_says_where_and_can_be_retried() {
+        let uploads = uploads("missing");
+        let id = uploads.begin("notes.txt", 12).unwrap();
+        uploads.chunk(id, 0, b"1234").unwrap();
+        uploads.chunk(id, 8, b"9abc").unwrap();
+
+        let missing = uploads.finish(id, checksum(b"123456789abc"));
+        assert_eq!(failed(missing), Some(UploadError::Missing(4)));
+        uploads.chunk(id, 4, b"5678").unwrap();
+        assert!(uploads.finish(id, checksum(b"123456789abc")).is_ok());
+        clean_up(uploads);
+    }
+}
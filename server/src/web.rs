@@ -1,59 +1,717 @@
-use async_stream::stream;
-use futures::TryStream;
-use log::info;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use futures::{stream, TryStream};
+use log::{error, info};
+use metrics::increment_counter;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use tarpc::serde::{Deserialize, Serialize};
-use tarpc::serde_transport::Transport;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 //use tarpc::Transport;
-use async_tungstenite::tokio::accept_async;
+use crate::auth::{self, Access};
+use crate::config::TlsFiles;
+use crate::health::Listening;
+use crate::ip_filter::IpFilter;
+use crate::limit::ConnectionLimit;
+use crate::origin::OriginPolicy;
+use crate::prometheus::{DENIED_CONNECTIONS, HANDSHAKE_FAILURES};
+use crate::shutdown::Shutdown;
+use crate::static_files::{self, StaticFiles};
+use crate::transport::{FrameMode, WsTransport};
+use async_tungstenite::tokio::{accept_hdr_async_with_config, TokioAdapter};
+use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use async_tungstenite::tungstenite::http::header::{HOST, ORIGIN, SEC_WEBSOCKET_PROTOCOL};
+use async_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use async_tungstenite::tungstenite::protocol::WebSocketConfig;
+use rpc::base64;
+use rpc::codec::Codec;
 use std::marker::Unpin;
-use tokio_serde::{Deserializer, Serializer};
-use ws_stream_tungstenite::*;
 
-pub async fn bind<Item, SinkItem, Codec, CodecFn>(
-    codec_fn: CodecFn,
-) -> Option<
+/// Port of the WebSocket listener unless `--ws-port` says otherwise.
+pub const DEFAULT_PORT: u16 = 8083;
+
+/// How long a client gets for the WebSocket handshake, after the TLS one and the request head
+/// read for the static files.
+pub const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the listener pauses after failing to accept, before it tries again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Where and how the WebSocket listener accepts connections.
+pub struct Endpoint {
+    pub addr: SocketAddr,
+    /// Accepts over TLS with these files.
+    pub tls: Option<TlsFiles>,
+    /// Bytes of the largest message a client may send, see [`crate::payload`]. Larger ones
+    /// close their connection with code 1009.
+    pub max_message: usize,
+    /// Answers the requests that aren't upgrades from these files, see
+    /// [`crate::static_files`].
+    pub files: Option<Arc<StaticFiles>>,
+    /// The pages allowed to connect, see [`crate::origin`].
+    pub origins: OriginPolicy,
+    /// The addresses allowed to connect, see [`crate::ip_filter`].
+    pub ips: IpFilter,
+}
+
+impl Endpoint {
+    /// tungstenite's limits for `max_message`, leaving room for base64 text frames.
+    fn websocket_config(&self) -> WebSocketConfig {
+        let armored = self.max_message.saturating_add(2) / 3 * 4;
+        WebSocketConfig {
+            max_message_size: Some(armored),
+            max_frame_size: Some(armored),
+            ..WebSocketConfig::default()
+        }
+    }
+}
+
+/// An accepted socket, either plain TCP or wrapped in TLS when the `tls` feature is enabled.
+pub struct ServerStream {
+    socket: Socket,
+    /// Bytes read before the handshake, read again first, see [`ServerStream::rewind`].
+    rewound: Vec<u8>,
+    /// How much of `rewound` has been read again.
+    reread: usize,
+}
+
+enum Socket {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl ServerStream {
+    fn new(socket: Socket) -> Self {
+        Self {
+            socket,
+            rewound: Vec::new(),
+            reread: 0,
+        }
+    }
+
+    /// Hands `bytes` read off the socket out again before anything else it receives.
+    fn rewind(&mut self, bytes: Vec<u8>) {
+        self.rewound = bytes;
+        self.reread = 0;
+    }
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.reread < this.rewound.len() {
+            let rest = &this.rewound[this.reread..];
+            let n = rest.len().min(buf.remaining());
+            buf.put_slice(&rest[..n]);
+            this.reread += n;
+            return Poll::Ready(Ok(()));
+        }
+        match &mut this.socket {
+            Socket::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().socket {
+            Socket::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            Socket::Plain(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Socket::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().socket {
+            Socket::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Socket::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// What the upgrade request told about the connection.
+struct Handshake<C> {
+    codec: Option<C>,
+    token: Option<String>,
+    /// Binary messages go in base64 text frames, see [`rpc::base64`].
+    base64: bool,
+}
+
+/// Accepts the upgrade if the client asks for the subprotocol of one of `codecs`, see
+/// [`rpc::protocol`], and echoes the first such one back. Codecs writing binary frames are
+/// also accepted with [`rpc::base64::SUFFIX`].
+// The error is what tungstenite's callbacks fail with, a whole response.
+#[allow(clippy::result_large_err)]
+fn negotiate<C: Codec>(
+    request: &Request,
+    mut response: Response,
+    codecs: &[C],
+    handshake: &mut Handshake<C>,
+) -> Result<Response, ErrorResponse> {
+    handshake.token = auth::query_token(request.uri().query());
+    let offered: Vec<&str> = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    let selected = codecs.iter().find_map(|codec| {
+        offered.iter().find_map(|&protocol| match base64::strip(protocol) {
+            Some(stripped) if !codec.text() && stripped == codec.protocol() => {
+                Some((codec, protocol, true))
+            }
+            _ if protocol == codec.protocol() => Some((codec, protocol, false)),
+            _ => None,
+        })
+    });
+    match selected {
+        Some((codec, protocol, base64)) => {
+            // Offered protocols come out of a header value, so they are valid in one.
+            let value = HeaderValue::from_str(protocol).expect("subprotocol from a header");
+            response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+            handshake.codec = Some(codec.clone());
+            handshake.base64 = base64;
+            Ok(response)
+        }
+        None => {
+            error!("Client offered none of our subprotocols: {:?}", offered);
+            let supported: Vec<&str> = codecs.iter().map(Codec::protocol).collect();
+            let reason = format!("expected one of the subprotocols {}", supported.join(", "));
+            let mut rejection = ErrorResponse::new(Some(reason));
+            *rejection.status_mut() = StatusCode::BAD_REQUEST;
+            Err(rejection)
+        }
+    }
+}
+
+/// Turns the upgrade away with 403 Forbidden unless `origins` lets the page it came from in.
+#[allow(clippy::result_large_err)]
+fn check_origin(request: &Request, origins: &OriginPolicy) -> Result<(), ErrorResponse> {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+    let origin = header(ORIGIN);
+    if origins.allows(origin, header(HOST)) {
+        return Ok(());
+    }
+    let reason = match origin {
+        Some(origin) => {
+            error!("Turning away a page of {}, it isn't an allowed origin", origin);
+            "the page's origin isn't allowed to connect"
+        }
+        None => {
+            error!("Turning away an upgrade without an Origin header");
+            "an Origin header is required"
+        }
+    };
+    let mut rejection = ErrorResponse::new(Some(reason.into()));
+    *rejection.status_mut() = StatusCode::FORBIDDEN;
+    Err(rejection)
+}
+
+/// The answer to upgrades past the connection limit.
+fn over_limit(limit: &ConnectionLimit) -> ErrorResponse {
+    let reason = format!("too many connections, at most {} are served", limit.max());
+    let mut rejection = ErrorResponse::new(Some(reason));
+    *rejection.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    rejection
+}
+
+/// What the handshake of every connection takes, shared by the tasks running them.
+struct Upgrader<C> {
+    access: Access,
+    codecs: Vec<C>,
+    limit: ConnectionLimit,
+    config: WebSocketConfig,
+    max_message: usize,
+    #[cfg(feature = "tls")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    files: Option<Arc<StaticFiles>>,
+    origins: OriginPolicy,
+    shutdown: Shutdown,
+}
+
+impl<C: Codec> Upgrader<C> {
+    /// Takes a socket just accepted from `addr` through TLS, the static files, the upgrade and
+    /// the token check. `None` if it didn't get through, or the static files answered it.
+    async fn upgrade<Item, SinkItem>(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> Option<WsTransport<TokioAdapter<ServerStream>, Item, SinkItem, C>> {
+        let mut stream = self.secure(stream, addr).await?;
+        if let Some(files) = &self.files {
+            let head = tokio::time::timeout(
+                static_files::HEAD_TIMEOUT,
+                static_files::read_head(&mut stream),
+            )
+            .await;
+            match head {
+                Ok(Ok(Some(head))) if head.is_upgrade() => stream.rewind(head.bytes),
+                Ok(Ok(Some(head))) => {
+                    if let Err(e) = files.clone().answer(stream, head).await {
+                        error!("Answering {} from the static files failed: {}", addr, e);
+                    }
+                    return None;
+                }
+                Ok(Ok(None)) => return None,
+                Ok(Err(e)) => {
+                    error!("Reading the request of {} failed: {}", addr, e);
+                    return None;
+                }
+                Err(_) => {
+                    error!("{} didn't send its request in time", addr);
+                    return None;
+                }
+            }
+        }
+        let mut handshake = Handshake {
+            codec: None,
+            token: None,
+            base64: false,
+        };
+        let permit = self.limit.try_acquire();
+        if permit.is_none() {
+            error!("Turning {} away, {} connections are open", addr, self.limit.open());
+        }
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, response: Response| match &permit {
+            Some(_) => {
+                check_origin(request, &self.origins)?;
+                negotiate(request, response, &self.codecs, &mut handshake)
+            }
+            None => Err(over_limit(&self.limit)),
+        };
+        let accepting = accept_hdr_async_with_config(stream, callback, Some(self.config));
+        let mut ws = match tokio::time::timeout(UPGRADE_TIMEOUT, accepting).await {
+            Ok(Ok(ws)) => ws,
+            Ok(Err(e)) => {
+                error!("WebSocket handshake with {} failed: {}", addr, e);
+                increment_counter!(HANDSHAKE_FAILURES, "stage" => "websocket");
+                return None;
+            }
+            Err(_) => {
+                error!("WebSocket handshake with {} timed out", addr);
+                increment_counter!(HANDSHAKE_FAILURES, "stage" => "websocket");
+                return None;
+            }
+        };
+        // The callback always picks a codec before accepting.
+        let codec = handshake.codec.take().expect("upgrade accepted without a codec");
+        let token = handshake.token.take();
+        let base64 = handshake.base64;
+        let authenticated = auth::authenticate(&mut ws, &codec, base64, token, &self.access).await;
+        let identity = match authenticated {
+            Ok(identity) => identity,
+            Err(e) => {
+                error!("Rejected {}: {}", addr, e);
+                increment_counter!(HANDSHAKE_FAILURES, "stage" => "auth");
+                return None;
+            }
+        };
+        match &identity {
+            Some(identity) => info!("New WebSocket connection: {} as {}", addr, identity),
+            None => info!("New WebSocket connection: {}", addr),
+        }
+        // The upgrade is only accepted with a permit.
+        let permit = permit.expect("upgrade accepted without a permit");
+        Some(
+            WsTransport::new(ws, codec)
+                .with_base64(base64)
+                .with_max_message(self.max_message)
+                .with_permit(permit)
+                .with_identity(identity)
+                .with_peer(addr)
+                .with_shutdown(&self.shutdown),
+        )
+    }
+
+    /// Runs the TLS handshake with `addr` if the endpoint has a certificate.
+    #[cfg(feature = "tls")]
+    async fn secure(&self, stream: TcpStream, addr: SocketAddr) -> Option<ServerStream> {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return Some(ServerStream::new(Socket::Plain(stream))),
+        };
+        match tokio::time::timeout(crate::tls::HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+            Ok(Ok(stream)) => Some(ServerStream::new(Socket::Tls(Box::new(stream)))),
+            Ok(Err(e)) => {
+                error!("TLS handshake with {} failed: {}", addr, e);
+                increment_counter!(HANDSHAKE_FAILURES, "stage" => "tls");
+                None
+            }
+            Err(_) => {
+                error!("TLS handshake with {} timed out", addr);
+                increment_counter!(HANDSHAKE_FAILURES, "stage" => "tls");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    async fn secure(&self, stream: TcpStream, _: SocketAddr) -> Option<ServerStream> {
+        Some(ServerStream::new(Socket::Plain(stream)))
+    }
+}
+
+/// Accepts WebSocket connections, letting only those through whose token `access` lets in,
+/// see [`crate::auth`], and no more than `limit` at once, on `endpoint`. `listening` tells
+/// whether the accept loop runs. Connections are drained once `shutdown` begins. Requests that
+/// aren't upgrades get the static files of `endpoint`, if it has any.
+///
+/// Each connection gets its handshake in a task of its own, so clients taking their time
+/// with it don't hold up the others. Fails if the address can't be bound or the TLS files
+/// can't be loaded.
+pub async fn bind<Item, SinkItem>(
+    access: Access,
+    limit: ConnectionLimit,
+    endpoint: Endpoint,
+    listening: Listening,
+    shutdown: Shutdown,
+) -> io::Result<
     impl TryStream<
-            Ok = Transport<
-                ws_stream_tungstenite::WsStream<
-                    async_tungstenite::tokio::TokioAdapter<tokio::net::TcpStream>,
-                >,
-                Item,
-                SinkItem,
-                Codec,
-            >,
-            Error = std::io::Error,
+        Ok = WsTransport<TokioAdapter<ServerStream>, Item, SinkItem>,
+        Error = std::io::Error,
     >,
 >
 where
-    Item: for<'de> Deserialize<'de> + Unpin,
-    SinkItem: Serialize + Unpin,
-    Codec: Serializer<SinkItem> + Deserializer<Item> + Unpin,
-    CodecFn: Fn() -> Codec,
+    Item: for<'de> Deserialize<'de> + Unpin + Send + 'static,
+    SinkItem: Serialize + Unpin + Send + 'static,
 {
-    info!("Binding RPC TCP Session");
+    let codecs = rpc::protocol::SUPPORTED
+        .iter()
+        .map(|protocol| FrameMode::from_protocol(protocol))
+        .collect();
+    bind_with_codecs(access, codecs, limit, endpoint, listening, shutdown).await
+}
 
-    //Setup the basic args for the socket.
-    let ip: Ipv4Addr = "127.0.0.1".parse::<Ipv4Addr>().unwrap();
-    let addr = SocketAddr::new(IpAddr::V4(ip), 8083);
+/// Like [`bind`], speaking the subprotocols of `codecs` instead of the built in ones. Clients
+/// asking for several get the first of `codecs` they asked for.
+pub async fn bind_with_codecs<Item, SinkItem, C>(
+    access: Access,
+    codecs: Vec<C>,
+    limit: ConnectionLimit,
+    endpoint: Endpoint,
+    listening: Listening,
+    shutdown: Shutdown,
+) -> io::Result<
+    impl TryStream<
+        Ok = WsTransport<TokioAdapter<ServerStream>, Item, SinkItem, C>,
+        Error = std::io::Error,
+    >,
+>
+where
+    Item: for<'de> Deserialize<'de> + Unpin + Send + 'static,
+    SinkItem: Serialize + Unpin + Send + 'static,
+    C: Codec + Unpin + Send + Sync + 'static,
+{
+    info!("Binding RPC TCP Session");
+    let config = endpoint.websocket_config();
+    let Endpoint {
+        addr,
+        tls,
+        max_message,
+        files,
+        origins,
+        ips,
+    } = endpoint;
 
-    //Create the socket
-    let stream = stream! {
-        let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    #[cfg(feature = "tls")]
+    let tls = match tls.as_ref().map(crate::tls::acceptor).transpose() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("Failed to load the TLS configuration: {}", e);
+            return Err(e);
+        }
+    };
+    #[cfg(not(feature = "tls"))]
+    if let Some(tls) = tls {
+        let message = format!(
+            "TLS certificate {} and key {} were given, but the server was built without the tls \
+             feature",
+            tls.cert.display(),
+            tls.key.display()
+        );
+        error!("{}", message);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
 
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind the WebSocket listener on {}: {}", addr, e);
+            return Err(e);
+        }
+    };
+    let upgrader = Arc::new(Upgrader {
+        access,
+        codecs,
+        limit,
+        config,
+        max_message,
+        #[cfg(feature = "tls")]
+        tls,
+        files,
+        origins,
+        shutdown,
+    });
+    let (upgraded, mut transports) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
         info!("Bound, waiting on clients");
-        while let Ok((stream, addr)) = listener.accept().await {
+        listening.set(true);
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                // Nobody takes the connections anymore.
+                _ = upgraded.closed() => break,
+            };
+            let (stream, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Most likely out of file descriptors, which closing connections give back.
+                    error!("Failed to accept a WebSocket connection: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            if !ips.admits(addr.ip()) {
+                info!("Closing the connection of {}, its address isn't allowed", addr);
+                increment_counter!(DENIED_CONNECTIONS, "listener" => "websocket");
+                continue;
+            }
             info!("WS Peer connected");
             info!("Peer address: {}", addr);
-            let ws = accept_async(stream).await.unwrap();
-            let ws_stream = WsStream::new(ws);
-            info!("New WebSocket connection: {}", addr);
-            let frame = Framed::new(ws_stream, LengthDelimitedCodec::new());
-            let tmp = tarpc::serde_transport::new(frame, codec_fn());
-            yield Ok(tmp)
+            let (upgrader, upgraded) = (upgrader.clone(), upgraded.clone());
+            tokio::spawn(async move {
+                if let Some(transport) = upgrader.upgrade(stream, addr).await {
+                    let _ = upgraded.send(Ok(transport));
+                }
+            });
         }
-    };
-    //pin_mut!(stream);
-    Some(stream)
+        error!("The WebSocket listener stopped accepting");
+        listening.set(false);
+    });
+    Ok(stream::poll_fn(move |cx| transports.poll_recv(cx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip_filter::FilterOrder;
+    use crate::shutdown::{self, Trigger};
+    use async_tungstenite::tokio::client_async;
+    use async_tungstenite::tungstenite::client::IntoClientRequest;
+    use async_tungstenite::WebSocketStream;
+    use futures::{future, TryStreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Accepted = WsTransport<TokioAdapter<ServerStream>, (), ()>;
+
+    /// A listener on a free port of the loopback address, the trigger draining it and where
+    /// it listens.
+    async fn listen(
+        endpoint: impl FnOnce(SocketAddr) -> Endpoint,
+    ) -> (
+        impl TryStream<Ok = Accepted, Error = io::Error>,
+        Trigger,
+        SocketAddr,
+    ) {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let (trigger, shutdown) = shutdown::channel(Duration::from_secs(1));
+        let limit = ConnectionLimit::new(100);
+        let listening = Listening::default();
+        let accepted = bind(
+            Access::Anonymous,
+            limit,
+            endpoint(addr),
+            listening,
+            shutdown,
+        );
+        (accepted.await.unwrap(), trigger, addr)
+    }
+
+    fn plain(addr: SocketAddr) -> Endpoint {
+        Endpoint {
+            addr,
+            tls: None,
+            max_message: 1024,
+            files: None,
+            origins: OriginPolicy::parse("", true).unwrap(),
+            ips: IpFilter::default(),
+        }
+    }
+
+    /// Upgrades `stream` to a WebSocket speaking JSON.
+    async fn upgrade<S>(stream: S, url: &str) -> WebSocketStream<TokioAdapter<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = url.into_client_request().unwrap();
+        let protocol = HeaderValue::from_static(rpc::protocol::JSON);
+        request
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+        client_async(request, stream).await.unwrap().0
+    }
+
+    async fn connect(addr: SocketAddr) -> WebSocketStream<TokioAdapter<TcpStream>> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        upgrade(stream, &format!("ws://{}", addr)).await
+    }
+
+    async fn next_within(
+        accepted: &mut (impl TryStream<Ok = Accepted, Error = io::Error> + Unpin),
+    ) -> Accepted {
+        let next = tokio::time::timeout(Duration::from_secs(5), accepted.try_next());
+        next.await
+            .expect("no connection came through")
+            .unwrap()
+            .expect("the listener stopped")
+    }
+
+    #[tokio::test]
+    async fn a_silent_client_does_not_hold_up_the_others() {
+        let (accepted, _trigger, addr) = listen(plain).await;
+        let mut accepted = Box::pin(accepted.into_stream());
+        let _silent = TcpStream::connect(addr).await.unwrap();
+
+        let _ws = connect(addr).await;
+        next_within(&mut accepted).await;
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_connects_is_served() {
+        let (accepted, _trigger, addr) = listen(plain).await;
+        let mut accepted = Box::pin(accepted.into_stream());
+
+        let _clients = future::join_all((0..50).map(|_| connect(addr))).await;
+        for _ in 0..50 {
+            next_within(&mut accepted).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn binding_a_taken_port_fails() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_trigger, shutdown) = shutdown::channel(Duration::from_secs(1));
+        let endpoint = plain(taken.local_addr().unwrap());
+        let limit = ConnectionLimit::new(100);
+        let bound = bind::<(), ()>(
+            Access::Anonymous,
+            limit,
+            endpoint,
+            Listening::default(),
+            shutdown,
+        );
+        assert!(bound.await.is_err());
+    }
+
+    /// `plain` letting in the loopback addresses, but 127.0.0.1 only if the allow list wins.
+    fn loopback_but_localhost(order: FilterOrder) -> impl FnOnce(SocketAddr) -> Endpoint {
+        move |addr| Endpoint {
+            ips: IpFilter::parse("127.0.0.0/8", "127.0.0.1/32", order).unwrap(),
+            ..plain(addr)
+        }
+    }
+
+    #[tokio::test]
+    async fn an_allowed_address_is_upgraded() {
+        let endpoint = loopback_but_localhost(FilterOrder::AllowWins);
+        let (accepted, _trigger, addr) = listen(endpoint).await;
+        let mut accepted = Box::pin(accepted.into_stream());
+
+        let _ws = connect(addr).await;
+        next_within(&mut accepted).await;
+    }
+
+    #[tokio::test]
+    async fn a_denied_address_is_closed_before_the_upgrade() {
+        let endpoint = loopback_but_localhost(FilterOrder::DenyWins);
+        let (accepted, _trigger, addr) = listen(endpoint).await;
+        let mut accepted = Box::pin(accepted.into_stream());
+
+        let mut denied = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 64];
+        let closed = tokio::time::timeout(Duration::from_secs(5), denied.read(&mut buf)).await;
+        assert_eq!(closed.expect("the connection stayed open").unwrap(), 0);
+        let next = tokio::time::timeout(Duration::from_millis(100), accepted.try_next()).await;
+        assert!(next.is_err(), "a denied connection came through");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stalled_upgrade_is_closed() {
+        let (_accepted, _trigger, addr) = listen(plain).await;
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        stalled.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+        let mut buf = [0; 64];
+        let closed = stalled.read(&mut buf);
+        let read = tokio::time::timeout(UPGRADE_TIMEOUT * 2, closed).await;
+        assert_eq!(read.expect("the upgrade didn't time out").unwrap(), 0);
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn upgrades_over_tls_with_a_self_signed_certificate() {
+        use tokio_rustls::rustls::{self, Certificate, RootCertStore};
+
+        let fixture = |name| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let (accepted, _trigger, addr) = listen(|addr| Endpoint {
+            tls: Some(TlsFiles {
+                cert: fixture("cert.pem").into(),
+                key: fixture("key.pem").into(),
+            }),
+            ..plain(addr)
+        })
+        .await;
+        let mut accepted = Box::pin(accepted.into_stream());
+
+        let pem = std::fs::read(fixture("cert.pem")).unwrap();
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut pem.as_slice()).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = "localhost".try_into().unwrap();
+        let stream = connector.connect(name, stream).await.unwrap();
+        let _ws = upgrade(stream, &format!("wss://localhost:{}", addr.port())).await;
+
+        next_within(&mut accepted).await;
+    }
 }
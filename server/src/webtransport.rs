@@ -0,0 +1,165 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::Stream;
+use log::{error, info};
+use metrics::increment_counter;
+use tarpc::serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use wtransport::endpoint::IncomingSession;
+use wtransport::{Connection, Endpoint, RecvStream, SendStream, ServerConfig};
+
+use crate::auth::{self, Access, Identity};
+use crate::config::TlsFiles;
+use crate::ip_filter::IpFilter;
+use crate::limit::{ConnectionLimit, ConnectionPermit};
+use crate::prometheus::DENIED_CONNECTIONS;
+use crate::transport::StreamTransport;
+
+/// UDP port of the listener unless `--webtransport-port` says otherwise.
+pub const DEFAULT_PORT: u16 = 4433;
+
+/// The bidirectional stream a client opened, which carries all of its tarpc messages.
+pub struct BiStream {
+    /// Dropping the connection would end the session under the stream.
+    connection: Connection,
+    send: SendStream,
+    recv: RecvStream,
+    _permit: ConnectionPermit,
+}
+
+impl AsyncRead for BiStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BiStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+/// Accepts WebTransport sessions on `addr`, yielding a transport for each session's first
+/// bidirectional stream. Sessions need a `token` query parameter `access` lets in, and an
+/// address `ips` admits. Sessions past `limit` are refused like TCP connections are.
+///
+/// The address is only known once the QUIC handshake finished, so turned away peers get as far
+/// as that, unlike on the WebSocket and TCP listeners; their session is refused.
+///
+/// WebTransport always runs over TLS, so `tls` is required unlike for the WebSocket listener.
+pub async fn bind<Item, SinkItem>(
+    addr: SocketAddr,
+    access: Access,
+    tls: Option<TlsFiles>,
+    ips: IpFilter,
+    limit: ConnectionLimit,
+) -> Option<impl Stream<Item = io::Result<StreamTransport<BiStream, Item, SinkItem>>>>
+where
+    Item: for<'de> Deserialize<'de> + Unpin + Send + 'static,
+    SinkItem: Serialize + Unpin + Send + 'static,
+{
+    info!("Binding WebTransport endpoint on {}", addr);
+    let tls = match tls {
+        Some(tls) => tls,
+        None => {
+            error!("WebTransport needs a certificate, set --tls-cert and --tls-key");
+            return None;
+        }
+    };
+    let certificate = match wtransport::Identity::load_pemfiles(&tls.cert, &tls.key).await {
+        Ok(certificate) => certificate,
+        Err(e) => {
+            error!("Failed to load the WebTransport certificate: {}", e);
+            return None;
+        }
+    };
+    let config = ServerConfig::builder()
+        .with_bind_address(addr)
+        .with_identity(&certificate)
+        .build();
+    let endpoint = match Endpoint::server(config) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Failed to bind the WebTransport endpoint: {}", e);
+            return None;
+        }
+    };
+
+    // The handshake and the client opening its stream both take a round trip, so they run
+    // in their own tasks instead of holding up the accept loop.
+    let (transports, accepted) = mpsc::unbounded();
+    tokio::spawn(async move {
+        info!("Bound, waiting on WebTransport clients");
+        loop {
+            let incoming = endpoint.accept().await;
+            let transports = transports.clone();
+            let (access, ips, limit) = (access.clone(), ips.clone(), limit.clone());
+            tokio::spawn(async move {
+                match accept(incoming, &access, &ips, &limit).await {
+                    Ok((stream, identity)) => {
+                        let peer = stream.connection.remote_address();
+                        let transport = StreamTransport::new(stream)
+                            .with_identity(identity)
+                            .with_peer(peer);
+                        let _ = transports.unbounded_send(Ok(transport));
+                    }
+                    Err(e) => error!("WebTransport session failed: {}", e),
+                }
+            });
+        }
+    });
+    Some(accepted)
+}
+
+/// The session's stream, and whose token opened it.
+async fn accept(
+    incoming: IncomingSession,
+    access: &Access,
+    ips: &IpFilter,
+    limit: &ConnectionLimit,
+) -> Result<(BiStream, Option<Identity>), Box<dyn std::error::Error + Send + Sync>> {
+    let request = incoming.await?;
+    let peer = request.remote_address();
+    info!("WebTransport session requested from {} for {}", peer, request.path());
+    // Dropping the request refuses the session.
+    if !ips.admits(peer.ip()) {
+        increment_counter!(DENIED_CONNECTIONS, "listener" => "webtransport");
+        return Err(format!("the address of {} isn't allowed", peer).into());
+    }
+    let permit = match limit.try_acquire() {
+        Some(permit) => permit,
+        None => return Err(format!("{} sessions are open already", limit.max()).into()),
+    };
+    let token = auth::query_token(request.path().split_once('?').map(|(_, query)| query));
+    let identity = auth::check(access, token.as_deref())?;
+    let connection = request.accept().await?;
+    let (send, recv) = connection.accept_bi().await?;
+    info!("New WebTransport connection: {}", connection.remote_address());
+    let stream = BiStream {
+        connection,
+        send,
+        recv,
+        _permit: permit,
+    };
+    Ok((stream, identity))
+}
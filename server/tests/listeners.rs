@@ -0,0 +1,321 @@
+//! The server binary as its clients see it: a WebSocket client of `client::native` and a
+//! stock `WorldClient` over tarpc's `serde_transport::tcp` calling `echo` side by side, the
+//! metrics scraped afterwards, the health endpoints probed meanwhile, and the drain of calls
+//! running at shutdown.
+//!
+//! Every server is stopped over `rpc::control` once its test is done, so these tests need the
+//! `test-util` feature.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use client::rpc_client::ClientBuilder;
+use client::transport::ConnectionState;
+use futures::future;
+use rpc::control::ControlClient;
+use rpc::{WorldClient, WorldError};
+use tarpc::context;
+use tarpc::tokio_serde::formats::Json;
+use tokio::task::LocalSet;
+
+/// Calls over each transport, below `--max-in-flight` so none is turned away as busy.
+const CALLS: usize = 50;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a server may take to exit once asked to shut down, after which it's killed.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The server binary on ports of its own, shut down once dropped.
+struct Server {
+    child: Child,
+    ws: SocketAddr,
+    tcp: SocketAddr,
+    control: SocketAddr,
+    /// Where `/metrics` is served, if asked for.
+    metrics: Option<SocketAddr>,
+    /// Where `/healthz` and `/readyz` are served, if asked for.
+    health: Option<SocketAddr>,
+}
+
+/// What a [`Server`] serves besides its WebSocket and TCP listeners, nothing by default.
+#[derive(Default)]
+struct Builder {
+    metrics: bool,
+    health: bool,
+    drain_timeout: Option<u64>,
+}
+
+impl Builder {
+    /// Serves `/metrics` too.
+    fn metrics(mut self) -> Self {
+        self.metrics = true;
+        self
+    }
+
+    /// Serves `/healthz` and `/readyz` too.
+    fn health(mut self) -> Self {
+        self.health = true;
+        self
+    }
+
+    /// Gives the calls running at shutdown `secs` seconds instead of the default.
+    fn drain_timeout(mut self, secs: u64) -> Self {
+        self.drain_timeout = Some(secs);
+        self
+    }
+
+    /// Starts the server and waits until it listens.
+    fn start(self) -> Server {
+        let (ws, tcp, control) = (free_addr(), free_addr(), free_addr());
+        let metrics = self.metrics.then(free_addr);
+        let health = self.health.then(free_addr);
+        let mut command = Command::new(env!("CARGO_BIN_EXE_server"));
+        if let Some(secs) = self.drain_timeout {
+            command.args(["--drain-timeout", &secs.to_string()]);
+        }
+        let child = command
+            .args(["--ws-port", &ws.port().to_string()])
+            .args(["--tcp-port", &tcp.port().to_string()])
+            .args(["--control-port", &control.port().to_string()])
+            .args(["--health-port", &or_off(health.map(|addr| addr.port()))])
+            .args(["--metrics-addr", &or_off(metrics)])
+            .args(["--allow-anonymous", "--allow-missing-origin"])
+            .args(["--rate-limits", "echo=1000/1000"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("can't start the server");
+        let server = Server {
+            child,
+            ws,
+            tcp,
+            control,
+            metrics,
+            health,
+        };
+        wait_for(ws);
+        wait_for(tcp);
+        wait_for(control);
+        for addr in metrics.into_iter().chain(health) {
+            wait_for(addr);
+        }
+        server
+    }
+}
+
+impl Server {
+    fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Asks the server to shut down, answered once the drain began.
+    async fn shut_down(&self) -> io::Result<()> {
+        let transport = tarpc::serde_transport::tcp::connect(self.control, Json::default).await?;
+        let control = ControlClient::new(tarpc::client::Config::default(), transport).spawn();
+        control
+            .shutdown(context::current())
+            .await
+            .map_err(io::Error::other)
+    }
+
+    /// How the process exited, unless it still runs after `timeout`.
+    fn exited_within(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let started = Instant::now();
+        loop {
+            match self.child.try_wait() {
+                Ok(None) if started.elapsed() < timeout => thread::sleep(Duration::from_millis(50)),
+                Ok(status) => return status,
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let requested = run(self.shut_down());
+            if requested.is_err() || self.exited_within(EXIT_TIMEOUT).is_none() {
+                let _ = self.child.kill();
+            }
+        }
+        let _ = self.child.wait();
+    }
+}
+
+fn or_off(value: Option<impl ToString>) -> String {
+    value.map_or("off".into(), |value| value.to_string())
+}
+
+/// A port nothing listens on right now.
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("no free port")
+}
+
+/// Waits until the server listens on `addr`.
+fn wait_for(addr: SocketAddr) {
+    let started = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        assert!(
+            started.elapsed() < STARTUP_TIMEOUT,
+            "server never listened on {}",
+            addr
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// The status and body `GET path` is answered with.
+fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split(' ').nth(1).and_then(|status| status.parse().ok());
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    (status.expect("not an HTTP response"), body.to_string())
+}
+
+/// The value of the sample `name` in a Prometheus scrape, labels and all.
+fn sample(scrape: &str, name: &str) -> Option<f64> {
+    scrape
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Runs `test` on a runtime the WebSocket client can spawn its tasks on, with `spawn_local`
+/// as `client::native` does.
+fn run<F: std::future::Future>(test: F) -> F::Output {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    LocalSet::new().block_on(&runtime, test)
+}
+
+#[test]
+fn echo_over_websocket_and_tcp_at_once() {
+    let server = Server::builder().start();
+    let (ws, tcp) = (server.ws, server.tcp);
+    run(async {
+        let world = ClientBuilder::new(format!("ws://{}", ws))
+            .connect()
+            .await
+            .unwrap();
+        let transport = tarpc::serde_transport::tcp::connect(tcp, Json::default)
+            .await
+            .unwrap();
+        let native = WorldClient::new(tarpc::client::Config::default(), transport);
+        tokio::task::spawn_local(native.dispatch);
+        let native = native.client;
+
+        let values: Vec<String> = (0..CALLS).map(|i| format!("echo {}", i)).collect();
+        let over_ws = values
+            .iter()
+            .map(|value| world.client().echo(context::current(), value.clone()));
+        let over_tcp = values
+            .iter()
+            .map(|value| native.echo(context::current(), value.clone()));
+        let (ws_answers, tcp_answers) =
+            future::join(future::join_all(over_ws), future::join_all(over_tcp)).await;
+        for ((value, ws), tcp) in values.iter().zip(ws_answers).zip(tcp_answers) {
+            assert_eq!(&ws.unwrap().unwrap(), value);
+            assert_eq!(&tcp.unwrap().unwrap(), value);
+        }
+    });
+}
+
+#[test]
+fn metrics_count_calls_and_the_bytes_on_the_wire() {
+    let server = Server::builder().metrics().start();
+    let value = "x".repeat(1000);
+    run(async {
+        let world = ClientBuilder::new(format!("ws://{}", server.ws))
+            .connect()
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            let answer = world.client().echo(context::current(), value.clone()).await;
+            assert_eq!(answer.unwrap().unwrap(), value);
+        }
+    });
+
+    let (status, scrape) = get(server.metrics.unwrap(), "/metrics");
+    assert_eq!(status, 200);
+    let calls = sample(&scrape, r#"rpc_requests_total{method="World.echo",outcome="ok"}"#);
+    assert_eq!(calls, Some(3.0), "{}", scrape);
+    // Every call sent its value there and back, and the handshake some more.
+    for name in ["rpc_request_bytes_total", "rpc_response_bytes_total"] {
+        let bytes = sample(&scrape, &format!(r#"{}{{transport="websocket"}}"#, name));
+        assert!(bytes.unwrap_or_default() > 3000.0, "{}", scrape);
+    }
+}
+
+#[test]
+fn calls_running_at_shutdown_drain_before_the_connection_goes_away() {
+    let server = Server::builder().drain_timeout(1).start();
+    run(async {
+        let world = ClientBuilder::new(format!("ws://{}", server.ws))
+            .connect()
+            .await
+            .unwrap();
+        let within = world.client().delay(context::current(), 200);
+        let past = world.client().delay(context::current(), 5_000);
+        let shutdown = async {
+            // Both delays are running by then.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            server.shut_down().await.unwrap();
+        };
+        let (within, past, ()) = future::join3(within, past, shutdown).await;
+        assert_eq!(within.unwrap(), Ok("Delayed for 200 ms".to_string()));
+        assert!(matches!(past.unwrap(), Err(WorldError::Unavailable(_))));
+
+        let closed = async {
+            loop {
+                if let ConnectionState::Closed(info) = world.transport().state() {
+                    return info;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let info = tokio::time::timeout(EXIT_TIMEOUT, closed).await.unwrap();
+        assert_eq!(info.code, 1001, "{}", info);
+    });
+}
+
+#[test]
+fn the_health_endpoints_answer_while_calls_are_served() {
+    let server = Server::builder().health().start();
+    let health = server.health.unwrap();
+    run(async {
+        let world = ClientBuilder::new(format!("ws://{}", server.ws))
+            .connect()
+            .await
+            .unwrap();
+        let answer = world.client().echo(context::current(), "up".into()).await;
+        assert_eq!(answer.unwrap().unwrap(), "up");
+
+        let (status, body) = get(health, "/healthz");
+        assert_eq!(status, 200, "{}", body);
+        let alive: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(alive["status"], "up");
+        let (status, body) = get(health, "/readyz");
+        assert_eq!(status, 200, "{}", body);
+        let ready: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(ready["listeners"]["websocket"], true, "{}", body);
+        assert_eq!(ready["connections"]["open"], 1, "{}", body);
+
+        let answer = world.client().echo(context::current(), "still up".into()).await;
+        assert_eq!(answer.unwrap().unwrap(), "still up");
+    });
+}